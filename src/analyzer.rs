@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::CompressedDictionary;
+
+/// Common words of three letters or more (shorter ones are never indexed) in Russian,
+/// Ukrainian and English, dropped by the `stopwords` filter without a file
+const DEFAULT_STOPWORDS: &[&str] = &[
+    "and",
+    "are",
+    "but",
+    "for",
+    "from",
+    "had",
+    "has",
+    "have",
+    "her",
+    "his",
+    "not",
+    "that",
+    "the",
+    "this",
+    "was",
+    "were",
+    "with",
+    "you",
+    "або",
+    "але",
+    "без",
+    "був",
+    "була",
+    "було",
+    "були",
+    "быть",
+    "вот",
+    "все",
+    "всё",
+    "даже",
+    "для",
+    "его",
+    "если",
+    "есть",
+    "еще",
+    "ещё",
+    "как",
+    "когда",
+    "меня",
+    "мне",
+    "над",
+    "нет",
+    "она",
+    "они",
+    "под",
+    "при",
+    "так",
+    "там",
+    "тот",
+    "уже",
+    "чем",
+    "что",
+    "это",
+    "який",
+    "яка",
+    "яке",
+    "які",
+];
+
+static CURRENT: LazyLock<RwLock<Arc<Analyzer>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Analyzer::default())));
+
+/// A step applied to every lowercased word after tokenization
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenFilter {
+    /// Spell `ё` as `е`, as most Russian text does
+    FoldYo,
+    /// Drop these words, kept sorted
+    Stopwords(Vec<String>),
+}
+
+impl fmt::Display for TokenFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenFilter::FoldYo => write!(f, "fold-yo"),
+            TokenFilter::Stopwords(words) => write!(f, "stopwords ({} words)", words.len()),
+        }
+    }
+}
+
+/// How words are turned into index terms: lowercased, then run through the filters in order.
+/// A build records its analyzer in the manifest and searches run query terms through the same
+/// chain, so a query spells terms the way the index does.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Analyzer {
+    pub filters: Vec<TokenFilter>,
+}
+
+impl Analyzer {
+    /// Parse a comma-separated chain such as `lowercase,fold-yo,stopwords=stop.txt`.
+    /// `lowercase` is always applied and may be omitted; `stopwords` without a file uses a
+    /// built-in list, a file has one word per line and `#` starts a comment.
+    pub fn from_spec(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut filters = Vec::new();
+        for step in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|step| !step.is_empty())
+        {
+            match step.split_once('=') {
+                None if step == "lowercase" => {}
+                None if step == "fold-yo" => filters.push(TokenFilter::FoldYo),
+                None if step == "stopwords" => filters.push(stopwords(
+                    DEFAULT_STOPWORDS.iter().map(|word| word.to_string()),
+                )),
+                Some(("stopwords", path)) => filters.push(stopwords(
+                    fs::read_to_string(path)?
+                        .lines()
+                        .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+                        .filter(|word| !word.is_empty()),
+                )),
+                _ => {
+                    return Err(format!(
+                        "Unknown analyzer step '{}', expected lowercase, fold-yo, stopwords or stopwords=FILE",
+                        step
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(Analyzer { filters })
+    }
+
+    /// The analyzer of the query being run, plain lowercasing until another is installed
+    pub fn current() -> Arc<Analyzer> {
+        CURRENT
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Run every query parsed from now on through this analyzer
+    pub fn install(self) {
+        *CURRENT
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(self);
+    }
+
+    /// Only lowercases, as every build did before analyzers were configurable
+    pub fn is_plain(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// The term `word` is indexed as, or `None` when a filter drops it
+    pub fn analyze(&self, word: &str) -> Option<String> {
+        let term = self.normalize(word);
+        for filter in &self.filters {
+            if let TokenFilter::Stopwords(words) = filter {
+                if words.binary_search(&term).is_ok() {
+                    return None;
+                }
+            }
+        }
+        Some(term)
+    }
+
+    /// `text` with the character-level steps applied but nothing dropped, for `*` and `?`
+    /// patterns that are matched against analyzed terms
+    pub fn normalize(&self, text: &str) -> String {
+        let mut normalized = text.to_lowercase();
+        for filter in &self.filters {
+            if *filter == TokenFilter::FoldYo {
+                normalized = normalized.replace('ё', "е");
+            }
+        }
+        normalized
+    }
+}
+
+/// `lowercase` followed by the filters, e.g. `lowercase, fold-yo`
+impl fmt::Display for Analyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lowercase")?;
+        for filter in &self.filters {
+            write!(f, ", {}", filter)?;
+        }
+        Ok(())
+    }
+}
+
+fn stopwords(words: impl Iterator<Item = String>) -> TokenFilter {
+    let mut words: Vec<String> = words.collect();
+    words.sort_unstable();
+    words.dedup();
+    TokenFilter::Stopwords(words)
+}
+
+/// A term spread across so much of the corpus that it barely tells documents apart
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopwordCandidate {
+    pub term: String,
+    pub document_frequency: usize,
+    /// Share of the corpus documents holding the term
+    pub document_share: f64,
+    pub frequency: u32,
+}
+
+/// Terms of `dictionary` held by at least `min_share` of its documents, the most widespread
+/// first and ties by frequency: a corpus-specific list for the `stopwords=FILE` step
+pub fn discover_stopwords(
+    dictionary: &CompressedDictionary,
+    min_share: f64,
+) -> Vec<StopwordCandidate> {
+    let documents = dictionary.documents.len();
+    if documents == 0 {
+        return Vec::new();
+    }
+    let mut candidates: Vec<StopwordCandidate> = dictionary
+        .sorted_terms
+        .iter()
+        .zip(&dictionary.term_entries)
+        .map(|(term, entry)| StopwordCandidate {
+            term: term.clone(),
+            document_frequency: entry.documents.len(),
+            document_share: entry.documents.len() as f64 / documents as f64,
+            frequency: entry.frequency,
+        })
+        .filter(|candidate| candidate.document_share >= min_share)
+        .collect();
+    candidates.sort_by(|a, b| {
+        b.document_frequency
+            .cmp(&a.document_frequency)
+            .then(b.frequency.cmp(&a.frequency))
+            .then_with(|| a.term.cmp(&b.term))
+    });
+    candidates
+}
+
+/// Write `candidates` as a stopword file `Analyzer::from_spec` reads, each word followed by a
+/// comment with its document frequency, under `header` comment lines
+pub fn write_stopword_file(
+    path: &str,
+    header: &str,
+    candidates: &[StopwordCandidate],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = String::new();
+    for line in header.lines() {
+        contents.push_str(&format!("# {}\n", line));
+    }
+    for candidate in candidates {
+        contents.push_str(&format!(
+            "{:<24} # {} documents ({:.0}%), {} occurrences\n",
+            candidate.term,
+            candidate.document_frequency,
+            candidate.document_share * 100.0,
+            candidate.frequency
+        ));
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FB2Parser, QueryAst};
+
+    #[test]
+    fn test_queries_are_analyzed_like_documents() {
+        let analyzer = Analyzer::from_spec("lowercase, fold-yo, stopwords").unwrap();
+        assert_eq!(
+            analyzer.to_string(),
+            format!(
+                "lowercase, fold-yo, stopwords ({} words)",
+                DEFAULT_STOPWORDS.len()
+            )
+        );
+        assert_eq!(analyzer.analyze("Ёлка"), Some("елка".to_string()));
+        assert_eq!(analyzer.analyze("Это"), None);
+        assert_eq!(analyzer.normalize("ёл*"), "ел*");
+        assert!(Analyzer::from_spec("lowercase").unwrap().is_plain());
+        assert!(Analyzer::from_spec("stem").is_err());
+        let parser = FB2Parser::new().with_analyzer(analyzer.clone());
+        assert_eq!(
+            parser.tokenize_text("Ёлка, это всё ёлка!"),
+            vec!["елка", "елка"]
+        );
+
+        let parse = |query: &str| QueryAst::parse_with(query, &analyzer).unwrap();
+        assert_eq!(
+            parse("Ёлка and (это or the) and not \"ёлка и это всё\""),
+            parse("елка and not \"елка и\"")
+        );
+        assert_eq!(parse("near/3(ель это сосна)"), parse("near/3(ель сосна)"));
+        assert_eq!(parse("near/3(это ель)"), parse("ель"));
+        assert_eq!(parse("ёл* or что"), QueryAst::Wildcard("ел*".to_string()));
+        assert!(QueryAst::parse_with("это and the", &analyzer).is_err());
+
+        let plain = Analyzer::default();
+        assert_eq!(
+            QueryAst::parse_with("Ёлка and the", &plain).unwrap(),
+            QueryAst::parse("ёлка and the").unwrap()
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stop.txt");
+        fs::write(&path, "# names\nАнна\nвронский\n").unwrap();
+        let analyzer = Analyzer::from_spec(&format!("stopwords={}", path.display())).unwrap();
+        assert_eq!(analyzer.analyze("анна"), None);
+        assert_eq!(analyzer.analyze("это"), Some("это".to_string()));
+    }
+
+    #[test]
+    fn test_discovered_stopwords_feed_the_analyzer() {
+        let mut dict = crate::Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "который князь который"),
+            ("b.fb2", "который война"),
+            ("c.fb2", "князь который мир"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let dictionary = CompressedDictionary::from_dictionary(&dict);
+        let candidates = discover_stopwords(&dictionary, 0.6);
+        let terms: Vec<&str> = candidates.iter().map(|c| c.term.as_str()).collect();
+        assert_eq!(terms, ["который", "князь"]);
+        assert_eq!(candidates[0].frequency, 4);
+        assert!((candidates[1].document_share - 2.0 / 3.0).abs() < 1e-12);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stops.txt");
+        let path = path.to_str().unwrap();
+        write_stopword_file(path, "terms in 60% of documents", &candidates).unwrap();
+        let analyzer = Analyzer::from_spec(&format!("stopwords={}", path)).unwrap();
+        assert_eq!(analyzer.analyze("Князь"), None);
+        assert_eq!(analyzer.analyze("война"), Some("война".to_string()));
+    }
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use zip::ZipArchive;
+
+use crate::{is_epub, EpubParser};
+
+/// Archives each thread keeps open, most recently read first
+const OPEN_ARCHIVES: usize = 4;
+
+/// Most bytes reserved up front for an entry; the size in the zip header is only a hint, and a
+/// malformed one must not abort the process
+pub const MAX_ENTRY_PREALLOCATION: u64 = 64 * 1024 * 1024;
+
+/// A zip file opened with its central directory read, and the size and modification time it
+/// had then
+struct OpenArchive {
+    path: PathBuf,
+    stamp: (u64, Option<SystemTime>),
+    zip: ZipArchive<File>,
+}
+
+thread_local! {
+    static ARCHIVES: RefCell<Vec<OpenArchive>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Which books a build collects from its input directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceFormat {
+    /// FB2 files, loose or in zip archives
+    #[default]
+    Fb2,
+    Epub,
+    /// Both, told apart by extension
+    Auto,
+}
+
+impl SourceFormat {
+    pub fn includes_fb2(self) -> bool {
+        matches!(self, SourceFormat::Fb2 | SourceFormat::Auto)
+    }
+
+    pub fn includes_epub(self) -> bool {
+        matches!(self, SourceFormat::Epub | SourceFormat::Auto)
+    }
+}
+
+impl FromStr for SourceFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "fb2" => Ok(SourceFormat::Fb2),
+            "epub" => Ok(SourceFormat::Epub),
+            "auto" => Ok(SourceFormat::Auto),
+            other => Err(format!(
+                "Unknown format '{}', expected fb2, epub or auto",
+                other
+            )),
+        }
+    }
+}
+
+/// Whether `path` names a zip container, `.zip` or `.fb2.zip`
+pub fn is_zip_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+fn is_fb2_name(name: &str) -> bool {
+    name.to_lowercase().ends_with(".fb2")
+}
+
+/// FB2 entries of the zip file at `archive` as paths through it, e.g.
+/// `books.zip/tolstoy/war.fb2`, in name order. These paths open with `open_source` like plain
+/// files, and their file name is the entry's, so a book is named the same zipped or not.
+pub fn archive_entries(archive: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let zip = ZipArchive::new(File::open(archive)?)?;
+    let mut names: Vec<&str> = zip.file_names().filter(|name| is_fb2_name(name)).collect();
+    names.sort_unstable();
+    Ok(names.into_iter().map(|name| archive.join(name)).collect())
+}
+
+/// The zip file `path` leads through and the name of the entry inside it, or `None` for a
+/// path to a plain file
+fn split_archive_path(path: &Path) -> Option<(&Path, String)> {
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| is_zip_archive(ancestor) && ancestor.is_file())?;
+    let entry: Vec<String> = path
+        .strip_prefix(archive)
+        .ok()?
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    Some((archive, entry.join("/")))
+}
+
+/// Run `read` on the zip file at `archive`. Each thread keeps its last few archives open, so
+/// the entries of one zip are read without parsing its central directory again for every
+/// entry; an archive changed on disk since it was opened is opened anew.
+fn with_archive<T>(
+    archive: &Path,
+    read: impl FnOnce(&mut ZipArchive<File>) -> io::Result<T>,
+) -> io::Result<T> {
+    let metadata = std::fs::metadata(archive)?;
+    let stamp = (metadata.len(), metadata.modified().ok());
+    ARCHIVES.with(|archives| {
+        let mut archives = archives.borrow_mut();
+        let open = match archives
+            .iter()
+            .position(|open| open.path == archive && open.stamp == stamp)
+        {
+            Some(i) => archives.remove(i),
+            None => OpenArchive {
+                path: archive.to_path_buf(),
+                stamp,
+                zip: ZipArchive::new(File::open(archive)?).map_err(io::Error::other)?,
+            },
+        };
+        archives.insert(0, open);
+        archives.truncate(OPEN_ARCHIVES);
+        read(&mut archives[0].zip)
+    })
+}
+
+/// Decompress all of `entry`, reserving no more than `MAX_ENTRY_PREALLOCATION` from the size its
+/// header claims
+pub fn read_zip_entry<R: Read + io::Seek>(
+    zip: &mut ZipArchive<R>,
+    entry: &str,
+) -> io::Result<Vec<u8>> {
+    let mut file = zip
+        .by_name(entry)
+        .map_err(|e| io::Error::other(format!("{}: {}", entry, e)))?;
+    let mut bytes = Vec::with_capacity(file.size().min(MAX_ENTRY_PREALLOCATION) as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_entry(archive: &Path, entry: &str) -> io::Result<Vec<u8>> {
+    with_archive(archive, |zip| read_zip_entry(zip, entry))
+}
+
+/// The file on disk a source is read from: `path` itself, or the archive holding it
+pub fn source_file(path: &Path) -> &Path {
+    split_archive_path(path).map_or(path, |(archive, _)| archive)
+}
+
+/// Open the source at `path`, a plain file, an entry of `archive_entries` or an EPUB book.
+/// Plain files are read as they are parsed; an entry is decompressed into memory whole first,
+/// and a book's chapters are read as `EpubParser::read_chapters` joins them.
+pub fn open_source(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if is_epub(path) {
+        return Ok(Box::new(Cursor::new(
+            EpubParser::new().read_chapters(path)?,
+        )));
+    }
+    match split_archive_path(path) {
+        Some((archive, entry)) => Ok(Box::new(Cursor::new(read_entry(archive, &entry)?))),
+        None => Ok(Box::new(BufReader::new(File::open(path)?))),
+    }
+}
+
+/// Contents of the source at `path`, decompressed for an archive entry and joined chapters
+/// for an EPUB book
+pub fn read_source(path: &Path) -> io::Result<Vec<u8>> {
+    if is_epub(path) {
+        return EpubParser::new().read_chapters(path);
+    }
+    match split_archive_path(path) {
+        Some((archive, entry)) => read_entry(archive, &entry),
+        None => std::fs::read(path),
+    }
+}
+
+/// Size of the source at `path` as `read_source` gives it
+pub fn source_len(path: &Path) -> io::Result<u64> {
+    if is_epub(path) {
+        return EpubParser::new().chapters_len(path);
+    }
+    match split_archive_path(path) {
+        Some((archive, entry)) => with_archive(archive, |zip| {
+            Ok(zip.by_name(&entry).map_err(io::Error::other)?.size())
+        }),
+        None => Ok(std::fs::metadata(path)?.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    #[test]
+    fn test_archive_entries_read_like_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("books.fb2.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, text) in [
+            ("tolstoy/war.fb2", "<p>Война</p>"),
+            ("readme.txt", "not a book"),
+            ("anna.FB2", "<p>Анна</p>"),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let plain = dir.path().join("plain.fb2");
+        std::fs::write(&plain, "<p>Мир</p>").unwrap();
+
+        let entries = archive_entries(&archive).unwrap();
+        assert_eq!(
+            entries,
+            [archive.join("anna.FB2"), archive.join("tolstoy/war.fb2")]
+        );
+        assert!(is_zip_archive(&archive) && !is_zip_archive(&plain));
+
+        let war = &entries[1];
+        assert_eq!(read_source(war).unwrap(), "<p>Война</p>".as_bytes());
+        assert_eq!(source_len(war).unwrap(), "<p>Война</p>".len() as u64);
+        assert_eq!(source_file(war), archive);
+        let mut text = String::new();
+        open_source(war).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "<p>Война</p>");
+
+        assert_eq!(source_file(&plain), plain);
+        assert_eq!(source_len(&plain).unwrap(), "<p>Мир</p>".len() as u64);
+        assert!(read_source(&archive.join("missing.fb2")).is_err());
+        assert!(open_source(&dir.path().join("missing.fb2")).is_err());
+    }
+
+    #[test]
+    fn test_archive_rewritten_on_disk_is_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("books.zip");
+        let write = |books: &[(&str, &str)]| {
+            let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+            for (name, text) in books {
+                zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+                zip.write_all(text.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        };
+
+        write(&[("a.fb2", "<p>Война</p>"), ("b.fb2", "<p>Мир</p>")]);
+        for entry in archive_entries(&archive).unwrap() {
+            assert!(source_len(&entry).unwrap() > 0);
+            assert!(!read_source(&entry).unwrap().is_empty());
+        }
+        write(&[("a.fb2", "<p>Анна Каренина</p>")]);
+        assert_eq!(
+            read_source(&archive.join("a.fb2")).unwrap(),
+            "<p>Анна Каренина</p>".as_bytes()
+        );
+        assert!(read_source(&archive.join("b.fb2")).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::persist::Persistable;
+use crate::tfidf::{idf, tf_weight};
+use crate::{query_terms, CompressedInvertedIndex, TopKStats};
+
+/// Slack for float rounding when the champions' last score is compared to a bound
+const SCORE_EPSILON: f64 = 1e-9;
+
+/// Documents with their scores, best first
+type Ranking = Vec<(String, f64)>;
+
+/// How a top-k ranking was computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankingPath {
+    /// The champion lists proved the result; `candidates` documents were scored
+    Champions { candidates: usize },
+    /// The champion lists could not, so the full posting lists were walked
+    FullPostings(TopKStats),
+}
+
+/// The documents a term occurs in most
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChampionList {
+    /// (document, occurrences), most occurrences first, ties by name
+    pub champions: Vec<(String, u32)>,
+    /// Most occurrences of the term in a document left out of `champions`
+    pub rest_max: u32,
+}
+
+/// A tier of short posting lists for ranked retrieval: for every term in more than `size`
+/// documents, the `size` documents it occurs in most. Top-k queries are scored on these
+/// documents alone, and the most occurrences of each term outside its list bound what any
+/// other document can score. When the k-th best champion score beats that bound the ranking
+/// is exact; otherwise ranking falls back to the full posting lists.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChampionLists {
+    /// Documents kept per term
+    pub size: usize,
+    /// Lists of the terms with more than `size` documents; other terms' posting lists are
+    /// short enough to read whole
+    pub lists: HashMap<String, ChampionList>,
+}
+
+impl Persistable for ChampionLists {
+    const SUFFIX: &'static str = "_champions";
+}
+
+impl ChampionLists {
+    /// Champion lists of every term of `index` with more than `size` documents
+    pub fn from_index(index: &CompressedInvertedIndex, size: usize) -> Self {
+        let terms: Vec<&String> = index
+            .compressed_index
+            .keys()
+            .filter(|term| index.document_frequency(term) > size)
+            .collect();
+        let lists = terms
+            .par_iter()
+            .map(|&term| {
+                let mut postings = index.get_postings_for_term(term).unwrap_or_default();
+                postings.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                let rest_max = postings.get(size).map_or(0, |&(_, count)| count);
+                postings.truncate(size);
+                let list = ChampionList {
+                    champions: postings,
+                    rest_max,
+                };
+                (term.clone(), list)
+            })
+            .collect();
+        ChampionLists { size, lists }
+    }
+
+    /// The `k` best of `documents` by the TF-IDF weight of the query's terms, equal to
+    /// `CompressedInvertedIndex::rank_top_k`: from the champion lists when they prove the
+    /// result, from the full posting lists otherwise
+    pub fn rank_top_k<I>(
+        &self,
+        index: &CompressedInvertedIndex,
+        documents: I,
+        query: &str,
+        k: usize,
+    ) -> Result<(Vec<(String, f64)>, RankingPath), String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let documents: HashSet<String> = documents.into_iter().collect();
+        if let Some((ranked, candidates)) = self.champion_top_k(index, &documents, query, k)? {
+            return Ok((ranked, RankingPath::Champions { candidates }));
+        }
+        let (ranked, stats) = index.rank_top_k(documents, query, k)?;
+        Ok((ranked, RankingPath::FullPostings(stats)))
+    }
+
+    /// The top `k` and the number of candidates scored, or `None` when a document outside
+    /// the champions might outscore the k-th
+    fn champion_top_k(
+        &self,
+        index: &CompressedInvertedIndex,
+        documents: &HashSet<String>,
+        query: &str,
+        k: usize,
+    ) -> Result<Option<(Ranking, usize)>, String> {
+        let mut lists: Vec<TermChampions> = Vec::new();
+        for term in query_terms(query)? {
+            let document_frequency = index.document_frequency(&term);
+            if document_frequency == 0 {
+                continue;
+            }
+            let weight = idf(index.doc_id_to_name.len(), document_frequency);
+            match self.lists.get(&term) {
+                Some(list) => {
+                    lists.push(TermChampions {
+                        weight,
+                        champions: list.champions.iter().cloned().collect(),
+                        rest_max: Some(list.rest_max),
+                    });
+                }
+                None => {
+                    let postings = index.get_postings_for_term(&term).unwrap_or_default();
+                    lists.push(TermChampions {
+                        weight,
+                        champions: postings.into_iter().collect(),
+                        rest_max: None,
+                    });
+                }
+            }
+        }
+
+        let candidates: HashSet<&String> = lists
+            .iter()
+            .flat_map(|list| list.champions.keys())
+            .filter(|document| documents.contains(*document))
+            .collect();
+        // Lowest and highest score of each candidate; they differ when a cut list lacks it
+        let mut scored: Vec<(&String, f64, f64)> = candidates
+            .iter()
+            .map(|&document| {
+                let (mut lowest, mut highest) = (0.0, 0.0);
+                for list in &lists {
+                    match (list.champions.get(document), list.rest_max) {
+                        (Some(&count), _) => {
+                            lowest += tf_weight(count) * list.weight;
+                            highest += tf_weight(count) * list.weight;
+                        }
+                        (None, Some(rest_max)) => highest += tf_weight(rest_max) * list.weight,
+                        (None, None) => {}
+                    }
+                }
+                (document, lowest, highest)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        if scored.len() < k || k == 0 {
+            return Ok(None);
+        }
+
+        let (top, rest) = scored.split_at(k);
+        if top.iter().any(|&(_, lowest, highest)| lowest != highest) {
+            return Ok(None);
+        }
+        let kth = top[k - 1].1;
+        let outside: f64 = lists
+            .iter()
+            .filter_map(|list| Some(tf_weight(list.rest_max?) * list.weight))
+            .sum();
+        let mut doubt = rest
+            .iter()
+            .filter(|&&(_, lowest, highest)| lowest != highest)
+            .map(|&(_, _, highest)| highest)
+            .fold(0.0, f64::max);
+        if documents.len() > candidates.len() {
+            doubt = doubt.max(outside);
+        }
+        if kth <= doubt + SCORE_EPSILON {
+            return Ok(None);
+        }
+        Ok(Some((
+            top.iter()
+                .map(|&(document, score, _)| (document.clone(), score))
+                .collect(),
+            candidates.len(),
+        )))
+    }
+}
+
+/// A query term as `champion_top_k` scores it
+struct TermChampions {
+    weight: f64,
+    /// Occurrences in the champions, or in every document when the list was not cut
+    champions: HashMap<String, u32>,
+    /// Most occurrences in a document left out of a cut list
+    rest_max: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dictionary, QueryParser};
+
+    #[test]
+    fn test_champions_agree_with_full_ranking() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "war war war war peace"),
+            ("b.fb2", "war war war peace peace"),
+            ("c.fb2", "war peace"),
+            ("d.fb2", "war forest"),
+            ("e.fb2", "war peace forest"),
+            ("f.fb2", "forest forest forest"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let index = CompressedInvertedIndex::from_dictionary(&dict);
+        let champions = ChampionLists::from_index(&index, 2);
+        let war = &champions.lists["war"];
+        assert_eq!(
+            war.champions,
+            [("a.fb2".to_string(), 4), ("b.fb2".to_string(), 3)]
+        );
+        assert_eq!(war.rest_max, 1);
+
+        let mut paths = Vec::new();
+        for (query, k) in [
+            ("war", 2),
+            ("war or peace", 1),
+            ("war", 4),
+            ("forest or peace", 2),
+            ("war and peace", 1),
+        ] {
+            let documents = index.search(query).unwrap();
+            let (ranked, path) = champions
+                .rank_top_k(&index, documents.clone(), query, k)
+                .unwrap();
+            let (expected, _) = index.rank_top_k(documents, query, k).unwrap();
+            assert_eq!(ranked, expected, "{}", query);
+            paths.push(matches!(path, RankingPath::Champions { .. }));
+        }
+        assert_eq!(paths, [true, true, false, false, true]);
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+/// Share of a document's letters the less frequent of Cyrillic and Latin must reach for the
+/// document to count as mixed rather than written in the other script
+pub const MIXED_SCRIPT_SHARE: f64 = 0.1;
+
+/// Share of body characters that are neither letters, digits, whitespace nor punctuation above
+/// which a document is flagged
+pub const MAX_NON_TEXT_RATIO: f64 = 0.05;
+
+/// Terms per kilobyte of file below which tokenization is suspected to have missed the text.
+/// Russian prose yields about 80.
+pub const DEFAULT_MIN_TERMS_PER_KB: f64 = 10.0;
+
+/// Character pairs of mis-decoded Cyrillic from which a document is flagged
+const MIN_MOJIBAKE_PAIRS: usize = 10;
+
+/// Second characters of UTF-8 Cyrillic read as Windows-1251: `привет` turns into `РїСЂРёРІРµС‚`
+const CP1251_CONTINUATIONS: &str =
+    "ЂЃ‚ѓ„…†‡€‰Љ‹ЊЌЋЏђ‘’“”•–—™љ›њќћџ\u{a0}ЎўЈ¤Ґ¦§Ё©Є«¬\u{ad}®Ї°±Ііґµ¶·ё№є»јЅѕї";
+
+/// The script most letters of a document are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Script {
+    Cyrillic,
+    Latin,
+    /// Both scripts, the smaller one above `MIXED_SCRIPT_SHARE` of the letters
+    Mixed,
+    /// Neither, e.g. a book of images or in another alphabet
+    Other,
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Script::Cyrillic => "cyrillic",
+            Script::Latin => "latin",
+            Script::Mixed => "mixed",
+            Script::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something in a document that keeps its text from being indexed as written
+#[derive(Debug, Clone, PartialEq)]
+pub enum CharsetProblem {
+    /// Byte sequences that are not UTF-8, which the parser cannot read; `declared` is the
+    /// encoding of the XML declaration, often `windows-1251`
+    InvalidUtf8 {
+        sequences: usize,
+        declared: Option<String>,
+    },
+    /// Cyrillic that was decoded with the wrong code page before being saved as UTF-8
+    Mojibake { pairs: usize },
+    /// Too much of the body is control characters, private use characters or other symbols
+    /// prose does not use
+    NonText { ratio: f64 },
+    /// The tokenizer kept few terms for the size of the file
+    FewTerms { terms: usize, per_kb: f64 },
+}
+
+impl fmt::Display for CharsetProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CharsetProblem::InvalidUtf8 {
+                sequences,
+                declared,
+            } => {
+                write!(f, "{} invalid UTF-8 sequences", sequences)?;
+                match declared {
+                    Some(encoding) => write!(f, " (declared encoding: {})", encoding),
+                    None => Ok(()),
+                }
+            }
+            CharsetProblem::Mojibake { pairs } => write!(
+                f,
+                "{} character pairs of Cyrillic decoded with the wrong code page",
+                pairs
+            ),
+            CharsetProblem::NonText { ratio } => {
+                write!(f, "{:.1}% of the body is not text", ratio * 100.0)
+            }
+            CharsetProblem::FewTerms { terms, per_kb } => write!(
+                f,
+                "only {} terms, {:.1} per KB, for the size of the file",
+                terms, per_kb
+            ),
+        }
+    }
+}
+
+/// Character counts of one document's body, for the charset report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CharsetProfile {
+    pub document: String,
+    pub bytes: u64,
+    /// Encoding named by the `<?xml?>` declaration
+    pub declared_encoding: Option<String>,
+    /// Characters of the body, markup excluded
+    pub characters: usize,
+    pub cyrillic: usize,
+    pub latin: usize,
+    pub other_letters: usize,
+    /// Characters neither letters, digits, whitespace nor punctuation, replacement characters
+    /// for invalid UTF-8 aside
+    pub non_text: usize,
+    /// Byte sequences of the whole file that are not UTF-8
+    pub invalid_sequences: usize,
+    pub mojibake_pairs: usize,
+    /// Terms the tokenizer kept
+    pub terms: usize,
+}
+
+impl CharsetProfile {
+    /// Profile the raw bytes of an FB2 file whose body the tokenizer turned into `terms` terms
+    pub fn measure(document: &str, raw: &[u8], terms: usize) -> CharsetProfile {
+        let text = String::from_utf8_lossy(raw);
+        let mut profile = CharsetProfile {
+            document: document.to_string(),
+            bytes: raw.len() as u64,
+            declared_encoding: declared_encoding(&text),
+            invalid_sequences: text.matches('\u{FFFD}').count(),
+            terms,
+            ..CharsetProfile::default()
+        };
+
+        let mut previous = ' ';
+        for ch in body_characters(&text) {
+            profile.characters += 1;
+            match ch {
+                // The Cyrillic block, letters of Ukrainian and the other alphabets included
+                '\u{400}'..='\u{4FF}' => profile.cyrillic += 1,
+                'a'..='z' | 'A'..='Z' => profile.latin += 1,
+                _ if ch.is_alphabetic() => profile.other_letters += 1,
+                // Counted with the invalid sequences instead
+                '\u{FFFD}' => {}
+                _ if !is_text(ch) => profile.non_text += 1,
+                _ => {}
+            }
+            if is_mojibake_pair(previous, ch) {
+                profile.mojibake_pairs += 1;
+            }
+            previous = ch;
+        }
+        profile
+    }
+
+    pub fn script(&self) -> Script {
+        let letters = self.cyrillic + self.latin;
+        if letters == 0 {
+            return Script::Other;
+        }
+        let minority = self.cyrillic.min(self.latin) as f64 / letters as f64;
+        if minority >= MIXED_SCRIPT_SHARE {
+            Script::Mixed
+        } else if self.cyrillic > self.latin {
+            Script::Cyrillic
+        } else {
+            Script::Latin
+        }
+    }
+
+    pub fn non_text_ratio(&self) -> f64 {
+        if self.characters == 0 {
+            return 0.0;
+        }
+        self.non_text as f64 / self.characters as f64
+    }
+
+    pub fn terms_per_kb(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
+        }
+        self.terms as f64 * 1024.0 / self.bytes as f64
+    }
+
+    /// What looks wrong with the document, nothing for a clean one
+    pub fn problems(&self, min_terms_per_kb: f64) -> Vec<CharsetProblem> {
+        let mut problems = Vec::new();
+        if self.invalid_sequences > 0 {
+            problems.push(CharsetProblem::InvalidUtf8 {
+                sequences: self.invalid_sequences,
+                declared: self.declared_encoding.clone(),
+            });
+        }
+        if self.mojibake_pairs >= MIN_MOJIBAKE_PAIRS {
+            problems.push(CharsetProblem::Mojibake {
+                pairs: self.mojibake_pairs,
+            });
+        }
+        if self.non_text_ratio() > MAX_NON_TEXT_RATIO {
+            problems.push(CharsetProblem::NonText {
+                ratio: self.non_text_ratio(),
+            });
+        }
+        if self.terms_per_kb() < min_terms_per_kb {
+            problems.push(CharsetProblem::FewTerms {
+                terms: self.terms,
+                per_kb: self.terms_per_kb(),
+            });
+        }
+        problems
+    }
+}
+
+/// The `encoding` attribute of the XML declaration, lowercased
+fn declared_encoding(text: &str) -> Option<String> {
+    let declaration = &text[..text.find("?>")?];
+    let value = declaration.split("encoding=").nth(1)?;
+    let quote = value.chars().next().filter(|&ch| ch == '"' || ch == '\'')?;
+    let value = &value[1..];
+    Some(value[..value.find(quote)?].to_lowercase())
+}
+
+/// Characters between the first `<body` and the last `</body>`, tags left out
+fn body_characters(text: &str) -> impl Iterator<Item = char> + '_ {
+    let start = text.find("<body").unwrap_or(0);
+    let end = text
+        .rfind("</body>")
+        .filter(|&end| end > start)
+        .unwrap_or(text.len());
+    let mut in_tag = false;
+    text[start..end].chars().filter(move |&ch| {
+        match ch {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                return false;
+            }
+            _ => {}
+        }
+        !in_tag
+    })
+}
+
+/// Letters, digits, whitespace and the punctuation and symbols of ordinary prose
+fn is_text(ch: char) -> bool {
+    ch.is_alphanumeric()
+        || ch.is_whitespace()
+        || ch.is_ascii_punctuation()
+        || matches!(
+            ch,
+            '\u{2010}'..='\u{205E}' | '«' | '»' | '№' | '§' | '©' | '°' | '×'
+        )
+}
+
+/// `Рџ`, `СЃ` and similar pairs come from UTF-8 Cyrillic read as Windows-1251, `Ð¿` and
+/// `Ñ\u{80}` from UTF-8 read as Latin-1
+fn is_mojibake_pair(first: char, second: char) -> bool {
+    match first {
+        'Р' | 'С' => CP1251_CONTINUATIONS.contains(second),
+        'Ð' | 'Ñ' => ('\u{80}'..='\u{BF}').contains(&second),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fb2(encoding: &str, body: &str) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"{}\"?><FictionBook><description><title-info>\
+             <book-title>Title</book-title></title-info></description><body><p>{}</p></body>\
+             </FictionBook>",
+            encoding, body
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_profiles_flag_broken_encodings() {
+        let clean =
+            CharsetProfile::measure("clean.fb2", &fb2("UTF-8", "Война и мир, том первый."), 4);
+        assert_eq!(clean.declared_encoding.as_deref(), Some("utf-8"));
+        assert_eq!(clean.script(), Script::Cyrillic);
+        assert_eq!(clean.cyrillic, 18);
+        assert_eq!(clean.non_text, 0);
+        assert!(clean.problems(DEFAULT_MIN_TERMS_PER_KB).is_empty());
+
+        let mixed = CharsetProfile::measure("mixed.fb2", &fb2("utf-8", "Война war мир peace"), 4);
+        assert_eq!(mixed.script(), Script::Mixed);
+
+        // Windows-1251 bytes of "мир" in a file read as UTF-8
+        let mut raw = fb2("windows-1251", "");
+        let body = raw.len() - "</p></body></FictionBook>".len();
+        raw.splice(body..body, [0xEC, 0xE8, 0xF0]);
+        let problems = CharsetProfile::measure("cp1251.fb2", &raw, 0).problems(0.0);
+        assert_eq!(
+            problems,
+            [CharsetProblem::InvalidUtf8 {
+                sequences: 3,
+                declared: Some("windows-1251".to_string())
+            }]
+        );
+
+        let garbled = "РїСЂРёРІРµС‚ ".repeat(3);
+        let profile = CharsetProfile::measure("garbled.fb2", &fb2("utf-8", &garbled), 6);
+        assert_eq!(profile.mojibake_pairs, 18);
+        assert!(matches!(
+            profile.problems(DEFAULT_MIN_TERMS_PER_KB)[..],
+            [CharsetProblem::Mojibake { pairs: 18 }]
+        ));
+
+        let noise = CharsetProfile::measure("noise.fb2", &fb2("utf-8", "мир\u{1}\u{2}\u{E000}"), 0);
+        let problems = noise.problems(DEFAULT_MIN_TERMS_PER_KB);
+        assert!(matches!(problems[0], CharsetProblem::NonText { .. }));
+        assert!(matches!(
+            problems[1],
+            CharsetProblem::FewTerms { terms: 0, .. }
+        ));
+        assert_eq!(noise.script(), Script::Cyrillic);
+    }
+}
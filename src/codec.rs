@@ -0,0 +1,606 @@
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::inverted_index::vb_encoding::{decode_delta_vb, encode_delta_vb, encode_vb, read_vb};
+
+/// Ids per frame-of-reference block
+const FRAME_SIZE: usize = 128;
+
+/// Compression of a posting list: sorted, deduplicated document ids to bytes and back
+pub trait Codec: Sync {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Vec<u32>;
+}
+
+/// Gaps between ids in 7-bit groups, a byte or more each
+pub struct VariableByte;
+
+/// Gaps in Elias gamma code: `n` zero bits, then the gap in `n + 1` bits. Small gaps take a
+/// few bits; a gap of 1 takes one.
+pub struct EliasGamma;
+
+/// Gaps in Rice code, Golomb code with a power-of-two divisor `2^k` picked per list from the
+/// mean gap: the quotient in unary, then the remainder in `k` bits
+pub struct GolombRice;
+
+/// Blocks of 128 ids, each its first id and the offsets of the others from it bit-packed at
+/// the width of the largest; fast to decode and compact when ids cluster
+pub struct FrameOfReference;
+
+/// Serialized roaring bitmaps
+pub struct Roaring;
+
+impl Codec for VariableByte {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8> {
+        encode_delta_vb(doc_ids.to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<u32> {
+        decode_delta_vb(bytes)
+    }
+}
+
+impl Codec for EliasGamma {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8> {
+        let mut bits = BitWriter::with_count(doc_ids.len());
+        for gap in gaps(doc_ids) {
+            let width = 64 - gap.leading_zeros();
+            bits.write(0, width - 1);
+            bits.write(gap, width);
+        }
+        bits.finish()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<u32> {
+        PostingEncoding::EliasGamma.postings(bytes).collect()
+    }
+}
+
+impl Codec for GolombRice {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8> {
+        let gaps: Vec<u64> = gaps(doc_ids).collect();
+        let mean = gaps.iter().sum::<u64>() / gaps.len().max(1) as u64;
+        let k = if mean > 1 {
+            63 - mean.leading_zeros()
+        } else {
+            0
+        };
+
+        let mut bits = BitWriter::with_count(doc_ids.len());
+        bits.write(k as u64, 6);
+        for gap in gaps {
+            let value = gap - 1;
+            for _ in 0..value >> k {
+                bits.write(1, 1);
+            }
+            bits.write(0, 1);
+            bits.write(value & ((1 << k) - 1), k);
+        }
+        bits.finish()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<u32> {
+        PostingEncoding::GolombRice.postings(bytes).collect()
+    }
+}
+
+impl Codec for FrameOfReference {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8> {
+        let mut bytes = encode_vb(doc_ids.len() as u32);
+        let mut previous = 0;
+        for frame in doc_ids.chunks(FRAME_SIZE) {
+            let first = frame[0];
+            let width = 32 - (frame[frame.len() - 1] - first).leading_zeros();
+            bytes.extend(encode_vb(first - previous));
+            bytes.push(width as u8);
+            let mut bits = BitWriter::default();
+            for &id in &frame[1..] {
+                bits.write((id - first) as u64, width);
+            }
+            bytes.extend(bits.finish());
+            previous = frame[frame.len() - 1];
+        }
+        bytes
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<u32> {
+        PostingEncoding::FrameOfReference.postings(bytes).collect()
+    }
+}
+
+impl Codec for Roaring {
+    fn encode(&self, doc_ids: &[u32]) -> Vec<u8> {
+        let bitmap: RoaringBitmap = doc_ids.iter().copied().collect();
+        let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+        bitmap
+            .serialize_into(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<u32> {
+        decode_roaring(bytes).into_iter().collect()
+    }
+}
+
+/// A corrupt roaring list reads as empty
+fn decode_roaring(bytes: &[u8]) -> RoaringBitmap {
+    RoaringBitmap::deserialize_from(bytes).unwrap_or_default()
+}
+
+/// How `CompressedInvertedIndex` stores its posting lists, chosen at build time and saved
+/// with the index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PostingEncoding {
+    /// Sorted document ids, VB-encoded as deltas
+    #[default]
+    VbDelta,
+    /// Serialized roaring bitmaps; larger than VB deltas unless terms cover a large share of
+    /// tens of thousands of documents, but Boolean queries AND, OR and NOT the bitmaps
+    /// without decoding them to ids
+    Roaring,
+    EliasGamma,
+    GolombRice,
+    FrameOfReference,
+}
+
+impl PostingEncoding {
+    pub const ALL: [PostingEncoding; 5] = [
+        PostingEncoding::VbDelta,
+        PostingEncoding::EliasGamma,
+        PostingEncoding::GolombRice,
+        PostingEncoding::FrameOfReference,
+        PostingEncoding::Roaring,
+    ];
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            PostingEncoding::VbDelta => &VariableByte,
+            PostingEncoding::Roaring => &Roaring,
+            PostingEncoding::EliasGamma => &EliasGamma,
+            PostingEncoding::GolombRice => &GolombRice,
+            PostingEncoding::FrameOfReference => &FrameOfReference,
+        }
+    }
+
+    /// Encode sorted, deduplicated document ids
+    pub fn encode(self, doc_ids: &[u32]) -> Vec<u8> {
+        self.codec().encode(doc_ids)
+    }
+
+    /// Sorted document ids of an encoded posting list
+    pub fn decode(self, bytes: &[u8]) -> Vec<u32> {
+        self.codec().decode(bytes)
+    }
+
+    /// Sorted document ids of an encoded posting list, decoded as they are read
+    pub fn postings(self, bytes: &[u8]) -> PostingIterator<'_> {
+        PostingIterator::new(self, bytes)
+    }
+
+    /// An encoded posting list as a bitmap
+    pub fn decode_bitmap(self, bytes: &[u8]) -> RoaringBitmap {
+        match self {
+            PostingEncoding::Roaring => decode_roaring(bytes),
+            _ => self.decode(bytes).into_iter().collect(),
+        }
+    }
+}
+
+impl FromStr for PostingEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "vb" | "vb-delta" => Ok(PostingEncoding::VbDelta),
+            "roaring" => Ok(PostingEncoding::Roaring),
+            "gamma" | "elias-gamma" => Ok(PostingEncoding::EliasGamma),
+            "rice" | "golomb-rice" => Ok(PostingEncoding::GolombRice),
+            "for" | "frame-of-reference" => Ok(PostingEncoding::FrameOfReference),
+            _ => Err(format!(
+                "Unknown posting codec: {} (expected vb-delta, gamma, rice, for or roaring)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PostingEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PostingEncoding::VbDelta => "vb-delta",
+            PostingEncoding::Roaring => "roaring",
+            PostingEncoding::EliasGamma => "gamma",
+            PostingEncoding::GolombRice => "rice",
+            PostingEncoding::FrameOfReference => "for",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Document ids of an encoded posting list, decoded one at a time so a scan that stops early
+/// or skips ahead with `advance_to` leaves the rest of the list undecoded. Frame-of-reference
+/// lists skip whole frames without unpacking them; roaring lists are deserialized up front.
+pub struct PostingIterator<'a> {
+    cursor: Cursor<'a>,
+}
+
+enum Cursor<'a> {
+    VariableByte {
+        bytes: &'a [u8],
+        pos: usize,
+        previous: u32,
+    },
+    EliasGamma {
+        bits: BitReader<'a>,
+        remaining: usize,
+        previous: i64,
+    },
+    GolombRice {
+        bits: BitReader<'a>,
+        k: u32,
+        remaining: usize,
+        previous: i64,
+    },
+    FrameOfReference(Frames<'a>),
+    Roaring(roaring::bitmap::IntoIter),
+}
+
+/// Position in a frame-of-reference list
+struct Frames<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Ids of the frames not started yet
+    remaining: usize,
+    first: u32,
+    /// Last id of the current frame, the base of the next
+    last: u32,
+    width: u32,
+    offsets: BitReader<'a>,
+    /// Ids of the current frame not yielded yet
+    left: usize,
+    /// The first id of the current frame until it is yielded
+    pending_first: Option<u32>,
+}
+
+impl<'a> PostingIterator<'a> {
+    pub fn new(encoding: PostingEncoding, bytes: &'a [u8]) -> Self {
+        let cursor = match encoding {
+            PostingEncoding::VbDelta => Cursor::VariableByte {
+                bytes,
+                pos: 0,
+                previous: 0,
+            },
+            PostingEncoding::EliasGamma => {
+                let (remaining, bits) = BitReader::with_count(bytes);
+                Cursor::EliasGamma {
+                    bits,
+                    remaining,
+                    previous: -1,
+                }
+            }
+            PostingEncoding::GolombRice => {
+                let (remaining, mut bits) = BitReader::with_count(bytes);
+                match bits.read(6) {
+                    Some(k) => Cursor::GolombRice {
+                        bits,
+                        k: k as u32,
+                        remaining,
+                        previous: -1,
+                    },
+                    None => Cursor::GolombRice {
+                        bits,
+                        k: 0,
+                        remaining: 0,
+                        previous: -1,
+                    },
+                }
+            }
+            PostingEncoding::FrameOfReference => Cursor::FrameOfReference(Frames::new(bytes)),
+            PostingEncoding::Roaring => Cursor::Roaring(decode_roaring(bytes).into_iter()),
+        };
+        PostingIterator { cursor }
+    }
+
+    /// The first remaining id not below `target`, consumed as `next` would, passing over the
+    /// ids before it
+    pub fn advance_to(&mut self, target: u32) -> Option<u32> {
+        match &mut self.cursor {
+            Cursor::FrameOfReference(frames) => frames.skip_frames_below(target),
+            Cursor::Roaring(ids) => ids.advance_to(target),
+            _ => {}
+        }
+        self.find(|&id| id >= target)
+    }
+}
+
+impl Iterator for PostingIterator<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match &mut self.cursor {
+            Cursor::VariableByte {
+                bytes,
+                pos,
+                previous,
+            } => {
+                if *pos >= bytes.len() {
+                    return None;
+                }
+                *previous += read_vb(bytes, pos);
+                Some(*previous)
+            }
+            Cursor::EliasGamma {
+                bits,
+                remaining,
+                previous,
+            } => {
+                if *remaining == 0 {
+                    return None;
+                }
+                let mut zeros = 0;
+                while bits.read(1) == Some(0) {
+                    zeros += 1;
+                }
+                next_gap(
+                    bits.read(zeros).map(|rest| (1 << zeros) | rest),
+                    remaining,
+                    previous,
+                )
+            }
+            Cursor::GolombRice {
+                bits,
+                k,
+                remaining,
+                previous,
+            } => {
+                if *remaining == 0 {
+                    return None;
+                }
+                let mut quotient = 0;
+                while bits.read(1) == Some(1) {
+                    quotient += 1;
+                }
+                let gap = bits
+                    .read(*k)
+                    .map(|remainder| (quotient << *k | remainder) + 1);
+                next_gap(gap, remaining, previous)
+            }
+            Cursor::FrameOfReference(frames) => frames.next(),
+            Cursor::Roaring(ids) => ids.next(),
+        }
+    }
+}
+
+/// Add a decoded gap to `previous`; a list cut short ends the iteration
+fn next_gap(gap: Option<u64>, remaining: &mut usize, previous: &mut i64) -> Option<u32> {
+    match gap {
+        Some(gap) => {
+            *remaining -= 1;
+            *previous += gap as i64;
+            Some(*previous as u32)
+        }
+        None => {
+            *remaining = 0;
+            None
+        }
+    }
+}
+
+impl<'a> Frames<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        let mut pos = 0;
+        let remaining = if bytes.is_empty() {
+            0
+        } else {
+            read_vb(bytes, &mut pos) as usize
+        };
+        Frames {
+            bytes,
+            pos,
+            remaining,
+            first: 0,
+            last: 0,
+            width: 0,
+            offsets: BitReader::new(&[]),
+            left: 0,
+            pending_first: None,
+        }
+    }
+
+    /// Read the header of the next frame; the last id comes straight from its packed offsets
+    fn start_frame(&mut self) -> bool {
+        if self.remaining == 0 || self.pos >= self.bytes.len() {
+            return false;
+        }
+        let frame = self.remaining.min(FRAME_SIZE);
+        let first = self.last + read_vb(self.bytes, &mut self.pos);
+        let Some(&width) = self.bytes.get(self.pos) else {
+            return false;
+        };
+        let width = width as u32;
+        let packed_len = ((frame - 1) * width as usize).div_ceil(8);
+        let end = self.bytes.len();
+        let packed = &self.bytes[(self.pos + 1).min(end)..(self.pos + 1 + packed_len).min(end)];
+        self.pos += 1 + packed_len;
+
+        self.first = first;
+        self.last = if frame > 1 {
+            let mut last = BitReader::new(packed);
+            last.position = (frame - 2) * width as usize;
+            last.read(width)
+                .map_or(first, |offset| first + offset as u32)
+        } else {
+            first
+        };
+        self.width = width;
+        self.offsets = BitReader::new(packed);
+        self.remaining -= frame;
+        self.left = frame;
+        self.pending_first = Some(first);
+        true
+    }
+
+    /// Pass over whole frames that end below `target` without unpacking their offsets
+    fn skip_frames_below(&mut self, target: u32) {
+        while self.left == 0 || self.last < target {
+            self.left = 0;
+            if !self.start_frame() {
+                return;
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            if self.left == 0 && !self.start_frame() {
+                return None;
+            }
+            self.left -= 1;
+            if let Some(first) = self.pending_first.take() {
+                return Some(first);
+            }
+            match self.offsets.read(self.width) {
+                Some(offset) => return Some(self.first + offset as u32),
+                None => self.left = 0,
+            }
+        }
+    }
+}
+
+/// Gaps between sorted ids, the first counted from -1 so every gap is at least 1
+fn gaps(doc_ids: &[u32]) -> impl Iterator<Item = u64> + '_ {
+    let mut previous = -1i64;
+    doc_ids.iter().map(move |&id| {
+        let gap = (id as i64 - previous) as u64;
+        previous = id as i64;
+        gap
+    })
+}
+
+/// Bits written most significant first, after a VB-encoded count when there is one
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte, 0 when it is full or there is none
+    used: u32,
+}
+
+impl BitWriter {
+    fn with_count(count: usize) -> Self {
+        BitWriter {
+            bytes: encode_vb(count as u32),
+            used: 0,
+        }
+    }
+
+    /// The low `width` bits of `value`
+    fn write(&mut self, value: u64, width: u32) {
+        for i in (0..width).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    /// The count written by `BitWriter::with_count` and a reader of the bits after it
+    fn with_count(bytes: &'a [u8]) -> (usize, Self) {
+        if bytes.is_empty() {
+            return (0, BitReader::new(bytes));
+        }
+        let mut pos = 0;
+        let count = read_vb(bytes, &mut pos) as usize;
+        (count, BitReader::new(&bytes[pos.min(bytes.len())..]))
+    }
+
+    /// The next `width` bits, or `None` past the end
+    fn read(&mut self, width: u32) -> Option<u64> {
+        if self.position + width as usize > self.bytes.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..width {
+            let byte = self.bytes[self.position / 8];
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = value << 1 | bit as u64;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_codec_round_trips() {
+        let lists: Vec<Vec<u32>> = vec![
+            Vec::new(),
+            vec![0],
+            vec![7],
+            (0..300).collect(),
+            (0..1000).map(|i| i * i).collect(),
+            vec![1, 2, 3, 1_000_000, 1_000_001, u32::MAX - 1],
+        ];
+        for encoding in PostingEncoding::ALL {
+            for doc_ids in &lists {
+                let bytes = encoding.encode(doc_ids);
+                assert_eq!(&encoding.decode(&bytes), doc_ids, "{}", encoding);
+                assert_eq!(encoding.decode_bitmap(&bytes).len(), doc_ids.len() as u64);
+            }
+            assert_eq!(encoding.to_string().parse(), Ok(encoding));
+        }
+
+        // Dense lists: one bit per gap in gamma code, a byte in VB
+        let dense: Vec<u32> = (0..800).collect();
+        assert_eq!(PostingEncoding::EliasGamma.encode(&dense).len(), 2 + 100);
+        assert!(
+            PostingEncoding::FrameOfReference.encode(&dense).len()
+                < PostingEncoding::VbDelta.encode(&dense).len()
+        );
+        let spread: Vec<u32> = (0..500).map(|i| i * 1000 + i % 7).collect();
+        assert!(
+            PostingEncoding::GolombRice.encode(&spread).len()
+                < PostingEncoding::VbDelta.encode(&spread).len()
+        );
+    }
+
+    #[test]
+    fn test_posting_iterator_skips_ahead() {
+        let doc_ids: Vec<u32> = (0..1000).map(|i| i * 3).collect();
+        for encoding in PostingEncoding::ALL {
+            let bytes = encoding.encode(&doc_ids);
+            let mut postings = encoding.postings(&bytes);
+            assert_eq!(postings.next(), Some(0), "{}", encoding);
+            assert_eq!(postings.advance_to(10), Some(12), "{}", encoding);
+            assert_eq!(postings.advance_to(12), Some(15), "{}", encoding);
+            // Past several frames of frame-of-reference lists at once
+            assert_eq!(postings.advance_to(1500), Some(1500), "{}", encoding);
+            assert_eq!(postings.next(), Some(1503), "{}", encoding);
+            assert_eq!(postings.advance_to(2997), Some(2997), "{}", encoding);
+            assert_eq!(postings.advance_to(2998), None, "{}", encoding);
+            assert_eq!(encoding.postings(&encoding.encode(&[])).next(), None);
+        }
+    }
+}
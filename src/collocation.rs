@@ -0,0 +1,155 @@
+use rayon::prelude::*;
+use std::str::FromStr;
+
+use crate::{CompressedDictionary, NGramPhraseIndex};
+
+/// Association measure used to score word pairs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssociationMeasure {
+    /// Pointwise mutual information: log2(P(a b) / (P(a) P(b)))
+    Pmi,
+    /// Dunning's log-likelihood ratio (G²) over the 2x2 contingency table
+    LogLikelihood,
+}
+
+impl FromStr for AssociationMeasure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pmi" => Ok(AssociationMeasure::Pmi),
+            "llr" | "g2" => Ok(AssociationMeasure::LogLikelihood),
+            _ => Err(format!("Unknown association measure: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Collocation {
+    pub first: String,
+    pub second: String,
+    pub frequency: u32,
+    pub score: f64,
+}
+
+/// Score every bigram with at least `min_frequency` occurrences and return the `top` best pairs
+pub fn extract_collocations(
+    phrase_index: &NGramPhraseIndex,
+    dictionary: &CompressedDictionary,
+    measure: AssociationMeasure,
+    top: usize,
+    min_frequency: u32,
+) -> Vec<Collocation> {
+    let total = dictionary.total_words as f64;
+    if total == 0.0 {
+        return Vec::new();
+    }
+
+    let frequent: Vec<(String, u32)> = phrase_index
+        .pair_frequencies()
+        .into_iter()
+        .filter(|&(_, frequency)| frequency >= min_frequency)
+        .collect();
+    let mut collocations: Vec<Collocation> = frequent
+        .par_iter()
+        .filter_map(|&(ref bigram, frequency)| {
+            let (first, second) = bigram.split_once(' ')?;
+            let first_frequency = dictionary.get_term_entry(first)?.frequency as f64;
+            let second_frequency = dictionary.get_term_entry(second)?.frequency as f64;
+
+            let score = match measure {
+                AssociationMeasure::Pmi => {
+                    pmi(frequency as f64, first_frequency, second_frequency, total)
+                }
+                AssociationMeasure::LogLikelihood => {
+                    log_likelihood(frequency as f64, first_frequency, second_frequency, total)
+                }
+            };
+
+            Some(Collocation {
+                first: first.to_string(),
+                second: second.to_string(),
+                frequency,
+                score,
+            })
+        })
+        .collect();
+
+    collocations.par_sort_unstable_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| (&a.first, &a.second).cmp(&(&b.first, &b.second)))
+    });
+    collocations.truncate(top);
+    collocations
+}
+
+fn pmi(pair: f64, first: f64, second: f64, total: f64) -> f64 {
+    ((pair * total) / (first * second)).log2()
+}
+
+fn log_likelihood(pair: f64, first: f64, second: f64, total: f64) -> f64 {
+    // Observed contingency table: rows = first word present/absent, columns = second word
+    let k11 = pair;
+    let k12 = (first - pair).max(0.0);
+    let k21 = (second - pair).max(0.0);
+    let k22 = (total - first - second + pair).max(0.0);
+
+    let row1 = k11 + k12;
+    let row2 = k21 + k22;
+    let col1 = k11 + k21;
+    let col2 = k12 + k22;
+
+    let term = |observed: f64, row: f64, col: f64| {
+        if observed > 0.0 && row > 0.0 && col > 0.0 {
+            observed * (observed * total / (row * col)).ln()
+        } else {
+            0.0
+        }
+    };
+
+    2.0 * (term(k11, row1, col1) + term(k12, row1, col2) + term(k21, row2, col1)
+        + term(k22, row2, col2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::Dictionary;
+
+    fn create_test_structures() -> (NGramPhraseIndex, CompressedDictionary) {
+        let text = "new york is big new york never sleeps the city is big the big end";
+        let words: Vec<String> = text.split_whitespace().map(|w| w.to_string()).collect();
+
+        let mut dict = Dictionary::new();
+        for word in &words {
+            dict.add_term(word.clone(), "doc1".to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let bigrams =
+            NGramPhraseIndex::from_dictionary_with_parser(&compressed, |_| Ok(words.clone()))
+                .unwrap();
+
+        (bigrams, compressed)
+    }
+
+    #[test]
+    fn test_measure_parsing() {
+        assert_eq!("pmi".parse(), Ok(AssociationMeasure::Pmi));
+        assert_eq!("LLR".parse(), Ok(AssociationMeasure::LogLikelihood));
+        assert!("chi2".parse::<AssociationMeasure>().is_err());
+    }
+
+    #[test]
+    fn test_strong_pair_ranks_first() {
+        let (bigrams, dictionary) = create_test_structures();
+
+        for measure in [AssociationMeasure::Pmi, AssociationMeasure::LogLikelihood] {
+            let collocations = extract_collocations(&bigrams, &dictionary, measure, 3, 2);
+            assert!(!collocations.is_empty());
+            assert_eq!(collocations[0].first, "new");
+            assert_eq!(collocations[0].second, "york");
+            assert_eq!(collocations[0].frequency, 2);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use crate::coordinate_index::{near_postings, phrase_words};
+use crate::dictionary::prefix_range;
+use crate::explain::TracedEvaluator;
+use crate::inverted_index::vb_encoding::{decode_delta_vb, encode_delta_vb};
+use crate::query::{Capabilities, QueryAst, QueryEvaluator, QueryParser};
+use crate::{is_stem_pattern, CoordinateIndex, PositionPostings};
+
+/// The positions of a term in one document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressedPositions {
+    /// Index of the document in `CompressedCoordinateIndex::documents`
+    pub doc_id: u32,
+    /// Word positions, delta and VB encoded
+    pub positions: Vec<u8>,
+}
+
+impl CompressedPositions {
+    pub fn decode(&self) -> Vec<usize> {
+        decode_delta_vb(&self.positions)
+            .into_iter()
+            .map(|position| position as usize)
+            .collect()
+    }
+}
+
+/// A `CoordinateIndex` with every (term, document) position list delta and VB encoded and
+/// documents referred to by id. Phrase and proximity queries decode the positions of a
+/// document only once every word before it matched there, so most lists of a selective
+/// phrase stay encoded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompressedCoordinateIndex {
+    /// Term -> positions per document, sorted by document id
+    pub index: HashMap<String, Vec<CompressedPositions>>,
+    /// Sorted document names; ids follow their order
+    pub documents: Vec<String>,
+    /// Bytes of encoded positions
+    pub compressed_size: usize,
+    /// Bytes the positions took as `usize`s
+    pub uncompressed_size: usize,
+    /// Sorted indexed terms for prefix lookups, built on first use
+    #[serde(skip)]
+    sorted_terms: OnceLock<Vec<String>>,
+}
+
+impl CompressedCoordinateIndex {
+    pub fn from_coordinate_index(coordinate_index: &CoordinateIndex) -> Self {
+        let mut documents = coordinate_index.documents.clone();
+        documents.sort_unstable();
+        documents.dedup();
+        let doc_ids: HashMap<&str, u32> = documents
+            .iter()
+            .enumerate()
+            .map(|(id, document)| (document.as_str(), id as u32))
+            .collect();
+
+        let index: HashMap<String, Vec<CompressedPositions>> = coordinate_index
+            .index
+            .par_iter()
+            .map(|(term, postings)| {
+                let mut compressed: Vec<CompressedPositions> = postings
+                    .iter()
+                    .filter_map(|posting| {
+                        let positions = posting.positions.iter().map(|&p| p as u32).collect();
+                        Some(CompressedPositions {
+                            doc_id: *doc_ids.get(posting.document.as_str())?,
+                            positions: encode_delta_vb(positions),
+                        })
+                    })
+                    .collect();
+                compressed.sort_unstable_by_key(|entry| entry.doc_id);
+                (term.clone(), compressed)
+            })
+            .collect();
+
+        let uncompressed_size = coordinate_index
+            .index
+            .values()
+            .flatten()
+            .map(|posting| posting.positions.len() * std::mem::size_of::<usize>())
+            .sum();
+        let compressed_size = index
+            .values()
+            .flatten()
+            .map(|entry| entry.positions.len())
+            .sum();
+        CompressedCoordinateIndex {
+            index,
+            documents,
+            compressed_size,
+            uncompressed_size,
+            sorted_terms: OnceLock::new(),
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .index
+                .iter()
+                .map(|(term, postings)| {
+                    term.len()
+                        + std::mem::size_of::<Vec<CompressedPositions>>()
+                        + postings
+                            .iter()
+                            .map(|entry| {
+                                std::mem::size_of::<CompressedPositions>() + entry.positions.len()
+                            })
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+            + self.documents.iter().map(|d| d.len()).sum::<usize>()
+    }
+
+    /// Compressed size relative to the uncompressed positions, in percent
+    pub fn compression_ratio(&self) -> f64 {
+        if self.uncompressed_size == 0 {
+            return 0.0;
+        }
+        self.compressed_size as f64 / self.uncompressed_size as f64 * 100.0
+    }
+
+    /// Indexed terms starting with `prefix`
+    pub fn terms_with_prefix(&self, prefix: &str) -> &[String] {
+        let sorted_terms = self.sorted_terms.get_or_init(|| {
+            let mut terms: Vec<String> = self.index.keys().cloned().collect();
+            terms.sort_unstable();
+            terms
+        });
+        prefix_range(sorted_terms, prefix)
+    }
+
+    /// Positions of a document in any of the posting lists, decoded and sorted
+    fn positions_in(alternatives: &[&Vec<CompressedPositions>], doc_id: u32) -> Vec<usize> {
+        let mut positions: Vec<usize> = alternatives
+            .iter()
+            .filter_map(|postings| {
+                postings
+                    .binary_search_by_key(&doc_id, |entry| entry.doc_id)
+                    .ok()
+                    .map(|idx| postings[idx].decode())
+            })
+            .flatten()
+            .collect();
+        if alternatives.len() > 1 {
+            positions.sort_unstable();
+        }
+        positions
+    }
+
+    /// Documents containing the words consecutively, see `CoordinateIndex::search_phrase`
+    pub fn search_phrase(&self, phrase: &str) -> Result<HashSet<String>, String> {
+        let words = phrase_words(phrase);
+        if words.len() == 1 && !is_stem_pattern(&words[0]) {
+            return self.search_term(&words[0]);
+        }
+        Ok(self.phrase_word_postings(&words).documents())
+    }
+
+    /// Every match of a phrase, see `CoordinateIndex::phrase_postings`
+    pub fn phrase_postings(&self, phrase: &str) -> PositionPostings {
+        self.phrase_word_postings(&phrase_words(phrase))
+    }
+
+    fn phrase_word_postings(&self, words: &[String]) -> PositionPostings {
+        if words.is_empty() {
+            return PositionPostings::new();
+        }
+        let last = words.len() - 1;
+        let alternatives: Vec<Vec<&Vec<CompressedPositions>>> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| {
+                if i == last && is_stem_pattern(word) {
+                    self.terms_with_prefix(word.trim_end_matches('*'))
+                        .iter()
+                        .filter_map(|term| self.index.get(term))
+                        .collect()
+                } else {
+                    self.index.get(word).into_iter().collect()
+                }
+            })
+            .collect();
+        if alternatives.iter().any(|postings| postings.is_empty()) {
+            return PositionPostings::new();
+        }
+
+        let candidates: HashSet<u32> = alternatives[0]
+            .iter()
+            .flat_map(|postings| postings.iter().map(|entry| entry.doc_id))
+            .collect();
+        let mut result = Vec::new();
+        for doc_id in candidates {
+            let mut current_positions = Self::positions_in(&alternatives[0], doc_id);
+            for (word_offset, word_alternatives) in alternatives.iter().enumerate().skip(1) {
+                let word_positions = Self::positions_in(word_alternatives, doc_id);
+                current_positions
+                    .retain(|&pos| word_positions.binary_search(&(pos + word_offset)).is_ok());
+                if current_positions.is_empty() {
+                    break;
+                }
+            }
+            let spans = current_positions
+                .into_iter()
+                .map(|start| (start, start + last))
+                .collect();
+            result.push((self.documents[doc_id as usize].clone(), spans));
+        }
+        PositionPostings::from_spans(result)
+    }
+
+    /// Every occurrence of `term`; empty for unknown terms
+    pub fn term_postings(&self, term: &str) -> PositionPostings {
+        PositionPostings::from_positions(self.index.get(term).into_iter().flatten().map(|entry| {
+            (
+                self.documents[entry.doc_id as usize].clone(),
+                entry.decode(),
+            )
+        }))
+    }
+
+    /// Matches of a near operand with their positions, see `CoordinateIndex`
+    fn positional_postings(&self, query: &QueryAst) -> Result<PositionPostings, String> {
+        match query {
+            QueryAst::Term(term) => Ok(self.term_postings(term)),
+            QueryAst::Phrase(words) => Ok(self.phrase_postings(&words.join(" "))),
+            QueryAst::Near { distance, operands } => {
+                self.near_operand_postings(*distance, operands)
+            }
+            QueryAst::Wildcard(pattern) => Err(format!(
+                "Wildcard patterns are not supported here: '{}'",
+                pattern
+            )),
+            QueryAst::And(left, right) => Ok(self
+                .positional_postings(left)?
+                .intersect(&self.positional_postings(right)?)),
+            QueryAst::Or(left, right) => Ok(self
+                .positional_postings(left)?
+                .union(&self.positional_postings(right)?)),
+            QueryAst::Not(_) => Err("NOT cannot be used inside a near operand".to_string()),
+        }
+    }
+
+    fn near_operand_postings(
+        &self,
+        distance: usize,
+        operands: &[QueryAst],
+    ) -> Result<PositionPostings, String> {
+        let operands = operands
+            .iter()
+            .map(|operand| self.positional_postings(operand))
+            .collect::<Result<_, _>>()?;
+        Ok(near_postings(operands, distance))
+    }
+}
+
+impl QueryParser for CompressedCoordinateIndex {
+    type Result = HashSet<String>;
+    type Error = String;
+    const CAPABILITIES: Capabilities = CoordinateIndex::CAPABILITIES;
+
+    fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
+        self.evaluate(&QueryAst::parse(query)?)
+    }
+
+    /// Documents of the term; their positions stay encoded
+    fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
+        match self.index.get(term) {
+            Some(postings) => Ok(postings
+                .iter()
+                .map(|entry| self.documents[entry.doc_id as usize].clone())
+                .collect()),
+            None => Err(format!("Term '{}' not found", term)),
+        }
+    }
+}
+
+impl QueryEvaluator for CompressedCoordinateIndex {
+    type Output = HashSet<String>;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.search_term(term)
+    }
+
+    fn evaluate_phrase(&self, words: &[String]) -> Result<Self::Output, String> {
+        self.search_phrase(&words.join(" "))
+    }
+
+    fn evaluate_near(
+        &self,
+        distance: usize,
+        operands: &[QueryAst],
+    ) -> Result<Self::Output, String> {
+        Ok(self.near_operand_postings(distance, operands)?.documents())
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left.intersection(&right).cloned().collect()
+    }
+
+    fn unite(&self, mut left: Self::Output, right: Self::Output) -> Self::Output {
+        left.extend(right);
+        left
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.documents
+            .iter()
+            .filter(|document| !operand.contains(*document))
+            .cloned()
+            .collect()
+    }
+}
+
+impl TracedEvaluator for CompressedCoordinateIndex {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len()
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.index.get(term).map_or(0, Vec::len)
+    }
+
+    fn term_method(&self) -> String {
+        "read the documents of the compressed positional posting list".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{is_indexable_word, CompressedDictionary, Dictionary};
+
+    #[test]
+    fn test_compressed_positions_answer_like_the_coordinate_index() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("анна".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let coordinate_index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "анна каренина вышла на станция анна каренина",
+                "doc2" => "анна каренина долго ждала поезд",
+                _ => "каренина анна поезд",
+            };
+            Ok(text
+                .split_whitespace()
+                .filter(|w| is_indexable_word(w))
+                .map(|w| w.to_string())
+                .collect())
+        })
+        .unwrap();
+        let index = CompressedCoordinateIndex::from_coordinate_index(&coordinate_index);
+        assert!(index.compressed_size < index.uncompressed_size);
+        assert_eq!(
+            index.term_postings("анна"),
+            coordinate_index.term_postings("анна")
+        );
+
+        for query in [
+            "\"анна каренина\"",
+            "\"анна кар*\"",
+            "near/3((\"анна каренина\") (поезд OR станция))",
+            "near/1(near/1(анна каренина) поезд)",
+            "анна and not \"каренина анна\"",
+            "станция or поезд",
+        ] {
+            assert_eq!(
+                index.search(query).unwrap(),
+                coordinate_index.search(query).unwrap(),
+                "{}",
+                query
+            );
+        }
+        assert!(index.search("near/2(анна (not поезд))").is_err());
+    }
+}
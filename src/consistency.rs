@@ -0,0 +1,93 @@
+use std::collections::{BTreeSet, HashSet};
+
+use crate::IndexKind;
+
+/// Documents one structure returned for a query, or the error it reported
+#[derive(Debug, Clone)]
+pub struct StructureResult {
+    pub kind: IndexKind,
+    pub documents: Result<HashSet<String>, String>,
+}
+
+/// A document returned by some structures but not by others
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub document: String,
+    pub found_by: Vec<IndexKind>,
+    /// Structures that missed the document, each with the reason given by `explain`
+    pub missed_by: Vec<(IndexKind, String)>,
+}
+
+/// Diff the result sets of structures that answered the same query. Structures that failed are
+/// left out, since their errors say more than a list of every document they missed. `explain`
+/// gives the reason a structure missed a document.
+pub fn compare_results<F>(results: &[StructureResult], mut explain: F) -> Vec<Discrepancy>
+where
+    F: FnMut(IndexKind, &str) -> String,
+{
+    let answered: Vec<(IndexKind, &HashSet<String>)> = results
+        .iter()
+        .filter_map(|result| Some((result.kind, result.documents.as_ref().ok()?)))
+        .collect();
+    let all_documents: BTreeSet<&String> = answered
+        .iter()
+        .flat_map(|(_, documents)| documents.iter())
+        .collect();
+
+    let mut discrepancies = Vec::new();
+    for document in all_documents {
+        let (found, missed): (Vec<_>, Vec<_>) = answered
+            .iter()
+            .partition(|(_, documents)| documents.contains(document));
+        if missed.is_empty() {
+            continue;
+        }
+        discrepancies.push(Discrepancy {
+            document: document.clone(),
+            found_by: found.iter().map(|(kind, _)| *kind).collect(),
+            missed_by: missed
+                .iter()
+                .map(|(kind, _)| (*kind, explain(*kind, document)))
+                .collect(),
+        });
+    }
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_disagreements_are_reported() {
+        let set = |documents: &[&str]| -> HashSet<String> {
+            documents.iter().map(|d| d.to_string()).collect()
+        };
+        let results = vec![
+            StructureResult {
+                kind: IndexKind::Matrix,
+                documents: Ok(set(&["a.fb2"])),
+            },
+            StructureResult {
+                kind: IndexKind::Inverted,
+                documents: Ok(set(&["a.fb2", "b.fb2"])),
+            },
+            StructureResult {
+                kind: IndexKind::Bigram,
+                documents: Err("Phrase must contain at least two words".to_string()),
+            },
+        ];
+
+        let discrepancies = compare_results(&results, |kind, document| {
+            format!("{} pruned {}", kind, document)
+        });
+        assert_eq!(
+            discrepancies,
+            vec![Discrepancy {
+                document: "b.fb2".to_string(),
+                found_by: vec![IndexKind::Inverted],
+                missed_by: vec![(IndexKind::Matrix, "matrix pruned b.fb2".to_string())],
+            }]
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use arrow::array::{ArrayRef, StringArray, UInt32Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+use crate::{CoordinateIndex, TermInterner};
+
+/// Windowed term co-occurrence counts for the most frequent terms of a collection
+#[derive(Debug, Clone)]
+pub struct CooccurrenceMatrix {
+    /// Terms included in the matrix, ordered by descending collection frequency
+    pub terms: Vec<String>,
+    /// Maximum distance (in positions) between two co-occurring terms
+    pub window: usize,
+    /// Symmetric pair counts keyed by (smaller term index, larger term index)
+    pub counts: HashMap<(u32, u32), u64>,
+    /// Persistent id of each term in `terms`, exported next to the term so pairs from different
+    /// runs can be joined on ids
+    pub term_ids: Option<Vec<u32>>,
+}
+
+impl CooccurrenceMatrix {
+    /// Count co-occurrences of the `top_n` most frequent terms within `window` positions
+    pub fn from_coordinate_index(index: &CoordinateIndex, top_n: usize, window: usize) -> Self {
+        println!(
+            "  CooccurrenceMatrix: Selecting top {} terms out of {}",
+            top_n,
+            index.index.len()
+        );
+
+        let mut term_frequencies: Vec<(&String, usize)> = index
+            .index
+            .iter()
+            .map(|(term, postings)| {
+                let frequency = postings.iter().map(|p| p.positions.len()).sum::<usize>();
+                (term, frequency)
+            })
+            .collect();
+        term_frequencies.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        term_frequencies.truncate(top_n);
+
+        let terms: Vec<String> = term_frequencies
+            .into_iter()
+            .map(|(term, _)| term.clone())
+            .collect();
+
+        // Gather (position, term index) occurrences per document for the selected terms only
+        let mut occurrences: HashMap<&str, Vec<(usize, u32)>> = HashMap::new();
+        for (term_idx, term) in terms.iter().enumerate() {
+            for posting in &index.index[term] {
+                let doc_occurrences = occurrences.entry(posting.document.as_str()).or_default();
+                for &position in &posting.positions {
+                    doc_occurrences.push((position, term_idx as u32));
+                }
+            }
+        }
+
+        println!(
+            "  CooccurrenceMatrix: Counting pairs in {} documents (window {})",
+            occurrences.len(),
+            window
+        );
+
+        let counts = occurrences
+            .into_par_iter()
+            .map(|(_, mut doc_occurrences)| {
+                doc_occurrences.sort_unstable();
+                let mut local_counts: HashMap<(u32, u32), u64> = HashMap::new();
+
+                for (i, &(position, term)) in doc_occurrences.iter().enumerate() {
+                    for &(other_position, other_term) in &doc_occurrences[i + 1..] {
+                        if other_position - position > window {
+                            break;
+                        }
+                        if other_term != term {
+                            let key = (term.min(other_term), term.max(other_term));
+                            *local_counts.entry(key).or_insert(0) += 1;
+                        }
+                    }
+                }
+
+                local_counts
+            })
+            .reduce(HashMap::new, |mut acc, local_counts| {
+                for (key, count) in local_counts {
+                    *acc.entry(key).or_insert(0) += count;
+                }
+                acc
+            });
+
+        println!(
+            "  CooccurrenceMatrix: Complete - {} non-zero pairs",
+            counts.len()
+        );
+
+        CooccurrenceMatrix {
+            terms,
+            window,
+            counts,
+            term_ids: None,
+        }
+    }
+
+    /// Take term ids from `interner`. Returns false, leaving ids unset, if any term has none.
+    pub fn assign_term_ids(&mut self, interner: &TermInterner) -> bool {
+        self.term_ids = self.terms.iter().map(|term| interner.get(term)).collect();
+        self.term_ids.is_some()
+    }
+
+    /// Persistent id of every term, if ids were assigned
+    fn id_map(&self) -> Option<HashMap<&str, u32>> {
+        let ids = self.term_ids.as_ref()?;
+        Some(
+            self.terms
+                .iter()
+                .map(String::as_str)
+                .zip(ids.iter().copied())
+                .collect(),
+        )
+    }
+
+    /// Co-occurrence count for a pair of terms (order does not matter)
+    pub fn count(&self, first: &str, second: &str) -> u64 {
+        let first_idx = self.terms.iter().position(|t| t == first);
+        let second_idx = self.terms.iter().position(|t| t == second);
+
+        match (first_idx, second_idx) {
+            (Some(a), Some(b)) => {
+                let key = (a.min(b) as u32, a.max(b) as u32);
+                self.counts.get(&key).copied().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Non-zero pairs sorted by descending count
+    pub fn sorted_pairs(&self) -> Vec<(&str, &str, u64)> {
+        let mut pairs: Vec<(&str, &str, u64)> = self
+            .counts
+            .iter()
+            .map(|(&(a, b), &count)| {
+                (
+                    self.terms[a as usize].as_str(),
+                    self.terms[b as usize].as_str(),
+                    count,
+                )
+            })
+            .collect();
+        pairs.sort_unstable_by(|a, b| b.2.cmp(&a.2).then_with(|| (a.0, a.1).cmp(&(b.0, b.1))));
+        pairs
+    }
+
+    /// Save pairs as CSV with a `term_a,term_b,count` header, or
+    /// `term_a_id,term_a,term_b_id,term_b,count` once term ids are assigned
+    pub fn save_as_csv(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+
+        if let Some(ids) = self.id_map() {
+            writeln!(writer, "term_a_id,term_a,term_b_id,term_b,count")?;
+            for (first, second, count) in self.sorted_pairs() {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{}",
+                    ids[first], first, ids[second], second, count
+                )?;
+            }
+        } else {
+            writeln!(writer, "term_a,term_b,count")?;
+            for (first, second, count) in self.sorted_pairs() {
+                writeln!(writer, "{},{},{}", first, second, count)?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(fs::metadata(path)?.len() as usize)
+    }
+
+    /// Save pairs as a Parquet file with `term_a`, `term_b` and `count` columns, plus
+    /// `term_a_id` and `term_b_id` once term ids are assigned
+    pub fn save_as_parquet(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let pairs = self.sorted_pairs();
+
+        let mut fields = vec![
+            Field::new("term_a", DataType::Utf8, false),
+            Field::new("term_b", DataType::Utf8, false),
+            Field::new("count", DataType::UInt64, false),
+        ];
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(pairs.iter().map(|p| p.0))),
+            Arc::new(StringArray::from_iter_values(pairs.iter().map(|p| p.1))),
+            Arc::new(UInt64Array::from_iter_values(pairs.iter().map(|p| p.2))),
+        ];
+        if let Some(ids) = self.id_map() {
+            fields.push(Field::new("term_a_id", DataType::UInt32, false));
+            fields.push(Field::new("term_b_id", DataType::UInt32, false));
+            columns.push(Arc::new(UInt32Array::from_iter_values(
+                pairs.iter().map(|p| ids[p.0]),
+            )));
+            columns.push(Arc::new(UInt32Array::from_iter_values(
+                pairs.iter().map(|p| ids[p.1]),
+            )));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+        let file = File::create(path)?;
+        let mut writer = ArrowWriter::try_new(file, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+
+        Ok(fs::metadata(path)?.len() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{CompressedDictionary, Dictionary};
+
+    fn create_test_index() -> CoordinateIndex {
+        let mut dict = Dictionary::new();
+        dict.add_term("war".to_string(), "doc1".to_string());
+        dict.add_term("war".to_string(), "doc2".to_string());
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+
+        CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "war and peace and war",
+                _ => "peace now war",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_windowed_counts() {
+        let index = create_test_index();
+        let matrix = CooccurrenceMatrix::from_coordinate_index(&index, 10, 2);
+
+        // doc1: war(0)-peace(2), peace(2)-war(4); doc2: peace(0)-war(2)
+        assert_eq!(matrix.count("war", "peace"), 3);
+        assert_eq!(matrix.count("peace", "war"), 3);
+        assert_eq!(matrix.count("war", "and"), 2);
+        assert_eq!(matrix.count("war", "missing"), 0);
+    }
+
+    #[test]
+    fn test_top_n_limits_terms() {
+        let index = create_test_index();
+        let matrix = CooccurrenceMatrix::from_coordinate_index(&index, 2, 5);
+
+        assert_eq!(matrix.terms.len(), 2);
+        assert_eq!(matrix.terms[0], "war");
+    }
+}
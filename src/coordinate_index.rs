@@ -1,9 +1,22 @@
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
-
-use crate::query::{tokenize, QueryParser};
-use crate::CompressedDictionary;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::dictionary::prefix_range;
+use crate::explain::{time_stage, trace_evaluation, QueryExplanation, TracedEvaluator};
+use crate::persist::Persistable;
+use crate::planner::IndexKind;
+use crate::query::{raw_term, Capabilities, QueryAst, QueryEvaluator, QueryParser};
+use crate::{
+    is_indexable_word, is_stem_pattern, read_posting_blocks, write_posting_blocks,
+    CompressedDictionary, DamagedBlock, ForwardIndex, PositionPostings, PostingBlocks, Span,
+    TokenSink,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PostingEntry {
@@ -11,115 +24,303 @@ pub struct PostingEntry {
     pub positions: Vec<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Where a query matched in one document, for building snippets and highlighting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchPositions {
+    pub document: String,
+    /// Inclusive word positions of every term, phrase or near match, in order; overlapping
+    /// matches are merged
+    pub spans: Vec<Span>,
+    /// Byte range in the source text of each span, once resolved with `resolve_offsets`
+    pub offsets: Option<Vec<Range<u64>>>,
+}
+
+impl MatchPositions {
+    /// Look up the source byte range of every span in the forward index, which records the
+    /// offsets of each token while parsing. Spans past the recorded tokens are dropped from
+    /// the offsets, so an index updated without its forward index gives fewer offsets.
+    pub fn resolve_offsets(&mut self, forward_index: &ForwardIndex) {
+        let offsets = self
+            .spans
+            .iter()
+            .filter_map(|&(start, end)| {
+                let first = forward_index.span_at(&self.document, start)?;
+                let last = forward_index.span_at(&self.document, end)?;
+                Some(first.start..last.end)
+            })
+            .collect();
+        self.offsets = Some(offsets);
+    }
+}
+
+/// Postings of each term, in document order
+type TermPostings = HashMap<String, Vec<PostingEntry>>;
+
+#[derive(Debug)]
 pub struct CoordinateIndex {
     pub index: HashMap<String, Vec<PostingEntry>>,
     pub documents: Vec<String>,
+    /// Posting blocks that failed their checksum on load; their terms are missing until
+    /// `restore_terms` rebuilds them
+    pub damaged_blocks: Vec<DamagedBlock>,
+    /// Sorted indexed terms for prefix lookups, built on first use
+    sorted_terms: OnceLock<Vec<String>>,
+}
+
+impl Persistable for CoordinateIndex {
+    const SUFFIX: &'static str = "_coordinate";
+
+    fn format_version() -> u32 {
+        3
+    }
+
+    fn write_payload(&self, data: &mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        data.extend(self.payload()?);
+        Ok(())
+    }
+
+    fn read_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_payload(payload)
+    }
+}
+
+/// Serialized as its posting block payload: the documents in the directory and the postings
+/// of every term in the blocks, see `PostingBlocks`
+impl Serialize for CoordinateIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.payload().map_err(S::Error::custom)?)
+    }
+}
+
+impl<'de> Deserialize<'de> for CoordinateIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::from_payload(&Vec::<u8>::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
 }
 
 impl CoordinateIndex {
+    /// Index the positions of every word of every document of `dictionary`, parsed by
+    /// `file_parser`. Documents are parsed and indexed in parallel, each into a map of its own;
+    /// the maps are merged at the end with postings in document order.
     pub fn from_dictionary_with_parser<F>(
         dictionary: &CompressedDictionary,
         file_parser: F,
     ) -> Result<Self, Box<dyn std::error::Error>>
     where
-        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>> + Sync,
+    {
+        Self::from_dictionary_with_tokens(dictionary, |document, sink| {
+            let words = file_parser(document)?;
+            let count = words.len();
+            for (position, word) in words.into_iter().enumerate() {
+                sink(word, position)?;
+            }
+            Ok(count)
+        })
+    }
+
+    /// `from_dictionary_with_parser` for parsers that stream: `tokenizer` hands every word of
+    /// a document to the sink with its position, e.g. through `FB2Parser::for_each_token`, and
+    /// returns the number of words. Only the positions map of each document is kept, never its
+    /// words.
+    pub fn from_dictionary_with_tokens<F>(
+        dictionary: &CompressedDictionary,
+        tokenizer: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str, &mut TokenSink) -> Result<usize, Box<dyn std::error::Error>> + Sync,
     {
         println!("    CoordinateIndex: Starting index construction");
-        let mut index: HashMap<String, HashMap<String, Vec<usize>>> = HashMap::new();
         let mut documents = HashSet::new();
 
         // Collect unique documents first to avoid duplicate processing
         println!("    CoordinateIndex: Collecting unique documents");
         for term_entry in &dictionary.term_entries {
-            for document in &term_entry.documents {
+            for document in term_entry.documents.names(&dictionary.documents) {
                 documents.insert(document.clone());
             }
         }
+        let mut documents: Vec<String> = documents.into_iter().collect();
+        documents.sort();
         println!(
             "    CoordinateIndex: Found {} unique documents",
             documents.len()
         );
 
-        // Process each document only once
-        println!("    CoordinateIndex: Processing documents");
-        let mut processed_count = 0;
-        for document in &documents {
-            processed_count += 1;
-            if processed_count % 10 == 0 {
-                println!(
-                    "    CoordinateIndex: Processed {}/{} documents",
-                    processed_count,
-                    documents.len()
-                );
-            }
-
-            let words = file_parser(document)?;
-
-            for (position, word) in words.iter().enumerate() {
-                index
-                    .entry(word.clone())
-                    .or_insert_with(HashMap::new)
-                    .entry(document.clone())
-                    .or_insert_with(Vec::new)
-                    .push(position);
-            }
-
-            if processed_count <= 5 || processed_count % 50 == 0 {
-                println!(
-                    "    CoordinateIndex: Document {} has {} words",
-                    document,
-                    words.len()
-                );
-            }
-        }
-
-        println!("    CoordinateIndex: Converting to final format in parallel");
-        let mut term_entries: Vec<_> = index.into_iter().collect();
-
-        // Process in parallel chunks to show progress
-        let chunk_size = 1000;
-        let final_index: HashMap<String, Vec<PostingEntry>> = term_entries
-            .par_chunks_mut(chunk_size)
-            .enumerate()
-            .flat_map(|(chunk_idx, chunk)| {
-                if (chunk_idx + 1) % 10 == 0 || chunk_idx == 0 {
+        // Process each document only once, on whichever thread is free
+        println!("    CoordinateIndex: Processing documents in parallel");
+        let processed = AtomicUsize::new(0);
+        let document_positions: Vec<HashMap<String, Vec<usize>>> = documents
+            .par_iter()
+            .map(|document| {
+                let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+                let mut sink = |word: String, position: usize| {
+                    positions.entry(word).or_default().push(position);
+                    Ok(())
+                };
+                // Box<dyn Error> cannot leave a worker thread, so errors travel as text
+                let words = tokenizer(document, &mut sink).map_err(|e| e.to_string())?;
+
+                let processed_count = processed.fetch_add(1, Ordering::Relaxed) + 1;
+                if processed_count.is_multiple_of(10) {
                     println!(
-                        "    CoordinateIndex: Processing chunk {} ({} terms)",
-                        chunk_idx + 1,
-                        (chunk_idx + 1) * chunk_size
+                        "    CoordinateIndex: Processed {}/{} documents",
+                        processed_count,
+                        documents.len()
                     );
                 }
-
-                chunk
-                    .par_iter_mut()
-                    .map(|(term, doc_positions)| {
-                        let mut postings = Vec::new();
-                        for (document, positions) in doc_positions.drain() {
-                            postings.push(PostingEntry {
-                                document,
-                                positions,
-                            });
-                        }
-                        postings.sort_by(|a, b| a.document.cmp(&b.document));
-                        (term.clone(), postings)
-                    })
-                    .collect::<Vec<_>>()
+                if processed_count <= 5 || processed_count.is_multiple_of(50) {
+                    println!(
+                        "    CoordinateIndex: Document {} has {} words",
+                        document, words
+                    );
+                }
+                Ok(positions)
             })
-            .collect();
-
-        let mut documents: Vec<String> = documents.into_iter().collect();
-        documents.sort();
+            .collect::<Result<_, String>>()?;
+
+        println!("    CoordinateIndex: Merging per-document positions in parallel");
+        // Each worker merges a run of documents in order and runs are joined in order, so
+        // every term's postings come out sorted by document
+        let final_index: TermPostings = documents
+            .par_iter()
+            .zip(document_positions)
+            .fold(HashMap::new, |mut index: TermPostings, (document, positions)| {
+                for (term, positions) in positions {
+                    index.entry(term).or_default().push(PostingEntry {
+                        document: document.clone(),
+                        positions,
+                    });
+                }
+                index
+            })
+            .reduce(HashMap::new, |mut left, right| {
+                for (term, postings) in right {
+                    left.entry(term).or_default().extend(postings);
+                }
+                left
+            });
 
         println!(
             "    CoordinateIndex: Construction complete - {} terms, {} documents",
             final_index.len(),
             documents.len()
         );
-        Ok(CoordinateIndex {
-            index: final_index,
+        Ok(Self::from_postings(final_index, documents))
+    }
+
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        write_posting_blocks(
+            &self.documents,
+            self.sorted_terms()
+                .iter()
+                .map(|term| (term.as_str(), &self.index[term])),
+        )
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let stored: PostingBlocks<Vec<String>, Vec<PostingEntry>> =
+            read_posting_blocks(payload, "the coordinate index")?;
+        let mut index = Self::from_postings(stored.entries.into_iter().collect(), stored.meta);
+        index.damaged_blocks = stored.damaged;
+        Ok(index)
+    }
+
+    /// Assemble an index from postings built elsewhere, e.g. merged SPIMI blocks
+    pub fn from_postings(index: HashMap<String, Vec<PostingEntry>>, documents: Vec<String>) -> Self {
+        CoordinateIndex {
+            index,
             documents,
-        })
+            damaged_blocks: Vec::new(),
+            sorted_terms: OnceLock::new(),
+        }
+    }
+
+    /// Index the words of a document not in the index yet, keeping postings sorted by document
+    pub fn add_document(&mut self, document: &str, words: &[String]) {
+        let mut positions: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (position, word) in words.iter().enumerate() {
+            positions.entry(word.as_str()).or_default().push(position);
+        }
+        for (term, positions) in positions {
+            let postings = self.index.entry(term.to_string()).or_default();
+            match postings.binary_search_by(|p| p.document.as_str().cmp(document)) {
+                Ok(idx) => postings[idx].positions = positions,
+                Err(idx) => postings.insert(
+                    idx,
+                    PostingEntry {
+                        document: document.to_string(),
+                        positions,
+                    },
+                ),
+            }
+        }
+        if let Err(idx) = self.documents.binary_search_by(|d| d.as_str().cmp(document)) {
+            self.documents.insert(idx, document.to_string());
+        }
+        self.sorted_terms = OnceLock::new();
+    }
+
+    /// Drop the postings of `documents`, and terms no other document has
+    pub fn remove_documents(&mut self, documents: &HashSet<String>) {
+        self.index.retain(|_, postings| {
+            postings.retain(|posting| !documents.contains(&posting.document));
+            !postings.is_empty()
+        });
+        self.documents.retain(|document| !documents.contains(document));
+        self.sorted_terms = OnceLock::new();
+    }
+
+    /// Rebuild the postings of the terms lost with `damaged_blocks` from the token positions
+    /// of `forward_index`, saved with the index. Returns the number of terms restored.
+    pub fn restore_terms(&mut self, forward_index: &ForwardIndex) -> usize {
+        let damaged = std::mem::take(&mut self.damaged_blocks);
+        let mut restored: TermPostings = HashMap::new();
+        // Documents are sorted, so the rebuilt postings come out in document order
+        for document in &self.documents {
+            let Some(words) = forward_index.words(document) else {
+                continue;
+            };
+            let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+            for (position, word) in words.into_iter().enumerate() {
+                if damaged.iter().any(|block| block.covers(&word)) {
+                    positions.entry(word).or_default().push(position);
+                }
+            }
+            for (term, positions) in positions {
+                restored.entry(term).or_default().push(PostingEntry {
+                    document: document.clone(),
+                    positions,
+                });
+            }
+        }
+        let count = restored.len();
+        self.index.extend(restored);
+        self.sorted_terms = OnceLock::new();
+        count
+    }
+
+    /// Sort postings by document, merge duplicate document entries and sort their positions
+    pub fn optimize(&mut self) {
+        self.index.par_iter_mut().for_each(|(_, postings)| {
+            postings.sort_by(|a, b| a.document.cmp(&b.document));
+            let mut merged: Vec<PostingEntry> = Vec::with_capacity(postings.len());
+            for posting in postings.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.document == posting.document => {
+                        last.positions.extend(posting.positions)
+                    }
+                    _ => merged.push(posting),
+                }
+            }
+            for posting in &mut merged {
+                posting.positions.sort_unstable();
+                posting.positions.dedup();
+            }
+            *postings = merged;
+        });
+        self.documents.sort();
+        self.documents.dedup();
     }
 
     pub fn memory_size(&self) -> usize {
@@ -142,250 +343,274 @@ impl CoordinateIndex {
             + self.documents.iter().map(|d| d.len()).sum::<usize>()
     }
 
-    pub fn search_phrase(&self, phrase: &str) -> Result<HashSet<String>, String> {
-        let words: Vec<&str> = phrase.split_whitespace().collect();
-        if words.is_empty() {
-            return Ok(HashSet::new());
+    /// Number of indexed positions (words) per document
+    pub fn document_lengths(&self) -> HashMap<&str, usize> {
+        let mut lengths: HashMap<&str, usize> = HashMap::new();
+        for postings in self.index.values() {
+            for posting in postings {
+                *lengths.entry(posting.document.as_str()).or_insert(0) += posting.positions.len();
+            }
         }
+        lengths
+    }
 
-        if words.len() == 1 {
-            return self.search_term(words[0]);
+    /// Indexed terms starting with `prefix`
+    pub fn terms_with_prefix(&self, prefix: &str) -> &[String] {
+        prefix_range(self.sorted_terms(), prefix)
+    }
+
+    fn sorted_terms(&self) -> &[String] {
+        self.sorted_terms.get_or_init(|| {
+            let mut terms: Vec<String> = self.index.keys().cloned().collect();
+            terms.sort_unstable();
+            terms
+        })
+    }
+
+    /// Positions of `document` in any of the posting lists, sorted
+    fn positions_in(alternatives: &[&Vec<PostingEntry>], document: &str) -> Vec<usize> {
+        let mut positions: Vec<usize> = alternatives
+            .iter()
+            .filter_map(|postings| {
+                postings
+                    .binary_search_by(|p| p.document.as_str().cmp(document))
+                    .ok()
+                    .map(|idx| &postings[idx].positions)
+            })
+            .flatten()
+            .copied()
+            .collect();
+        if alternatives.len() > 1 {
+            positions.sort_unstable();
         }
+        positions
+    }
 
-        let first_word = words[0].to_lowercase();
-        let first_postings = match self.index.get(&first_word) {
-            Some(postings) => postings,
-            None => return Ok(HashSet::new()),
-        };
+    /// `search` with every node of the query traced and each stage timed
+    pub fn explain(&self, query: &str) -> Result<QueryExplanation, String> {
+        let mut stages = Vec::new();
+        let query = time_stage(&mut stages, "parse", || QueryAst::parse(query))?;
+        let (documents, trace) =
+            time_stage(&mut stages, "evaluate", || trace_evaluation(self, &query))?;
+        Ok(QueryExplanation {
+            kind: IndexKind::Coordinate,
+            trace,
+            stages,
+            documents,
+        })
+    }
 
-        let mut result = HashSet::new();
-
-        for posting in first_postings {
-            let document = &posting.document;
-
-            let mut all_words_found = true;
-            let mut current_positions = posting.positions.clone();
-
-            for (word_offset, word) in words.iter().enumerate().skip(1) {
-                let word_lower = word.to_lowercase();
-
-                if let Some(word_postings) = self.index.get(&word_lower) {
-                    if let Some(word_posting) =
-                        word_postings.iter().find(|p| p.document == *document)
-                    {
-                        let next_positions: Vec<usize> = current_positions
-                            .iter()
-                            .filter_map(|&pos| {
-                                if word_posting.positions.contains(&(pos + word_offset)) {
-                                    Some(pos + word_offset)
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-
-                        if next_positions.is_empty() {
-                            all_words_found = false;
-                            break;
-                        }
-                        current_positions = next_positions;
-                    } else {
-                        all_words_found = false;
-                        break;
-                    }
-                } else {
-                    all_words_found = false;
-                    break;
+    /// `search` returning where each matching document matched: the positions of the terms,
+    /// phrases and near operands outside NOT, in documents sorted by name
+    pub fn search_with_positions(&self, query: &str) -> Result<Vec<MatchPositions>, String> {
+        let query = QueryAst::parse(query)?;
+        let documents = self.evaluate(&query)?;
+        let matches = self.match_postings(&query)?;
+
+        let mut documents: Vec<String> = documents.into_iter().collect();
+        documents.sort();
+        Ok(documents
+            .into_iter()
+            .map(|document| {
+                let mut spans = matches.spans(&document).to_vec();
+                spans.sort_unstable();
+                spans.dedup();
+                let spans = merge_overlapping(spans);
+                MatchPositions {
+                    document,
+                    spans,
+                    offsets: None,
                 }
-            }
+            })
+            .collect())
+    }
 
-            if all_words_found && !current_positions.is_empty() {
-                result.insert(document.clone());
+    /// Matches of the positive operands of `query`; a document NOT excludes matches nothing
+    /// worth highlighting
+    fn match_postings(&self, query: &QueryAst) -> Result<PositionPostings, String> {
+        match query {
+            QueryAst::And(left, right) | QueryAst::Or(left, right) => {
+                Ok(self.match_postings(left)?.union(&self.match_postings(right)?))
             }
+            QueryAst::Not(_) | QueryAst::Wildcard(_) => Ok(PositionPostings::new()),
+            operand => self.positional_postings(operand),
         }
-
-        Ok(result)
     }
 
-    pub fn search_proximity(
-        &self,
-        words: &[&str],
-        max_distance: usize,
-    ) -> Result<HashSet<String>, String> {
-        if words.len() < 2 {
-            return Err("Proximity search requires at least two words".to_string());
+    /// Documents containing the words consecutively. A final `prefix*` word matches any indexed
+    /// term with that prefix, so `"new yor*"` finds "new york" and "new yorkers". Words the
+    /// tokenizer drops are skipped, as they were when positions were assigned.
+    pub fn search_phrase(&self, phrase: &str) -> Result<HashSet<String>, String> {
+        let words = phrase_words(phrase);
+        if words.len() == 1 && !is_stem_pattern(&words[0]) {
+            return self.search_term(&words[0]);
         }
+        Ok(self.phrase_word_postings(&words).documents())
+    }
 
-        let first_word = words[0].to_lowercase();
-        let first_postings = match self.index.get(&first_word) {
-            Some(postings) => postings,
-            None => return Ok(HashSet::new()),
-        };
+    /// Every match of a phrase, with the same word rules as `search_phrase`
+    pub fn phrase_postings(&self, phrase: &str) -> PositionPostings {
+        self.phrase_word_postings(&phrase_words(phrase))
+    }
 
-        let mut result = HashSet::new();
-
-        for posting in first_postings {
-            let document = &posting.document;
-            let mut found_proximity = false;
-
-            for &first_pos in &posting.positions {
-                let mut all_words_in_range = true;
-
-                for word in words.iter().skip(1) {
-                    let word_lower = word.to_lowercase();
-
-                    if let Some(word_postings) = self.index.get(&word_lower) {
-                        if let Some(word_posting) =
-                            word_postings.iter().find(|p| p.document == *document)
-                        {
-                            let word_in_range = word_posting.positions.iter().any(|&pos| {
-                                let distance = if pos > first_pos {
-                                    pos - first_pos
-                                } else {
-                                    first_pos - pos
-                                };
-                                distance <= max_distance
-                            });
-
-                            if !word_in_range {
-                                all_words_in_range = false;
-                                break;
-                            }
-                        } else {
-                            all_words_in_range = false;
-                            break;
-                        }
-                    } else {
-                        all_words_in_range = false;
-                        break;
-                    }
+    fn phrase_word_postings(&self, words: &[String]) -> PositionPostings {
+        let mut result = Vec::new();
+        if words.is_empty() {
+            return PositionPostings::new();
+        }
+
+        let last = words.len() - 1;
+        let alternatives: Vec<Vec<&Vec<PostingEntry>>> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| {
+                if i == last && is_stem_pattern(word) {
+                    self.terms_with_prefix(word.trim_end_matches('*'))
+                        .iter()
+                        .filter_map(|term| self.index.get(term))
+                        .collect()
+                } else {
+                    self.index.get(word).into_iter().collect()
                 }
+            })
+            .collect();
+        if alternatives.iter().any(|postings| postings.is_empty()) {
+            return PositionPostings::new();
+        }
+
+        let candidates: HashSet<&String> = alternatives[0]
+            .iter()
+            .flat_map(|postings| postings.iter().map(|p| &p.document))
+            .collect();
+
+        for document in candidates {
+            let mut current_positions = Self::positions_in(&alternatives[0], document);
 
-                if all_words_in_range {
-                    found_proximity = true;
+            for (word_offset, word_alternatives) in alternatives.iter().enumerate().skip(1) {
+                let word_positions = Self::positions_in(word_alternatives, document);
+                current_positions
+                    .retain(|&pos| word_positions.binary_search(&(pos + word_offset)).is_ok());
+                if current_positions.is_empty() {
                     break;
                 }
             }
 
-            if found_proximity {
-                result.insert(document.clone());
-            }
+            let spans = current_positions
+                .into_iter()
+                .map(|start| (start, start + last))
+                .collect();
+            result.push((document.clone(), spans));
         }
 
-        Ok(result)
+        PositionPostings::from_spans(result)
     }
 
-    fn parse_or_expr(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_and_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "or" {
-            *pos += 1;
-            let right = self.parse_and_expr(tokens, pos)?;
-            result = result.union(&right).cloned().collect();
-        }
-
-        Ok(result)
+    /// Every occurrence of `term`; empty for unknown terms
+    pub fn term_postings(&self, term: &str) -> PositionPostings {
+        PositionPostings::from_positions(
+            self.index
+                .get(term)
+                .into_iter()
+                .flatten()
+                .map(|p| (p.document.clone(), p.positions.clone())),
+        )
     }
 
-    fn parse_and_expr(
+    pub fn search_proximity(
         &self,
-        tokens: &[String],
-        pos: &mut usize,
+        words: &[&str],
+        max_distance: usize,
     ) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_not_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "and" {
-            *pos += 1;
-            let right = self.parse_not_expr(tokens, pos)?;
-            result = result.intersection(&right).cloned().collect();
+        let operands: Vec<PositionPostings> = words
+            .iter()
+            .map(|w| w.to_lowercase())
+            .filter(|w| is_indexable_word(w))
+            .map(|w| self.term_postings(&w))
+            .collect();
+        if operands.len() < 2 {
+            return Err("Proximity search requires at least two words".to_string());
         }
+        Ok(near_postings(operands, max_distance).documents())
+    }
 
-        Ok(result)
+    /// Matches of a near operand with their positions, so an enclosing near operator can
+    /// measure distances from them
+    fn positional_postings(&self, query: &QueryAst) -> Result<PositionPostings, String> {
+        match query {
+            QueryAst::Term(term) => Ok(self.term_postings(term)),
+            QueryAst::Phrase(words) => Ok(self.phrase_postings(&words.join(" "))),
+            QueryAst::Near { distance, operands } => {
+                self.near_operand_postings(*distance, operands)
+            }
+            QueryAst::Wildcard(pattern) => Err(format!(
+                "Wildcard patterns are not supported here: '{}'",
+                pattern
+            )),
+            // Documents of both or either side, with both sides' positions
+            QueryAst::And(left, right) => Ok(self
+                .positional_postings(left)?
+                .intersect(&self.positional_postings(right)?)),
+            QueryAst::Or(left, right) => Ok(self
+                .positional_postings(left)?
+                .union(&self.positional_postings(right)?)),
+            QueryAst::Not(_) => Err("NOT cannot be used inside a near operand".to_string()),
+        }
     }
 
-    fn parse_not_expr(
+    fn near_operand_postings(
         &self,
-        tokens: &[String],
-        pos: &mut usize,
-    ) -> Result<HashSet<String>, String> {
-        if *pos < tokens.len() && tokens[*pos] == "not" {
-            *pos += 1;
-            let result = self.parse_primary(tokens, pos)?;
-            let all_docs: HashSet<String> = self.documents.iter().cloned().collect();
-            Ok(all_docs.difference(&result).cloned().collect())
-        } else {
-            self.parse_primary(tokens, pos)
-        }
+        distance: usize,
+        operands: &[QueryAst],
+    ) -> Result<PositionPostings, String> {
+        let operands = operands
+            .iter()
+            .map(|operand| self.positional_postings(operand))
+            .collect::<Result<_, _>>()?;
+        Ok(near_postings(operands, distance))
     }
+}
 
-    fn parse_primary(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        if *pos >= tokens.len() {
-            return Err("Unexpected end of query".to_string());
+/// Sorted spans with the ones that overlap or touch joined
+fn merge_overlapping(spans: Vec<Span>) -> Vec<Span> {
+    let mut merged: Vec<Span> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end + 1 => *last_end = (*last_end).max(end),
+            _ => merged.push((start, end)),
         }
+    }
+    merged
+}
 
-        if tokens[*pos] == "(" {
-            *pos += 1;
-            let result = self.parse_or_expr(tokens, pos)?;
-            if *pos >= tokens.len() || tokens[*pos] != ")" {
-                return Err("Missing closing parenthesis".to_string());
-            }
-            *pos += 1;
-            Ok(result)
-        } else if tokens[*pos] == "\"" {
-            *pos += 1;
-            let mut phrase_words = Vec::new();
-            while *pos < tokens.len() && tokens[*pos] != "\"" {
-                phrase_words.push(tokens[*pos].clone());
-                *pos += 1;
-            }
-            if *pos >= tokens.len() {
-                return Err("Missing closing quote".to_string());
-            }
-            *pos += 1;
-            self.search_phrase(&phrase_words.join(" "))
-        } else if tokens[*pos].starts_with("near/") {
-            let distance_str = tokens[*pos].strip_prefix("near/").unwrap();
-            let distance: usize = distance_str
-                .parse()
-                .map_err(|_| format!("Invalid distance in near operator: {}", distance_str))?;
-
-            *pos += 1;
-            if *pos >= tokens.len() || tokens[*pos] != "(" {
-                return Err("Expected '(' after near operator".to_string());
-            }
-            *pos += 1;
-
-            let mut words = Vec::new();
-            while *pos < tokens.len() && tokens[*pos] != ")" {
-                words.push(tokens[*pos].as_str());
-                *pos += 1;
-            }
-            if *pos >= tokens.len() {
-                return Err("Missing closing parenthesis for near operator".to_string());
-            }
-            *pos += 1;
-
-            if words.len() < 2 {
-                return Err("Near operator requires at least two words".to_string());
-            }
+/// Lowercased phrase words the tokenizer would have indexed, plus a trailing `prefix*`
+pub(crate) fn phrase_words(phrase: &str) -> Vec<String> {
+    phrase
+        .split_whitespace()
+        .map(|w| raw_term(w).to_lowercase())
+        .filter(|w| is_indexable_word(w) || is_stem_pattern(w))
+        .collect()
+}
 
-            self.search_proximity(&words, distance)
-        } else {
-            let term = &tokens[*pos];
-            *pos += 1;
-            self.search_term(term)
-        }
-    }
+/// Matches of the first operand that have a match of every other operand within
+/// `max_distance`; keeping them lets an enclosing near operator measure from them again
+pub(crate) fn near_postings(operands: Vec<PositionPostings>, max_distance: usize) -> PositionPostings {
+    let mut operands = operands.into_iter();
+    let anchor = operands.next().unwrap_or_default();
+    operands.fold(anchor, |anchor, operand| {
+        anchor.within(&operand, max_distance)
+    })
 }
 
 impl QueryParser for CoordinateIndex {
     type Result = HashSet<String>;
     type Error = String;
+    const CAPABILITIES: Capabilities = Capabilities::PHRASES
+        .union(Capabilities::POSITIONS)
+        .union(Capabilities::NOT)
+        .union(Capabilities::RANKING);
 
     fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
-        let tokens = tokenize(&query.to_lowercase())?;
-        let mut pos = 0;
-        self.parse_or_expr(&tokens, &mut pos)
+        self.evaluate(&QueryAst::parse(query)?)
     }
 
     fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
@@ -396,3 +621,341 @@ impl QueryParser for CoordinateIndex {
         }
     }
 }
+
+impl QueryEvaluator for CoordinateIndex {
+    type Output = HashSet<String>;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.search_term(term)
+    }
+
+    fn evaluate_phrase(&self, words: &[String]) -> Result<Self::Output, String> {
+        self.search_phrase(&words.join(" "))
+    }
+
+    fn evaluate_near(
+        &self,
+        distance: usize,
+        operands: &[QueryAst],
+    ) -> Result<Self::Output, String> {
+        Ok(self.near_operand_postings(distance, operands)?.documents())
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left.intersection(&right).cloned().collect()
+    }
+
+    fn unite(&self, mut left: Self::Output, right: Self::Output) -> Self::Output {
+        left.extend(right);
+        left
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.documents
+            .iter()
+            .filter(|document| !operand.contains(*document))
+            .cloned()
+            .collect()
+    }
+}
+
+impl TracedEvaluator for CoordinateIndex {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len()
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.index.get(term).map_or(0, Vec::len)
+    }
+
+    fn term_method(&self) -> String {
+        "read the positional posting list".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::Dictionary;
+    use crate::tokenize_plain_text_with_offsets;
+
+    #[test]
+    fn test_phrase_prefix() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("new".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "the new york times",
+                "doc2" => "new yorkers and new jersey",
+                _ => "york is not new",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap();
+
+        let docs = |query: &str| {
+            let mut docs: Vec<String> = index.search(query).unwrap().into_iter().collect();
+            docs.sort();
+            docs
+        };
+        assert_eq!(docs("\"new yor*\""), vec!["doc1", "doc2"]);
+        assert_eq!(docs("\"the new yor*\""), vec!["doc1"]);
+        assert_eq!(docs("\"new york times\""), vec!["doc1"]);
+        assert!(docs("\"new xyz*\"").is_empty());
+    }
+
+    #[test]
+    fn test_negated_phrase_and_proximity() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("война".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "война и мир",
+                "doc2" => "мир после войны и война",
+                _ => "война далеко не мир",
+            };
+            Ok(text
+                .split_whitespace()
+                .filter(|w| is_indexable_word(w))
+                .map(|w| w.to_string())
+                .collect())
+        })
+        .unwrap();
+
+        let docs = |query: &str| {
+            let mut docs: Vec<String> = index.search(query).unwrap().into_iter().collect();
+            docs.sort();
+            docs
+        };
+        assert_eq!(docs("\"война и мир\""), vec!["doc1"]);
+        assert_eq!(docs("NOT \"война и мир\""), vec!["doc2", "doc3"]);
+        assert_eq!(docs("not near/1(война мир)"), vec!["doc2", "doc3"]);
+        assert_eq!(docs("война and not near/2(война мир)"), vec!["doc2"]);
+    }
+
+    #[test]
+    fn test_match_positions_resolve_to_source_offsets() {
+        let texts = |doc: &str| match doc {
+            "doc1" => "the war and the peace of war",
+            _ => "war without peace",
+        };
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let tokens = tokenize_plain_text_with_offsets(texts(doc));
+            Ok(tokens.into_iter().map(|(word, _, _)| word).collect())
+        })
+        .unwrap();
+        let forward_index =
+            ForwardIndex::from_documents_with_tokenizer(&compressed.documents, |doc| {
+                Ok(tokenize_plain_text_with_offsets(texts(doc)))
+            })
+            .unwrap();
+
+        // The phrase touches the last "war", so the two are highlighted as one stretch
+        let mut matches = index.search_with_positions("war and \"the peace\"").unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].spans, vec![(1, 1), (3, 5)]);
+        matches[0].resolve_offsets(&forward_index);
+        assert_eq!(matches[0].offsets, Some(vec![4..7, 12..28]));
+
+        let matches = index.search_with_positions("war and not \"the peace\"").unwrap();
+        assert_eq!(matches[0].document, "doc2");
+        assert_eq!(matches[0].spans, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_nested_near_operands() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("анна".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "анна каренина вышла на станция",
+                "doc2" => "анна каренина долго ждала поезд",
+                _ => "каренина анна поезд",
+            };
+            Ok(text
+                .split_whitespace()
+                .filter(|w| is_indexable_word(w))
+                .map(|w| w.to_string())
+                .collect())
+        })
+        .unwrap();
+
+        let docs = |query: &str| {
+            let mut docs: Vec<String> = index.search(query).unwrap().into_iter().collect();
+            docs.sort();
+            docs
+        };
+        assert_eq!(
+            docs("near/3((\"анна каренина\") (поезд OR станция))"),
+            vec!["doc1"]
+        );
+        assert_eq!(
+            docs("near/4(\"анна каренина\" (поезд OR станция))"),
+            vec!["doc1", "doc2"]
+        );
+        assert_eq!(docs("near/1(near/1(анна каренина) поезд)"), vec!["doc3"]);
+        assert!(index.search("near/2(анна (not поезд))").is_err());
+    }
+    #[test]
+    fn test_parallel_build_keeps_document_order() {
+        let mut dict = Dictionary::new();
+        let names: Vec<String> = (0..200).map(|i| format!("doc{:03}", i)).collect();
+        for name in &names {
+            dict.add_term("common".to_string(), name.clone());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let number: usize = doc[3..].parse()?;
+            let mut words = vec!["common".to_string(); number % 3 + 1];
+            words.push(format!("word{}", number % 7));
+            Ok(words)
+        })
+        .unwrap();
+
+        assert_eq!(index.documents, names);
+        let common: Vec<&String> = index.index["common"].iter().map(|p| &p.document).collect();
+        assert_eq!(common, names.iter().collect::<Vec<_>>());
+        assert_eq!(index.index["common"][5].positions, vec![0, 1, 2]);
+        let word3 = &index.index["word3"];
+        assert!(word3.windows(2).all(|w| w[0].document < w[1].document));
+        assert_eq!(word3[0].document, "doc003");
+        assert_eq!(word3[0].positions, vec![1]);
+
+        let failed = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            if doc == "doc150" {
+                Err("unreadable file".into())
+            } else {
+                Ok(Vec::new())
+            }
+        });
+        assert_eq!(failed.unwrap_err().to_string(), "unreadable file");
+    }
+
+    #[test]
+    fn test_optimize_merges_postings_and_keeps_results() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let build = || {
+            CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+                let text = match doc {
+                    "doc1" => "war and peace and war",
+                    "doc2" => "peace after the war and peace",
+                    _ => "war war war",
+                };
+                Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+            })
+            .unwrap()
+        };
+        let mut index = build();
+        // Postings out of document order, one document split over two entries, as merging
+        // segments leaves them
+        let postings = index.index.get_mut("war").unwrap();
+        postings.reverse();
+        let doc3 = postings.iter_mut().find(|p| p.document == "doc3").unwrap();
+        let tail = doc3.positions.split_off(1);
+        postings.push(PostingEntry {
+            document: "doc3".to_string(),
+            positions: tail,
+        });
+
+        let queries = [
+            "\"war and peace\"",
+            "\"war war\"",
+            "near/2(peace war)",
+            "war and not peace",
+        ];
+        let results = |index: &CoordinateIndex| -> Vec<Vec<String>> {
+            queries
+                .iter()
+                .map(|query| {
+                    let mut docs: Vec<String> = index.search(query).unwrap().into_iter().collect();
+                    docs.sort();
+                    docs
+                })
+                .collect()
+        };
+        index.optimize();
+
+        assert_eq!(results(&index), results(&build()));
+        let postings = &index.index["war"];
+        let documents: Vec<&str> = postings.iter().map(|p| p.document.as_str()).collect();
+        assert_eq!(documents, ["doc1", "doc2", "doc3"]);
+        assert_eq!(postings[2].positions, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_damaged_posting_blocks_are_restored_from_forward_index() {
+        // Words "waa" to "wln", enough terms for three blocks
+        let words: Vec<String> = (0..300u16)
+            .map(|i| {
+                let (first, second) = (b'a' + (i / 26) as u8, b'a' + (i % 26) as u8);
+                format!("w{}{}", char::from(first), char::from(second))
+            })
+            .collect();
+        let doc1 = words.join(" ");
+        let doc2: Vec<&str> = words
+            .iter()
+            .step_by(3)
+            .chain(words.iter().step_by(2))
+            .map(String::as_str)
+            .collect();
+        let doc2 = doc2.join(" ");
+        let texts = |doc: &str| if doc == "doc1" { doc1.clone() } else { doc2.clone() };
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2"] {
+            dict.add_term("waa".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let tokens = tokenize_plain_text_with_offsets(&texts(doc));
+            Ok(tokens.into_iter().map(|(word, _, _)| word).collect())
+        })
+        .unwrap();
+        let forward_index =
+            ForwardIndex::from_documents_with_tokenizer(&compressed.documents, |doc| {
+                Ok(tokenize_plain_text_with_offsets(&texts(doc)))
+            })
+            .unwrap();
+
+        let mut data = index.to_bytes().unwrap();
+        let offset = data.windows(3).position(|window| window == b"whs").unwrap();
+        data[offset] ^= 0xff;
+        let mut damaged = CoordinateIndex::from_bytes(&data).unwrap();
+        assert_eq!(damaged.damaged_blocks.len(), 1);
+        assert!(!damaged.index.contains_key("whs"));
+        assert!(damaged.index.contains_key("waa"));
+
+        assert_eq!(damaged.restore_terms(&forward_index), 128);
+        let restored = CoordinateIndex::from_bytes(&damaged.to_bytes().unwrap()).unwrap();
+        let postings = |index: &CoordinateIndex, term: &str| -> Vec<(String, Vec<usize>)> {
+            index.index[term]
+                .iter()
+                .map(|posting| (posting.document.clone(), posting.positions.clone()))
+                .collect()
+        };
+        assert_eq!(restored.index.len(), index.index.len());
+        for term in &words {
+            assert_eq!(postings(&restored, term), postings(&index, term));
+        }
+        for query in ["whs", "\"whr whs wht\"", "near/3(waa whs)"] {
+            assert_eq!(restored.search(query).unwrap(), index.search(query).unwrap());
+        }
+    }
+}
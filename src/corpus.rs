@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::pipeline::MIN_FILE_SIZE;
+use crate::Dictionary;
+
+/// Why nothing in an input qualified as a corpus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmptyReason {
+    /// No `.fb2` files, or a Parquet file without rows
+    NoDocuments,
+    /// Every file was skipped, e.g. for being smaller than the minimum file size
+    NoQualifyingDocuments { candidates: usize },
+    /// Documents were read but none had a word the tokenizer keeps
+    NoIndexableWords { documents: usize },
+    /// Fewer documents qualified than the build requires
+    TooFewDocuments { documents: usize, minimum: usize },
+}
+
+/// A corpus the build refuses to index, instead of writing empty or broken structures.
+/// `hint` tells the user how to fix the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorpusError {
+    EmptyCorpus {
+        source: String,
+        reason: EmptyReason,
+    },
+    /// A Parquet file whose columns hold no text the loader can read
+    SchemaMismatch {
+        source: String,
+        /// Column names and types, as `parquet-inspect` shows them
+        columns: Vec<(String, String)>,
+        problem: String,
+    },
+}
+
+impl CorpusError {
+    /// What to change so the build succeeds
+    pub fn hint(&self) -> String {
+        match self {
+            CorpusError::EmptyCorpus { reason, .. } => match reason {
+                EmptyReason::NoDocuments => {
+                    "Check the input path: builds read `.fb2` files from the directory and its \
+                     subdirectories, and Parquet builds need at least one row in the file or in \
+                     the `.parquet` part files of the directory"
+                        .to_string()
+                }
+                EmptyReason::NoQualifyingDocuments { .. } => format!(
+                    "Files smaller than {} bytes are skipped; add larger books or check the \
+                     warnings above for files that could not be read",
+                    MIN_FILE_SIZE
+                ),
+                EmptyReason::NoIndexableWords { .. } => {
+                    "Only words of three or more Cyrillic or Latin letters are indexed; check \
+                     the encoding of the input and that --analyzer stopwords and --strip \
+                     patterns do not remove every word"
+                        .to_string()
+                }
+                EmptyReason::TooFewDocuments { minimum, .. } => format!(
+                    "Add documents to the input or lower --min-documents from {}",
+                    minimum
+                ),
+            },
+            CorpusError::SchemaMismatch { .. } => {
+                "Run `grimoire parquet-inspect` to see the columns, then name the text, id and \
+                 metadata columns with --text-col, --id-col and --meta-cols"
+                    .to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for CorpusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorpusError::EmptyCorpus { source, reason } => {
+                write!(f, "Empty corpus in {}: ", source)?;
+                match reason {
+                    EmptyReason::NoDocuments => write!(f, "no documents found"),
+                    EmptyReason::NoQualifyingDocuments { candidates } => write!(
+                        f,
+                        "none of the {} documents found qualified for indexing",
+                        candidates
+                    ),
+                    EmptyReason::NoIndexableWords { documents } => {
+                        write!(f, "{} documents hold no indexable words", documents)
+                    }
+                    EmptyReason::TooFewDocuments { documents, minimum } => write!(
+                        f,
+                        "{} documents qualified, at least {} are required",
+                        documents, minimum
+                    ),
+                }
+            }
+            CorpusError::SchemaMismatch {
+                source,
+                columns,
+                problem,
+            } => {
+                let columns: Vec<String> = columns
+                    .iter()
+                    .map(|(name, data_type)| format!("{} ({})", name, data_type))
+                    .collect();
+                write!(
+                    f,
+                    "Schema mismatch in {}: {}; columns: {}",
+                    source,
+                    problem,
+                    if columns.is_empty() {
+                        "none".to_string()
+                    } else {
+                        columns.join(", ")
+                    }
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CorpusError {}
+
+/// Check a built dictionary holds a corpus worth indexing: at least `min_documents` of the
+/// `candidates` input documents qualified and some word was indexed
+pub fn validate_corpus(
+    source: &str,
+    candidates: usize,
+    dictionary: &Dictionary,
+    min_documents: usize,
+) -> Result<(), CorpusError> {
+    let documents = dictionary.total_documents as usize;
+    let reason = if candidates == 0 {
+        EmptyReason::NoDocuments
+    } else if documents == 0 {
+        EmptyReason::NoQualifyingDocuments { candidates }
+    } else if dictionary.terms.is_empty() {
+        EmptyReason::NoIndexableWords { documents }
+    } else if documents < min_documents {
+        EmptyReason::TooFewDocuments {
+            documents,
+            minimum: min_documents,
+        }
+    } else {
+        return Ok(());
+    };
+    Err(CorpusError::EmptyCorpus {
+        source: source.to_string(),
+        reason,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degenerate_corpora_are_rejected() {
+        let empty = |reason| CorpusError::EmptyCorpus {
+            source: "books".to_string(),
+            reason,
+        };
+        let mut dictionary = Dictionary::new();
+        assert_eq!(
+            validate_corpus("books", 0, &dictionary, 1),
+            Err(empty(EmptyReason::NoDocuments))
+        );
+        assert_eq!(
+            validate_corpus("books", 3, &dictionary, 1),
+            Err(empty(EmptyReason::NoQualifyingDocuments { candidates: 3 }))
+        );
+        dictionary.add_file_stats(200_000);
+        assert_eq!(
+            validate_corpus("books", 3, &dictionary, 1),
+            Err(empty(EmptyReason::NoIndexableWords { documents: 1 }))
+        );
+        dictionary.add_term("war".to_string(), "a.fb2".to_string());
+        assert!(validate_corpus("books", 3, &dictionary, 1).is_ok());
+        let error = validate_corpus("books", 3, &dictionary, 2).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Empty corpus in books: 1 documents qualified, at least 2 are required"
+        );
+        assert!(error.hint().contains("--min-documents"));
+    }
+}
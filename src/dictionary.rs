@@ -1,5 +1,12 @@
+use crate::estimate::{estimate_hits, union_frequency, wildcard_prefix};
+use crate::persist::Persistable;
+use crate::TuningConfig;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use smallvec::SmallVec;
+use std::collections::hash_map::RandomState;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::hash::BuildHasher;
 use std::fs;
 use std::io::Write;
 use rayon::prelude::*;
@@ -106,7 +113,7 @@ mod front_packing {
 
         for (i, ch) in first.char_indices() {
             if terms.iter().all(|term| {
-                term.chars().nth(i).map_or(false, |c| c == ch)
+                term.chars().nth(i) == Some(ch)
             }) {
                 prefix_len = i + ch.len_utf8();
             } else {
@@ -121,15 +128,223 @@ mod front_packing {
 
 use front_packing::*;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Sorted, deduplicated document ids; most terms occur in a handful of documents, so short
+/// sets stay inline without a heap allocation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocIdSet(SmallVec<[u32; 4]>);
+
+impl DocIdSet {
+    pub fn new() -> Self {
+        DocIdSet(SmallVec::new())
+    }
+
+    /// Insert an id, returning false if it was already present. Ids usually arrive in
+    /// ascending order, which makes this an append.
+    pub fn insert(&mut self, id: u32) -> bool {
+        match self.0.last() {
+            Some(&last) if last < id => {
+                self.0.push(id);
+                true
+            }
+            None => {
+                self.0.push(id);
+                true
+            }
+            _ => match self.0.binary_search(&id) {
+                Ok(_) => false,
+                Err(pos) => {
+                    self.0.insert(pos, id);
+                    true
+                }
+            },
+        }
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.0.binary_search(&id).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Ids in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Merge another set into this one, keeping ids sorted
+    pub fn union_with(&mut self, other: &DocIdSet) {
+        let mut merged = SmallVec::with_capacity(self.0.len() + other.0.len());
+        let (mut a, mut b) = (self.0.iter().peekable(), other.0.iter().peekable());
+        while let (Some(&&x), Some(&&y)) = (a.peek(), b.peek()) {
+            if x <= y {
+                a.next();
+                if x == y {
+                    b.next();
+                }
+                merged.push(x);
+            } else {
+                b.next();
+                merged.push(y);
+            }
+        }
+        merged.extend(a.copied());
+        merged.extend(b.copied());
+        self.0 = merged;
+    }
+
+    /// Heap bytes beyond the inline buffer
+    pub fn heap_size(&self) -> usize {
+        if self.0.spilled() {
+            self.0.capacity() * std::mem::size_of::<u32>()
+        } else {
+            0
+        }
+    }
+
+    /// Resolve ids against a document table
+    pub fn names<'a>(&'a self, documents: &'a [String]) -> impl Iterator<Item = &'a String> + 'a {
+        self.iter().map(move |id| &documents[id as usize])
+    }
+
+    /// Owned document names, for APIs that still work with `HashSet<String>`
+    pub fn to_names(&self, documents: &[String]) -> HashSet<String> {
+        self.names(documents).cloned().collect()
+    }
+}
+
+impl FromIterator<u32> for DocIdSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        let mut ids: SmallVec<[u32; 4]> = iter.into_iter().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        DocIdSet(ids)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TermEntry {
     pub frequency: u32,
-    pub documents: HashSet<String>,
+    pub documents: DocIdSet,
+    /// Occurrences of the term in each document of `documents`, by document id
+    pub term_frequencies: HashMap<u32, u32>,
+}
+
+impl TermEntry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `count` more occurrences of the term in a document
+    pub fn add_occurrences(&mut self, document_id: u32, count: u32) {
+        self.frequency += count;
+        self.documents.insert(document_id);
+        *self.term_frequencies.entry(document_id).or_insert(0) += count;
+    }
+
+    /// Occurrences of the term in a document, 0 if it does not occur there
+    pub fn term_frequency(&self, document_id: u32) -> u32 {
+        self.term_frequencies.get(&document_id).copied().unwrap_or(0)
+    }
+
+    /// Add the counts of another entry for the same term
+    pub fn merge(&mut self, other: &TermEntry) {
+        self.frequency += other.frequency;
+        self.documents.union_with(&other.documents);
+        for (&document_id, &count) in &other.term_frequencies {
+            *self.term_frequencies.entry(document_id).or_insert(0) += count;
+        }
+    }
+}
+
+/// The run of a sorted term list starting with `prefix`
+pub fn prefix_range<'a>(sorted_terms: &'a [String], prefix: &str) -> &'a [String] {
+    let start = sorted_terms.partition_point(|term| term.as_str() < prefix);
+    let len = sorted_terms[start..].partition_point(|term| term.starts_with(prefix));
+    &sorted_terms[start..start + len]
+}
+
+/// Per-document (term, occurrence count) pairs of one shard
+type TermCounts = Vec<(String, u32)>;
+
+/// Borrowed dictionary entry ordered by rank: higher frequency first, ties by term
+struct Ranked<'a> {
+    term: &'a str,
+    entry: &'a TermEntry,
+}
+
+impl Ranked<'_> {
+    fn key(&self) -> (Reverse<u32>, &str) {
+        (Reverse(self.entry.frequency), self.term)
+    }
+}
+
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Ranked<'_> {}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Order borrowed entries by frequency without cloning terms. With a limit only the top `k`
+/// are kept in a bounded heap whose root is the worst candidate, so memory stays O(k).
+fn rank_by_frequency<'a, I>(entries: I, limit: Option<usize>) -> Vec<(&'a str, &'a TermEntry)>
+where
+    I: Iterator<Item = (&'a str, &'a TermEntry)>,
+{
+    let entries = entries.map(|(term, entry)| Ranked { term, entry });
+    let ranked = match limit {
+        Some(k) => {
+            let mut heap = BinaryHeap::with_capacity(k.saturating_add(1).min(1 << 16));
+            for candidate in entries {
+                if heap.len() < k {
+                    heap.push(candidate);
+                } else if heap.peek().is_some_and(|worst| candidate < *worst) {
+                    heap.pop();
+                    heap.push(candidate);
+                }
+            }
+            heap.into_sorted_vec()
+        }
+        None => {
+            let mut ranked: Vec<Ranked> = entries.collect();
+            if ranked.len() > TuningConfig::current().parallel_sort_threshold {
+                ranked.par_sort_unstable();
+            } else {
+                ranked.sort_unstable();
+            }
+            ranked
+        }
+    };
+    ranked.into_iter().map(|r| (r.term, r.entry)).collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dictionary {
     pub terms: HashMap<String, TermEntry>,
+    /// Document names indexed by the ids stored in term entries
+    pub documents: Vec<String>,
+    /// Reverse lookup for `documents`, rebuilt on demand after deserialization
+    #[serde(skip)]
+    document_ids: HashMap<String, u32>,
     pub total_words: u64,
     pub total_documents: u32,
     pub collection_size_bytes: u64,
@@ -145,6 +360,8 @@ pub struct CompressedDictionary {
     pub sorted_terms: Vec<String>,
     /// Term entries mapped by index (parallel to sorted_terms)
     pub term_entries: Vec<TermEntry>,
+    /// Document names indexed by the ids stored in term entries
+    pub documents: Vec<String>,
     /// Statistics
     pub total_words: u64,
     pub total_documents: u32,
@@ -154,32 +371,228 @@ pub struct CompressedDictionary {
     pub compressed_terms_size: usize,
 }
 
+impl Persistable for CompressedDictionary {
+    const SUFFIX: &'static str = "";
+}
+
+impl Default for Dictionary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Dictionary {
     pub fn new() -> Self {
         Dictionary {
             terms: HashMap::new(),
+            documents: Vec::new(),
+            document_ids: HashMap::new(),
             total_words: 0,
             total_documents: 0,
             collection_size_bytes: 0,
         }
     }
 
+    /// Id of a document name, registering it in the document table if it is new
+    pub fn document_id(&mut self, document: &str) -> u32 {
+        if self.document_ids.len() < self.documents.len() {
+            self.document_ids = self
+                .documents
+                .iter()
+                .enumerate()
+                .map(|(id, name)| (name.clone(), id as u32))
+                .collect();
+        }
+        if let Some(&id) = self.document_ids.get(document) {
+            return id;
+        }
+        let id = self.documents.len() as u32;
+        self.documents.push(document.to_string());
+        self.document_ids.insert(document.to_string(), id);
+        id
+    }
+
     pub fn add_term(&mut self, term: String, document: String) {
-        let entry = self.terms.entry(term).or_insert(TermEntry {
-            frequency: 0,
-            documents: HashSet::new(),
-        });
-        entry.frequency += 1;
-        entry.documents.insert(document);
+        let document_id = self.document_id(&document);
+        self.terms
+            .entry(term)
+            .or_default()
+            .add_occurrences(document_id, 1);
         self.total_words += 1;
     }
 
+    /// Occurrences of `term` in `document`
+    pub fn term_frequency(&self, term: &str, document: &str) -> u32 {
+        // The id map is empty after deserialization until a document is added
+        let id = self.document_ids.get(document).copied().or_else(|| {
+            self.documents
+                .iter()
+                .position(|name| name == document)
+                .map(|id| id as u32)
+        });
+        match (self.terms.get(term), id) {
+            (Some(entry), Some(id)) => entry.term_frequency(id),
+            _ => 0,
+        }
+    }
+
+    /// Names of the documents containing `term`
+    pub fn term_documents(&self, term: &str) -> Option<HashSet<String>> {
+        self.terms
+            .get(term)
+            .map(|entry| entry.documents.to_names(&self.documents))
+    }
+
     pub fn merge_terms(&mut self, terms: Vec<(String, String)>) {
         for (term, document) in terms {
             self.add_term(term, document);
         }
     }
 
+    /// Merge parsed documents with a hash-partitioned parallel reduce: every document's words are
+    /// counted and split into shards by term hash, then each shard is reduced on its own thread.
+    /// Shards own disjoint term sets, so combining them needs no locking.
+    pub fn merge_documents_sharded(&mut self, documents: Vec<(String, Vec<String>)>) {
+        let shard_count = (rayon::current_num_threads() * 4).max(1);
+        let hasher = RandomState::new();
+
+        let partitioned: Vec<(String, Vec<TermCounts>)> = documents
+            .into_par_iter()
+            .map(|(document, words)| {
+                let mut counts: HashMap<String, u32> = HashMap::new();
+                for word in words {
+                    *counts.entry(word).or_insert(0) += 1;
+                }
+
+                let mut shards: Vec<TermCounts> = vec![Vec::new(); shard_count];
+                for (term, count) in counts {
+                    let shard = (hasher.hash_one(&term) % shard_count as u64) as usize;
+                    shards[shard].push((term, count));
+                }
+                (document, shards)
+            })
+            .collect();
+
+        // Transpose to shard-major order so each reducer owns its input
+        let mut shard_inputs: Vec<Vec<(u32, TermCounts)>> =
+            (0..shard_count).map(|_| Vec::new()).collect();
+        for (document, shards) in partitioned {
+            let document_id = self.document_id(&document);
+            for (shard, terms) in shards.into_iter().enumerate() {
+                if !terms.is_empty() {
+                    shard_inputs[shard].push((document_id, terms));
+                }
+            }
+        }
+
+        let reduced: Vec<(HashMap<String, TermEntry>, u64)> = shard_inputs
+            .into_par_iter()
+            .map(|inputs| {
+                let mut terms: HashMap<String, TermEntry> = HashMap::new();
+                let mut words = 0u64;
+                for (document_id, doc_terms) in inputs {
+                    for (term, count) in doc_terms {
+                        terms
+                            .entry(term)
+                            .or_default()
+                            .add_occurrences(document_id, count);
+                        words += count as u64;
+                    }
+                }
+                (terms, words)
+            })
+            .collect();
+
+        for (terms, words) in reduced {
+            self.total_words += words;
+            if self.terms.is_empty() {
+                self.terms = terms;
+                continue;
+            }
+            for (term, entry) in terms {
+                match self.terms.get_mut(&term) {
+                    Some(existing) => existing.merge(&entry),
+                    None => {
+                        self.terms.insert(term, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add a document's term counts, as counted by the build pipeline's analyze stage
+    pub fn add_document_counts(&mut self, document_id: u32, counts: HashMap<String, u32>) {
+        for (term, count) in counts {
+            self.terms
+                .entry(term)
+                .or_default()
+                .add_occurrences(document_id, count);
+            self.total_words += count as u64;
+        }
+    }
+
+    /// Give every document the id `new_ids[old id]`; `new_ids` must be a permutation
+    pub fn renumber_documents(&mut self, new_ids: &[u32]) {
+        let mut documents = vec![String::new(); self.documents.len()];
+        for (old, name) in std::mem::take(&mut self.documents).into_iter().enumerate() {
+            documents[new_ids[old] as usize] = name;
+        }
+        self.documents = documents;
+        self.document_ids.clear();
+
+        for entry in self.terms.values_mut() {
+            entry.documents = entry.documents.iter().map(|id| new_ids[id as usize]).collect();
+            entry.term_frequencies = entry
+                .term_frequencies
+                .drain()
+                .map(|(id, count)| (new_ids[id as usize], count))
+                .collect();
+        }
+    }
+
+    /// Drop documents from every term entry and the document table, renumbering the rest
+    /// densely in their current order. Terms left without documents are removed. Returns the
+    /// number of documents removed; the collection size keeps their bytes, which are not
+    /// recorded per document.
+    pub fn remove_documents(&mut self, documents: &HashSet<String>) -> usize {
+        let mut new_ids = vec![None; self.documents.len()];
+        let mut kept = Vec::with_capacity(self.documents.len());
+        for (id, name) in std::mem::take(&mut self.documents).into_iter().enumerate() {
+            if !documents.contains(&name) {
+                new_ids[id] = Some(kept.len() as u32);
+                kept.push(name);
+            }
+        }
+        let removed = new_ids.len() - kept.len();
+        self.documents = kept;
+        self.document_ids.clear();
+        self.total_documents = self.total_documents.saturating_sub(removed as u32);
+
+        let mut removed_words = 0u64;
+        self.terms.retain(|_, entry| {
+            entry.documents = entry
+                .documents
+                .iter()
+                .filter_map(|id| new_ids[id as usize])
+                .collect();
+            entry.term_frequencies = entry
+                .term_frequencies
+                .drain()
+                .filter_map(|(id, count)| match new_ids[id as usize] {
+                    Some(new_id) => Some((new_id, count)),
+                    None => {
+                        entry.frequency -= count;
+                        removed_words += count as u64;
+                        None
+                    }
+                })
+                .collect();
+            !entry.documents.is_empty()
+        });
+        self.total_words -= removed_words;
+        removed
+    }
+
     pub fn add_file_stats(&mut self, file_size: u64) {
         self.collection_size_bytes += file_size;
         self.total_documents += 1;
@@ -198,9 +611,7 @@ impl Dictionary {
 
         let mut terms: Vec<String> = self.terms.keys().cloned().collect();
 
-        const CHUNK_SIZE: usize = 1000;
-
-        if terms.len() > CHUNK_SIZE {
+        if terms.len() > TuningConfig::current().parallel_threshold {
             terms.par_sort_unstable();
         } else {
             terms.sort_unstable();
@@ -222,15 +633,25 @@ impl Dictionary {
             .collect();
 
         // Sort by frequency (descending) using parallel sort for large datasets
-        if results.len() > 10000 {
-            results.par_sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        if results.len() > TuningConfig::current().parallel_sort_threshold {
+            results.par_sort_unstable_by_key(|(_, frequency)| std::cmp::Reverse(*frequency));
         } else {
-            results.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            results.sort_unstable_by_key(|(_, frequency)| std::cmp::Reverse(*frequency));
         }
 
         results
     }
 
+    /// Entries by descending frequency, borrowing terms instead of cloning them
+    pub fn iter_by_frequency(&self) -> impl Iterator<Item = (&str, &TermEntry)> {
+        rank_by_frequency(self.terms.iter().map(|(t, e)| (t.as_str(), e)), None).into_iter()
+    }
+
+    /// The `k` most frequent entries, selected with a bounded heap
+    pub fn top_by_frequency(&self, k: usize) -> Vec<(&str, &TermEntry)> {
+        rank_by_frequency(self.terms.iter().map(|(t, e)| (t.as_str(), e)), Some(k))
+    }
+
     pub fn save_as_binary(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
         let data = bincode::serialize(self)?;
         let size = data.len();
@@ -261,15 +682,12 @@ impl Dictionary {
         );
         file.write_all(header.as_bytes())?;
 
-        let sorted_terms = self.extract_terms_by_frequency_parallel();
-
-        for (term, frequency) in sorted_terms {
-            let docs_count = self.terms.get(&term).unwrap().documents.len();
+        for (term, entry) in self.iter_by_frequency() {
             let line = format!(
                 "{}: {} (docs: {})\n",
                 term,
-                frequency,
-                docs_count
+                entry.frequency,
+                entry.documents.len()
             );
             file.write_all(line.as_bytes())?;
         }
@@ -320,6 +738,7 @@ impl CompressedDictionary {
             term_offsets,
             sorted_terms,
             term_entries,
+            documents: dictionary.documents.clone(),
             total_words: dictionary.total_words,
             total_documents: dictionary.total_documents,
             collection_size_bytes: dictionary.collection_size_bytes,
@@ -328,39 +747,151 @@ impl CompressedDictionary {
         }
     }
 
-    /// Get a term by binary search in the sorted terms
+    /// Expand back into a regular dictionary, e.g. to add documents to a saved index
+    pub fn to_dictionary(&self) -> Dictionary {
+        Dictionary {
+            terms: self
+                .sorted_terms
+                .iter()
+                .cloned()
+                .zip(self.term_entries.iter().cloned())
+                .collect(),
+            documents: self.documents.clone(),
+            document_ids: HashMap::new(),
+            total_words: self.total_words,
+            total_documents: self.total_documents,
+            collection_size_bytes: self.collection_size_bytes,
+        }
+    }
+
+    /// Get a term by binary search in the front-packed terms
     pub fn get_term(&self, term: &str) -> Option<String> {
-        match self.sorted_terms.binary_search(&term.to_string()) {
-            Ok(index) => {
-                let (prefix_start, prefix_len, suffix_start, suffix_len) = self.term_offsets[index];
-
-                if suffix_start == 0 && suffix_len == 0 {
-                    // Non-compressed term: prefix_start is actually term start, prefix_len is term length
-                    Some(self.terms_string[prefix_start..prefix_start + prefix_len].to_string())
-                } else {
-                    // Front-packed term: reconstruct from prefix + suffix
-                    let prefix = &self.terms_string[prefix_start..prefix_start + prefix_len];
-                    let suffix = &self.terms_string[suffix_start..suffix_start + suffix_len];
-                    Some(format!("{}{}", prefix, suffix))
-                }
-            }
-            Err(_) => None,
+        self.binary_search_packed(term)
+            .ok()
+            .map(|index| self.packed_term(index))
+    }
+
+    /// Term at `index` of the sorted order, decoded from the front-packed string
+    pub fn packed_term(&self, index: usize) -> String {
+        let (prefix, suffix) = self.packed_parts(&self.term_offsets[index]);
+        format!("{}{}", prefix, suffix)
+    }
+
+    /// Binary search for `term` like `sorted_terms.binary_search`, comparing against terms
+    /// decoded from `terms_string` and `term_offsets` only, without allocating. Returns the
+    /// index of the term or where it would be inserted.
+    pub fn binary_search_packed(&self, term: &str) -> Result<usize, usize> {
+        let term = term.as_bytes();
+        self.term_offsets.binary_search_by(|offsets| {
+            let (prefix, suffix) = self.packed_parts(offsets);
+            let split = prefix.len().min(term.len());
+            // A prefix longer than `term` never compares equal, so the suffix only matters
+            // once all of the prefix matched
+            prefix
+                .as_bytes()
+                .cmp(&term[..split])
+                .then_with(|| suffix.as_bytes().cmp(&term[split..]))
+        })
+    }
+
+    /// Shared prefix and own suffix of a term; unpacked terms are all prefix
+    fn packed_parts(&self, offsets: &(usize, usize, usize, usize)) -> (&str, &str) {
+        let &(prefix_start, prefix_len, suffix_start, suffix_len) = offsets;
+        let prefix = &self.terms_string[prefix_start..prefix_start + prefix_len];
+        if suffix_start == 0 && suffix_len == 0 {
+            // Non-compressed term: prefix_start is actually term start, prefix_len is term length
+            (prefix, "")
+        } else {
+            (
+                prefix,
+                &self.terms_string[suffix_start..suffix_start + suffix_len],
+            )
         }
     }
 
     /// Check if a term exists using binary search
     pub fn contains_term(&self, term: &str) -> bool {
-        self.sorted_terms.binary_search(&term.to_string()).is_ok()
+        self.binary_search_packed(term).is_ok()
     }
 
     /// Get term entry by binary search
     pub fn get_term_entry(&self, term: &str) -> Option<&TermEntry> {
-        match self.sorted_terms.binary_search(&term.to_string()) {
+        match self.binary_search_packed(term) {
             Ok(index) => self.term_entries.get(index),
             Err(_) => None,
         }
     }
 
+    /// Terms starting with `prefix`, located by binary search
+    pub fn prefix_range(&self, prefix: &str) -> &[String] {
+        prefix_range(&self.sorted_terms, prefix)
+    }
+
+    /// Approximate hit count of a Boolean query from document frequencies, see `estimate_hits`
+    pub fn estimate(&self, query: &str) -> Result<usize, String> {
+        let document_frequency = |term: &str| match self.get_term_entry(term) {
+            Some(entry) => entry.documents.len(),
+            None => 0,
+        };
+        estimate_hits(query, self.documents.len(), |term| {
+            Ok(match wildcard_prefix(term) {
+                Some(prefix) => union_frequency(
+                    self.prefix_range(prefix)
+                        .iter()
+                        .map(|term| document_frequency(term)),
+                    self.documents.len(),
+                ),
+                None => document_frequency(term),
+            })
+        })
+    }
+
+    /// Names of the documents containing `term`
+    pub fn term_documents(&self, term: &str) -> Option<HashSet<String>> {
+        self.get_term_entry(term)
+            .map(|entry| entry.documents.to_names(&self.documents))
+    }
+
+    /// Occurrences of `term` in the document with id `document_id`
+    pub fn term_frequency(&self, term: &str, document_id: u32) -> u32 {
+        self.get_term_entry(term)
+            .map_or(0, |entry| entry.term_frequency(document_id))
+    }
+
+    /// Document name for an id stored in a term entry
+    pub fn document_name(&self, id: u32) -> &str {
+        &self.documents[id as usize]
+    }
+
+    /// Drop documents no term refers to and renumber the rest densely, keeping name order
+    pub fn optimize(&mut self) {
+        let mut referenced = vec![false; self.documents.len()];
+        for entry in &self.term_entries {
+            for id in entry.documents.iter() {
+                referenced[id as usize] = true;
+            }
+        }
+
+        let mut remap = vec![0u32; self.documents.len()];
+        let mut documents = Vec::new();
+        for (id, name) in std::mem::take(&mut self.documents).into_iter().enumerate() {
+            if referenced[id] {
+                remap[id] = documents.len() as u32;
+                documents.push(name);
+            }
+        }
+        self.documents = documents;
+
+        for entry in &mut self.term_entries {
+            entry.documents = entry.documents.iter().map(|id| remap[id as usize]).collect();
+            entry.term_frequencies = entry
+                .term_frequencies
+                .drain()
+                .map(|(id, count)| (remap[id as usize], count))
+                .collect();
+        }
+    }
+
     /// Get compression statistics
     pub fn compression_stats(&self) -> (usize, usize, f64) {
         let ratio = if self.original_terms_size > 0 {
@@ -395,15 +926,32 @@ impl CompressedDictionary {
             .collect();
 
         // Use parallel sort for large datasets
-        if term_frequencies.len() > 10000 {
-            term_frequencies.par_sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        if term_frequencies.len() > TuningConfig::current().parallel_sort_threshold {
+            term_frequencies.par_sort_unstable_by_key(|(_, frequency)| std::cmp::Reverse(*frequency));
         } else {
-            term_frequencies.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            term_frequencies.sort_unstable_by_key(|(_, frequency)| std::cmp::Reverse(*frequency));
         }
 
         term_frequencies
     }
 
+    /// Entries by descending frequency, borrowing terms instead of cloning them
+    pub fn iter_by_frequency(&self) -> impl Iterator<Item = (&str, &TermEntry)> {
+        rank_by_frequency(self.entries(), None).into_iter()
+    }
+
+    /// The `k` most frequent entries, selected with a bounded heap
+    pub fn top_by_frequency(&self, k: usize) -> Vec<(&str, &TermEntry)> {
+        rank_by_frequency(self.entries(), Some(k))
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&str, &TermEntry)> {
+        self.sorted_terms
+            .iter()
+            .map(String::as_str)
+            .zip(self.term_entries.iter())
+    }
+
     /// Memory size of the compressed dictionary
     pub fn memory_size(&self) -> usize {
         std::mem::size_of::<Self>()
@@ -412,8 +960,10 @@ impl CompressedDictionary {
             + self.sorted_terms.iter().map(|s| s.len()).sum::<usize>()
             + self.term_entries.iter().map(|entry| {
                 std::mem::size_of::<TermEntry>()
-                    + entry.documents.len() * (std::mem::size_of::<String>() + 16)
+                    + entry.documents.heap_size()
+                    + entry.term_frequencies.capacity() * std::mem::size_of::<(u32, u32)>()
             }).sum::<usize>()
+            + self.documents.iter().map(|d| d.len()).sum::<usize>()
     }
 
     /// Save compressed dictionary as JSON
@@ -424,9 +974,9 @@ impl CompressedDictionary {
         Ok(size)
     }
 
-    /// Save compressed dictionary as binary
+    /// Save compressed dictionary as binary, in the versioned layout `Persistable` reads
     pub fn save_as_binary(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
-        let data = bincode::serialize(self)?;
+        let data = self.to_bytes()?;
         let size = data.len();
         fs::write(path, data)?;
         Ok(size)
@@ -462,10 +1012,8 @@ impl CompressedDictionary {
         );
         file.write_all(header.as_bytes())?;
 
-        let sorted_terms = self.extract_terms_by_frequency_parallel();
-
-        for (term, frequency) in sorted_terms.iter().take(1000) { // Limit output for readability
-            let line = format!("{}: {}\n", term, frequency);
+        for (term, entry) in self.top_by_frequency(1000) { // Limit output for readability
+            let line = format!("{}: {}\n", term, entry.frequency);
             file.write_all(line.as_bytes())?;
         }
 
@@ -473,6 +1021,43 @@ impl CompressedDictionary {
     }
 }
 
+/// Term and document arrays shared by both dictionaries, so structures can be built from
+/// either one with index math on document ids instead of name lookups
+pub trait CorpusSource {
+    /// Document names indexed by the ids stored in term entries
+    fn document_names(&self) -> &[String];
+
+    /// Every term with its entry, sorted by term
+    fn sorted_entries(&self) -> Vec<(&str, &TermEntry)>;
+}
+
+impl CorpusSource for Dictionary {
+    fn document_names(&self) -> &[String] {
+        &self.documents
+    }
+
+    fn sorted_entries(&self) -> Vec<(&str, &TermEntry)> {
+        let mut entries: Vec<(&str, &TermEntry)> =
+            self.terms.iter().map(|(t, e)| (t.as_str(), e)).collect();
+        if entries.len() > TuningConfig::current().parallel_threshold {
+            entries.par_sort_unstable_by_key(|(term, _)| *term);
+        } else {
+            entries.sort_unstable_by_key(|(term, _)| *term);
+        }
+        entries
+    }
+}
+
+impl CorpusSource for CompressedDictionary {
+    fn document_names(&self) -> &[String] {
+        &self.documents
+    }
+
+    fn sorted_entries(&self) -> Vec<(&str, &TermEntry)> {
+        self.entries().collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -549,7 +1134,7 @@ mod tests {
         assert!(!concatenated_string.is_empty());
 
         // Verify we can extract terms using offsets
-        for (_, &(prefix_start, prefix_len, suffix_start, suffix_len)) in term_offsets.iter().enumerate() {
+        for &(prefix_start, prefix_len, suffix_start, suffix_len) in term_offsets.iter() {
             let extracted_term = if suffix_start == 0 && suffix_len == 0 {
                 // Non-compressed term
                 concatenated_string[prefix_start..prefix_start + prefix_len].to_string()
@@ -592,4 +1177,176 @@ mod tests {
             assert!(freq_terms[i-1].1 >= freq_terms[i].1, "Frequencies should be sorted descending");
         }
     }
+
+    #[test]
+    fn test_top_by_frequency_matches_full_order() {
+        let mut dict = Dictionary::new();
+        for i in 0..200 {
+            for _ in 0..(i % 17) {
+                dict.add_term(format!("term{:03}", i), "doc1".to_string());
+            }
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+
+        let full: Vec<(&str, u32)> = compressed
+            .iter_by_frequency()
+            .map(|(term, entry)| (term, entry.frequency))
+            .collect();
+        let top: Vec<(&str, u32)> = compressed
+            .top_by_frequency(25)
+            .into_iter()
+            .map(|(term, entry)| (term, entry.frequency))
+            .collect();
+
+        assert_eq!(full.len(), compressed.dictionary_size());
+        assert_eq!(top, full[..25]);
+        assert_eq!(top[0], ("term016", 16));
+        assert_eq!(dict.top_by_frequency(25).len(), 25);
+        assert!(compressed.top_by_frequency(0).is_empty());
+    }
+
+    #[test]
+    fn test_sharded_merge_matches_sequential() {
+        let documents = vec![
+            ("a.fb2".to_string(), vec!["war", "peace", "war"]),
+            ("b.fb2".to_string(), vec!["peace", "love"]),
+            ("c.fb2".to_string(), vec![]),
+        ];
+
+        let mut sequential = Dictionary::new();
+        for (document, words) in &documents {
+            for word in words {
+                sequential.add_term(word.to_string(), document.clone());
+            }
+        }
+
+        let mut sharded = Dictionary::new();
+        sharded.add_term("war".to_string(), "existing.fb2".to_string());
+        sharded.merge_documents_sharded(
+            documents
+                .into_iter()
+                .map(|(d, words)| (d, words.into_iter().map(String::from).collect()))
+                .collect(),
+        );
+
+        assert_eq!(sharded.total_words, sequential.total_words + 1);
+        assert_eq!(sharded.terms.len(), sequential.terms.len());
+        assert_eq!(sharded.terms["war"].frequency, 3);
+        assert_eq!(sharded.terms["war"].documents.len(), 2);
+        assert_eq!(
+            sharded.term_documents("peace"),
+            sequential.term_documents("peace")
+        );
+        assert_eq!(sequential.term_frequency("war", "a.fb2"), 2);
+        assert_eq!(sharded.term_frequency("war", "a.fb2"), 2);
+        assert_eq!(sharded.term_frequency("war", "existing.fb2"), 1);
+        assert_eq!(sharded.term_frequency("war", "b.fb2"), 0);
+
+        // Counts follow the ids renumbered by optimize
+        let mut compressed = CompressedDictionary::from_dictionary(&sharded);
+        compressed.optimize();
+        let id = compressed.documents.iter().position(|d| d == "a.fb2").unwrap() as u32;
+        assert_eq!(compressed.term_frequency("war", id), 2);
+        assert_eq!(compressed.term_frequency("love", id), 0);
+    }
+
+    #[test]
+    fn test_doc_id_set_stays_sorted() {
+        let mut dict = Dictionary::new();
+        for doc in ["b.fb2", "a.fb2", "b.fb2", "c.fb2"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        dict.add_term("peace".to_string(), "c.fb2".to_string());
+
+        let entry = &dict.terms["war"];
+        assert_eq!(entry.frequency, 4);
+        assert_eq!(entry.documents.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(entry.documents.heap_size(), 0);
+
+        let mut ids: DocIdSet = [5, 1].into_iter().collect();
+        ids.union_with(&entry.documents);
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![0, 1, 2, 5]);
+
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let peace = compressed.get_term_entry("peace").unwrap();
+        assert_eq!(compressed.document_name(peace.documents.iter().next().unwrap()), "c.fb2");
+    }
+
+    #[test]
+    fn test_binary_search_packed_matches_sorted_terms() {
+        let mut dict = Dictionary::new();
+        for term in [
+            "inform", "informed", "information", "informal", "компьютер", "компания", "компот",
+            "war", "warm", "ward", "zebra", "ёж", "еж",
+        ] {
+            dict.add_term(term.to_string(), "a.fb2".to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        assert!(compressed.compressed_terms_size < compressed.original_terms_size);
+
+        for (index, term) in compressed.sorted_terms.iter().enumerate() {
+            assert_eq!(compressed.binary_search_packed(term), Ok(index));
+            assert_eq!(compressed.packed_term(index), *term);
+        }
+        for missing in ["", "a", "info", "informa", "informedx", "wa", "warz", "комп", "яблоко"] {
+            assert_eq!(
+                compressed.binary_search_packed(missing),
+                compressed.sorted_terms.binary_search(&missing.to_string()),
+                "{}",
+                missing
+            );
+        }
+        assert_eq!(compressed.get_term("компот").as_deref(), Some("компот"));
+        assert!(!compressed.contains_term("комп"));
+    }
+
+    #[test]
+    fn test_optimize_renumbers_documents_and_keeps_lookups() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "war peace war"),
+            ("b.fb2", "war"),
+            ("c.fb2", "peace forest forest"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let mut compressed = CompressedDictionary::from_dictionary(&dict);
+        // Forget b.fb2 in every entry but keep its name, as removing its postings would
+        let b = compressed.documents.iter().position(|d| d == "b.fb2").unwrap() as u32;
+        for entry in &mut compressed.term_entries {
+            entry.documents = entry.documents.iter().filter(|&id| id != b).collect();
+            entry.term_frequencies.remove(&b);
+        }
+
+        let lookups = |dictionary: &CompressedDictionary| -> Vec<(String, Vec<(String, u32)>)> {
+            dictionary
+                .sorted_terms
+                .iter()
+                .map(|term| {
+                    let entry = dictionary.get_term_entry(term).unwrap();
+                    let mut documents: Vec<(String, u32)> = entry
+                        .documents
+                        .iter()
+                        .map(|id| {
+                            let name = dictionary.document_name(id).to_string();
+                            (name, dictionary.term_frequency(term, id))
+                        })
+                        .collect();
+                    documents.sort();
+                    (term.clone(), documents)
+                })
+                .collect()
+        };
+        let before = lookups(&compressed);
+        compressed.optimize();
+
+        assert_eq!(compressed.documents, ["a.fb2", "c.fb2"]);
+        assert_eq!(lookups(&compressed), before);
+        assert_eq!(
+            compressed.term_documents("war").unwrap(),
+            HashSet::from(["a.fb2".to_string()])
+        );
+    }
 }
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use crate::tfidf::{cosine_similarity, idf, normalize, tf_weight, SparseVector};
+use crate::CoordinateIndex;
+
+/// TF-IDF vectors for the given documents, built from coordinate index term frequencies
+pub fn document_vectors(
+    index: &CoordinateIndex,
+    documents: &[&str],
+) -> HashMap<String, SparseVector> {
+    let mut vectors: HashMap<String, SparseVector> = documents
+        .iter()
+        .map(|document| (document.to_string(), SparseVector::new()))
+        .collect();
+    let total_documents = index.documents.len();
+
+    for (term, postings) in &index.index {
+        let term_idf = idf(total_documents, postings.len());
+        for posting in postings {
+            if let Some(vector) = vectors.get_mut(&posting.document) {
+                let weight = tf_weight(posting.positions.len() as u32) * term_idf;
+                vector.insert(term.clone(), weight);
+            }
+        }
+    }
+
+    for vector in vectors.values_mut() {
+        normalize(vector);
+    }
+    vectors
+}
+
+/// Maximal Marginal Relevance re-ranking of the first `top_n` results.
+///
+/// Each step picks the document maximizing
+/// `lambda * relevance - (1 - lambda) * max similarity to already selected documents`,
+/// with relevance min-max normalized to [0, 1]. Results past `top_n` keep their order.
+pub fn mmr_rerank<'d>(
+    ranked: &[(&'d str, f64)],
+    vectors: &HashMap<String, SparseVector>,
+    lambda: f64,
+    top_n: usize,
+) -> Vec<(&'d str, f64)> {
+    let lambda = lambda.clamp(0.0, 1.0);
+    let split = ranked.len().min(top_n);
+    let (head, tail) = ranked.split_at(split);
+
+    let max_score = head.iter().map(|r| r.1).fold(f64::NEG_INFINITY, f64::max);
+    let min_score = head.iter().map(|r| r.1).fold(f64::INFINITY, f64::min);
+    let relevance = |score: f64| {
+        if max_score > min_score {
+            (score - min_score) / (max_score - min_score)
+        } else {
+            1.0
+        }
+    };
+
+    let empty = SparseVector::new();
+    let vector = |document: &str| vectors.get(document).unwrap_or(&empty);
+
+    let mut remaining: Vec<(&str, f64)> = head.to_vec();
+    let mut selected: Vec<(&str, f64)> = Vec::with_capacity(split);
+
+    while !remaining.is_empty() {
+        let (best_idx, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(i, &(document, score))| {
+                let redundancy = selected
+                    .iter()
+                    .map(|(chosen, _)| cosine_similarity(vector(document), vector(chosen)))
+                    .fold(0.0, f64::max);
+                (i, lambda * relevance(score) - (1.0 - lambda) * redundancy)
+            })
+            // Earlier (higher ranked) candidates win ties
+            .fold((0, f64::NEG_INFINITY), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            });
+        selected.push(remaining.remove(best_idx));
+    }
+
+    selected.extend_from_slice(tail);
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(terms: &[(&str, f64)]) -> SparseVector {
+        let mut vector: SparseVector = terms.iter().map(|(t, w)| (t.to_string(), *w)).collect();
+        normalize(&mut vector);
+        vector
+    }
+
+    #[test]
+    fn test_near_duplicates_are_pushed_down() {
+        let mut vectors = HashMap::new();
+        vectors.insert("a".to_string(), vector(&[("war", 1.0), ("peace", 1.0)]));
+        vectors.insert(
+            "a_copy".to_string(),
+            vector(&[("war", 1.0), ("peace", 1.0)]),
+        );
+        vectors.insert("b".to_string(), vector(&[("war", 1.0), ("love", 1.0)]));
+        let ranked = vec![("a", -1.0), ("a_copy", -1.1), ("b", -1.5), ("c", -9.0)];
+
+        let reranked = mmr_rerank(&ranked, &vectors, 0.3, 3);
+        let order: Vec<&str> = reranked.iter().map(|r| r.0).collect();
+        assert_eq!(order, vec!["a", "b", "a_copy", "c"]);
+
+        // lambda = 1 is pure relevance ordering
+        let unchanged = mmr_rerank(&ranked, &vectors, 1.0, 3);
+        assert_eq!(unchanged, ranked);
+    }
+}
@@ -0,0 +1,170 @@
+use std::cmp::Ordering;
+
+use crate::codec::PostingIterator;
+
+/// A list this many times longer than the other is galloped through instead of merged
+const GALLOP_RATIO: usize = 8;
+
+/// Ids present in both sorted, deduplicated lists. Lists of similar length are merged; a
+/// much longer list is galloped through, so a rare term intersected with a common one costs
+/// O(short * log(long / short)) instead of O(long).
+pub fn intersect_doc_ids(left: &[u32], right: &[u32]) -> Vec<u32> {
+    let (short, long) = if left.len() <= right.len() {
+        (left, right)
+    } else {
+        (right, left)
+    };
+    if short.is_empty() {
+        Vec::new()
+    } else if long.len() / short.len() >= GALLOP_RATIO {
+        gallop_intersect(short, long)
+    } else {
+        merge_intersect(short, long)
+    }
+}
+
+/// Ids present in either sorted, deduplicated list
+pub fn unite_doc_ids(left: &[u32], right: &[u32]) -> Vec<u32> {
+    let mut united = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        match left[i].cmp(&right[j]) {
+            Ordering::Less => {
+                united.push(left[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                united.push(right[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                united.push(left[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    united.extend_from_slice(&left[i..]);
+    united.extend_from_slice(&right[j..]);
+    united
+}
+
+/// Ids of `left` missing from `right`, both sorted and deduplicated
+pub fn subtract_doc_ids(left: &[u32], right: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(left.len());
+    let mut excluded = right.iter().peekable();
+    for &id in left {
+        while excluded.next_if(|&&excluded| excluded < id).is_some() {}
+        if excluded.peek() != Some(&&id) {
+            difference.push(id);
+        }
+    }
+    difference
+}
+
+/// Ids below `total` missing from the sorted, deduplicated `operand`
+pub fn complement_doc_ids(operand: &[u32], total: u32) -> Vec<u32> {
+    let mut complement = Vec::with_capacity((total as usize).saturating_sub(operand.len()));
+    let mut excluded = operand.iter().peekable();
+    for id in 0..total {
+        if excluded.next_if_eq(&&id).is_none() {
+            complement.push(id);
+        }
+    }
+    complement
+}
+
+/// Ids of the sorted, deduplicated `doc_ids` that `postings` holds. The posting list is
+/// skipped through from one id to the next and left undecoded past the last of them.
+pub fn intersect_postings(doc_ids: &[u32], mut postings: PostingIterator) -> Vec<u32> {
+    let mut common = Vec::with_capacity(doc_ids.len());
+    let mut current = None;
+    for &id in doc_ids {
+        if current.is_none_or(|current| current < id) {
+            current = postings.advance_to(id);
+        }
+        match current {
+            None => break,
+            Some(current) if current == id => common.push(id),
+            Some(_) => {}
+        }
+    }
+    common
+}
+
+fn merge_intersect(left: &[u32], right: &[u32]) -> Vec<u32> {
+    let mut common = Vec::with_capacity(left.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        match left[i].cmp(&right[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                common.push(left[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    common
+}
+
+fn gallop_intersect(short: &[u32], long: &[u32]) -> Vec<u32> {
+    let mut common = Vec::with_capacity(short.len());
+    let mut rest = long;
+    for &id in short {
+        rest = &rest[gallop(rest, id)..];
+        match rest.first() {
+            None => break,
+            Some(&first) if first == id => {
+                common.push(id);
+                rest = &rest[1..];
+            }
+            Some(_) => {}
+        }
+    }
+    common
+}
+
+/// Index of the first id of `sorted` not below `target`: probe 1, 2, 4, ... ids ahead, then
+/// binary search the last step, so the cost grows with the distance skipped, not the length
+fn gallop(sorted: &[u32], target: u32) -> usize {
+    let mut bound = 1;
+    while bound <= sorted.len() && sorted[bound - 1] < target {
+        bound *= 2;
+    }
+    let start = bound / 2;
+    let end = bound.min(sorted.len());
+    start + sorted[start..end].partition_point(|&id| id < target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_and_gallop_agree() {
+        let common: Vec<u32> = (0..1000).step_by(3).collect();
+        let rare = [0, 2, 3, 299, 300, 301, 999, 2000];
+        assert_eq!(intersect_doc_ids(&rare, &common), [0, 3, 300, 999]);
+        assert_eq!(
+            gallop_intersect(&rare, &common),
+            merge_intersect(&rare, &common)
+        );
+        let odd: Vec<u32> = (1..1000).step_by(2).collect();
+        assert_eq!(
+            intersect_doc_ids(&odd, &common),
+            (3..1000).step_by(6).collect::<Vec<u32>>()
+        );
+        assert!(intersect_doc_ids(&[], &common).is_empty());
+
+        assert_eq!(unite_doc_ids(&[1, 4, 7], &[2, 4, 9]), [1, 2, 4, 7, 9]);
+        assert_eq!(complement_doc_ids(&[0, 2, 5], 6), [1, 3, 4]);
+        assert_eq!(complement_doc_ids(&[], 3), [0, 1, 2]);
+        assert_eq!(subtract_doc_ids(&[1, 4, 7, 9], &[0, 4, 5, 9, 12]), [1, 7]);
+
+        let bytes = crate::PostingEncoding::FrameOfReference.encode(&common);
+        let postings = crate::PostingEncoding::FrameOfReference.postings(&bytes);
+        assert_eq!(intersect_postings(&rare, postings), [0, 3, 300, 999]);
+    }
+}
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::persist::Persistable;
+use crate::ForwardIndex;
+
+/// Language of a document, from its FB2 `<lang>` or guessed from the letters of its words
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Language {
+    Russian,
+    Ukrainian,
+    English,
+    Unknown,
+}
+
+impl Language {
+    /// Language of an FB2 `<lang>` code such as `ru` or `uk-UA`
+    pub fn from_code(code: &str) -> Self {
+        match code.split(['-', '_']).next().unwrap_or("") {
+            "ru" => Language::Russian,
+            "uk" | "ua" => Language::Ukrainian,
+            "en" => Language::English,
+            _ => Language::Unknown,
+        }
+    }
+
+    /// Guess from letters only one of the languages uses: `і ї є ґ` for Ukrainian,
+    /// `ы э ъ ё` for Russian; other Cyrillic text counts as Russian and Latin text as English
+    pub fn detect<'a, I>(words: I) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let (mut ukrainian, mut russian, mut cyrillic, mut latin) = (0usize, 0, 0, 0);
+        for word in words {
+            if word.contains(['і', 'ї', 'є', 'ґ']) {
+                ukrainian += 1;
+            } else if word.contains(['ы', 'э', 'ъ', 'ё']) {
+                russian += 1;
+            }
+            match word.chars().next() {
+                Some(c) if c.is_ascii_alphabetic() => latin += 1,
+                Some(_) => cyrillic += 1,
+                None => {}
+            }
+        }
+        if cyrillic == 0 && latin == 0 {
+            Language::Unknown
+        } else if latin > cyrillic {
+            Language::English
+        } else if ukrainian > russian {
+            Language::Ukrainian
+        } else {
+            Language::Russian
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            Language::Russian => "ru",
+            Language::Ukrainian => "uk",
+            Language::English => "en",
+            Language::Unknown => "unknown",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/// Length and language of one document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentNorm {
+    /// Indexed body words, the positions the coordinate index assigns
+    pub length: u32,
+    /// Indexed words of the book title
+    pub title_length: u32,
+    pub language: Language,
+}
+
+impl DocumentNorm {
+    /// Norm of a document with body `words`, `title_length` title words and the `<lang>` code
+    /// it declares, if any; the language is guessed from the words when none is known
+    pub fn measure<'a, I>(words: I, title_length: usize, declared: Option<&str>) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+        I::IntoIter: Clone,
+    {
+        let words = words.into_iter();
+        let language = declared
+            .map(Language::from_code)
+            .filter(|language| *language != Language::Unknown)
+            .unwrap_or_else(|| Language::detect(words.clone()));
+        DocumentNorm {
+            length: words.count() as u32,
+            title_length: title_length as u32,
+            language,
+        }
+    }
+}
+
+/// Per-document norms recorded at build time, so rankers get document and average field
+/// lengths without summing posting lists at query time
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentNorms {
+    pub documents: HashMap<String, DocumentNorm>,
+    pub total_length: u64,
+    pub total_title_length: u64,
+}
+
+impl Persistable for DocumentNorms {
+    const SUFFIX: &'static str = "_norms";
+}
+
+impl DocumentNorms {
+    /// Norms of every document of a forward index, whose tokens are the body words.
+    /// `metadata` gives the title length and declared `<lang>` code of a document.
+    pub fn from_forward_index<F>(index: &ForwardIndex, metadata: F) -> Self
+    where
+        F: Fn(&str) -> (usize, Option<String>),
+    {
+        let mut norms = DocumentNorms::default();
+        for (document, spans) in &index.documents {
+            let (title_length, declared) = metadata(document);
+            let words = spans
+                .iter()
+                .map(|span| index.terms[span.term as usize].as_str());
+            norms.insert(
+                document,
+                DocumentNorm::measure(words, title_length, declared.as_deref()),
+            );
+        }
+        norms
+    }
+
+    /// Add or replace the norm of a document
+    pub fn insert(&mut self, document: &str, norm: DocumentNorm) {
+        if let Some(old) = self.documents.insert(document.to_string(), norm) {
+            self.total_length -= old.length as u64;
+            self.total_title_length -= old.title_length as u64;
+        }
+        self.total_length += norm.length as u64;
+        self.total_title_length += norm.title_length as u64;
+    }
+
+    pub fn remove(&mut self, document: &str) -> Option<DocumentNorm> {
+        let norm = self.documents.remove(document)?;
+        self.total_length -= norm.length as u64;
+        self.total_title_length -= norm.title_length as u64;
+        Some(norm)
+    }
+
+    pub fn get(&self, document: &str) -> Option<&DocumentNorm> {
+        self.documents.get(document)
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Body length of `document`, 0 if it is not recorded
+    pub fn length(&self, document: &str) -> usize {
+        self.get(document).map_or(0, |norm| norm.length as usize)
+    }
+
+    pub fn average_length(&self) -> f64 {
+        average(self.total_length, self.len())
+    }
+
+    pub fn average_title_length(&self) -> f64 {
+        average(self.total_title_length, self.len())
+    }
+
+    /// BM25 length normalization `1 - b + b * |d| / avgdl` of `document`
+    pub fn length_norm(&self, document: &str, b: f64) -> f64 {
+        let average = self.average_length();
+        if average == 0.0 {
+            return 1.0;
+        }
+        1.0 - b + b * self.length(document) as f64 / average
+    }
+
+    /// Body length of every document, as `CoordinateIndex::document_lengths` counts them
+    pub fn document_lengths(&self) -> HashMap<&str, usize> {
+        self.documents
+            .iter()
+            .map(|(document, norm)| (document.as_str(), norm.length as usize))
+            .collect()
+    }
+
+    /// Number of documents in each language
+    pub fn languages(&self) -> Vec<(Language, usize)> {
+        let mut counts: HashMap<Language, usize> = HashMap::new();
+        for norm in self.documents.values() {
+            *counts.entry(norm.language).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(Language, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| {
+            b.1.cmp(&a.1)
+                .then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+        });
+        counts
+    }
+}
+
+fn average(total: u64, count: usize) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FB2Parser;
+    use std::fs;
+
+    #[test]
+    fn test_norms_keep_lengths_and_languages() {
+        assert_eq!(Language::from_code("uk-UA"), Language::Ukrainian);
+        assert_eq!(Language::from_code("de"), Language::Unknown);
+        assert_eq!(
+            Language::detect(["він", "пішов", "додому"]),
+            Language::Ukrainian
+        );
+        assert_eq!(
+            Language::detect(["мы", "пошли", "домой"]),
+            Language::Russian
+        );
+        assert_eq!(Language::detect(["war", "and", "мир"]), Language::English);
+        assert_eq!(Language::detect([]), Language::Unknown);
+
+        let mut norms = DocumentNorms::default();
+        let norm = |length, language| DocumentNorm {
+            length,
+            title_length: 2,
+            language,
+        };
+        norms.insert("a.fb2", norm(100, Language::Russian));
+        norms.insert("b.fb2", norm(300, Language::Russian));
+        norms.insert("c.fb2", norm(50, Language::English));
+        norms.insert("c.fb2", norm(200, Language::English));
+        assert_eq!(norms.average_length(), 200.0);
+        assert_eq!(norms.average_title_length(), 2.0);
+        assert_eq!(norms.length_norm("b.fb2", 0.75), 1.375);
+        assert_eq!(norms.length_norm("missing.fb2", 0.0), 1.0);
+        assert_eq!(
+            norms.languages(),
+            vec![(Language::Russian, 2), (Language::English, 1)]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let book = dir.path().join("book.fb2");
+        fs::write(
+            &book,
+            "<FictionBook><description><title-info><book-title>Тихий Дон</book-title>\
+             <lang>uk</lang></title-info></description><body><p>мир</p></body></FictionBook>",
+        )
+        .unwrap();
+        let parser = FB2Parser::new();
+        assert_eq!(parser.parse_title(&book).unwrap(), ["тихий", "дон"]);
+        assert_eq!(parser.parse_language(&book).unwrap().as_deref(), Some("uk"));
+
+        assert!(DocumentNorms::load_if_present(dir.path(), "idx")
+            .unwrap()
+            .is_none());
+        norms.save(dir.path(), "idx").unwrap();
+        let loaded = DocumentNorms::load(dir.path(), "idx").unwrap();
+        assert_eq!(loaded.documents, norms.documents);
+        assert_eq!(loaded.total_length, 600);
+    }
+}
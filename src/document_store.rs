@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::persist::Persistable;
+use crate::BookMetadata;
+
+/// What the store keeps of one document
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredDocument {
+    /// File name, the key every other structure uses
+    pub name: String,
+    /// `<book-title>` as written
+    pub title: Option<String>,
+    /// Full names of the `<author>`s of `<title-info>`
+    pub authors: Vec<String>,
+    /// Lowercased genre codes
+    pub genres: Vec<String>,
+    /// Text of the `<annotation>`
+    pub annotation: Option<String>,
+    /// Path of the file the document was indexed from
+    pub path: String,
+    /// Indexed body words
+    pub length: u32,
+    /// Body as `FB2Parser::parse_body` gives it, compressed with `DocumentStore::compress`;
+    /// kept only by stores built with texts
+    pub body: Option<Vec<u8>>,
+}
+
+impl StoredDocument {
+    /// The document `name`, indexed from `path` with `length` body words, described by the
+    /// metadata of its `<title-info>`
+    pub fn new(
+        name: &str,
+        path: &Path,
+        metadata: BookMetadata,
+        length: u32,
+        body: Option<Vec<u8>>,
+    ) -> Self {
+        StoredDocument {
+            name: name.to_string(),
+            title: metadata.title,
+            authors: metadata.authors,
+            genres: metadata.genres,
+            annotation: metadata.annotation,
+            path: path.to_string_lossy().to_string(),
+            length,
+            body,
+        }
+    }
+
+    /// The decompressed body, if it was stored
+    pub fn text(&self) -> Option<String> {
+        let bytes = lz4_flex::decompress_size_prepended(self.body.as_ref()?).ok()?;
+        String::from_utf8(bytes).ok()
+    }
+
+    /// `Title — Author, Author`, with the file name standing in for a missing title
+    pub fn heading(&self) -> String {
+        let title = self.title.as_deref().unwrap_or(&self.name);
+        if self.authors.is_empty() {
+            title.to_string()
+        } else {
+            format!("{} — {}", title, self.authors.join(", "))
+        }
+    }
+}
+
+/// Per-document fields kept next to the index so results can be shown by title and author,
+/// and snippets cut without the source files at hand. Ids are positions in the store: a build
+/// stores documents in the order of the dictionary's document table, so the two agree until
+/// documents are updated or compacted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentStore {
+    documents: Vec<StoredDocument>,
+    ids: HashMap<String, u32>,
+    /// Whether documents are stored with their bodies
+    keeps_bodies: bool,
+}
+
+impl Persistable for DocumentStore {
+    const SUFFIX: &'static str = "_store";
+}
+
+impl DocumentStore {
+    pub fn new(keeps_bodies: bool) -> Self {
+        DocumentStore {
+            keeps_bodies,
+            ..Self::default()
+        }
+    }
+
+    pub fn keeps_bodies(&self) -> bool {
+        self.keeps_bodies
+    }
+
+    /// Text in the stored form, so documents can be compressed in parallel and inserted after
+    pub fn compress(text: &str) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(text.as_bytes())
+    }
+
+    /// Add a document, or replace the one with its name in place, returning its id. The body
+    /// is dropped unless the store keeps bodies.
+    pub fn insert(&mut self, mut document: StoredDocument) -> u32 {
+        if !self.keeps_bodies {
+            document.body = None;
+        }
+        if let Some(&id) = self.ids.get(&document.name) {
+            self.documents[id as usize] = document;
+            return id;
+        }
+        let id = self.documents.len() as u32;
+        self.ids.insert(document.name.clone(), id);
+        self.documents.push(document);
+        id
+    }
+
+    pub fn get(&self, id: u32) -> Option<&StoredDocument> {
+        self.documents.get(id as usize)
+    }
+
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn document(&self, name: &str) -> Option<&StoredDocument> {
+        self.get(self.id(name)?)
+    }
+
+    /// Body of the document named `name`
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.document(name)?.text()
+    }
+
+    /// The heading of the document named `name` followed by the name, or the name alone when
+    /// neither its title nor its authors are known
+    pub fn describe(&self, name: &str) -> String {
+        match self.document(name) {
+            Some(document) if document.title.is_some() || !document.authors.is_empty() => {
+                format!("{} ({})", document.heading(), name)
+            }
+            _ => name.to_string(),
+        }
+    }
+
+    /// Drop `documents`, renumbering the rest in order
+    pub fn remove_documents(&mut self, documents: &HashSet<String>) {
+        self.documents
+            .retain(|document| !documents.contains(&document.name));
+        self.ids = self
+            .documents
+            .iter()
+            .enumerate()
+            .map(|(id, document)| (document.name.clone(), id as u32))
+            .collect();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StoredDocument> {
+        self.documents.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Bytes of compressed bodies
+    pub fn compressed_size(&self) -> usize {
+        self.documents
+            .iter()
+            .filter_map(|document| document.body.as_ref())
+            .map(Vec::len)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(name: &str, title: Option<&str>, authors: &[&str], text: &str) -> StoredDocument {
+        let metadata = BookMetadata {
+            title: title.map(str::to_string),
+            authors: authors.iter().map(|author| author.to_string()).collect(),
+            ..BookMetadata::default()
+        };
+        StoredDocument::new(
+            name,
+            &Path::new("books").join(name),
+            metadata,
+            text.split_whitespace().count() as u32,
+            Some(DocumentStore::compress(text)),
+        )
+    }
+
+    #[test]
+    fn test_documents_keep_ids_metadata_and_bodies() {
+        let mut store = DocumentStore::new(true);
+        let text = "Всё смешалось в доме Облонских.";
+        let anna = stored("anna.fb2", Some("Анна Каренина"), &["Лев Толстой"], text);
+        assert_eq!(store.insert(anna), 0);
+        assert_eq!(store.insert(stored("war.fb2", None, &[], "Ну, князь")), 1);
+        assert_eq!(store.text("anna.fb2").as_deref(), Some(text));
+        assert!(store.text("idiot.fb2").is_none());
+        assert_eq!(
+            store.describe("anna.fb2"),
+            "Анна Каренина — Лев Толстой (anna.fb2)"
+        );
+        assert_eq!(store.describe("war.fb2"), "war.fb2");
+        assert_eq!(store.describe("idiot.fb2"), "idiot.fb2");
+        assert_eq!(store.get(0).unwrap().length, 5);
+
+        // Replacing keeps the id, removing renumbers the rest
+        let title = Some("Война и мир");
+        store.insert(stored("war.fb2", title, &["Лев Толстой"], "Ну, князь"));
+        assert_eq!(store.id("war.fb2"), Some(1));
+        store.remove_documents(&HashSet::from(["anna.fb2".to_string()]));
+        assert_eq!(store.id("war.fb2"), Some(0));
+        assert_eq!(store.get(0).unwrap().title.as_deref(), title);
+        assert_eq!(store.len(), 1);
+
+        let mut metadata_only = DocumentStore::new(false);
+        metadata_only.insert(stored("anna.fb2", None, &[], text));
+        assert!(metadata_only.text("anna.fb2").is_none());
+        assert_eq!(metadata_only.compressed_size(), 0);
+    }
+}
@@ -0,0 +1,325 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::{read_zip_entry, resolve_entity, BookMetadata};
+
+/// Whether `path` names an EPUB book
+pub fn is_epub(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+}
+
+/// Reads EPUB books: a zip of XHTML chapters listed in reading order by the spine of the
+/// package document `META-INF/container.xml` points to. The chapters are handed to
+/// `FB2Parser` one after another, each with its own `<body>`, so EPUB and FB2 books go
+/// through the same tokenizing and indexing; metadata comes from the package's Dublin Core.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EpubParser;
+
+impl EpubParser {
+    pub fn new() -> Self {
+        EpubParser
+    }
+
+    /// Entry names of the chapters of the book at `path`, in reading order
+    pub fn spine(&self, path: &Path) -> io::Result<Vec<String>> {
+        let mut zip = open_zip(path)?;
+        let package = package_path(&mut zip)?;
+        let package_xml = read_zip_entry(&mut zip, &package)?;
+        let mut xml_reader = Reader::from_reader(package_xml.as_slice());
+        xml_reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut manifest: HashMap<String, String> = HashMap::new();
+        let mut order = Vec::new();
+        loop {
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e) | Event::Empty(ref e)) => match e.local_name().as_ref() {
+                    b"item" => {
+                        if let (Some(id), Some(href)) = (attribute(e, "id"), attribute(e, "href")) {
+                            manifest.insert(id, href);
+                        }
+                    }
+                    b"itemref" => order.extend(attribute(e, "idref")),
+                    _ => {}
+                },
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(io::Error::other(format!("{}: {}", package, e))),
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        let chapters: Vec<String> = order
+            .iter()
+            .filter_map(|id| manifest.get(id))
+            .map(|href| resolve_href(&package, href))
+            .collect();
+        if chapters.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} has no chapters in its spine",
+                path.display()
+            )));
+        }
+        Ok(chapters)
+    }
+
+    /// XHTML of every chapter in reading order, one document after another. HTML entities such
+    /// as `&rdquo;` are left in place for the readers to resolve with `resolve_entity`.
+    pub fn read_chapters(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let chapters = self.spine(path)?;
+        let mut zip = open_zip(path)?;
+        let mut text = String::new();
+        for chapter in chapters {
+            let xhtml = read_zip_entry(&mut zip, &chapter)?;
+            text.push_str(&String::from_utf8_lossy(&xhtml));
+            text.push('\n');
+        }
+        Ok(text.into_bytes())
+    }
+
+    /// Uncompressed size of the chapters of the book at `path`
+    pub fn chapters_len(&self, path: &Path) -> io::Result<u64> {
+        let chapters = self.spine(path)?;
+        let mut zip = open_zip(path)?;
+        let mut bytes = 0;
+        for chapter in chapters {
+            bytes += zip.by_name(&chapter).map_err(io::Error::other)?.size() + 1;
+        }
+        Ok(bytes)
+    }
+
+    /// Title, creators, subjects, description, language and date of the package metadata
+    pub fn metadata(&self, path: &Path) -> Result<BookMetadata, Box<dyn std::error::Error>> {
+        let mut zip = open_zip(path)?;
+        let package = package_path(&mut zip)?;
+        let package_xml = read_zip_entry(&mut zip, &package)?;
+        let mut xml_reader = Reader::from_reader(package_xml.as_slice());
+        xml_reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut metadata = BookMetadata::default();
+        let mut in_metadata = false;
+        let mut element: Option<Vec<u8>> = None;
+        loop {
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"metadata" => {
+                    in_metadata = true;
+                }
+                Ok(Event::End(ref e)) if e.local_name().as_ref() == b"metadata" => break,
+                Ok(Event::Start(ref e)) if in_metadata => {
+                    element = Some(e.local_name().as_ref().to_vec());
+                }
+                Ok(Event::Text(e)) if element.is_some() => {
+                    let text = e.unescape_with(resolve_entity)?.trim().to_string();
+                    match element.as_deref() {
+                        Some(b"title") if metadata.title.is_none() => metadata.title = Some(text),
+                        Some(b"creator") => metadata.authors.push(text),
+                        Some(b"subject") => metadata.genres.push(text.to_lowercase()),
+                        Some(b"description") => metadata.annotation = Some(text),
+                        Some(b"language") => metadata.language = Some(text.to_lowercase()),
+                        Some(b"date") if metadata.date.is_none() => metadata.date = Some(text),
+                        _ => {}
+                    }
+                }
+                Ok(Event::End(_)) => element = None,
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    eprintln!("Error parsing {}: {}", path.display(), e);
+                    break;
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+        Ok(metadata)
+    }
+}
+
+fn open_zip(path: &Path) -> io::Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(path)?).map_err(io::Error::other)
+}
+
+/// Entry name of the package document, the `full-path` of the first `<rootfile>`
+fn package_path(zip: &mut ZipArchive<File>) -> io::Result<String> {
+    let container = read_zip_entry(zip, "META-INF/container.xml")?;
+    let mut xml_reader = Reader::from_reader(container.as_slice());
+    let mut buf = Vec::new();
+    loop {
+        match xml_reader.read_event_into(&mut buf) {
+            Ok(Event::Start(ref e) | Event::Empty(ref e))
+                if e.local_name().as_ref() == b"rootfile" =>
+            {
+                if let Some(path) = attribute(e, "full-path") {
+                    return Ok(path);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(io::Error::other(format!("META-INF/container.xml: {}", e))),
+            _ => {}
+        }
+        buf.clear();
+    }
+    Err(io::Error::other(
+        "META-INF/container.xml names no package document",
+    ))
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    let value = element.try_get_attribute(name).ok()??;
+    Some(value.unescape_value_with(resolve_entity).ok()?.into_owned())
+}
+
+/// Entry name of `href`, relative to the package document at `package`, without its fragment
+/// and with `%XX` escapes decoded
+fn resolve_href(package: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<&str> = package.split('/').collect();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    percent_decode(&parts.join("/"))
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FB2Parser;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    /// Write a book of two chapters listed in the spine in the opposite order of the manifest
+    fn write_book(path: &Path, first: &str, second: &str) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        let chapter = |text: &str| {
+            format!(
+                "<?xml version=\"1.0\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\">\
+                 <head><title>Глава</title></head><body><p>{}</p></body></html>",
+                text
+            )
+        };
+        for (name, contents) in [
+            ("mimetype", "application/epub+zip".to_string()),
+            (
+                "META-INF/container.xml",
+                "<container><rootfiles><rootfile full-path=\"OEBPS/content.opf\"/>\
+                 </rootfiles></container>"
+                    .to_string(),
+            ),
+            (
+                "OEBPS/content.opf",
+                "<package xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><metadata>\
+                 <dc:title>Война и мир</dc:title><dc:creator>Лев Толстой</dc:creator>\
+                 <dc:language>RU</dc:language><dc:subject>Prose</dc:subject>\
+                 <dc:date>1869</dc:date></metadata><manifest>\
+                 <item id=\"two\" href=\"Text/part%202.xhtml#start\"/>\
+                 <item id=\"one\" href=\"Text/part1.xhtml\"/></manifest>\
+                 <spine><itemref idref=\"one\"/><itemref idref=\"two\"/></spine></package>"
+                    .to_string(),
+            ),
+            ("OEBPS/Text/part1.xhtml", chapter(first)),
+            ("OEBPS/Text/part 2.xhtml", chapter(second)),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_chapters_are_read_in_spine_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("war.epub");
+        write_book(&path, "Ну,&nbsp;князь, Генуя", "Князь Андрей");
+
+        let epub = EpubParser::new();
+        assert_eq!(
+            epub.spine(&path).unwrap(),
+            ["OEBPS/Text/part1.xhtml", "OEBPS/Text/part 2.xhtml"]
+        );
+        assert_eq!(
+            epub.chapters_len(&path).unwrap(),
+            epub.read_chapters(&path).unwrap().len() as u64
+        );
+        let metadata = epub.metadata(&path).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Война и мир"));
+        assert_eq!(metadata.authors, ["Лев Толстой"]);
+        assert_eq!(metadata.genres, ["prose"]);
+        assert_eq!(metadata.language.as_deref(), Some("ru"));
+        assert_eq!(metadata.date.as_deref(), Some("1869"));
+
+        // The FB2 parser reads the chapter bodies, not their heads
+        let parser = FB2Parser::new();
+        assert_eq!(
+            parser.parse_file(&path).unwrap(),
+            ["князь", "генуя", "князь", "андрей"]
+        );
+        assert_eq!(parser.parse_metadata(&path).unwrap(), metadata);
+        assert_eq!(parser.parse_title(&path).unwrap(), ["война", "мир"]);
+        let offsets = parser.parse_file_with_offsets(&path).unwrap();
+        let source = crate::read_source(&path).unwrap();
+        let (word, start, end) = &offsets[3];
+        assert_eq!(word, "андрей");
+        assert_eq!(&source[*start..*end], "Андрей".as_bytes());
+        assert!(epub.spine(&dir.path().join("missing.epub")).is_err());
+    }
+
+    #[test]
+    fn test_html_entities_do_not_fail_the_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quotes.epub");
+        write_book(
+            &path,
+            "&ldquo;Hello&rdquo; &mdash; &lsquo;князь&rsquo; &copy;&nbsp;1869",
+            "Andrei&rsquo;s &eacute;t&eacute; &bogus; Андрей",
+        );
+
+        let parser = FB2Parser::new();
+        let words = parser.parse_file(&path).unwrap();
+        assert_eq!(words, ["hello", "князь", "andrei", "андрей"]);
+        let offsets = parser.parse_file_with_offsets(&path).unwrap();
+        let source = crate::read_source(&path).unwrap();
+        let (word, start, end) = &offsets[1];
+        assert_eq!(word, "князь");
+        assert_eq!(&source[*start..*end], "князь".as_bytes());
+        assert_eq!(
+            EpubParser::new().chapters_len(&path).unwrap(),
+            source.len() as u64
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::query::{raw_term, QueryAst};
+
+/// Approximate number of documents matching a Boolean query, from document frequencies alone.
+///
+/// Terms are assumed to occur independently: `a and b` matches a fraction `p(a) * p(b)` of the
+/// collection, `a or b` a fraction `p(a) + p(b) - p(a) * p(b)` and `not a` a fraction
+/// `1 - p(a)`. Phrases and near/N groups are estimated as the conjunction of their operands, so
+/// they are overestimated. `document_frequency` returns the number of documents containing a
+/// term, or an estimate of it for wildcard patterns.
+pub fn estimate_hits<F>(
+    query: &str,
+    total_documents: usize,
+    mut document_frequency: F,
+) -> Result<usize, String>
+where
+    F: FnMut(&str) -> Result<usize, String>,
+{
+    if query.split_whitespace().next().is_none() {
+        return Err("Empty query".to_string());
+    }
+    estimate_query(
+        &QueryAst::parse(query)?,
+        total_documents,
+        &mut document_frequency,
+    )
+}
+
+/// `estimate_hits` for a parsed query or one of its sub-expressions
+pub fn estimate_query<F>(
+    query: &QueryAst,
+    total_documents: usize,
+    document_frequency: &mut F,
+) -> Result<usize, String>
+where
+    F: FnMut(&str) -> Result<usize, String>,
+{
+    if total_documents == 0 {
+        return Ok(0);
+    }
+    let fraction = estimate_fraction(query, total_documents as f64, document_frequency)?;
+    Ok((fraction * total_documents as f64).round() as usize)
+}
+
+/// Literal prefix of a wildcard pattern, or `None` for a plain term. A pattern is estimated by
+/// the terms sharing its prefix, which overestimates infix and suffix patterns.
+pub fn wildcard_prefix(term: &str) -> Option<&str> {
+    term.find(['*', '?']).map(|end| &term[..end])
+}
+
+/// Documents containing at least one of several terms, assuming they occur independently
+pub fn union_frequency<I>(frequencies: I, total_documents: usize) -> usize
+where
+    I: IntoIterator<Item = usize>,
+{
+    if total_documents == 0 {
+        return 0;
+    }
+    let total = total_documents as f64;
+    let missing: f64 = frequencies
+        .into_iter()
+        .map(|frequency| 1.0 - (frequency as f64 / total).min(1.0))
+        .product();
+    ((1.0 - missing) * total).round() as usize
+}
+
+/// Fraction of the collection matching `query`
+fn estimate_fraction<F>(
+    query: &QueryAst,
+    total_documents: f64,
+    document_frequency: &mut F,
+) -> Result<f64, String>
+where
+    F: FnMut(&str) -> Result<usize, String>,
+{
+    let mut fraction =
+        |query: &QueryAst| estimate_fraction(query, total_documents, document_frequency);
+    match query {
+        QueryAst::Term(term) | QueryAst::Wildcard(term) => {
+            term_fraction(term, total_documents, document_frequency)
+        }
+        QueryAst::Phrase(words) => words.iter().try_fold(1.0, |product, word| {
+            Ok(product * term_fraction(raw_term(word), total_documents, document_frequency)?)
+        }),
+        QueryAst::Near { operands, .. } => operands
+            .iter()
+            .try_fold(1.0, |product, operand| Ok(product * fraction(operand)?)),
+        QueryAst::And(left, right) => Ok(fraction(left)? * fraction(right)?),
+        QueryAst::Or(left, right) => {
+            let (left, right) = (fraction(left)?, fraction(right)?);
+            Ok(left + right - left * right)
+        }
+        QueryAst::Not(operand) => Ok(1.0 - fraction(operand)?),
+    }
+}
+
+fn term_fraction<F>(
+    term: &str,
+    total_documents: f64,
+    document_frequency: &mut F,
+) -> Result<f64, String>
+where
+    F: FnMut(&str) -> Result<usize, String>,
+{
+    let frequency = document_frequency(term)? as f64;
+    Ok((frequency / total_documents).min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_independence_estimates() {
+        let df = |term: &str| {
+            Ok(match term {
+                "war" => 50,
+                "peace" => 20,
+                "anna" => 10,
+                _ => 0,
+            })
+        };
+
+        assert_eq!(estimate_hits("war", 100, df), Ok(50));
+        assert_eq!(estimate_hits("war and peace", 100, df), Ok(10));
+        assert_eq!(estimate_hits("war or peace", 100, df), Ok(60));
+        assert_eq!(estimate_hits("not war", 100, df), Ok(50));
+        assert_eq!(estimate_hits("war and (peace or anna)", 100, df), Ok(14));
+        assert_eq!(estimate_hits("\"war peace\"", 100, df), Ok(10));
+        assert_eq!(estimate_hits("near/3(war anna)", 100, df), Ok(5));
+        assert_eq!(estimate_hits("war and missing", 100, df), Ok(0));
+        assert!(estimate_hits("(war", 100, df).is_err());
+    }
+
+    #[test]
+    fn test_wildcard_helpers() {
+        assert_eq!(wildcard_prefix("tolst*"), Some("tolst"));
+        assert_eq!(wildcard_prefix("*ing"), Some(""));
+        assert_eq!(wildcard_prefix("war"), None);
+        assert_eq!(union_frequency([50, 50], 100), 75);
+        assert_eq!(union_frequency([], 100), 0);
+    }
+}
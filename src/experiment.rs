@@ -0,0 +1,79 @@
+use std::fmt;
+
+/// Index variant a query is routed to in an A/B experiment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variant::A => write!(f, "A"),
+            Variant::B => write!(f, "B"),
+        }
+    }
+}
+
+/// Routes a fixed percentage of queries to variant B. Routing hashes the normalized query,
+/// so repeating a query always reaches the same variant and runs stay comparable.
+#[derive(Debug, Clone)]
+pub struct AbRouter {
+    b_percent: u32,
+}
+
+impl AbRouter {
+    pub fn new(b_percent: u32) -> Result<Self, String> {
+        if b_percent > 100 {
+            return Err(format!(
+                "Variant B share must be between 0 and 100, got {}",
+                b_percent
+            ));
+        }
+        Ok(AbRouter { b_percent })
+    }
+
+    pub fn route(&self, query: &str) -> Variant {
+        let normalized = query
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        if (fnv1a(normalized.as_bytes()) % 100) < self.b_percent as u64 {
+            Variant::B
+        } else {
+            Variant::A
+        }
+    }
+}
+
+/// FNV-1a, chosen over `DefaultHasher` because its output is stable across Rust releases
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_is_sticky_and_proportional() {
+        let router = AbRouter::new(30).unwrap();
+        assert_eq!(router.route("War and  Peace"), router.route("war and peace"));
+
+        let routed_to_b = (0..2000)
+            .filter(|i| router.route(&format!("query {}", i)) == Variant::B)
+            .count();
+        assert!((450..750).contains(&routed_to_b), "{} routed to B", routed_to_b);
+
+        assert_eq!(AbRouter::new(0).unwrap().route("war"), Variant::A);
+        assert_eq!(AbRouter::new(100).unwrap().route("war"), Variant::B);
+        assert!(AbRouter::new(101).is_err());
+    }
+}
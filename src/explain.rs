@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::planner::IndexKind;
+use crate::query::{QueryAst, QueryEvaluator};
+
+/// How one node of a query was evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvaluationTrace {
+    /// The operator or leaf, e.g. `AND`, `term war` or `pattern tol*`
+    pub operation: String,
+    /// What the structure did, e.g. the strategy a pattern was expanded with
+    pub method: String,
+    /// Size of the posting lists the node read itself, its operands' not included
+    pub postings: usize,
+    /// Documents left after the node
+    pub candidates: usize,
+    /// Time the node took, its operands' included
+    pub elapsed: Duration,
+    pub operands: Vec<EvaluationTrace>,
+}
+
+impl EvaluationTrace {
+    /// One line per node, operands indented under their operator
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.push_lines(0, &mut lines);
+        lines
+    }
+
+    fn push_lines(&self, depth: usize, lines: &mut Vec<String>) {
+        let postings = if self.postings > 0 {
+            format!(", {} postings", self.postings)
+        } else {
+            String::new()
+        };
+        lines.push(format!(
+            "{}{}: {}{}, {} candidates in {:.2?}",
+            "  ".repeat(depth),
+            self.operation,
+            self.method,
+            postings,
+            self.candidates,
+            self.elapsed
+        ));
+        for operand in &self.operands {
+            operand.push_lines(depth + 1, lines);
+        }
+    }
+}
+
+/// A query evaluated by one structure with every node traced and every stage timed, what
+/// `search --explain` prints
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryExplanation {
+    /// The structure that answered the query
+    pub kind: IndexKind,
+    pub trace: EvaluationTrace,
+    /// Stages in the order they ran, e.g. parse, optimize, evaluate
+    pub stages: Vec<(&'static str, Duration)>,
+    pub documents: HashSet<String>,
+}
+
+impl QueryExplanation {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Evaluated by: {} ({} documents)",
+            self.kind,
+            self.documents.len()
+        )];
+        lines.extend(
+            self.trace
+                .lines()
+                .into_iter()
+                .map(|line| format!("  {}", line)),
+        );
+        let stages: Vec<String> = self
+            .stages
+            .iter()
+            .map(|(stage, elapsed)| format!("{} {:.2?}", stage, elapsed))
+            .collect();
+        lines.push(format!("Stages: {}", stages.join(", ")));
+        lines
+    }
+}
+
+/// Run `stage`, appending its duration to `stages` under `name`
+pub fn time_stage<T, F>(
+    stages: &mut Vec<(&'static str, Duration)>,
+    name: &'static str,
+    stage: F,
+) -> T
+where
+    F: FnOnce() -> T,
+{
+    let start = Instant::now();
+    let result = stage();
+    stages.push((name, start.elapsed()));
+    result
+}
+
+/// What an evaluator reports about its work so `trace_evaluation` can explain a query
+pub trait TracedEvaluator: QueryEvaluator {
+    /// Documents in an intermediate result
+    fn candidate_count(&self, output: &Self::Output) -> usize;
+
+    /// Size of the posting list of `term`, 0 when it is not indexed
+    fn posting_list_size(&self, term: &str) -> usize;
+
+    /// How `evaluate_term` reads a posting list
+    fn term_method(&self) -> String {
+        "read the posting list".to_string()
+    }
+
+    /// How `intersect_term` combines a result with the posting list of `term`
+    fn intersect_term_method(&self, term: &str) -> String {
+        format!("read the posting list of {} and intersected", term)
+    }
+
+    /// `evaluate_wildcard` with how the pattern was expanded and the size of the posting
+    /// lists of the terms it expanded to
+    fn trace_wildcard(&self, pattern: &str) -> Result<(Self::Output, String, usize), String> {
+        Ok((self.evaluate_wildcard(pattern)?, "expanded".to_string(), 0))
+    }
+}
+
+/// Evaluate `query` exactly as `QueryEvaluator::evaluate` does, recording for every node how
+/// it was evaluated, the posting lists it read, the documents left after it and its time
+pub fn trace_evaluation<E>(
+    evaluator: &E,
+    query: &QueryAst,
+) -> Result<(E::Output, EvaluationTrace), String>
+where
+    E: TracedEvaluator + ?Sized,
+{
+    let start = Instant::now();
+    let (output, operation, method, postings, operands) = match query {
+        QueryAst::Term(term) => (
+            evaluator.evaluate_term(term)?,
+            format!("term {}", term),
+            evaluator.term_method(),
+            evaluator.posting_list_size(term),
+            Vec::new(),
+        ),
+        QueryAst::Phrase(words) => (
+            evaluator.evaluate_phrase(words)?,
+            format!("phrase \"{}\"", words.join(" ")),
+            "matched word positions".to_string(),
+            words
+                .iter()
+                .map(|word| evaluator.posting_list_size(word))
+                .sum(),
+            Vec::new(),
+        ),
+        QueryAst::Near { distance, operands } => {
+            let mut words = Vec::new();
+            for operand in operands {
+                leaf_words(operand, &mut words);
+            }
+            (
+                evaluator.evaluate_near(*distance, operands)?,
+                format!("near/{}", distance),
+                format!("matched positions within {} words", distance),
+                words
+                    .iter()
+                    .map(|word| evaluator.posting_list_size(word))
+                    .sum(),
+                Vec::new(),
+            )
+        }
+        QueryAst::Wildcard(pattern) => {
+            let (output, method, postings) = evaluator.trace_wildcard(pattern)?;
+            (
+                output,
+                format!("pattern {}", pattern),
+                method,
+                postings,
+                Vec::new(),
+            )
+        }
+        QueryAst::And(left, right) => {
+            let (left, left_trace) = trace_evaluation(evaluator, left)?;
+            match right.as_ref() {
+                QueryAst::Term(term) => (
+                    evaluator.intersect_term(left, term)?,
+                    "AND".to_string(),
+                    evaluator.intersect_term_method(term),
+                    evaluator.posting_list_size(term),
+                    vec![left_trace],
+                ),
+                QueryAst::Not(subtracted) => {
+                    let (right, right_trace) = trace_evaluation(evaluator, subtracted)?;
+                    (
+                        evaluator.difference(left, right),
+                        "AND NOT".to_string(),
+                        "difference".to_string(),
+                        0,
+                        vec![left_trace, right_trace],
+                    )
+                }
+                right => {
+                    let (right, right_trace) = trace_evaluation(evaluator, right)?;
+                    (
+                        evaluator.intersect(left, right),
+                        "AND".to_string(),
+                        "intersection".to_string(),
+                        0,
+                        vec![left_trace, right_trace],
+                    )
+                }
+            }
+        }
+        QueryAst::Or(left, right) => {
+            let (left, left_trace) = trace_evaluation(evaluator, left)?;
+            let (right, right_trace) = trace_evaluation(evaluator, right)?;
+            (
+                evaluator.unite(left, right),
+                "OR".to_string(),
+                "union".to_string(),
+                0,
+                vec![left_trace, right_trace],
+            )
+        }
+        QueryAst::Not(operand) => {
+            let (operand, operand_trace) = trace_evaluation(evaluator, operand)?;
+            (
+                evaluator.complement(operand),
+                "NOT".to_string(),
+                "complement".to_string(),
+                0,
+                vec![operand_trace],
+            )
+        }
+    };
+    let trace = EvaluationTrace {
+        operation,
+        method,
+        postings,
+        candidates: evaluator.candidate_count(&output),
+        elapsed: start.elapsed(),
+        operands,
+    };
+    Ok((output, trace))
+}
+
+/// Terms and phrase words under a near/N operand
+fn leaf_words<'a>(query: &'a QueryAst, words: &mut Vec<&'a str>) {
+    match query {
+        QueryAst::Term(term) => words.push(term),
+        QueryAst::Phrase(phrase) => words.extend(phrase.iter().map(String::as_str)),
+        QueryAst::Near { operands, .. } => {
+            for operand in operands {
+                leaf_words(operand, words);
+            }
+        }
+        QueryAst::And(left, right) | QueryAst::Or(left, right) => {
+            leaf_words(left, words);
+            leaf_words(right, words);
+        }
+        QueryAst::Not(operand) => leaf_words(operand, words),
+        QueryAst::Wildcard(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CompressedInvertedIndex, Dictionary, WildcardSearchEngine};
+
+    #[test]
+    fn test_trace_follows_evaluation() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "war peace forest"),
+            ("b.fb2", "war peace"),
+            ("c.fb2", "war forest"),
+            ("d.fb2", "forest"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+
+        let index = CompressedInvertedIndex::from_dictionary(&dict);
+        let explanation = index.explain("war and not forest and peace").unwrap();
+        let mut documents: Vec<&String> = explanation.documents.iter().collect();
+        documents.sort();
+        assert_eq!(documents, ["b.fb2"]);
+        let stages: Vec<&str> = explanation.stages.iter().map(|(stage, _)| *stage).collect();
+        assert_eq!(stages, ["parse", "optimize", "evaluate", "names"]);
+
+        let trace = &explanation.trace;
+        assert_eq!((trace.operation.as_str(), trace.candidates), ("AND NOT", 1));
+        let and = &trace.operands[0];
+        assert_eq!((and.operation.as_str(), and.candidates), ("AND", 2));
+        assert!(and.method.starts_with("skipped through"), "{}", and.method);
+        assert_eq!(and.postings, 3);
+        assert_eq!(and.operands[0].operation, "term peace");
+        assert_eq!(trace.operands[1].operation, "term forest");
+        assert_eq!(trace.lines().len(), 4);
+
+        let engine = WildcardSearchEngine::from_dictionary(dict);
+        let explanation = engine.explain("pea* or f?rest or w*r*").unwrap();
+        let methods: Vec<&str> = explanation
+            .trace
+            .operands
+            .iter()
+            .flat_map(|operand| operand.operands.iter().chain([operand]))
+            .filter(|node| node.operation.starts_with("pattern"))
+            .map(|node| node.method.as_str())
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                "Permutation Index expanded to 1 terms",
+                "Permutation Index expanded to 1 terms",
+                "Trigram Index expanded to 1 terms"
+            ]
+        );
+        assert_eq!(explanation.documents.len(), 4);
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::persist::Persistable;
+
+/// Metadata fields a filter can name, as `field:value`
+pub const METADATA_FIELDS: [&str; 3] = ["author", "genre", "lang"];
+
+/// Zones of a book besides its body that a query can restrict a term to, as `zone:term`
+pub const ZONE_FIELDS: [&str; 2] = ["title", "annotation"];
+
+/// The field a plain query term searches: the text of the book
+pub const DEFAULT_FIELD: &str = "body";
+
+/// Documents by metadata value, per field: the FB2 genre codes, language and authors of each
+/// book, and the terms of its title and annotation zones.
+/// Documents are kept by name, so the index survives the renumbering of other structures.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FieldIndex {
+    /// Field name to value to sorted document names
+    pub fields: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+impl Persistable for FieldIndex {
+    const SUFFIX: &'static str = "_fields";
+}
+
+impl FieldIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `document` has `value` in `field`; values are matched lowercased
+    pub fn insert(&mut self, field: &str, value: &str, document: &str) {
+        let documents = self
+            .fields
+            .entry(field.to_string())
+            .or_default()
+            .entry(value.trim().to_lowercase())
+            .or_default();
+        if let Err(position) = documents.binary_search_by(|name| name.as_str().cmp(document)) {
+            documents.insert(position, document.to_string());
+        }
+    }
+
+    /// Record the terms of one zone of `document`, as the parser tokenized them
+    pub fn insert_terms(&mut self, zone: &str, terms: &[String], document: &str) {
+        for term in terms {
+            self.insert(zone, term, document);
+        }
+    }
+
+    /// Documents whose `field` holds `value`, or a value with `value` as one of its parts
+    /// separated by `_` or spaces: `genre:fantasy` matches both `fantasy` and `sf_fantasy`, and
+    /// `author:толстой` matches `лев толстой`. `None` when no document has the field at all.
+    pub fn documents(&self, field: &str, value: &str) -> Option<HashSet<&String>> {
+        let values = self.fields.get(field)?;
+        let value = value.to_lowercase();
+        Some(
+            values
+                .iter()
+                .filter(|(candidate, _)| {
+                    **candidate == value
+                        || candidate
+                            .split(|ch: char| ch == '_' || ch.is_whitespace())
+                            .any(|part| part == value)
+                })
+                .flat_map(|(_, documents)| documents)
+                .collect(),
+        )
+    }
+
+    /// Drop `documents` from every value, and values no other document has
+    pub fn remove_documents(&mut self, documents: &HashSet<String>) {
+        for values in self.fields.values_mut() {
+            for names in values.values_mut() {
+                names.retain(|name| !documents.contains(name));
+            }
+            values.retain(|_, names| !names.is_empty());
+        }
+    }
+
+    /// Number of distinct values of `field`
+    pub fn value_count(&self, field: &str) -> usize {
+        self.fields.get(field).map_or(0, BTreeMap::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_values_match_whole_codes_and_parts() {
+        let mut fields = FieldIndex::new();
+        fields.insert("genre", "sf_fantasy", "tower.fb2");
+        fields.insert("genre", "Fantasy", "hobbit.fb2");
+        fields.insert("genre", "prose_classic", "war.fb2");
+        fields.insert("lang", "ru", "war.fb2");
+        fields.insert("lang", "ru", "war.fb2");
+        fields.insert("author", "Лев Толстой", "war.fb2");
+
+        let mut fantasy: Vec<&String> = fields
+            .documents("genre", "fantasy")
+            .unwrap()
+            .into_iter()
+            .collect();
+        fantasy.sort();
+        assert_eq!(fantasy, ["hobbit.fb2", "tower.fb2"]);
+        assert_eq!(fields.documents("genre", "sf").unwrap().len(), 1);
+        assert!(fields.documents("genre", "horror").unwrap().is_empty());
+        assert!(fields.documents("title", "war").is_none());
+        assert_eq!(fields.documents("author", "толстой").unwrap().len(), 1);
+        assert_eq!(fields.documents("author", "Лев толстой").unwrap().len(), 1);
+        assert_eq!(fields.documents("author", "лев").unwrap().len(), 1);
+        assert_eq!(fields.fields["lang"]["ru"], ["war.fb2"]);
+
+        let title = ["война".to_string(), "и".to_string(), "мир".to_string()];
+        fields.insert_terms("title", &title, "war.fb2");
+        assert_eq!(fields.value_count("title"), 3);
+        assert!(fields.documents("title", "война и мир").unwrap().is_empty());
+
+        fields.remove_documents(&HashSet::from(["war.fb2".to_string()]));
+        assert_eq!(fields.value_count("genre"), 2);
+        assert_eq!(fields.value_count("lang"), 0);
+    }
+}
@@ -0,0 +1,230 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::{read_source, source_file, source_len};
+
+/// A source file as it was when last indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFingerprint {
+    pub bytes: u64,
+    /// Modification time in nanoseconds since the Unix epoch, 0 where the file system has none
+    pub modified: u128,
+    /// CRC-32 of the contents
+    pub checksum: u32,
+}
+
+impl FileFingerprint {
+    /// Fingerprint the file at `path` as it is on disk now
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        let (bytes, modified) = size_and_mtime(path)?;
+        Ok(FileFingerprint {
+            bytes,
+            modified,
+            checksum: crc32fast::hash(&read_source(path)?),
+        })
+    }
+}
+
+/// How the files of an input directory differ from the ones fingerprinted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileChanges {
+    /// Files not fingerprinted before
+    pub added: Vec<PathBuf>,
+    /// Files whose contents changed
+    pub modified: Vec<PathBuf>,
+    pub unchanged: usize,
+    /// Names fingerprinted before whose files are gone
+    pub removed: Vec<String>,
+}
+
+impl FileChanges {
+    /// New and modified files, the ones to index
+    pub fn changed(&self) -> Vec<PathBuf> {
+        self.added.iter().chain(&self.modified).cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Fingerprints of the source files an index was built from, by file name, so a later build
+/// can tell which files need parsing. Size and modification time decide when both match; a
+/// file that was only touched is recognized by its checksum and keeps being skipped. Files
+/// too small to index are fingerprinted too, so they are not looked at again either.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FingerprintCache {
+    files: BTreeMap<String, FileFingerprint>,
+}
+
+impl FingerprintCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn path(prefix: &str) -> String {
+        format!("{}_fingerprints.bin", prefix)
+    }
+
+    /// Load the cache of `prefix`, or `None` when the index has none
+    pub fn load(prefix: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let path = Self::path(prefix);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        Ok(Some(bincode::deserialize(&fs::read(&path)?)?))
+    }
+
+    pub fn save(&self, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(Self::path(prefix), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Fingerprint `files` as they are now, in parallel; files that cannot be read are left
+    /// out, so they count as new next time
+    pub fn record_files(&mut self, files: &[PathBuf]) {
+        let fingerprints: Vec<(String, FileFingerprint)> = files
+            .par_iter()
+            .filter_map(|file| Some((file_name(file), FileFingerprint::of(file).ok()?)))
+            .collect();
+        self.files.extend(fingerprints);
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.files.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FileFingerprint> {
+        self.files.get(name)
+    }
+
+    /// Compare `files`, the whole input, with the fingerprints. Touched files whose contents
+    /// did not change get their new modification time recorded.
+    pub fn changes(&mut self, files: &[PathBuf]) -> FileChanges {
+        let classified: Vec<(&PathBuf, String, Option<FileFingerprint>, bool)> = files
+            .par_iter()
+            .map(|file| {
+                let name = file_name(file);
+                let Some(known) = self.files.get(&name) else {
+                    return (file, name, None, false);
+                };
+                match size_and_mtime(file) {
+                    Ok((bytes, modified)) if bytes == known.bytes && modified == known.modified => {
+                        (file, name, None, true)
+                    }
+                    _ => match FileFingerprint::of(file) {
+                        Ok(now) if now.bytes == known.bytes && now.checksum == known.checksum => {
+                            (file, name, Some(now), true)
+                        }
+                        _ => (file, name, None, false),
+                    },
+                }
+            })
+            .collect();
+
+        let mut changes = FileChanges::default();
+        let mut present = HashSet::new();
+        for (file, name, touched, unchanged) in classified {
+            if unchanged {
+                changes.unchanged += 1;
+            } else if self.files.contains_key(&name) {
+                changes.modified.push(file.clone());
+            } else {
+                changes.added.push(file.clone());
+            }
+            if let Some(fingerprint) = touched {
+                self.files.insert(name.clone(), fingerprint);
+            }
+            present.insert(name);
+        }
+        changes.removed = self
+            .files
+            .keys()
+            .filter(|name| !present.contains(*name))
+            .cloned()
+            .collect();
+        changes
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Size of the source and modification time of the file holding it, the archive for an entry
+fn size_and_mtime(path: &Path) -> std::io::Result<(u64, u128)> {
+    let modified = fs::metadata(source_file(path))?
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    Ok((source_len(path)?, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_changes_are_found_by_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        let books: Vec<PathBuf> = ["a.fb2", "b.fb2", "c.fb2", "d.fb2"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for (book, text) in books.iter().zip(["war", "peace", "forest", "tower"]) {
+            fs::write(book, text).unwrap();
+        }
+        assert!(FingerprintCache::load(&prefix).unwrap().is_none());
+        let mut cache = FingerprintCache::new();
+        cache.record_files(&books[..3]);
+        cache.save(&prefix).unwrap();
+        let mut cache = FingerprintCache::load(&prefix).unwrap().unwrap();
+
+        // b is rewritten with other text of its size, c only touched, d is new and a is gone
+        fs::write(&books[1], "pious").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .append(true)
+            .open(&books[1])
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        fs::File::options()
+            .append(true)
+            .open(&books[2])
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let changes = cache.changes(&books[1..]);
+        assert_eq!(changes.added, [books[3].clone()]);
+        assert_eq!(changes.modified, [books[1].clone()]);
+        assert_eq!(changes.unchanged, 1);
+        assert_eq!(changes.removed, ["a.fb2"]);
+        assert_eq!(changes.changed().len(), 2);
+
+        let touched = cache.get("c.fb2").unwrap();
+        assert_eq!(touched, &FileFingerprint::of(&books[2]).unwrap());
+        assert!(cache.remove("a.fb2"));
+        cache.record_files(&changes.changed());
+        assert!(cache.changes(&books[1..]).is_empty());
+        assert_eq!(cache.len(), 3);
+    }
+}
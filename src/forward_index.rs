@@ -0,0 +1,229 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::persist::Persistable;
+use crate::{OffsetToken, TermInterner};
+
+/// One token of a document: its term and byte range in the source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSpan {
+    /// Index into `ForwardIndex::terms`
+    pub term: u32,
+    pub start: u64,
+    /// Exclusive
+    pub end: u64,
+}
+
+/// Per-document token sequence with byte offsets back into the original FB2 file or Parquet
+/// text value. Token positions are the same positions stored by the coordinate index.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardIndex {
+    pub terms: Vec<String>,
+    pub documents: HashMap<String, Vec<TokenSpan>>,
+}
+
+impl Persistable for ForwardIndex {
+    const SUFFIX: &'static str = "_forward";
+}
+
+impl ForwardIndex {
+    /// Build from documents and a tokenizer returning `(term, start, end)` triples in order
+    pub fn from_documents_with_tokenizer<F>(
+        documents: &[String],
+        tokenizer: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str) -> Result<Vec<OffsetToken>, Box<dyn std::error::Error>> + Sync,
+    {
+        Self::from_documents_with_interner(documents, &mut TermInterner::new(), tokenizer)
+    }
+
+    /// Like `from_documents_with_tokenizer`, taking term ids from `interner` so they match
+    /// earlier builds; new terms are added to it
+    pub fn from_documents_with_interner<F>(
+        documents: &[String],
+        interner: &mut TermInterner,
+        tokenizer: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str) -> Result<Vec<OffsetToken>, Box<dyn std::error::Error>> + Sync,
+    {
+        println!(
+            "    ForwardIndex: Tokenizing {} documents with offsets",
+            documents.len()
+        );
+
+        let tokenized: Vec<(String, Vec<OffsetToken>)> = documents
+            .par_iter()
+            .map(|document| {
+                tokenizer(document)
+                    .map(|tokens| (document.clone(), tokens))
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<_, String>>()?;
+
+        let mut documents = HashMap::new();
+        for (document, tokens) in tokenized {
+            let spans = tokens
+                .into_iter()
+                .map(|(term, start, end)| TokenSpan {
+                    term: interner.intern(&term),
+                    start: start as u64,
+                    end: end as u64,
+                })
+                .collect();
+            documents.insert(document, spans);
+        }
+        let index = ForwardIndex {
+            terms: interner.terms().to_vec(),
+            documents,
+        };
+
+        println!(
+            "    ForwardIndex: Complete - {} documents, {} distinct terms",
+            index.documents.len(),
+            index.terms.len()
+        );
+        Ok(index)
+    }
+
+    /// Add or replace the tokens of one document, taking term ids from `interner`, the one
+    /// the index was built with
+    pub fn insert_document(
+        &mut self,
+        document: &str,
+        tokens: Vec<OffsetToken>,
+        interner: &mut TermInterner,
+    ) {
+        let spans = tokens
+            .into_iter()
+            .map(|(term, start, end)| TokenSpan {
+                term: interner.intern(&term),
+                start: start as u64,
+                end: end as u64,
+            })
+            .collect();
+        self.documents.insert(document.to_string(), spans);
+        self.terms = interner.terms().to_vec();
+    }
+
+    /// `(position, span)` for every occurrence of `term` in `document`
+    pub fn locate(&self, document: &str, term: &str) -> Vec<(usize, TokenSpan)> {
+        let term_id = match self.terms.iter().position(|t| t == term) {
+            Some(id) => id as u32,
+            None => return Vec::new(),
+        };
+
+        self.documents
+            .get(document)
+            .map(|spans| {
+                spans
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, span)| span.term == term_id)
+                    .map(|(position, span)| (position, *span))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Terms of `document` in token order, the words its parser gave
+    pub fn words(&self, document: &str) -> Option<Vec<String>> {
+        let spans = self.documents.get(document)?;
+        Some(spans.iter().map(|span| self.terms[span.term as usize].clone()).collect())
+    }
+
+    /// Byte range of the token at `position`
+    pub fn span_at(&self, document: &str, position: usize) -> Option<TokenSpan> {
+        self.documents.get(document)?.get(position).copied()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.terms.iter().map(|t| t.len()).sum::<usize>()
+            + self
+                .documents
+                .iter()
+                .map(|(d, spans)| d.len() + spans.len() * std::mem::size_of::<TokenSpan>())
+                .sum::<usize>()
+    }
+}
+
+/// Whitespace tokenization used for Parquet text columns: keep alphanumeric characters of each
+/// chunk, lowercase, drop words of two bytes or fewer. Offsets cover the whole chunk.
+pub fn tokenize_plain_text_with_offsets(text: &str) -> Vec<OffsetToken> {
+    let mut tokens = Vec::new();
+    let mut chunk_start = None;
+
+    for (offset, ch) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (ch.is_whitespace(), chunk_start) {
+            (false, None) => chunk_start = Some(offset),
+            (true, Some(start)) => {
+                let word: String = text[start..offset]
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .collect::<String>()
+                    .to_lowercase();
+                if word.len() > 2 {
+                    tokens.push((word, start, offset));
+                }
+                chunk_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FB2Parser;
+    use std::io::Write;
+
+    #[test]
+    fn test_fb2_offsets_point_into_source() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let source = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<FictionBook><body><section>\n\
+                      <p>Война &amp; мир, &quot;Tolstoy&quot;</p>\n<p>  Анна Каренина</p>\n\
+                      </section></body></FictionBook>";
+        file.write_all(source.as_bytes()).unwrap();
+
+        let parser = FB2Parser::new();
+        let with_offsets = parser.parse_file_with_offsets(file.path()).unwrap();
+        let words = parser.parse_file(file.path()).unwrap();
+        let terms: Vec<&String> = with_offsets.iter().map(|(w, _, _)| w).collect();
+        assert_eq!(terms, words.iter().collect::<Vec<_>>());
+
+        for (word, start, end) in &with_offsets {
+            assert_eq!(source[*start..*end].to_lowercase(), *word);
+        }
+
+        let index = ForwardIndex::from_documents_with_tokenizer(&["book.fb2".to_string()], |_| {
+            Ok(with_offsets.clone())
+        })
+        .unwrap();
+        let located = index.locate("book.fb2", "каренина");
+        assert_eq!(located.len(), 1);
+        assert_eq!(located[0].0, 4);
+    }
+
+    #[test]
+    fn test_plain_text_offsets() {
+        let text = "War, and  peace!\tok";
+        let tokens = tokenize_plain_text_with_offsets(text);
+        assert_eq!(
+            tokens,
+            vec![
+                ("war".to_string(), 0, 4),
+                ("and".to_string(), 5, 8),
+                ("peace".to_string(), 10, 16)
+            ]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Constant of reciprocal rank fusion from Cormack et al.; it damps the weight of the very
+/// first ranks so one list's top document cannot outvote agreement between the others
+pub const RRF_K: f64 = 60.0;
+
+/// How the scores of one result list are rescaled before lists from different structures or
+/// shards are compared. TF-IDF sums and query likelihood logarithms live on unrelated scales,
+/// and even one model's scores shift with each index's statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreNormalization {
+    /// Scores as they are
+    None,
+    /// (score - min) / (max - min), so the best hit scores 1 and the worst 0
+    MinMax,
+    /// (score - mean) / standard deviation
+    ZScore,
+    /// Scores replaced by rank, (len - rank) / len: 1 for the first of a list
+    Rank,
+}
+
+impl FromStr for ScoreNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ScoreNormalization::None),
+            "min-max" | "minmax" => Ok(ScoreNormalization::MinMax),
+            "z-score" | "zscore" => Ok(ScoreNormalization::ZScore),
+            "rank" => Ok(ScoreNormalization::Rank),
+            _ => Err(format!("Unknown score normalization: {}", s)),
+        }
+    }
+}
+
+impl ScoreNormalization {
+    /// Rescale the scores of `ranking`, given best first, in place
+    pub fn apply<D>(self, ranking: &mut [(D, f64)]) {
+        if ranking.is_empty() {
+            return;
+        }
+        let len = ranking.len() as f64;
+        match self {
+            ScoreNormalization::None => {}
+            ScoreNormalization::MinMax => {
+                let min = ranking
+                    .iter()
+                    .map(|(_, s)| *s)
+                    .fold(f64::INFINITY, f64::min);
+                let max = ranking
+                    .iter()
+                    .map(|(_, s)| *s)
+                    .fold(f64::NEG_INFINITY, f64::max);
+                for (_, score) in ranking.iter_mut() {
+                    // A list of equal scores says nothing about order; all of it counts as best
+                    *score = if max > min {
+                        (*score - min) / (max - min)
+                    } else {
+                        1.0
+                    };
+                }
+            }
+            ScoreNormalization::ZScore => {
+                let mean = ranking.iter().map(|(_, s)| s).sum::<f64>() / len;
+                let variance = ranking.iter().map(|(_, s)| (s - mean).powi(2)).sum::<f64>() / len;
+                let deviation = variance.sqrt();
+                for (_, score) in ranking.iter_mut() {
+                    *score = if deviation > 0.0 {
+                        (*score - mean) / deviation
+                    } else {
+                        0.0
+                    };
+                }
+            }
+            ScoreNormalization::Rank => {
+                for (rank, (_, score)) in ranking.iter_mut().enumerate() {
+                    *score = (len - rank as f64) / len;
+                }
+            }
+        }
+    }
+}
+
+/// Merge result lists from several backends by summing each document's normalized scores
+/// (CombSUM); a document missing from a list gets nothing from it. Best first, ties by name.
+pub fn combine_normalized(
+    lists: &[Vec<(String, f64)>],
+    normalization: ScoreNormalization,
+) -> Vec<(String, f64)> {
+    let mut combined: HashMap<&str, f64> = HashMap::new();
+    for list in lists {
+        let mut normalized: Vec<(&str, f64)> = list
+            .iter()
+            .map(|(document, score)| (document.as_str(), *score))
+            .collect();
+        normalization.apply(&mut normalized);
+        for (document, score) in normalized {
+            *combined.entry(document).or_insert(0.0) += score;
+        }
+    }
+    sorted(combined)
+}
+
+/// Merge result lists, each best first, by reciprocal rank fusion: a document scores the sum
+/// of 1 / (k + rank) over the lists holding it, ranks counted from 1. Only ranks are used, so
+/// lists scored on any scale merge fairly. Best first, ties by name.
+pub fn reciprocal_rank_fusion(lists: &[Vec<(String, f64)>], k: f64) -> Vec<(String, f64)> {
+    let mut fused: HashMap<&str, f64> = HashMap::new();
+    for list in lists {
+        for (rank, (document, _)) in list.iter().enumerate() {
+            *fused.entry(document.as_str()).or_insert(0.0) += 1.0 / (k + rank as f64 + 1.0);
+        }
+    }
+    sorted(fused)
+}
+
+fn sorted(scores: HashMap<&str, f64>) -> Vec<(String, f64)> {
+    let mut ranked: Vec<(String, f64)> = scores
+        .into_iter()
+        .map(|(document, score)| (document.to_string(), score))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(entries: &[(&str, f64)]) -> Vec<(String, f64)> {
+        entries
+            .iter()
+            .map(|&(document, score)| (document.to_string(), score))
+            .collect()
+    }
+
+    #[test]
+    fn test_lists_on_different_scales_fuse() {
+        let mut scores = list(&[("a", 8.0), ("b", 4.0), ("c", 2.0)]);
+        ScoreNormalization::MinMax.apply(&mut scores);
+        assert_eq!(scores, list(&[("a", 1.0), ("b", 1.0 / 3.0), ("c", 0.0)]));
+        let mut scores = list(&[("a", 3.0), ("b", 1.0)]);
+        ScoreNormalization::ZScore.apply(&mut scores);
+        assert_eq!(scores, list(&[("a", 1.0), ("b", -1.0)]));
+        let mut scores = list(&[("a", -2.5), ("b", -7.0), ("c", -9.0), ("d", -9.5)]);
+        ScoreNormalization::Rank.apply(&mut scores);
+        assert_eq!(
+            scores,
+            list(&[("a", 1.0), ("b", 0.75), ("c", 0.5), ("d", 0.25)])
+        );
+        assert_eq!("z-score".parse(), Ok(ScoreNormalization::ZScore));
+        assert!("softmax".parse::<ScoreNormalization>().is_err());
+
+        // TF-IDF sums against query likelihood logarithms: raw scores would let the first
+        // list decide everything
+        let tfidf = list(&[
+            ("war.fb2", 24.0),
+            ("peace.fb2", 12.0),
+            ("forest.fb2", 11.5),
+            ("both.fb2", 11.0),
+        ]);
+        let likelihood = list(&[
+            ("both.fb2", -3.0),
+            ("peace.fb2", -4.0),
+            ("forest.fb2", -9.0),
+        ]);
+        let lists = [tfidf, likelihood];
+        let combined = combine_normalized(&lists, ScoreNormalization::MinMax);
+        assert_eq!(combined[0].0, "both.fb2");
+        assert_eq!(combined.len(), 4);
+
+        let fused = reciprocal_rank_fusion(&lists, RRF_K);
+        assert_eq!(fused[0].0, "peace.fb2");
+        assert!((fused[0].1 - 2.0 / 62.0).abs() < 1e-12);
+        assert_eq!(fused[1].0, "both.fb2");
+        assert_eq!(fused.last().unwrap().0, "war.fb2");
+    }
+}
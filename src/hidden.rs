@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Soft-deleted documents: kept in every index but excluded from query results by default
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HiddenDocuments {
+    /// Document name mapped to the reason it was hidden
+    pub documents: BTreeMap<String, String>,
+}
+
+impl HiddenDocuments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the hidden set, treating a missing file as an empty set
+    pub fn load_or_default(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if !Path::new(path).exists() {
+            return Ok(Self::new());
+        }
+        let data = fs::read(path)?;
+        Ok(bincode::deserialize(&data)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Returns false if the document was already hidden (the reason is updated either way)
+    pub fn hide(&mut self, document: &str, reason: &str) -> bool {
+        self.documents
+            .insert(document.to_string(), reason.to_string())
+            .is_none()
+    }
+
+    /// Returns false if the document was not hidden
+    pub fn undelete(&mut self, document: &str) -> bool {
+        self.documents.remove(document).is_some()
+    }
+
+    pub fn is_hidden(&self, document: &str) -> bool {
+        self.documents.contains_key(document)
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hide_and_undelete_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hidden.bin");
+        let path = path.to_str().unwrap();
+
+        let mut hidden = HiddenDocuments::load_or_default(path).unwrap();
+        assert!(hidden.is_empty());
+        assert!(hidden.hide("spam.fb2", "quality"));
+        assert!(!hidden.hide("spam.fb2", "duplicate"));
+        hidden.save(path).unwrap();
+
+        let mut loaded = HiddenDocuments::load_or_default(path).unwrap();
+        assert!(loaded.is_hidden("spam.fb2"));
+        assert_eq!(loaded.documents["spam.fb2"], "duplicate");
+        assert!(loaded.undelete("spam.fb2"));
+        assert!(!loaded.undelete("spam.fb2"));
+        assert!(!loaded.is_hidden("spam.fb2"));
+    }
+}
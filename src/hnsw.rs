@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::persist::Persistable;
+use crate::tfidf::SparseVector;
+use crate::{document_vectors, CoordinateIndex};
+
+/// Shape of the graph: links per node and how wide insertions and searches look
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswParams {
+    /// Links a node keeps on every layer above the bottom one, which keeps twice as many
+    pub m: usize,
+    /// Candidates considered when linking a new node
+    pub ef_construction: usize,
+    /// Candidates considered per search, raised to `k` when fewer
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: 12,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+}
+
+/// A document vector with terms as ids of the index vocabulary, sorted by id
+type PackedVector = Vec<(u32, f32)>;
+
+/// Hierarchical navigable small world graph over L2-normalized TF-IDF document vectors, for
+/// approximate nearest neighbors by cosine similarity. A search descends from a sparse top
+/// layer to the full bottom one, so it compares the query with a few hundred documents rather
+/// than all of them; the price is an occasional neighbor missed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HnswIndex {
+    params: HnswParams,
+    documents: Vec<String>,
+    document_ids: HashMap<String, u32>,
+    vocabulary: HashMap<String, u32>,
+    vectors: Vec<PackedVector>,
+    /// Neighbors of each node on each layer it is on, bottom layer first
+    links: Vec<Vec<Vec<u32>>>,
+    entry_point: Option<u32>,
+}
+
+impl Persistable for HnswIndex {
+    const SUFFIX: &'static str = "_hnsw";
+}
+
+/// A candidate node and its distance, `1 - cosine`, to the query
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl HnswIndex {
+    pub fn new(params: HnswParams) -> Self {
+        HnswIndex {
+            params: HnswParams {
+                m: params.m.max(2),
+                ..params
+            },
+            documents: Vec::new(),
+            document_ids: HashMap::new(),
+            vocabulary: HashMap::new(),
+            vectors: Vec::new(),
+            links: Vec::new(),
+            entry_point: None,
+        }
+    }
+
+    /// Index every vector, in document name order so the graph is the same on every build
+    pub fn from_vectors(vectors: &HashMap<String, SparseVector>, params: HnswParams) -> Self {
+        let mut index = HnswIndex::new(params);
+        let mut documents: Vec<&String> = vectors.keys().collect();
+        documents.sort();
+        for document in documents {
+            index.insert(document, &vectors[document]);
+        }
+        index
+    }
+
+    /// Index the TF-IDF vector of every document of a coordinate index
+    pub fn from_coordinate_index(index: &CoordinateIndex, params: HnswParams) -> Self {
+        let documents: Vec<&str> = index.documents.iter().map(String::as_str).collect();
+        Self::from_vectors(&document_vectors(index, &documents), params)
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Add a document with its normalized vector; a document indexed already is left alone
+    pub fn insert(&mut self, document: &str, vector: &SparseVector) {
+        if self.document_ids.contains_key(document) {
+            return;
+        }
+        // New terms get ids in term order, so the same documents always pack the same way
+        let mut terms: Vec<(&String, &f64)> = vector.iter().collect();
+        terms.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        let mut packed: PackedVector = terms
+            .into_iter()
+            .map(|(term, &weight)| {
+                let next = self.vocabulary.len() as u32;
+                (
+                    *self.vocabulary.entry(term.clone()).or_insert(next),
+                    weight as f32,
+                )
+            })
+            .collect();
+        packed.sort_unstable_by_key(|&(term, _)| term);
+
+        let node = self.documents.len() as u32;
+        let level = self.random_level(node);
+        self.documents.push(document.to_string());
+        self.document_ids.insert(document.to_string(), node);
+        self.vectors.push(packed);
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+        let query = self.vectors[node as usize].clone();
+        let top_level = self.level(entry_point);
+        let mut entry = entry_point;
+        for layer in (level + 1..=top_level).rev() {
+            entry = self.closest(&query, entry, layer);
+        }
+        for layer in (0..=level.min(top_level)).rev() {
+            let found = self.search_layer(&query, entry, self.params.ef_construction, layer);
+            entry = found[0].node;
+            let neighbors: Vec<u32> = found
+                .iter()
+                .take(self.params.m)
+                .map(|candidate| candidate.node)
+                .collect();
+            for &neighbor in &neighbors {
+                self.links[neighbor as usize][layer].push(node);
+                self.prune(neighbor, layer);
+            }
+            self.links[node as usize][layer] = neighbors;
+        }
+        if level > top_level {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// The `k` documents closest to `vector`, most similar first, with their cosine similarity
+    pub fn search(&self, vector: &SparseVector, k: usize) -> Vec<(String, f64)> {
+        let mut query: PackedVector = vector
+            .iter()
+            .filter_map(|(term, &weight)| Some((*self.vocabulary.get(term)?, weight as f32)))
+            .collect();
+        query.sort_unstable_by_key(|&(term, _)| term);
+        self.search_packed(&query, k, None)
+    }
+
+    /// "More like this": the `k` documents closest to an indexed one, itself left out. `None`
+    /// when `document` is not indexed.
+    pub fn similar_to(&self, document: &str, k: usize) -> Option<Vec<(String, f64)>> {
+        let node = *self.document_ids.get(document)?;
+        Some(self.search_packed(&self.vectors[node as usize], k, Some(node)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Layers above the bottom one
+    pub fn layers(&self) -> usize {
+        self.entry_point.map_or(0, |entry| self.level(entry))
+    }
+
+    fn search_packed(
+        &self,
+        query: &PackedVector,
+        k: usize,
+        exclude: Option<u32>,
+    ) -> Vec<(String, f64)> {
+        let Some(entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+        let mut entry = entry_point;
+        for layer in (1..=self.level(entry_point)).rev() {
+            entry = self.closest(query, entry, layer);
+        }
+        let ef = self.params.ef_search.max(k + 1);
+        self.search_layer(query, entry, ef, 0)
+            .into_iter()
+            .filter(|candidate| Some(candidate.node) != exclude)
+            .take(k)
+            .map(|candidate| {
+                let document = self.documents[candidate.node as usize].clone();
+                (document, 1.0 - candidate.distance as f64)
+            })
+            .collect()
+    }
+
+    /// Top layer of `node`
+    fn level(&self, node: u32) -> usize {
+        self.links[node as usize].len() - 1
+    }
+
+    /// Layers drawn from an exponential distribution with rate `ln m`, hashed from the node id
+    /// instead of a random generator so builds are reproducible
+    fn random_level(&self, node: u32) -> usize {
+        let mut hash = (node as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        hash ^= hash >> 31;
+        let uniform = ((hash >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() / (self.params.m as f64).ln()) as usize
+    }
+
+    fn distance(&self, query: &PackedVector, node: u32) -> f32 {
+        let vector = &self.vectors[node as usize];
+        let (mut i, mut j, mut dot) = (0, 0, 0.0);
+        while i < query.len() && j < vector.len() {
+            match query[i].0.cmp(&vector[j].0) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    dot += query[i].1 * vector[j].1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        1.0 - dot
+    }
+
+    /// Greedy walk on `layer` from `entry` to the node closest to `query`
+    fn closest(&self, query: &PackedVector, mut entry: u32, layer: usize) -> u32 {
+        let mut best = self.distance(query, entry);
+        loop {
+            let mut improved = false;
+            for &neighbor in &self.links[entry as usize][layer] {
+                let distance = self.distance(query, neighbor);
+                if distance < best {
+                    best = distance;
+                    entry = neighbor;
+                    improved = true;
+                }
+            }
+            if !improved {
+                return entry;
+            }
+        }
+    }
+
+    /// Best-first search of `layer` keeping the `ef` closest nodes seen, closest first
+    fn search_layer(
+        &self,
+        query: &PackedVector,
+        entry: u32,
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let start = Candidate {
+            distance: self.distance(query, entry),
+            node: entry,
+        };
+        let mut visited = HashSet::from([entry]);
+        let mut frontier = BinaryHeap::from([Reverse(start)]);
+        let mut found = BinaryHeap::from([start]);
+
+        while let Some(Reverse(current)) = frontier.pop() {
+            if found.len() >= ef
+                && found
+                    .peek()
+                    .is_some_and(|far| current.distance > far.distance)
+            {
+                break;
+            }
+            for &neighbor in &self.links[current.node as usize][layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let candidate = Candidate {
+                    distance: self.distance(query, neighbor),
+                    node: neighbor,
+                };
+                if found.len() < ef || found.peek().is_some_and(|far| candidate < *far) {
+                    frontier.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// Keep only the closest links of `node` on `layer` once it has more than allowed
+    fn prune(&mut self, node: u32, layer: usize) {
+        let limit = if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        };
+        if self.links[node as usize][layer].len() <= limit {
+            return;
+        }
+        let vector = self.vectors[node as usize].clone();
+        let mut neighbors: Vec<Candidate> = self.links[node as usize][layer]
+            .iter()
+            .map(|&neighbor| Candidate {
+                distance: self.distance(&vector, neighbor),
+                node: neighbor,
+            })
+            .collect();
+        neighbors.sort_unstable();
+        neighbors.truncate(limit);
+        self.links[node as usize][layer] = neighbors.iter().map(|c| c.node).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tfidf::{cosine_similarity, normalize};
+
+    /// Documents on a few topics, each a mix of its topic's words and some shared noise
+    fn vectors() -> HashMap<String, SparseVector> {
+        let topics = ["война", "любовь", "море", "космос", "поезд"];
+        let mut vectors = HashMap::new();
+        for i in 0..200 {
+            let topic = topics[i % topics.len()];
+            let mut vector: SparseVector = (0..6)
+                .map(|j| (format!("{}{}", topic, (i * 7 + j) % 10), 1.0 + j as f64))
+                .collect();
+            vector.insert(format!("шум{}", i % 13), 0.5);
+            normalize(&mut vector);
+            vectors.insert(format!("doc{:03}.fb2", i), vector);
+        }
+        vectors
+    }
+
+    #[test]
+    fn test_neighbors_match_brute_force() {
+        let vectors = vectors();
+        let index = HnswIndex::from_vectors(&vectors, HnswParams::default());
+        assert_eq!(index.len(), 200);
+
+        let mut recalled = 0;
+        for document in ["doc000.fb2", "doc001.fb2", "doc057.fb2", "doc123.fb2"] {
+            let mut exact: Vec<(&String, f64)> = vectors
+                .iter()
+                .filter(|(other, _)| *other != document)
+                .map(|(other, vector)| (other, cosine_similarity(&vectors[document], vector)))
+                .collect();
+            exact.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+            // Ties with the tenth neighbor count as found
+            let tenth = exact[9].1 - 1e-4;
+
+            let similar = index.similar_to(document, 10).unwrap();
+            assert_eq!(similar.len(), 10);
+            assert!(similar.iter().all(|(other, _)| other != document));
+            assert!(similar.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            recalled += similar
+                .iter()
+                .filter(|(_, similarity)| *similarity >= tenth)
+                .count();
+        }
+        assert!(recalled >= 36, "recalled {} of 40 neighbors", recalled);
+
+        let query = &vectors["doc002.fb2"];
+        assert_eq!(index.search(query, 1)[0].0, "doc002.fb2");
+        assert!(index.similar_to("missing.fb2", 5).is_none());
+    }
+}
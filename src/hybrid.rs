@@ -0,0 +1,237 @@
+use roaring::RoaringBitmap;
+
+use crate::query::{QueryAst, QueryEvaluator};
+use crate::{glob_match, CompressedInvertedIndex, FieldIndex, TopKStats};
+use crate::{DEFAULT_FIELD, METADATA_FIELDS, ZONE_FIELDS};
+
+/// A query in two parts: a Boolean filter over terms and metadata fields, and a ranking query
+/// whose terms score only the documents the filter lets through, as in
+/// `рак* and genre:fantasy RANK BY body:"тёмная башня"`. Terms may name the `body` field,
+/// which plain terms search anyway, or the `title` and `annotation` zones. A query naming a
+/// metadata field or zone without `RANK BY`, such as `author:tolstoy and title:мир`, is a
+/// filter alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HybridQuery {
+    /// Boolean query selecting the documents to rank; empty selects every document
+    pub filter_query: String,
+    /// Query whose terms weigh the selected documents; it selects nothing itself. Empty for a
+    /// filter alone.
+    pub ranking_query: String,
+}
+
+impl HybridQuery {
+    /// Split a query at `RANK BY`, in any case. `None` for queries without it that name no
+    /// metadata field or zone either.
+    pub fn parse(query: &str) -> Option<Result<HybridQuery, String>> {
+        let words: Vec<(usize, &str)> = query
+            .split_whitespace()
+            .map(|word| (word.as_ptr() as usize - query.as_ptr() as usize, word))
+            .collect();
+        let Some(position) = words.windows(2).position(|pair| {
+            pair[0].1.eq_ignore_ascii_case("rank") && pair[1].1.eq_ignore_ascii_case("by")
+        }) else {
+            return names_field(query).then(|| {
+                Ok(HybridQuery {
+                    filter_query: strip_default_field(query),
+                    ranking_query: String::new(),
+                })
+            });
+        };
+        let filter_end = words[position].0;
+        let (by_start, by) = words[position + 1];
+        let filter_query = strip_default_field(&query[..filter_end]);
+        let ranking_query = strip_default_field(&query[by_start + by.len()..]);
+        if ranking_query.is_empty() {
+            return Some(Err("Nothing to rank by after RANK BY".to_string()));
+        }
+        Some(Ok(HybridQuery {
+            filter_query,
+            ranking_query,
+        }))
+    }
+
+    /// Ids, in `index`, of the documents passing the filter. Field terms are looked up in
+    /// `fields`, which indexes built before fields were saved lack.
+    pub fn filter(
+        &self,
+        index: &CompressedInvertedIndex,
+        fields: Option<&FieldIndex>,
+    ) -> Result<RoaringBitmap, String> {
+        let evaluator = FilterEvaluator { index, fields };
+        if self.filter_query.is_empty() {
+            return Ok(evaluator.complement(RoaringBitmap::new()));
+        }
+        evaluator.evaluate(&QueryAst::parse(&self.filter_query)?)
+    }
+
+    pub fn is_filter_only(&self) -> bool {
+        self.ranking_query.is_empty()
+    }
+
+    /// The best `k` of `candidates` by the TF-IDF weight of the ranking query's terms
+    pub fn rank(
+        &self,
+        index: &CompressedInvertedIndex,
+        candidates: &RoaringBitmap,
+        k: usize,
+    ) -> Result<(Vec<(String, f64)>, TopKStats), String> {
+        index.rank_top_k_in(candidates, &self.ranking_query, k)
+    }
+}
+
+/// Whether a term of `query` is `field:value` for one of the metadata fields or zones
+fn names_field(query: &str) -> bool {
+    query.split_whitespace().any(|word| {
+        let term = word.trim_start_matches(['(', '"']);
+        term.split_once(':').is_some_and(|(field, _)| {
+            METADATA_FIELDS
+                .iter()
+                .chain(&ZONE_FIELDS)
+                .any(|known| field.eq_ignore_ascii_case(known))
+        })
+    })
+}
+
+/// Words of `part` with any `body:` field prefix dropped
+fn strip_default_field(part: &str) -> String {
+    let prefix = format!("{}:", DEFAULT_FIELD);
+    part.split_whitespace()
+        .map(|word| {
+            let opening = word.len() - word.trim_start_matches('(').len();
+            let rest = &word[opening..];
+            if rest.len() >= prefix.len()
+                && rest.is_char_boundary(prefix.len())
+                && rest[..prefix.len()].eq_ignore_ascii_case(&prefix)
+            {
+                format!("{}{}", &word[..opening], &rest[prefix.len()..])
+            } else {
+                word.to_string()
+            }
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Evaluates a filter to a bitmap of inverted index ids: terms and patterns from the
+/// posting lists, `field:value` and `zone:term` terms from the field index
+struct FilterEvaluator<'a> {
+    index: &'a CompressedInvertedIndex,
+    fields: Option<&'a FieldIndex>,
+}
+
+impl QueryEvaluator for FilterEvaluator<'_> {
+    type Output = RoaringBitmap;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        if let Some((field, value)) = term.split_once(':') {
+            if METADATA_FIELDS.contains(&field) || ZONE_FIELDS.contains(&field) {
+                let fields = self.fields.ok_or_else(|| {
+                    format!("No field index is saved, rebuild to filter by {}", term)
+                })?;
+                let documents = fields.documents(field, value).unwrap_or_default();
+                return Ok(documents
+                    .into_iter()
+                    .filter_map(|document| self.index.doc_name_to_id.get(document).copied())
+                    .collect());
+            }
+        }
+        self.index
+            .compressed_index
+            .get(term)
+            .map(|bytes| self.index.encoding.decode_bitmap(bytes))
+            .ok_or_else(|| format!("Term '{}' not found", term))
+    }
+
+    fn evaluate_wildcard(&self, pattern: &str) -> Result<Self::Output, String> {
+        Ok(self
+            .index
+            .compressed_index
+            .iter()
+            .filter(|(term, _)| glob_match(term, pattern))
+            .map(|(_, bytes)| self.index.encoding.decode_bitmap(bytes))
+            .fold(RoaringBitmap::new(), |matched, documents| {
+                matched | documents
+            }))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left & right
+    }
+
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left | right
+    }
+
+    fn difference(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left - right
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        let mut everything = RoaringBitmap::new();
+        everything.insert_range(0..self.index.doc_id_to_name.len() as u32);
+        everything - operand
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressedDictionary, Dictionary};
+
+    #[test]
+    fn test_filter_bitmap_limits_ranking() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "ракета башня башня"),
+            ("b.fb2", "рак башня"),
+            ("c.fb2", "ракета темная башня башня башня"),
+            ("d.fb2", "лес башня"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let index = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dict),
+        );
+        let mut fields = FieldIndex::new();
+        fields.insert("genre", "sf_fantasy", "a.fb2");
+        fields.insert("genre", "fantasy", "b.fb2");
+        fields.insert("genre", "prose_classic", "c.fb2");
+        fields.insert_terms("title", &["башня".to_string()], "d.fb2");
+
+        let query = HybridQuery::parse("рак* AND genre:fantasy RANK BY body:\"темная башня\"")
+            .unwrap()
+            .unwrap();
+        assert_eq!(query.filter_query, "рак* AND genre:fantasy");
+        assert_eq!(query.ranking_query, "\"темная башня\"");
+        assert!(HybridQuery::parse("рак and башня").is_none());
+        let filter = HybridQuery::parse("(Genre:fantasy or рак) and not ракета")
+            .unwrap()
+            .unwrap();
+        assert!(filter.is_filter_only());
+        assert_eq!(filter.filter(&index, Some(&fields)).unwrap().len(), 1);
+        assert!(HybridQuery::parse("рак rank by").unwrap().is_err());
+        let title = HybridQuery::parse("Title:башня or ракета")
+            .unwrap()
+            .unwrap();
+        assert!(title.is_filter_only());
+        assert_eq!(title.filter(&index, Some(&fields)).unwrap().len(), 3);
+
+        let candidates = query.filter(&index, Some(&fields)).unwrap();
+        assert_eq!(candidates.len(), 2);
+        // c.fb2 scores best on "темная башня" but is no fantasy, so it is never scored
+        let (ranked, stats) = query.rank(&index, &candidates, 10).unwrap();
+        let ranked: Vec<&str> = ranked
+            .iter()
+            .map(|(document, _)| document.as_str())
+            .collect();
+        assert_eq!(ranked, ["a.fb2", "b.fb2"]);
+        assert!(stats.scored <= 2);
+        assert!(query.filter(&index, None).is_err());
+
+        let everything = HybridQuery::parse("RANK BY темная").unwrap().unwrap();
+        assert_eq!(everything.filter(&index, None).unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::persist::{split_prefix, Persistable};
+use crate::{
+    Analyzer, CompressedDictionary, CompressedInvertedIndex, CoordinateIndex, Dictionary,
+    FB2Parser, IncidenceMatrix, IndexKind, IndexManifest, NGramPhraseIndex, SearchResults,
+    SharedSearchers, WildcardSearchEngine,
+};
+
+/// Every search structure of a corpus built in memory from plain text, without touching the
+/// filesystem. Meant for small synthetic corpora in tests, doctests and downstream crates;
+/// searches route between the structures exactly as `SharedSearchers` does for a saved index.
+///
+/// ```
+/// use grimoire::{Grimoire, IndexKind};
+///
+/// let grimoire = Grimoire::index_in_memory(vec![
+///     ("a", "war and peace"),
+///     ("b", "peace in the forest"),
+/// ])
+/// .unwrap();
+/// let (kind, documents) = grimoire.search("\"war and peace\"").unwrap();
+/// assert_eq!(kind, IndexKind::Coordinate);
+/// assert!(documents.contains("a") && !documents.contains("b"));
+/// ```
+pub struct Grimoire {
+    dictionary: Arc<CompressedDictionary>,
+    matrix: IncidenceMatrix,
+    bigram: NGramPhraseIndex,
+    searchers: SharedSearchers,
+    /// The parser's analyzer, recorded in the manifest by `save`
+    analyzer: Analyzer,
+}
+
+impl Grimoire {
+    /// Index `(id, text)` documents with the default parser
+    pub fn index_in_memory<S, T>(documents: Vec<(S, T)>) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        S: Into<String>,
+        T: AsRef<str>,
+    {
+        Self::index_in_memory_with(documents, &FB2Parser::new())
+    }
+
+    /// Index `(id, text)` documents, tokenizing each text as `parser` tokenizes FB2 bodies
+    pub fn index_in_memory_with<S, T>(
+        documents: Vec<(S, T)>,
+        parser: &FB2Parser,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        S: Into<String>,
+        T: AsRef<str>,
+    {
+        let mut dictionary = Dictionary::new();
+        let mut words: HashMap<String, Vec<String>> = HashMap::new();
+        for (id, text) in documents {
+            let id = id.into();
+            let text = text.as_ref();
+            if words.contains_key(&id) {
+                return Err(format!("Duplicate document id: {}", id).into());
+            }
+            let document_words = parser.parse_text(&id, text);
+            dictionary.document_id(&id);
+            dictionary.add_file_stats(text.len() as u64);
+            for word in &document_words {
+                dictionary.add_term(word.clone(), id.clone());
+            }
+            words.insert(id, document_words);
+        }
+
+        let dictionary = Arc::new(CompressedDictionary::from_dictionary(&dictionary));
+        let parse = |document: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Ok(words[document].clone())
+        };
+        let coordinate = CoordinateIndex::from_dictionary_with_parser(&dictionary, parse)?;
+        let bigram = NGramPhraseIndex::from_dictionary_with_parser(&dictionary, parse)?;
+        let searchers = SharedSearchers::from_structures(
+            CompressedInvertedIndex::from_compressed_dictionary(&dictionary),
+            coordinate,
+            WildcardSearchEngine::from_compressed_dictionary(Arc::clone(&dictionary)),
+        );
+
+        Ok(Grimoire {
+            matrix: IncidenceMatrix::from_dictionary(&*dictionary),
+            dictionary,
+            bigram,
+            searchers,
+            analyzer: parser.analyzer().clone(),
+        })
+    }
+
+    /// Save every structure under `prefix` as a build names them and commit them to the
+    /// manifest, so `SharedSearchers::open(prefix)` and the CLI search them like a built index
+    pub fn save(&self, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (dir, name) = split_prefix(prefix);
+        self.dictionary.save(dir, name)?;
+        self.matrix.save(dir, name)?;
+        self.inverted().save(dir, name)?;
+        self.bigram.save(dir, name)?;
+        self.coordinate().save(dir, name)?;
+        self.wildcard().save(dir, name)?;
+        IndexManifest::commit_with_analyzer(
+            prefix,
+            &[
+                "",
+                "_matrix",
+                "_index",
+                "_bigram",
+                "_coordinate",
+                "_wildcard",
+            ],
+            self.analyzer.clone(),
+        )?;
+        Ok(())
+    }
+
+    /// Documents matching the query and the structure that answered, see
+    /// `SharedSearchers::search`
+    pub fn search(&self, query: &str) -> Result<(IndexKind, HashSet<String>), String> {
+        self.searchers.search(query)
+    }
+
+    /// Documents matching the query in name order
+    pub fn results(&self, query: &str) -> Result<SearchResults, String> {
+        self.searchers.results(query)
+    }
+
+    pub fn dictionary(&self) -> &CompressedDictionary {
+        &self.dictionary
+    }
+
+    pub fn matrix(&self) -> &IncidenceMatrix {
+        &self.matrix
+    }
+
+    pub fn bigram(&self) -> &NGramPhraseIndex {
+        &self.bigram
+    }
+
+    pub fn inverted(&self) -> Arc<CompressedInvertedIndex> {
+        self.searchers.inverted().expect("built in memory")
+    }
+
+    pub fn coordinate(&self) -> Arc<CoordinateIndex> {
+        self.searchers.coordinate().expect("built in memory")
+    }
+
+    pub fn wildcard(&self) -> Arc<WildcardSearchEngine> {
+        self.searchers.wildcard().expect("built in memory")
+    }
+
+    /// The routing searchers, to share between threads
+    pub fn searchers(&self) -> &SharedSearchers {
+        &self.searchers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Analyzer, QueryParser};
+
+    #[test]
+    fn test_every_structure_answers_in_memory() {
+        let grimoire = Grimoire::index_in_memory(vec![
+            ("a.fb2".to_string(), "War and peace in the forest"),
+            ("b.fb2".to_string(), "Peace before war and freedom"),
+            ("c.fb2".to_string(), "Love and hate of freedom"),
+        ])
+        .unwrap();
+        let found = |documents: HashSet<String>| {
+            let mut found: Vec<String> = documents.into_iter().collect();
+            found.sort();
+            found
+        };
+
+        assert_eq!(grimoire.dictionary().total_documents, 3);
+        let (kind, documents) = grimoire.search("freedom and not hate").unwrap();
+        assert_eq!(kind, IndexKind::Inverted);
+        assert_eq!(found(documents), ["b.fb2"]);
+        let (kind, documents) = grimoire.search("near/1(before peace)").unwrap();
+        assert_eq!(kind, IndexKind::Coordinate);
+        assert_eq!(found(documents), ["b.fb2"]);
+        let (kind, documents) = grimoire.search("fre*").unwrap();
+        assert_eq!(kind, IndexKind::Wildcard);
+        assert_eq!(found(documents), ["b.fb2", "c.fb2"]);
+        assert_eq!(
+            found(grimoire.bigram().search("\"war and\"").unwrap()),
+            ["a.fb2", "b.fb2"]
+        );
+        let rows = grimoire.matrix().search("love").unwrap();
+        assert_eq!(grimoire.matrix().get_matching_documents(&rows), ["c.fb2"]);
+        assert_eq!(
+            grimoire.results("peace").unwrap().collect::<Vec<_>>(),
+            ["a.fb2", "b.fb2"]
+        );
+
+        let parser = FB2Parser::new().with_analyzer(Analyzer::from_spec("stopwords").unwrap());
+        let filtered = Grimoire::index_in_memory_with(vec![("a", "the forest")], &parser).unwrap();
+        assert!(!filtered.dictionary().contains_term("the"));
+        assert!(Grimoire::index_in_memory(vec![("a", "war"), ("a", "peace")]).is_err());
+    }
+}
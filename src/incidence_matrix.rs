@@ -1,9 +1,10 @@
 use bit_vec::BitVec;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 
-use crate::dictionary::CompressedDictionary;
-use crate::query::{tokenize, QueryParser};
+use crate::dictionary::CorpusSource;
+use crate::persist::Persistable;
+use crate::query::{Capabilities, QueryAst, QueryEvaluator, QueryParser};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IncidenceMatrix {
@@ -12,31 +13,41 @@ pub struct IncidenceMatrix {
     pub matrix: Vec<BitVec>,
 }
 
-impl IncidenceMatrix {
-    pub fn from_dictionary(dictionary: &CompressedDictionary) -> Self {
-        let terms = dictionary.extract_terms_parallel();
+impl Persistable for IncidenceMatrix {
+    const SUFFIX: &'static str = "_matrix";
+}
 
-        let mut documents: HashSet<String> = HashSet::new();
-        for term_entry in &dictionary.term_entries {
-            for doc in &term_entry.documents {
-                documents.insert(doc.clone());
+impl IncidenceMatrix {
+    pub fn from_dictionary<D: CorpusSource>(dictionary: &D) -> Self {
+        let names = dictionary.document_names();
+        let entries = dictionary.sorted_entries();
+
+        // Columns are the referenced documents in name order; `column[id]` maps a document id
+        // straight to its column
+        let mut referenced = vec![false; names.len()];
+        for (_, entry) in &entries {
+            for id in entry.documents.iter() {
+                referenced[id as usize] = true;
             }
         }
-        let mut documents: Vec<String> = documents.into_iter().collect();
-        documents.sort();
-
-        let mut matrix = Vec::new();
-        for term in &terms {
-            let mut row = BitVec::from_elem(documents.len(), false);
-            if let Some(term_entry) = dictionary.get_term_entry(term) {
-                for doc in &term_entry.documents {
-                    if let Some(doc_idx) = documents.iter().position(|d| d == doc) {
-                        row.set(doc_idx, true);
-                    }
-                }
-            }
-            matrix.push(row);
+        let mut ids: Vec<usize> = (0..names.len()).filter(|&id| referenced[id]).collect();
+        ids.sort_by(|&a, &b| names[a].cmp(&names[b]));
+        let mut column = vec![0; names.len()];
+        for (col, &id) in ids.iter().enumerate() {
+            column[id] = col;
         }
+        let documents: Vec<String> = ids.iter().map(|&id| names[id].clone()).collect();
+
+        let (terms, matrix) = entries
+            .into_par_iter()
+            .map(|(term, entry)| {
+                let mut row = BitVec::from_elem(documents.len(), false);
+                for id in entry.documents.iter() {
+                    row.set(column[id as usize], true);
+                }
+                (term.to_string(), row)
+            })
+            .unzip();
 
         IncidenceMatrix {
             terms,
@@ -56,61 +67,6 @@ impl IncidenceMatrix {
                 .sum::<usize>()
     }
 
-    fn parse_or_expr(&self, tokens: &[String], pos: &mut usize) -> Result<BitVec, String> {
-        let mut result = self.parse_and_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "or" {
-            *pos += 1;
-            let right = self.parse_and_expr(tokens, pos)?;
-            result.or(&right);
-        }
-
-        Ok(result)
-    }
-
-    fn parse_and_expr(&self, tokens: &[String], pos: &mut usize) -> Result<BitVec, String> {
-        let mut result = self.parse_not_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "and" {
-            *pos += 1;
-            let right = self.parse_not_expr(tokens, pos)?;
-            result.and(&right);
-        }
-
-        Ok(result)
-    }
-
-    fn parse_not_expr(&self, tokens: &[String], pos: &mut usize) -> Result<BitVec, String> {
-        if *pos < tokens.len() && tokens[*pos] == "not" {
-            *pos += 1;
-            let mut result = self.parse_primary(tokens, pos)?;
-            result.negate();
-            Ok(result)
-        } else {
-            self.parse_primary(tokens, pos)
-        }
-    }
-
-    fn parse_primary(&self, tokens: &[String], pos: &mut usize) -> Result<BitVec, String> {
-        if *pos >= tokens.len() {
-            return Err("Unexpected end of query".to_string());
-        }
-
-        if tokens[*pos] == "(" {
-            *pos += 1;
-            let result = self.parse_or_expr(tokens, pos)?;
-            if *pos >= tokens.len() || tokens[*pos] != ")" {
-                return Err("Missing closing parenthesis".to_string());
-            }
-            *pos += 1;
-            Ok(result)
-        } else {
-            let term = &tokens[*pos];
-            *pos += 1;
-            self.search_term(term)
-        }
-    }
-
     pub fn get_matching_documents(&self, result: &BitVec) -> Vec<&String> {
         result
             .iter()
@@ -129,18 +85,82 @@ impl IncidenceMatrix {
 impl QueryParser for IncidenceMatrix {
     type Result = BitVec;
     type Error = String;
+    const CAPABILITIES: Capabilities = Capabilities::NOT;
 
     fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
-        let tokens = tokenize(&query.to_lowercase())?;
-        let mut pos = 0;
-        self.parse_or_expr(&tokens, &mut pos)
+        self.evaluate(&QueryAst::parse(query)?)
     }
 
     fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
-        if let Some(term_idx) = self.terms.iter().position(|t| t == term) {
+        if let Ok(term_idx) = self.terms.binary_search_by(|t| t.as_str().cmp(term)) {
             Ok(self.matrix[term_idx].clone())
         } else {
             Err(format!("Term '{}' not found", term))
         }
     }
 }
+
+impl QueryEvaluator for IncidenceMatrix {
+    type Output = BitVec;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.search_term(term)
+    }
+
+    fn intersect(&self, mut left: BitVec, right: BitVec) -> BitVec {
+        left.and(&right);
+        left
+    }
+
+    fn unite(&self, mut left: BitVec, right: BitVec) -> BitVec {
+        left.or(&right);
+        left
+    }
+
+    fn complement(&self, mut operand: BitVec) -> BitVec {
+        operand.negate();
+        operand
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{CompressedDictionary, Dictionary};
+
+    #[test]
+    fn test_same_matrix_from_either_dictionary() {
+        let mut dict = Dictionary::new();
+        dict.add_term("war".to_string(), "c.fb2".to_string());
+        dict.add_term("peace".to_string(), "a.fb2".to_string());
+        dict.add_term("war".to_string(), "a.fb2".to_string());
+        dict.add_term("love".to_string(), "b.fb2".to_string());
+
+        let from_dictionary = IncidenceMatrix::from_dictionary(&dict);
+        let from_compressed =
+            IncidenceMatrix::from_dictionary(&CompressedDictionary::from_dictionary(&dict));
+
+        assert_eq!(from_dictionary.terms, vec!["love", "peace", "war"]);
+        assert_eq!(from_dictionary.documents, vec!["a.fb2", "b.fb2", "c.fb2"]);
+        assert_eq!(from_dictionary.matrix, from_compressed.matrix);
+        assert_eq!(
+            from_dictionary.get_matching_documents(&from_dictionary.search("war").unwrap()),
+            vec!["a.fb2", "c.fb2"]
+        );
+    }
+
+    #[test]
+    fn test_escaped_reserved_words_are_terms() {
+        let mut dict = Dictionary::new();
+        dict.add_term("and".to_string(), "a.fb2".to_string());
+        dict.add_term("war".to_string(), "a.fb2".to_string());
+        dict.add_term("war".to_string(), "b.fb2".to_string());
+        let matrix = IncidenceMatrix::from_dictionary(&dict);
+        let documents = |query: &str| matrix.get_matching_documents(&matrix.search(query).unwrap());
+
+        assert_eq!(documents("\\and"), vec!["a.fb2"]);
+        assert_eq!(documents("war and \"and\""), vec!["a.fb2"]);
+        assert_eq!(documents("war and not \\and"), vec!["b.fb2"]);
+        assert!(matrix.search("war and").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use regex::Regex;
+
+/// A step run on the body text of every document before it is split into words, e.g. to strip
+/// boilerplate or redact personal data. Register processors on `FB2Parser::with_processor`;
+/// they run in registration order and see the output of the previous one.
+pub trait DocumentProcessor: Send + Sync {
+    /// Shown in build output
+    fn name(&self) -> &str;
+
+    /// Rewritten body text of `document`. Text nodes are separated by newlines.
+    fn process(&self, document: &str, text: String) -> String;
+}
+
+/// Replaces every match of a pattern, removing it from the index when the replacement has no
+/// words of its own
+pub struct PatternReplacer {
+    name: String,
+    pattern: Regex,
+    replacement: String,
+}
+
+impl PatternReplacer {
+    pub fn new(name: &str, pattern: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(PatternReplacer {
+            name: name.to_string(),
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Drops matches of `pattern`
+    pub fn remove(pattern: &str) -> Result<Self, regex::Error> {
+        Self::new(&format!("strip /{}/", pattern), pattern, " ")
+    }
+
+    /// Drops e-mail addresses
+    pub fn redact_emails() -> Self {
+        Self::new(
+            "redact e-mail addresses",
+            r"[\w.+-]+@[\w-]+(\.[\w-]+)+",
+            " ",
+        )
+        .unwrap()
+    }
+}
+
+impl DocumentProcessor for PatternReplacer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&self, _document: &str, text: String) -> String {
+        match self.pattern.replace_all(&text, self.replacement.as_str()) {
+            std::borrow::Cow::Borrowed(_) => text,
+            std::borrow::Cow::Owned(replaced) => replaced,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FB2Parser;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_processors_run_before_tokenization() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let source = "<FictionBook><body><section>\n\
+                      <p>Chapter One</p>\n<p>Write to anna.karenina@mail.example today.</p>\n\
+                      </section></body></FictionBook>";
+        file.write_all(source.as_bytes()).unwrap();
+
+        let parser = FB2Parser::new()
+            .with_processor(Arc::new(PatternReplacer::remove(r"Chapter \w+").unwrap()))
+            .with_processor(Arc::new(PatternReplacer::redact_emails()));
+        assert_eq!(
+            parser.processors().map(|p| p.name()).collect::<Vec<_>>(),
+            vec![r"strip /Chapter \w+/", "redact e-mail addresses"]
+        );
+
+        let words = parser.parse_file(file.path()).unwrap();
+        assert_eq!(words, vec!["write", "today"]);
+        assert_eq!(
+            parser.parse_sentences(file.path()).unwrap(),
+            vec!["Write to   today."]
+        );
+
+        let with_offsets = parser.parse_file_with_offsets(file.path()).unwrap();
+        assert_eq!(with_offsets.len(), words.len());
+        for (word, start, end) in &with_offsets {
+            assert_eq!(source[*start..*end].to_lowercase(), *word);
+        }
+
+        let unprocessed = FB2Parser::new().parse_file(file.path()).unwrap();
+        assert!(unprocessed.contains(&"karenina".to_string()));
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::persist::Persistable;
+
+/// Term to term id mapping kept across builds. Ids are handed out in first-seen order and never
+/// reassigned, so a term keeps its id in every build that loads the saved interner, and
+/// structures or exports from different runs can be compared id for id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TermInterner {
+    /// Term of each id
+    terms: Vec<String>,
+    #[serde(skip)]
+    ids: HashMap<String, u32>,
+}
+
+impl Persistable for TermInterner {
+    const SUFFIX: &'static str = "_interner";
+}
+
+impl TermInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the interner saved as `name` in `dir`, starting empty when there is none
+    pub fn load_or_default(dir: &Path, name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(mut interner) = Self::load_if_present(dir, name)? else {
+            return Ok(Self::new());
+        };
+        interner.ids = interner
+            .terms
+            .iter()
+            .enumerate()
+            .map(|(id, term)| (term.clone(), id as u32))
+            .collect();
+        Ok(interner)
+    }
+
+    /// Id of `term`, assigning the next free id to a term seen for the first time
+    pub fn intern(&mut self, term: &str) -> u32 {
+        if let Some(&id) = self.ids.get(term) {
+            return id;
+        }
+        let id = self.terms.len() as u32;
+        self.terms.push(term.to_string());
+        self.ids.insert(term.to_string(), id);
+        id
+    }
+
+    /// Intern every term, returning how many were new
+    pub fn intern_all<'a, I>(&mut self, terms: I) -> usize
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let before = self.len();
+        for term in terms {
+            self.intern(term);
+        }
+        self.len() - before
+    }
+
+    pub fn get(&self, term: &str) -> Option<u32> {
+        self.ids.get(term).copied()
+    }
+
+    pub fn term(&self, id: u32) -> Option<&str> {
+        self.terms.get(id as usize).map(String::as_str)
+    }
+
+    /// Terms indexed by id
+    pub fn terms(&self) -> &[String] {
+        &self.terms
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_survive_save_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut interner = TermInterner::load_or_default(dir.path(), "idx").unwrap();
+        assert_eq!(interner.intern_all(["war", "peace", "war"]), 2);
+        interner.save(dir.path(), "idx").unwrap();
+
+        let mut reloaded = TermInterner::load_or_default(dir.path(), "idx").unwrap();
+        assert_eq!(reloaded.intern_all(["anna", "peace"]), 1);
+        assert_eq!(reloaded.get("war"), Some(0));
+        assert_eq!(reloaded.get("peace"), Some(1));
+        assert_eq!(reloaded.intern("anna"), 2);
+        assert_eq!(reloaded.term(1), Some("peace"));
+        assert_eq!(reloaded.get("missing"), None);
+    }
+}
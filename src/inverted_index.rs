@@ -1,12 +1,30 @@
-use serde::{Deserialize, Serialize};
+use roaring::RoaringBitmap;
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use rayon::prelude::*;
 
-use crate::dictionary::{Dictionary, CompressedDictionary};
-use crate::query::{tokenize, QueryParser};
+use crate::codec::{PostingEncoding, PostingIterator};
+use crate::dictionary::{Dictionary, CompressedDictionary, TermEntry};
+use crate::doc_ids::{
+    complement_doc_ids, intersect_doc_ids, intersect_postings, subtract_doc_ids, unite_doc_ids,
+};
+use crate::explain::{time_stage, trace_evaluation, QueryExplanation, TracedEvaluator};
+use crate::optimizer::{explain_query, optimize_query};
+use crate::persist::Persistable;
+use crate::planner::IndexKind;
+use crate::{
+    read_posting_blocks, write_posting_blocks, DamagedBlock, PostingBlocks, TuningConfig,
+};
+use crate::query::{query_terms, Capabilities, QueryAst, QueryEvaluator, QueryParser};
+use crate::tfidf::{idf, rank_by_tfidf, tf_weight};
+use crate::top_k::{wand_top_k, TermCursor, TopKStats};
 
 /// Variable-Byte encoding utilities for compressing document IDs
-mod vb_encoding {
+pub(crate) mod vb_encoding {
     /// Encode a single integer using Variable-Byte encoding
     pub fn encode_vb(mut n: u32) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -44,6 +62,24 @@ mod vb_encoding {
         numbers
     }
 
+    /// Decode the single VB-encoded integer starting at `*pos` and advance past it
+    pub fn read_vb(bytes: &[u8], pos: &mut usize) -> u32 {
+        let mut n = 0u32;
+        let mut shift = 0;
+        while *pos < bytes.len() {
+            let byte = bytes[*pos];
+            *pos += 1;
+            if byte < 128 {
+                n += (byte as u32) << shift;
+                shift += 7;
+            } else {
+                n += ((byte - 128) as u32) << shift;
+                break;
+            }
+        }
+        n
+    }
+
     /// Encode a list of integers with delta compression + VB encoding
     pub fn encode_delta_vb(mut numbers: Vec<u32>) -> Vec<u8> {
         if numbers.is_empty() {
@@ -80,47 +116,192 @@ mod vb_encoding {
 
 use vb_encoding::*;
 
+/// Size of every posting list in one encoding and how long Boolean operations over term
+/// pairs took, decoding the lists included
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingReport {
+    pub encoding: PostingEncoding,
+    pub size_bytes: usize,
+    /// Term pairs each operation ran on; pairs with an unindexed term are skipped
+    pub operations: usize,
+    pub and_time: Duration,
+    pub or_time: Duration,
+    /// Complement of the first term of every pair
+    pub not_time: Duration,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InvertedIndex {
     pub index: HashMap<String, HashSet<String>>,
     pub documents: Vec<String>,
+    /// Occurrences of each term per document; a posting without a count occurs once
+    #[serde(default)]
+    pub term_frequencies: HashMap<String, HashMap<String, u32>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct CompressedInvertedIndex {
     /// Compressed posting lists: term -> compressed document IDs
     pub compressed_index: HashMap<String, Vec<u8>>,
+    /// VB-encoded occurrence counts, one per document ID of the term's posting list
+    pub compressed_frequencies: HashMap<String, Vec<u8>>,
     /// Document ID to document name mapping
     pub doc_id_to_name: Vec<String>,
-    /// Document name to ID mapping for fast lookups
+    /// Document name to ID mapping for fast lookups; derived from `doc_id_to_name` on load
     pub doc_name_to_id: HashMap<String, u32>,
     /// Total memory used by compressed data
     pub compressed_size: usize,
     /// Original uncompressed size for comparison
     pub uncompressed_size: usize,
+    /// How the posting lists of `compressed_index` are encoded
+    pub encoding: PostingEncoding,
+    /// Highest occurrence count of each term in one document, bounding the score the term
+    /// adds to any document so top-k ranking can skip documents that cannot make it
+    pub max_term_frequencies: HashMap<String, u32>,
+    /// Posting blocks that failed their checksum on load; their terms are missing until
+    /// `restore_terms` rebuilds them
+    pub damaged_blocks: Vec<DamagedBlock>,
+}
+
+impl Persistable for CompressedInvertedIndex {
+    const SUFFIX: &'static str = "_index";
+
+    fn format_version() -> u32 {
+        3
+    }
+
+    fn write_payload(&self, data: &mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        data.extend(self.payload()?);
+        Ok(())
+    }
+
+    fn read_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_payload(payload)
+    }
+}
+
+/// Compressed document ids, VB-encoded occurrence counts and highest count of one term
+type StoredPostings = (Vec<u8>, Vec<u8>, u32);
+
+/// Fields of `CompressedInvertedIndex` kept in the posting block directory, without the ones
+/// derived on load; the postings of every term go in the blocks, see `PostingBlocks`
+#[derive(Serialize, Deserialize)]
+struct InvertedIndexMeta<'a> {
+    doc_id_to_name: Cow<'a, [String]>,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    encoding: PostingEncoding,
+}
+
+/// Serialized as its posting block payload
+impl Serialize for CompressedInvertedIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.payload().map_err(S::Error::custom)?)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressedInvertedIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::from_payload(&Vec::<u8>::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl CompressedInvertedIndex {
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let meta = InvertedIndexMeta {
+            doc_id_to_name: Cow::Borrowed(&self.doc_id_to_name),
+            compressed_size: self.compressed_size,
+            uncompressed_size: self.uncompressed_size,
+            encoding: self.encoding,
+        };
+        let mut terms: Vec<&String> = self.compressed_index.keys().collect();
+        terms.sort_unstable();
+        write_posting_blocks(
+            &meta,
+            terms.into_iter().map(|term| {
+                let counts = self.compressed_frequencies.get(term).map_or(&[][..], Vec::as_slice);
+                let max = self.max_term_frequencies.get(term).copied().unwrap_or(1);
+                (term.as_str(), (&self.compressed_index[term], counts, max))
+            }),
+        )
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let stored: PostingBlocks<InvertedIndexMeta, StoredPostings> =
+            read_posting_blocks(payload, "the inverted index")?;
+        let mut compressed_index = HashMap::with_capacity(stored.entries.len());
+        let mut compressed_frequencies = HashMap::with_capacity(stored.entries.len());
+        let mut max_term_frequencies = HashMap::with_capacity(stored.entries.len());
+        for (term, (doc_ids, counts, max)) in stored.entries {
+            compressed_frequencies.insert(term.clone(), counts);
+            max_term_frequencies.insert(term.clone(), max);
+            compressed_index.insert(term, doc_ids);
+        }
+        let doc_id_to_name = stored.meta.doc_id_to_name.into_owned();
+        Ok(CompressedInvertedIndex {
+            doc_name_to_id: doc_name_to_id(&doc_id_to_name),
+            compressed_index,
+            compressed_frequencies,
+            doc_id_to_name,
+            compressed_size: stored.meta.compressed_size,
+            uncompressed_size: stored.meta.uncompressed_size,
+            encoding: stored.meta.encoding,
+            max_term_frequencies,
+            damaged_blocks: stored.damaged,
+        })
+    }
+}
+
+/// The highest VB-encoded occurrence count of every term
+fn max_term_frequencies(compressed_frequencies: &HashMap<String, Vec<u8>>) -> HashMap<String, u32> {
+    compressed_frequencies
+        .iter()
+        .map(|(term, counts)| {
+            let max = decode_vb(counts).into_iter().max().unwrap_or(1);
+            (term.clone(), max)
+        })
+        .collect()
+}
+
+/// Occurrences of a dictionary term in each of its documents, by document name
+fn occurrence_counts(entry: &TermEntry, documents: &[String]) -> HashMap<String, u32> {
+    entry
+        .documents
+        .iter()
+        .map(|id| (documents[id as usize].clone(), entry.term_frequency(id).max(1)))
+        .collect()
+}
+
+fn doc_name_to_id(doc_id_to_name: &[String]) -> HashMap<String, u32> {
+    doc_id_to_name
+        .iter()
+        .enumerate()
+        .map(|(id, name)| (name.clone(), id as u32))
+        .collect()
 }
 
 impl InvertedIndex {
     pub fn from_dictionary(dictionary: &CompressedDictionary) -> Self {
         // Use parallel processing for large dictionaries
-        const PARALLEL_THRESHOLD: usize = 1000;
-        
-        if dictionary.sorted_terms.len() < PARALLEL_THRESHOLD {
+        if dictionary.sorted_terms.len() < TuningConfig::current().parallel_threshold {
             // Sequential processing for small dictionaries
             let mut index = HashMap::new();
             let mut documents = HashSet::new();
 
+            let mut term_frequencies = HashMap::new();
+
             for (i, term) in dictionary.sorted_terms.iter().enumerate() {
                 let term_entry = &dictionary.term_entries[i];
-                index.insert(term.clone(), term_entry.documents.clone());
-                for doc in &term_entry.documents {
+                index.insert(term.clone(), term_entry.documents.to_names(&dictionary.documents));
+                term_frequencies.insert(term.clone(), occurrence_counts(term_entry, &dictionary.documents));
+                for doc in term_entry.documents.names(&dictionary.documents) {
                     documents.insert(doc.clone());
                 }
             }
 
             let mut documents: Vec<String> = documents.into_iter().collect();
             documents.sort();
-            InvertedIndex { index, documents }
+            InvertedIndex { index, documents, term_frequencies }
         } else {
             // Parallel processing for large dictionaries
             let term_data: Vec<_> = dictionary.sorted_terms.iter().zip(dictionary.term_entries.iter()).collect();
@@ -129,26 +310,32 @@ impl InvertedIndex {
             let index: HashMap<String, HashSet<String>> = term_data
                 .par_iter()
                 .map(|(term, term_entry)| {
-                    ((*term).clone(), term_entry.documents.clone())
+                    ((*term).clone(), term_entry.documents.to_names(&dictionary.documents))
+                })
+                .collect();
+            let term_frequencies: HashMap<String, HashMap<String, u32>> = term_data
+                .par_iter()
+                .map(|(term, term_entry)| {
+                    ((*term).clone(), occurrence_counts(term_entry, &dictionary.documents))
                 })
                 .collect();
 
             // Collect all unique documents in parallel
             let all_docs: HashSet<String> = term_data
                 .par_iter()
-                .flat_map(|(_, term_entry)| term_entry.documents.par_iter().cloned())
+                .flat_map_iter(|(_, term_entry)| term_entry.documents.names(&dictionary.documents).cloned())
                 .collect();
 
             let mut documents: Vec<String> = all_docs.into_iter().collect();
             
             // Use parallel sort for large document collections
-            if documents.len() > 10000 {
+            if documents.len() > TuningConfig::current().parallel_sort_threshold {
                 documents.par_sort_unstable();
             } else {
                 documents.sort_unstable();
             }
 
-            InvertedIndex { index, documents }
+            InvertedIndex { index, documents, term_frequencies }
         }
     }
 
@@ -165,90 +352,102 @@ impl InvertedIndex {
                 .sum::<usize>()
             + self.documents.iter().map(|d| d.len()).sum::<usize>()
     }
+}
 
-    fn parse_or_expr(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_and_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "or" {
-            *pos += 1;
-            let right = self.parse_and_expr(tokens, pos)?;
-            result = result.union(&right).cloned().collect();
-        }
+impl QueryParser for InvertedIndex {
+    type Result = HashSet<String>;
+    type Error = String;
+    const CAPABILITIES: Capabilities = Capabilities::NOT.union(Capabilities::RANKING);
 
-        Ok(result)
+    fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
+        self.evaluate(&QueryAst::parse(query)?)
     }
 
-    fn parse_and_expr(
-        &self,
-        tokens: &[String],
-        pos: &mut usize,
-    ) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_not_expr(tokens, pos)?;
-
-        while *pos < tokens.len() && tokens[*pos] == "and" {
-            *pos += 1;
-            let right = self.parse_not_expr(tokens, pos)?;
-            result = result.intersection(&right).cloned().collect();
+    fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
+        if let Some(docs) = self.index.get(term) {
+            Ok(docs.clone())
+        } else {
+            Err(format!("Term '{}' not found", term))
         }
+    }
+}
+
+impl QueryEvaluator for InvertedIndex {
+    type Output = HashSet<String>;
 
-        Ok(result)
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.search_term(term)
     }
 
-    fn parse_not_expr(
-        &self,
-        tokens: &[String],
-        pos: &mut usize,
-    ) -> Result<HashSet<String>, String> {
-        if *pos < tokens.len() && tokens[*pos] == "not" {
-            *pos += 1;
-            let result = self.parse_primary(tokens, pos)?;
-            let all_docs: HashSet<String> = self.documents.iter().cloned().collect();
-            Ok(all_docs.difference(&result).cloned().collect())
-        } else {
-            self.parse_primary(tokens, pos)
-        }
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left.intersection(&right).cloned().collect()
     }
 
-    fn parse_primary(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        if *pos >= tokens.len() {
-            return Err("Unexpected end of query".to_string());
-        }
+    fn unite(&self, mut left: Self::Output, right: Self::Output) -> Self::Output {
+        left.extend(right);
+        left
+    }
 
-        if tokens[*pos] == "(" {
-            *pos += 1;
-            let result = self.parse_or_expr(tokens, pos)?;
-            if *pos >= tokens.len() || tokens[*pos] != ")" {
-                return Err("Missing closing parenthesis".to_string());
-            }
-            *pos += 1;
-            Ok(result)
-        } else {
-            let term = &tokens[*pos];
-            *pos += 1;
-            self.search_term(term)
-        }
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.documents
+            .iter()
+            .filter(|document| !operand.contains(*document))
+            .cloned()
+            .collect()
     }
 }
 
-impl QueryParser for InvertedIndex {
-    type Result = HashSet<String>;
-    type Error = String;
+impl InvertedIndex {
+    /// Occurrences of `term` in `document`
+    pub fn term_frequency(&self, term: &str, document: &str) -> u32 {
+        match self.term_frequencies.get(term).and_then(|counts| counts.get(document)) {
+            Some(&count) => count,
+            None => self.index.get(term).map_or(0, |docs| docs.contains(document) as u32),
+        }
+    }
 
-    fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
-        let tokens = tokenize(&query.to_lowercase())?;
-        let mut pos = 0;
-        self.parse_or_expr(&tokens, &mut pos)
+    /// Documents matching a Boolean query, the `k` with the highest TF-IDF score first
+    pub fn search_ranked(&self, query: &str, k: usize) -> Result<Vec<(String, f64)>, String> {
+        let documents = self.search(query)?;
+        self.rank_documents(documents, query, k)
     }
 
-    fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
-        if let Some(docs) = self.index.get(term) {
-            Ok(docs.clone())
-        } else {
-            Err(format!("Term '{}' not found", term))
-        }
+    /// Rank `documents` by the TF-IDF weight of the query's terms, keeping the best `k`
+    pub fn rank_documents<I>(&self, documents: I, query: &str, k: usize) -> Result<Vec<(String, f64)>, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let terms = query_terms(query)?;
+        let mut ranked = rank_by_tfidf(
+            documents,
+            &terms,
+            self.documents.len(),
+            |term| self.index.get(term).map_or(0, HashSet::len),
+            |term, document| self.term_frequency(term, document),
+        );
+        ranked.truncate(k);
+        Ok(ranked)
     }
 }
 
+/// Sorted document ids of a posting set, VB-encoded as deltas, and the term's occurrence count
+/// in each of them
+fn encode_postings(
+    index: &InvertedIndex,
+    term: &str,
+    docs: &HashSet<String>,
+    doc_name_to_id: &HashMap<String, u32>,
+) -> (usize, Vec<u8>, Vec<u8>) {
+    let mut postings: Vec<(u32, u32)> = docs
+        .iter()
+        .filter_map(|doc| Some((*doc_name_to_id.get(doc)?, index.term_frequency(term, doc))))
+        .collect();
+    postings.sort_unstable();
+    let frequencies: Vec<u8> = postings.iter().flat_map(|&(_, count)| encode_vb(count)).collect();
+    let doc_ids: Vec<u32> = postings.into_iter().map(|(id, _)| id).collect();
+    (doc_ids.len(), encode_delta_vb(doc_ids), frequencies)
+}
+
 impl CompressedInvertedIndex {
     /// Create a compressed inverted index from a regular inverted index
     pub fn from_inverted_index(index: &InvertedIndex) -> Self {
@@ -258,29 +457,22 @@ impl CompressedInvertedIndex {
         let mut doc_id_to_name = index.documents.clone();
         doc_id_to_name.sort(); // Ensure consistent ordering
         
-        let doc_name_to_id: HashMap<String, u32> = doc_id_to_name
-            .iter()
-            .enumerate()
-            .map(|(id, name)| (name.clone(), id as u32))
-            .collect();
+        let doc_name_to_id = doc_name_to_id(&doc_id_to_name);
 
         let mut compressed_index = HashMap::new();
+        let mut compressed_frequencies = HashMap::new();
         let mut total_compressed_size = 0;
         let mut total_uncompressed_size = 0;
 
         // Use parallel processing for large indexes
-        const PARALLEL_THRESHOLD: usize = 1000;
-        
-        if index.index.len() < PARALLEL_THRESHOLD {
+        if index.index.len() < TuningConfig::current().parallel_threshold {
             // Sequential compression for small indexes
             for (term, docs) in &index.index {
-                let doc_ids: Vec<u32> = docs
-                    .iter()
-                    .filter_map(|doc| doc_name_to_id.get(doc).copied())
-                    .collect();
+                let (doc_count, compressed_bytes, frequencies) =
+                    encode_postings(index, term, docs, &doc_name_to_id);
                 
-                let uncompressed_size = doc_ids.len() * 4; // 4 bytes per u32
-                let compressed_bytes = encode_delta_vb(doc_ids);
+                let uncompressed_size = doc_count * 4; // 4 bytes per u32
+                compressed_frequencies.insert(term.clone(), frequencies);
                 
                 total_uncompressed_size += uncompressed_size;
                 total_compressed_size += compressed_bytes.len();
@@ -291,18 +483,19 @@ impl CompressedInvertedIndex {
             // Parallel compression for large indexes
             let entries: Vec<_> = index.index.iter().collect();
             
-            let compressed_entries: HashMap<String, Vec<u8>> = entries
+            let encoded: Vec<(String, Vec<u8>, Vec<u8>)> = entries
                 .par_iter()
                 .map(|(term, docs)| {
-                    let doc_ids: Vec<u32> = docs
-                        .iter()
-                        .filter_map(|doc| doc_name_to_id.get(doc).copied())
-                        .collect();
-                    
-                    let compressed_bytes = encode_delta_vb(doc_ids);
-                    ((*term).clone(), compressed_bytes)
+                    let (_, compressed_bytes, frequencies) =
+                        encode_postings(index, term, docs, &doc_name_to_id);
+                    ((*term).clone(), compressed_bytes, frequencies)
                 })
                 .collect();
+            let mut compressed_entries: HashMap<String, Vec<u8>> = HashMap::with_capacity(encoded.len());
+            for (term, compressed_bytes, frequencies) in encoded {
+                compressed_frequencies.insert(term.clone(), frequencies);
+                compressed_entries.insert(term, compressed_bytes);
+            }
 
             // Calculate sizes
             for (term, docs) in &index.index {
@@ -330,11 +523,15 @@ impl CompressedInvertedIndex {
         );
 
         CompressedInvertedIndex {
+            max_term_frequencies: max_term_frequencies(&compressed_frequencies),
             compressed_index,
+            compressed_frequencies,
             doc_id_to_name,
             doc_name_to_id,
             compressed_size: total_compressed_size,
             uncompressed_size: total_uncompressed_size,
+            encoding: PostingEncoding::VbDelta,
+            damaged_blocks: Vec::new(),
         }
     }
 
@@ -351,72 +548,437 @@ impl CompressedInvertedIndex {
     /// Create compressed inverted index from compressed dictionary
     pub fn from_compressed_dictionary(dictionary: &CompressedDictionary) -> Self {
         println!("CompressedInvertedIndex: Creating compressed index from compressed dictionary...");
-        
-        // Use parallel processing for large dictionaries
-        const PARALLEL_THRESHOLD: usize = 1000;
-        
-        if dictionary.sorted_terms.len() < PARALLEL_THRESHOLD {
-            // Sequential processing for small dictionaries
-            let mut index = HashMap::new();
-            let mut documents = HashSet::new();
+        Self::from_inverted_index(&InvertedIndex::from_dictionary(dictionary))
+    }
 
-            for (i, term) in dictionary.sorted_terms.iter().enumerate() {
-                let term_entry = &dictionary.term_entries[i];
-                index.insert(term.clone(), term_entry.documents.clone());
-                for doc in &term_entry.documents {
-                    documents.insert(doc.clone());
-                }
+    /// Decompress posting list for a specific term
+    pub fn get_documents_for_term(&self, term: &str) -> Option<Vec<String>> {
+        if let Some(compressed_bytes) = self.compressed_index.get(term) {
+            let doc_ids = self.encoding.decode(compressed_bytes);
+            let documents: Vec<String> = doc_ids
+                .into_iter()
+                .filter_map(|id| self.doc_id_to_name.get(id as usize).cloned())
+                .collect();
+            Some(documents)
+        } else {
+            None
+        }
+    }
+
+    /// Sorted ids of the documents containing `term`
+    pub fn doc_ids_for_term(&self, term: &str) -> Option<Vec<u32>> {
+        self.compressed_index.get(term).map(|bytes| self.encoding.decode(bytes))
+    }
+
+    /// Documents containing `term`, counted from its occurrence counts without decoding the
+    /// posting list: every VB-encoded count ends in a byte with the high bit set
+    pub fn document_frequency(&self, term: &str) -> usize {
+        self.compressed_frequencies
+            .get(term)
+            .map_or(0, |counts| counts.iter().filter(|&&byte| byte >= 128).count())
+    }
+
+    /// `query` with AND operands ordered by ascending document frequency and negations last,
+    /// see `optimize_query`
+    pub fn optimize_query(&self, query: QueryAst) -> QueryAst {
+        optimize_query(query, self.doc_id_to_name.len(), &mut |term| {
+            self.planning_frequency(term)
+        })
+    }
+
+    /// The evaluation order of an optimized query with estimated hits, see `explain_query`
+    pub fn explain_query(&self, query: &QueryAst) -> Vec<String> {
+        explain_query(query, self.doc_id_to_name.len(), &mut |term| {
+            self.planning_frequency(term)
+        })
+    }
+
+    /// `search` with every node of the optimized query traced and each stage timed
+    pub fn explain(&self, query: &str) -> Result<QueryExplanation, String> {
+        let mut stages = Vec::new();
+        let query = time_stage(&mut stages, "parse", || QueryAst::parse(query))?;
+        let query = time_stage(&mut stages, "optimize", || self.optimize_query(query));
+        let (documents, trace) = match self.encoding {
+            PostingEncoding::Roaring => {
+                let (bitmap, trace) = time_stage(&mut stages, "evaluate", || {
+                    trace_evaluation(&BitmapEvaluator(self), &query)
+                })?;
+                (time_stage(&mut stages, "names", || self.document_names(bitmap)), trace)
             }
+            _ => {
+                let (doc_ids, trace) =
+                    time_stage(&mut stages, "evaluate", || trace_evaluation(self, &query))?;
+                (time_stage(&mut stages, "names", || self.document_names(doc_ids)), trace)
+            }
+        };
+        Ok(QueryExplanation { kind: IndexKind::Inverted, trace, stages, documents })
+    }
 
-            let mut documents: Vec<String> = documents.into_iter().collect();
-            documents.sort();
-            
-            let regular_index = InvertedIndex { index, documents };
-            Self::from_inverted_index(&regular_index)
+    /// Document frequency the optimizer plans with; patterns are assumed to match everything
+    fn planning_frequency(&self, term: &str) -> usize {
+        if term.contains(['*', '?']) {
+            self.doc_id_to_name.len()
         } else {
-            // Parallel processing for large dictionaries
-            let term_data: Vec<_> = dictionary.sorted_terms.iter().zip(dictionary.term_entries.iter()).collect();
-            
-            // Build index in parallel
-            let index: HashMap<String, HashSet<String>> = term_data
-                .par_iter()
-                .map(|(term, term_entry)| {
-                    ((*term).clone(), term_entry.documents.clone())
+            self.document_frequency(term)
+        }
+    }
+
+    /// The posting list of `term`, decoded lazily as it is iterated
+    pub fn postings_for_term(&self, term: &str) -> Option<PostingIterator<'_>> {
+        self.compressed_index.get(term).map(|bytes| self.encoding.postings(bytes))
+    }
+
+    /// Sorted ids of the documents containing any of `terms`; many posting lists, as a broad
+    /// wildcard pattern expands to, are decoded in parallel
+    pub fn doc_ids_for_terms<'a, I>(&self, terms: I) -> Vec<u32>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let postings: Vec<&Vec<u8>> = terms
+            .into_iter()
+            .filter_map(|term| self.compressed_index.get(term))
+            .collect();
+        let mut doc_ids: Vec<u32> = if postings.len() > TuningConfig::current().parallel_lookup_threshold {
+            postings.par_iter().flat_map_iter(|bytes| self.encoding.decode(bytes)).collect()
+        } else {
+            postings.iter().flat_map(|bytes| self.encoding.decode(bytes)).collect()
+        };
+        doc_ids.sort_unstable();
+        doc_ids.dedup();
+        doc_ids
+    }
+
+    /// Sorted ids of named documents; documents this index doesn't know are left out
+    pub fn doc_ids_of<'a, I>(&self, documents: I) -> Vec<u32>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut doc_ids: Vec<u32> = documents
+            .into_iter()
+            .filter_map(|document| self.doc_name_to_id.get(document).copied())
+            .collect();
+        doc_ids.sort_unstable();
+        doc_ids.dedup();
+        doc_ids
+    }
+
+    /// Names of the documents with the given ids, the last step of Boolean evaluation
+    pub fn document_names<I: IntoIterator<Item = u32>>(&self, doc_ids: I) -> HashSet<String> {
+        doc_ids
+            .into_iter()
+            .filter_map(|id| self.doc_id_to_name.get(id as usize).cloned())
+            .collect()
+    }
+
+    /// Sorted ids of the documents matching a Boolean query, evaluated on bitmaps for a
+    /// roaring-encoded index; `search` names them all at once
+    pub fn search_ids(&self, query: &str) -> Result<Vec<u32>, String> {
+        let query = self.optimize_query(QueryAst::parse(query)?);
+        match self.encoding {
+            PostingEncoding::Roaring => {
+                Ok(BitmapEvaluator(self).evaluate(&query)?.iter().collect())
+            }
+            _ => self.evaluate(&query),
+        }
+    }
+
+    /// Re-encode every posting list, e.g. to roaring bitmaps right after a build
+    pub fn with_encoding(mut self, encoding: PostingEncoding) -> Self {
+        if encoding != self.encoding {
+            let current = self.encoding;
+            self.compressed_index
+                .par_iter_mut()
+                .for_each(|(_, bytes)| *bytes = encoding.encode(&current.decode(bytes)));
+            self.compressed_size = self.compressed_index.values().map(Vec::len).sum();
+            self.encoding = encoding;
+        }
+        self
+    }
+
+    /// Encode the posting lists in every encoding and time AND, OR and NOT over the posting
+    /// lists of `term_pairs` in each, to pick an encoding for a collection
+    pub fn compare_encodings(&self, term_pairs: &[(&str, &str)]) -> Vec<EncodingReport> {
+        PostingEncoding::ALL
+            .into_iter()
+            .map(|encoding| self.measure_encoding(encoding, term_pairs))
+            .collect()
+    }
+
+    fn measure_encoding(
+        &self,
+        encoding: PostingEncoding,
+        term_pairs: &[(&str, &str)],
+    ) -> EncodingReport {
+        let postings: HashMap<&str, Vec<u8>> = self
+            .compressed_index
+            .par_iter()
+            .map(|(term, bytes)| (term.as_str(), encoding.encode(&self.encoding.decode(bytes))))
+            .collect();
+        let pairs: Vec<(&[u8], &[u8])> = term_pairs
+            .iter()
+            .filter_map(|(left, right)| Some((postings.get(left)?, postings.get(right)?)))
+            .map(|(left, right)| (left.as_slice(), right.as_slice()))
+            .collect();
+        let time = |operation: &dyn Fn(&[u8], &[u8]) -> usize| {
+            let start = Instant::now();
+            let matched: usize = pairs.iter().map(|&(left, right)| operation(left, right)).sum();
+            std::hint::black_box(matched);
+            start.elapsed()
+        };
+
+        let total = self.doc_id_to_name.len() as u32;
+        let ids = |bytes: &[u8]| encoding.decode(bytes);
+        let bitmap = |bytes: &[u8]| encoding.decode_bitmap(bytes);
+        let (and_time, or_time, not_time) = match encoding {
+            PostingEncoding::Roaring => {
+                let everything: RoaringBitmap = (0..total).collect();
+                (
+                    time(&|left, right| (bitmap(left) & bitmap(right)).len() as usize),
+                    time(&|left, right| (bitmap(left) | bitmap(right)).len() as usize),
+                    time(&|left, _| (&everything - bitmap(left)).len() as usize),
+                )
+            }
+            _ => (
+                time(&|left, right| intersect_doc_ids(&ids(left), &ids(right)).len()),
+                time(&|left, right| unite_doc_ids(&ids(left), &ids(right)).len()),
+                time(&|left, _| complement_doc_ids(&ids(left), total).len()),
+            ),
+        };
+        EncodingReport {
+            encoding,
+            size_bytes: postings.values().map(Vec::len).sum(),
+            operations: pairs.len(),
+            and_time,
+            or_time,
+            not_time,
+        }
+    }
+
+    /// Decompress the posting list of a term with the term's occurrence count in each document
+    pub fn get_postings_for_term(&self, term: &str) -> Option<Vec<(String, u32)>> {
+        let doc_ids = self.encoding.decode(self.compressed_index.get(term)?);
+        let counts = self
+            .compressed_frequencies
+            .get(term)
+            .map_or_else(Vec::new, |bytes| decode_vb(bytes));
+        Some(
+            doc_ids
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, id)| {
+                    let name = self.doc_id_to_name.get(id as usize)?;
+                    Some((name.clone(), counts.get(i).copied().unwrap_or(1)))
                 })
+                .collect(),
+        )
+    }
+
+    /// Occurrences of `term` in `document`
+    pub fn term_frequency(&self, term: &str, document: &str) -> u32 {
+        self.get_postings_for_term(term)
+            .and_then(|postings| postings.into_iter().find(|(doc, _)| doc == document))
+            .map_or(0, |(_, count)| count)
+    }
+
+    /// Documents matching a Boolean query, the `k` with the highest TF-IDF score first
+    pub fn search_ranked(&self, query: &str, k: usize) -> Result<Vec<(String, f64)>, String> {
+        let documents = self.search(query)?;
+        self.rank_documents(documents, query, k)
+    }
+
+    /// Rank `documents` by the TF-IDF weight of the query's terms, keeping the best `k`, see
+    /// `rank_top_k`
+    pub fn rank_documents<I>(&self, documents: I, query: &str, k: usize) -> Result<Vec<(String, f64)>, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Ok(self.rank_top_k(documents, query, k)?.0)
+    }
+
+    /// Rank `documents` by the TF-IDF weight of the query's terms and keep the best `k`,
+    /// walking the terms' posting lists with WAND (see `wand_top_k`) so only documents that
+    /// can still make the top `k` are scored. Ties go to the name first in order, as ids
+    /// follow names. Documents without a query term score 0 and fill the places left.
+    pub fn rank_top_k<I>(
+        &self,
+        documents: I,
+        query: &str,
+        k: usize,
+    ) -> Result<(Vec<(String, f64)>, TopKStats), String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let documents: Vec<String> = documents.into_iter().collect();
+        let candidates = self.doc_ids_of(&documents);
+        let (mut ranked, stats) =
+            self.wand_ranked(query, k, |id| candidates.binary_search(&id).is_ok())?;
+        if ranked.len() < k {
+            let scored: HashSet<String> = ranked.iter().map(|(doc, _)| doc.clone()).collect();
+            let mut unscored: Vec<String> = documents
+                .into_iter()
+                .filter(|document| !scored.contains(document))
                 .collect();
+            unscored.sort_unstable();
+            unscored.dedup();
+            let places = k - ranked.len();
+            ranked.extend(unscored.into_iter().take(places).map(|document| (document, 0.0)));
+        }
+        Ok((ranked, stats))
+    }
 
-            // Collect all unique documents in parallel
-            let all_docs: HashSet<String> = term_data
-                .par_iter()
-                .flat_map(|(_, term_entry)| term_entry.documents.par_iter().cloned())
+    /// `rank_top_k` over the documents whose ids are in `candidates`, such as the bitmap a
+    /// Boolean filter produced; documents outside it are never scored
+    pub fn rank_top_k_in(
+        &self,
+        candidates: &RoaringBitmap,
+        query: &str,
+        k: usize,
+    ) -> Result<(Vec<(String, f64)>, TopKStats), String> {
+        let (mut ranked, stats) = self.wand_ranked(query, k, |id| candidates.contains(id))?;
+        if ranked.len() < k {
+            let scored: HashSet<&str> = ranked.iter().map(|(doc, _)| doc.as_str()).collect();
+            let mut unscored: Vec<&String> = candidates
+                .iter()
+                .filter_map(|id| self.doc_id_to_name.get(id as usize))
+                .filter(|document| !scored.contains(document.as_str()))
                 .collect();
+            unscored.sort_unstable();
+            let places = k - ranked.len();
+            ranked.extend(
+                unscored
+                    .into_iter()
+                    .take(places)
+                    .map(|document| (document.clone(), 0.0)),
+            );
+        }
+        Ok((ranked, stats))
+    }
 
-            let mut documents: Vec<String> = all_docs.into_iter().collect();
-            
-            // Use parallel sort for large document collections
-            if documents.len() > 10000 {
-                documents.par_sort_unstable();
-            } else {
+    /// The best `k` of the documents `accept` lets through that hold a query term, by WAND
+    fn wand_ranked<F>(
+        &self,
+        query: &str,
+        k: usize,
+        accept: F,
+    ) -> Result<(Vec<(String, f64)>, TopKStats), String>
+    where
+        F: Fn(u32) -> bool,
+    {
+        let terms = query_terms(query)?;
+        let cursors: Vec<TermCursor> = terms
+            .iter()
+            .filter_map(|term| {
+                let doc_ids = self.encoding.decode(self.compressed_index.get(term)?);
+                let counts = self
+                    .compressed_frequencies
+                    .get(term)
+                    .map_or_else(Vec::new, |bytes| decode_vb(bytes));
+                let weight = idf(self.doc_id_to_name.len(), doc_ids.len());
+                let max_count = self.max_term_frequencies.get(term).copied().unwrap_or(1);
+                Some(TermCursor::new(doc_ids, counts, weight, tf_weight(max_count) * weight))
+            })
+            .collect();
+        let (ranked, stats) = wand_top_k(cursors, k, accept);
+        let ranked = ranked
+            .into_iter()
+            .map(|(id, score)| (self.doc_id_to_name[id as usize].clone(), score))
+            .collect();
+        Ok((ranked, stats))
+    }
+
+    /// Re-encode every posting list sorted and deduplicated in whichever encoding stores them
+    /// in the fewest bytes, drop unreferenced documents and recompute the size statistics
+    pub fn optimize(&mut self) {
+        let mut postings: Vec<(String, Vec<(String, u32)>)> = self
+            .compressed_index
+            .keys()
+            .map(|term| {
+                let mut documents = self.get_postings_for_term(term).unwrap_or_default();
                 documents.sort_unstable();
-            }
+                documents.dedup_by(|duplicate, kept| {
+                    let same = duplicate.0 == kept.0;
+                    if same {
+                        kept.1 += duplicate.1;
+                    }
+                    same
+                });
+                (term.clone(), documents)
+            })
+            .collect();
+        postings.sort_unstable_by(|a, b| a.0.cmp(&b.0));
 
-            let regular_index = InvertedIndex { index, documents };
-            Self::from_inverted_index(&regular_index)
+        let mut doc_id_to_name: Vec<String> = postings
+            .iter()
+            .flat_map(|(_, documents)| documents.iter().map(|(doc, _)| doc.clone()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        doc_id_to_name.sort_unstable();
+        let doc_name_to_id = doc_name_to_id(&doc_id_to_name);
+
+        let postings: Vec<(String, Vec<u32>, Vec<u8>)> = postings
+            .into_iter()
+            .map(|(term, documents)| {
+                let doc_ids = documents.iter().map(|(doc, _)| doc_name_to_id[doc]).collect();
+                let frequencies = documents.iter().flat_map(|&(_, count)| encode_vb(count)).collect();
+                (term, doc_ids, frequencies)
+            })
+            .collect();
+        self.encoding = PostingEncoding::ALL
+            .into_iter()
+            .min_by_key(|encoding| {
+                postings
+                    .iter()
+                    .map(|(_, doc_ids, _)| encoding.encode(doc_ids).len())
+                    .sum::<usize>()
+            })
+            .unwrap_or(self.encoding);
+
+        self.compressed_index.clear();
+        self.compressed_frequencies.clear();
+        self.uncompressed_size = 0;
+        self.compressed_size = 0;
+        for (term, doc_ids, frequencies) in postings {
+            self.uncompressed_size += doc_ids.len() * 4;
+            let compressed_bytes = self.encoding.encode(&doc_ids);
+            self.compressed_size += compressed_bytes.len();
+            self.compressed_frequencies.insert(term.clone(), frequencies);
+            self.compressed_index.insert(term, compressed_bytes);
         }
+        self.compressed_index.shrink_to_fit();
+        self.compressed_frequencies.shrink_to_fit();
+        self.max_term_frequencies = max_term_frequencies(&self.compressed_frequencies);
+        self.doc_id_to_name = doc_id_to_name;
+        self.doc_name_to_id = doc_name_to_id;
     }
 
-    /// Decompress posting list for a specific term
-    pub fn get_documents_for_term(&self, term: &str) -> Option<Vec<String>> {
-        if let Some(compressed_bytes) = self.compressed_index.get(term) {
-            let doc_ids = decode_delta_vb(compressed_bytes);
-            let documents: Vec<String> = doc_ids
-                .into_iter()
-                .filter_map(|id| self.doc_id_to_name.get(id as usize).cloned())
+    /// Rebuild the postings of the terms lost with `damaged_blocks` from `dictionary`, the
+    /// dictionary the index was built from. Returns the number of terms restored.
+    pub fn restore_terms(&mut self, dictionary: &CompressedDictionary) -> usize {
+        let damaged = std::mem::take(&mut self.damaged_blocks);
+        let mut restored = 0;
+        for (term, entry) in dictionary.sorted_terms.iter().zip(&dictionary.term_entries) {
+            if !damaged.iter().any(|block| block.covers(term)) {
+                continue;
+            }
+            let mut postings: Vec<(u32, u32)> = entry
+                .documents
+                .iter()
+                .filter_map(|id| {
+                    let doc_id = *self.doc_name_to_id.get(&dictionary.documents[id as usize])?;
+                    Some((doc_id, entry.term_frequency(id).max(1)))
+                })
                 .collect();
-            Some(documents)
-        } else {
-            None
+            postings.sort_unstable();
+            let max = postings.iter().map(|&(_, count)| count).max().unwrap_or(1);
+            let counts = postings.iter().flat_map(|&(_, count)| encode_vb(count)).collect();
+            let doc_ids: Vec<u32> = postings.into_iter().map(|(id, _)| id).collect();
+            self.max_term_frequencies.insert(term.clone(), max);
+            self.compressed_frequencies.insert(term.clone(), counts);
+            self.compressed_index.insert(term.clone(), self.encoding.encode(&doc_ids));
+            restored += 1;
         }
+        restored
     }
 
     /// Get compression statistics
@@ -436,10 +998,11 @@ impl CompressedInvertedIndex {
                 .iter()
                 .map(|(k, v)| k.len() + v.len())
                 .sum::<usize>()
+            + self.compressed_frequencies.values().map(Vec::len).sum::<usize>()
             + self.doc_id_to_name.iter().map(|s| s.len()).sum::<usize>()
             + self.doc_name_to_id
-                .iter()
-                .map(|(k, _)| k.len() + 4)
+                .keys()
+                .map(|k| k.len() + 4)
                 .sum::<usize>()
     }
 }
@@ -447,11 +1010,10 @@ impl CompressedInvertedIndex {
 impl QueryParser for CompressedInvertedIndex {
     type Result = HashSet<String>;
     type Error = String;
+    const CAPABILITIES: Capabilities = Capabilities::NOT.union(Capabilities::RANKING);
 
     fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
-        let tokens = tokenize(&query.to_lowercase())?;
-        let mut pos = 0;
-        self.parse_or_expr(&tokens, &mut pos)
+        Ok(self.document_names(self.search_ids(query)?))
     }
 
     fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
@@ -463,60 +1025,272 @@ impl QueryParser for CompressedInvertedIndex {
     }
 }
 
-// Implement the same query parsing methods for CompressedInvertedIndex
-impl CompressedInvertedIndex {
-    fn parse_or_expr(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_and_expr(tokens, pos)?;
+/// Evaluates on sorted document ids decoded straight from the posting lists; names are
+/// only looked up for the final result
+impl QueryEvaluator for CompressedInvertedIndex {
+    type Output = Vec<u32>;
 
-        while *pos < tokens.len() && tokens[*pos] == "or" {
-            *pos += 1;
-            let right = self.parse_and_expr(tokens, pos)?;
-            result = result.union(&right).cloned().collect();
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.doc_ids_for_term(term)
+            .ok_or_else(|| format!("Term '{}' not found", term))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        intersect_doc_ids(&left, &right)
+    }
+
+    fn difference(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        subtract_doc_ids(&left, &right)
+    }
+
+    fn intersect_term(&self, operand: Self::Output, term: &str) -> Result<Self::Output, String> {
+        let postings = self
+            .postings_for_term(term)
+            .ok_or_else(|| format!("Term '{}' not found", term))?;
+        Ok(intersect_postings(&operand, postings))
+    }
+
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        unite_doc_ids(&left, &right)
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        complement_doc_ids(&operand, self.doc_id_to_name.len() as u32)
+    }
+}
+
+impl TracedEvaluator for CompressedInvertedIndex {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len()
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.document_frequency(term)
+    }
+
+    fn term_method(&self) -> String {
+        format!("decoded the {} posting list", self.encoding)
+    }
+
+    fn intersect_term_method(&self, term: &str) -> String {
+        format!("skipped through the {} posting list of {}", self.encoding, term)
+    }
+}
+
+/// Evaluates a roaring-encoded index on its bitmaps, with bitmap AND, OR and NOT
+struct BitmapEvaluator<'a>(&'a CompressedInvertedIndex);
+
+impl QueryEvaluator for BitmapEvaluator<'_> {
+    type Output = RoaringBitmap;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.0
+            .compressed_index
+            .get(term)
+            .map(|bytes| self.0.encoding.decode_bitmap(bytes))
+            .ok_or_else(|| format!("Term '{}' not found", term))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left & right
+    }
+
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left | right
+    }
+
+    fn difference(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left - right
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        let mut everything = RoaringBitmap::new();
+        everything.insert_range(0..self.0.doc_id_to_name.len() as u32);
+        everything - operand
+    }
+}
+
+impl TracedEvaluator for BitmapEvaluator<'_> {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len() as usize
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.0.document_frequency(term)
+    }
+
+    fn term_method(&self) -> String {
+        "deserialized the roaring bitmap".to_string()
+    }
+
+    fn intersect_term_method(&self, term: &str) -> String {
+        format!("deserialized the roaring bitmap of {} and intersected", term)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranked_search_orders_by_tfidf() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "war war war peace"),
+            ("b.fb2", "war peace peace"),
+            ("c.fb2", "war forest"),
+            ("d.fb2", "forest"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
         }
+        let mut index =
+            CompressedInvertedIndex::from_compressed_dictionary(&CompressedDictionary::from_dictionary(&dict));
+        index.optimize();
+        assert_eq!(index.term_frequency("war", "a.fb2"), 3);
+        assert_eq!(index.term_frequency("war", "d.fb2"), 0);
 
-        Ok(result)
+        let ranked = index.search_ranked("war or peace", 2).unwrap();
+        let documents: Vec<&str> = ranked.iter().map(|(doc, _)| doc.as_str()).collect();
+        assert_eq!(documents, vec!["a.fb2", "b.fb2"]);
+        assert!(ranked[0].1 > ranked[1].1);
+
+        let uncompressed = InvertedIndex::from_dictionary(&CompressedDictionary::from_dictionary(&dict));
+        assert_eq!(uncompressed.search_ranked("war or peace", 2).unwrap(), ranked);
+        assert!(index.search_ranked("war and not war", 5).unwrap().is_empty());
+        assert_eq!(index.search_ranked("not war", 5).unwrap(), [("d.fb2".to_string(), 0.0)]);
+        let all = ["a.fb2", "b.fb2", "c.fb2", "d.fb2"].map(String::from);
+        let (top, stats) = index.rank_top_k(all.clone(), "war or peace", 1).unwrap();
+        assert_eq!(top, ranked[..1]);
+        assert!(stats.scored < all.len());
+        assert_eq!(index.rank_documents(all, "war or peace", 9).unwrap().len(), 4);
+        for query in ["war and peace", "forest or peace", "war and not (peace or forest)", "not war"] {
+            assert_eq!(index.search(query).unwrap(), uncompressed.search(query).unwrap());
+        }
+        assert!(index.search("war and missing").is_err());
     }
 
-    fn parse_and_expr(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        let mut result = self.parse_not_expr(tokens, pos)?;
+    #[test]
+    fn test_every_posting_encoding_answers_alike() {
+        let mut dict = Dictionary::new();
+        for i in 0..300 {
+            let document = format!("{:03}.fb2", i);
+            dict.add_term("common".to_string(), document.clone());
+            if i % 3 == 0 {
+                dict.add_term("third".to_string(), document.clone());
+            }
+            if i % 100 == 7 {
+                dict.add_term("rare".to_string(), document);
+            }
+        }
+        let dictionary = CompressedDictionary::from_dictionary(&dict);
+        let vb = CompressedInvertedIndex::from_compressed_dictionary(&dictionary);
+        let mut roaring = CompressedInvertedIndex::from_compressed_dictionary(&dictionary)
+            .with_encoding(PostingEncoding::Roaring);
 
-        while *pos < tokens.len() && tokens[*pos] == "and" {
-            *pos += 1;
-            let right = self.parse_not_expr(tokens, pos)?;
-            result = result.intersection(&right).cloned().collect();
+        for query in ["rare and third", "rare or not third", "not common", "third and not rare"] {
+            assert_eq!(roaring.search(query).unwrap(), vb.search(query).unwrap());
         }
+        assert_eq!(roaring.get_documents_for_term("rare"), vb.get_documents_for_term("rare"));
+        assert!(roaring.search("missing or rare").is_err());
+
+        let loaded: CompressedInvertedIndex =
+            bincode::deserialize(&bincode::serialize(&roaring).unwrap()).unwrap();
+        assert_eq!(loaded.encoding, PostingEncoding::Roaring);
+        let roaring_size = roaring.compressed_size;
+        roaring.optimize();
+        assert_eq!(roaring.search("rare").unwrap().len(), 3);
+        assert_eq!("roaring".parse::<PostingEncoding>(), Ok(PostingEncoding::Roaring));
+
+        let reports = vb.compare_encodings(&[("rare", "third"), ("common", "missing")]);
+        assert_eq!(reports.len(), PostingEncoding::ALL.len());
+        let size = |encoding| {
+            let report = reports.iter().find(|report| report.encoding == encoding).unwrap();
+            assert_eq!(report.operations, 1);
+            report.size_bytes
+        };
+        assert_eq!(size(PostingEncoding::VbDelta), vb.compressed_size);
+        assert_eq!(size(PostingEncoding::Roaring), roaring_size);
+        // Optimizing switched to whichever encoding is smallest
+        assert_eq!(size(roaring.encoding), roaring.compressed_size);
+        assert!(reports.iter().all(|report| report.size_bytes >= roaring.compressed_size));
 
-        Ok(result)
+        let gamma = CompressedInvertedIndex::from_compressed_dictionary(&dictionary)
+            .with_encoding(PostingEncoding::EliasGamma);
+        let query = "rare or not third";
+        assert_eq!(gamma.search(query).unwrap(), vb.search(query).unwrap());
+        assert_eq!(size(PostingEncoding::EliasGamma), gamma.compressed_size);
     }
 
-    fn parse_not_expr(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        if *pos < tokens.len() && tokens[*pos] == "not" {
-            *pos += 1;
-            let result = self.parse_primary(tokens, pos)?;
-            let all_docs: HashSet<String> = self.doc_id_to_name.iter().cloned().collect();
-            Ok(all_docs.difference(&result).cloned().collect())
-        } else {
-            self.parse_primary(tokens, pos)
+    #[test]
+    fn test_optimize_keeps_results_and_picks_the_smallest_encoding() {
+        let mut dict = Dictionary::new();
+        for i in 0..40 {
+            let document = format!("{:02}.fb2", i);
+            dict.add_term("war".to_string(), document.clone());
+            if i % 3 == 0 {
+                dict.add_term("peace".to_string(), document.clone());
+                dict.add_term("peace".to_string(), document.clone());
+            }
+            if i % 7 == 0 {
+                dict.add_term("forest".to_string(), document);
+            }
         }
+        let mut index =
+            CompressedInvertedIndex::from_compressed_dictionary(&CompressedDictionary::from_dictionary(&dict))
+                .with_encoding(PostingEncoding::Roaring);
+        let queries = ["war and peace", "forest or peace", "war and not (peace or forest)", "not peace"];
+        let results = |index: &CompressedInvertedIndex| -> Vec<_> {
+            queries.iter().map(|query| index.search(query).unwrap()).collect()
+        };
+        let before = results(&index);
+        let ranked = index.search_ranked("war or peace", 5).unwrap();
+
+        index.optimize();
+        assert_eq!(results(&index), before);
+        assert_eq!(index.search_ranked("war or peace", 5).unwrap(), ranked);
+        assert_eq!(index.term_frequency("peace", "03.fb2"), 2);
+
+        let size = |encoding: PostingEncoding| -> usize {
+            index
+                .compressed_index
+                .values()
+                .map(|bytes| encoding.encode(&index.encoding.decode(bytes)).len())
+                .sum()
+        };
+        let (_, compressed_size, _) = index.compression_stats();
+        assert_eq!(compressed_size, size(index.encoding));
+        assert!(PostingEncoding::ALL.into_iter().all(|encoding| size(encoding) >= compressed_size));
     }
 
-    fn parse_primary(&self, tokens: &[String], pos: &mut usize) -> Result<HashSet<String>, String> {
-        if *pos >= tokens.len() {
-            return Err("Unexpected end of query".to_string());
+    #[test]
+    fn test_damaged_posting_blocks_are_skipped_and_restored() {
+        let mut dict = Dictionary::new();
+        for i in 0..300 {
+            dict.add_term(format!("term{:03}", i), "a.fb2".to_string());
+            dict.add_term(format!("term{:03}", i), format!("{}.fb2", i % 7));
         }
+        let dictionary = CompressedDictionary::from_dictionary(&dict);
+        let index = CompressedInvertedIndex::from_compressed_dictionary(&dictionary);
+        let mut data = index.to_bytes().unwrap();
+        // The length prefix of a term in the middle block
+        let offset = data.windows(7).position(|window| window == b"term200").unwrap();
+        data[offset - 8] ^= 0xff;
 
-        if tokens[*pos] == "(" {
-            *pos += 1;
-            let result = self.parse_or_expr(tokens, pos)?;
-            if *pos >= tokens.len() || tokens[*pos] != ")" {
-                return Err("Missing closing parenthesis".to_string());
-            }
-            *pos += 1;
-            Ok(result)
-        } else {
-            let term = &tokens[*pos];
-            *pos += 1;
-            self.search_term(term)
+        let mut damaged = CompressedInvertedIndex::from_bytes(&data).unwrap();
+        assert_eq!(damaged.damaged_blocks.len(), 1);
+        assert_eq!(damaged.damaged_blocks[0].first_term, "term128");
+        assert!(damaged.search("term200").is_err());
+        assert_eq!(damaged.search("term100").unwrap(), index.search("term100").unwrap());
+
+        assert_eq!(damaged.restore_terms(&dictionary), 128);
+        assert!(damaged.damaged_blocks.is_empty());
+        let restored = CompressedInvertedIndex::from_bytes(&damaged.to_bytes().unwrap()).unwrap();
+        for query in ["term200", "term127 or term128", "term255 and not term256"] {
+            assert_eq!(restored.search(query).unwrap(), index.search(query).unwrap());
         }
+        assert_eq!(restored.term_frequency("term200", "4.fb2"), 1);
+        assert_eq!(restored.max_term_frequencies, index.max_term_frequencies);
     }
 }
@@ -1,53 +1,213 @@
-pub mod bigram_index;
+pub mod analyzer;
+pub mod archive;
+pub mod champions;
+pub mod charset;
+pub mod codec;
+pub mod collocation;
+pub mod compressed_coordinate_index;
+pub mod consistency;
+pub mod cooccurrence;
 pub mod coordinate_index;
+pub mod corpus;
 pub mod dictionary;
+pub mod diversify;
+pub mod doc_ids;
+pub mod document_norms;
+pub mod document_store;
+pub mod entities;
+pub mod epub;
+pub mod estimate;
+pub mod experiment;
+pub mod explain;
+pub mod fields;
+pub mod fingerprints;
+pub mod forward_index;
+pub mod fusion;
+pub mod hidden;
+#[cfg(feature = "ann")]
+pub mod hnsw;
+pub mod hybrid;
+pub mod in_memory;
 pub mod incidence_matrix;
+pub mod ingest;
+pub mod interner;
 pub mod inverted_index;
+pub mod manifest;
+pub mod memory;
+pub mod merge_policy;
+pub mod multi_index;
+pub mod ngram_index;
+pub mod operator_aliases;
+pub mod optimizer;
 pub mod parser;
 pub mod parquet_loader;
 pub mod permutation_index;
+pub mod persist;
+pub mod pipeline;
+pub mod planner;
+pub mod position_postings;
+pub mod posting_blocks;
+pub mod profile;
 pub mod query;
+pub mod query_likelihood;
+pub mod query_log;
+pub mod result_cache;
+pub mod sample;
+pub mod scheduler;
+pub mod searcher;
+pub mod snippets;
 pub mod spimi;
 pub mod suffix_tree;
+pub mod surface_forms;
+pub mod temporal;
+pub mod term_blocks;
+pub mod summarizer;
+pub mod tfidf;
+pub mod tombstones;
+pub mod top_k;
+pub mod transliteration;
+pub mod trec;
+pub mod tui;
+pub mod tuning;
+pub mod two_phase;
 pub mod trigram_index;
+pub mod update;
+pub mod usage;
 pub mod wildcard_search;
-
-pub use bigram_index::*;
+pub mod write_buffer;
+pub mod zones;
+
+pub use analyzer::*;
+pub use archive::*;
+pub use champions::*;
+pub use charset::*;
+pub use codec::*;
+pub use collocation::*;
+pub use compressed_coordinate_index::*;
+pub use consistency::*;
+pub use cooccurrence::*;
 pub use coordinate_index::*;
+pub use corpus::*;
 pub use dictionary::*;
+pub use diversify::*;
+pub use doc_ids::*;
+pub use document_norms::*;
+pub use document_store::*;
+pub use entities::*;
+pub use epub::*;
+pub use estimate::*;
+pub use experiment::*;
+pub use explain::*;
+pub use fields::*;
+pub use fingerprints::*;
+pub use forward_index::*;
+pub use fusion::*;
+pub use hidden::*;
+#[cfg(feature = "ann")]
+pub use hnsw::*;
+pub use hybrid::*;
+pub use in_memory::*;
 pub use incidence_matrix::*;
+pub use ingest::*;
+pub use interner::*;
 pub use inverted_index::*;
+pub use manifest::*;
+pub use memory::*;
+pub use merge_policy::*;
+pub use multi_index::*;
+pub use ngram_index::*;
+pub use operator_aliases::*;
+pub use optimizer::*;
 pub use parser::*;
 pub use parquet_loader::*;
 pub use permutation_index::*;
+pub use persist::*;
+pub use pipeline::*;
+pub use planner::*;
+pub use position_postings::*;
+pub use posting_blocks::*;
+pub use profile::*;
 pub use query::*;
+pub use query_likelihood::*;
+pub use query_log::*;
+pub use result_cache::*;
+pub use sample::*;
+pub use scheduler::*;
+pub use searcher::*;
+pub use snippets::*;
 pub use spimi::*;
 pub use suffix_tree::*;
+pub use surface_forms::*;
+pub use temporal::*;
+pub use term_blocks::*;
+pub use summarizer::*;
+pub use tombstones::*;
+pub use top_k::*;
+pub use transliteration::*;
+pub use trec::*;
+pub use tui::*;
+pub use tuning::*;
+pub use two_phase::*;
 pub use trigram_index::*;
+pub use update::*;
+pub use usage::*;
 pub use wildcard_search::*;
+pub use write_buffer::*;
+pub use zones::*;
 
 use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use std::fs;
-use std::sync::{Arc, Mutex};
 use walkdir::WalkDir;
 
+/// FB2 files under `directory`, and the FB2 entries of zip archives there as paths through
+/// their archive (see `archive_entries`); archives that cannot be read are reported and skipped
 pub fn collect_fb2_files(directory: &str) -> Vec<std::path::PathBuf> {
-    WalkDir::new(directory)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| e.path().extension().map_or(false, |ext| ext == "fb2"))
-        .map(|e| e.path().to_path_buf())
-        .collect()
+    collect_documents(directory, SourceFormat::Fb2)
+}
+
+/// Books of `format` under `directory`: FB2 files as `collect_fb2_files` finds them and
+/// `.epub` files
+pub fn collect_documents(directory: &str, format: SourceFormat) -> Vec<std::path::PathBuf> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(directory).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if format.includes_epub() && is_epub(path) {
+            files.push(path.to_path_buf());
+        } else if !format.includes_fb2() {
+            continue;
+        } else if path.extension().is_some_and(|ext| ext == "fb2") {
+            files.push(path.to_path_buf());
+        } else if is_zip_archive(path) {
+            match archive_entries(path) {
+                Ok(entries) => files.extend(entries),
+                Err(e) => eprintln!("Error reading archive {}: {}", path.display(), e),
+            }
+        }
+    }
+    files
 }
 
 pub fn build_dictionary(
     files: &[std::path::PathBuf],
     show_progress: bool,
 ) -> Result<Dictionary, Box<dyn std::error::Error>> {
-    let mut dictionary = Dictionary::new();
-
+    build_dictionary_profiled(
+        files,
+        &FB2Parser::new(),
+        &PipelineOptions::default(),
+        show_progress,
+        &mut BuildProfile::new(),
+    )
+}
+pub fn build_dictionary_profiled(
+    files: &[std::path::PathBuf],
+    parser: &FB2Parser,
+    options: &PipelineOptions,
+    show_progress: bool,
+    profile: &mut BuildProfile,
+) -> Result<Dictionary, Box<dyn std::error::Error>> {
     let pb = if show_progress {
         let pb = ProgressBar::new(files.len() as u64);
         pb.set_style(
@@ -55,142 +215,34 @@ pub fn build_dictionary(
                 .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
                 .unwrap(),
         );
-        Arc::new(Mutex::new(Some(pb)))
+        Some(pb)
     } else {
-        Arc::new(Mutex::new(None))
+        None
     };
 
-    let pb_clone = Arc::clone(&pb);
-
-    println!("Processing {} files with parallel parser...", files.len());
-
-    // Process files in parallel and collect results
-    let results: Vec<_> = files
-        .par_iter()
-        .enumerate()
-        .map(|(index, file_path)| {
-            let parser = FB2Parser::new();
-
-            // Update progress bar
-            if let Ok(pb_lock) = pb_clone.lock() {
-                if let Some(ref pb) = *pb_lock {
-                    pb.set_message(format!("Processing {}", file_path.display()));
-                }
-            }
-
-            if index < 5 || index % 50 == 0 {
-                println!(
-                    "  Processing file {}/{}: {}",
-                    index + 1,
-                    files.len(),
-                    file_path.display()
-                );
-            }
-
-            let file_size = match fs::metadata(file_path) {
-                Ok(metadata) => metadata.len(),
-                Err(e) => {
-                    eprintln!("Error reading metadata for {}: {}", file_path.display(), e);
-                    return None;
-                }
-            };
-
-            if file_size < 150_000 {
-                if index < 5 {
-                    eprintln!(
-                        "Warning: {} is smaller than 150KB ({} bytes)",
-                        file_path.display(),
-                        file_size
-                    );
-                }
-                return None;
-            }
-
-            let document_name = file_path
-                .file_name()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
-
-            let words = match parser.parse_file(file_path) {
-                Ok(words) => {
-                    if index < 5 {
-                        println!("    Parsed {} words from {}", words.len(), document_name);
-                    }
-                    words
-                }
-                Err(e) => {
-                    eprintln!("Error processing {}: {}", file_path.display(), e);
-                    return None;
-                }
-            };
-
-            // Update progress bar
-            if let Ok(pb_lock) = pb_clone.lock() {
-                if let Some(ref pb) = *pb_lock {
-                    pb.inc(1);
-                }
-            }
-
-            Some((file_size, document_name, words))
-        })
-        .collect();
-
     println!(
-        "Parallel processing complete, {} results collected",
-        results.len()
+        "Processing {} files with {} parse and {} analyze workers (queue capacity {})...",
+        files.len(),
+        options.parse_workers,
+        options.analyze_workers,
+        options.queue_capacity
     );
 
-    // Merge results into dictionary sequentially
-    println!("Merging results into dictionary...");
-    let mut merged_count = 0;
-    for result in results {
-        if let Some((file_size, document_name, words)) = result {
-            merged_count += 1;
-            if merged_count <= 5 || merged_count % 50 == 0 {
-                println!(
-                    "  Merging document {}: {} ({} words)",
-                    merged_count,
-                    document_name,
-                    words.len()
-                );
-            }
-
-            dictionary.add_file_stats(file_size);
-
-            let terms: Vec<(String, String)> = words
-                .into_iter()
-                .map(|word| (word, document_name.clone()))
-                .collect();
-
-            println!(
-                "    Merging {} terms from {} into dictionary",
-                terms.len(),
-                document_name
-            );
-            dictionary.merge_terms(terms);
-            println!(
-                "    Merged terms from {} into dictionary",
-                document_name
-            );
-
-            if merged_count <= 5 || merged_count % 50 == 0 {
-                println!(
-                    "    Dictionary now has {} unique terms",
-                    dictionary.terms.len()
-                );
-            }
-        }
-    }
+    let (dictionary, queues) = run_build_pipeline(files, parser, options, pb.as_ref(), profile);
     println!(
-        "Dictionary merge complete - {} documents processed",
-        merged_count
+        "Dictionary build complete - {} documents processed, {} unique terms",
+        dictionary.documents.len(),
+        dictionary.terms.len()
     );
+    for queue in &queues {
+        println!(
+            "  Queue {}: max depth {}/{}, {} sends blocked",
+            queue.name, queue.max_depth, queue.capacity, queue.blocked_sends
+        );
+    }
 
-    if let Ok(pb_lock) = pb.lock() {
-        if let Some(ref pb) = *pb_lock {
-            pb.finish_with_message("Dictionary building completed");
-        }
+    if let Some(pb) = pb {
+        pb.finish_with_message("Dictionary building completed");
     }
 
     Ok(dictionary)
@@ -1,27 +1,185 @@
-use clap::{Arg, Command};
+use clap::{value_parser, Arg, Command};
+use clap_complete::Shell;
+use rayon::prelude::*;
 use grimoire::{
-    build_dictionary, collect_fb2_files, BigramIndex, CoordinateIndex, FB2Parser, IncidenceMatrix,
-    CompressedInvertedIndex, ParallelSPIMIIndexer, ParquetLoader, QueryParser, WildcardSearchEngine,
+    append_query_log, append_usage_log, build_dictionary_profiled, capable_structures,
+    collect_documents, collect_fb2_files, combine_normalized, compact_index, compare_results,
+    discover_stopwords, document_vectors, expand_stems_with, extract_collocations, make_snippet,
+    mmr_rerank, parse_date_key, parse_topics, plan_query, query_terms, rank_by_zones, read_source,
+    reciprocal_rank_fusion, refresh_index, run_tui, split_prefix, structure_files, term_spans,
+    tokenize_plain_text_with_offsets, update_index, validate_corpus, write_stopword_file, AbRouter,
+    Analyzer, AssociationMeasure, BookMetadata, BuildProfile, Capabilities, ChampionLists,
+    CharsetProfile, ColumnMapping, CompactionLog, CompressedCoordinateIndex, CompressedDictionary,
+    CompressedInvertedIndex, ConflictPolicy, CooccurrenceMatrix, CoordinateIndex, CorpusError,
+    DamagedBlock, DocumentNorms, DocumentSample, DocumentStore, EmptyReason, FB2Parser,
+    FederatedRanking, FieldIndex, FingerprintCache, ForwardIndex, HiddenDocuments, HybridQuery,
+    IncidenceMatrix, IndexKind, IndexManifest, IndexUsage, MultiIndexSearcher, NGramPhraseIndex,
+    OperatorAliases, ParallelSPIMIIndexer, ParquetDocument, ParquetLoader,
+    PartitionedPermutationIndex, PatternReplacer, Persistable, PipelineOptions, PlannerOptions,
+    PositionalSPIMIIndexer, PostingEncoding, QueryAst, QueryLikelihoodScorer, QueryParser,
+    QuerySuggester, RankingPath, RefreshReport, ResultCache, ResultPage, ScoreNormalization, Script,
+    SharedSearchers, SourceFormat, Span, StoredDocument, StructureResult, Summarizer, SurfaceForms,
+    TemporalPartitions, TermBlockFile, TermInterner, Tombstones, TrackingAllocator,
+    TransliterationBridge, TransliterationTable, TrecRun, TuiOptions, TuningConfig, Variant,
+    WildcardSearchEngine, ZoneWeights, ALL_STRUCTURES, MAX_PHRASE_WORDS, MIN_PHRASE_WORDS,
+    PHRASE_INDEX_DOCUMENT_MARKER, PHRASE_INDEX_STAGE_MARKER,
 };
+use grimoire::tfidf::cosine_similarity;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
+use std::sync::Arc;
 use std::time::Instant;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Command::new("Grimoire")
+/// Entries kept in a prefix's search result cache
+const RESULT_CACHE_CAPACITY: usize = 1024;
+
+/// Documents listed as slowest to parse in `build --profile`
+const BUILD_PROFILE_SLOWEST: usize = 10;
+
+/// Parquet documents buffered between the row group readers and the SPIMI indexer
+const PARQUET_CHANNEL_CAPACITY: usize = 4096;
+
+/// Counts heap use so builds can report the peak of every phase
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        if let Some(corpus_error) = e.downcast_ref::<CorpusError>() {
+            eprintln!("Hint: {}", corpus_error.hint());
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = build_cli().get_matches();
+
+    match matches.subcommand() {
+        Some(("build", sub_matches)) => {
+            handle_build_command(sub_matches)?;
+        }
+        Some(("update", sub_matches)) => {
+            handle_update_command(sub_matches)?;
+        }
+        Some(("watch", sub_matches)) => {
+            handle_watch_command(sub_matches)?;
+        }
+        Some(("search", sub_matches)) => {
+            handle_search_command(sub_matches)?;
+        }
+        Some(("multi-search", sub_matches)) => {
+            handle_multi_search_command(sub_matches)?;
+        }
+        Some(("run", sub_matches)) => {
+            handle_run_command(sub_matches)?;
+        }
+        Some(("suggest-queries", sub_matches)) => {
+            handle_suggest_queries_command(sub_matches)?;
+        }
+        Some(("terms", sub_matches)) => {
+            handle_terms_command(sub_matches)?;
+        }
+        Some(("dump-term", sub_matches)) => {
+            handle_dump_term_command(sub_matches)?;
+        }
+        Some(("discover-stopwords", sub_matches)) => {
+            handle_discover_stopwords_command(sub_matches)?;
+        }
+        Some(("hide", sub_matches)) => {
+            handle_hide_command(sub_matches)?;
+        }
+        Some(("undelete", sub_matches)) => {
+            handle_undelete_command(sub_matches)?;
+        }
+        Some(("delete", sub_matches)) => {
+            handle_delete_command(sub_matches)?;
+        }
+        Some(("compact", sub_matches)) => {
+            handle_compact_command(sub_matches)?;
+        }
+        Some(("advisor", sub_matches)) => {
+            handle_advisor_command(sub_matches)?;
+        }
+        Some(("postings-compare", sub_matches)) => {
+            handle_postings_compare_command(sub_matches)?;
+        }
+        Some(("locate", sub_matches)) => {
+            handle_locate_command(sub_matches)?;
+        }
+        Some(("similar", sub_matches)) => {
+            handle_similar_command(sub_matches)?;
+        }
+        Some(("inspect", sub_matches)) => {
+            handle_inspect_command(sub_matches)?;
+        }
+        Some(("summarize", sub_matches)) => {
+            handle_summarize_command(sub_matches)?;
+        }
+        Some(("charset-report", sub_matches)) => {
+            handle_charset_report_command(sub_matches)?;
+        }
+        Some(("parquet-inspect", sub_matches)) => {
+            handle_parquet_inspect_command(sub_matches)?;
+        }
+        Some(("parquet-build", sub_matches)) => {
+            handle_parquet_build_command(sub_matches)?;
+        }
+        Some(("cooccurrence", sub_matches)) => {
+            handle_cooccurrence_command(sub_matches)?;
+        }
+        Some(("collocations", sub_matches)) => {
+            handle_collocations_command(sub_matches)?;
+        }
+        Some(("optimize", sub_matches)) => {
+            handle_optimize_command(sub_matches)?;
+        }
+        Some(("fsck", sub_matches)) => {
+            handle_fsck_command(sub_matches)?;
+        }
+        Some(("tune", sub_matches)) => {
+            handle_tune_command(sub_matches)?;
+        }
+        Some(("tui", sub_matches)) => {
+            handle_tui_command(sub_matches)?;
+        }
+        Some(("completions", sub_matches)) => {
+            handle_completions_command(sub_matches);
+        }
+        Some(("man", sub_matches)) => {
+            handle_man_command(sub_matches)?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Every subcommand and flag; completions and man pages are generated from it as well
+fn build_cli() -> Command {
+    Command::new("Grimoire")
         .version("1.0")
         .about("FB2 text processing and Boolean search")
         .subcommand_required(true)
         .subcommand(
             Command::new("build")
-                .about("Build dictionary and search structures from FB2 files")
+                .about("Build dictionary and search structures from FB2 or EPUB books")
                 .arg(
                     Arg::new("input")
                         .short('i')
                         .long("input")
                         .value_name("DIRECTORY")
-                        .help("Input directory containing FB2 files")
+                        .help("Input directory containing FB2 files, loose or in .zip and .fb2.zip archives, or EPUB books")
                         .required(true),
                 )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Books to index: fb2, epub, or auto for both by extension")
+                        .default_value("fb2"),
+                )
                 .arg(
                     Arg::new("output")
                         .short('o')
@@ -37,17 +195,139 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_name("FORMATS")
                         .help("Serialization formats (binary,json,text)")
                         .default_value("binary,json,text"),
+                )
+                .arg(
+                    Arg::new("partition-years")
+                        .long("partition-years")
+                        .value_name("YEARS")
+                        .help("Width of temporal partitions in years")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("memory-limit")
+                        .long("memory-limit")
+                        .value_name("MB")
+                        .help("Build the coordinate index with SPIMI blocks spilled to disk, keeping at most MB of positions in memory"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .help("Report time and heap peak per build phase and time per document, saved to PREFIX_profile.json")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("incremental")
+                        .long("incremental")
+                        .help(
+                            "Parse only files added or modified since the index at PREFIX was \
+                             built, by the fingerprints it saved, and delete documents whose \
+                             files are gone",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tuning")
+                        .long("tuning")
+                        .value_name("SETTING")
+                        .help("Parallelism thresholds: 'default', 'auto' to measure this machine, or a file saved by 'tune'")
+                        .default_value("default"),
+                )
+                .arg(
+                    Arg::new("strip")
+                        .long("strip")
+                        .value_name("REGEX")
+                        .help("Remove text matching REGEX from every document before indexing; repeatable")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("redact-emails")
+                        .long("redact-emails")
+                        .help("Remove e-mail addresses from every document before indexing")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("analyzer")
+                        .long("analyzer")
+                        .value_name("STEPS")
+                        .help("Comma-separated steps turning words into terms: lowercase, fold-yo (ё as е), stopwords (built-in list) or stopwords=FILE; saved in the manifest and applied to queries")
+                        .default_value("lowercase"),
+                )
+                .arg(
+                    Arg::new("queue-capacity")
+                        .long("queue-capacity")
+                        .value_name("N")
+                        .help("Documents buffered between the parse, analyze and index stages before a stage waits for the next")
+                        .default_value("16"),
+                )
+                .arg(
+                    Arg::new("min-documents")
+                        .long("min-documents")
+                        .value_name("N")
+                        .help("Refuse to build when fewer documents qualify for indexing")
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("max-docs")
+                        .long("max-docs")
+                        .value_name("N")
+                        .help("Index at most N documents, for a quick experiment before a full build"),
+                )
+                .arg(
+                    Arg::new("sample-ratio")
+                        .long("sample-ratio")
+                        .value_name("P")
+                        .help("Index a share P of the documents, picked by a hash of their names so runs keep the same ones"),
+                )
+                .arg(
+                    Arg::new("postings-codec")
+                        .long("postings-codec")
+                        .alias("postings")
+                        .value_name("CODEC")
+                        .help("Posting list codec of the inverted index, saved with it; compare them with postings-compare")
+                        .value_parser(["vb-delta", "gamma", "rice", "for", "roaring"])
+                        .default_value("vb-delta"),
+                )
+                .arg(
+                    Arg::new("champions")
+                        .long("champions")
+                        .value_name("N")
+                        .help("Keep the N documents each term occurs in most as its champion list, to answer ranked queries without reading whole posting lists"),
+                )
+                .arg(
+                    Arg::new("hnsw")
+                        .long("hnsw")
+                        .help(
+                            "Build a nearest neighbor index over document TF-IDF vectors for \
+                             similar; needs the ann feature",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("store-text")
+                        .long("store-text")
+                        .help(
+                            "Keep the compressed body of every document in the document store, \
+                             for search --snippets",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("phrase-words")
+                        .long("phrase-words")
+                        .value_name("N")
+                        .help("Words per key of the phrase index, 2 to 4; phrases up to N words long are answered without position checks")
+                        .default_value("2"),
                 ),
         )
         .subcommand(
-            Command::new("search")
-                .about("Search using Boolean queries")
+            Command::new("update")
+                .about("Add new FB2 files to a built index without rebuilding it")
                 .arg(
-                    Arg::new("query")
-                        .short('q')
-                        .long("query")
-                        .value_name("QUERY")
-                        .help("Boolean query (e.g., 'term1 and term2', 'term1 or term2', 'not term1')")
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("DIRECTORY")
+                        .help("Directory of FB2 files; files already indexed are handled by --on-conflict")
                         .required(true),
                 )
                 .arg(
@@ -55,471 +335,3712 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short('d')
                         .long("dict")
                         .value_name("FILE")
-                        .help("Dictionary file prefix")
+                        .help("Dictionary file prefix of the build to update")
                         .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("strip")
+                        .long("strip")
+                        .value_name("REGEX")
+                        .help("Remove text matching REGEX from every new document, as the build did; repeatable")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("redact-emails")
+                        .long("redact-emails")
+                        .help("Remove e-mail addresses from every new document, as the build did")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("on-conflict")
+                        .long("on-conflict")
+                        .value_name("POLICY")
+                        .help("What to do with files whose name is indexed already: replace their postings, skip them, or refuse the update")
+                        .value_parser(["replace", "skip", "error"])
+                        .default_value("skip"),
                 ),
         )
         .subcommand(
-            Command::new("parquet-inspect")
-                .about("Inspect Parquet file schema and sample data")
+            Command::new("watch")
+                .about("Keep an index up to date, indexing FB2 files as they are added or modified")
                 .arg(
                     Arg::new("input")
                         .short('i')
                         .long("input")
-                        .value_name("FILE")
-                        .help("Parquet file to inspect")
+                        .value_name("DIRECTORY")
+                        .help("Directory of FB2 files the index was built from")
                         .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix of a build that saved fingerprints")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("Time between scans of the directory")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("strip")
+                        .long("strip")
+                        .value_name("REGEX")
+                        .help("Remove text matching REGEX from every new document, as the build did; repeatable")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("redact-emails")
+                        .long("redact-emails")
+                        .help("Remove e-mail addresses from every new document, as the build did")
+                        .action(clap::ArgAction::SetTrue),
                 ),
         )
         .subcommand(
-            Command::new("parquet-build")
-                .about("Build dictionary and search structures from Parquet file")
+            Command::new("search")
+                .about("Search using Boolean queries")
+                .arg(
+                    Arg::new("query")
+                        .short('q')
+                        .long("query")
+                        .value_name("QUERY")
+                        .help("Boolean query (e.g., 'term1 and term2', 'term1 or term2', 'not term1'); prefix with @matrix, @inverted, @bigram, @coordinate, @wildcard or @all to pick structures")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("positions")
+                        .long("positions")
+                        .help(
+                            "Print the word positions each coordinate index hit matched at, and \
+                             their source byte ranges when the forward index is saved",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("snippets")
+                        .long("snippets")
+                        .help(
+                            "Print the passage of each hit around its best matches, matched \
+                             words in brackets; needs an index built with --store-text",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("snippet-words")
+                        .long("snippet-words")
+                        .value_name("N")
+                        .help("Words of context on each side of the matches of a snippet")
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::new("summaries")
+                        .long("summaries")
+                        .help("Print an extractive summary for each hit")
+                        .requires("input")
+                        .action(clap::ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("input")
                         .short('i')
                         .long("input")
+                        .value_name("DIRECTORY")
+                        .help("Directory containing the indexed FB2 files (for summaries)"),
+                )
+                .arg(
+                    Arg::new("sentences")
+                        .long("sentences")
+                        .value_name("N")
+                        .help("Number of sentences per summary")
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("rank")
+                        .long("rank")
+                        .value_name("MODEL")
+                        .help("Ranking of coordinate index results")
+                        .value_parser(["boolean", "qld"])
+                        .default_value("boolean"),
+                )
+                .arg(
+                    Arg::new("lambda")
+                        .long("lambda")
+                        .value_name("LAMBDA")
+                        .help("Jelinek-Mercer collection weight for --rank qld")
+                        .default_value("0.1"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("DATE")
+                        .help("Only documents dated on or after DATE (YYYY[-MM[-DD]])"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_name("DATE")
+                        .help("Only documents dated on or before DATE (YYYY[-MM[-DD]])"),
+                )
+                .arg(
+                    Arg::new("diversify")
+                        .long("diversify")
+                        .help("Re-rank the top results with Maximal Marginal Relevance (needs --rank qld)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("mmr-lambda")
+                        .long("mmr-lambda")
+                        .value_name("LAMBDA")
+                        .help("MMR trade-off: 1.0 is pure relevance, 0.0 is pure novelty")
+                        .default_value("0.7"),
+                )
+                .arg(
+                    Arg::new("mmr-top")
+                        .long("mmr-top")
+                        .value_name("N")
+                        .help("Number of top ranked results to diversify")
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::new("expand")
+                        .long("expand")
+                        .value_name("N")
+                        .help("Expand stem* terms to the stem plus up to N dictionary terms (per query: @expand=N)"),
+                )
+                .arg(
+                    Arg::new("translit")
+                        .long("translit")
+                        .help("Also match query terms written in the other script (Cyrillic <-> Latin)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("translit-table")
+                        .long("translit-table")
                         .value_name("FILE")
-                        .help("Parquet file to process")
-                        .required(true),
+                        .help("Transliteration overrides, one 'cyrillic=latin' pair per line")
+                        .requires("translit"),
                 )
                 .arg(
-                    Arg::new("output")
-                        .short('o')
-                        .long("output")
-                        .value_name("PREFIX")
-                        .help("Output file prefix")
-                        .default_value("parquet_dictionary"),
+                    Arg::new("include-hidden")
+                        .long("include-hidden")
+                        .help("Include soft-deleted (hidden) documents in results")
+                        .action(clap::ArgAction::SetTrue),
                 )
                 .arg(
-                    Arg::new("spimi")
-                        .long("spimi")
-                        .help("Use SPIMI indexing for large datasets")
+                    Arg::new("log-queries")
+                        .long("log-queries")
+                        .help(
+                            "Append the query and its result count to the query log, and the \
+                             structures it used to the usage log",
+                        )
                         .action(clap::ArgAction::SetTrue),
                 )
                 .arg(
-                    Arg::new("memory-limit")
-                        .long("memory-limit")
-                        .value_name("MB")
-                        .help("Memory limit for SPIMI indexing in MB")
-                        .default_value("512"),
+                    Arg::new("raw-query")
+                        .long("raw-query")
+                        .help("Only lowercase query terms instead of running them through the analyzer the index was built with")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("operator-aliases")
+                        .long("operator-aliases")
+                        .value_name("SETTING")
+                        .help("Words read as operators besides and/or/not/near: 'default' (и, или, не, рядом/N and Ukrainian і, або, поруч/N), 'none', or a file of 'alias=operator' lines")
+                        .default_value("default"),
+                )
+                .arg(
+                    Arg::new("cache")
+                        .long("cache")
+                        .help("Reuse results cached for the current index generation")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("ranked")
+                        .long("ranked")
+                        .help("Order inverted index results by TF-IDF relevance")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("top-k")
+                        .long("top-k")
+                        .value_name("N")
+                        .help("Number of results kept by --ranked")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("rank-zones")
+                        .long("rank-zones")
+                        .help(
+                            "Order --ranked and RANK BY results by weighted zone score, so \
+                             title and annotation matches count for more than body matches",
+                        )
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("zone-weights")
+                        .long("zone-weights")
+                        .value_name("WEIGHTS")
+                        .help("Weights of the zones for --rank-zones, scaled to sum to 1")
+                        .default_value("title=0.5,annotation=0.2,body=0.3"),
+                )
+                .arg(
+                    Arg::new("show-expansions")
+                        .long("show-expansions")
+                        .help("List the dictionary terms a wildcard pattern matched and their document counts")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-expansions")
+                        .long("max-expansions")
+                        .value_name("N")
+                        .help("Terms a wildcard pattern is expanded to, the most frequent first")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::new("all-expansions")
+                        .long("all-expansions")
+                        .help("Expand wildcard patterns to every matching term")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("verify")
+                        .long("verify")
+                        .help("Run the query on every capable structure and report documents they disagree on")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("explain")
+                        .long("explain")
+                        .help("Print the optimizer's evaluation order, then trace the query: the structure used, how each operator ran, posting list sizes, candidates and stage timings")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Print at most N results per structure"),
+                )
+                .arg(
+                    Arg::new("offset")
+                        .long("offset")
+                        .value_name("N")
+                        .help("Skip the first N results per structure")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("variant-b")
+                        .long("variant-b")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix of an alternative index for A/B experiments"),
+                )
+                .arg(
+                    Arg::new("b-percent")
+                        .long("b-percent")
+                        .value_name("PERCENT")
+                        .help("Share of queries routed to the --variant-b index")
+                        .default_value("50")
+                        .requires("variant-b"),
                 ),
-        );
-
-    let matches = cli.get_matches();
-
-    match matches.subcommand() {
-        Some(("build", sub_matches)) => {
-            handle_build_command(sub_matches)?;
-        }
-        Some(("search", sub_matches)) => {
-            handle_search_command(sub_matches)?;
-        }
-        Some(("parquet-inspect", sub_matches)) => {
-            handle_parquet_inspect_command(sub_matches)?;
+        )
+        .subcommand(
+            Command::new("multi-search")
+                .about("Search several separately built indexes as one, ranked with their combined statistics")
+                .arg(
+                    Arg::new("query")
+                        .short('q')
+                        .long("query")
+                        .value_name("QUERY")
+                        .help("Search query")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("segment")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix of a segment; repeat for each segment")
+                        .action(clap::ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("rank")
+                        .long("rank")
+                        .value_name("MODEL")
+                        .help("Scoring of matching documents")
+                        .value_parser(["tfidf", "qld"])
+                        .default_value("tfidf"),
+                )
+                .arg(
+                    Arg::new("lambda")
+                        .long("lambda")
+                        .value_name("LAMBDA")
+                        .help("Jelinek-Mercer collection weight for --rank qld")
+                        .default_value("0.1"),
+                )
+                .arg(
+                    Arg::new("merge")
+                        .long("merge")
+                        .value_name("METHOD")
+                        .help("How segment results are merged: 'global' scores every segment with combined statistics; 'rrf' (reciprocal rank fusion) and 'combsum' (sum of --normalize'd scores) merge rankings each segment scored on its own")
+                        .value_parser(["global", "rrf", "combsum"])
+                        .default_value("global"),
+                )
+                .arg(
+                    Arg::new("normalize")
+                        .long("normalize")
+                        .value_name("METHOD")
+                        .help("Score normalization of each segment's ranking for --merge combsum")
+                        .value_parser(["none", "min-max", "z-score", "rank"])
+                        .default_value("min-max"),
+                )
+                .arg(
+                    Arg::new("rrf-k")
+                        .long("rrf-k")
+                        .value_name("K")
+                        .help("Constant added to every rank by --merge rrf; larger values flatten the weight of top ranks")
+                        .default_value("60"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Show at most N results"),
+                )
+                .arg(
+                    Arg::new("offset")
+                        .long("offset")
+                        .value_name("N")
+                        .help("Skip the first N results")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("raw-query")
+                        .long("raw-query")
+                        .help("Only lowercase query terms instead of running them through the analyzer the index was built with")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("operator-aliases")
+                        .long("operator-aliases")
+                        .value_name("SETTING")
+                        .help("Words read as operators besides and/or/not/near: 'default' (и, или, не, рядом/N and Ukrainian і, або, поруч/N), 'none', or a file of 'alias=operator' lines")
+                        .default_value("default"),
+                ),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Run a file of queries and write their rankings as a TREC run file")
+                .arg(
+                    Arg::new("queries")
+                        .long("queries")
+                        .value_name("FILE")
+                        .help("Query file, one 'topic-id<TAB>query' line per topic; '#' starts a comment line")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Run file to write, one 'topic Q0 document rank score tag' line per result")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("rank")
+                        .long("rank")
+                        .value_name("MODEL")
+                        .help("Scoring of matching documents")
+                        .value_parser(["tfidf", "qld"])
+                        .default_value("tfidf"),
+                )
+                .arg(
+                    Arg::new("lambda")
+                        .long("lambda")
+                        .value_name("LAMBDA")
+                        .help("Jelinek-Mercer collection weight for --rank qld")
+                        .default_value("0.1"),
+                )
+                .arg(
+                    Arg::new("top-k")
+                        .long("top-k")
+                        .value_name("K")
+                        .help("Results written per topic")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::new("run-tag")
+                        .long("run-tag")
+                        .value_name("TAG")
+                        .help("Name of the run, written in its last column")
+                        .default_value("grimoire"),
+                )
+                .arg(
+                    Arg::new("include-hidden")
+                        .long("include-hidden")
+                        .help("Include soft-deleted (hidden) documents in results")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("raw-query")
+                        .long("raw-query")
+                        .help("Only lowercase query terms instead of running them through the analyzer the index was built with")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("operator-aliases")
+                        .long("operator-aliases")
+                        .value_name("SETTING")
+                        .help("Words read as operators besides and/or/not/near: 'default' (и, или, не, рядом/N and Ukrainian і, або, поруч/N), 'none', or a file of 'alias=operator' lines")
+                        .default_value("default"),
+                ),
+        )
+        .subcommand(
+            Command::new("suggest-queries")
+                .about("Suggest completions and related queries from the query log")
+                .arg(
+                    Arg::new("text")
+                        .help("Partial or complete query")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .short('n')
+                        .long("limit")
+                        .value_name("N")
+                        .help("Number of suggestions of each kind")
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            Command::new("terms")
+                .about("List dictionary terms by prefix or range from the on-disk term blocks")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Terms starting with PREFIX")
+                        .conflicts_with_all(["from", "to"]),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("TERM")
+                        .help("First term of the range (inclusive)")
+                        .default_value(""),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("TERM")
+                        .help("End of the range (exclusive); the last term when omitted"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .short('n')
+                        .long("limit")
+                        .value_name("N")
+                        .help("Maximum number of terms to print")
+                        .default_value("100"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump-term")
+                .about("Show a term's posting list and the spellings in the text behind it")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("term")
+                        .long("term")
+                        .value_name("TERM")
+                        .help("Word to look up, analyzed as the index analyzes words")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .short('n')
+                        .long("limit")
+                        .value_name("N")
+                        .help("Maximum number of documents to print")
+                        .default_value("20"),
+                ),
+        )
+        .subcommand(
+            Command::new("discover-stopwords")
+                .about("List terms most documents hold as stopword candidates for --analyzer")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("top-df")
+                        .long("top-df")
+                        .value_name("SHARE")
+                        .help("Keep terms held by at least this share of the documents, 0 to 1")
+                        .default_value("0.6"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .short('n')
+                        .long("limit")
+                        .value_name("N")
+                        .help("Keep at most N candidates, the most widespread"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help(
+                            "Write the candidates as a stopword file for --analyzer stopwords=FILE",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("hide")
+                .about("Soft-delete a document so queries exclude it by default")
+                .arg(
+                    Arg::new("document")
+                        .long("doc")
+                        .value_name("NAME")
+                        .help("Document name as stored in the index")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("reason")
+                        .long("reason")
+                        .value_name("TEXT")
+                        .help("Why the document is hidden")
+                        .default_value("manual"),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                ),
+        )
+        .subcommand(
+            Command::new("undelete")
+                .about("Restore a soft-deleted document")
+                .arg(
+                    Arg::new("document")
+                        .long("doc")
+                        .value_name("NAME")
+                        .help("Document name as stored in the index")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                ),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete documents from an index; queries skip them until 'compact' removes them")
+                .arg(
+                    Arg::new("document")
+                        .long("doc")
+                        .value_name("NAME")
+                        .help("Document name as stored in the index; repeatable")
+                        .action(clap::ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                ),
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Rewrite the structures of an index without its deleted documents")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                ),
+        )
+        .subcommand(
+            Command::new("advisor")
+                .about("Suggest structures to drop or add from the logged structure usage")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("min-queries")
+                        .long("min-queries")
+                        .value_name("N")
+                        .help("Logged queries needed before advising")
+                        .default_value("20"),
+                ),
+        )
+        .subcommand(
+            Command::new("postings-compare")
+                .about("Compare the size and AND/OR/NOT speed of every posting list codec")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("pairs")
+                        .long("pairs")
+                        .value_name("N")
+                        .help("Term pairs to run each operation on")
+                        .default_value("1000"),
+                ),
+        )
+        .subcommand(
+            Command::new("locate")
+                .about("Show positions and source byte offsets of a term in a document")
+                .arg(
+                    Arg::new("document")
+                        .long("doc")
+                        .value_name("NAME")
+                        .help("Document name as stored in the index")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("term")
+                        .long("term")
+                        .value_name("TERM")
+                        .help("Term to locate")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("DIRECTORY")
+                        .help("Directory with the source FB2 files, to print each occurrence in context"),
+                ),
+        )
+        .subcommand(
+            Command::new("similar")
+                .about("List the documents whose TF-IDF vectors are closest to a document's")
+                .arg(
+                    Arg::new("document")
+                        .long("doc")
+                        .value_name("NAME")
+                        .help("Document name as stored in the index")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .short('n')
+                        .long("top")
+                        .value_name("N")
+                        .help("Number of neighbors to print")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("exact")
+                        .long("exact")
+                        .help("Compare with every document even if the index has an HNSW graph")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Show sizes of saved structures and temporal partition statistics")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .help("Also list the N most frequent terms"),
+                ),
+        )
+        .subcommand(
+            Command::new("summarize")
+                .about("Print an extractive summary of an FB2 file")
+                .arg(
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .value_name("FILE")
+                        .help("FB2 file to summarize")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix (source of document frequencies)")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("sentences")
+                        .long("sentences")
+                        .value_name("N")
+                        .help("Number of sentences in the summary")
+                        .default_value("3"),
+                ),
+        )
+        .subcommand(
+            Command::new("charset-report")
+                .about("Report the script, non-text share and encoding problems of every FB2 file")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("DIRECTORY")
+                        .help("Input directory containing FB2 files")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("min-terms-per-kb")
+                        .long("min-terms-per-kb")
+                        .value_name("N")
+                        .help("Flag documents yielding fewer terms per kilobyte of file")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("flagged")
+                        .long("flagged")
+                        .help("List only documents with problems")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("parquet-inspect")
+                .about("Inspect Parquet file schema and sample data")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("PATH")
+                        .help("Parquet file, or directory of part files such as a Hive-partitioned dataset")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("text-col")
+                        .long("text-col")
+                        .value_name("COLUMN")
+                        .help("Column holding document text; guessed from column names when omitted"),
+                )
+                .arg(
+                    Arg::new("id-col")
+                        .long("id-col")
+                        .value_name("COLUMN")
+                        .help("String or integer column holding document ids; rows are numbered when there is none"),
+                )
+                .arg(
+                    Arg::new("meta-cols")
+                        .long("meta-cols")
+                        .value_name("COLUMNS")
+                        .help("Comma-separated string columns kept as document metadata"),
+                ),
+        )
+        .subcommand(
+            Command::new("parquet-build")
+                .about("Build dictionary and search structures from Parquet file")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("PATH")
+                        .help("Parquet file, or directory of part files such as a Hive-partitioned dataset; parts are read in parallel")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PREFIX")
+                        .help("Output file prefix")
+                        .default_value("parquet_dictionary"),
+                )
+                .arg(
+                    Arg::new("spimi")
+                        .long("spimi")
+                        .help("Use SPIMI indexing for large datasets")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("memory-limit")
+                        .long("memory-limit")
+                        .value_name("MB")
+                        .help("Memory limit for SPIMI indexing in MB")
+                        .default_value("512"),
+                )
+                .arg(
+                    Arg::new("text-col")
+                        .long("text-col")
+                        .value_name("COLUMN")
+                        .help("Column holding document text; guessed from column names when omitted"),
+                )
+                .arg(
+                    Arg::new("id-col")
+                        .long("id-col")
+                        .value_name("COLUMN")
+                        .help("String or integer column holding document ids; rows are numbered when there is none"),
+                )
+                .arg(
+                    Arg::new("meta-cols")
+                        .long("meta-cols")
+                        .value_name("COLUMNS")
+                        .help("Comma-separated string columns kept as document metadata"),
+                )
+                .arg(
+                    Arg::new("partition-metadata")
+                        .long("partition-metadata")
+                        .help("Keep the column=value directories of a partitioned dataset as document metadata")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("read-threads")
+                        .long("read-threads")
+                        .value_name("N")
+                        .help("Row groups read in parallel; with --spimi they feed the indexer as they are read (default: all cores)"),
+                )
+                .arg(
+                    Arg::new("tuning")
+                        .long("tuning")
+                        .value_name("SETTING")
+                        .help("Parallelism thresholds: 'default', 'auto' to measure this machine, or a file saved by 'tune'")
+                        .default_value("default"),
+                )
+                .arg(
+                    Arg::new("min-documents")
+                        .long("min-documents")
+                        .value_name("N")
+                        .help("Refuse to build when fewer documents qualify for indexing")
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("max-docs")
+                        .long("max-docs")
+                        .value_name("N")
+                        .help("Index at most N documents, for a quick experiment before a full build"),
+                )
+                .arg(
+                    Arg::new("sample-ratio")
+                        .long("sample-ratio")
+                        .value_name("P")
+                        .help("Index a share P of the documents, picked by a hash of their names so runs keep the same ones"),
+                )
+                .arg(
+                    Arg::new("postings-codec")
+                        .long("postings-codec")
+                        .alias("postings")
+                        .value_name("CODEC")
+                        .help("Posting list codec of the inverted index, saved with it; compare them with postings-compare")
+                        .value_parser(["vb-delta", "gamma", "rice", "for", "roaring"])
+                        .default_value("vb-delta"),
+                )
+                .arg(
+                    Arg::new("champions")
+                        .long("champions")
+                        .value_name("N")
+                        .help("Keep the N documents each term occurs in most as its champion list, to answer ranked queries without reading whole posting lists"),
+                ),
+        )
+        .subcommand(
+            Command::new("cooccurrence")
+                .about("Export windowed term co-occurrence counts from the coordinate index")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .help("Number of most frequent terms to include")
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("window")
+                        .short('w')
+                        .long("window")
+                        .value_name("POSITIONS")
+                        .help("Maximum distance between co-occurring terms")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Output file")
+                        .default_value("cooccurrence.csv"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Export format")
+                        .value_parser(["csv", "parquet"])
+                        .default_value("csv"),
+                ),
+        )
+        .subcommand(
+            Command::new("collocations")
+                .about("Rank word bigrams by association strength")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .help("Number of collocations to print")
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("measure")
+                        .short('m')
+                        .long("measure")
+                        .value_name("MEASURE")
+                        .help("Association measure")
+                        .value_parser(["pmi", "llr"])
+                        .default_value("pmi"),
+                )
+                .arg(
+                    Arg::new("min-freq")
+                        .long("min-freq")
+                        .value_name("COUNT")
+                        .help("Ignore bigrams occurring fewer times than this")
+                        .default_value("5"),
+                ),
+        )
+        .subcommand(
+            Command::new("optimize")
+                .about("Rewrite saved structures in their most compact form")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .visible_alias("prefix")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                ),
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Check the checksums of the on-disk term blocks and posting lists and optionally repair damaged ones")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .help("Rebuild damaged blocks from the saved dictionary and forward index")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("tune")
+                .about("Measure this machine and save parallelism thresholds for --tuning")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to save the thresholds")
+                        .default_value("tuning.json"),
+                ),
+        )
+        .subcommand(
+            Command::new("tui")
+                .about("Interactive dashboard for build progress, memory usage and search")
+                .arg(
+                    Arg::new("dict_file")
+                        .short('d')
+                        .long("dict")
+                        .value_name("FILE")
+                        .help("Dictionary file prefix")
+                        .default_value("dictionary"),
+                )
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("DIRECTORY")
+                        .help("Directory containing FB2 files; enables building with F5"),
+                )
+                .arg(
+                    Arg::new("build")
+                        .long("build")
+                        .help("Start building as soon as the dashboard opens")
+                        .requires("input")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::new("shell")
+                        .long("shell")
+                        .value_name("SHELL")
+                        .help("Shell to generate completions for")
+                        .value_parser(value_parser!(Shell))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("man")
+                .about("Print the man page")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("DIRECTORY")
+                        .help("Write grimoire.1 and a page per subcommand into DIRECTORY instead"),
+                ),
+        )
+}
+
+fn handle_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    install_tuning(matches)?;
+    let input_dir = matches.get_one::<String>("input").unwrap();
+    let output_prefix = matches.get_one::<String>("output").unwrap();
+    let (output_dir, output_name) = split_prefix(output_prefix);
+    if matches.get_flag("hnsw") && !cfg!(feature = "ann") {
+        return Err(HNSW_UNAVAILABLE.into());
+    }
+    let formats: Vec<&str> = matches
+        .get_one::<String>("formats")
+        .unwrap()
+        .split(',')
+        .collect();
+
+    let min_documents: usize = matches.get_one::<String>("min-documents").unwrap().parse()?;
+    let postings: PostingEncoding = matches.get_one::<String>("postings-codec").unwrap().parse()?;
+    let champions: Option<usize> = matches
+        .get_one::<String>("champions")
+        .map(|size| size.parse())
+        .transpose()?;
+    let phrase_words: usize = matches.get_one::<String>("phrase-words").unwrap().parse()?;
+    if !(MIN_PHRASE_WORDS..=MAX_PHRASE_WORDS).contains(&phrase_words) {
+        return Err(format!(
+            "--phrase-words must be between {} and {}",
+            MIN_PHRASE_WORDS, MAX_PHRASE_WORDS
+        )
+        .into());
+    }
+
+    let sample = document_sample(matches)?;
+
+    let format_name = matches.get_one::<String>("format").unwrap();
+    let format: SourceFormat = format_name.parse()?;
+    println!("Collecting books from: {} (format: {})", input_dir, format_name);
+    let mut files = collect_documents(input_dir, format);
+    if !sample.is_full() {
+        let found = files.len();
+        files.sort();
+        files = sample.apply(files, |file| {
+            file.strip_prefix(input_dir).unwrap_or(file).to_str().unwrap_or_default()
+        });
+        println!("Sampled {} of {} FB2 files", files.len(), found);
+    }
+
+    if files.is_empty() {
+        return Err(CorpusError::EmptyCorpus {
+            source: input_dir.to_string(),
+            reason: EmptyReason::NoDocuments,
+        }
+        .into());
+    }
+
+    if files.len() < 10 {
+        eprintln!(
+            "Warning: Found only {} FB2 files (requirement: at least 10)",
+            files.len()
+        );
+    }
+
+    println!("Found {} FB2 files", files.len());
+    for file in &files[..std::cmp::min(5, files.len())] {
+        println!("  - {}", file.display());
+    }
+    if files.len() > 5 {
+        println!("  ... and {} more", files.len() - 5);
+    }
+
+    let analyzer = Analyzer::from_spec(matches.get_one::<String>("analyzer").unwrap())?;
+    let parser = document_parser(matches, analyzer)?;
+    if matches.get_flag("incremental") {
+        let fingerprints_path = FingerprintCache::path(output_prefix);
+        if std::path::Path::new(&fingerprints_path).exists() {
+            let start_time = Instant::now();
+            let report = refresh_index(output_prefix, &files, &parser)?;
+            print_refresh_report(&report, start_time.elapsed());
+            return Ok(());
+        }
+        println!("No fingerprints saved in {}, building from scratch", fingerprints_path);
+    }
+
+    // Documents are named by file name, and those in archives are only found through them
+    let sources = source_paths(&files);
+    let source_path = |doc_name: &str| {
+        sources
+            .get(doc_name)
+            .cloned()
+            .unwrap_or_else(|| std::path::Path::new(input_dir).join(doc_name))
+    };
+
+    println!("\nBuilding dictionary...");
+    let mut profile = BuildProfile::new();
+    let start_time = Instant::now();
+    let pipeline_options = PipelineOptions {
+        queue_capacity: matches.get_one::<String>("queue-capacity").unwrap().parse()?,
+        ..PipelineOptions::default()
+    };
+    let regular_dictionary =
+        build_dictionary_profiled(&files, &parser, &pipeline_options, true, &mut profile)?;
+    let build_time = start_time.elapsed();
+    validate_corpus(input_dir, files.len(), &regular_dictionary, min_documents)?;
+
+    println!("Compressing dictionary...");
+    let compress_start = Instant::now();
+    // Shared with the wildcard engine instead of cloned into it
+    let dictionary = Arc::new(grimoire::CompressedDictionary::from_dictionary(
+        &regular_dictionary,
+    ));
+    let compress_time = compress_start.elapsed();
+    profile.record("compress", compress_time);
+    println!("Dictionary compression completed in {:.2?}", compress_time);
+
+    println!("\n=== COLLECTION STATISTICS ===");
+    println!(
+        "Collection size: {} bytes ({:.2} MB)",
+        dictionary.collection_size_bytes,
+        dictionary.collection_size_bytes as f64 / 1_048_576.0
+    );
+    println!("Total documents: {}", dictionary.total_documents);
+    println!("Total words: {}", dictionary.total_words);
+    println!(
+        "Dictionary size: {} unique terms",
+        dictionary.dictionary_size()
+    );
+    println!("Build time: {:.2?}", build_time);
+
+    println!("\n=== BUILDING SEARCH STRUCTURES ===");
+
+    let incidence_start = Instant::now();
+    let incidence_matrix = IncidenceMatrix::from_dictionary(&*dictionary);
+    let incidence_time = incidence_start.elapsed();
+    profile.record("structures;matrix", incidence_time);
+    let incidence_size = incidence_matrix.memory_size();
+
+    let inverted_start = Instant::now();
+    let inverted_index =
+        CompressedInvertedIndex::from_compressed_dictionary(&dictionary).with_encoding(postings);
+    let inverted_time = inverted_start.elapsed();
+    profile.record("structures;inverted", inverted_time);
+    let inverted_size = inverted_index.memory_size();
+
+    println!("{} of {}-word phrases...", PHRASE_INDEX_STAGE_MARKER, phrase_words);
+    println!("  Dictionary has {} unique terms", dictionary.sorted_terms.len());
+    let bigram_start = Instant::now();
+    let bigram_index =
+        NGramPhraseIndex::from_dictionary_with_order(&dictionary, phrase_words, |doc_name| {
+            println!("  {} {}", PHRASE_INDEX_DOCUMENT_MARKER, doc_name);
+            let file_path = source_path(doc_name);
+            let result = parser.parse_file(&file_path);
+            if let Ok(ref words) = result {
+                println!("    Parsed {} words from {}", words.len(), doc_name);
+            } else {
+                println!("    Failed to parse {}", doc_name);
+            }
+            result
+        })?;
+    let bigram_time = bigram_start.elapsed();
+    profile.record("structures;bigram", bigram_time);
+    let bigram_size = bigram_index.memory_size();
+    println!(
+        "  Phrase index built with {} phrases",
+        bigram_index.len()
+    );
+    let (bigram_original, bigram_compressed, bigram_ratio) = bigram_index.compression_stats();
+    println!(
+        "  Phrase keys and postings compressed to {:.2}% ({} -> {} bytes)",
+        bigram_ratio * 100.0,
+        bigram_original,
+        bigram_compressed
+    );
+
+    println!("Building coordinate index...");
+    let coordinate_start = Instant::now();
+    let coordinate_index = if let Some(memory_limit) = matches.get_one::<String>("memory-limit") {
+        let memory_limit: usize = memory_limit.parse()?;
+        println!("  Using positional SPIMI indexing (memory limit: {} MB)", memory_limit);
+        let mut indexer = PositionalSPIMIIndexer::new(memory_limit, "./spimi_temp")?;
+        for doc_name in &dictionary.documents {
+            println!("  Processing document for coordinate index: {}", doc_name);
+            let file_path = source_path(doc_name);
+            parser.for_each_token(&file_path, |word, position| {
+                indexer.add_token(doc_name, word, position)
+            })?;
+        }
+        indexer.finalize()?
+    } else {
+        CoordinateIndex::from_dictionary_with_tokens(&dictionary, |doc_name, sink| {
+            println!("  Processing document for coordinate index: {}", doc_name);
+            let file_path = source_path(doc_name);
+            let result = parser.for_each_token(&file_path, sink);
+            if let Ok(words) = result {
+                println!("    Parsed {} words from {}", words, doc_name);
+            } else {
+                println!("    Failed to parse {}", doc_name);
+            }
+            result
+        })?
+    };
+    let coordinate_time = coordinate_start.elapsed();
+    profile.record("structures;coordinate", coordinate_time);
+    let coordinate_size = coordinate_index.memory_size();
+    println!(
+        "  Coordinate index built with {} terms",
+        coordinate_index.index.len()
+    );
+
+    // Terms keep the ids of earlier builds under this prefix; new ones are appended
+    let mut interner = TermInterner::load_or_default(output_dir, output_name)?;
+    let new_terms = interner.intern_all(dictionary.sorted_terms.iter().map(String::as_str));
+    println!(
+        "Interned {} new terms ({} total)",
+        new_terms,
+        interner.len()
+    );
+
+    println!("Building forward index...");
+    let forward_start = Instant::now();
+    let forward_index = ForwardIndex::from_documents_with_interner(
+        &coordinate_index.documents,
+        &mut interner,
+        |doc_name| {
+            let file_path = source_path(doc_name);
+            parser.parse_file_with_offsets(&file_path)
+        },
+    )?;
+    let forward_time = forward_start.elapsed();
+    profile.record("structures;forward", forward_time);
+    let forward_size = forward_index.memory_size();
+
+    println!("Building temporal partitions...");
+    let partitions_start = Instant::now();
+    let partition_years: u32 = matches.get_one::<String>("partition-years").unwrap().parse()?;
+    let document_lengths = coordinate_index.document_lengths();
+    let partitions = TemporalPartitions::from_documents(
+        coordinate_index.documents.iter().map(|doc_name| {
+            let file_path = source_path(doc_name);
+            let date = parser.parse_date(&file_path).unwrap_or(None);
+            let words = document_lengths.get(doc_name.as_str()).copied().unwrap_or(0);
+            (doc_name.clone(), date, words)
+        }),
+        partition_years,
+    );
+    profile.record("structures;partitions", partitions_start.elapsed());
+
+    println!("Recording document norms...");
+    let norms_start = Instant::now();
+    let norms = DocumentNorms::from_forward_index(&forward_index, |doc_name| {
+        let file_path = source_path(doc_name);
+        let title = parser.parse_title(&file_path).unwrap_or_default();
+        (title.len(), parser.parse_language(&file_path).unwrap_or(None))
+    });
+    profile.record("structures;norms", norms_start.elapsed());
+
+    println!("Recording metadata fields...");
+    let fields_start = Instant::now();
+    let mut fields = FieldIndex::new();
+    let mut metadata: HashMap<&str, BookMetadata> = HashMap::new();
+    for doc_name in &coordinate_index.documents {
+        let file_path = source_path(doc_name);
+        let book = parser.parse_metadata(&file_path).unwrap_or_else(|e| {
+            println!("    Failed to parse metadata of {}: {}", doc_name, e);
+            BookMetadata::default()
+        });
+        for genre in &book.genres {
+            fields.insert("genre", genre, doc_name);
+        }
+        if let Some(language) = &book.language {
+            fields.insert("lang", language, doc_name);
+        }
+        for author in &book.authors {
+            fields.insert("author", author, doc_name);
+        }
+        if let Some(title) = &book.title {
+            fields.insert_terms("title", &parser.tokenize_text(title), doc_name);
+        }
+        if let Some(annotation) = &book.annotation {
+            fields.insert_terms("annotation", &parser.tokenize_text(annotation), doc_name);
+        }
+        metadata.insert(doc_name, book);
+    }
+    profile.record("structures;fields", fields_start.elapsed());
+
+    let store_text = matches.get_flag("store-text");
+    if store_text {
+        println!("Recording surface forms and storing documents with their texts...");
+    } else {
+        println!("Recording surface forms and storing documents...");
+    }
+    let bodies_start = Instant::now();
+    let bodies: Vec<(SurfaceForms, StoredDocument)> = coordinate_index
+        .documents
+        .par_iter()
+        .map(|doc_name| {
+            let file_path = source_path(doc_name);
+            let text = parser.parse_body(&file_path).unwrap_or_default();
+            let mut forms = SurfaceForms::new();
+            forms.add_text(&text, &parser.tokenize_text_with_offsets(&text));
+            let stored = StoredDocument::new(
+                doc_name,
+                &file_path,
+                metadata.get(doc_name.as_str()).cloned().unwrap_or_default(),
+                norms.length(doc_name) as u32,
+                store_text.then(|| DocumentStore::compress(&text)),
+            );
+            (forms, stored)
+        })
+        .collect();
+    let mut surface_forms = SurfaceForms::new();
+    let mut stored: HashMap<String, StoredDocument> = HashMap::new();
+    for (forms, document) in bodies {
+        surface_forms.merge(forms);
+        stored.insert(document.name.clone(), document);
+    }
+    // In dictionary order, so store ids are dictionary ids
+    let mut store = DocumentStore::new(store_text);
+    for doc_name in &dictionary.documents {
+        if let Some(document) = stored.remove(doc_name) {
+            store.insert(document);
+        }
+    }
+    let mut rest: Vec<StoredDocument> = stored.into_values().collect();
+    rest.sort_by(|a, b| a.name.cmp(&b.name));
+    for document in rest {
+        store.insert(document);
+    }
+    profile.record("structures;bodies", bodies_start.elapsed());
+
+    println!("Building wildcard search engine...");
+    let wildcard_start = Instant::now();
+    let wildcard_engine = WildcardSearchEngine::from_compressed_dictionary(Arc::clone(&dictionary))
+        .with_postings_encoding(postings);
+    let wildcard_time = wildcard_start.elapsed();
+    profile.record("structures;wildcard", wildcard_time);
+    let wildcard_stats = wildcard_engine.memory_size();
+
+    println!(
+        "Incidence Matrix: {} bytes, built in {:.2?}",
+        incidence_size, incidence_time
+    );
+    println!(
+        "Inverted Index: {} bytes ({} postings), built in {:.2?}",
+        inverted_size, postings, inverted_time
+    );
+    println!(
+        "Bigram Index: {} bytes, built in {:.2?}",
+        bigram_size, bigram_time
+    );
+    println!(
+        "Coordinate Index: {} bytes, built in {:.2?}",
+        coordinate_size, coordinate_time
+    );
+    println!(
+        "Wildcard Engine: {} bytes, built in {:.2?}",
+        wildcard_stats.total_size, wildcard_time
+    );
+    println!(
+        "Forward Index: {} bytes, built in {:.2?}",
+        forward_size, forward_time
+    );
+
+    let serialize_start = Instant::now();
+    incidence_matrix.save(output_dir, output_name)?;
+    inverted_index.save(output_dir, output_name)?;
+    bigram_index.save(output_dir, output_name)?;
+    coordinate_index.save(output_dir, output_name)?;
+    wildcard_engine.save(output_dir, output_name)?;
+    let terms_path = format!("{}_terms.bin", output_prefix);
+    let terms_size = TermBlockFile::write(&*dictionary, &terms_path)?;
+    let permuterm_size = wildcard_engine
+        .permutation_index()
+        .save_partitioned(output_prefix)?;
+
+    forward_index.save(output_dir, output_name)?;
+    let interner_size = interner.save(output_dir, output_name)?;
+    partitions.save(output_dir, output_name)?;
+    let norms_size = norms.save(output_dir, output_name)?;
+    fields.save(output_dir, output_name)?;
+    surface_forms.save(output_dir, output_name)?;
+    let store_size = store.save(output_dir, output_name)?;
+    profile.record("serialize;structures", serialize_start.elapsed());
+
+    let saved_path = |suffix: &str| format!("{}{}.bin", output_prefix, suffix);
+    println!("Saved incidence matrix to: {}", saved_path(IncidenceMatrix::SUFFIX));
+    println!("Saved inverted index to: {}", saved_path(CompressedInvertedIndex::SUFFIX));
+    println!("Saved phrase index to: {}", saved_path(NGramPhraseIndex::SUFFIX));
+    println!("Saved coordinate index to: {}", saved_path(CoordinateIndex::SUFFIX));
+    println!("Saved wildcard engine to: {}", saved_path(WildcardSearchEngine::SUFFIX));
+    println!("Saved term blocks to: {} ({} bytes)", terms_path, terms_size);
+    println!(
+        "Saved partitioned permuterm index to: {}_permuterm*.bin ({} bytes)",
+        output_prefix, permuterm_size
+    );
+    println!("Saved forward index to: {}", saved_path(ForwardIndex::SUFFIX));
+    println!(
+        "Saved term ids to: {} ({} bytes)",
+        saved_path(TermInterner::SUFFIX),
+        interner_size
+    );
+    println!("Saved temporal partitions to: {}", saved_path(TemporalPartitions::SUFFIX));
+    println!(
+        "Saved document norms to: {} ({} bytes, average length {:.1} words)",
+        saved_path(DocumentNorms::SUFFIX),
+        norms_size,
+        norms.average_length()
+    );
+    println!(
+        "Saved metadata fields to: {} ({} genres, {} languages, {} authors, {} title and {} \
+         annotation terms)",
+        saved_path(FieldIndex::SUFFIX),
+        fields.value_count("genre"),
+        fields.value_count("lang"),
+        fields.value_count("author"),
+        fields.value_count("title"),
+        fields.value_count("annotation")
+    );
+    println!(
+        "Saved surface forms to: {} ({} terms spelled other ways in the text)",
+        saved_path(SurfaceForms::SUFFIX),
+        surface_forms.len()
+    );
+    println!(
+        "Saved document store to: {} ({} documents, {} bytes{})",
+        saved_path(DocumentStore::SUFFIX),
+        store.len(),
+        store_size,
+        if store.keeps_bodies() { " with texts" } else { "" }
+    );
+    let mut structures = vec![
+        "",
+        "_matrix",
+        "_index",
+        "_bigram",
+        "_coordinate",
+        "_wildcard",
+        "_permuterm",
+        "_terms",
+        "_forward",
+        "_interner",
+        "_partitions",
+        "_norms",
+        "_fields",
+        "_surface",
+        "_store",
+    ];
+    if let Some(size) = champions {
+        let champion_lists = ChampionLists::from_index(&inverted_index, size);
+        let champions_size = champion_lists.save(output_dir, output_name)?;
+        println!(
+            "Saved champion lists to: {} ({} terms, {} bytes)",
+            saved_path(ChampionLists::SUFFIX),
+            champion_lists.lists.len(),
+            champions_size
+        );
+        structures.push("_champions");
+    }
+    if matches.get_flag("hnsw") {
+        save_hnsw_index(&coordinate_index, output_prefix)?;
+        structures.push("_hnsw");
+    }
+
+    println!("\n=== STRUCTURE COMPARISON ===");
+    println!("Incidence Matrix:      {} bytes", incidence_size);
+    println!("Inverted Index:        {} bytes", inverted_size);
+    println!("Phrase Index:          {} bytes", bigram_size);
+    println!("Coordinate Index:      {} bytes", coordinate_size);
+    let compressed_coordinate = CompressedCoordinateIndex::from_coordinate_index(&coordinate_index);
+    println!(
+        "  with compressed positions: {} bytes (positions at {:.1}% of their size)",
+        compressed_coordinate.memory_size(),
+        compressed_coordinate.compression_ratio()
+    );
+    println!("Forward Index:         {} bytes", forward_size);
+    println!("Wildcard Engine:       {} bytes", wildcard_stats.total_size);
+    println!(
+        "  - Suffix Tree:       {} bytes",
+        wildcard_stats.suffix_tree_size
+    );
+    println!(
+        "  - Permutation Index: {} bytes",
+        wildcard_stats.permutation_index_size
+    );
+    println!(
+        "  - Trigram Index:     {} bytes",
+        wildcard_stats.trigram_index_size
+    );
+
+    let efficiency_ratio = inverted_size as f64 / incidence_size as f64;
+    println!("Space efficiency (Index/Matrix): {:.2}x", efficiency_ratio);
+    let coordinate_ratio = coordinate_size as f64 / inverted_size as f64;
+    println!(
+        "Space overhead (Coordinate/Inverted): {:.2}x",
+        coordinate_ratio
+    );
+
+    println!("\n=== DATA STRUCTURE ANALYSIS ===");
+    println!("Incidence Matrix:");
+    println!("  - Space: O(|T| × |D|) where T=terms, D=documents");
+    println!("  - Search: O(|T|) for term lookup + O(|D|) for Boolean operations");
+    println!(
+        "  - Memory: {} bits per term-document pair",
+        incidence_matrix.matrix.len() * incidence_matrix.documents.len()
+    );
+
+    println!("Inverted Index:");
+    println!("  - Space: O(|unique_postings|) - only stores actual occurrences");
+    println!("  - Search: O(1) for term lookup + O(|posting_lists|) for Boolean operations");
+    println!("  - Memory: Variable size based on term distribution");
+
+    println!("Phrase Index:");
+    println!("  - Space: O(|unique_phrases|) - stores {}-word combinations", bigram_index.n);
+    println!("  - Search: Optimized for phrase search with exact word order");
+    println!("  - Memory: {} phrases indexed", bigram_index.len());
+
+    println!("Coordinate Index:");
+    println!("  - Space: O(|postings| × |positions|) - stores position information");
+    println!("  - Search: Supports phrase and proximity search with position verification");
+    println!("  - Memory: Includes position data for each term occurrence");
+
+    println!("\n=== SAVING DICTIONARY ===");
+    let mut format_sizes = Vec::new();
+
+    for format in &formats {
+        let start_time = Instant::now();
+        let (file_path, size) = match format.trim() {
+            "binary" => {
+                let path = format!("{}.bin", output_prefix);
+                let size = dictionary.save_as_binary(&path)?;
+                (path, size)
+            }
+            "json" => {
+                let path = format!("{}.json", output_prefix);
+                let size = dictionary.save_as_json(&path)?;
+                (path, size)
+            }
+            "text" => {
+                let path = format!("{}.txt", output_prefix);
+                let size = dictionary.save_as_text(&path)?;
+                (path, size)
+            }
+            _ => {
+                eprintln!("Unknown format: {}", format);
+                continue;
+            }
+        };
+        let save_time = start_time.elapsed();
+        profile.record("serialize;dictionary", save_time);
+        format_sizes.push((format.to_string(), size, save_time));
+        println!(
+            "Saved {} format: {} ({} bytes, {:.2?})",
+            format, file_path, size, save_time
+        );
+    }
+
+    println!("\n=== FORMAT COMPARISON ===");
+    format_sizes.sort_by_key(|(_, size, _)| *size);
+    for (format, size, time) in &format_sizes {
+        println!("{:>6}: {:>10} bytes ({:>6.2?})", format, size, time);
+    }
+
+    if let Some((smallest_format, smallest_size, _)) = format_sizes.first() {
+        if let Some((largest_format, largest_size, _)) = format_sizes.last() {
+            let ratio = *largest_size as f64 / *smallest_size as f64;
+            println!(
+                "Size ratio ({}:{}): {:.2}x",
+                largest_format, smallest_format, ratio
+            );
+        }
+    }
+
+    let manifest =
+        IndexManifest::commit_with_analyzer(output_prefix, &structures, parser.analyzer().clone())?;
+    println!("\nCommitted index generation {}", manifest.generation);
+    let mut fingerprints = FingerprintCache::new();
+    fingerprints.record_files(&files);
+    fingerprints.save(output_prefix)?;
+    println!(
+        "Saved fingerprints of {} files to: {}",
+        fingerprints.len(),
+        FingerprintCache::path(output_prefix)
+    );
+
+    profile.record_peak_resident();
+    let memory = profile.memory_report();
+    if !memory.is_empty() {
+        println!("\n=== MEMORY ===");
+        print!("{}", memory);
+    }
+
+    if matches.get_flag("profile") {
+        let profile_path = BuildProfile::path(output_prefix);
+        let previous = BuildProfile::load(&profile_path)?;
+        println!("\n=== BUILD PROFILE ===");
+        if previous.is_some() {
+            println!("Changes are relative to the previous profile in {}", profile_path);
+        }
+        print!("{}", profile.report(BUILD_PROFILE_SLOWEST, previous.as_ref()));
+        profile.save(&profile_path)?;
+        println!("Saved build profile to: {}", profile_path);
+    }
+
+    Ok(())
+}
+
+/// Path of every collected file by the name its document gets
+fn source_paths(files: &[std::path::PathBuf]) -> HashMap<String, std::path::PathBuf> {
+    files
+        .iter()
+        .map(|file| {
+            let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+            (name, file.clone())
+        })
+        .collect()
+}
+
+fn handle_update_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir = matches.get_one::<String>("input").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+
+    let mut files = collect_fb2_files(input_dir);
+    files.sort();
+    println!("Found {} FB2 files in {}", files.len(), input_dir);
+    let parser = document_parser(matches, IndexManifest::analyzer(dict_prefix)?)?;
+
+    let on_conflict: ConflictPolicy = matches.get_one::<String>("on-conflict").unwrap().parse()?;
+
+    let start_time = Instant::now();
+    let report = update_index(dict_prefix, &files, &parser, on_conflict)?;
+    for document in &report.added {
+        println!("  + {}", document);
+    }
+    for document in &report.replaced {
+        println!("  ~ {}", document);
+    }
+    println!(
+        "Added {} documents, replaced {}, {} already indexed, {} skipped in {:.2?}",
+        report.added.len(),
+        report.replaced.len(),
+        report.already_indexed.len(),
+        report.skipped.len(),
+        start_time.elapsed()
+    );
+    if report.added.is_empty() && report.replaced.is_empty() {
+        println!("Index unchanged at generation {}", report.generation);
+    } else {
+        println!("Committed index generation {}", report.generation);
+    }
+
+    Ok(())
+}
+
+fn handle_watch_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir = matches.get_one::<String>("input").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let interval: u64 = matches.get_one::<String>("interval").unwrap().parse()?;
+    let parser = document_parser(matches, IndexManifest::analyzer(dict_prefix)?)?;
+
+    println!("Watching {} every {}s, press Ctrl-C to stop", input_dir, interval);
+    loop {
+        let mut files = collect_fb2_files(input_dir);
+        files.sort();
+        let start_time = Instant::now();
+        let report = refresh_index(dict_prefix, &files, &parser)?;
+        if !report.changes.is_empty() {
+            print_refresh_report(&report, start_time.elapsed());
+        }
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+/// Print what an incremental build or a watch pass changed
+fn print_refresh_report(report: &RefreshReport, elapsed: std::time::Duration) {
+    let changes = &report.changes;
+    for document in &report.update.added {
+        println!("  + {}", document);
+    }
+    for document in &report.update.replaced {
+        println!("  ~ {}", document);
+    }
+    for document in &report.deleted {
+        println!("  - {}", document);
+    }
+    println!(
+        "{} files unchanged, {} new, {} modified, {} gone; added {} documents, replaced {}, \
+         deleted {}, {} skipped in {:.2?}",
+        changes.unchanged,
+        changes.added.len(),
+        changes.modified.len(),
+        changes.removed.len(),
+        report.update.added.len(),
+        report.update.replaced.len(),
+        report.deleted.len(),
+        report.update.skipped.len(),
+        elapsed
+    );
+    if changes.is_empty() {
+        println!("Index unchanged at generation {}", report.update.generation);
+    } else if report.update.added.is_empty() && report.update.replaced.is_empty() {
+        println!("Deleted documents wait for 'compact' to remove them from the structures");
+    } else {
+        println!("Committed index generation {}", report.update.generation);
+    }
+}
+
+fn handle_search_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    install_operator_aliases(matches)?;
+    let raw_query = matches.get_one::<String>("query").unwrap();
+    let mut dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    if let Some(variant_b) = matches.get_one::<String>("variant-b") {
+        let b_percent: u32 = matches.get_one::<String>("b-percent").unwrap().parse()?;
+        let variant = AbRouter::new(b_percent)?.route(raw_query);
+        if variant == Variant::B {
+            dict_prefix = variant_b;
+        }
+        // Each variant keeps its own query log, so metrics stay separate
+        println!("Variant: {} ({})", variant, dict_prefix);
+    }
+    install_query_analyzer(&[dict_prefix], matches.get_flag("raw-query"))?;
+    if let Some(hybrid) = HybridQuery::parse(raw_query) {
+        return handle_hybrid_search(matches, dict_prefix, &hybrid?);
+    }
+    let rank_model = matches.get_one::<String>("rank").unwrap();
+    let lambda: f64 = matches.get_one::<String>("lambda").unwrap().parse()?;
+
+    let planner_options = PlannerOptions {
+        needs_positions: rank_model == "qld",
+        expansion_limit: matches
+            .get_one::<String>("expand")
+            .map(|limit| limit.parse())
+            .transpose()?
+            .filter(|&limit| limit > 0),
+    };
+    let mut plan = plan_query(raw_query, &planner_options)?;
+    let unsupported = plan.retain_supported();
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let available: Vec<IndexKind> = structure_files(dict_prefix)
+        .into_iter()
+        .map(|(kind, _)| kind)
+        .collect();
+    let unavailable = plan.retain_available(&available);
+
+    println!("Loading search structures...");
+
+    if matches.get_flag("translit") {
+        let table = match matches.get_one::<String>("translit-table") {
+            Some(path) => TransliterationTable::from_file(path)?,
+            None => TransliterationTable::default(),
+        };
+        let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+        let bridge = TransliterationBridge::new(table, &dictionary.sorted_terms);
+        plan.query = bridge.rewrite_query(&plan.query)?;
+        println!("Transliterated query: {}", plan.query);
+    }
+    if let Some(limit) = plan.expansion_limit {
+        plan.query = match TermBlockFile::open(&format!("{}_terms.bin", dict_prefix)) {
+            // Scans only the blocks holding each stem instead of loading the wildcard engine
+            Ok(blocks) => expand_stems_with(&plan.query, |stem| {
+                blocks.expand_prefix(stem, limit).map_err(|e| e.to_string())
+            })?,
+            Err(_) if available.contains(&IndexKind::Wildcard) => {
+                let wildcard_engine = WildcardSearchEngine::load(dict_dir, dict_name)?;
+                wildcard_engine.expand_stems(&plan.query, limit)?
+            }
+            Err(_) => {
+                eprintln!("Warning: neither term blocks nor the wildcard engine are saved, stems are not expanded");
+                plan.query.clone()
+            }
+        };
+        println!("Expanded query: {}", plan.query);
+    }
+    let query = &plan.query;
+    let used_structures = plan.loaded_structures();
+
+    let since = parse_date_arg(matches, "since", false)?;
+    let until = parse_date_arg(matches, "until", true)?;
+    let time_slice = if since.is_some() || until.is_some() {
+        let partitions = TemporalPartitions::load(dict_dir, dict_name)?;
+        let slice = partitions.slice(since, until);
+        println!(
+            "Time slice: {} documents from {} partitions ({} partitions skipped)",
+            slice.documents.len(),
+            slice.scanned_partitions,
+            slice.skipped_partitions
+        );
+        Some(slice.documents)
+    } else {
+        None
+    };
+    let hidden = if matches.get_flag("include-hidden") {
+        HiddenDocuments::new()
+    } else {
+        HiddenDocuments::load_or_default(&format!("{}_hidden.bin", dict_prefix))?
+    };
+    if !hidden.is_empty() {
+        println!("Excluding {} hidden documents", hidden.len());
+    }
+    let tombstones = Tombstones::load_or_default(dict_prefix)?;
+    if !tombstones.is_empty() {
+        println!("Excluding {} deleted documents awaiting compaction", tombstones.len());
+    }
+    let is_visible = |doc: &str| {
+        !hidden.is_hidden(doc)
+            && !tombstones.is_deleted(doc)
+            && time_slice.as_ref().is_none_or(|documents| documents.contains(doc))
+    };
+    if matches.get_flag("verify") {
+        return verify_structures(dict_prefix, query, plan.required, &is_visible);
+    }
+
+    let summary_dictionary = if matches.get_flag("summaries") {
+        Some(CompressedDictionary::load(dict_dir, dict_name)?)
+    } else {
+        None
+    };
+    let summary_sentences: usize = matches.get_one::<String>("sentences").unwrap().parse()?;
+    let store = DocumentStore::load_if_present(dict_dir, dict_name)?;
+    let snippets = SnippetPrinter::load(matches, store.as_ref(), dict_prefix, query)?;
+    let page = ResultPage::new(
+        matches.get_one::<String>("offset").unwrap().parse()?,
+        matches
+            .get_one::<String>("limit")
+            .map(|limit| limit.parse())
+            .transpose()?,
+    );
+    let print_documents = |docs: &[&String]| {
+        print_page_bounds(&page, docs.len());
+        for doc in page.apply(docs.iter()) {
+            println!("  - {}", describe_document(store.as_ref(), doc));
+            if let Some(snippets) = &snippets {
+                snippets.print(doc);
+            }
+            if let Some(ref dictionary) = summary_dictionary {
+                let input_dir = matches.get_one::<String>("input").unwrap();
+                let file_path = std::path::Path::new(input_dir).join(doc);
+                print_summary(dictionary, &file_path, summary_sentences);
+            }
         }
-        Some(("parquet-build", sub_matches)) => {
-            handle_parquet_build_command(sub_matches)?;
+    };
+
+    // Largest visible result set across the structures that ran, for the query log
+    let mut result_count = 0;
+
+    let cache_path = format!("{}_cache.bin", dict_prefix);
+    let mut cache = if matches.get_flag("cache") {
+        let generation = IndexManifest::current_generation(dict_prefix)?;
+        Some(ResultCache::load(&cache_path, generation, RESULT_CACHE_CAPACITY))
+    } else {
+        None
+    };
+
+    println!("Query: {}", query);
+    println!(
+        "Plan: {} ({})",
+        plan.structures
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        match (plan.hinted, plan.split) {
+            (true, _) => "hinted",
+            (false, true) => "planner, split",
+            (false, false) => "planner",
+        }
+    );
+    for reason in unsupported {
+        println!("Skipped: {}", reason);
+    }
+    for warning in unavailable {
+        eprintln!("Warning: {}", warning);
+    }
+    if matches.get_flag("explain") {
+        let explained = SharedSearchers::open(dict_prefix).inverted().and_then(|inverted| {
+            let optimized = inverted.optimize_query(QueryAst::parse(query)?);
+            Ok(inverted.explain_query(&optimized))
+        });
+        match explained {
+            Ok(lines) => {
+                println!("Evaluation order:");
+                for line in lines {
+                    println!("  {}", line);
+                }
+            }
+            Err(e) => println!("Evaluation order: unavailable ({})", e),
+        }
+        let traced = [IndexKind::Inverted, IndexKind::Coordinate, IndexKind::Wildcard];
+        if plan.split || traced.iter().any(|&kind| plan.uses(kind)) {
+            match SharedSearchers::open(dict_prefix).explain(&plan) {
+                Ok(explanation) => {
+                    for line in explanation.lines() {
+                        println!("{}", line);
+                    }
+                }
+                Err(e) => println!("Trace: unavailable ({})", e),
+            }
+        } else {
+            println!("Trace: only the inverted, coordinate and wildcard structures are traced");
+        }
+    }
+    if let Ok(blocks) = TermBlockFile::open(&format!("{}_terms.bin", dict_prefix)) {
+        match blocks.estimate(query) {
+            Ok(estimate) => println!("Estimated hits: about {}", estimate),
+            Err(e) => println!("Estimated hits: unavailable ({})", e),
+        }
+    }
+
+    if plan.split {
+        println!("\n=== SPLIT SEARCH ===");
+        println!("Phrases and near/N groups: coordinate index; patterns: wildcard engine");
+        let result = cached_search(&mut cache, IndexKind::Coordinate, query, || {
+            let searchers = SharedSearchers::open(dict_prefix);
+            let split_start = Instant::now();
+            let result = searchers.search_routed(query).map(|documents| {
+                let mut documents: Vec<String> = documents.into_iter().collect();
+                documents.sort();
+                documents
+            });
+            Ok((result, split_start.elapsed()))
+        })?;
+        match result {
+            (Ok(result), split_time) => {
+                let mut matching_docs: Vec<&String> = result.iter().collect();
+                matching_docs.retain(|doc| is_visible(doc));
+                result_count = result_count.max(matching_docs.len());
+                println!(
+                    "Found {} documents in {:.2?}",
+                    matching_docs.len(),
+                    split_time
+                );
+                print_documents(&matching_docs);
+            }
+            (Err(e), _) => println!("Error: {}", e),
+        }
+        // The split search answered the whole query; no structure can on its own
+        plan.structures.clear();
+    }
+
+    if plan.uses(IndexKind::Matrix) {
+        println!("\n=== INCIDENCE MATRIX SEARCH ===");
+        let result = cached_search(&mut cache, IndexKind::Matrix, query, || {
+            let incidence_matrix = IncidenceMatrix::load(dict_dir, dict_name)?;
+
+            let matrix_start = Instant::now();
+            let result = incidence_matrix.search(query).map(|result| {
+                incidence_matrix
+                    .get_matching_documents(&result)
+                    .into_iter()
+                    .cloned()
+                    .collect()
+            });
+            Ok((result, matrix_start.elapsed()))
+        })?;
+        match result {
+            (Ok(result), matrix_time) => {
+                let mut matching_docs: Vec<&String> = result.iter().collect();
+                matching_docs.retain(|doc| is_visible(doc));
+                result_count = result_count.max(matching_docs.len());
+                println!(
+                    "Found {} documents in {:.2?}",
+                    matching_docs.len(),
+                    matrix_time
+                );
+                print_documents(&matching_docs);
+            }
+            (Err(e), _) => println!("Error: {}", e),
+        }
+    }
+
+    if plan.uses(IndexKind::Inverted) {
+        println!("\n=== INVERTED INDEX SEARCH ===");
+        let mut loaded_index = None;
+        let result = cached_search(&mut cache, IndexKind::Inverted, query, || {
+            let inverted_index = CompressedInvertedIndex::load(dict_dir, dict_name)?;
+
+            let index_start = Instant::now();
+            let result = inverted_index.search(query).map(sorted_documents);
+            loaded_index = Some(inverted_index);
+            Ok((result, index_start.elapsed()))
+        })?;
+        match result {
+            (Ok(result), index_time) => {
+                let mut docs: Vec<_> = result.iter().filter(|doc| is_visible(doc)).collect();
+                docs.sort();
+                result_count = result_count.max(docs.len());
+                println!("Found {} documents in {:.2?}", docs.len(), index_time);
+                if matches.get_flag("ranked") {
+                    let inverted_index = match loaded_index {
+                        Some(index) => index,
+                        None => CompressedInvertedIndex::load(dict_dir, dict_name)?,
+                    };
+                    let top_k: usize = matches.get_one::<String>("top-k").unwrap().parse()?;
+                    let matched = docs.len();
+                    let documents = docs.into_iter().cloned();
+                    let ranked = if let Some(weights) = zone_weights(matches)? {
+                        let fields = FieldIndex::load_if_present(dict_dir, dict_name)?
+                            .ok_or("No field index is saved, rebuild to rank by zones")?;
+                        let candidates: roaring::RoaringBitmap = documents
+                            .filter_map(|doc| inverted_index.doc_name_to_id.get(&doc).copied())
+                            .collect();
+                        println!(
+                            "Ranked by zone score ({}), top {} of {} documents:",
+                            weights, top_k, matched
+                        );
+                        let index = &inverted_index;
+                        rank_by_zones(index, &fields, &weights, query, &candidates, top_k)?
+                    } else {
+                        let champions = ChampionLists::load_if_present(dict_dir, dict_name)?;
+                        let (ranked, path) = match &champions {
+                            Some(champions) => {
+                                champions.rank_top_k(&inverted_index, documents, query, top_k)?
+                            }
+                            None => {
+                                let (ranked, stats) =
+                                    inverted_index.rank_top_k(documents, query, top_k)?;
+                                (ranked, RankingPath::FullPostings(stats))
+                            }
+                        };
+                        match path {
+                            RankingPath::Champions { candidates } => println!(
+                                "Ranked by TF-IDF, top {} from champion lists ({} of {} documents scored):",
+                                top_k, candidates, matched
+                            ),
+                            RankingPath::FullPostings(stats) => {
+                                if champions.is_some() {
+                                    println!(
+                                        "Champion lists could not settle the top {}, reading full posting lists",
+                                        top_k
+                                    );
+                                }
+                                println!(
+                                    "Ranked by TF-IDF, top {} ({} of {} documents scored):",
+                                    top_k, stats.scored, matched
+                                );
+                            }
+                        }
+                        ranked
+                    };
+                    print_page_bounds(&page, ranked.len());
+                    for (doc, score) in page.apply(ranked.into_iter()) {
+                        println!("  - {} ({:.4})", describe_document(store.as_ref(), &doc), score);
+                        if let Some(snippets) = &snippets {
+                            snippets.print(&doc);
+                        }
+                    }
+                } else {
+                    print_documents(&docs);
+                }
+            }
+            (Err(e), _) => println!("Error: {}", e),
+        }
+    }
+
+    if plan.uses(IndexKind::Bigram) {
+        println!("\n=== BIGRAM INDEX PHRASE SEARCH ===");
+        let mut loaded_index = None;
+        let result = cached_search(&mut cache, IndexKind::Bigram, query, || {
+            let bigram_index = NGramPhraseIndex::load(dict_dir, dict_name)?;
+
+            let bigram_start = Instant::now();
+            let result = bigram_index.search(query).map(sorted_documents);
+            loaded_index = Some(bigram_index);
+            Ok((result, bigram_start.elapsed()))
+        })?;
+        match result {
+            (Ok(result), bigram_time) => {
+                let mut docs: Vec<_> = result.iter().filter(|doc| is_visible(doc)).collect();
+                docs.sort();
+                result_count = result_count.max(docs.len());
+                println!("Found {} documents in {:.2?}", docs.len(), bigram_time);
+                // A query that is a single phrase is ranked by the phrase's TF-IDF
+                match single_phrase(query) {
+                    Some(phrase) if !docs.is_empty() => {
+                        let bigram_index = match loaded_index {
+                            Some(index) => index,
+                            None => NGramPhraseIndex::load(dict_dir, dict_name)?,
+                        };
+                        println!("Ranked by phrase TF-IDF:");
+                        let ranked =
+                            bigram_index.rank_phrase(phrase, docs.iter().map(|doc| doc.as_str()));
+                        print_page_bounds(&page, ranked.len());
+                        for (doc, score) in page.apply(ranked.into_iter()) {
+                            println!(
+                                "  - {} ({:.4}, {} occurrences)",
+                                doc,
+                                score,
+                                bigram_index.phrase_freq(phrase, doc)
+                            );
+                        }
+                    }
+                    _ => print_documents(&docs),
+                }
+            }
+            (Err(e), _) => println!("Error: {}", e),
+        }
+    }
+
+    if plan.uses(IndexKind::Coordinate) {
+        let coordinate_index = CoordinateIndex::load(dict_dir, dict_name)?;
+
+        println!("\n=== COORDINATE INDEX SEARCH ===");
+        let coordinate_start = Instant::now();
+        match coordinate_index.search(query) {
+            Ok(result) => {
+                let coordinate_time = coordinate_start.elapsed();
+                let mut docs: Vec<_> = result.iter().filter(|doc| is_visible(doc)).collect();
+                docs.sort();
+                result_count = result_count.max(docs.len());
+                println!("Found {} documents in {:.2?}", docs.len(), coordinate_time);
+                if rank_model == "qld" {
+                    let terms = query_terms(query)?;
+                    let norms = DocumentNorms::load_if_present(dict_dir, dict_name)?;
+                    let scorer = match &norms {
+                        Some(norms) => {
+                            QueryLikelihoodScorer::with_norms(&coordinate_index, lambda, norms)
+                        }
+                        None => QueryLikelihoodScorer::new(&coordinate_index, lambda),
+                    };
+                    let mut ranked = scorer.rank(docs, &terms);
+                    if matches.get_flag("diversify") {
+                        let mmr_lambda: f64 =
+                            matches.get_one::<String>("mmr-lambda").unwrap().parse()?;
+                        let mmr_top: usize =
+                            matches.get_one::<String>("mmr-top").unwrap().parse()?;
+                        let candidates: Vec<&str> =
+                            ranked.iter().take(mmr_top).map(|(doc, _)| *doc).collect();
+                        let vectors = document_vectors(&coordinate_index, &candidates);
+                        ranked = mmr_rerank(&ranked, &vectors, mmr_lambda, mmr_top);
+                        println!(
+                            "Ranked by query likelihood (lambda {}), top {} diversified with MMR (lambda {}):",
+                            lambda, mmr_top, mmr_lambda
+                        );
+                    } else {
+                        println!("Ranked by query likelihood (lambda {}):", lambda);
+                    }
+                    print_page_bounds(&page, ranked.len());
+                    for (doc, score) in page.apply(ranked.into_iter()) {
+                        println!("  - {} ({:.4})", describe_document(store.as_ref(), doc), score);
+                        if let Some(snippets) = &snippets {
+                            snippets.print(doc);
+                        }
+                    }
+                } else if matches.get_flag("positions") {
+                    print_match_positions(
+                        &coordinate_index,
+                        query,
+                        dict_prefix,
+                        &is_visible,
+                        &page,
+                    )?;
+                } else {
+                    print_documents(&docs);
+                }
+            }
+            Err(e) => println!("Error: {}", e),
+        }
+
+        if query.contains("near/") {
+            println!("\n=== PROXIMITY SEARCH EXAMPLE ===");
+            println!("Example proximity searches:");
+            println!("  near/5(word1 word2) - finds 'word1' and 'word2' within 5 positions");
+            println!("  near/10(love peace) - finds 'love' and 'peace' within 10 positions");
         }
-        _ => unreachable!(),
+    }
+
+    if plan.uses(IndexKind::Wildcard) {
+        let max_expansions = if matches.get_flag("all-expansions") {
+            None
+        } else {
+            Some(matches.get_one::<String>("max-expansions").unwrap().parse()?)
+        };
+        let wildcard_engine = WildcardSearchEngine::load(dict_dir, dict_name)?
+            .with_max_expansions(max_expansions);
+
+        println!("\n=== WILDCARD SEARCH ===");
+        let wildcard_result = wildcard_engine.search_with_stats(query);
+
+        println!("Strategy: {}", wildcard_result.strategy);
+        println!("Search time: {:.2?}", wildcard_result.search_time);
+        for truncation in &wildcard_result.truncations {
+            println!(
+                "Expanded '{}' to the {} most frequent of {} matching terms, \
+                 --all-expansions searches them all",
+                truncation.pattern, truncation.expanded, truncation.matched
+            );
+        }
+
+        if let Some(error) = wildcard_result.error {
+            println!("Error: {}", error);
+        } else {
+            let mut docs: Vec<_> = wildcard_result
+                .documents
+                .iter()
+                .filter(|doc| is_visible(doc))
+                .collect();
+            docs.sort();
+            result_count = result_count.max(docs.len());
+            if matches.get_flag("show-expansions") {
+                println!("Expanded to {} terms:", wildcard_result.expansions.len());
+                for expansion in &wildcard_result.expansions {
+                    println!(
+                        "  {} ({} documents)",
+                        expansion.term, expansion.document_count
+                    );
+                }
+            }
+            println!("Found {} documents", docs.len());
+            print_documents(&docs);
+        }
+
+        println!("\n=== WILDCARD SEARCH EXAMPLES ===");
+        println!("Prefix wildcard: cat* - finds 'cat', 'cats', 'category', etc.");
+        println!("Suffix wildcard: *ing - finds 'running', 'testing', 'building', etc.");
+        println!("Middle wildcard: c*t - finds 'cat', 'cart', 'coat', etc.");
+        println!("Multiple wildcards: *test* - finds any word containing 'test'");
+        println!("Single character: c?t - finds 'cat', 'cut', 'cot', etc.");
+    }
+
+    if let Some(cache) = cache {
+        cache.save(&cache_path)?;
+    }
+    if matches.get_flag("log-queries") {
+        append_query_log(&format!("{}_queries.log", dict_prefix), raw_query, result_count)?;
+        append_usage_log(dict_prefix, query, &used_structures)?;
     }
 
     Ok(())
 }
 
-fn handle_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let input_dir = matches.get_one::<String>("input").unwrap();
-    let output_prefix = matches.get_one::<String>("output").unwrap();
-    let formats: Vec<&str> = matches
-        .get_one::<String>("formats")
-        .unwrap()
-        .split(',')
+/// Run a `filter RANK BY ranking` query: the filter is evaluated to a bitmap over the
+/// inverted index, and only its documents are scored for the ranking terms. A filter alone
+/// lists its documents by name.
+fn handle_hybrid_search(
+    matches: &clap::ArgMatches,
+    dict_prefix: &str,
+    hybrid: &HybridQuery,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let top_k: usize = matches.get_one::<String>("top-k").unwrap().parse()?;
+    let page = ResultPage::new(
+        matches.get_one::<String>("offset").unwrap().parse()?,
+        matches
+            .get_one::<String>("limit")
+            .map(|limit| limit.parse())
+            .transpose()?,
+    );
+
+    println!("Loading search structures...");
+    let start = Instant::now();
+    let inverted_index = CompressedInvertedIndex::load(dict_dir, dict_name)?;
+    let fields = FieldIndex::load_if_present(dict_dir, dict_name)?;
+    let mut candidates = hybrid.filter(&inverted_index, fields.as_ref())?;
+
+    let hidden = if matches.get_flag("include-hidden") {
+        HiddenDocuments::new()
+    } else {
+        HiddenDocuments::load_or_default(&format!("{}_hidden.bin", dict_prefix))?
+    };
+    let tombstones = Tombstones::load_or_default(dict_prefix)?;
+    let excluded: roaring::RoaringBitmap = candidates
+        .iter()
+        .filter(|&id| {
+            let doc = &inverted_index.doc_id_to_name[id as usize];
+            hidden.is_hidden(doc) || tombstones.is_deleted(doc)
+        })
+        .collect();
+    candidates -= excluded;
+    let filter_time = start.elapsed();
+    println!(
+        "Filter matched {} documents in {:.2?}: {}",
+        candidates.len(),
+        filter_time,
+        if hybrid.filter_query.is_empty() { "everything" } else { &hybrid.filter_query }
+    );
+
+    let store = DocumentStore::load_if_present(dict_dir, dict_name)?;
+    if hybrid.is_filter_only() {
+        let snippets =
+            SnippetPrinter::load(matches, store.as_ref(), dict_prefix, &hybrid.filter_query)?;
+        let mut docs: Vec<&String> = candidates
+            .iter()
+            .map(|id| &inverted_index.doc_id_to_name[id as usize])
+            .collect();
+        docs.sort();
+        print_page_bounds(&page, docs.len());
+        for doc in page.apply(docs.into_iter()) {
+            println!("  - {}", describe_document(store.as_ref(), doc));
+            if let Some(snippets) = &snippets {
+                snippets.print(doc);
+            }
+        }
+        return Ok(());
+    }
+
+    let ranked = match zone_weights(matches)? {
+        Some(weights) => {
+            let fields = fields
+                .as_ref()
+                .ok_or("No field index is saved, rebuild to rank by zones")?;
+            let query = &hybrid.ranking_query;
+            let ranked =
+                rank_by_zones(&inverted_index, fields, &weights, query, &candidates, top_k)?;
+            println!(
+                "Ranked by zone score of {} ({}), top {} of {} documents in {:.2?}:",
+                hybrid.ranking_query,
+                weights,
+                top_k,
+                candidates.len(),
+                start.elapsed() - filter_time
+            );
+            ranked
+        }
+        None => {
+            let (ranked, stats) = hybrid.rank(&inverted_index, &candidates, top_k)?;
+            println!(
+                "Ranked by TF-IDF of {}, top {} ({} of {} documents scored) in {:.2?}:",
+                hybrid.ranking_query,
+                top_k,
+                stats.scored,
+                candidates.len(),
+                start.elapsed() - filter_time
+            );
+            ranked
+        }
+    };
+    let snippets =
+        SnippetPrinter::load(matches, store.as_ref(), dict_prefix, &hybrid.ranking_query)?;
+    print_page_bounds(&page, ranked.len());
+    for (doc, score) in page.apply(ranked.into_iter()) {
+        println!("  - {} ({:.4})", describe_document(store.as_ref(), &doc), score);
+        if let Some(snippets) = &snippets {
+            snippets.print(&doc);
+        }
+    }
+    Ok(())
+}
+
+/// Zone weights to rank by with `--rank-zones`, `None` to rank by TF-IDF
+fn zone_weights(matches: &clap::ArgMatches) -> Result<Option<ZoneWeights>, String> {
+    if !matches.get_flag("rank-zones") {
+        return Ok(None);
+    }
+    matches.get_one::<String>("zone-weights").unwrap().parse().map(Some)
+}
+
+/// A result line's name for `document`: its title and authors when the index has a document
+/// store that knows them
+fn describe_document(store: Option<&DocumentStore>, document: &str) -> String {
+    store.map_or_else(|| document.to_string(), |store| store.describe(document))
+}
+
+/// Prints a snippet under each hit from the bodies in the document store. Matches are the
+/// positions the coordinate index finds for the query, so phrases are marked whole; documents it
+/// has no positions for fall back to the query terms found in their text.
+struct SnippetPrinter<'a> {
+    store: &'a DocumentStore,
+    parser: FB2Parser,
+    positions: HashMap<String, Vec<Span>>,
+    terms: HashSet<String>,
+    context: usize,
+}
+
+impl<'a> SnippetPrinter<'a> {
+    /// `None` unless `--snippets` is given
+    fn load(
+        matches: &clap::ArgMatches,
+        store: Option<&'a DocumentStore>,
+        dict_prefix: &str,
+        query: &str,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !matches.get_flag("snippets") {
+            return Ok(None);
+        }
+        let (dict_dir, dict_name) = split_prefix(dict_prefix);
+        let store = store.filter(|store| store.keeps_bodies()).ok_or_else(|| {
+            format!(
+                "{} has no stored texts, rebuild it with --store-text for snippets",
+                dict_prefix
+            )
+        })?;
+        let positions = match CoordinateIndex::load_if_present(dict_dir, dict_name)? {
+            // A query the coordinate index rejects still gets term snippets
+            Some(index) => index
+                .search_with_positions(query)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|hit| (hit.document, hit.spans))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(Some(SnippetPrinter {
+            store,
+            parser: FB2Parser::new().with_analyzer(Analyzer::current().as_ref().clone()),
+            positions,
+            terms: query_terms(query)?.into_iter().collect(),
+            context: matches.get_one::<String>("snippet-words").unwrap().parse()?,
+        }))
+    }
+
+    fn print(&self, document: &str) {
+        let Some(text) = self.store.text(document) else {
+            return;
+        };
+        let tokens = self.parser.tokenize_text_with_offsets(&text);
+        let spans = match self.positions.get(document) {
+            Some(spans) => spans.clone(),
+            None => term_spans(&tokens, &self.terms),
+        };
+        if let Some(snippet) = make_snippet(&text, &tokens, &spans, self.context) {
+            println!("    {}", snippet);
+        }
+    }
+}
+
+/// Sorted documents and the time one structure took to produce them
+type TimedResult = (Result<Vec<String>, String>, std::time::Duration);
+
+/// Run `search` unless the cache already holds the result for this structure and query;
+/// cache hits skip loading the structure entirely
+fn cached_search<F>(
+    cache: &mut Option<ResultCache>,
+    kind: IndexKind,
+    query: &str,
+    search: F,
+) -> Result<TimedResult, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<TimedResult, Box<dyn std::error::Error>>,
+{
+    let lookup_start = Instant::now();
+    if let Some(documents) = cache.as_ref().and_then(|cache| cache.get(kind, query)) {
+        println!(
+            "Cached result (generation {})",
+            cache.as_ref().map_or(0, |cache| cache.generation())
+        );
+        return Ok((Ok(documents.clone()), lookup_start.elapsed()));
+    }
+
+    let (result, elapsed) = search()?;
+    if let (Some(cache), Ok(documents)) = (cache.as_mut(), &result) {
+        cache.insert(kind, query, documents.clone());
+    }
+    Ok((result, elapsed))
+}
+
+/// The phrase of a query consisting of one quoted phrase and nothing else
+fn single_phrase(query: &str) -> Option<&str> {
+    let phrase = query.trim().strip_prefix('"')?.strip_suffix('"')?;
+    (!phrase.contains('"')).then_some(phrase)
+}
+
+/// Documents of one term in a loaded structure, `None` when the structure lacks the term
+type TermLookup = Box<dyn Fn(&str) -> Option<HashSet<String>>>;
+
+/// Run `query` on every structure able to answer it and print the documents they disagree on,
+/// with the likely reason each structure missed a document
+fn verify_structures(
+    dict_prefix: &str,
+    query: &str,
+    required: Capabilities,
+    is_visible: &dyn Fn(&str) -> bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    let saved: Vec<IndexKind> = structure_files(dict_prefix)
+        .into_iter()
+        .map(|(kind, _)| kind)
+        .collect();
+    let (structures, missing): (Vec<IndexKind>, Vec<IndexKind>) = capable_structures(required)
+        .into_iter()
+        .partition(|kind| saved.contains(kind));
+    println!("Query: {}", query);
+    for kind in missing {
+        eprintln!("Warning: the {} index is not saved, skipped it", kind);
+    }
+    println!(
+        "Verifying across: {}",
+        structures
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut results = Vec::new();
+    let mut lookups: HashMap<IndexKind, TermLookup> = HashMap::new();
+    for kind in structures {
+        let (documents, lookup): (Result<HashSet<String>, String>, Option<TermLookup>) =
+            match kind {
+                IndexKind::Matrix => {
+                    let matrix = IncidenceMatrix::load(dict_dir, dict_name)?;
+                    let documents = matrix.search(query).map(|row| {
+                        matrix
+                            .get_matching_documents(&row)
+                            .into_iter()
+                            .cloned()
+                            .collect()
+                    });
+                    let lookup: TermLookup = Box::new(move |term| {
+                        let row = matrix.search_term(term).ok()?;
+                        Some(matrix.get_matching_documents(&row).into_iter().cloned().collect())
+                    });
+                    (documents, Some(lookup))
+                }
+                IndexKind::Inverted => {
+                    let index = CompressedInvertedIndex::load(dict_dir, dict_name)?;
+                    let documents = index.search(query);
+                    let lookup: TermLookup = Box::new(move |term| {
+                        Some(index.get_documents_for_term(term)?.into_iter().collect())
+                    });
+                    (documents, Some(lookup))
+                }
+                IndexKind::Bigram => {
+                    let index = NGramPhraseIndex::load(dict_dir, dict_name)?;
+                    (index.search(query), None)
+                }
+                IndexKind::Coordinate => {
+                    let index = CoordinateIndex::load(dict_dir, dict_name)?;
+                    let documents = index.search(query);
+                    let lookup: TermLookup = Box::new(move |term| {
+                        let postings = index.index.get(term)?;
+                        Some(postings.iter().map(|p| p.document.clone()).collect())
+                    });
+                    (documents, Some(lookup))
+                }
+                IndexKind::Wildcard => {
+                    // Every match, as the other structures find them
+                    let engine =
+                        WildcardSearchEngine::load(dict_dir, dict_name)?.with_max_expansions(None);
+                    (engine.search(query), None)
+                }
+            };
+        let documents = documents.map(|documents| {
+            documents
+                .into_iter()
+                .filter(|doc| is_visible(doc))
+                .collect::<HashSet<_>>()
+        });
+        match &documents {
+            Ok(documents) => println!("{:<12} {} documents", kind.to_string(), documents.len()),
+            Err(e) => println!("{:<12} error: {}", kind.to_string(), e),
+        }
+        if let Some(lookup) = lookup {
+            lookups.insert(kind, lookup);
+        }
+        results.push(StructureResult { kind, documents });
+    }
+
+    // A missed document is explained by the first positive query term the dictionary places in
+    // it that the structure has no posting for
+    let terms: Vec<String> = query_terms(query)?
+        .into_iter()
+        .filter(|term| !term.contains(['*', '?']))
+        .collect();
+    let discrepancies = compare_results(&results, |kind, document| {
+        let Some(lookup) = lookups.get(&kind) else {
+            return format!("the {} index evaluates the query differently", kind);
+        };
+        for term in &terms {
+            let in_document = dictionary
+                .term_documents(term)
+                .is_some_and(|documents| documents.contains(document));
+            if !in_document {
+                continue;
+            }
+            return match lookup(term) {
+                None => format!("term '{}' is missing from the {} index", term, kind),
+                Some(documents) if !documents.contains(document) => {
+                    format!("the {} index has no posting of '{}' here", kind, term)
+                }
+                Some(_) => continue,
+            };
+        }
+        format!("the {} index evaluates the query differently", kind)
+    });
+
+    println!("\n=== CONSISTENCY CHECK ===");
+    if discrepancies.is_empty() {
+        println!("All structures that answered agree");
+    }
+    for discrepancy in &discrepancies {
+        println!(
+            "{} (found by {})",
+            discrepancy.document,
+            discrepancy
+                .found_by
+                .iter()
+                .map(|kind| kind.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        for (kind, reason) in &discrepancy.missed_by {
+            println!("  missing from {}: {}", kind, reason);
+        }
+    }
+    Ok(())
+}
+
+/// Hits of the coordinate index with the word positions they matched at, and the source byte
+/// ranges of the matches when the forward index is saved
+fn print_match_positions(
+    coordinate_index: &CoordinateIndex,
+    query: &str,
+    dict_prefix: &str,
+    is_visible: &dyn Fn(&str) -> bool,
+    page: &ResultPage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let forward_index = ForwardIndex::load_if_present(dict_dir, dict_name)?;
+    let mut hits = coordinate_index.search_with_positions(query)?;
+    hits.retain(|hit| is_visible(&hit.document));
+
+    print_page_bounds(page, hits.len());
+    for mut hit in page.apply(hits.into_iter()) {
+        let spans: Vec<String> = hit
+            .spans
+            .iter()
+            .map(|&(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}-{}", start, end)
+                }
+            })
+            .collect();
+        println!("  - {} at positions {}", hit.document, spans.join(", "));
+        if let Some(forward_index) = &forward_index {
+            hit.resolve_offsets(forward_index);
+            let offsets: Vec<String> = hit
+                .offsets
+                .unwrap_or_default()
+                .iter()
+                .map(|bytes| format!("{}..{}", bytes.start, bytes.end))
+                .collect();
+            println!("    bytes {}", offsets.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Which results of `total` a limited or offset page shows
+fn print_page_bounds(page: &ResultPage, total: usize) {
+    if let Some(bounds) = page.describe(total) {
+        println!("{}", bounds);
+    }
+}
+
+fn sorted_documents(documents: std::collections::HashSet<String>) -> Vec<String> {
+    let mut documents: Vec<String> = documents.into_iter().collect();
+    documents.sort();
+    documents
+}
+
+fn handle_suggest_queries_command(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = matches.get_one::<String>("text").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let limit: usize = matches.get_one::<String>("limit").unwrap().parse()?;
+
+    let suggester = QuerySuggester::from_log(&format!("{}_queries.log", dict_prefix))?;
+    println!("Query log: {} successful queries", suggester.len());
+
+    println!("\n=== COMPLETIONS ===");
+    for (query, stats) in suggester.complete(text, limit) {
+        println!(
+            "{} ({} runs, {:.0}% with results)",
+            query,
+            stats.runs,
+            stats.success_rate() * 100.0
+        );
+    }
+
+    // Complete the word being typed from the dictionary as well, if term blocks were built
+    let last_word = text.split_whitespace().last().filter(|_| !text.ends_with(' '));
+    if let (Some(word), Ok(blocks)) = (
+        last_word,
+        TermBlockFile::open(&format!("{}_terms.bin", dict_prefix)),
+    ) {
+        println!("\n=== TERM COMPLETIONS ===");
+        for term in blocks.expand_prefix(&word.to_lowercase(), limit)? {
+            println!("{}", term);
+        }
+    }
+
+    println!("\n=== RELATED QUERIES ===");
+    for (query, stats) in suggester.related(text, limit) {
+        println!(
+            "{} ({} runs, {:.0}% with results)",
+            query,
+            stats.runs,
+            stats.success_rate() * 100.0
+        );
+    }
+
+    Ok(())
+}
+
+fn parse_date_arg(
+    matches: &clap::ArgMatches,
+    id: &str,
+    upper: bool,
+) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    match matches.get_one::<String>(id) {
+        Some(value) => parse_date_key(value, upper)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid --{} date: {}", id, value).into()),
+        None => Ok(None),
+    }
+}
+
+fn handle_terms_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let limit: usize = matches.get_one::<String>("limit").unwrap().parse()?;
+
+    let blocks = TermBlockFile::open(&format!("{}_terms.bin", dict_prefix))?;
+    let records = match matches.get_one::<String>("prefix") {
+        Some(prefix) => blocks.prefix(prefix)?,
+        None => blocks.range(
+            matches.get_one::<String>("from").unwrap(),
+            matches.get_one::<String>("to").map(String::as_str),
+        )?,
+    };
+
+    println!(
+        "{} of {} terms ({} blocks)",
+        records.len(),
+        blocks.term_count(),
+        blocks.block_count()
+    );
+    for record in records.iter().take(limit) {
+        println!(
+            "{}: {} (docs: {})",
+            record.term, record.frequency, record.document_count
+        );
+    }
+    if records.len() > limit {
+        println!("... {} more", records.len() - limit);
+    }
+
+    Ok(())
+}
+
+fn handle_dump_term_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let word = matches.get_one::<String>("term").unwrap();
+    let limit: usize = matches.get_one::<String>("limit").unwrap().parse()?;
+
+    let analyzer = IndexManifest::analyzer(dict_prefix)?;
+    let term = analyzer
+        .analyze(word)
+        .ok_or_else(|| format!("'{}' is a stopword of the analyzer ({})", word, analyzer))?;
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    let entry = dictionary
+        .get_term_entry(&term)
+        .ok_or_else(|| format!("Term '{}' not found", term))?;
+    let surface_forms = SurfaceForms::load_if_present(dict_dir, dict_name)?;
+
+    println!(
+        "Term: {} ({} occurrences in {} documents)",
+        term,
+        entry.frequency,
+        entry.documents.len()
+    );
+    match &surface_forms {
+        Some(surface_forms) => {
+            let forms = surface_forms.forms(&term);
+            let own = entry.frequency - forms.iter().map(|(_, count)| count).sum::<u32>();
+            println!(
+                "Surface forms (most often written {}):",
+                surface_forms.display_form(&term, entry.frequency)
+            );
+            if own > 0 {
+                println!("  {} ({})", term, own);
+            }
+            for (surface, count) in forms {
+                println!("  {} ({})", surface, count);
+            }
+        }
+        None => println!("No surface forms are saved, rebuild to record them"),
+    }
+
+    let mut documents: Vec<(&str, u32)> = entry
+        .documents
+        .iter()
+        .map(|id| (dictionary.document_name(id), entry.term_frequency(id)))
         .collect();
+    documents.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("Posting list, most occurrences first:");
+    for (document, count) in documents.iter().take(limit) {
+        println!("  - {} ({})", document, count);
+    }
+    if documents.len() > limit {
+        println!("  ... {} more", documents.len() - limit);
+    }
+    Ok(())
+}
 
-    println!("Collecting FB2 files from: {}", input_dir);
-    let files = collect_fb2_files(input_dir);
+fn handle_discover_stopwords_command(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let min_share: f64 = matches.get_one::<String>("top-df").unwrap().parse()?;
+    if !(min_share > 0.0 && min_share <= 1.0) {
+        return Err("--top-df must be a share of documents above 0 and at most 1".into());
+    }
 
-    if files.is_empty() {
-        eprintln!("No FB2 files found in {}", input_dir);
-        return Ok(());
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    let mut candidates = discover_stopwords(&dictionary, min_share);
+    if let Some(limit) = matches.get_one::<String>("limit") {
+        candidates.truncate(limit.parse()?);
     }
 
-    if files.len() < 10 {
-        eprintln!(
-            "Warning: Found only {} FB2 files (requirement: at least 10)",
-            files.len()
+    println!(
+        "{} terms are held by at least {:.0}% of {} documents:",
+        candidates.len(),
+        min_share * 100.0,
+        dictionary.documents.len()
+    );
+    for candidate in &candidates {
+        println!(
+            "  {} ({} documents, {:.0}%, {} occurrences)",
+            candidate.term,
+            candidate.document_frequency,
+            candidate.document_share * 100.0,
+            candidate.frequency
         );
     }
 
-    println!("Found {} FB2 files", files.len());
-    for file in &files[..std::cmp::min(5, files.len())] {
-        println!("  - {}", file.display());
+    if let Some(output) = matches.get_one::<String>("output") {
+        let header = format!(
+            "Stopword candidates of {}: terms in at least {:.0}% of {} documents",
+            dict_prefix,
+            min_share * 100.0,
+            dictionary.documents.len()
+        );
+        write_stopword_file(output, &header, &candidates)?;
+        println!("Saved stopword candidates to: {}", output);
+        // The terms are already analyzed, so the list goes after the steps the index used
+        println!(
+            "Review the list, then rebuild adding stopwords={} to the analyzer ({})",
+            output,
+            IndexManifest::analyzer(dict_prefix)?
+        );
     }
-    if files.len() > 5 {
-        println!("  ... and {} more", files.len() - 5);
+
+    Ok(())
+}
+
+fn handle_hide_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let document = matches.get_one::<String>("document").unwrap();
+    let reason = matches.get_one::<String>("reason").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let hidden_path = format!("{}_hidden.bin", dict_prefix);
+
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    if !dictionary.documents.iter().any(|indexed| indexed == document) {
+        return Err(format!("{} is not in the index", document).into());
     }
 
-    println!("\nBuilding dictionary...");
-    let start_time = Instant::now();
-    let regular_dictionary = build_dictionary(&files, true)?;
-    let build_time = start_time.elapsed();
+    let mut hidden = HiddenDocuments::load_or_default(&hidden_path)?;
+    if hidden.hide(document, reason) {
+        println!("Hidden {} ({})", document, reason);
+    } else {
+        println!("{} was already hidden, reason updated to: {}", document, reason);
+    }
+    hidden.save(&hidden_path)?;
 
-    println!("Compressing dictionary...");
-    let compress_start = Instant::now();
-    let dictionary = grimoire::CompressedDictionary::from_dictionary(&regular_dictionary);
-    let compress_time = compress_start.elapsed();
-    println!("Dictionary compression completed in {:.2?}", compress_time);
+    Ok(())
+}
 
-    println!("\n=== COLLECTION STATISTICS ===");
-    println!(
-        "Collection size: {} bytes ({:.2} MB)",
-        dictionary.collection_size_bytes,
-        dictionary.collection_size_bytes as f64 / 1_048_576.0
-    );
-    println!("Total documents: {}", dictionary.total_documents);
-    println!("Total words: {}", dictionary.total_words);
-    println!(
-        "Dictionary size: {} unique terms",
-        dictionary.dictionary_size()
-    );
-    println!("Build time: {:.2?}", build_time);
+fn handle_undelete_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let document = matches.get_one::<String>("document").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let hidden_path = format!("{}_hidden.bin", dict_prefix);
 
-    println!("\n=== BUILDING SEARCH STRUCTURES ===");
+    let mut hidden = HiddenDocuments::load_or_default(&hidden_path)?;
+    if hidden.undelete(document) {
+        hidden.save(&hidden_path)?;
+        println!("Restored {}", document);
+    } else {
+        println!("{} is not hidden", document);
+    }
 
-    let incidence_start = Instant::now();
-    let incidence_matrix = IncidenceMatrix::from_dictionary(&dictionary);
-    let incidence_time = incidence_start.elapsed();
-    let incidence_size = incidence_matrix.memory_size();
+    Ok(())
+}
 
-    let inverted_start = Instant::now();
-    let inverted_index = CompressedInvertedIndex::from_compressed_dictionary(&dictionary);
-    let inverted_time = inverted_start.elapsed();
-    let inverted_size = inverted_index.memory_size();
+fn handle_delete_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    let indexed: HashSet<&str> = dictionary.documents.iter().map(String::as_str).collect();
 
-    println!("Building bigram index...");
-    println!("  Dictionary has {} unique terms", dictionary.sorted_terms.len());
-    let bigram_start = Instant::now();
-    let parser = FB2Parser::new();
-    let bigram_index = BigramIndex::from_dictionary_with_parser(&dictionary, |doc_name| {
-        println!("  Processing document for bigram index: {}", doc_name);
-        let file_path = std::path::Path::new(input_dir).join(doc_name);
-        let result = parser.parse_file(&file_path);
-        if let Ok(ref words) = result {
-            println!("    Parsed {} words from {}", words.len(), doc_name);
+    let mut tombstones = Tombstones::load_or_default(dict_prefix)?;
+    for document in matches.get_many::<String>("document").unwrap() {
+        if !indexed.contains(document.as_str()) {
+            eprintln!("{} is not in the index", document);
+        } else if tombstones.delete(document) {
+            println!("Deleted {}", document);
         } else {
-            println!("    Failed to parse {}", doc_name);
+            println!("{} was already deleted", document);
         }
-        result
-    })?;
-    let bigram_time = bigram_start.elapsed();
-    let bigram_size = bigram_index.memory_size();
+    }
+    tombstones.save(dict_prefix)?;
     println!(
-        "  Bigram index built with {} bigrams",
-        bigram_index.index.len()
+        "{} documents wait for 'compact' to remove them from the structures",
+        tombstones.len()
     );
 
-    println!("Building coordinate index...");
-    let coordinate_start = Instant::now();
-    let coordinate_index = CoordinateIndex::from_dictionary_with_parser(&dictionary, |doc_name| {
-        println!("  Processing document for coordinate index: {}", doc_name);
-        let file_path = std::path::Path::new(input_dir).join(doc_name);
-        let result = parser.parse_file(&file_path);
-        if let Ok(ref words) = result {
-            println!("    Parsed {} words from {}", words.len(), doc_name);
-        } else {
-            println!("    Failed to parse {}", doc_name);
-        }
-        result
-    })?;
-    let coordinate_time = coordinate_start.elapsed();
-    let coordinate_size = coordinate_index.memory_size();
-    println!(
-        "  Coordinate index built with {} terms",
-        coordinate_index.index.len()
-    );
+    Ok(())
+}
 
-    println!("Building wildcard search engine...");
-    let wildcard_start = Instant::now();
-    let wildcard_engine = WildcardSearchEngine::from_compressed_dictionary(dictionary.clone());
-    let wildcard_time = wildcard_start.elapsed();
-    let wildcard_stats = wildcard_engine.memory_size();
+fn handle_compact_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
 
+    let start_time = Instant::now();
+    let report = compact_index(dict_prefix)?;
+    if report.removed.is_empty() {
+        println!("No deleted documents; index unchanged at generation {}", report.generation);
+        return Ok(());
+    }
+    for document in &report.removed {
+        println!("  - {}", document);
+    }
     println!(
-        "Incidence Matrix: {} bytes, built in {:.2?}",
-        incidence_size, incidence_time
+        "Removed {} documents in {:.2?}, committed index generation {}",
+        report.removed.len(),
+        start_time.elapsed(),
+        report.generation
     );
+
+    Ok(())
+}
+
+fn handle_advisor_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let min_queries: u64 = matches.get_one::<String>("min-queries").unwrap().parse()?;
+
+    let usage = IndexUsage::from_log(dict_prefix)?;
+    let present = structure_files(dict_prefix);
+    println!("=== STRUCTURE USAGE ===");
     println!(
-        "Inverted Index: {} bytes, built in {:.2?}",
-        inverted_size, inverted_time
+        "Queries logged: {} ({} with phrases, {} with near/N, {} with wildcards)",
+        usage.queries, usage.phrase_queries, usage.near_queries, usage.wildcard_queries
     );
+    for kind in ALL_STRUCTURES {
+        let size = match present.iter().find(|(present, _)| *present == kind) {
+            Some((_, bytes)) => format!("{:.1} MB", *bytes as f64 / 1_048_576.0),
+            None => "missing".to_string(),
+        };
+        println!(
+            "  {:<12} {:>6} queries  {}",
+            kind.to_string(),
+            usage.uses(kind),
+            size
+        );
+    }
+
+    println!("\n=== ADVICE ===");
+    if usage.queries < min_queries {
+        println!(
+            "Too few queries to advise: {} of {} logged. Searches log structure usage in the \
+             TUI and with search --log-queries.",
+            usage.queries, min_queries
+        );
+        return Ok(());
+    }
+    let advice = usage.advise(&present, min_queries);
+    if advice.is_empty() {
+        println!("Every structure on disk is used and no query needed a missing one");
+    }
+    for advice in advice {
+        println!("  {}", advice);
+    }
+
+    Ok(())
+}
+
+fn handle_postings_compare_command(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let pairs: usize = matches.get_one::<String>("pairs").unwrap().parse()?;
+
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let index = CompressedInvertedIndex::load(dict_dir, dict_name)?;
+    // Pair the most common terms with terms spread over every frequency, the mix Boolean
+    // queries combine
+    let mut terms: Vec<(&str, usize)> = index
+        .compressed_index
+        .keys()
+        .map(|term| (term.as_str(), index.doc_ids_for_term(term).map_or(0, |ids| ids.len())))
+        .collect();
+    terms.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    let pairs = pairs.min(terms.len());
+    let step = (terms.len() / pairs.max(1)).max(1);
+    let term_pairs: Vec<(&str, &str)> = (0..pairs)
+        .map(|i| (terms[i].0, terms[(i * step) % terms.len()].0))
+        .collect();
+
     println!(
-        "Bigram Index: {} bytes, built in {:.2?}",
-        bigram_size, bigram_time
+        "=== POSTING CODECS ({} term pairs, {} documents, index saved as {}) ===",
+        term_pairs.len(),
+        index.doc_id_to_name.len(),
+        index.encoding
     );
-    println!(
-        "Coordinate Index: {} bytes, built in {:.2?}",
-        coordinate_size, coordinate_time
+    println!("{:<10} {:>12} {:>12} {:>12} {:>12}", "Codec", "Bytes", "AND", "OR", "NOT");
+    for report in index.compare_encodings(&term_pairs) {
+        println!(
+            "{:<10} {:>12} {:>12} {:>12} {:>12}",
+            report.encoding.to_string(),
+            report.size_bytes,
+            format!("{:.2?}", report.and_time),
+            format!("{:.2?}", report.or_time),
+            format!("{:.2?}", report.not_time)
+        );
+    }
+
+    Ok(())
+}
+
+fn handle_multi_search_command(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    install_operator_aliases(matches)?;
+    let query = matches.get_one::<String>("query").unwrap();
+    let prefixes: Vec<&String> = matches.get_many::<String>("segment").unwrap().collect();
+    install_query_analyzer(&prefixes, matches.get_flag("raw-query"))?;
+    let ranking = match matches.get_one::<String>("rank").unwrap().as_str() {
+        "qld" => FederatedRanking::QueryLikelihood {
+            lambda: matches.get_one::<String>("lambda").unwrap().parse()?,
+        },
+        _ => FederatedRanking::TfIdf,
+    };
+    let page = ResultPage::new(
+        matches.get_one::<String>("offset").unwrap().parse()?,
+        matches
+            .get_one::<String>("limit")
+            .map(|limit| limit.parse())
+            .transpose()?,
     );
+
+    let searcher = MultiIndexSearcher::open(&prefixes);
+    let start = Instant::now();
+    let merge = matches.get_one::<String>("merge").unwrap();
+    if merge == "global" {
+        let hits = searcher.search(query, ranking)?;
+        println!(
+            "Found {} documents in {} segments in {:.2?}",
+            hits.len(),
+            prefixes.len(),
+            start.elapsed()
+        );
+        print_page_bounds(&page, hits.len());
+        for hit in page.apply(hits.into_iter()) {
+            println!(
+                "  - {} ({:.4}) [{}]",
+                hit.document, hit.score, prefixes[hit.segment]
+            );
+        }
+        return Ok(());
+    }
+
+    let per_segment = searcher.search_separately(query, ranking)?;
+    let mut found_in: HashMap<String, Vec<&str>> = HashMap::new();
+    for hit in per_segment.iter().flatten() {
+        found_in
+            .entry(hit.document.clone())
+            .or_default()
+            .push(prefixes[hit.segment]);
+    }
+    let lists: Vec<Vec<(String, f64)>> = per_segment
+        .into_iter()
+        .map(|hits| hits.into_iter().map(|hit| (hit.document, hit.score)).collect())
+        .collect();
+    let merged = if merge == "rrf" {
+        let k: f64 = matches.get_one::<String>("rrf-k").unwrap().parse()?;
+        println!("Merged by reciprocal rank fusion (k {})", k);
+        reciprocal_rank_fusion(&lists, k)
+    } else {
+        let normalize = matches.get_one::<String>("normalize").unwrap();
+        let normalization: ScoreNormalization = normalize.parse()?;
+        println!("Merged by summing {} normalized scores", normalize);
+        combine_normalized(&lists, normalization)
+    };
     println!(
-        "Wildcard Engine: {} bytes, built in {:.2?}",
-        wildcard_stats.total_size, wildcard_time
+        "Found {} documents in {} segments in {:.2?}",
+        merged.len(),
+        prefixes.len(),
+        start.elapsed()
     );
+    print_page_bounds(&page, merged.len());
+    for (document, score) in page.apply(merged.into_iter()) {
+        println!(
+            "  - {} ({:.4}) [{}]",
+            document,
+            score,
+            found_in[&document].join(", ")
+        );
+    }
+    Ok(())
+}
 
-    let matrix_path = format!("{}_matrix.bin", output_prefix);
-    let index_path = format!("{}_index.bin", output_prefix);
-    let bigram_path = format!("{}_bigram.bin", output_prefix);
-    let coordinate_path = format!("{}_coordinate.bin", output_prefix);
+fn handle_run_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    install_operator_aliases(matches)?;
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    install_query_analyzer(&[dict_prefix], matches.get_flag("raw-query"))?;
+    let queries_path = matches.get_one::<String>("queries").unwrap();
+    let output_path = matches.get_one::<String>("output").unwrap();
+    let top_k: usize = matches.get_one::<String>("top-k").unwrap().parse()?;
+    let ranking = match matches.get_one::<String>("rank").unwrap().as_str() {
+        "qld" => FederatedRanking::QueryLikelihood {
+            lambda: matches.get_one::<String>("lambda").unwrap().parse()?,
+        },
+        _ => FederatedRanking::TfIdf,
+    };
 
-    let matrix_data = bincode::serialize(&incidence_matrix)?;
-    fs::write(&matrix_path, matrix_data)?;
+    let topics = parse_topics(&fs::read_to_string(queries_path)?)
+        .map_err(|e| format!("{}: {}", queries_path, e))?;
+    let hidden = if matches.get_flag("include-hidden") {
+        HiddenDocuments::new()
+    } else {
+        HiddenDocuments::load_or_default(&format!("{}_hidden.bin", dict_prefix))?
+    };
+    let file = std::io::BufWriter::new(fs::File::create(output_path)?);
+    let mut run = TrecRun::new(file, matches.get_one::<String>("run-tag").unwrap())?;
 
-    let index_data = bincode::serialize(&inverted_index)?;
-    fs::write(&index_path, index_data)?;
+    // Tombstoned documents are already left out by the segment's searchers
+    let searcher = MultiIndexSearcher::open(&[dict_prefix]);
+    let start = Instant::now();
+    let mut failed = 0;
+    for topic in &topics {
+        let hits = match searcher.search(&topic.query, ranking) {
+            Ok(hits) => hits,
+            Err(e) => {
+                eprintln!("Warning: topic {} skipped: {}", topic.id, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let ranked: Vec<(String, f64)> = hits
+            .into_iter()
+            .filter(|hit| !hidden.is_hidden(&hit.document))
+            .take(top_k)
+            .map(|hit| (hit.document, hit.score))
+            .collect();
+        run.write_topic(&topic.id, &ranked)?;
+    }
+    let lines = run.lines();
+    run.finish()?;
 
-    let bigram_data = bincode::serialize(&bigram_index)?;
-    fs::write(&bigram_path, bigram_data)?;
+    println!(
+        "Ran {} of {} topics in {:.2?}, {} results written to {}",
+        topics.len() - failed,
+        topics.len(),
+        start.elapsed(),
+        lines,
+        output_path
+    );
+    Ok(())
+}
 
-    let coordinate_data = bincode::serialize(&coordinate_index)?;
-    fs::write(&coordinate_path, coordinate_data)?;
+fn handle_locate_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let document = matches.get_one::<String>("document").unwrap();
+    let term = matches.get_one::<String>("term").unwrap().to_lowercase();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
 
-    let wildcard_path = format!("{}_wildcard.bin", output_prefix);
-    let wildcard_data = bincode::serialize(&wildcard_engine)?;
-    fs::write(&wildcard_path, wildcard_data)?;
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let forward_index = ForwardIndex::load(dict_dir, dict_name)?;
 
-    println!("Saved incidence matrix to: {}", matrix_path);
-    println!("Saved inverted index to: {}", index_path);
-    println!("Saved bigram index to: {}", bigram_path);
-    println!("Saved coordinate index to: {}", coordinate_path);
-    println!("Saved wildcard engine to: {}", wildcard_path);
+    if !forward_index.documents.contains_key(document) {
+        return Err(format!("Document '{}' is not in the forward index", document).into());
+    }
 
-    println!("\n=== STRUCTURE COMPARISON ===");
-    println!("Incidence Matrix:      {} bytes", incidence_size);
-    println!("Inverted Index:        {} bytes", inverted_size);
-    println!("Bigram Index:          {} bytes", bigram_size);
-    println!("Coordinate Index:      {} bytes", coordinate_size);
-    println!("Wildcard Engine:       {} bytes", wildcard_stats.total_size);
-    println!(
-        "  - Suffix Tree:       {} bytes",
-        wildcard_stats.suffix_tree_size
-    );
-    println!(
-        "  - Permutation Index: {} bytes",
-        wildcard_stats.permutation_index_size
-    );
-    println!(
-        "  - Trigram Index:     {} bytes",
-        wildcard_stats.trigram_index_size
-    );
+    let source = match matches.get_one::<String>("input") {
+        Some(input_dir) => Some(fs::read(std::path::Path::new(input_dir).join(document))?),
+        None => None,
+    };
 
-    let efficiency_ratio = inverted_size as f64 / incidence_size as f64;
-    println!("Space efficiency (Index/Matrix): {:.2}x", efficiency_ratio);
-    let coordinate_ratio = coordinate_size as f64 / inverted_size as f64;
+    let occurrences = forward_index.locate(document, &term);
     println!(
-        "Space overhead (Coordinate/Inverted): {:.2}x",
-        coordinate_ratio
+        "'{}' occurs {} times in {}",
+        term,
+        occurrences.len(),
+        document
     );
+    for (position, span) in occurrences {
+        print!("  position {:>7}  bytes {}..{}", position, span.start, span.end);
+        match source {
+            Some(ref bytes) => println!(
+                "  {}",
+                context_snippet(bytes, span.start as usize, span.end as usize)
+            ),
+            None => println!(),
+        }
+    }
 
-    println!("\n=== DATA STRUCTURE ANALYSIS ===");
-    println!("Incidence Matrix:");
-    println!("  - Space: O(|T| × |D|) where T=terms, D=documents");
-    println!("  - Search: O(|T|) for term lookup + O(|D|) for Boolean operations");
+    Ok(())
+}
+
+/// Source text around a byte range with the range itself bracketed
+fn context_snippet(bytes: &[u8], start: usize, end: usize) -> String {
+    const CONTEXT: usize = 40;
+    let before = String::from_utf8_lossy(&bytes[start.saturating_sub(CONTEXT)..start]);
+    let matched = String::from_utf8_lossy(&bytes[start..end]);
+    let after = String::from_utf8_lossy(&bytes[end..(end + CONTEXT).min(bytes.len())]);
+
+    // Lossy decoding of a cut multi-byte character leaves a replacement char at the edges
+    format!(
+        "...{}[{}]{}...",
+        before.trim_start_matches('\u{FFFD}').replace('\n', " "),
+        matched,
+        after.trim_end_matches('\u{FFFD}').replace('\n', " ")
+    )
+}
+
+const HNSW_UNAVAILABLE: &str = "--hnsw needs the ann feature: rebuild grimoire with --features ann";
+
+#[cfg(feature = "ann")]
+fn save_hnsw_index(
+    coordinate_index: &CoordinateIndex,
+    output_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (output_dir, output_name) = split_prefix(output_prefix);
+    let start = Instant::now();
+    let hnsw = grimoire::HnswIndex::from_coordinate_index(coordinate_index, Default::default());
+    let hnsw_size = hnsw.save(output_dir, output_name)?;
     println!(
-        "  - Memory: {} bits per term-document pair",
-        incidence_matrix.matrix.len() * incidence_matrix.documents.len()
+        "Saved HNSW index to: {}{}.bin ({} documents, {} layers, {} bytes, built in {:.2?})",
+        output_prefix,
+        grimoire::HnswIndex::SUFFIX,
+        hnsw.len(),
+        hnsw.layers(),
+        hnsw_size,
+        start.elapsed()
     );
+    Ok(())
+}
 
-    println!("Inverted Index:");
-    println!("  - Space: O(|unique_postings|) - only stores actual occurrences");
-    println!("  - Search: O(1) for term lookup + O(|posting_lists|) for Boolean operations");
-    println!("  - Memory: Variable size based on term distribution");
+#[cfg(not(feature = "ann"))]
+fn save_hnsw_index(
+    _coordinate_index: &CoordinateIndex,
+    _output_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err(HNSW_UNAVAILABLE.into())
+}
 
-    println!("Bigram Index:");
-    println!("  - Space: O(|unique_bigrams|) - stores two-word combinations");
-    println!("  - Search: Optimized for phrase search with exact word order");
-    println!("  - Memory: {} bigrams indexed", bigram_index.index.len());
+/// Documents and their cosine similarity to another, the closest first
+type Neighbors = Vec<(String, f64)>;
 
-    println!("Coordinate Index:");
-    println!("  - Space: O(|postings| × |positions|) - stores position information");
-    println!("  - Search: Supports phrase and proximity search with position verification");
-    println!("  - Memory: Includes position data for each term occurrence");
+/// Neighbors of `document` from the prefix's HNSW graph; `None` when there is no graph or the
+/// document is not in it
+#[cfg(feature = "ann")]
+fn nearest_neighbors(
+    dict_prefix: &str,
+    document: &str,
+    k: usize,
+) -> Result<Option<Neighbors>, Box<dyn std::error::Error>> {
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    Ok(grimoire::HnswIndex::load_if_present(dict_dir, dict_name)?
+        .and_then(|hnsw| hnsw.similar_to(document, k)))
+}
 
-    println!("\n=== SAVING DICTIONARY ===");
-    let mut format_sizes = Vec::new();
+#[cfg(not(feature = "ann"))]
+fn nearest_neighbors(
+    _dict_prefix: &str,
+    _document: &str,
+    _k: usize,
+) -> Result<Option<Neighbors>, Box<dyn std::error::Error>> {
+    Ok(None)
+}
 
-    for format in &formats {
-        let start_time = Instant::now();
-        let (file_path, size) = match format.trim() {
-            "binary" => {
-                let path = format!("{}.bin", output_prefix);
-                let size = dictionary.save_as_binary(&path)?;
-                (path, size)
-            }
-            "json" => {
-                let path = format!("{}.json", output_prefix);
-                let size = dictionary.save_as_json(&path)?;
-                (path, size)
-            }
-            "text" => {
-                let path = format!("{}.txt", output_prefix);
-                let size = dictionary.save_as_text(&path)?;
-                (path, size)
-            }
-            _ => {
-                eprintln!("Unknown format: {}", format);
-                continue;
-            }
-        };
-        let save_time = start_time.elapsed();
-        format_sizes.push((format.to_string(), size, save_time));
-        println!(
-            "Saved {} format: {} ({} bytes, {:.2?})",
-            format, file_path, size, save_time
-        );
-    }
+fn handle_similar_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let document = matches.get_one::<String>("document").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let top_k: usize = matches.get_one::<String>("top").unwrap().parse()?;
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
 
-    println!("\n=== FORMAT COMPARISON ===");
-    format_sizes.sort_by_key(|(_, size, _)| *size);
-    for (format, size, time) in &format_sizes {
-        println!("{:>6}: {:>10} bytes ({:>6.2?})", format, size, time);
-    }
+    let hidden = HiddenDocuments::load_or_default(&format!("{}_hidden.bin", dict_prefix))?;
+    let tombstones = Tombstones::load_or_default(dict_prefix)?;
+    let excluded = hidden.documents.len() + tombstones.len();
 
-    if let Some((smallest_format, smallest_size, _)) = format_sizes.first() {
-        if let Some((largest_format, largest_size, _)) = format_sizes.last() {
-            let ratio = *largest_size as f64 / *smallest_size as f64;
-            println!(
-                "Size ratio ({}:{}): {:.2}x",
-                largest_format, smallest_format, ratio
-            );
+    // Excluded documents may come back as neighbors, so ask for enough to drop them all
+    let start = Instant::now();
+    let approximate = if matches.get_flag("exact") {
+        None
+    } else {
+        nearest_neighbors(dict_prefix, document, top_k + excluded)?
+    };
+    let (method, neighbors) = match approximate {
+        Some(neighbors) => ("HNSW", neighbors),
+        None => {
+            let coordinate_index = CoordinateIndex::load(dict_dir, dict_name)?;
+            if !coordinate_index.documents.contains(document) {
+                return Err(format!("Document '{}' is not in the index", document).into());
+            }
+            let documents: Vec<&str> =
+                coordinate_index.documents.iter().map(String::as_str).collect();
+            let vectors = document_vectors(&coordinate_index, &documents);
+            let query = &vectors[document];
+            let mut neighbors: Neighbors = vectors
+                .iter()
+                .filter(|(other, _)| *other != document)
+                .map(|(other, vector)| (other.clone(), cosine_similarity(query, vector)))
+                .collect();
+            neighbors.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            ("exact", neighbors)
         }
-    }
+    };
 
+    let store = DocumentStore::load_if_present(dict_dir, dict_name)?;
+    println!(
+        "Documents similar to {} ({} search in {:.2?}):",
+        describe_document(store.as_ref(), document),
+        method,
+        start.elapsed()
+    );
+    for (neighbor, similarity) in neighbors
+        .iter()
+        .filter(|(neighbor, _)| !hidden.is_hidden(neighbor) && !tombstones.is_deleted(neighbor))
+        .take(top_k)
+    {
+        println!("  - {} ({:.4})", describe_document(store.as_ref(), neighbor), similarity);
+    }
     Ok(())
 }
 
-fn handle_search_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let query = matches.get_one::<String>("query").unwrap();
+fn handle_inspect_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
     let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
 
-    let matrix_path = format!("{}_matrix.bin", dict_prefix);
-    let index_path = format!("{}_index.bin", dict_prefix);
-    let bigram_path = format!("{}_bigram.bin", dict_prefix);
-    let coordinate_path = format!("{}_coordinate.bin", dict_prefix);
-    let wildcard_path = format!("{}_wildcard.bin", dict_prefix);
+    println!("=== SAVED STRUCTURES ===");
+    for suffix in [
+        "",
+        "_matrix",
+        "_index",
+        "_bigram",
+        "_coordinate",
+        "_wildcard",
+        "_permuterm",
+        "_terms",
+        "_forward",
+        "_interner",
+        "_partitions",
+        "_norms",
+        "_fields",
+        "_surface",
+        "_store",
+        "_champions",
+        "_hnsw",
+        "_hidden",
+        "_deleted",
+        "_manifest",
+        "_cache",
+    ] {
+        let path = format!("{}{}.bin", dict_prefix, suffix);
+        match fs::metadata(&path) {
+            Ok(metadata) => println!("{:<40} {} bytes", path, metadata.len()),
+            Err(_) => println!("{:<40} missing", path),
+        }
+    }
+    if let Ok(permuterm) = PartitionedPermutationIndex::open(dict_prefix) {
+        println!("Permuterm partitions: {}", permuterm.partition_count());
+    }
 
-    println!("Loading search structures...");
+    if let Some(top) = matches.get_one::<String>("top") {
+        let top: usize = top.parse()?;
+        let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
 
-    let matrix_data = fs::read(&matrix_path)?;
-    let incidence_matrix: IncidenceMatrix = bincode::deserialize(&matrix_data)?;
+        println!("\n=== TOP {} TERMS ===", top);
+        for (term, entry) in dictionary.top_by_frequency(top) {
+            println!(
+                "{:<30} {} occurrences in {} documents",
+                term,
+                entry.frequency,
+                entry.documents.len()
+            );
+        }
+    }
 
-    let index_data = fs::read(&index_path)?;
-    let inverted_index: CompressedInvertedIndex = bincode::deserialize(&index_data)?;
+    if let Some(manifest) = IndexManifest::load(dict_prefix)? {
+        println!(
+            "\nGeneration {} (committed at unix time {})",
+            manifest.generation, manifest.committed_at
+        );
+    }
 
-    let bigram_data = fs::read(&bigram_path)?;
-    let bigram_index: BigramIndex = bincode::deserialize(&bigram_data)?;
+    if let Some(norms) = DocumentNorms::load_if_present(dict_dir, dict_name)? {
+        println!("\n=== DOCUMENT NORMS ===");
+        println!(
+            "{} documents, average length {:.1} words, average title {:.1} words",
+            norms.len(),
+            norms.average_length(),
+            norms.average_title_length()
+        );
+        for (language, count) in norms.languages() {
+            println!("  {}: {} documents", language, count);
+        }
+    }
 
-    let coordinate_data = fs::read(&coordinate_path)?;
-    let coordinate_index: CoordinateIndex = bincode::deserialize(&coordinate_data)?;
+    let hidden = HiddenDocuments::load_or_default(&format!("{}_hidden.bin", dict_prefix))?;
+    if !hidden.is_empty() {
+        println!("\n=== HIDDEN DOCUMENTS ===");
+        for (document, reason) in &hidden.documents {
+            println!("{} ({})", document, reason);
+        }
+    }
 
-    let wildcard_data = fs::read(&wildcard_path)?;
-    let wildcard_engine: WildcardSearchEngine = bincode::deserialize(&wildcard_data)?;
+    let tombstones = Tombstones::load_or_default(dict_prefix)?;
+    if !tombstones.is_empty() {
+        println!("\n=== DELETED DOCUMENTS (awaiting compaction) ===");
+        for document in &tombstones.documents {
+            println!("{}", document);
+        }
+    }
 
-    println!("Query: {}", query);
-    println!("\n=== INCIDENCE MATRIX SEARCH ===");
-    let matrix_start = Instant::now();
-    match incidence_matrix.search(query) {
-        Ok(result) => {
-            let matrix_time = matrix_start.elapsed();
-            let matching_docs = incidence_matrix.get_matching_documents(&result);
+    let merges = CompactionLog::load(dict_prefix)?;
+    if !merges.is_empty() {
+        println!("\n=== SEGMENT MERGES ===");
+        for merge in &merges {
             println!(
-                "Found {} documents in {:.2?}",
-                matching_docs.len(),
-                matrix_time
+                "{}: {} segments ({} bytes), {} documents, wrote {} bytes in {:.2?}, write amplification {:.2}",
+                merge.output,
+                merge.inputs.len(),
+                merge.input_bytes(),
+                merge.documents,
+                merge.output_bytes,
+                merge.elapsed,
+                merge.write_amplification
             );
-            for doc in &matching_docs {
-                println!("  - {}", doc);
-            }
         }
-        Err(e) => println!("Error: {}", e),
     }
 
-    println!("\n=== INVERTED INDEX SEARCH ===");
-    let index_start = Instant::now();
-    match inverted_index.search(query) {
-        Ok(result) => {
-            let index_time = index_start.elapsed();
-            let mut docs: Vec<_> = result.iter().collect();
-            docs.sort();
-            println!("Found {} documents in {:.2?}", docs.len(), index_time);
-            for doc in &docs {
-                println!("  - {}", doc);
-            }
-        }
-        Err(e) => println!("Error: {}", e),
+    let Some(partitions) = TemporalPartitions::load_if_present(dict_dir, dict_name)? else {
+        return Ok(());
+    };
+
+    println!(
+        "\n=== TEMPORAL PARTITIONS ({} years each) ===",
+        partitions.span_years
+    );
+    for partition in &partitions.partitions {
+        let (first, last) = partition.date_range().unwrap_or((0, 0));
+        println!(
+            "{}-{}: {} documents, {} words, dated {}..{}",
+            partition.start_year,
+            partition.end_year,
+            partition.documents.len(),
+            partition.total_words,
+            format_date_key(first),
+            format_date_key(last)
+        );
     }
+    println!("Undated: {} documents", partitions.undated.len());
 
-    if query.contains('"') {
-        println!("\n=== BIGRAM INDEX PHRASE SEARCH ===");
-        let bigram_start = Instant::now();
-        match bigram_index.search(query) {
-            Ok(result) => {
-                let bigram_time = bigram_start.elapsed();
-                let mut docs: Vec<_> = result.iter().collect();
-                docs.sort();
-                println!("Found {} documents in {:.2?}", docs.len(), bigram_time);
-                for doc in &docs {
-                    println!("  - {}", doc);
-                }
+    Ok(())
+}
+
+fn format_date_key(key: u32) -> String {
+    format!("{:04}-{:02}-{:02}", key / 10_000, key / 100 % 100, key % 100)
+}
+
+fn print_summary(dictionary: &CompressedDictionary, file_path: &std::path::Path, sentences: usize) {
+    match FB2Parser::new().parse_sentences(file_path) {
+        Ok(all_sentences) => {
+            for sentence in Summarizer::new(dictionary).summarize(&all_sentences, sentences) {
+                println!("      > {}", sentence);
             }
-            Err(e) => println!("Error: {}", e),
         }
+        Err(e) => println!("      (no summary: {})", e),
     }
+}
 
-    println!("\n=== COORDINATE INDEX SEARCH ===");
-    let coordinate_start = Instant::now();
-    match coordinate_index.search(query) {
-        Ok(result) => {
-            let coordinate_time = coordinate_start.elapsed();
-            let mut docs: Vec<_> = result.iter().collect();
-            docs.sort();
-            println!("Found {} documents in {:.2?}", docs.len(), coordinate_time);
-            for doc in &docs {
-                println!("  - {}", doc);
-            }
+fn handle_charset_report_command(
+    matches: &clap::ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir = matches.get_one::<String>("input").unwrap();
+    let min_terms_per_kb: f64 = matches.get_one::<String>("min-terms-per-kb").unwrap().parse()?;
+    let mut files = collect_fb2_files(input_dir);
+    if files.is_empty() {
+        return Err(CorpusError::EmptyCorpus {
+            source: input_dir.to_string(),
+            reason: EmptyReason::NoDocuments,
         }
-        Err(e) => println!("Error: {}", e),
+        .into());
     }
+    files.sort();
+
+    let parser = FB2Parser::new();
+    let profiles = files
+        .par_iter()
+        .map(|file| {
+            let raw = read_source(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+            let name = file.strip_prefix(input_dir).unwrap_or(file);
+            // A file the XML reader stops on early yields only the terms before the error
+            let terms = parser.parse_file(file).map_or(0, |words| words.len());
+            Ok(CharsetProfile::measure(&name.to_string_lossy(), &raw, terms))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
 
-    if query.contains("near/") {
-        println!("\n=== PROXIMITY SEARCH EXAMPLE ===");
-        println!("Example proximity searches:");
-        println!("  near/5(word1 word2) - finds 'word1' and 'word2' within 5 positions");
-        println!("  near/10(love peace) - finds 'love' and 'peace' within 10 positions");
+    let mut scripts: BTreeMap<Script, usize> = BTreeMap::new();
+    let mut flagged = 0;
+    for profile in &profiles {
+        *scripts.entry(profile.script()).or_insert(0) += 1;
+        let problems = profile.problems(min_terms_per_kb);
+        if !problems.is_empty() {
+            flagged += 1;
+        } else if matches.get_flag("flagged") {
+            continue;
+        }
+        println!(
+            "{:<40} {:<8} {:>10} bytes  {:>5.1}% non-text  {:>6.1} terms/KB",
+            profile.document,
+            profile.script().to_string(),
+            profile.bytes,
+            profile.non_text_ratio() * 100.0,
+            profile.terms_per_kb()
+        );
+        for problem in problems {
+            println!("    ! {}", problem);
+        }
     }
 
-    if query.contains('*') || query.contains('?') {
-        println!("\n=== WILDCARD SEARCH ===");
-        let wildcard_result = wildcard_engine.search_with_stats(query);
+    let scripts: Vec<String> = scripts
+        .iter()
+        .map(|(script, count)| format!("{} {}", count, script))
+        .collect();
+    println!(
+        "\n{} documents: {}; {} flagged",
+        profiles.len(),
+        scripts.join(", "),
+        flagged
+    );
+    Ok(())
+}
 
-        println!("Strategy: {}", wildcard_result.strategy);
-        println!("Search time: {:.2?}", wildcard_result.search_time);
+fn handle_summarize_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let file_path = matches.get_one::<String>("file").unwrap();
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let sentences: usize = matches.get_one::<String>("sentences").unwrap().parse()?;
 
-        if let Some(error) = wildcard_result.error {
-            println!("Error: {}", error);
-        } else {
-            let mut docs: Vec<_> = wildcard_result.documents.iter().collect();
-            docs.sort();
-            println!("Found {} documents", docs.len());
-            for doc in &docs {
-                println!("  - {}", doc);
-            }
-        }
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
 
-        println!("\n=== WILDCARD SEARCH EXAMPLES ===");
-        println!("Prefix wildcard: cat* - finds 'cat', 'cats', 'category', etc.");
-        println!("Suffix wildcard: *ing - finds 'running', 'testing', 'building', etc.");
-        println!("Middle wildcard: c*t - finds 'cat', 'cart', 'coat', etc.");
-        println!("Multiple wildcards: *test* - finds any word containing 'test'");
-        println!("Single character: c?t - finds 'cat', 'cut', 'cot', etc.");
-    }
+    println!("Summary of {}:", file_path);
+    print_summary(&dictionary, std::path::Path::new(file_path), sentences);
 
     Ok(())
 }
@@ -528,45 +4049,118 @@ fn handle_parquet_inspect_command(matches: &clap::ArgMatches) -> Result<(), Box<
     let input_file = matches.get_one::<String>("input").unwrap();
 
     println!("Inspecting Parquet file: {}", input_file);
-    let loader = ParquetLoader::new(input_file);
+    let loader = ParquetLoader::new(input_file).with_columns(column_mapping(matches));
     loader.inspect_schema()?;
 
+    let columns = loader.resolved_columns()?;
+    println!("\nDocuments are read from:");
+    println!("  Text: {}", columns.text.unwrap_or_default());
+    println!("  Id: {}", columns.id.as_deref().unwrap_or("none, rows are numbered"));
+    println!(
+        "  Metadata: {}",
+        if columns.metadata.is_empty() {
+            "none".to_string()
+        } else {
+            columns.metadata.join(", ")
+        }
+    );
+
     Ok(())
 }
 
+/// Columns named with --text-col, --id-col and --meta-cols; the rest are guessed
+fn document_sample(matches: &clap::ArgMatches) -> Result<DocumentSample, Box<dyn std::error::Error>> {
+    let max_docs = matches.get_one::<String>("max-docs").map(|n| n.parse()).transpose()?;
+    let ratio = matches.get_one::<String>("sample-ratio").map(|p| p.parse()).transpose()?;
+    Ok(DocumentSample::new(max_docs, ratio)?)
+}
+
+fn column_mapping(matches: &clap::ArgMatches) -> ColumnMapping {
+    let mut columns = ColumnMapping::new();
+    if let Some(text) = matches.get_one::<String>("text-col") {
+        columns = columns.with_text(text);
+    }
+    if let Some(id) = matches.get_one::<String>("id-col") {
+        columns = columns.with_id(id);
+    }
+    if let Some(metadata) = matches.get_one::<String>("meta-cols") {
+        let metadata: Vec<&str> = metadata
+            .split(',')
+            .map(str::trim)
+            .filter(|column| !column.is_empty())
+            .collect();
+        columns = columns.with_metadata(&metadata);
+    }
+    columns
+}
+
 fn handle_parquet_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    install_tuning(matches)?;
     let input_file = matches.get_one::<String>("input").unwrap();
     let output_prefix = matches.get_one::<String>("output").unwrap();
+    let (output_dir, output_name) = split_prefix(output_prefix);
     let use_spimi = matches.get_flag("spimi");
     let memory_limit: usize = matches.get_one::<String>("memory-limit").unwrap().parse()?;
+    let min_documents: usize = matches.get_one::<String>("min-documents").unwrap().parse()?;
+    let postings: PostingEncoding = matches.get_one::<String>("postings-codec").unwrap().parse()?;
+    let champions: Option<usize> = matches
+        .get_one::<String>("champions")
+        .map(|size| size.parse())
+        .transpose()?;
 
     println!("Processing Parquet file: {}", input_file);
-    let loader = ParquetLoader::new(input_file);
+    let mut loader = ParquetLoader::new(input_file)
+        .with_columns(column_mapping(matches))
+        .with_partition_metadata(matches.get_flag("partition-metadata"))
+        .with_sample(document_sample(matches)?);
+    if let Some(threads) = matches.get_one::<String>("read-threads") {
+        loader = loader.with_read_threads(threads.parse()?);
+    }
 
     let start_time = Instant::now();
-    let documents = loader.load_documents()?;
+    let (documents, spimi_dictionary) = if use_spimi {
+        // Surface schema problems with their hint before any thread starts
+        loader.resolved_columns()?;
+        println!("Building dictionary using SPIMI indexing while reading (memory limit: {} MB)",
+                 memory_limit);
+        let indexer = ParallelSPIMIIndexer::new(memory_limit, "./spimi_temp", None)?;
+        let (sender, receiver) = crossbeam_channel::bounded(PARQUET_CHANNEL_CAPACITY);
+
+        let (sent, indexed) = std::thread::scope(|scope| {
+            let reader = scope.spawn(move || loader.send_documents(&sender).map_err(|e| e.to_string()));
+            let indexed = indexer.build_index_streaming(receiver, |doc: &ParquetDocument| {
+                (doc.id.as_str(), doc.text.as_str())
+            });
+            (reader.join().unwrap(), indexed)
+        });
+        sent?;
+        let (dictionary, mut documents) = indexed?;
+        documents.sort_by(|a, b| a.id.cmp(&b.id));
+        println!("SPIMI indexing completed in {:.2?}", start_time.elapsed());
+        (documents, Some(dictionary))
+    } else {
+        (loader.load_documents()?, None)
+    };
     let load_time = start_time.elapsed();
 
     println!("Loaded {} documents in {:.2?}", documents.len(), load_time);
+    let loaded_documents = documents.len();
 
-    let dictionary = if use_spimi {
-        println!("Building dictionary using SPIMI indexing (memory limit: {} MB)", memory_limit);
-        let build_start = Instant::now();
-
-        let doc_pairs: Vec<(String, String)> = documents
-            .into_iter()
-            .map(|doc| (doc.id, doc.text))
-            .collect();
-
-        let indexer = ParallelSPIMIIndexer::new(memory_limit, "./spimi_temp", None)?;
-        let regular_dictionary = indexer.build_index(doc_pairs, |processed, total| {
-            if processed % 10000 == 0 {
-                println!("SPIMI: Processed {}/{} documents", processed, total);
-            }
+    println!("Building forward index...");
+    let texts: HashMap<&str, &str> = documents
+        .iter()
+        .map(|doc| (doc.id.as_str(), doc.text.as_str()))
+        .collect();
+    let document_ids: Vec<String> = documents.iter().map(|doc| doc.id.clone()).collect();
+    let mut interner = TermInterner::load_or_default(output_dir, output_name)?;
+    let forward_index =
+        ForwardIndex::from_documents_with_interner(&document_ids, &mut interner, |id| {
+            Ok(tokenize_plain_text_with_offsets(texts[id]))
         })?;
+    drop(texts);
 
-        let build_time = build_start.elapsed();
-        println!("SPIMI indexing completed in {:.2?}", build_time);
+    let dictionary = if let Some(regular_dictionary) = spimi_dictionary {
+        validate_corpus(input_file, loaded_documents, &regular_dictionary, min_documents)?;
 
         println!("Compressing dictionary...");
         let compress_start = Instant::now();
@@ -586,15 +4180,9 @@ fn handle_parquet_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dy
                 println!("Processing document {}/{}", i, documents.len());
             }
 
-            let words: Vec<String> = doc.text
-                .split_whitespace()
-                .map(|word| {
-                    word.chars()
-                        .filter(|c| c.is_alphanumeric())
-                        .collect::<String>()
-                        .to_lowercase()
-                })
-                .filter(|word| !word.is_empty() && word.len() > 2)
+            let words: Vec<String> = tokenize_plain_text_with_offsets(&doc.text)
+                .into_iter()
+                .map(|(word, _, _)| word)
                 .collect();
 
             regular_dictionary.add_file_stats(doc.text.len() as u64);
@@ -606,6 +4194,7 @@ fn handle_parquet_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dy
 
         let build_time = build_start.elapsed();
         println!("Traditional indexing completed in {:.2?}", build_time);
+        validate_corpus(input_file, loaded_documents, &regular_dictionary, min_documents)?;
 
         println!("Compressing dictionary...");
         let compress_start = Instant::now();
@@ -615,6 +4204,7 @@ fn handle_parquet_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dy
 
         dictionary
     };
+    let dictionary = Arc::new(dictionary);
 
     println!("\n=== PARQUET COLLECTION STATISTICS ===");
     println!("Collection size: {} bytes ({:.2} MB)",
@@ -628,47 +4218,511 @@ fn handle_parquet_build_command(matches: &clap::ArgMatches) -> Result<(), Box<dy
     println!("\n=== BUILDING SEARCH STRUCTURES ===");
 
     let incidence_start = Instant::now();
-    let incidence_matrix = IncidenceMatrix::from_dictionary(&dictionary);
+    let incidence_matrix = IncidenceMatrix::from_dictionary(&*dictionary);
     let incidence_time = incidence_start.elapsed();
     let incidence_size = incidence_matrix.memory_size();
 
     let inverted_start = Instant::now();
-    let inverted_index = CompressedInvertedIndex::from_compressed_dictionary(&dictionary);
+    let inverted_index =
+        CompressedInvertedIndex::from_compressed_dictionary(&dictionary).with_encoding(postings);
     let inverted_time = inverted_start.elapsed();
     let inverted_size = inverted_index.memory_size();
 
     println!("Building wildcard search engine...");
     let wildcard_start = Instant::now();
-    let wildcard_engine = WildcardSearchEngine::from_compressed_dictionary(dictionary.clone());
+    let wildcard_engine = WildcardSearchEngine::from_compressed_dictionary(Arc::clone(&dictionary))
+        .with_postings_encoding(postings);
     let wildcard_time = wildcard_start.elapsed();
     let wildcard_stats = wildcard_engine.memory_size();
 
     println!("Incidence Matrix: {} bytes, built in {:.2?}", incidence_size, incidence_time);
-    println!("Inverted Index: {} bytes, built in {:.2?}", inverted_size, inverted_time);
+    println!(
+        "Inverted Index: {} bytes ({} postings), built in {:.2?}",
+        inverted_size, postings, inverted_time
+    );
     println!("Wildcard Engine: {} bytes, built in {:.2?}", wildcard_stats.total_size, wildcard_time);
 
     // Save indexes
-    let matrix_path = format!("{}_matrix.bin", output_prefix);
-    let index_path = format!("{}_index.bin", output_prefix);
-    let wildcard_path = format!("{}_wildcard.bin", output_prefix);
+    incidence_matrix.save(output_dir, output_name)?;
+    inverted_index.save(output_dir, output_name)?;
+    wildcard_engine.save(output_dir, output_name)?;
+    let terms_path = format!("{}_terms.bin", output_prefix);
+    let terms_size = TermBlockFile::write(&*dictionary, &terms_path)?;
+    let permuterm_size = wildcard_engine
+        .permutation_index()
+        .save_partitioned(output_prefix)?;
 
-    let matrix_data = bincode::serialize(&incidence_matrix)?;
-    fs::write(&matrix_path, matrix_data)?;
+    let saved_path = |suffix: &str| format!("{}{}.bin", output_prefix, suffix);
+    println!("Saved incidence matrix to: {}", saved_path(IncidenceMatrix::SUFFIX));
+    println!("Saved inverted index to: {}", saved_path(CompressedInvertedIndex::SUFFIX));
+    println!("Saved wildcard engine to: {}", saved_path(WildcardSearchEngine::SUFFIX));
+    println!("Saved term blocks to: {} ({} bytes)", terms_path, terms_size);
+    println!(
+        "Saved partitioned permuterm index to: {}_permuterm*.bin ({} bytes)",
+        output_prefix, permuterm_size
+    );
 
-    let index_data = bincode::serialize(&inverted_index)?;
-    fs::write(&index_path, index_data)?;
+    forward_index.save(output_dir, output_name)?;
+    println!("Saved forward index to: {}", saved_path(ForwardIndex::SUFFIX));
 
-    let wildcard_data = bincode::serialize(&wildcard_engine)?;
-    fs::write(&wildcard_path, wildcard_data)?;
+    // Parquet documents have no title or declared language
+    let norms = DocumentNorms::from_forward_index(&forward_index, |_| (0, None));
+    let norms_size = norms.save(output_dir, output_name)?;
+    println!(
+        "Saved document norms to: {} ({} bytes)",
+        saved_path(DocumentNorms::SUFFIX),
+        norms_size
+    );
+    let mut structures = vec![
+        "",
+        "_matrix",
+        "_index",
+        "_wildcard",
+        "_permuterm",
+        "_terms",
+        "_forward",
+        "_interner",
+        "_norms",
+    ];
+    if let Some(size) = champions {
+        let champion_lists = ChampionLists::from_index(&inverted_index, size);
+        let champions_size = champion_lists.save(output_dir, output_name)?;
+        println!(
+            "Saved champion lists to: {} ({} terms, {} bytes)",
+            saved_path(ChampionLists::SUFFIX),
+            champion_lists.lists.len(),
+            champions_size
+        );
+        structures.push("_champions");
+    }
 
-    println!("Saved incidence matrix to: {}", matrix_path);
-    println!("Saved inverted index to: {}", index_path);
-    println!("Saved wildcard engine to: {}", wildcard_path);
+    let new_terms = interner.intern_all(dictionary.sorted_terms.iter().map(String::as_str));
+    let interner_size = interner.save(output_dir, output_name)?;
+    println!(
+        "Saved term ids to: {} ({} new of {}, {} bytes)",
+        saved_path(TermInterner::SUFFIX),
+        new_terms,
+        interner.len(),
+        interner_size
+    );
 
     // Save dictionary
     let dict_path = format!("{}.bin", output_prefix);
     let dict_size = dictionary.save_as_binary(&dict_path)?;
     println!("Saved dictionary to: {} ({} bytes)", dict_path, dict_size);
 
+    // Documents from Parquet are only lowercased
+    let manifest = IndexManifest::commit_with_analyzer(output_prefix, &structures, Analyzer::default())?;
+    println!("Committed index generation {}", manifest.generation);
+
+    Ok(())
+}
+
+fn handle_cooccurrence_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let top_n: usize = matches.get_one::<String>("top").unwrap().parse()?;
+    let window: usize = matches.get_one::<String>("window").unwrap().parse()?;
+    let output_path = matches.get_one::<String>("output").unwrap();
+    let format = matches.get_one::<String>("format").unwrap();
+
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    println!(
+        "Loading coordinate index from: {}",
+        CoordinateIndex::file_path(dict_dir, dict_name).display()
+    );
+    let coordinate_index = CoordinateIndex::load(dict_dir, dict_name)?;
+
+    let start_time = Instant::now();
+    let mut matrix = CooccurrenceMatrix::from_coordinate_index(&coordinate_index, top_n, window);
+    println!("Co-occurrence counting completed in {:.2?}", start_time.elapsed());
+
+    let interner = TermInterner::load_or_default(dict_dir, dict_name)?;
+    if !interner.is_empty() && !matrix.assign_term_ids(&interner) {
+        println!("Some terms have no saved id; exporting without term ids");
+    }
+
+    let size = match format.as_str() {
+        "parquet" => matrix.save_as_parquet(output_path)?,
+        _ => matrix.save_as_csv(output_path)?,
+    };
+    println!(
+        "Saved {} term pairs to: {} ({} bytes)",
+        matrix.counts.len(),
+        output_path,
+        size
+    );
+
+    println!("\n=== TOP CO-OCCURRING PAIRS ===");
+    for (first, second, count) in matrix.sorted_pairs().iter().take(10) {
+        println!("{:>10}  {} / {}", count, first, second);
+    }
+
+    Ok(())
+}
+
+fn handle_collocations_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let top: usize = matches.get_one::<String>("top").unwrap().parse()?;
+    let measure: AssociationMeasure = matches.get_one::<String>("measure").unwrap().parse()?;
+    let min_frequency: u32 = matches.get_one::<String>("min-freq").unwrap().parse()?;
+
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+    println!("Loading dictionary and bigram index...");
+    let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+    let bigram_index = NGramPhraseIndex::load(dict_dir, dict_name)?;
+
+    let start_time = Instant::now();
+    let collocations =
+        extract_collocations(&bigram_index, &dictionary, measure, top, min_frequency);
+    println!("Scored word pairs in {:.2?}", start_time.elapsed());
+
+    println!("\n=== TOP COLLOCATIONS ({:?}) ===", measure);
+    for (rank, collocation) in collocations.iter().enumerate() {
+        println!(
+            "{:>4}. {:>10.3}  {} {} ({} occurrences)",
+            rank + 1,
+            collocation.score,
+            collocation.first,
+            collocation.second,
+            collocation.frequency
+        );
+    }
+
+    Ok(())
+}
+
+/// FB2 parser running the document processors requested on the command line
+fn document_parser(
+    matches: &clap::ArgMatches,
+    analyzer: Analyzer,
+) -> Result<FB2Parser, Box<dyn std::error::Error>> {
+    println!("Analyzer: {}", analyzer);
+    let mut parser = FB2Parser::new().with_analyzer(analyzer);
+    for pattern in matches.get_many::<String>("strip").into_iter().flatten() {
+        parser = parser.with_processor(Arc::new(PatternReplacer::remove(pattern)?));
+    }
+    if matches.get_flag("redact-emails") {
+        parser = parser.with_processor(Arc::new(PatternReplacer::redact_emails()));
+    }
+    for processor in parser.processors() {
+        println!("Document processor: {}", processor.name());
+    }
+    Ok(parser)
+}
+
+/// Install the thresholds named by `--tuning` before anything is built
+fn install_tuning(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let setting = matches.get_one::<String>("tuning").unwrap();
+    let config = TuningConfig::from_setting(setting)?;
+    if config != TuningConfig::DEFAULT {
+        println!("Tuning ({}): {:?}", setting, config);
+    }
+    config.install();
+    Ok(())
+}
+
+/// Install the analyzer the indexes under `prefixes` were built with, so query terms are spelled
+/// like index terms; `raw` keeps plain lowercasing
+fn install_query_analyzer<S: AsRef<str>>(
+    prefixes: &[S],
+    raw: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if raw {
+        println!("Raw query: terms are only lowercased");
+        Analyzer::default().install();
+        return Ok(());
+    }
+    let Some((first, rest)) = prefixes.split_first() else {
+        return Ok(());
+    };
+    let analyzer = IndexManifest::analyzer(first.as_ref())?;
+    for prefix in rest {
+        if IndexManifest::analyzer(prefix.as_ref())? != analyzer {
+            eprintln!(
+                "Warning: {} was built with another analyzer than {}; queries use the one of {}",
+                prefix.as_ref(),
+                first.as_ref(),
+                first.as_ref()
+            );
+        }
+    }
+    if !analyzer.is_plain() {
+        println!("Query analyzer: {}", analyzer);
+    }
+    analyzer.install();
+    Ok(())
+}
+
+/// Install the aliases named by `--operator-aliases` before the query is parsed
+fn install_operator_aliases(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let setting = matches.get_one::<String>("operator-aliases").unwrap();
+    let aliases = OperatorAliases::from_setting(setting)?;
+    if !matches!(setting.as_str(), "default" | "none") {
+        let entries: Vec<String> = aliases
+            .entries()
+            .into_iter()
+            .map(|(alias, operator)| format!("{}={}", alias, operator.keyword()))
+            .collect();
+        println!("Operator aliases ({}): {}", setting, entries.join(", "));
+    }
+    aliases.install();
+    Ok(())
+}
+
+fn handle_tune_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let output_path = matches.get_one::<String>("output").unwrap();
+
+    println!(
+        "Calibrating on {} threads...",
+        rayon::current_num_threads()
+    );
+    let start_time = Instant::now();
+    let config = TuningConfig::calibrate();
+    println!("Calibrated in {:.2?}", start_time.elapsed());
+
+    let default = TuningConfig::DEFAULT;
+    println!("{:<28} {:>12} {:>12}", "", "measured", "default");
+    for (name, measured, default) in [
+        ("Parallel threshold", config.parallel_threshold, default.parallel_threshold),
+        (
+            "Parallel sort threshold",
+            config.parallel_sort_threshold,
+            default.parallel_sort_threshold,
+        ),
+        ("Chunk size", config.chunk_size, default.chunk_size),
+        (
+            "Parallel lookup threshold",
+            config.parallel_lookup_threshold,
+            default.parallel_lookup_threshold,
+        ),
+    ] {
+        println!("{:<28} {:>12} {:>12}", name, measured, default);
+    }
+
+    let policy = config.merge_policy;
+    println!(
+        "Segment merges: {} segments per tier from {} bytes, none above {} bytes (edit merge_policy to change)",
+        policy.max_segments_per_tier, policy.min_merge_bytes, policy.max_segment_bytes
+    );
+
+    config.save(output_path)?;
+    println!("Saved tuning to: {} (use with --tuning {})", output_path, output_path);
+    Ok(())
+}
+
+fn handle_optimize_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let start_time = Instant::now();
+
+    println!("=== OPTIMIZING {} ===", dict_prefix);
+    let mut saved = 0i64;
+    saved += optimize_structure(dict_prefix, CompressedDictionary::optimize)?;
+    saved += optimize_structure(dict_prefix, CompressedInvertedIndex::optimize)?;
+    saved += optimize_structure(dict_prefix, NGramPhraseIndex::optimize)?;
+    saved += optimize_structure(dict_prefix, CoordinateIndex::optimize)?;
+    saved += optimize_structure(dict_prefix, WildcardSearchEngine::optimize)?;
+
+    let structures = IndexManifest::load(dict_prefix)?
+        .map(|manifest| manifest.structures)
+        .unwrap_or_default();
+    let structures: Vec<&str> = structures.iter().map(String::as_str).collect();
+    let manifest = IndexManifest::commit(dict_prefix, &structures)?;
+
+    println!(
+        "Optimization finished in {:.2?}, {} bytes saved, now generation {}",
+        start_time.elapsed(),
+        saved,
+        manifest.generation
+    );
+    Ok(())
+}
+
+fn handle_fsck_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dict_prefix = matches.get_one::<String>("dict_file").unwrap();
+    let repair = matches.get_flag("repair");
+    let terms_path = format!("{}_terms.bin", dict_prefix);
+    let (dict_dir, dict_name) = split_prefix(dict_prefix);
+
+    println!("=== CHECKING {} ===", terms_path);
+    let mut blocks = TermBlockFile::open(&terms_path)?;
+    let damaged = blocks.verify()?;
+    println!(
+        "{} blocks, {} terms, {} damaged",
+        blocks.block_count(),
+        blocks.term_count(),
+        damaged.len()
+    );
+    for block in &damaged {
+        println!("  - {}", block);
+    }
+    if repair && !damaged.is_empty() {
+        let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+        let repaired = blocks.repair(&dictionary)?;
+        println!("Rebuilt {} blocks from {}.bin", repaired.len(), dict_prefix);
+    }
+
+    let mut damaged_count = damaged.len();
+    damaged_count += check_posting_blocks(
+        dict_prefix,
+        repair,
+        |index: &CompressedInvertedIndex| index.damaged_blocks.clone(),
+        |index| {
+            let dictionary = CompressedDictionary::load(dict_dir, dict_name)?;
+            Ok(index.restore_terms(&dictionary))
+        },
+    )?;
+    damaged_count += check_posting_blocks(
+        dict_prefix,
+        repair,
+        |index: &CoordinateIndex| index.damaged_blocks.clone(),
+        |index| Ok(index.restore_terms(&load_forward_index(dict_prefix)?)),
+    )?;
+    damaged_count += check_posting_blocks(
+        dict_prefix,
+        repair,
+        |index: &NGramPhraseIndex| index.damaged_blocks.clone(),
+        |index| Ok(index.restore_phrases(&load_forward_index(dict_prefix)?)),
+    )?;
+    if damaged_count > 0 && !repair {
+        return Err(format!(
+            "{} damaged blocks; searches skip their terms until they are repaired with --repair",
+            damaged_count
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Check a structure whose posting lists are stored in checksummed blocks: list the blocks
+/// that failed on load and with `repair` rebuild their terms through `restore`, writing the
+/// structure back through a temporary file. Returns the number of damaged blocks; a
+/// structure the index was built without has none.
+fn check_posting_blocks<T, D, R>(
+    prefix: &str,
+    repair: bool,
+    damaged_blocks: D,
+    restore: R,
+) -> Result<usize, Box<dyn std::error::Error>>
+where
+    T: Persistable,
+    D: FnOnce(&T) -> Vec<DamagedBlock>,
+    R: FnOnce(&mut T) -> Result<usize, Box<dyn std::error::Error>>,
+{
+    let (dir, name) = split_prefix(prefix);
+    let path = T::file_path(dir, name);
+    let Some(mut structure) = T::load_if_present(dir, name)? else {
+        return Ok(0);
+    };
+    println!("=== CHECKING {} ===", path.display());
+    let damaged = damaged_blocks(&structure);
+    println!("{} damaged posting blocks", damaged.len());
+    for block in &damaged {
+        println!("  - {}", block);
+    }
+    if !repair || damaged.is_empty() {
+        return Ok(damaged.len());
+    }
+
+    let restored = restore(&mut structure)?;
+    let temp_path = path.with_extension("bin.tmp");
+    fs::write(&temp_path, structure.to_bytes()?)?;
+    fs::rename(&temp_path, &path)?;
+    println!("Rebuilt {} terms of {} blocks", restored, damaged.len());
+    Ok(damaged.len())
+}
+
+/// The forward index saved with `prefix`, which positional postings are rebuilt from
+fn load_forward_index(prefix: &str) -> Result<ForwardIndex, Box<dyn std::error::Error>> {
+    let (dir, name) = split_prefix(prefix);
+    ForwardIndex::load_if_present(dir, name)?.ok_or_else(|| {
+        format!(
+            "{} has no forward index to rebuild positions from; rebuild the index instead",
+            prefix
+        )
+        .into()
+    })
+}
+
+/// Load a structure, compact it and write it back through a temporary file so an interrupted
+/// run never leaves a truncated structure behind. Returns the number of bytes saved.
+fn optimize_structure<T, F>(prefix: &str, optimize: F) -> Result<i64, Box<dyn std::error::Error>>
+where
+    T: Persistable,
+    F: FnOnce(&mut T),
+{
+    let (dir, name) = split_prefix(prefix);
+    let path = T::file_path(dir, name);
+    let Ok(data) = fs::read(&path) else {
+        println!("{:<40} missing, skipped", path.display());
+        return Ok(0);
+    };
+    let mut structure = T::from_bytes(&data)?;
+    optimize(&mut structure);
+    let optimized = structure.to_bytes()?;
+
+    let temp_path = path.with_extension("bin.tmp");
+    fs::write(&temp_path, &optimized)?;
+    fs::rename(&temp_path, &path)?;
+
+    println!("{:<40} {} -> {} bytes", path.display(), data.len(), optimized.len());
+    Ok(data.len() as i64 - optimized.len() as i64)
+}
+
+fn handle_tui_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    install_query_analyzer(&[matches.get_one::<String>("dict_file").unwrap()], false)?;
+    run_tui(TuiOptions {
+        prefix: matches.get_one::<String>("dict_file").unwrap().clone(),
+        input: matches.get_one::<String>("input").cloned(),
+        build_on_start: matches.get_flag("build"),
+    })
+}
+
+fn handle_completions_command(matches: &clap::ArgMatches) {
+    let shell = *matches.get_one::<Shell>("shell").unwrap();
+    clap_complete::generate(shell, &mut build_cli(), "grimoire", &mut std::io::stdout());
+}
+
+fn handle_man_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let cli = build_cli().name("grimoire");
+    match matches.get_one::<String>("output") {
+        Some(directory) => {
+            fs::create_dir_all(directory)?;
+            clap_mangen::generate_to(cli, directory)?;
+            println!("Man pages written to {}", directory);
+        }
+        None => clap_mangen::Man::new(cli).render(&mut std::io::stdout())?,
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grimoire::Dictionary;
+
+    #[test]
+    fn test_hide_rejects_documents_not_in_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dict = Dictionary::new();
+        dict.add_term("war".to_string(), "war.fb2".to_string());
+        CompressedDictionary::from_dictionary(&dict)
+            .save(dir.path(), "idx")
+            .unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        let hide = |document: &str| {
+            let args = ["grimoire", "hide", "--doc", document, "-d", &prefix];
+            let matches = build_cli().get_matches_from(args);
+            handle_hide_command(matches.subcommand_matches("hide").unwrap())
+        };
+
+        let error = hide("wra.fb2").unwrap_err();
+        assert_eq!(error.to_string(), "wra.fb2 is not in the index");
+        let hidden_path = format!("{}_hidden.bin", prefix);
+        assert!(HiddenDocuments::load_or_default(&hidden_path).unwrap().is_empty());
+
+        hide("war.fb2").unwrap();
+        let hidden = HiddenDocuments::load_or_default(&hidden_path).unwrap();
+        assert!(hidden.is_hidden("war.fb2"));
+        assert_eq!(hidden.len(), 1);
+    }
+}
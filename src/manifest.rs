@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Analyzer;
+
+/// Generation record for the structures saved under one prefix. Every rebuild or rewrite
+/// bumps the generation, so anything derived from an older generation can detect it is stale.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexManifest {
+    pub generation: u64,
+    /// Unix time the generation was committed
+    pub committed_at: u64,
+    /// Structure file suffixes written by the generation, e.g. `_index`
+    pub structures: Vec<String>,
+    /// Analyzer the documents were indexed with, for searches to apply to query terms
+    pub analyzer: Analyzer,
+}
+
+/// Manifests written before the analyzer was recorded
+#[derive(Deserialize)]
+struct LegacyManifest {
+    generation: u64,
+    committed_at: u64,
+    structures: Vec<String>,
+}
+
+impl IndexManifest {
+    pub fn path(prefix: &str) -> String {
+        format!("{}_manifest.bin", prefix)
+    }
+
+    /// Manifest of `prefix`, or `None` for indexes built before manifests existed. Older
+    /// manifests without an analyzer get the plain one every build used back then.
+    pub fn load(prefix: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let data = match fs::read(Self::path(prefix)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match bincode::deserialize(&data) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(e) => match bincode::deserialize::<LegacyManifest>(&data) {
+                Ok(legacy) => Ok(Some(IndexManifest {
+                    generation: legacy.generation,
+                    committed_at: legacy.committed_at,
+                    structures: legacy.structures,
+                    analyzer: Analyzer::default(),
+                })),
+                Err(_) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Analyzer the index under `prefix` was built with; plain for indexes without a manifest
+    pub fn analyzer(prefix: &str) -> Result<Analyzer, Box<dyn std::error::Error>> {
+        Ok(Self::load(prefix)?.map_or_else(Analyzer::default, |manifest| manifest.analyzer))
+    }
+
+    /// Current generation; 0 when no manifest has been written
+    pub fn current_generation(prefix: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(Self::load(prefix)?.map_or(0, |manifest| manifest.generation))
+    }
+
+    /// Record a new generation of structures rewritten from the current ones, keeping the
+    /// analyzer they were built with
+    pub fn commit(
+        prefix: &str,
+        structures: &[&str],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::commit_with_analyzer(prefix, structures, Self::analyzer(prefix)?)
+    }
+
+    /// Record a new generation once all of its structures are on disk. The manifest is
+    /// replaced with a rename, so readers see either the old generation or the new one.
+    pub fn commit_with_analyzer(
+        prefix: &str,
+        structures: &[&str],
+        analyzer: Analyzer,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let manifest = IndexManifest {
+            generation: Self::current_generation(prefix)? + 1,
+            committed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            structures: structures.iter().map(|s| s.to_string()).collect(),
+            analyzer,
+        };
+
+        let path = Self::path(prefix);
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, bincode::serialize(&manifest)?)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_bumps_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+
+        assert_eq!(IndexManifest::current_generation(&prefix).unwrap(), 0);
+        IndexManifest::commit(&prefix, &["", "_index"]).unwrap();
+        let manifest = IndexManifest::commit(&prefix, &["", "_index"]).unwrap();
+
+        assert_eq!(manifest.generation, 2);
+        let loaded = IndexManifest::load(&prefix).unwrap().unwrap();
+        assert_eq!(loaded.generation, 2);
+        assert_eq!(loaded.structures, vec!["", "_index"]);
+    }
+
+    #[test]
+    fn test_rewrites_keep_the_build_analyzer() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+
+        // Written by builds that predate analyzers
+        let legacy = (3u64, 1_700_000_000u64, vec!["".to_string()]);
+        fs::write(
+            IndexManifest::path(&prefix),
+            bincode::serialize(&legacy).unwrap(),
+        )
+        .unwrap();
+        let loaded = IndexManifest::load(&prefix).unwrap().unwrap();
+        assert_eq!(loaded.generation, 3);
+        assert!(loaded.analyzer.is_plain());
+
+        let analyzer = Analyzer::from_spec("fold-yo,stopwords").unwrap();
+        IndexManifest::commit_with_analyzer(&prefix, &[""], analyzer.clone()).unwrap();
+        let manifest = IndexManifest::commit(&prefix, &["", "_index"]).unwrap();
+        assert_eq!(manifest.generation, 5);
+        assert_eq!(IndexManifest::analyzer(&prefix).unwrap(), analyzer);
+    }
+}
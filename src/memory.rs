@@ -0,0 +1,143 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+static COUNTERS: Counters = Counters::new();
+
+/// The system allocator, counting live heap bytes and their peak so a build can report how
+/// much memory each phase needed. A binary opts in with
+/// `#[global_allocator] static ALLOCATOR: TrackingAllocator = TrackingAllocator;`; without
+/// it nothing is counted and `TrackingAllocator::take_peak` gives `None`. Counting costs two
+/// relaxed atomic updates per allocation.
+pub struct TrackingAllocator;
+
+impl TrackingAllocator {
+    /// Heap bytes allocated now
+    pub fn allocated() -> u64 {
+        COUNTERS.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Most heap bytes allocated at once since the last `take_peak`
+    pub fn peak() -> u64 {
+        COUNTERS.peak.load(Ordering::Relaxed)
+    }
+
+    /// The peak since the last call, starting a new one from what is allocated now; `None`
+    /// when the tracker is not the global allocator
+    pub fn take_peak() -> Option<u64> {
+        COUNTERS.take_peak()
+    }
+}
+
+/// Live and peak bytes of a tracking allocator
+struct Counters {
+    allocated: AtomicU64,
+    peak: AtomicU64,
+    /// Set by the first allocation counted
+    active: AtomicBool,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            allocated: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    fn added(&self, bytes: usize) {
+        let allocated = self.allocated.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        self.peak.fetch_max(allocated, Ordering::Relaxed);
+        if !self.active.load(Ordering::Relaxed) {
+            self.active.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn removed(&self, bytes: usize) {
+        self.allocated.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn take_peak(&self) -> Option<u64> {
+        if !self.active.load(Ordering::Relaxed) {
+            return None;
+        }
+        let allocated = self.allocated.load(Ordering::Relaxed);
+        Some(self.peak.swap(allocated, Ordering::Relaxed))
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+        if !pointer.is_null() {
+            COUNTERS.added(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc_zeroed(layout);
+        if !pointer.is_null() {
+            COUNTERS.added(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        COUNTERS.removed(layout.size());
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let moved = System.realloc(pointer, layout, new_size);
+        if !moved.is_null() {
+            COUNTERS.removed(layout.size());
+            COUNTERS.added(new_size);
+        }
+        moved
+    }
+}
+
+/// A `/proc/PID/status` field given in kilobytes, e.g. `VmRSS`, in bytes; `None` where procfs
+/// is unavailable
+pub fn process_status_bytes(pid: u32, field: &str) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| {
+        line.strip_prefix(field)
+            .is_some_and(|rest| rest.starts_with(':'))
+    })?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Most memory this process has had resident, including what the allocator does not see such
+/// as memory-mapped files
+pub fn peak_resident_memory() -> Option<u64> {
+    process_status_bytes(std::process::id(), "VmHWM")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peak_is_taken_per_phase() {
+        // The test binary keeps the system allocator, so counters are driven by hand
+        let counters = Counters::new();
+        assert_eq!(counters.take_peak(), None);
+        counters.added(1000);
+        counters.added(3000);
+        counters.removed(1000);
+        assert_eq!(counters.take_peak(), Some(4000));
+        counters.removed(2000);
+        counters.added(500);
+        assert_eq!(counters.take_peak(), Some(3000));
+        assert_eq!(counters.take_peak(), Some(1500));
+
+        if cfg!(target_os = "linux") {
+            assert!(peak_resident_memory().unwrap() > 0);
+        }
+        assert!(process_status_bytes(std::process::id(), "NoSuchField").is_none());
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::Duration;
+
+/// When the segments of a `NearRealTimeIndex` are merged. Segments fall into tiers by size,
+/// each tier `max_segments_per_tier` times larger than the one below, starting at
+/// `min_merge_bytes`; a tier holding `max_segments_per_tier` segments has its smallest ones
+/// merged into one of the next tier. Sizes are those of the segments' stored text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergePolicy {
+    /// Segments a tier holds before they are merged, at least 2
+    pub max_segments_per_tier: usize,
+    /// Segments smaller than this all count as the lowest tier
+    pub min_merge_bytes: u64,
+    /// A merge never produces a segment larger than this, and larger segments are never merged
+    pub max_segment_bytes: u64,
+}
+
+impl MergePolicy {
+    pub const DEFAULT: MergePolicy = MergePolicy {
+        max_segments_per_tier: 10,
+        min_merge_bytes: 1 << 20,
+        max_segment_bytes: 256 << 20,
+    };
+
+    /// Tier of a segment of `bytes`, 0 for the smallest
+    pub fn tier(&self, bytes: u64) -> u32 {
+        let factor = self.max_segments_per_tier.max(2) as u64;
+        let mut bound = self.min_merge_bytes.max(1).saturating_mul(factor);
+        let mut tier = 0;
+        while bytes >= bound {
+            tier += 1;
+            bound = bound.saturating_mul(factor);
+            if bound == u64::MAX {
+                break;
+            }
+        }
+        tier
+    }
+
+    /// Positions in `segments`, given as sizes in bytes, of the segments to merge next, the
+    /// lowest full tier first; `None` when no tier is full
+    pub fn select(&self, segments: &[u64]) -> Option<Vec<usize>> {
+        let per_tier = self.max_segments_per_tier.max(2);
+        let mut tiers: Vec<(u32, usize)> = segments
+            .iter()
+            .enumerate()
+            .filter(|&(_, &bytes)| bytes < self.max_segment_bytes)
+            .map(|(position, &bytes)| (self.tier(bytes), position))
+            .collect();
+        tiers.sort_unstable_by_key(|&(tier, position)| (tier, segments[position], position));
+
+        for tier in tiers.chunk_by(|a, b| a.0 == b.0) {
+            if tier.len() < per_tier {
+                continue;
+            }
+            let mut chosen: Vec<usize> = tier[..per_tier]
+                .iter()
+                .map(|&(_, position)| position)
+                .collect();
+            while chosen.len() >= 2
+                && chosen.iter().map(|&p| segments[p]).sum::<u64>() > self.max_segment_bytes
+            {
+                chosen.pop();
+            }
+            if chosen.len() >= 2 {
+                chosen.sort_unstable();
+                return Some(chosen);
+            }
+        }
+        None
+    }
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What one segment merge read, wrote and took
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeStats {
+    /// Unix time the merge finished
+    pub merged_at: u64,
+    /// Prefix of the merged segment
+    pub output: String,
+    /// Prefixes and stored text sizes of the segments merged
+    pub inputs: Vec<(String, u64)>,
+    /// Bytes of structures the merge wrote
+    pub output_bytes: u64,
+    pub documents: usize,
+    pub elapsed: Duration,
+    /// Bytes written for the merged documents, by their flush and every merge since, per
+    /// byte of their text
+    pub write_amplification: f64,
+}
+
+impl MergeStats {
+    /// Stored text merged, in bytes
+    pub fn input_bytes(&self) -> u64 {
+        self.inputs.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    fn to_line(&self) -> String {
+        let inputs: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|(prefix, bytes)| format!("{}={}", prefix, bytes))
+            .collect();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+            self.merged_at,
+            self.output,
+            inputs.join(","),
+            self.output_bytes,
+            self.documents,
+            self.elapsed.as_millis(),
+            self.write_amplification
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [merged_at, output, inputs, output_bytes, documents, millis, amplification] =
+            fields[..]
+        else {
+            return None;
+        };
+        let inputs = inputs
+            .split(',')
+            .map(|input| {
+                let (prefix, bytes) = input.rsplit_once('=')?;
+                Some((prefix.to_string(), bytes.parse().ok()?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(MergeStats {
+            merged_at: merged_at.parse().ok()?,
+            output: output.to_string(),
+            inputs,
+            output_bytes: output_bytes.parse().ok()?,
+            documents: documents.parse().ok()?,
+            elapsed: Duration::from_millis(millis.parse().ok()?),
+            write_amplification: amplification.parse().ok()?,
+        })
+    }
+}
+
+/// Merges recorded in `{prefix}_compactions.log`, one tab-separated line per merge:
+/// unix time, merged segment, `prefix=bytes` inputs, bytes written, documents, milliseconds
+/// and write amplification
+pub struct CompactionLog;
+
+impl CompactionLog {
+    pub fn path(prefix: &str) -> String {
+        format!("{}_compactions.log", prefix)
+    }
+
+    pub fn append(prefix: &str, stats: &MergeStats) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::path(prefix))?;
+        writeln!(file, "{}", stats.to_line())?;
+        Ok(())
+    }
+
+    /// Merges logged under `prefix`, oldest first; a missing log means no merges yet
+    pub fn load(prefix: &str) -> Result<Vec<MergeStats>, Box<dyn std::error::Error>> {
+        match fs::read_to_string(Self::path(prefix)) {
+            Ok(contents) => Ok(contents.lines().filter_map(MergeStats::from_line).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_tiers_merge_smallest_first() {
+        let policy = MergePolicy {
+            max_segments_per_tier: 3,
+            min_merge_bytes: 100,
+            max_segment_bytes: 1000,
+        };
+        assert_eq!(policy.tier(10), 0);
+        assert_eq!(policy.tier(299), 0);
+        assert_eq!(policy.tier(300), 1);
+        assert_eq!(policy.tier(900), 2);
+
+        assert_eq!(policy.select(&[50, 400, 60]), None);
+        assert_eq!(policy.select(&[50, 400, 60, 10, 20]), Some(vec![0, 3, 4]));
+        // The full tier's merge would outgrow the largest segment, so it takes fewer
+        assert_eq!(policy.select(&[400, 800, 500, 700]), Some(vec![0, 2]));
+        assert_eq!(policy.select(&[1500, 2600, 3000]), None);
+
+        let stats = MergeStats {
+            merged_at: 1,
+            output: "idx_segment4".to_string(),
+            inputs: vec![
+                ("idx_segment1".to_string(), 50),
+                ("idx_segment2".to_string(), 60),
+            ],
+            output_bytes: 900,
+            documents: 2,
+            elapsed: Duration::from_millis(12),
+            write_amplification: 8.5,
+        };
+        assert_eq!(MergeStats::from_line(&stats.to_line()), Some(stats.clone()));
+        assert_eq!(stats.input_bytes(), 110);
+    }
+}
@@ -0,0 +1,361 @@
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use crate::persist::{split_prefix, Persistable};
+use crate::tfidf::{idf, tf_weight};
+use crate::{query_terms, CoordinateIndex, DocumentNorms, SharedSearchers};
+
+/// How `MultiIndexSearcher` scores the documents matching a query
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FederatedRanking {
+    /// Sum over query terms of tf_weight(tf) * idf(N, df)
+    TfIdf,
+    /// Query likelihood with Jelinek-Mercer smoothing, see `QueryLikelihoodScorer`
+    QueryLikelihood { lambda: f64 },
+}
+
+/// Collection statistics of the query terms summed over every segment, so a segment scores its
+/// documents as if all segments were one index
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlobalStatistics {
+    pub total_documents: usize,
+    /// Positions indexed across all documents
+    pub collection_length: usize,
+    pub document_frequencies: HashMap<String, usize>,
+    pub collection_frequencies: HashMap<String, usize>,
+}
+
+impl GlobalStatistics {
+    fn merge(mut self, other: GlobalStatistics) -> GlobalStatistics {
+        self.total_documents += other.total_documents;
+        self.collection_length += other.collection_length;
+        for (term, df) in other.document_frequencies {
+            *self.document_frequencies.entry(term).or_insert(0) += df;
+        }
+        for (term, cf) in other.collection_frequencies {
+            *self.collection_frequencies.entry(term).or_insert(0) += cf;
+        }
+        self
+    }
+}
+
+/// A scored document and the segment it was found in
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentHit {
+    pub segment: usize,
+    pub document: String,
+    pub score: f64,
+}
+
+/// Searches several independently built indexes (segments or shards) as one collection.
+/// Scores need collection-wide statistics, which no single segment has, so a search runs in
+/// two passes: the first sums document and collection frequencies of the query terms over all
+/// segments, the second has each segment match the query and score its hits with those sums.
+/// Merged rankings then equal those of a single index built from every document.
+#[derive(Debug, Clone)]
+pub struct MultiIndexSearcher {
+    segments: Vec<SharedSearchers>,
+    /// Length of every document per segment, counted once on first use
+    document_lengths: Vec<Arc<OnceLock<HashMap<String, usize>>>>,
+}
+
+impl MultiIndexSearcher {
+    /// Search the indexes saved under `prefixes`, each loaded on first use
+    pub fn open<S: AsRef<str>>(prefixes: &[S]) -> Self {
+        Self::from_segments(
+            prefixes
+                .iter()
+                .map(|prefix| SharedSearchers::open(prefix.as_ref()))
+                .collect(),
+        )
+    }
+
+    pub fn from_segments(segments: Vec<SharedSearchers>) -> Self {
+        let document_lengths = segments.iter().map(|_| Arc::default()).collect();
+        MultiIndexSearcher {
+            segments,
+            document_lengths,
+        }
+    }
+
+    pub fn segments(&self) -> &[SharedSearchers] {
+        &self.segments
+    }
+
+    /// First pass: document and collection frequencies of `terms` over all segments
+    pub fn global_statistics(&self, terms: &[String]) -> Result<GlobalStatistics, String> {
+        let per_segment = (0..self.segments.len())
+            .into_par_iter()
+            .map(|segment| self.segment_statistics(segment, terms))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(per_segment
+            .into_iter()
+            .fold(GlobalStatistics::default(), GlobalStatistics::merge))
+    }
+
+    /// Second pass: match the query in every segment and score the hits with global
+    /// statistics. Hits come back by descending score, ties broken by document name and then
+    /// segment.
+    pub fn search(
+        &self,
+        query: &str,
+        ranking: FederatedRanking,
+    ) -> Result<Vec<SegmentHit>, String> {
+        let terms = query_terms(query)?;
+        let statistics = self.global_statistics(&terms)?;
+
+        let per_segment = (0..self.segments.len())
+            .into_par_iter()
+            .map(|segment| self.segment_hits(segment, query, &terms, &statistics, ranking))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut hits: Vec<SegmentHit> = per_segment.into_iter().flatten().collect();
+        sort_hits(&mut hits);
+        Ok(hits)
+    }
+
+    /// Match the query in every segment and score its hits with that segment's statistics
+    /// alone, as separate backends would. Rankings come back per segment, best first, and are
+    /// only comparable once normalized or fused, see `combine_normalized` and
+    /// `reciprocal_rank_fusion`.
+    pub fn search_separately(
+        &self,
+        query: &str,
+        ranking: FederatedRanking,
+    ) -> Result<Vec<Vec<SegmentHit>>, String> {
+        let terms = query_terms(query)?;
+        (0..self.segments.len())
+            .into_par_iter()
+            .map(|segment| {
+                let statistics = self.segment_statistics(segment, &terms)?;
+                let mut hits = self.segment_hits(segment, query, &terms, &statistics, ranking)?;
+                sort_hits(&mut hits);
+                Ok(hits)
+            })
+            .collect()
+    }
+
+    fn segment_hits(
+        &self,
+        segment: usize,
+        query: &str,
+        terms: &[String],
+        statistics: &GlobalStatistics,
+        ranking: FederatedRanking,
+    ) -> Result<Vec<SegmentHit>, String> {
+        let (_, documents) = self.segments[segment].search(query)?;
+        let index = self.segments[segment].coordinate()?;
+        let lengths = self.document_lengths(segment, &index);
+        Ok(documents
+            .into_iter()
+            .map(|document| {
+                let score = score(&index, lengths, &document, terms, statistics, ranking);
+                SegmentHit {
+                    segment,
+                    document,
+                    score,
+                }
+            })
+            .collect())
+    }
+
+    fn segment_statistics(
+        &self,
+        segment: usize,
+        terms: &[String],
+    ) -> Result<GlobalStatistics, String> {
+        let index = self.segments[segment].coordinate()?;
+        let lengths = self.document_lengths(segment, &index);
+        let mut statistics = GlobalStatistics {
+            total_documents: index.documents.len(),
+            collection_length: lengths.values().sum(),
+            ..GlobalStatistics::default()
+        };
+        for term in terms {
+            let postings = index.index.get(term).map_or(&[][..], Vec::as_slice);
+            statistics
+                .document_frequencies
+                .insert(term.clone(), postings.len());
+            statistics.collection_frequencies.insert(
+                term.clone(),
+                postings.iter().map(|p| p.positions.len()).sum(),
+            );
+        }
+        Ok(statistics)
+    }
+
+    /// Lengths recorded in the segment's norms, counted from the index for segments built
+    /// without them
+    fn document_lengths(&self, segment: usize, index: &CoordinateIndex) -> &HashMap<String, usize> {
+        self.document_lengths[segment].get_or_init(|| {
+            let (dir, name) = split_prefix(self.segments[segment].prefix());
+            let norms = DocumentNorms::load_if_present(dir, name).ok().flatten();
+            let lengths = match &norms {
+                Some(norms) => norms.document_lengths(),
+                None => index.document_lengths(),
+            };
+            lengths
+                .into_iter()
+                .map(|(document, length)| (document.to_string(), length))
+                .collect()
+        })
+    }
+}
+
+/// Descending score, ties broken by document name and then segment
+fn sort_hits(hits: &mut [SegmentHit]) {
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.document.cmp(&b.document))
+            .then_with(|| a.segment.cmp(&b.segment))
+    });
+}
+
+fn score(
+    index: &CoordinateIndex,
+    lengths: &HashMap<String, usize>,
+    document: &str,
+    terms: &[String],
+    statistics: &GlobalStatistics,
+    ranking: FederatedRanking,
+) -> f64 {
+    let document_length = lengths.get(document).copied().unwrap_or(0);
+    terms
+        .iter()
+        .map(|term| {
+            let term_frequency = index.index.get(term).map_or(0, |postings| {
+                postings
+                    .binary_search_by(|p| p.document.as_str().cmp(document))
+                    .map_or(0, |i| postings[i].positions.len())
+            });
+            match ranking {
+                FederatedRanking::TfIdf => {
+                    let df = statistics.document_frequencies.get(term).copied();
+                    tf_weight(term_frequency as u32)
+                        * idf(statistics.total_documents, df.unwrap_or(0))
+                }
+                FederatedRanking::QueryLikelihood { lambda } => {
+                    let lambda = lambda.clamp(0.0, 1.0);
+                    let cf = statistics.collection_frequencies.get(term).copied();
+                    let document_probability = if document_length > 0 {
+                        term_frequency as f64 / document_length as f64
+                    } else {
+                        0.0
+                    };
+                    let collection_probability = if statistics.collection_length > 0 {
+                        cf.unwrap_or(0) as f64 / statistics.collection_length as f64
+                    } else {
+                        0.0
+                    };
+                    let probability =
+                        (1.0 - lambda) * document_probability + lambda * collection_probability;
+                    // Same convention as `QueryLikelihoodScorer`: unseen terms count for nothing
+                    if probability > 0.0 {
+                        probability.ln()
+                    } else {
+                        0.0
+                    }
+                }
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CompressedDictionary, CompressedInvertedIndex, Dictionary, PostingEntry,
+        QueryLikelihoodScorer,
+    };
+
+    fn save(prefix: &str, documents: &[(&str, &str)]) -> CoordinateIndex {
+        let mut postings: HashMap<String, Vec<PostingEntry>> = HashMap::new();
+        let mut dictionary = Dictionary::new();
+        for (name, text) in documents {
+            for (position, word) in text.split_whitespace().enumerate() {
+                dictionary.add_term(word.to_string(), name.to_string());
+                let entries = postings.entry(word.to_string()).or_default();
+                match entries.last_mut() {
+                    Some(last) if last.document == *name => last.positions.push(position),
+                    _ => entries.push(PostingEntry {
+                        document: name.to_string(),
+                        positions: vec![position],
+                    }),
+                }
+            }
+        }
+        let names = documents.iter().map(|(name, _)| name.to_string()).collect();
+        let mut coordinate = CoordinateIndex::from_postings(postings, names);
+        coordinate.optimize();
+        let inverted = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dictionary),
+        );
+        let (dir, name) = split_prefix(prefix);
+        coordinate.save(dir, name).unwrap();
+        inverted.save(dir, name).unwrap();
+        coordinate
+    }
+
+    #[test]
+    fn test_segment_scores_match_a_single_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let first = [("a.fb2", "war and peace war"), ("b.fb2", "peace at last")];
+        let second = [
+            ("c.fb2", "war war war without end"),
+            ("d.fb2", "forest of peace and war"),
+            ("e.fb2", "a quiet forest"),
+        ];
+        save(&path("first"), &first);
+        save(&path("second"), &second);
+        let whole = save(&path("whole"), &[first.as_slice(), &second].concat());
+
+        let searcher = MultiIndexSearcher::open(&[path("first"), path("second")]);
+        let statistics = searcher
+            .global_statistics(&["war".to_string(), "forest".to_string()])
+            .unwrap();
+        assert_eq!(statistics.total_documents, 5);
+        assert_eq!(statistics.document_frequencies["war"], 3);
+        assert_eq!(statistics.collection_frequencies["war"], 6);
+
+        let terms = query_terms("war or peace").unwrap();
+        let scorer = QueryLikelihoodScorer::new(&whole, 0.3);
+        let hits = searcher
+            .search(
+                "war or peace",
+                FederatedRanking::QueryLikelihood { lambda: 0.3 },
+            )
+            .unwrap();
+        let mut candidates: Vec<String> = hits.iter().map(|hit| hit.document.clone()).collect();
+        candidates.sort();
+        let expected = scorer.rank(&candidates, &terms);
+        assert_eq!(hits.len(), expected.len());
+        for (hit, (document, score)) in hits.iter().zip(&expected) {
+            assert_eq!(hit.document, *document);
+            assert!((hit.score - score).abs() < 1e-9);
+        }
+        assert!(hits
+            .iter()
+            .all(|hit| (hit.segment == 1) == (hit.document.as_str() >= "c.fb2")));
+
+        let hits = searcher
+            .search("war and not peace", FederatedRanking::TfIdf)
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].document, "c.fb2");
+        let expected = tf_weight(3) * idf(5, 3);
+        assert!((hits[0].score - expected).abs() < 1e-9);
+
+        // Scored with the second segment's statistics alone: 3 documents, 2 with "war"
+        let separate = searcher
+            .search_separately("war and not peace", FederatedRanking::TfIdf)
+            .unwrap();
+        assert!(separate[0].is_empty());
+        assert_eq!(separate[1].len(), 1);
+        let expected = tf_weight(3) * idf(3, 2);
+        assert!((separate[1][0].score - expected).abs() < 1e-9);
+    }
+}
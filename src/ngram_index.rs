@@ -0,0 +1,1071 @@
+use rayon::prelude::*;
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::inverted_index::vb_encoding::{
+    decode_delta_vb, decode_vb, encode_delta_vb, encode_vb, read_vb,
+};
+use crate::persist::Persistable;
+use crate::query::{raw_term, Capabilities, QueryAst, QueryEvaluator, QueryParser};
+use crate::tfidf::{idf, tf_weight};
+use crate::{
+    is_indexable_word, read_posting_blocks, write_posting_blocks, CompressedDictionary,
+    DamagedBlock, ForwardIndex, PostingBlocks,
+};
+
+/// Keys per front-coding block; lookups binary search the block heads, then scan one block
+const FRONT_CODING_BLOCK: usize = 8;
+
+/// Sorted strings front-coded in blocks: each block stores its first key in full and every
+/// other key as the length of the prefix shared with its predecessor plus the rest
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FrontCodedKeys {
+    data: Vec<u8>,
+    /// Offset of every block in `data`
+    block_offsets: Vec<usize>,
+    len: usize,
+    /// Total length of the keys before coding
+    pub uncompressed_size: usize,
+}
+
+impl FrontCodedKeys {
+    /// Code `keys`, which must be sorted
+    pub fn from_sorted<S: AsRef<str>>(keys: &[S]) -> Self {
+        let mut coded = FrontCodedKeys {
+            len: keys.len(),
+            ..Default::default()
+        };
+        for block in keys.chunks(FRONT_CODING_BLOCK) {
+            coded.block_offsets.push(coded.data.len());
+            let mut previous: &[u8] = &[];
+            for key in block {
+                let key = key.as_ref().as_bytes();
+                let shared = previous.iter().zip(key).take_while(|(a, b)| a == b).count();
+                coded.data.extend(encode_vb(shared as u32));
+                coded.data.extend(encode_vb((key.len() - shared) as u32));
+                coded.data.extend_from_slice(&key[shared..]);
+                coded.uncompressed_size += key.len();
+                previous = key;
+            }
+        }
+        coded
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Coded size in bytes
+    pub fn compressed_size(&self) -> usize {
+        self.data.len() + self.block_offsets.len() * std::mem::size_of::<usize>()
+    }
+
+    /// Decode the keys of one block into `keys`
+    fn decode_block(&self, block: usize, keys: &mut Vec<Vec<u8>>) {
+        let end = self
+            .block_offsets
+            .get(block + 1)
+            .copied()
+            .unwrap_or(self.data.len());
+        let mut pos = self.block_offsets[block];
+        let mut previous: Vec<u8> = Vec::new();
+        while pos < end {
+            let shared = read_vb(&self.data, &mut pos) as usize;
+            let suffix_len = read_vb(&self.data, &mut pos) as usize;
+            let mut key = previous[..shared].to_vec();
+            key.extend_from_slice(&self.data[pos..pos + suffix_len]);
+            pos += suffix_len;
+            keys.push(key.clone());
+            previous = key;
+        }
+    }
+
+    /// First key of a block, stored with no shared prefix
+    fn block_head(&self, block: usize) -> &[u8] {
+        let mut pos = self.block_offsets[block];
+        let _shared = read_vb(&self.data, &mut pos);
+        let len = read_vb(&self.data, &mut pos) as usize;
+        &self.data[pos..pos + len]
+    }
+
+    /// Position of `key` in sorted order
+    pub fn find(&self, key: &str) -> Option<usize> {
+        let key = key.as_bytes();
+        // Number of blocks whose head is <= key; the key can only be in the last of them
+        let (mut block, mut end) = (0, self.block_offsets.len());
+        while block < end {
+            let mid = (block + end) / 2;
+            if self.block_head(mid) <= key {
+                block = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        if block == 0 {
+            return None;
+        }
+        let mut keys = Vec::with_capacity(FRONT_CODING_BLOCK);
+        self.decode_block(block - 1, &mut keys);
+        keys.iter()
+            .position(|candidate| candidate.as_slice() == key)
+            .map(|offset| (block - 1) * FRONT_CODING_BLOCK + offset)
+    }
+
+    /// Every key in sorted order
+    pub fn iter(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.block_offsets.len()).flat_map(move |block| {
+            let mut keys = Vec::with_capacity(FRONT_CODING_BLOCK);
+            self.decode_block(block, &mut keys);
+            keys.into_iter()
+                .map(|key| String::from_utf8(key).expect("front-coded keys are valid UTF-8"))
+        })
+    }
+
+    /// Positions and keys of every key starting with `prefix`, in sorted order
+    pub fn with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (usize, String)> + 'a {
+        let prefix = prefix.as_bytes();
+        // Number of blocks whose head sorts before the prefix; the first match may be in the
+        // last of them
+        let (mut before, mut end) = (0, self.block_offsets.len());
+        while before < end {
+            let mid = (before + end) / 2;
+            if self.block_head(mid) < prefix {
+                before = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        (before.saturating_sub(1)..self.block_offsets.len())
+            .flat_map(move |block| {
+                let mut keys = Vec::with_capacity(FRONT_CODING_BLOCK);
+                self.decode_block(block, &mut keys);
+                keys.into_iter()
+                    .enumerate()
+                    .map(move |(offset, key)| (block * FRONT_CODING_BLOCK + offset, key))
+            })
+            .skip_while(move |(_, key)| key.as_slice() < prefix)
+            .take_while(move |(_, key)| key.starts_with(prefix))
+            .map(|(position, key)| {
+                let key = String::from_utf8(key).expect("front-coded keys are valid UTF-8");
+                (position, key)
+            })
+    }
+}
+
+/// Fewest and most words an `NGramPhraseIndex` can key its phrases by
+pub const MIN_PHRASE_WORDS: usize = 2;
+pub const MAX_PHRASE_WORDS: usize = 4;
+
+/// Documents and per-document counts of one indexed phrase during construction
+#[derive(Default)]
+struct PhrasePostings {
+    documents: Vec<u32>,
+    counts: Vec<u32>,
+    frequency: u32,
+}
+
+/// Phrase index keyed by runs of `n` consecutive words, with front-coded keys and delta + VB
+/// coded document ids. The last words of a document, too few for a full run, are keyed by
+/// the shorter runs they make, so every occurrence of every phrase of 2 to `n` words starts
+/// exactly one key: such phrases are answered and counted exactly, with no positions to
+/// verify. Longer phrases are matched by their `n`-word windows.
+///
+/// Saved as the `_bigram` structure, which with `n` = 2 it is.
+#[derive(Debug)]
+pub struct NGramPhraseIndex {
+    /// Sorted phrases ("first second ...")
+    pub keys: FrontCodedKeys,
+    /// Coded document ids of each phrase, parallel to `keys`
+    pub postings: Vec<Vec<u8>>,
+    /// Number of occurrences of each phrase across the collection, parallel to `keys`
+    pub frequencies: Vec<u32>,
+    /// VB coded occurrence counts of each phrase per document, parallel to its postings
+    pub document_frequencies: Vec<Vec<u8>>,
+    /// Document names indexed by the ids in `postings`
+    pub documents: Vec<String>,
+    /// Size the postings would take as document-name strings
+    pub uncompressed_postings_size: usize,
+    /// Words per key, from `MIN_PHRASE_WORDS` to `MAX_PHRASE_WORDS`
+    pub n: usize,
+    /// Posting blocks that failed their checksum on load; their phrases are missing until
+    /// `restore_phrases` rebuilds them
+    pub damaged_blocks: Vec<DamagedBlock>,
+}
+
+impl Persistable for NGramPhraseIndex {
+    const SUFFIX: &'static str = "_bigram";
+
+    fn format_version() -> u32 {
+        4
+    }
+
+    fn write_payload(&self, data: &mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        data.extend(self.payload()?);
+        Ok(())
+    }
+
+    fn read_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_payload(payload)
+    }
+}
+
+/// Coded document ids, coded per-document counts and collection frequency of one phrase
+type StoredPhrasePostings = (Vec<u8>, Vec<u8>, u32);
+
+/// Fields of `NGramPhraseIndex` kept in the posting block directory; the postings of every
+/// phrase go in the blocks, see `PostingBlocks`, and the keys are front-coded again on load
+#[derive(Serialize, Deserialize)]
+struct PhraseIndexMeta<'a> {
+    documents: Cow<'a, [String]>,
+    uncompressed_postings_size: usize,
+    n: usize,
+}
+
+/// Serialized as its posting block payload
+impl Serialize for NGramPhraseIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.payload().map_err(S::Error::custom)?)
+    }
+}
+
+impl<'de> Deserialize<'de> for NGramPhraseIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::from_payload(&Vec::<u8>::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl NGramPhraseIndex {
+    /// Bigram index, keyed by word pairs
+    pub fn from_dictionary_with_parser<F>(
+        dictionary: &CompressedDictionary,
+        file_parser: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        Self::from_dictionary_with_order(dictionary, MIN_PHRASE_WORDS, file_parser)
+    }
+
+    /// Index keyed by runs of `n` words
+    pub fn from_dictionary_with_order<F>(
+        dictionary: &CompressedDictionary,
+        n: usize,
+        file_parser: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        if !(MIN_PHRASE_WORDS..=MAX_PHRASE_WORDS).contains(&n) {
+            return Err(format!(
+                "Phrases must be {} to {} words long, got {}",
+                MIN_PHRASE_WORDS, MAX_PHRASE_WORDS, n
+            )
+            .into());
+        }
+        println!("    NGramPhraseIndex: Starting {}-word index construction", n);
+        let mut index: HashMap<String, PhrasePostings> = HashMap::new();
+        let mut documents = HashSet::new();
+
+        // Collect unique documents first to avoid duplicate processing
+        println!("    NGramPhraseIndex: Collecting unique documents");
+        for term_entry in &dictionary.term_entries {
+            for document in term_entry.documents.names(&dictionary.documents) {
+                documents.insert(document.clone());
+            }
+        }
+        let mut documents: Vec<String> = documents.into_iter().collect();
+        documents.sort();
+        println!(
+            "    NGramPhraseIndex: Found {} unique documents",
+            documents.len()
+        );
+
+        // Process each document only once
+        println!("    NGramPhraseIndex: Processing documents");
+        let mut uncompressed_postings_size = 0;
+        for (doc_id, document) in documents.iter().enumerate() {
+            let processed_count = doc_id + 1;
+            if processed_count % 10 == 0 {
+                println!(
+                    "    NGramPhraseIndex: Processed {}/{} documents",
+                    processed_count,
+                    documents.len()
+                );
+            }
+
+            let words = file_parser(document)?;
+
+            let mut phrase_count = 0;
+            for phrase in document_phrases(&words, n) {
+                let postings = index.entry(phrase).or_default();
+                postings.frequency += 1;
+                // Documents are visited in id order, so a repeat is always the last entry
+                if postings.documents.last() == Some(&(doc_id as u32)) {
+                    *postings.counts.last_mut().unwrap() += 1;
+                } else {
+                    postings.documents.push(doc_id as u32);
+                    postings.counts.push(1);
+                    uncompressed_postings_size += document.len();
+                }
+                phrase_count += 1;
+            }
+
+            if processed_count <= 5 || processed_count % 50 == 0 {
+                println!(
+                    "    NGramPhraseIndex: Document {} generated {} phrases",
+                    document, phrase_count
+                );
+            }
+        }
+
+        let index = Self::from_postings(index, documents, uncompressed_postings_size, n);
+        println!(
+            "    NGramPhraseIndex: Construction complete - {} phrases, {} documents",
+            index.keys.len(),
+            index.documents.len()
+        );
+        Ok(index)
+    }
+
+    /// Add documents not in the index yet, given with their words. Ids follow document name
+    /// order, so the postings of documents sorting after a new one are renumbered and every
+    /// list is re-encoded.
+    pub fn add_documents(&mut self, documents: &[(String, Vec<String>)]) {
+        let documents: Vec<&(String, Vec<String>)> = documents
+            .iter()
+            .filter(|(name, _)| self.documents.binary_search(name).is_err())
+            .collect();
+        let mut names: Vec<String> = self.documents.clone();
+        names.extend(documents.iter().map(|(name, _)| name.clone()));
+        names.sort();
+        names.dedup();
+        let id_of = |name: &str| {
+            names
+                .binary_search_by(|n| n.as_str().cmp(name))
+                .expect("every document is in the new name table") as u32
+        };
+        let renumbered: Vec<u32> = self.documents.iter().map(|name| id_of(name)).collect();
+
+        let mut index: HashMap<String, PhrasePostings> = HashMap::with_capacity(self.keys.len());
+        for (key_index, phrase) in self.keys.iter().enumerate() {
+            let postings = PhrasePostings {
+                documents: decode_delta_vb(&self.postings[key_index])
+                    .into_iter()
+                    .map(|id| renumbered[id as usize])
+                    .collect(),
+                counts: decode_vb(&self.document_frequencies[key_index]),
+                frequency: self.frequencies[key_index],
+            };
+            index.insert(phrase, postings);
+        }
+
+        let mut uncompressed_postings_size = self.uncompressed_postings_size;
+        for (name, words) in documents {
+            let id = id_of(name);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for phrase in document_phrases(words, self.n) {
+                *counts.entry(phrase).or_insert(0) += 1;
+            }
+            for (phrase, count) in counts {
+                let postings = index.entry(phrase).or_default();
+                postings.frequency += count;
+                postings.documents.push(id);
+                postings.counts.push(count);
+                uncompressed_postings_size += name.len();
+            }
+        }
+        for postings in index.values_mut() {
+            if !postings.documents.is_sorted() {
+                let mut pairs: Vec<(u32, u32)> = postings
+                    .documents
+                    .iter()
+                    .copied()
+                    .zip(postings.counts.iter().copied())
+                    .collect();
+                pairs.sort_unstable();
+                (postings.documents, postings.counts) = pairs.into_iter().unzip();
+            }
+        }
+
+        *self = Self::from_postings(index, names, uncompressed_postings_size, self.n);
+    }
+
+    /// Drop `documents` from every posting list, and phrases no other document has. The
+    /// remaining documents are renumbered densely, keeping name order.
+    pub fn remove_documents(&mut self, documents: &HashSet<String>) {
+        let mut new_ids = vec![None; self.documents.len()];
+        let mut names = Vec::with_capacity(self.documents.len());
+        for (id, name) in self.documents.iter().enumerate() {
+            if !documents.contains(name) {
+                new_ids[id] = Some(names.len() as u32);
+                names.push(name.clone());
+            }
+        }
+
+        let mut index: HashMap<String, PhrasePostings> = HashMap::with_capacity(self.keys.len());
+        let mut uncompressed_postings_size = self.uncompressed_postings_size;
+        for (key_index, phrase) in self.keys.iter().enumerate() {
+            let mut postings = PhrasePostings::default();
+            let ids = decode_delta_vb(&self.postings[key_index]);
+            let counts = decode_vb(&self.document_frequencies[key_index]);
+            for (id, count) in ids.into_iter().zip(counts) {
+                match new_ids[id as usize] {
+                    Some(new_id) => {
+                        postings.documents.push(new_id);
+                        postings.counts.push(count);
+                        postings.frequency += count;
+                    }
+                    None => uncompressed_postings_size -= self.documents[id as usize].len(),
+                }
+            }
+            if !postings.documents.is_empty() {
+                index.insert(phrase, postings);
+            }
+        }
+
+        *self = Self::from_postings(index, names, uncompressed_postings_size, self.n);
+    }
+
+    /// Rebuild the postings of the phrases lost with `damaged_blocks` from the words of every
+    /// document in `forward_index`, saved with the index. Returns the number of phrases
+    /// restored.
+    pub fn restore_phrases(&mut self, forward_index: &ForwardIndex) -> usize {
+        let damaged = std::mem::take(&mut self.damaged_blocks);
+        let mut index: HashMap<String, PhrasePostings> = HashMap::with_capacity(self.keys.len());
+        for (key_index, phrase) in self.keys.iter().enumerate() {
+            let postings = PhrasePostings {
+                documents: decode_delta_vb(&self.postings[key_index]),
+                counts: decode_vb(&self.document_frequencies[key_index]),
+                frequency: self.frequencies[key_index],
+            };
+            index.insert(phrase, postings);
+        }
+
+        // The saved size still counts the lost postings, so it is kept as it is
+        let mut restored = HashSet::new();
+        for (doc_id, document) in self.documents.iter().enumerate() {
+            let Some(words) = forward_index.words(document) else {
+                continue;
+            };
+            for phrase in document_phrases(&words, self.n) {
+                if !damaged.iter().any(|block| block.covers(&phrase)) {
+                    continue;
+                }
+                let postings = index.entry(phrase.clone()).or_default();
+                postings.frequency += 1;
+                // Documents are visited in id order, so a repeat is always the last entry
+                if postings.documents.last() == Some(&(doc_id as u32)) {
+                    *postings.counts.last_mut().unwrap() += 1;
+                } else {
+                    postings.documents.push(doc_id as u32);
+                    postings.counts.push(1);
+                }
+                restored.insert(phrase);
+            }
+        }
+
+        let documents = std::mem::take(&mut self.documents);
+        *self = Self::from_postings(index, documents, self.uncompressed_postings_size, self.n);
+        restored.len()
+    }
+
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let meta = PhraseIndexMeta {
+            documents: Cow::Borrowed(&self.documents),
+            uncompressed_postings_size: self.uncompressed_postings_size,
+            n: self.n,
+        };
+        write_posting_blocks(
+            &meta,
+            self.keys.iter().enumerate().map(|(key_index, phrase)| {
+                let postings = (
+                    &self.postings[key_index],
+                    &self.document_frequencies[key_index],
+                    self.frequencies[key_index],
+                );
+                (phrase, postings)
+            }),
+        )
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let stored: PostingBlocks<PhraseIndexMeta, StoredPhrasePostings> =
+            read_posting_blocks(payload, "the phrase index")?;
+        let keys: Vec<&String> = stored.entries.iter().map(|(phrase, _)| phrase).collect();
+        let keys = FrontCodedKeys::from_sorted(&keys);
+        let mut postings = Vec::with_capacity(stored.entries.len());
+        let mut document_frequencies = Vec::with_capacity(stored.entries.len());
+        let mut frequencies = Vec::with_capacity(stored.entries.len());
+        for (_, (documents, counts, frequency)) in stored.entries {
+            postings.push(documents);
+            document_frequencies.push(counts);
+            frequencies.push(frequency);
+        }
+        Ok(NGramPhraseIndex {
+            keys,
+            postings,
+            frequencies,
+            document_frequencies,
+            documents: stored.meta.documents.into_owned(),
+            uncompressed_postings_size: stored.meta.uncompressed_postings_size,
+            n: stored.meta.n,
+            damaged_blocks: stored.damaged,
+        })
+    }
+
+    /// Front-code the keys and encode the postings of phrases whose document ids are sorted
+    fn from_postings(
+        index: HashMap<String, PhrasePostings>,
+        documents: Vec<String>,
+        uncompressed_postings_size: usize,
+        n: usize,
+    ) -> Self {
+        println!("    NGramPhraseIndex: Compressing posting lists in parallel");
+        let mut entries: Vec<(String, PhrasePostings)> = index.into_iter().collect();
+        entries.par_sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let keys: Vec<&String> = entries.iter().map(|(phrase, _)| phrase).collect();
+        let keys = FrontCodedKeys::from_sorted(&keys);
+        let coded: Vec<(Vec<u8>, Vec<u8>, u32)> = entries
+            .into_par_iter()
+            .map(|(_, postings)| {
+                let counts = postings.counts.into_iter().flat_map(encode_vb).collect();
+                (encode_delta_vb(postings.documents), counts, postings.frequency)
+            })
+            .collect();
+        let mut postings = Vec::with_capacity(coded.len());
+        let mut document_frequencies = Vec::with_capacity(coded.len());
+        let mut frequencies = Vec::with_capacity(coded.len());
+        for (documents, counts, frequency) in coded {
+            postings.push(documents);
+            document_frequencies.push(counts);
+            frequencies.push(frequency);
+        }
+
+        NGramPhraseIndex {
+            keys,
+            postings,
+            frequencies,
+            document_frequencies,
+            documents,
+            uncompressed_postings_size,
+            n,
+            damaged_blocks: Vec::new(),
+        }
+    }
+
+    /// Number of distinct indexed phrases
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Number of times the phrase occurs in the collection. Phrases longer than `n` words get
+    /// the count of their rarest window, an upper bound on the true count.
+    pub fn frequency(&self, phrase: &str) -> u32 {
+        let words: Vec<String> = phrase.split_whitespace().map(str::to_string).collect();
+        if words.len() < MIN_PHRASE_WORDS {
+            return 0;
+        }
+        words
+            .windows(words.len().min(self.n))
+            .map(|window| {
+                self.key_positions(window)
+                    .into_iter()
+                    .map(|key| self.frequencies[key])
+                    .sum()
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Every indexed phrase with its number of occurrences, in sorted order
+    pub fn iter_frequencies(&self) -> impl Iterator<Item = (String, u32)> + '_ {
+        self.keys.iter().zip(self.frequencies.iter().copied())
+    }
+
+    /// Every word pair with its number of occurrences, in sorted order. Each occurrence of a
+    /// pair starts exactly one key, so its count adds up those of the keys it begins.
+    pub fn pair_frequencies(&self) -> Vec<(String, u32)> {
+        if self.n == MIN_PHRASE_WORDS {
+            return self.iter_frequencies().collect();
+        }
+        let mut pairs: BTreeMap<String, u32> = BTreeMap::new();
+        for (phrase, frequency) in self.iter_frequencies() {
+            let pair = match phrase.match_indices(' ').nth(1) {
+                Some((end, _)) => phrase[..end].to_string(),
+                None => phrase,
+            };
+            *pairs.entry(pair).or_insert(0) += frequency;
+        }
+        pairs.into_iter().collect()
+    }
+
+    /// Positions in `keys` of the keys a phrase of 2 to `n` words starts: the phrase itself,
+    /// or when shorter, every key it is a whole-word prefix of
+    fn key_positions(&self, words: &[String]) -> Vec<usize> {
+        let phrase = words.join(" ");
+        if words.len() == self.n {
+            return self.keys.find(&phrase).into_iter().collect();
+        }
+        self.keys
+            .with_prefix(&phrase)
+            .filter(|(_, key)| key.len() == phrase.len() || key.as_bytes()[phrase.len()] == b' ')
+            .map(|(position, _)| position)
+            .collect()
+    }
+
+    /// Ids of the documents containing a phrase of 2 to `n` words
+    fn phrase_document_ids(&self, words: &[String]) -> HashSet<u32> {
+        self.key_positions(words)
+            .into_iter()
+            .flat_map(|key| decode_delta_vb(&self.postings[key]))
+            .collect()
+    }
+
+    /// (uncompressed, compressed, ratio) sizes of the keys and postings together
+    pub fn compression_stats(&self) -> (usize, usize, f64) {
+        let original = self.keys.uncompressed_size + self.uncompressed_postings_size;
+        let compressed =
+            self.keys.compressed_size() + self.postings.iter().map(Vec::len).sum::<usize>();
+        let ratio = if original > 0 {
+            compressed as f64 / original as f64
+        } else {
+            1.0
+        };
+        (original, compressed, ratio)
+    }
+
+    /// Re-encode every posting list sorted and deduplicated, adding up the counts of
+    /// duplicate documents
+    pub fn optimize(&mut self) {
+        self.postings
+            .par_iter_mut()
+            .zip(self.document_frequencies.par_iter_mut())
+            .for_each(|(postings, counts)| {
+                let mut entries: Vec<(u32, u32)> = decode_delta_vb(postings)
+                    .into_iter()
+                    .zip(decode_vb(counts))
+                    .collect();
+                entries.sort_unstable();
+                let mut merged: Vec<(u32, u32)> = Vec::with_capacity(entries.len());
+                for (id, count) in entries {
+                    match merged.last_mut() {
+                        Some((last, total)) if *last == id => *total += count,
+                        _ => merged.push((id, count)),
+                    }
+                }
+                *postings = encode_delta_vb(merged.iter().map(|&(id, _)| id).collect());
+                *counts = merged.iter().flat_map(|&(_, count)| encode_vb(count)).collect();
+            });
+    }
+
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.keys.compressed_size()
+            + self
+                .postings
+                .iter()
+                .chain(&self.document_frequencies)
+                .map(|p| p.len() + std::mem::size_of::<Vec<u8>>())
+                .sum::<usize>()
+            + self.frequencies.len() * std::mem::size_of::<u32>()
+            + self.documents.iter().map(|d| d.len()).sum::<usize>()
+    }
+
+    /// Occurrences of a phrase of 2 to `n` words in the document with id `document`
+    fn phrase_document_frequency(&self, words: &[String], document: u32) -> u32 {
+        self.key_positions(words)
+            .into_iter()
+            .map(|key| {
+                decode_delta_vb(&self.postings[key])
+                    .binary_search(&document)
+                    .map_or(0, |position| {
+                        decode_vb(&self.document_frequencies[key])[position]
+                    })
+            })
+            .sum()
+    }
+
+    /// Occurrences of a phrase in `document`. Phrases longer than `n` words get the count of
+    /// their rarest window, an upper bound on the true count.
+    pub fn phrase_freq(&self, phrase: &str, document: &str) -> u32 {
+        let Ok(document) = self.documents.binary_search_by(|name| name.as_str().cmp(document))
+        else {
+            return 0;
+        };
+        let windows = self.phrase_windows(phrase);
+        if windows.is_empty() {
+            return 0;
+        }
+        windows
+            .iter()
+            .map(|window| self.phrase_document_frequency(window, document as u32))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Rank documents by the TF-IDF weight of a phrase, highest first: sublinear
+    /// `phrase_freq` times the idf of the documents containing the phrase
+    pub fn rank_phrase<'a, I>(&self, phrase: &str, documents: I) -> Vec<(&'a str, f64)>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let document_frequency = self.search_phrase(phrase).map_or(0, |docs| docs.len());
+        let weight = idf(self.documents.len(), document_frequency);
+        let mut ranked: Vec<(&str, f64)> = documents
+            .into_iter()
+            .map(|document| {
+                let score = tf_weight(self.phrase_freq(phrase, document)) * weight;
+                (document, score)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        ranked
+    }
+
+    /// Documents containing the phrase, or for phrases longer than `n` words every window of
+    /// it; unindexed short words are skipped
+    pub fn search_phrase(&self, phrase: &str) -> Result<HashSet<String>, String> {
+        let windows = self.phrase_windows(phrase);
+        if windows.is_empty() {
+            return Err("Phrase must contain at least two words".to_string());
+        }
+
+        let mut result: Option<HashSet<u32>> = None;
+
+        for window in windows {
+            let ids = self.phrase_document_ids(&window);
+            if ids.is_empty() {
+                return Ok(HashSet::new());
+            }
+            result = Some(match result {
+                None => ids,
+                Some(mut existing) => {
+                    existing.retain(|id| ids.contains(id));
+                    existing
+                }
+            });
+        }
+
+        Ok(result
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| self.documents[id as usize].clone())
+            .collect())
+    }
+
+    /// The phrase whole when it has at most `n` words, its `n`-word windows otherwise;
+    /// lowercased, with unindexed short words skipped
+    fn phrase_windows(&self, phrase: &str) -> Vec<Vec<String>> {
+        let words: Vec<String> = phrase
+            .split_whitespace()
+            .map(raw_term)
+            .filter(|w| is_indexable_word(w))
+            .map(|w| w.to_lowercase())
+            .collect();
+        if words.len() < MIN_PHRASE_WORDS {
+            return Vec::new();
+        }
+        words
+            .windows(words.len().min(self.n))
+            .map(<[String]>::to_vec)
+            .collect()
+    }
+}
+
+/// Keys of a document's words: every run of `n` words, then the shorter runs of at least two
+/// words that end the document
+fn document_phrases(words: &[String], n: usize) -> impl Iterator<Item = String> + '_ {
+    (0..words.len()).filter_map(move |start| {
+        let end = (start + n).min(words.len());
+        (end - start >= MIN_PHRASE_WORDS).then(|| words[start..end].join(" "))
+    })
+}
+
+impl QueryParser for NGramPhraseIndex {
+    type Result = HashSet<String>;
+    type Error = String;
+    const CAPABILITIES: Capabilities = Capabilities::PHRASES
+        .union(Capabilities::NOT)
+        .union(Capabilities::RANKING);
+
+    fn search(&self, query: &str) -> Result<Self::Result, Self::Error> {
+        self.evaluate(&QueryAst::parse(query)?)
+    }
+
+    fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error> {
+        Err(format!(
+            "Phrase index doesn't support single term search: '{}'",
+            term
+        ))
+    }
+}
+
+impl QueryEvaluator for NGramPhraseIndex {
+    type Output = HashSet<String>;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.search_term(term)
+    }
+
+    fn evaluate_phrase(&self, words: &[String]) -> Result<Self::Output, String> {
+        if words.len() < MIN_PHRASE_WORDS {
+            return Err("Phrase must contain at least two words".to_string());
+        }
+        self.search_phrase(&words.join(" "))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        left.intersection(&right).cloned().collect()
+    }
+
+    fn unite(&self, mut left: Self::Output, right: Self::Output) -> Self::Output {
+        left.extend(right);
+        left
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.documents
+            .iter()
+            .filter(|document| !operand.contains(*document))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::Dictionary;
+    use crate::tokenize_plain_text_with_offsets;
+
+    #[test]
+    fn test_front_coded_keys_round_trip() {
+        let keys: Vec<String> = (0..20).map(|i| format!("war and peace {:02}", i)).collect();
+        let coded = FrontCodedKeys::from_sorted(&keys);
+
+        assert_eq!(coded.iter().collect::<Vec<_>>(), keys);
+        assert!(coded.compressed_size() < coded.uncompressed_size);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(coded.find(key), Some(i));
+        }
+        assert_eq!(coded.find("a"), None);
+        assert_eq!(coded.find("war and peace 05x"), None);
+        assert_eq!(coded.find("zzz"), None);
+    }
+
+    #[test]
+    fn test_compressed_phrase_search() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = NGramPhraseIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "war and peace and war and peace",
+                "doc2" => "peace and war",
+                _ => "and peace",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap();
+
+        let mut docs: Vec<String> = index
+            .search("\"war and peace\"")
+            .unwrap()
+            .into_iter()
+            .collect();
+        docs.sort();
+        assert_eq!(docs, vec!["doc1"]);
+        assert_eq!(index.frequency("and peace"), 3);
+        assert_eq!(index.frequency("peace war"), 0);
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_phrase_frequencies_rank_documents() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let mut index = NGramPhraseIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "war and peace",
+                "doc2" => "war and peace and war and peace and war",
+                _ => "peace and war",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap();
+        index.optimize();
+
+        assert_eq!(index.phrase_freq("peace and", "doc2"), 2);
+        assert_eq!(index.phrase_freq("war and peace", "doc2"), 2);
+        assert_eq!(index.phrase_freq("war and peace", "doc3"), 0);
+        assert_eq!(index.phrase_freq("war and peace", "missing"), 0);
+
+        let ranked = index.rank_phrase("war and peace", ["doc1", "doc2"]);
+        assert_eq!(ranked[0].0, "doc2");
+        assert!(ranked[0].1 > ranked[1].1);
+    }
+
+    #[test]
+    fn test_longer_keys_answer_shorter_phrases_exactly() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let text = |doc: &str| match doc {
+            "doc1" => "war and peace and war",
+            "doc2" => "peace and war and peace",
+            _ => "and war",
+        };
+        let mut index = NGramPhraseIndex::from_dictionary_with_order(&compressed, 3, |doc| {
+            Ok(text(doc).split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap();
+        assert!(NGramPhraseIndex::from_dictionary_with_order(&compressed, 5, |_| Ok(Vec::new()))
+            .is_err());
+
+        let keys: Vec<String> = index.keys.with_prefix("and war").map(|(_, key)| key).collect();
+        assert_eq!(keys, ["and war", "and war and"]);
+        // "and war" ends doc1 and makes up doc3, so only tail keys hold it there
+        assert_eq!(index.frequency("and war"), 3);
+        assert_eq!(index.phrase_freq("and war", "doc1"), 1);
+        assert_eq!(index.phrase_freq("and war", "doc3"), 1);
+        assert_eq!(index.frequency("war and peace"), 2);
+        assert_eq!(index.frequency("peace and war and"), 1);
+        assert_eq!(
+            found(index.search_phrase("and war").unwrap()),
+            ["doc1", "doc2", "doc3"]
+        );
+        assert_eq!(
+            found(index.search_phrase("war and peace").unwrap()),
+            ["doc1", "doc2"]
+        );
+        assert_eq!(
+            found(index.search_phrase("peace and war and peace").unwrap()),
+            ["doc2"]
+        );
+
+        let pairs: HashMap<String, u32> = index.pair_frequencies().into_iter().collect();
+        assert_eq!(pairs["and war"], 3);
+        assert_eq!(pairs["war and"], 2);
+        assert_eq!(pairs.len(), 4);
+
+        index.add_documents(&[("doc0".to_string(), vec!["war".into(), "and".into()])]);
+        assert_eq!(index.n, 3);
+        assert_eq!(index.frequency("war and"), 3);
+        assert_eq!(found(index.search_phrase("war and").unwrap()).len(), 3);
+    }
+
+    fn found(documents: HashSet<String>) -> Vec<String> {
+        let mut documents: Vec<String> = documents.into_iter().collect();
+        documents.sort();
+        documents
+    }
+
+    #[test]
+    fn test_optimize_keeps_phrase_results() {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let mut index = NGramPhraseIndex::from_dictionary_with_order(&compressed, 3, |doc| {
+            let text = match doc {
+                "doc1" => "war and peace and war and peace",
+                "doc2" => "peace and war and peace",
+                _ => "and peace",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap();
+
+        let queries = [
+            "\"war and peace\"",
+            "\"and peace\"",
+            "\"peace and war\" or \"and peace\"",
+        ];
+        let results = |index: &NGramPhraseIndex| -> Vec<Vec<String>> {
+            queries
+                .iter()
+                .map(|query| {
+                    let mut docs: Vec<String> = index.search(query).unwrap().into_iter().collect();
+                    docs.sort();
+                    docs
+                })
+                .collect()
+        };
+        let before = results(&index);
+        let frequency = index.phrase_freq("war and peace", "doc1");
+        index.optimize();
+
+        assert_eq!(results(&index), before);
+        assert_eq!(index.phrase_freq("war and peace", "doc1"), frequency);
+        assert_eq!(frequency, 2);
+    }
+
+    #[test]
+    fn test_damaged_posting_blocks_are_restored_from_forward_index() {
+        // Words "waa" to "wln", enough phrases for three blocks
+        let words: Vec<String> = (0..300u16)
+            .map(|i| {
+                let (first, second) = (b'a' + (i / 26) as u8, b'a' + (i % 26) as u8);
+                format!("w{}{}", char::from(first), char::from(second))
+            })
+            .collect();
+        let texts = |doc: &str| match doc {
+            "doc1" => words.join(" "),
+            _ => words.iter().rev().step_by(2).cloned().collect::<Vec<_>>().join(" "),
+        };
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2"] {
+            dict.add_term("waa".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = NGramPhraseIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let tokens = tokenize_plain_text_with_offsets(&texts(doc));
+            Ok(tokens.into_iter().map(|(word, _, _)| word).collect())
+        })
+        .unwrap();
+        let forward_index =
+            ForwardIndex::from_documents_with_tokenizer(&compressed.documents, |doc| {
+                Ok(tokenize_plain_text_with_offsets(&texts(doc)))
+            })
+            .unwrap();
+
+        let mut data = index.to_bytes().unwrap();
+        let offset = data.windows(7).position(|window| window == b"whr whs").unwrap();
+        data[offset] ^= 0xff;
+        let mut damaged = NGramPhraseIndex::from_bytes(&data).unwrap();
+        assert_eq!(damaged.damaged_blocks.len(), 1);
+        assert!(damaged.len() < index.len());
+        assert!(damaged.search("\"whr whs\"").unwrap().is_empty());
+
+        assert!(damaged.restore_phrases(&forward_index) > 0);
+        let restored = NGramPhraseIndex::from_bytes(&damaged.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.len(), index.len());
+        assert_eq!(restored.uncompressed_postings_size, index.uncompressed_postings_size);
+        for query in ["\"whr whs\"", "\"whs whq\"", "\"waa wab\" or \"wlm wlk\""] {
+            assert_eq!(restored.search(query).unwrap(), index.search(query).unwrap());
+        }
+        assert_eq!(restored.phrase_freq("whr whs", "doc1"), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// Default aliases: Russian and Ukrainian spellings of the Boolean operators and of near/N
+const DEFAULT_ALIASES: &[(&str, Operator)] = &[
+    ("и", Operator::And),
+    ("і", Operator::And),
+    ("или", Operator::Or),
+    ("або", Operator::Or),
+    ("не", Operator::Not),
+    ("рядом", Operator::Near),
+    ("поруч", Operator::Near),
+];
+
+static CURRENT: LazyLock<RwLock<Arc<OperatorAliases>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(OperatorAliases::default())));
+
+/// Query operators a word can stand for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    And,
+    Or,
+    Not,
+    /// Takes the distance after a slash, as in `near/5`
+    Near,
+}
+
+impl Operator {
+    /// How the query parsers spell the operator
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Operator::And => "and",
+            Operator::Or => "or",
+            Operator::Not => "not",
+            Operator::Near => "near",
+        }
+    }
+
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword {
+            "and" => Some(Operator::And),
+            "or" => Some(Operator::Or),
+            "not" => Some(Operator::Not),
+            "near" => Some(Operator::Near),
+            _ => None,
+        }
+    }
+}
+
+/// Words read as query operators besides the English keywords, e.g. `или` for `or` and
+/// `рядом/5` for `near/5`. The tokenizer rewrites them outside phrases, so every structure
+/// accepts them. Set once per process with `OperatorAliases::install`; an alias is searched
+/// for as a word when escaped (`\\и`) or quoted on its own (`"и"`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorAliases {
+    aliases: HashMap<String, Operator>,
+}
+
+impl Default for OperatorAliases {
+    fn default() -> Self {
+        OperatorAliases {
+            aliases: DEFAULT_ALIASES
+                .iter()
+                .map(|(alias, operator)| (alias.to_string(), *operator))
+                .collect(),
+        }
+    }
+}
+
+impl OperatorAliases {
+    /// No aliases: only the English keywords are operators
+    pub fn none() -> Self {
+        OperatorAliases {
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Load aliases from a file of `alias=operator` lines, where the operator is `and`, `or`,
+    /// `not` or `near`; `#` starts a comment. Entries are added to the defaults.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut aliases = Self::default();
+        for (line_number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (alias, keyword) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{}:{}: expected alias=operator", path, line_number + 1))?;
+            let operator =
+                Operator::from_keyword(&keyword.trim().to_lowercase()).ok_or_else(|| {
+                    format!(
+                        "{}:{}: unknown operator '{}', expected and, or, not or near",
+                        path,
+                        line_number + 1,
+                        keyword.trim()
+                    )
+                })?;
+            let alias = alias.trim().to_lowercase();
+            if alias.is_empty() || alias.contains([' ', '/', '(', ')', '"']) {
+                return Err(
+                    format!("{}:{}: invalid alias '{}'", path, line_number + 1, alias).into(),
+                );
+            }
+            aliases.insert(&alias, operator);
+        }
+        Ok(aliases)
+    }
+
+    /// `default` keeps the built-in aliases, `none` turns aliases off and anything else is an
+    /// alias file
+    pub fn from_setting(setting: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match setting {
+            "default" => Ok(Self::default()),
+            "none" => Ok(Self::none()),
+            path => Self::from_file(path),
+        }
+    }
+
+    pub fn insert(&mut self, alias: &str, operator: Operator) {
+        self.aliases.insert(alias.to_lowercase(), operator);
+    }
+
+    /// The installed aliases, the defaults until others are installed
+    pub fn current() -> Arc<OperatorAliases> {
+        CURRENT
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Use these aliases for every query parsed from now on
+    pub fn install(self) {
+        *CURRENT
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(self);
+    }
+
+    /// The English keyword for a lowercase alias token, `near/N` for a near alias with a
+    /// distance, or `None` when the token is no alias
+    pub fn canonical(&self, token: &str) -> Option<String> {
+        match token.split_once('/') {
+            Some((alias, distance)) => match self.aliases.get(alias) {
+                Some(Operator::Near) => Some(format!("near/{}", distance)),
+                _ => None,
+            },
+            None => match self.aliases.get(token) {
+                Some(Operator::Near) | None => None,
+                Some(operator) => Some(operator.keyword().to_string()),
+            },
+        }
+    }
+
+    /// Aliases and the operators they stand for, sorted by alias
+    pub fn entries(&self) -> Vec<(&str, Operator)> {
+        let mut entries: Vec<(&str, Operator)> = self
+            .aliases
+            .iter()
+            .map(|(alias, operator)| (alias.as_str(), *operator))
+            .collect();
+        entries.sort_unstable_by_key(|(alias, _)| *alias);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{query_terms, tokenize, QueryAst};
+
+    #[test]
+    fn test_aliases_parse_like_keywords() {
+        let parse = |query: &str| QueryAst::parse(query).unwrap();
+        assert_eq!(
+            parse("война И (мир ИЛИ любовь) и НЕ ненависть"),
+            parse("война and (мир or любовь) and not ненависть")
+        );
+        assert_eq!(
+            parse("рядом/5(анна поезд) або поруч/2(мир война)"),
+            parse("near/5(анна поезд) or near/2(мир война)")
+        );
+
+        // Phrases, escaped and quoted aliases stay words
+        assert_eq!(
+            tokenize("\"война и мир\" и \\или или \"не\"").unwrap(),
+            vec![
+                "\"",
+                "война",
+                "и",
+                "мир",
+                "\"",
+                "and",
+                "\\или",
+                "or",
+                "\\не"
+            ]
+        );
+        assert_eq!(
+            query_terms("мир и не война").unwrap(),
+            vec!["мир".to_string()]
+        );
+        assert_eq!(OperatorAliases::default().canonical("рядом"), None);
+        assert_eq!(OperatorAliases::none().canonical("и"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.txt");
+        fs::write(&path, "# German\nund = and\nnah=NEAR\n").unwrap();
+        let aliases = OperatorAliases::from_file(&path.to_string_lossy()).unwrap();
+        assert_eq!(aliases.canonical("und"), Some("and".to_string()));
+        assert_eq!(aliases.canonical("nah/3"), Some("near/3".to_string()));
+        assert_eq!(aliases.canonical("или"), Some("or".to_string()));
+
+        fs::write(&path, "und = xor\n").unwrap();
+        assert!(OperatorAliases::from_file(&path.to_string_lossy()).is_err());
+    }
+}
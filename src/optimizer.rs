@@ -0,0 +1,161 @@
+use crate::estimate::estimate_query;
+use crate::query::QueryAst;
+
+/// Reorder the operands of every AND chain, however it was parenthesized, by ascending
+/// estimated hit count, so intersections start from the rarest operand and the lists carried
+/// between them stay short. Negated operands go last: `not b and a` becomes `a and not b`,
+/// which evaluators subtract from `a` instead of materializing the complement of `b`. A
+/// chain of negations only keeps its first complement. Estimates assume independent terms,
+/// see `estimate_hits`; `document_frequency` returns the documents containing a term.
+pub fn optimize_query<F>(
+    query: QueryAst,
+    total_documents: usize,
+    document_frequency: &mut F,
+) -> QueryAst
+where
+    F: FnMut(&str) -> usize,
+{
+    match query {
+        QueryAst::And(..) => {
+            let mut operands = Vec::new();
+            flatten_and(query, &mut operands);
+            let mut operands: Vec<(bool, usize, QueryAst)> = operands
+                .into_iter()
+                .map(|operand| {
+                    let operand = optimize_query(operand, total_documents, document_frequency);
+                    let hits = estimate(&operand, total_documents, document_frequency);
+                    (matches!(operand, QueryAst::Not(_)), hits, operand)
+                })
+                .collect();
+            operands.sort_by_key(|&(negated, hits, _)| (negated, hits));
+            operands
+                .into_iter()
+                .map(|(_, _, operand)| operand)
+                .reduce(|left, right| QueryAst::And(Box::new(left), Box::new(right)))
+                .expect("an AND has operands")
+        }
+        QueryAst::Or(left, right) => QueryAst::Or(
+            Box::new(optimize_query(*left, total_documents, document_frequency)),
+            Box::new(optimize_query(*right, total_documents, document_frequency)),
+        ),
+        QueryAst::Not(operand) => QueryAst::Not(Box::new(optimize_query(
+            *operand,
+            total_documents,
+            document_frequency,
+        ))),
+        query => query,
+    }
+}
+
+/// The evaluation order of an optimized query as an indented tree, one node per line with
+/// its estimated hit count; `a and not b` shows as the difference it is evaluated as
+pub fn explain_query<F>(
+    query: &QueryAst,
+    total_documents: usize,
+    document_frequency: &mut F,
+) -> Vec<String>
+where
+    F: FnMut(&str) -> usize,
+{
+    let mut lines = Vec::new();
+    explain_node(query, 0, total_documents, document_frequency, &mut lines);
+    lines
+}
+
+fn explain_node<F>(
+    query: &QueryAst,
+    depth: usize,
+    total_documents: usize,
+    document_frequency: &mut F,
+    lines: &mut Vec<String>,
+) where
+    F: FnMut(&str) -> usize,
+{
+    let hits = estimate(query, total_documents, document_frequency);
+    let (label, children): (String, Vec<&QueryAst>) = match query {
+        QueryAst::Term(term) => (format!("term {}", term), Vec::new()),
+        QueryAst::Wildcard(pattern) => (format!("pattern {}", pattern), Vec::new()),
+        QueryAst::Phrase(words) => (format!("phrase \"{}\"", words.join(" ")), Vec::new()),
+        QueryAst::Near { distance, operands } => {
+            (format!("near/{}", distance), operands.iter().collect())
+        }
+        QueryAst::And(left, right) => match right.as_ref() {
+            QueryAst::Not(subtracted) => ("AND NOT".to_string(), vec![left, subtracted]),
+            _ => ("AND".to_string(), vec![left, right]),
+        },
+        QueryAst::Or(left, right) => ("OR".to_string(), vec![left, right]),
+        QueryAst::Not(operand) => ("NOT".to_string(), vec![operand]),
+    };
+    lines.push(format!(
+        "{}{} (~{} documents)",
+        "  ".repeat(depth),
+        label,
+        hits
+    ));
+    for child in children {
+        explain_node(child, depth + 1, total_documents, document_frequency, lines);
+    }
+}
+
+/// Operands of nested ANDs, left to right
+fn flatten_and(query: QueryAst, operands: &mut Vec<QueryAst>) {
+    match query {
+        QueryAst::And(left, right) => {
+            flatten_and(*left, operands);
+            flatten_and(*right, operands);
+        }
+        operand => operands.push(operand),
+    }
+}
+
+fn estimate<F>(query: &QueryAst, total_documents: usize, document_frequency: &mut F) -> usize
+where
+    F: FnMut(&str) -> usize,
+{
+    estimate_query(query, total_documents, &mut |term| {
+        Ok(document_frequency(term))
+    })
+    .expect("frequencies are infallible")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_and_operands_ordered_by_selectivity() {
+        let mut df = |term: &str| match term {
+            "war" => 50,
+            "peace" => 20,
+            "anna" => 5,
+            "love" => 30,
+            _ => 0,
+        };
+        let optimize = |query: &str, df: &mut dyn FnMut(&str) -> usize| {
+            optimize_query(QueryAst::parse(query).unwrap(), 100, &mut |term| df(term))
+        };
+
+        assert_eq!(
+            optimize("not love and war and (peace and anna)", &mut df),
+            QueryAst::parse("anna and peace and war and not love").unwrap()
+        );
+        assert_eq!(
+            optimize("war or (peace and anna)", &mut df),
+            QueryAst::parse("war or (anna and peace)").unwrap()
+        );
+        assert_eq!(
+            optimize("not war and not anna", &mut df),
+            QueryAst::parse("not war and not anna").unwrap()
+        );
+
+        let plan = optimize("not love and war", &mut df);
+        assert_eq!(
+            explain_query(&plan, 100, &mut df),
+            [
+                "AND NOT (~35 documents)",
+                "  term war (~50 documents)",
+                "  term love (~30 documents)",
+            ]
+        );
+    }
+}
@@ -1,8 +1,20 @@
-use arrow::array::{Array, Int64Array, StringArray};
+use arrow::array::{Array, ArrayRef, Int64Array, StringArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Schema};
 use arrow::record_batch::RecordBatch;
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use crossbeam_channel::Sender;
+use rayon::prelude::*;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use walkdir::WalkDir;
+
+use crate::{CorpusError, DocumentSample, EmptyReason};
+
+type SendError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug, Clone)]
 pub struct ParquetDocument {
@@ -11,143 +23,450 @@ pub struct ParquetDocument {
     pub metadata: Option<String>,
 }
 
+/// Which columns documents are read from. Columns left unset are guessed from their names:
+/// text from a column named like `text`, `content` or `body`, ids from one named like `id`
+/// and metadata from ones named like `title`, `subject` or `category`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnMapping {
+    pub text: Option<String>,
+    pub id: Option<String>,
+    /// Metadata columns; values of several are joined with `; `
+    pub metadata: Vec<String>,
+}
+
+impl ColumnMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_text(mut self, column: &str) -> Self {
+        self.text = Some(column.to_string());
+        self
+    }
+
+    pub fn with_id(mut self, column: &str) -> Self {
+        self.id = Some(column.to_string());
+        self
+    }
+
+    pub fn with_metadata<S: AsRef<str>>(mut self, columns: &[S]) -> Self {
+        self.metadata = columns.iter().map(|column| column.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Column indexes in `schema`, checking every column has a type documents can be read
+    /// from: strings for text and metadata, strings or integers for ids
+    fn resolve(&self, schema: &Schema, source: &str) -> Result<DocumentColumns, CorpusError> {
+        let mismatch = |problem: String| CorpusError::SchemaMismatch {
+            source: source.to_string(),
+            columns: schema
+                .fields()
+                .iter()
+                .map(|field| (field.name().clone(), field.data_type().to_string()))
+                .collect(),
+            problem,
+        };
+        let find = |role: &str, name: &str| {
+            schema
+                .index_of(name)
+                .map_err(|_| mismatch(format!("no {} column named {}", role, name)))
+        };
+
+        let mut text = self.text.as_deref().map(|name| find("text", name)).transpose()?;
+        let mut id = self.id.as_deref().map(|name| find("id", name)).transpose()?;
+        let mut metadata = self
+            .metadata
+            .iter()
+            .map(|name| find("metadata", name))
+            .collect::<Result<Vec<usize>, _>>()?;
+
+        // Guess the columns left unset from their names, then fall back to string columns
+        let guess_metadata = self.metadata.is_empty();
+        let mut guessed_text = None;
+        for (i, field) in schema.fields().iter().enumerate() {
+            if Some(i) == text || Some(i) == id || metadata.contains(&i) {
+                continue;
+            }
+            let name = field.name().to_lowercase();
+            if name.contains("text") || name.contains("content") || name.contains("body") {
+                guessed_text = Some(i);
+            } else if name.contains("id") && id.is_none() {
+                id = Some(i);
+            } else if guess_metadata
+                && (name.contains("title") || name.contains("subject") || name.contains("category"))
+            {
+                metadata = vec![i];
+            }
+        }
+        if text.is_none() {
+            text = guessed_text;
+        }
+        if text.is_none() {
+            for (i, field) in schema.fields().iter().enumerate() {
+                if is_string_type(field.data_type()) && Some(i) != id && !metadata.contains(&i) {
+                    if text.is_none() {
+                        text = Some(i);
+                    } else if id.is_none() {
+                        id = Some(i);
+                    }
+                }
+            }
+        }
+
+        let text = text.ok_or_else(|| mismatch("no text column".to_string()))?;
+        let check = |role: &str, idx: usize, readable: fn(&DataType) -> bool, expected: &str| {
+            let field = schema.field(idx);
+            if readable(field.data_type()) {
+                Ok(())
+            } else {
+                Err(mismatch(format!(
+                    "{} column {} is {}, not {}",
+                    role,
+                    field.name(),
+                    field.data_type(),
+                    expected
+                )))
+            }
+        };
+        check("text", text, is_string_type, "a string column")?;
+        if let Some(id) = id {
+            check("id", id, is_id_type, "a string or integer column")?;
+        }
+        for &idx in &metadata {
+            check("metadata", idx, is_string_type, "a string column")?;
+        }
+
+        Ok(DocumentColumns { text, id, metadata })
+    }
+}
+
+/// Plain, large, view and dictionary-encoded strings
+fn is_string_type(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => true,
+        DataType::Dictionary(_, values) => is_string_type(values),
+        _ => false,
+    }
+}
+
+fn is_id_type(data_type: &DataType) -> bool {
+    is_string_type(data_type) || data_type.is_integer()
+}
+
+/// One file of a Parquet dataset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParquetPart {
+    pub path: PathBuf,
+    /// `(column, value)` of every Hive-style `column=value` directory between the dataset
+    /// root and the file
+    pub partitions: Vec<(String, String)>,
+}
+
+/// Reads documents from a Parquet file, or from every part file of a dataset directory such
+/// as one partitioned as `lang=uk/year=2020/part-0.parquet`
 pub struct ParquetLoader {
     file_path: String,
+    columns: ColumnMapping,
+    partition_metadata: bool,
+    read_threads: usize,
+    sample: DocumentSample,
+}
+
+/// One row group of a part, the unit parallel readers take
+struct RowGroup {
+    /// Index into the dataset's parts
+    part: usize,
+    /// Index within the part
+    index: usize,
+    /// Row number of its first row in the dataset
+    first_row: usize,
+    columns: DocumentColumns,
+}
+
+/// Indexes of the columns documents are read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DocumentColumns {
+    text: usize,
+    id: Option<usize>,
+    metadata: Vec<usize>,
 }
 
 impl ParquetLoader {
     pub fn new<P: AsRef<Path>>(file_path: P) -> Self {
         ParquetLoader {
             file_path: file_path.as_ref().to_string_lossy().to_string(),
+            columns: ColumnMapping::default(),
+            partition_metadata: false,
+            read_threads: rayon::current_num_threads(),
+            sample: DocumentSample::default(),
         }
     }
 
-    pub fn load_documents(&self) -> Result<Vec<ParquetDocument>, Box<dyn std::error::Error>> {
-        let file = File::open(&self.file_path)?;
-        let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
-        let mut reader = builder.build()?;
+    /// Read only a sample of the documents, keyed by document id. A cap alone stops reading
+    /// after the row groups that hold the first `max_docs` rows.
+    pub fn with_sample(mut self, sample: DocumentSample) -> Self {
+        self.sample = sample;
+        self
+    }
 
-        let mut documents = Vec::new();
-        println!("Loading documents from Parquet file: {}", self.file_path);
-        let mut total_batches = 0;
-        let mut total_documents = 0;
+    /// Row groups read at once; defaults to one per core
+    pub fn with_read_threads(mut self, threads: usize) -> Self {
+        self.read_threads = threads.max(1);
+        self
+    }
 
-        while let Some(batch) = reader.next() {
-            let batch = batch?;
-            total_batches += 1;
-            
-            if total_batches % 10 == 0 {
-                println!("  Processed {} batches, {} documents so far", total_batches, total_documents);
-            }
+    /// Append the `column=value` partitions of a part to the metadata of its documents
+    pub fn with_partition_metadata(mut self, enabled: bool) -> Self {
+        self.partition_metadata = enabled;
+        self
+    }
 
-            let docs = self.process_batch(&batch)?;
-            total_documents += docs.len();
-            documents.extend(docs);
+    /// The files read: the input itself, or every `.parquet` file under an input directory
+    /// in path order. Files and directories starting with `_` or `.`, such as `_SUCCESS`
+    /// markers and `_temporary` directories, are skipped.
+    pub fn parts(&self) -> Result<Vec<ParquetPart>, Box<dyn std::error::Error>> {
+        let root = Path::new(&self.file_path);
+        if !root.is_dir() {
+            return Ok(vec![ParquetPart {
+                path: root.to_path_buf(),
+                partitions: Vec::new(),
+            }]);
         }
 
-        println!("Parquet loading complete: {} documents from {} batches", total_documents, total_batches);
-        Ok(documents)
+        let mut parts = Vec::new();
+        let entries = WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                let name = entry.file_name().to_string_lossy();
+                entry.depth() == 0 || !(name.starts_with('_') || name.starts_with('.'))
+            });
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type().is_file()
+                || entry.path().extension().is_none_or(|ext| ext != "parquet")
+            {
+                continue;
+            }
+            let directories = entry.path().strip_prefix(root)?.parent();
+            let partitions = directories
+                .into_iter()
+                .flat_map(|directories| directories.components())
+                .filter_map(|component| {
+                    let (column, value) = component.as_os_str().to_str()?.split_once('=')?;
+                    Some((column.to_string(), value.to_string()))
+                })
+                .collect();
+            parts.push(ParquetPart {
+                path: entry.into_path(),
+                partitions,
+            });
+        }
+        Ok(parts)
     }
 
-    fn process_batch(&self, batch: &RecordBatch) -> Result<Vec<ParquetDocument>, Box<dyn std::error::Error>> {
-        let schema = batch.schema();
-        let mut documents = Vec::new();
+    /// The first part; a dataset's columns are resolved and inspected from it
+    fn first_part(&self) -> Result<ParquetPart, Box<dyn std::error::Error>> {
+        self.parts()?.into_iter().next().ok_or_else(|| {
+            CorpusError::EmptyCorpus {
+                source: self.file_path.clone(),
+                reason: EmptyReason::NoDocuments,
+            }
+            .into()
+        })
+    }
 
-        // Try to find text-like columns in the schema
-        let mut text_column_idx = None;
-        let mut id_column_idx = None;
-        let mut metadata_column_idx = None;
+    /// Read documents from the mapped columns instead of guessing them all
+    pub fn with_columns(mut self, columns: ColumnMapping) -> Self {
+        self.columns = columns;
+        self
+    }
 
-        for (i, field) in schema.fields().iter().enumerate() {
-            let name = field.name().to_lowercase();
-            if name.contains("text") || name.contains("content") || name.contains("body") {
-                text_column_idx = Some(i);
-            } else if name.contains("id") && id_column_idx.is_none() {
-                id_column_idx = Some(i);
-            } else if name.contains("title") || name.contains("subject") || name.contains("category") {
-                metadata_column_idx = Some(i);
-            }
+    /// Names of the text, id and metadata columns documents would be read from
+    pub fn resolved_columns(&self) -> Result<ColumnMapping, Box<dyn std::error::Error>> {
+        let part = self.first_part()?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&part.path)?)?;
+        let schema = builder.schema();
+        let columns = self.columns.resolve(schema, &part.path.to_string_lossy())?;
+        let name = |idx: usize| schema.field(idx).name().clone();
+        Ok(ColumnMapping {
+            text: Some(name(columns.text)),
+            id: columns.id.map(name),
+            metadata: columns.metadata.iter().map(|&idx| name(idx)).collect(),
+        })
+    }
+
+    /// Documents of every part, in part and row order. Row groups are read in parallel by
+    /// `read_threads` readers; rows without an id are numbered across the whole dataset.
+    pub fn load_documents(&self) -> Result<Vec<ParquetDocument>, Box<dyn std::error::Error>> {
+        let (parts, row_groups) = self.row_groups()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.read_threads)
+            .build()?;
+        let loaded: Vec<Result<Vec<ParquetDocument>, SendError>> = pool.install(|| {
+            row_groups
+                .par_iter()
+                .map(|row_group| self.read_row_group(&parts[row_group.part], row_group))
+                .collect()
+        });
+
+        let mut documents = Vec::new();
+        for row_group_documents in loaded {
+            documents.extend(row_group_documents.map_err(|e| e as Box<dyn std::error::Error>)?);
         }
+        if let Some(max_docs) = self.sample.max_docs {
+            documents.truncate(max_docs);
+        }
+        println!("Parquet loading complete: {} documents", documents.len());
+        Ok(documents)
+    }
 
-        // If we don't find obvious text columns, look for string columns
-        if text_column_idx.is_none() {
-            for (i, field) in schema.fields().iter().enumerate() {
-                if matches!(field.data_type(), arrow::datatypes::DataType::Utf8 | arrow::datatypes::DataType::LargeUtf8) {
-                    if text_column_idx.is_none() {
-                        text_column_idx = Some(i);
-                    } else if id_column_idx.is_none() {
-                        id_column_idx = Some(i);
-                    }
+    /// Send the documents of every part into `sender` as `read_threads` readers decode row
+    /// groups, for an indexer to consume while the rest is still being read. Documents arrive
+    /// in no particular order; a full channel holds the readers back. Returns the number sent,
+    /// stopping early once the receiver is gone.
+    pub fn send_documents(
+        &self,
+        sender: &Sender<ParquetDocument>,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        if self.sample.max_docs.is_some() {
+            // The cap keeps the first documents in dataset order, which racing readers
+            // cannot tell apart, so the sample is loaded in order and sent from memory
+            let documents = self.load_documents()?;
+            let total = documents.len();
+            for (sent, document) in documents.into_iter().enumerate() {
+                if sender.send(document).is_err() {
+                    return Ok(sent);
                 }
             }
+            return Ok(total);
         }
+        let (parts, row_groups) = self.row_groups()?;
+        let next_row_group = AtomicUsize::new(0);
+        let sent = AtomicUsize::new(0);
+        let failure: Mutex<Option<SendError>> = Mutex::new(None);
 
-        let text_column_idx = text_column_idx.ok_or("No text column found in Parquet file")?;
-        
-        // Extract data from the identified columns
-        let text_array = batch.column(text_column_idx)
-            .as_any()
-            .downcast_ref::<StringArray>()
-            .ok_or("Text column is not a string array")?;
-
-        let id_array = id_column_idx.map(|idx| batch.column(idx));
-
-        let metadata_array = if let Some(idx) = metadata_column_idx {
-            Some(batch.column(idx)
-                .as_any()
-                .downcast_ref::<StringArray>()
-                .ok_or("Metadata column is not a string array")?)
-        } else {
-            None
-        };
-
-        for i in 0..batch.num_rows() {
-            if text_array.is_valid(i) {
-                let text = text_array.value(i);
-                let id = if let Some(id_col) = id_array {
-                    // Try different array types for ID
-                    if let Some(string_arr) = id_col.as_any().downcast_ref::<StringArray>() {
-                        if string_arr.is_valid(i) {
-                            string_arr.value(i).to_string()
-                        } else {
-                            format!("doc_{}", i)
+        thread::scope(|scope| {
+            for _ in 0..self.read_threads.min(row_groups.len()) {
+                scope.spawn(|| loop {
+                    let index = next_row_group.fetch_add(1, Ordering::Relaxed);
+                    let Some(row_group) = row_groups.get(index) else {
+                        break;
+                    };
+                    if failure.lock().unwrap().is_some() {
+                        break;
+                    }
+                    match self.read_row_group(&parts[row_group.part], row_group) {
+                        Ok(documents) => {
+                            for document in documents {
+                                if sender.send(document).is_err() {
+                                    return;
+                                }
+                                sent.fetch_add(1, Ordering::Relaxed);
+                            }
                         }
-                    } else if let Some(int_arr) = id_col.as_any().downcast_ref::<Int64Array>() {
-                        if int_arr.is_valid(i) {
-                            int_arr.value(i).to_string()
-                        } else {
-                            format!("doc_{}", i)
+                        Err(e) => {
+                            failure.lock().unwrap().get_or_insert(e);
+                            break;
                         }
-                    } else {
-                        format!("doc_{}", i)
-                    }
-                } else {
-                    format!("doc_{}", i)
-                };
-
-                let metadata = if let Some(meta_arr) = metadata_array {
-                    if meta_arr.is_valid(i) {
-                        Some(meta_arr.value(i).to_string())
-                    } else {
-                        None
                     }
-                } else {
-                    None
-                };
-
-                documents.push(ParquetDocument {
-                    id,
-                    text: text.to_string(),
-                    metadata,
                 });
             }
+        });
+
+        match failure.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(sent.into_inner()),
+        }
+    }
+
+    /// Every row group of every part with the row number of its first row, from the file
+    /// footers. The columns of each part are resolved here, so a file without rows still
+    /// reports a schema it cannot read.
+    fn row_groups(&self) -> Result<(Vec<ParquetPart>, Vec<RowGroup>), Box<dyn std::error::Error>> {
+        let parts = self.parts()?;
+        if parts.is_empty() {
+            return Err(CorpusError::EmptyCorpus {
+                source: self.file_path.clone(),
+                reason: EmptyReason::NoDocuments,
+            }
+            .into());
+        }
+
+        let mut row_groups = Vec::new();
+        let mut first_row = 0;
+        for (part_index, part) in parts.iter().enumerate() {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&part.path)?)?;
+            let columns = self.columns.resolve(builder.schema(), &part.path.to_string_lossy())?;
+            for (index, metadata) in builder.metadata().row_groups().iter().enumerate() {
+                if self.sample.ratio.is_none()
+                    && self.sample.max_docs.is_some_and(|max_docs| first_row >= max_docs)
+                {
+                    break;
+                }
+                row_groups.push(RowGroup {
+                    part: part_index,
+                    index,
+                    first_row,
+                    columns: columns.clone(),
+                });
+                first_row += metadata.num_rows() as usize;
+            }
         }
+        println!(
+            "Reading {} row groups of {} Parquet files under {} with {} threads",
+            row_groups.len(),
+            parts.len(),
+            self.file_path,
+            self.read_threads
+        );
+        Ok((parts, row_groups))
+    }
+
+    fn read_row_group(
+        &self,
+        part: &ParquetPart,
+        row_group: &RowGroup,
+    ) -> Result<Vec<ParquetDocument>, SendError> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&part.path)?)?
+            .with_row_groups(vec![row_group.index])
+            .build()?;
+        let partitions: Vec<String> = if self.partition_metadata {
+            part.partitions
+                .iter()
+                .map(|(column, value)| format!("{}={}", column, value))
+                .collect()
+        } else {
+            Vec::new()
+        };
 
+        let mut documents = Vec::new();
+        let mut first_row = row_group.first_row;
+        for batch in reader {
+            let batch = batch?;
+            process_batch(&batch, &row_group.columns, &partitions, first_row, &mut documents)?;
+            first_row += batch.num_rows();
+        }
+        documents.retain(|document| self.sample.keeps(&document.id));
         Ok(documents)
     }
 
     pub fn inspect_schema(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let file = File::open(&self.file_path)?;
+        let parts = self.parts()?;
+        if Path::new(&self.file_path).is_dir() {
+            let mut partition_columns: Vec<&str> = Vec::new();
+            for (column, _) in parts.iter().flat_map(|part| &part.partitions) {
+                if !partition_columns.contains(&column.as_str()) {
+                    partition_columns.push(column);
+                }
+            }
+            println!("Parquet dataset: {} part files", parts.len());
+            if !partition_columns.is_empty() {
+                println!("Partition columns: {}", partition_columns.join(", "));
+            }
+        }
+        let part = self.first_part()?;
+        let file = File::open(&part.path)?;
         let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
         let schema = builder.schema().clone();
 
@@ -189,6 +508,62 @@ impl ParquetLoader {
     }
 }
 
+/// Append the documents of one batch, whose first row is row `first_row` of the dataset.
+/// Rows without text are skipped and rows without an id are named after their row.
+fn process_batch(
+    batch: &RecordBatch,
+    columns: &DocumentColumns,
+    partitions: &[String],
+    first_row: usize,
+    documents: &mut Vec<ParquetDocument>,
+) -> Result<(), SendError> {
+    let text_array = string_column(batch, columns.text)?;
+    let id_array = columns.id.map(|idx| string_column(batch, idx)).transpose()?;
+    let metadata_arrays = columns
+        .metadata
+        .iter()
+        .map(|&idx| string_column(batch, idx))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for i in 0..batch.num_rows() {
+        if !text_array.is_valid(i) {
+            continue;
+        }
+        let id = match &id_array {
+            Some(ids) if ids.is_valid(i) => ids.value(i).to_string(),
+            _ => format!("doc_{}", first_row + i),
+        };
+        let metadata: Vec<&str> = metadata_arrays
+            .iter()
+            .filter(|values| values.is_valid(i))
+            .map(|values| values.value(i))
+            .chain(partitions.iter().map(String::as_str))
+            .collect();
+
+        documents.push(ParquetDocument {
+            id,
+            text: text_array.value(i).to_string(),
+            metadata: (!metadata.is_empty()).then(|| metadata.join("; ")),
+        });
+    }
+
+    Ok(())
+}
+
+/// A text, id or metadata column as plain strings, whatever string or integer encoding the
+/// file uses
+fn string_column(
+    batch: &RecordBatch,
+    idx: usize,
+) -> Result<StringArray, SendError> {
+    let column: ArrayRef = cast(batch.column(idx), &DataType::Utf8)?;
+    Ok(column
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or("Column did not cast to strings")?
+        .clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,4 +573,230 @@ mod tests {
         let loader = ParquetLoader::new("test.parquet");
         assert_eq!(loader.file_path, "test.parquet");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_schema_without_text_is_a_mismatch() {
+        use arrow::datatypes::Field;
+        use parquet::arrow::ArrowWriter;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, schema: Schema| {
+            let path = dir.path().join(name);
+            let file = File::create(&path).unwrap();
+            let writer = ArrowWriter::try_new(file, Arc::new(schema), None).unwrap();
+            writer.close().unwrap();
+            ParquetLoader::new(path)
+        };
+
+        let id = Field::new("id", DataType::Int64, false);
+        let numbers = write("numbers.parquet", Schema::new(vec![id]));
+        let error = numbers.load_documents().unwrap_err();
+        match error.downcast_ref::<CorpusError>() {
+            Some(CorpusError::SchemaMismatch { columns, problem, .. }) => {
+                assert_eq!(problem, "no text column");
+                assert_eq!(columns, &[("id".to_string(), "Int64".to_string())]);
+            }
+            other => panic!("expected a schema mismatch, got {:?}", other),
+        }
+
+        // Without rows the schema is still fine; the build reports the empty corpus
+        let text = Field::new("text", DataType::Utf8, true);
+        let empty = write("empty.parquet", Schema::new(vec![text]));
+        assert!(empty.load_documents().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mapped_columns_read_any_string_encoding() {
+        use arrow::array::{DictionaryArray, LargeStringArray};
+        use arrow::datatypes::{Field, Int32Type};
+        use parquet::arrow::ArrowWriter;
+        use std::sync::Arc;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("body", DataType::LargeUtf8, true),
+            Field::new("key", DataType::Int64, true),
+            Field::new(
+                "genre",
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                true,
+            ),
+            Field::new("author", DataType::Utf8, true),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(LargeStringArray::from(vec![Some("war and peace"), None, Some("anna")])),
+            Arc::new(Int64Array::from(vec![Some(7), Some(8), None])),
+            Arc::new(
+                vec![Some("novel"), Some("novel"), None]
+                    .into_iter()
+                    .collect::<DictionaryArray<Int32Type>>(),
+            ),
+            Arc::new(StringArray::from(vec![Some("Tolstoy"), None, Some("Tolstoy")])),
+        ];
+        let batch = RecordBatch::try_new(Arc::clone(&schema), columns).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("books.parquet");
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        // A second row group, read as another batch
+        writer.flush().unwrap();
+        writer.write(&batch.slice(0, 1)).unwrap();
+        writer.close().unwrap();
+
+        let mapping = ColumnMapping::new()
+            .with_id("key")
+            .with_metadata(&["genre", "author"]);
+        let loader = ParquetLoader::new(&path).with_columns(mapping);
+        let resolved = loader.resolved_columns().unwrap();
+        assert_eq!(resolved.text.as_deref(), Some("body"));
+        let documents = loader.load_documents().unwrap();
+        let summary: Vec<(&str, &str, Option<&str>)> = documents
+            .iter()
+            .map(|doc| (doc.id.as_str(), doc.text.as_str(), doc.metadata.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("7", "war and peace", Some("novel; Tolstoy")),
+                ("doc_2", "anna", Some("Tolstoy")),
+                ("7", "war and peace", Some("novel; Tolstoy")),
+            ]
+        );
+
+        let problem = |mapping: ColumnMapping| {
+            match ParquetLoader::new(&path).with_columns(mapping).load_documents() {
+                Err(e) => match e.downcast_ref::<CorpusError>() {
+                    Some(CorpusError::SchemaMismatch { problem, .. }) => problem.clone(),
+                    _ => panic!("expected a schema mismatch, got {}", e),
+                },
+                Ok(_) => panic!("expected a schema mismatch"),
+            }
+        };
+        assert_eq!(
+            problem(ColumnMapping::new().with_text("key")),
+            "text column key is Int64, not a string column"
+        );
+        assert_eq!(
+            problem(ColumnMapping::new().with_id("isbn")),
+            "no id column named isbn"
+        );
+    }
+
+    #[test]
+    fn test_partitioned_dataset_reads_every_part() {
+        use arrow::datatypes::Field;
+        use parquet::arrow::ArrowWriter;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("text", DataType::Utf8, true),
+            Field::new("title", DataType::Utf8, true),
+        ]));
+        let write = |part: &str, texts: Vec<&str>| {
+            let path = dir.path().join(part);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let titles = vec!["Book"; texts.len()];
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(StringArray::from(texts)),
+                Arc::new(StringArray::from(titles)),
+            ];
+            let batch = RecordBatch::try_new(Arc::clone(&schema), columns).unwrap();
+            let file = File::create(&path).unwrap();
+            let mut writer = ArrowWriter::try_new(file, Arc::clone(&schema), None).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+        };
+        write("lang=uk/year=1869/part-0.parquet", vec!["війна", "мир"]);
+        write("lang=en/part-0.parquet", vec!["war"]);
+        write("_temporary/part-9.parquet", vec!["unfinished"]);
+        std::fs::write(dir.path().join("_SUCCESS"), "").unwrap();
+
+        let loader = ParquetLoader::new(dir.path()).with_partition_metadata(true);
+        let parts = loader.parts().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].partitions, [("lang".to_string(), "en".to_string())]);
+        assert_eq!(parts[1].partitions.len(), 2);
+        assert_eq!(loader.resolved_columns().unwrap().text.as_deref(), Some("text"));
+
+        let documents = loader.load_documents().unwrap();
+        let summary: Vec<(&str, &str, Option<&str>)> = documents
+            .iter()
+            .map(|doc| (doc.id.as_str(), doc.text.as_str(), doc.metadata.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("doc_0", "war", Some("Book; lang=en")),
+                ("doc_1", "війна", Some("Book; lang=uk; year=1869")),
+                ("doc_2", "мир", Some("Book; lang=uk; year=1869")),
+            ]
+        );
+        let plain = ParquetLoader::new(dir.path()).load_documents().unwrap();
+        assert_eq!(plain[1].metadata.as_deref(), Some("Book"));
+
+        let empty = tempfile::tempdir().unwrap();
+        let error = ParquetLoader::new(empty.path()).load_documents().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CorpusError>(),
+            Some(CorpusError::EmptyCorpus { .. })
+        ));
+    }
+
+    #[test]
+    fn test_row_groups_stream_into_spimi_indexer() {
+        use crate::ParallelSPIMIIndexer;
+        use arrow::datatypes::Field;
+        use parquet::arrow::ArrowWriter;
+        use parquet::file::properties::WriterProperties;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("books.parquet");
+        let schema = Arc::new(Schema::new(vec![Field::new("text", DataType::Utf8, true)]));
+        let texts: Vec<String> = (0..10).map(|i| format!("war peace chapter{}", i)).collect();
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(StringArray::from(texts)) as ArrayRef],
+        )
+        .unwrap();
+        let properties = WriterProperties::builder().set_max_row_group_size(3).build();
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), schema, Some(properties)).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let texts = |documents: &[ParquetDocument]| -> Vec<String> {
+            documents.iter().map(|doc| format!("{}: {}", doc.id, doc.text)).collect()
+        };
+        let loader = ParquetLoader::new(&path).with_read_threads(3);
+        let ordered = loader.load_documents().unwrap();
+        assert_eq!(ordered.len(), 10);
+        assert_eq!(ordered[9].id, "doc_9");
+        let capped = ParquetLoader::new(&path)
+            .with_sample(DocumentSample::new(Some(4), None).unwrap())
+            .load_documents()
+            .unwrap();
+        assert_eq!(texts(&capped), texts(&ordered[..4]));
+
+        // A one-document channel keeps the readers waiting on the indexer throughout
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let indexer = ParallelSPIMIIndexer::new(1, dir.path().join("spimi"), Some(2)).unwrap();
+        let (sent, (dictionary, mut streamed)) = thread::scope(|scope| {
+            let reader = scope.spawn(move || loader.send_documents(&sender).unwrap());
+            let indexed = indexer
+                .build_index_streaming(receiver, |doc: &ParquetDocument| {
+                    (doc.id.as_str(), doc.text.as_str())
+                })
+                .unwrap();
+            (reader.join().unwrap(), indexed)
+        });
+        assert_eq!(sent, 10);
+        streamed.sort_by_key(|doc| doc.id[4..].parse::<usize>().unwrap());
+        assert_eq!(texts(&streamed), texts(&ordered));
+        assert_eq!(dictionary.total_documents, 10);
+        assert_eq!(dictionary.terms["peace"].frequency, 10);
+        assert_eq!(dictionary.terms["chapter7"].frequency, 1);
+    }
+}
@@ -1,51 +1,287 @@
+use quick_xml::escape::unescape_with;
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use regex::Regex;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::{is_epub, open_source, resolve_entity, Analyzer, DocumentProcessor, EpubParser};
+
+/// A word with the byte range `[start, end)` it occupies in the source text
+pub type OffsetToken = (String, usize, usize);
+
+/// Receives each word of a document with its position as `FB2Parser::for_each_token` reads
+/// it; an error stops the parse
+pub type TokenSink<'a> = dyn FnMut(String, usize) -> Result<(), Box<dyn std::error::Error>> + 'a;
+
+/// What the `<title-info>` of an FB2 file says about the book
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BookMetadata {
+    /// `<book-title>` as written
+    pub title: Option<String>,
+    /// Full name of every `<author>`: first, middle and last name, or the nickname of an author
+    /// without them
+    pub authors: Vec<String>,
+    /// Lowercased genre codes, e.g. `sf_fantasy`
+    pub genres: Vec<String>,
+    /// Text of the `<annotation>` paragraphs
+    pub annotation: Option<String>,
+    /// Lowercased `<lang>` code, e.g. `ru`
+    pub language: Option<String>,
+    /// `<date>`, its machine-readable `value` attribute when there is one
+    pub date: Option<String>,
+}
+
+#[derive(Clone)]
 pub struct FB2Parser {
     word_regex: Regex,
+    /// Run on every document's body text before it is tokenized
+    processors: Vec<Arc<dyn DocumentProcessor>>,
+    /// Turns every word into the term it is indexed as
+    analyzer: Analyzer,
+}
+
+impl Default for FB2Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FB2Parser {
     pub fn new() -> Self {
         FB2Parser {
             word_regex: Regex::new(r"\b[а-яёА-ЯЁa-zA-Z]{3,}\b").unwrap(),
+            processors: Vec::new(),
+            analyzer: Analyzer::default(),
         }
     }
 
+    /// Index words as `analyzer` spells them instead of only lowercasing them
+    pub fn with_analyzer(mut self, analyzer: Analyzer) -> Self {
+        self.analyzer = analyzer;
+        self
+    }
+
+    pub fn analyzer(&self) -> &Analyzer {
+        &self.analyzer
+    }
+
+    /// Run `processor` on the body text of every parsed document, after those registered before
+    pub fn with_processor(mut self, processor: Arc<dyn DocumentProcessor>) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = &dyn DocumentProcessor> {
+        self.processors.iter().map(|processor| processor.as_ref())
+    }
+
     pub fn parse_file(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+        let mut words = Vec::new();
+        self.for_each_token(path, |word, _| {
+            words.push(word);
+            Ok(())
+        })?;
+        Ok(words)
+    }
+
+    /// Hand every body word to `on_token` with its position as the XML is read, so a book is
+    /// never held in memory as a list of words; returns the number of words. Positions match
+    /// `parse_file`. Processors work on the whole body, so with any registered the body text
+    /// is read first and streamed from there.
+    pub fn for_each_token<F>(
+        &self,
+        path: &Path,
+        mut on_token: F,
+    ) -> Result<usize, Box<dyn std::error::Error>>
+    where
+        F: FnMut(String, usize) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let mut position = 0;
+        let mut tokenize = |text: &str| {
+            for word_match in self.word_regex.find_iter(text) {
+                if let Some(word) = self.analyzer.analyze(word_match.as_str()) {
+                    on_token(word, position)?;
+                    position += 1;
+                }
+            }
+            Ok(())
+        };
+        if self.processors.is_empty() {
+            self.read_body_text(path, |text| tokenize(&text))?;
+        } else {
+            for text in self.body_text(path)? {
+                tokenize(&text)?;
+            }
+        }
+        Ok(position)
+    }
+
+    pub fn parse_file_with_positions(
+        &self,
+        path: &Path,
+    ) -> Result<Vec<(String, usize)>, Box<dyn std::error::Error>> {
+        Ok(self
+            .parse_file(path)?
+            .into_iter()
+            .enumerate()
+            .map(|(position, word)| (word, position))
+            .collect())
+    }
+
+    /// Words of `text` given as the body of `document`, after the registered processors
+    pub fn parse_text(&self, document: &str, text: &str) -> Vec<String> {
+        let text = self
+            .processors
+            .iter()
+            .fold(text.to_string(), |text, processor| {
+                processor.process(document, text)
+            });
+        self.tokenize_text(&text)
+    }
+
+    /// Body text after the registered processors, its text nodes joined by newlines. Its words
+    /// from `tokenize_text_with_offsets` are numbered the way `parse_file` numbers positions.
+    pub fn parse_body(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.body_text(path)?.join("\n"))
+    }
+
+    /// Body text nodes after the registered processors ran on them. Processors see the whole
+    /// body with nodes joined by newlines and the result is split back on newlines.
+    fn body_text(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let nodes = self.raw_body_text(path)?;
+        if self.processors.is_empty() {
+            return Ok(nodes);
+        }
+        let document = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let text = self
+            .processors
+            .iter()
+            .fold(nodes.join("\n"), |text, processor| {
+                processor.process(&document, text)
+            });
+        Ok(text.split('\n').map(String::from).collect())
+    }
+
+    /// Unescaped text nodes of the body
+    fn raw_body_text(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut nodes = Vec::new();
+        self.read_body_text(path, |text| {
+            nodes.push(text);
+            Ok(())
+        })?;
+        Ok(nodes)
+    }
+
+    /// Hand every unescaped text node of the body to `on_text` as it is read
+    fn read_body_text<F>(
+        &self,
+        path: &Path,
+        mut on_text: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(String) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let reader = open_source(path)?;
         let mut xml_reader = Reader::from_reader(reader);
         xml_reader.trim_text(true);
 
+        let mut buf = Vec::new();
+        let mut in_body = false;
+
+        loop {
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"body" => {
+                    in_body = true;
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"body" => {
+                    in_body = false;
+                }
+                Ok(Event::Text(e)) if in_body => {
+                    on_text(e.unescape_with(resolve_entity)?.into_owned())?;
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    eprintln!("Error parsing {}: {}", path.display(), e);
+                    break;
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Body words with their byte range in the source file; positions match `parse_file`.
+    /// Words left by processors are matched back to the source in order; a word a processor
+    /// introduced gets an empty range where the previous word ended.
+    pub fn parse_file_with_offsets(
+        &self,
+        path: &Path,
+    ) -> Result<Vec<OffsetToken>, Box<dyn std::error::Error>> {
+        let source = self.source_words_with_offsets(path)?;
+        if self.processors.is_empty() {
+            return Ok(source);
+        }
+
+        let mut next = 0;
+        let mut last_end = 0;
+        let mut words = Vec::new();
+        for word in self.parse_file(path)? {
+            let (start, end) = match source[next..].iter().position(|(w, _, _)| *w == word) {
+                Some(skip) => {
+                    let (_, start, end) = source[next + skip];
+                    next += skip + 1;
+                    (start, end)
+                }
+                None => (last_end, last_end),
+            };
+            last_end = end;
+            words.push((word, start, end));
+        }
+        Ok(words)
+    }
+
+    fn source_words_with_offsets(
+        &self,
+        path: &Path,
+    ) -> Result<Vec<OffsetToken>, Box<dyn std::error::Error>> {
+        let reader = open_source(path)?;
+        // Untrimmed so every text event spans exactly the raw bytes before the next tag
+        let mut xml_reader = Reader::from_reader(reader);
+
         let mut words = Vec::new();
         let mut buf = Vec::new();
         let mut in_body = false;
 
         loop {
             match xml_reader.read_event_into(&mut buf) {
-                Ok(Event::Start(ref e)) => {
-                    if e.name().as_ref() == b"body" {
-                        in_body = true;
-                    }
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"body" => {
+                    in_body = true;
                 }
-                Ok(Event::End(ref e)) => {
-                    if e.name().as_ref() == b"body" {
-                        in_body = false;
-                    }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"body" => {
+                    in_body = false;
                 }
-                Ok(Event::Text(e)) => {
-                    if in_body {
-                        let text = e.unescape()?;
-                        for word_match in self.word_regex.find_iter(&text) {
-                            let word = word_match.as_str().to_lowercase();
-                            if word.len() >= 3 {
-                                words.push(word);
-                            }
+                Ok(Event::Text(e)) if in_body => {
+                    let raw = std::str::from_utf8(&e)?;
+                    let text_start = xml_reader.buffer_position() - raw.len();
+                    let (text, raw_starts, raw_ends) = unescape_with_offsets(raw);
+
+                    for word_match in self.word_regex.find_iter(&text) {
+                        let Some(word) = self.analyzer.analyze(word_match.as_str()) else {
+                            continue;
+                        };
+                        if word.len() >= 3 {
+                            words.push((
+                                word,
+                                text_start + raw_starts[word_match.start()],
+                                text_start + raw_ends[word_match.end() - 1],
+                            ));
                         }
                     }
                 }
@@ -62,44 +298,185 @@ impl FB2Parser {
         Ok(words)
     }
 
-    pub fn parse_file_with_positions(
+    /// Extract body text split into sentences; a sentence never spans two text nodes
+    pub fn parse_sentences(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .body_text(path)?
+            .iter()
+            .flat_map(|text| split_sentences(text))
+            .collect())
+    }
+
+    /// Publication date from `<title-info><date>`, preferring the machine-readable `value` attribute
+    pub fn parse_date(&self, path: &Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if is_epub(path) {
+            return Ok(EpubParser::new().metadata(path)?.date);
+        }
+        let reader = open_source(path)?;
+        let mut xml_reader = Reader::from_reader(reader);
+        xml_reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut in_title_info = false;
+        let mut in_date = false;
+
+        loop {
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"title-info" => {
+                    in_title_info = true;
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"title-info" => {
+                    break;
+                }
+                Ok(Event::Start(ref e)) if in_title_info && e.name().as_ref() == b"date" => {
+                    if let Some(value) = e.try_get_attribute("value")? {
+                        return Ok(Some(
+                            value
+                                .unescape_value_with(resolve_entity)?
+                                .trim()
+                                .to_string(),
+                        ));
+                    }
+                    in_date = true;
+                }
+                Ok(Event::Empty(ref e)) if in_title_info && e.name().as_ref() == b"date" => {
+                    if let Some(value) = e.try_get_attribute("value")? {
+                        return Ok(Some(
+                            value
+                                .unescape_value_with(resolve_entity)?
+                                .trim()
+                                .to_string(),
+                        ));
+                    }
+                }
+                Ok(Event::Text(e)) if in_date => {
+                    return Ok(Some(e.unescape_with(resolve_entity)?.trim().to_string()));
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"date" => {
+                    in_date = false;
+                }
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"body" => break,
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    eprintln!("Error parsing {}: {}", path.display(), e);
+                    break;
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(None)
+    }
+
+    /// Language code from `<title-info><lang>`, e.g. `ru`
+    pub fn parse_language(
         &self,
         path: &Path,
-    ) -> Result<Vec<(String, usize)>, Box<dyn std::error::Error>> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .title_info_text(path, b"lang")?
+            .map(|lang| lang.to_lowercase()))
+    }
+
+    /// Words of `<title-info><book-title>`, analyzed like body words
+    pub fn parse_title(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .title_info_text(path, b"book-title")?
+            .map(|title| self.tokenize_text(&title))
+            .unwrap_or_default())
+    }
+
+    /// Genre codes from every `<title-info><genre>`, e.g. `sf_fantasy`
+    pub fn parse_genres(&self, path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .title_info_texts(path, b"genre")?
+            .into_iter()
+            .map(|genre| genre.to_lowercase())
+            .collect())
+    }
+
+    /// Metadata of `<description><title-info>`, read in one pass, or of the package document
+    /// of an EPUB book
+    pub fn parse_metadata(&self, path: &Path) -> Result<BookMetadata, Box<dyn std::error::Error>> {
+        if is_epub(path) {
+            return EpubParser::new().metadata(path);
+        }
+        let reader = open_source(path)?;
         let mut xml_reader = Reader::from_reader(reader);
         xml_reader.trim_text(true);
 
-        let mut words = Vec::new();
         let mut buf = Vec::new();
-        let mut in_body = false;
-        let mut position = 0;
+        let mut metadata = BookMetadata::default();
+        let mut in_title_info = false;
+        // Child of `<title-info>` being read, and the element inside an `<author>`
+        let mut element: Option<Vec<u8>> = None;
+        let mut part: Option<Vec<u8>> = None;
+        let mut names = Vec::new();
+        let mut nickname = None;
+        let mut annotation = Vec::new();
 
         loop {
             match xml_reader.read_event_into(&mut buf) {
-                Ok(Event::Start(ref e)) => {
-                    if e.name().as_ref() == b"body" {
-                        in_body = true;
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"body" => break,
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"title-info" => {
+                    in_title_info = true;
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"title-info" => break,
+                Ok(Event::Start(ref e)) if in_title_info && element.is_none() => {
+                    if e.name().as_ref() == b"date" {
+                        if let Some(value) = e.try_get_attribute("value")? {
+                            metadata.date = Some(
+                                value
+                                    .unescape_value_with(resolve_entity)?
+                                    .trim()
+                                    .to_string(),
+                            );
+                        }
                     }
+                    element = Some(e.name().as_ref().to_vec());
                 }
-                Ok(Event::End(ref e)) => {
-                    if e.name().as_ref() == b"body" {
-                        in_body = false;
+                Ok(Event::Empty(ref e)) if in_title_info && e.name().as_ref() == b"date" => {
+                    if let Some(value) = e.try_get_attribute("value")? {
+                        metadata.date = Some(
+                            value
+                                .unescape_value_with(resolve_entity)?
+                                .trim()
+                                .to_string(),
+                        );
+                    }
+                }
+                Ok(Event::Start(ref e)) if element.as_deref() == Some(b"author") => {
+                    part = Some(e.name().as_ref().to_vec());
+                }
+                Ok(Event::Text(e)) if element.is_some() => {
+                    let text = e.unescape_with(resolve_entity)?.trim().to_string();
+                    match (element.as_deref(), part.as_deref()) {
+                        (Some(b"book-title"), _) => metadata.title = Some(text),
+                        (Some(b"genre"), _) => metadata.genres.push(text.to_lowercase()),
+                        (Some(b"lang"), _) => metadata.language = Some(text.to_lowercase()),
+                        (Some(b"date"), _) if metadata.date.is_none() => metadata.date = Some(text),
+                        (Some(b"annotation"), _) => annotation.push(text),
+                        (Some(b"author"), Some(b"first-name" | b"middle-name" | b"last-name")) => {
+                            names.push(text)
+                        }
+                        (Some(b"author"), Some(b"nickname")) => nickname = Some(text),
+                        _ => {}
                     }
                 }
-                Ok(Event::Text(e)) => {
-                    if in_body {
-                        let text = e.unescape()?;
-                        for word_match in self.word_regex.find_iter(&text) {
-                            let word = word_match.as_str().to_lowercase();
-                            if word.len() >= 3 {
-                                words.push((word, position));
-                                position += 1;
-                            }
+                Ok(Event::End(ref e)) if element.as_deref() == Some(e.name().as_ref()) => {
+                    if e.name().as_ref() == b"author" {
+                        let name = names.join(" ");
+                        names.clear();
+                        match nickname.take() {
+                            Some(nickname) if name.is_empty() => metadata.authors.push(nickname),
+                            _ if !name.is_empty() => metadata.authors.push(name),
+                            _ => {}
                         }
                     }
+                    element = None;
                 }
+                Ok(Event::End(_)) => part = None,
                 Ok(Event::Eof) => break,
                 Err(e) => {
                     eprintln!("Error parsing {}: {}", path.display(), e);
@@ -110,6 +487,248 @@ impl FB2Parser {
             buf.clear();
         }
 
-        Ok(words)
+        if !annotation.is_empty() {
+            metadata.annotation = Some(annotation.join(" "));
+        }
+        Ok(metadata)
+    }
+
+    /// Text of the first `element` inside `<title-info>`
+    fn title_info_text(
+        &self,
+        path: &Path,
+        element: &[u8],
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self.title_info_texts(path, element)?.into_iter().next())
+    }
+
+    /// Text of every `element` inside `<title-info>`
+    fn title_info_texts(
+        &self,
+        path: &Path,
+        element: &[u8],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if is_epub(path) {
+            let metadata = EpubParser::new().metadata(path)?;
+            return Ok(match element {
+                b"book-title" => metadata.title.into_iter().collect(),
+                b"lang" => metadata.language.into_iter().collect(),
+                b"genre" => metadata.genres,
+                _ => Vec::new(),
+            });
+        }
+        let reader = open_source(path)?;
+        let mut xml_reader = Reader::from_reader(reader);
+        xml_reader.trim_text(true);
+
+        let mut buf = Vec::new();
+        let mut in_title_info = false;
+        let mut in_element = false;
+        let mut texts = Vec::new();
+
+        loop {
+            match xml_reader.read_event_into(&mut buf) {
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"title-info" => {
+                    in_title_info = true;
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == b"title-info" => break,
+                Ok(Event::Start(ref e)) if in_title_info && e.name().as_ref() == element => {
+                    in_element = true;
+                }
+                Ok(Event::Text(e)) if in_element => {
+                    texts.push(e.unescape_with(resolve_entity)?.trim().to_string());
+                }
+                Ok(Event::End(ref e)) if e.name().as_ref() == element => {
+                    in_element = false;
+                }
+                Ok(Event::Start(ref e)) if e.name().as_ref() == b"body" => break,
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    eprintln!("Error parsing {}: {}", path.display(), e);
+                    break;
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(texts)
+    }
+
+    /// Word position at which every body sentence starts, for `PositionPostings::same_sentence`
+    pub fn sentence_starts(&self, path: &Path) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+        let mut starts = Vec::new();
+        let mut position = 0;
+        for sentence in self.parse_sentences(path)? {
+            let words = self.tokenize_text(&sentence).len();
+            if words > 0 {
+                starts.push(position);
+                position += words;
+            }
+        }
+        Ok(starts)
+    }
+
+    /// Tokenize arbitrary text with the same rules used for FB2 bodies
+    pub fn tokenize_text(&self, text: &str) -> Vec<String> {
+        self.word_regex
+            .find_iter(text)
+            .filter_map(|word_match| self.analyzer.analyze(word_match.as_str()))
+            .collect()
+    }
+
+    /// `tokenize_text` with the byte range of each word in `text`
+    pub fn tokenize_text_with_offsets(&self, text: &str) -> Vec<OffsetToken> {
+        self.word_regex
+            .find_iter(text)
+            .filter_map(|word_match| {
+                let word = self.analyzer.analyze(word_match.as_str())?;
+                Some((word, word_match.start(), word_match.end()))
+            })
+            .collect()
+    }
+}
+
+/// Whether the tokenizer keeps `word`: three or more Cyrillic or Latin letters. Query words
+/// failing this never appear in an index, so phrase and proximity operands skip them.
+pub fn is_indexable_word(word: &str) -> bool {
+    word.chars().count() >= 3
+        && word
+            .chars()
+            .all(|ch| matches!(ch, 'а'..='я' | 'А'..='Я' | 'ё' | 'Ё' | 'a'..='z' | 'A'..='Z'))
+}
+
+/// Unescape XML text, mapping every output byte to the raw byte range of the character or
+/// entity reference it came from
+fn unescape_with_offsets(raw: &str) -> (String, Vec<usize>, Vec<usize>) {
+    let mut text = String::with_capacity(raw.len());
+    let mut raw_starts = Vec::with_capacity(raw.len());
+    let mut raw_ends = Vec::with_capacity(raw.len());
+    let mut rest = raw;
+
+    while let Some(ch) = rest.chars().next() {
+        let offset = raw.len() - rest.len();
+        let entity = if ch == '&' {
+            rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+                unescape_with(&rest[..=end], resolve_entity)
+                    .ok()
+                    .map(|s| (s.into_owned(), end + 1))
+            })
+        } else {
+            None
+        };
+        let (decoded, consumed) = entity.unwrap_or_else(|| (ch.to_string(), ch.len_utf8()));
+
+        for _ in 0..decoded.len() {
+            raw_starts.push(offset);
+            raw_ends.push(offset + consumed);
+        }
+        text.push_str(&decoded);
+        rest = &rest[consumed..];
+    }
+
+    (text, raw_starts, raw_ends)
+}
+
+/// Split text on sentence-final punctuation followed by whitespace
+pub fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        current.push(ch);
+        let at_boundary = matches!(ch, '.' | '!' | '?' | '…')
+            && chars.peek().is_none_or(|next| next.is_whitespace());
+        if at_boundary {
+            let sentence = current.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+            current.clear();
+        }
+    }
+
+    let sentence = current.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence.to_string());
+    }
+
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_is_read_from_title_info_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("anna.fb2");
+        std::fs::write(
+            &path,
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <FictionBook><description>\
+             <title-info>\
+             <genre>Prose_Classic</genre><genre>love</genre>\
+             <author><first-name>Лев</first-name><middle-name>Николаевич</middle-name>\
+             <last-name>Толстой</last-name><nickname>Граф</nickname></author>\
+             <author><nickname>Редактор</nickname></author>\
+             <book-title>Анна Каренина</book-title>\
+             <annotation><p>Все счастливые семьи похожи друг на друга.</p>\
+             <p>Каждая несчастливая семья несчастлива по-своему.</p></annotation>\
+             <date value=\"1878-01-01\">1878</date>\
+             <lang>RU</lang>\
+             </title-info>\
+             <src-title-info>\
+             <author><first-name>Leo</first-name><last-name>Tolstoy</last-name></author>\
+             <book-title>Anna Karenina</book-title><date>1877</date><lang>en</lang>\
+             </src-title-info>\
+             </description><body><p>Все смешалось в доме Облонских.</p></body></FictionBook>",
+        )
+        .unwrap();
+
+        let metadata = FB2Parser::new().parse_metadata(&path).unwrap();
+        assert_eq!(
+            metadata,
+            BookMetadata {
+                title: Some("Анна Каренина".to_string()),
+                authors: vec!["Лев Николаевич Толстой".to_string(), "Редактор".to_string()],
+                genres: vec!["prose_classic".to_string(), "love".to_string()],
+                annotation: Some(
+                    "Все счастливые семьи похожи друг на друга. \
+                     Каждая несчастливая семья несчастлива по-своему."
+                        .to_string()
+                ),
+                language: Some("ru".to_string()),
+                date: Some("1878-01-01".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_self_closing_date_is_read_from_its_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poems.fb2");
+        std::fs::write(
+            &path,
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <FictionBook><description>\
+             <title-info><book-title>Стихотворения</book-title>\
+             <date value=\"1829-05-01\"/><lang>ru</lang></title-info>\
+             <document-info><date>2010</date></document-info>\
+             </description><body><p>Я помню чудное мгновенье.</p></body></FictionBook>",
+        )
+        .unwrap();
+
+        let parser = FB2Parser::new();
+        assert_eq!(
+            parser.parse_date(&path).unwrap(),
+            Some("1829-05-01".to_string())
+        );
+        assert_eq!(
+            parser.parse_metadata(&path).unwrap().date,
+            Some("1829-05-01".to_string())
+        );
     }
 }
@@ -1,14 +1,24 @@
-use crate::CompressedDictionary;
+use crate::{glob_match, CompressedDictionary, TuningConfig};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::sync::{Arc, Mutex};
 
+/// Rotations and their terms sharing one first character, sorted by rotation
+type RotationPartition = Vec<(String, Vec<String>)>;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PermutationIndex {
     index: HashMap<String, HashSet<String>>,
 }
 
+impl Default for PermutationIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PermutationIndex {
     pub fn new() -> Self {
         PermutationIndex {
@@ -26,7 +36,7 @@ impl PermutationIndex {
         let terms = dictionary.extract_terms_parallel();
 
         // Process terms in parallel chunks
-        let chunk_size = 1000;
+        let chunk_size = TuningConfig::current().chunk_size;
         let chunks: Vec<_> = terms.chunks(chunk_size).collect();
 
         chunks
@@ -82,7 +92,7 @@ impl PermutationIndex {
         let terms = dictionary.extract_terms_parallel();
 
         // Process terms in parallel chunks
-        let chunk_size = 1000;
+        let chunk_size = TuningConfig::current().chunk_size;
         let chunks: Vec<_> = terms.chunks(chunk_size).collect();
 
         chunks
@@ -113,7 +123,7 @@ impl PermutationIndex {
                 }
 
                 let processed = (chunk_idx + 1) * chunk_size;
-                if processed % 5000 == 0 || chunk_idx == chunks.len() - 1 {
+                if processed.is_multiple_of(5000) || chunk_idx == chunks.len() - 1 {
                     println!(
                         "      PermutationIndex: Processed ~{} terms",
                         processed.min(terms.len())
@@ -150,20 +160,33 @@ impl PermutationIndex {
             return HashSet::new();
         }
 
+        if pattern.contains('?') {
+            // `?` stands for exactly one character, so the anchored rotation prefix only narrows
+            // the candidates and each one is checked character by character
+            return self
+                .find_by_rotation_prefix(&rotation_prefix(pattern))
+                .into_iter()
+                .filter(|term| glob_match(term, pattern))
+                .collect();
+        }
+
         let mut results = HashSet::new();
 
         if pattern.contains('*') {
-            let pattern_with_marker = if pattern.ends_with('*') {
-                pattern.replacen('*', "$", 1)
-            } else if pattern.starts_with('*') {
-                format!("${}", &pattern[1..])
+            if let Some(prefix) = pattern.strip_suffix('*').filter(|p| !p.contains('*')) {
+                // X* is the rotation $X..., so the match is anchored at the start of the term
+                return self.find_by_rotation_prefix(&format!("${}", prefix));
+            }
+            if let Some(suffix) = pattern.strip_prefix('*').filter(|s| !s.contains('*')) {
+                // *Y is the rotation Y$..., anchored at the end of the term
+                return self.find_by_rotation_prefix(&format!("{}$", suffix));
+            }
+
+            let parts: Vec<&str> = pattern.split('*').collect();
+            let pattern_with_marker = if parts.len() == 2 {
+                format!("{}${}", parts[1], parts[0])
             } else {
-                let parts: Vec<&str> = pattern.split('*').collect();
-                if parts.len() == 2 {
-                    format!("{}${}", parts[1], parts[0])
-                } else {
-                    pattern.to_string()
-                }
+                pattern.to_string()
             };
 
             for (rotation, terms) in &self.index {
@@ -183,13 +206,19 @@ impl PermutationIndex {
         results
     }
 
+    fn find_by_rotation_prefix(&self, rotation_prefix: &str) -> HashSet<String> {
+        self.index
+            .iter()
+            .filter(|(rotation, _)| rotation.starts_with(rotation_prefix))
+            .flat_map(|(_, terms)| terms.iter().cloned())
+            .collect()
+    }
+
     fn matches_wildcard_pattern(&self, rotation: &str, pattern: &str) -> bool {
         if pattern.contains('$') {
-            if pattern.ends_with('$') {
-                let prefix = &pattern[..pattern.len() - 1];
+            if let Some(prefix) = pattern.strip_suffix('$') {
                 rotation.starts_with(prefix)
-            } else if pattern.starts_with('$') {
-                let suffix = &pattern[1..];
+            } else if let Some(suffix) = pattern.strip_prefix('$') {
                 rotation.ends_with(suffix)
             } else {
                 let parts: Vec<&str> = pattern.split('$').collect();
@@ -204,6 +233,38 @@ impl PermutationIndex {
         }
     }
 
+    /// Write the rotations as one file per first rotation character, `{prefix}_permuterm_{code
+    /// point in hex}.bin`, plus the list of partitions in `{prefix}_permuterm.bin`. Returns the
+    /// number of bytes written.
+    pub fn save_partitioned(&self, prefix: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut partitions: BTreeMap<char, RotationPartition> = BTreeMap::new();
+        for (rotation, terms) in &self.index {
+            let Some(first) = rotation.chars().next() else {
+                continue;
+            };
+            let mut terms: Vec<String> = terms.iter().cloned().collect();
+            terms.sort_unstable();
+            partitions
+                .entry(first)
+                .or_default()
+                .push((rotation.clone(), terms));
+        }
+
+        let mut written = 0;
+        for (first, rotations) in &mut partitions {
+            rotations.sort_unstable();
+            let data = bincode::serialize(rotations)?;
+            written += data.len();
+            fs::write(PartitionedPermutationIndex::partition_path(prefix, *first), data)?;
+        }
+        let characters: Vec<char> = partitions.into_keys().collect();
+        let data = bincode::serialize(&characters)?;
+        written += data.len();
+        fs::write(format!("{}_permuterm.bin", prefix), data)?;
+
+        Ok(written)
+    }
+
     pub fn memory_size(&self) -> usize {
         let mut size = std::mem::size_of::<PermutationIndex>();
 
@@ -219,6 +280,95 @@ impl PermutationIndex {
     }
 }
 
+/// Permutation index saved with `PermutationIndex::save_partitioned`. Only the list of
+/// partitions is read up front; a query reads just the partition its rotation prefix falls in,
+/// so prefix, suffix and single-`*` patterns never touch the rest of the rotations.
+#[derive(Debug)]
+pub struct PartitionedPermutationIndex {
+    prefix: String,
+    characters: Vec<char>,
+}
+
+impl PartitionedPermutationIndex {
+    pub fn open(prefix: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let data = fs::read(format!("{}_permuterm.bin", prefix))?;
+        Ok(PartitionedPermutationIndex {
+            prefix: prefix.to_string(),
+            characters: bincode::deserialize(&data)?,
+        })
+    }
+
+    fn partition_path(prefix: &str, first: char) -> String {
+        format!("{}_permuterm_{:x}.bin", prefix, first as u32)
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.characters.len()
+    }
+
+    /// First rotation characters of the partitions `pattern` can match in
+    pub fn partitions_for(&self, pattern: &str) -> Vec<char> {
+        let stars = pattern.matches('*').count();
+        let key = if pattern.contains('?') {
+            rotation_prefix(pattern).chars().next()
+        } else if stars == 0 {
+            pattern.chars().next()
+        } else if stars == 1 && pattern.ends_with('*') {
+            // X* is the rotation $X...
+            Some('$')
+        } else if stars == 1 && pattern.starts_with('*') {
+            // *Y is the rotation Y$...
+            pattern[1..].chars().next()
+        } else if stars == 1 {
+            // X*Y is checked against the rotations starting with X
+            pattern.chars().next()
+        } else {
+            None
+        };
+
+        match key {
+            Some(first) if self.characters.contains(&first) => vec![first],
+            Some(_) => Vec::new(),
+            None => self.characters.clone(),
+        }
+    }
+
+    /// Same matches as `PermutationIndex::find_matching_terms` over the full index
+    pub fn find_matching_terms(
+        &self,
+        pattern: &str,
+    ) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        if pattern.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let mut index = HashMap::new();
+        for first in self.partitions_for(pattern) {
+            let data = fs::read(Self::partition_path(&self.prefix, first))?;
+            let rotations: RotationPartition = bincode::deserialize(&data)?;
+            index.extend(
+                rotations
+                    .into_iter()
+                    .map(|(rotation, terms)| (rotation, terms.into_iter().collect())),
+            );
+        }
+
+        Ok(PermutationIndex { index }.find_matching_terms(pattern))
+    }
+}
+
+/// Start of the rotations of every term a pattern `X...Y` with wildcards in between can
+/// match: the term ends with `Y` and starts with `X`, so its rotation starts with `Y$X`
+fn rotation_prefix(pattern: &str) -> String {
+    match (pattern.find(['*', '?']), pattern.rfind(['*', '?'])) {
+        (Some(first), Some(last)) => {
+            // Both wildcards are one byte, so slicing around them is safe
+            format!("{}${}", &pattern[last + 1..], &pattern[..first])
+        }
+        _ => format!("{}$", pattern),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -271,4 +421,80 @@ mod tests {
         assert!(results.contains("wonderful"));
         assert!(!results.contains("hello"));
     }
+
+    #[test]
+    fn test_permutation_index_anchors_prefix_and_suffix() {
+        let mut dict = Dictionary::new();
+        dict.add_term("anna".to_string(), "doc1".to_string());
+        dict.add_term("station".to_string(), "doc1".to_string());
+        dict.add_term("ingot".to_string(), "doc1".to_string());
+        dict.add_term("sing".to_string(), "doc1".to_string());
+
+        let compressed_dict = CompressedDictionary::from_dictionary(&dict);
+        let perm_index = PermutationIndex::from_dictionary(&compressed_dict);
+
+        let prefix: Vec<String> = perm_index.find_matching_terms("an*").into_iter().collect();
+        assert_eq!(prefix, vec!["anna"]);
+        let suffix: Vec<String> = perm_index.find_matching_terms("*ing").into_iter().collect();
+        assert_eq!(suffix, vec!["sing"]);
+    }
+
+    #[test]
+    fn test_partitioned_index_matches_full_index() {
+        let mut dict = Dictionary::new();
+        for term in ["anna", "station", "ingot", "sing", "hello", "help", "world"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+        let compressed_dict = CompressedDictionary::from_dictionary(&dict);
+        let perm_index = PermutationIndex::from_dictionary(&compressed_dict);
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        perm_index.save_partitioned(&prefix).unwrap();
+        let partitioned = PartitionedPermutationIndex::open(&prefix).unwrap();
+
+        assert_eq!(partitioned.partitions_for("hel*"), vec!['$']);
+        assert_eq!(partitioned.partitions_for("*ing"), vec!['i']);
+        assert!(partitioned.partitions_for("*q").is_empty());
+        for pattern in ["hel*", "*ing", "w*d", "anna", "*o*", "*q"] {
+            assert_eq!(
+                partitioned.find_matching_terms(pattern).unwrap(),
+                perm_index.find_matching_terms(pattern),
+                "pattern {}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_character_wildcard_on_cyrillic() {
+        let mut dict = Dictionary::new();
+        for term in ["мир", "мор", "мира", "война", "воина", "вина"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+        let compressed_dict = CompressedDictionary::from_dictionary(&dict);
+        let perm_index = PermutationIndex::from_dictionary(&compressed_dict);
+        let matches = |pattern: &str| {
+            let mut terms: Vec<String> =
+                perm_index.find_matching_terms(pattern).into_iter().collect();
+            terms.sort();
+            terms
+        };
+
+        assert_eq!(matches("м?р"), vec!["мир", "мор"]);
+        assert_eq!(matches("?ир?"), vec!["мира"]);
+        assert_eq!(matches("в*?на"), vec!["вина", "воина", "война"]);
+        assert_eq!(matches("во?н*"), vec!["воина", "война"]);
+        assert!(matches("м??р").is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        perm_index.save_partitioned(&prefix).unwrap();
+        let partitioned = PartitionedPermutationIndex::open(&prefix).unwrap();
+        assert_eq!(partitioned.partitions_for("м?р"), vec!['р']);
+        assert_eq!(
+            partitioned.find_matching_terms("м?р").unwrap(),
+            perm_index.find_matching_terms("м?р")
+        );
+    }
 }
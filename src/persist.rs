@@ -0,0 +1,142 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Starts every structure file written since files were versioned
+const MAGIC: &[u8; 8] = b"GRIMOIRE";
+
+/// A structure saved to one file, `{name}{SUFFIX}.bin` in a directory. The file starts with a
+/// header naming the format version it was written in, so a build never misreads a structure
+/// whose fields have changed; files from before headers were written are read as they are.
+pub trait Persistable: Serialize + DeserializeOwned {
+    /// Appended to the index name, e.g. `_index`; empty for the dictionary itself
+    const SUFFIX: &'static str;
+
+    /// Version of the serialized layout; bump it whenever the stored fields change
+    fn format_version() -> u32 {
+        1
+    }
+
+    fn file_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}{}.bin", name, Self::SUFFIX))
+    }
+
+    /// The structure after the header; structures laid out on their own, such as the posting
+    /// blocks of `write_posting_blocks`, override this together with `read_payload`
+    fn write_payload(&self, data: &mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        bincode::serialize_into(data, self)?;
+        Ok(())
+    }
+
+    fn read_payload(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(bincode::deserialize(payload)?)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = MAGIC.to_vec();
+        data.extend(Self::format_version().to_le_bytes());
+        self.write_payload(&mut data)?;
+        Ok(data)
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(versioned) = data.strip_prefix(MAGIC.as_slice()) else {
+            return Ok(bincode::deserialize(data)?);
+        };
+        let (version, payload) = versioned
+            .split_first_chunk::<4>()
+            .ok_or("structure file ends inside its header")?;
+        let version = u32::from_le_bytes(*version);
+        if version != Self::format_version() {
+            return Err(format!(
+                "written in format version {}, this build reads version {}; rebuild the index",
+                version,
+                Self::format_version()
+            )
+            .into());
+        }
+        Self::read_payload(payload)
+    }
+
+    /// Write the structure as `name` in `dir`, returning the bytes written
+    fn save(&self, dir: &Path, name: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let data = self.to_bytes()?;
+        fs::write(Self::file_path(dir, name), &data)?;
+        Ok(data.len())
+    }
+
+    fn load(dir: &Path, name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Self::file_path(dir, name);
+        let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_bytes(&data).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// The structure saved as `name`, or `None` for indexes built without it
+    fn load_if_present(dir: &Path, name: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match fs::metadata(Self::file_path(dir, name)) {
+            Ok(_) => Self::load(dir, name).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Directory and index name of a structure prefix such as `out/books`, the form the command
+/// line takes
+pub fn split_prefix(prefix: &str) -> (&Path, &str) {
+    let path = Path::new(prefix);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    (dir, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressedDictionary, CompressedInvertedIndex, Dictionary, TermInterner};
+
+    #[test]
+    fn test_structures_round_trip_with_their_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut dict = Dictionary::new();
+        dict.add_term("war".to_string(), "a.fb2".to_string());
+        dict.add_term("peace".to_string(), "b.fb2".to_string());
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+        let index = CompressedInvertedIndex::from_compressed_dictionary(&compressed);
+
+        compressed.save(dir.path(), "idx").unwrap();
+        let size = index.save(dir.path(), "idx").unwrap();
+        assert!(dir.path().join("idx.bin").exists());
+        assert_eq!(
+            fs::metadata(dir.path().join("idx_index.bin"))
+                .unwrap()
+                .len(),
+            size as u64
+        );
+        let loaded = CompressedInvertedIndex::load(dir.path(), "idx").unwrap();
+        assert_eq!(loaded.doc_id_to_name, index.doc_id_to_name);
+        let loaded = CompressedDictionary::load(dir.path(), "idx").unwrap();
+        assert_eq!(loaded.sorted_terms, compressed.sorted_terms);
+        assert!(TermInterner::load_if_present(dir.path(), "idx")
+            .unwrap()
+            .is_none());
+
+        // Written before structure files had a header
+        let legacy = dir.path().join("old_index.bin");
+        fs::write(&legacy, bincode::serialize(&index).unwrap()).unwrap();
+        assert!(CompressedInvertedIndex::load(dir.path(), "old").is_ok());
+
+        let mut future = MAGIC.to_vec();
+        future.extend(7u32.to_le_bytes());
+        fs::write(dir.path().join("new_index.bin"), future).unwrap();
+        let error = CompressedInvertedIndex::load(dir.path(), "new").unwrap_err();
+        assert!(error.to_string().contains("format version 7"), "{}", error);
+
+        assert_eq!(split_prefix("out/books"), (Path::new("out"), "books"));
+        assert_eq!(split_prefix("books"), (Path::new(""), "books"));
+    }
+}
@@ -0,0 +1,391 @@
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use indicatif::ProgressBar;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{source_len, BuildProfile, Dictionary, FB2Parser, ParseScheduler, WorkerUtilization};
+
+/// Files smaller than this are not indexed
+pub(crate) const MIN_FILE_SIZE: u64 = 150_000;
+
+/// Worker counts of the build pipeline's stages and the size of the queues between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// Documents a queue holds before the stage feeding it blocks
+    pub queue_capacity: usize,
+    pub parse_workers: usize,
+    pub analyze_workers: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        let threads = rayon::current_num_threads();
+        PipelineOptions {
+            queue_capacity: 16,
+            parse_workers: threads,
+            analyze_workers: (threads / 2).max(1),
+        }
+    }
+}
+
+/// How full a queue between two stages got over a build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub max_depth: usize,
+    /// Sends that found the queue full and waited for the next stage
+    pub blocked_sends: usize,
+}
+
+struct QueueGauge {
+    name: &'static str,
+    capacity: usize,
+    max_depth: AtomicUsize,
+    blocked_sends: AtomicUsize,
+}
+
+impl QueueGauge {
+    fn new(name: &'static str, capacity: usize) -> Self {
+        QueueGauge {
+            name,
+            capacity,
+            max_depth: AtomicUsize::new(0),
+            blocked_sends: AtomicUsize::new(0),
+        }
+    }
+
+    /// Send `item`, waiting while the queue is full. False once the receiving stage is gone.
+    fn send<T>(&self, sender: &Sender<T>, item: T) -> bool {
+        let sent = match sender.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(item)) => {
+                self.blocked_sends.fetch_add(1, Ordering::Relaxed);
+                sender.send(item).is_ok()
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        };
+        self.max_depth.fetch_max(sender.len(), Ordering::Relaxed);
+        sent
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            name: self.name,
+            capacity: self.capacity,
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            blocked_sends: self.blocked_sends.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct ParsedDocument {
+    /// Position in the file list
+    index: usize,
+    name: String,
+    bytes: u64,
+    words: Vec<String>,
+    parse_time: Duration,
+}
+
+struct AnalyzedDocument {
+    index: usize,
+    name: String,
+    bytes: u64,
+    word_count: usize,
+    counts: HashMap<String, u32>,
+    parse_time: Duration,
+}
+
+/// Build a dictionary with parse, analyze and index stages running at once, connected by
+/// bounded queues so a slow stage holds back the ones feeding it instead of letting parsed
+/// documents pile up in memory. Parse workers read and tokenize files as a `ParseScheduler`
+/// hands them out, analyze workers count each document's terms and the calling thread merges
+/// the counts into the dictionary. Documents get ids in file order whatever order they finish
+/// in.
+pub fn run_build_pipeline(
+    files: &[PathBuf],
+    parser: &FB2Parser,
+    options: &PipelineOptions,
+    progress: Option<&ProgressBar>,
+    profile: &mut BuildProfile,
+) -> (Dictionary, Vec<QueueStats>) {
+    let capacity = options.queue_capacity.max(1);
+    let (parsed_sender, parsed_receiver) = bounded::<ParsedDocument>(capacity);
+    let (analyzed_sender, analyzed_receiver) = bounded::<AnalyzedDocument>(capacity);
+    let parsed_gauge = QueueGauge::new("parse -> analyze", capacity);
+    let analyzed_gauge = QueueGauge::new("analyze -> index", capacity);
+    let scheduler = ParseScheduler::for_files(files, options.parse_workers);
+    let started = AtomicUsize::new(0);
+    let start = Instant::now();
+    let parse_finished = Mutex::new(start);
+
+    let mut dictionary = Dictionary::new();
+    // File index of every document id, in the order documents reached the index stage
+    let mut arrivals: Vec<usize> = Vec::new();
+    let mut timings = Vec::new();
+
+    let workers = thread::scope(|scope| {
+        let parse_workers: Vec<_> = (0..scheduler.workers())
+            .map(|worker| {
+                let sender = parsed_sender.clone();
+                let (scheduler, started, parsed_gauge, parse_finished) =
+                    (&scheduler, &started, &parsed_gauge, &parse_finished);
+                scope.spawn(move || {
+                    let mut utilization = WorkerUtilization {
+                        worker,
+                        files: 0,
+                        stolen: 0,
+                        bytes: 0,
+                        busy_millis: 0.0,
+                        blocked_millis: 0.0,
+                    };
+                    while let Some(task) = scheduler.next(worker) {
+                        let position = started.fetch_add(1, Ordering::Relaxed);
+                        let busy = Instant::now();
+                        let file_path = &files[task.index];
+                        let parsed = parse_document(
+                            parser,
+                            file_path,
+                            task.index,
+                            position,
+                            files.len(),
+                            &sender,
+                        );
+                        utilization.busy_millis += busy.elapsed().as_secs_f64() * 1000.0;
+                        utilization.files += 1;
+                        utilization.stolen += task.stolen as usize;
+                        utilization.bytes += task.bytes;
+                        if let Some(progress) = progress {
+                            progress.inc(1);
+                        }
+                        if let Some(document) = parsed {
+                            let blocked = Instant::now();
+                            let sent = parsed_gauge.send(&sender, document);
+                            utilization.blocked_millis += blocked.elapsed().as_secs_f64() * 1000.0;
+                            if !sent {
+                                break;
+                            }
+                        }
+                    }
+                    let mut finished = parse_finished.lock().unwrap();
+                    *finished = (*finished).max(Instant::now());
+                    utilization
+                })
+            })
+            .collect();
+        drop(parsed_sender);
+
+        for _ in 0..options.analyze_workers.max(1) {
+            let (receiver, sender) = (parsed_receiver.clone(), analyzed_sender.clone());
+            let analyzed_gauge = &analyzed_gauge;
+            scope.spawn(move || {
+                for document in receiver {
+                    let mut counts: HashMap<String, u32> = HashMap::new();
+                    for word in &document.words {
+                        *counts.entry(word.clone()).or_insert(0) += 1;
+                    }
+                    let analyzed = AnalyzedDocument {
+                        index: document.index,
+                        name: document.name,
+                        bytes: document.bytes,
+                        word_count: document.words.len(),
+                        counts,
+                        parse_time: document.parse_time,
+                    };
+                    if !analyzed_gauge.send(&sender, analyzed) {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(analyzed_sender);
+
+        for document in &analyzed_receiver {
+            let id = dictionary.document_id(&document.name);
+            arrivals.push(document.index);
+            dictionary.add_file_stats(document.bytes);
+            dictionary.add_document_counts(id, document.counts);
+            timings.push((
+                document.index,
+                document.name,
+                document.bytes,
+                document.word_count,
+                document.parse_time,
+            ));
+            if let Some(progress) = progress {
+                progress.set_message(format!(
+                    "queues: parsed {}/{}, analyzed {}/{}",
+                    parsed_receiver.len(),
+                    capacity,
+                    analyzed_receiver.len(),
+                    capacity
+                ));
+            }
+        }
+
+        parse_workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let parse_finished = *parse_finished.lock().unwrap();
+    profile.record("parse", parse_finished - start);
+    profile.record("merge", parse_finished.elapsed());
+
+    // Renumber documents into file order
+    let mut by_file: Vec<usize> = (0..arrivals.len()).collect();
+    by_file.sort_unstable_by_key(|&id| arrivals[id]);
+    let mut new_ids = vec![0u32; arrivals.len()];
+    for (new_id, old_id) in by_file.into_iter().enumerate() {
+        new_ids[old_id] = new_id as u32;
+    }
+    dictionary.renumber_documents(&new_ids);
+
+    timings.sort_unstable_by_key(|timing| timing.0);
+    for (_, name, bytes, words, parse_time) in timings {
+        profile.record_document(&name, bytes, words, parse_time);
+    }
+    for worker in workers {
+        profile.record_worker(worker);
+    }
+
+    (
+        dictionary,
+        vec![parsed_gauge.stats(), analyzed_gauge.stats()],
+    )
+}
+
+/// Parse the file at `index` of the list, the `position`th to be started, or `None` when it
+/// is too small or unreadable
+fn parse_document(
+    parser: &FB2Parser,
+    file_path: &Path,
+    index: usize,
+    position: usize,
+    file_count: usize,
+    queue: &Sender<ParsedDocument>,
+) -> Option<ParsedDocument> {
+    if position < 5 || position.is_multiple_of(50) {
+        println!(
+            "  Processing file {}/{}: {} (parse queue {}/{})",
+            position + 1,
+            file_count,
+            file_path.display(),
+            queue.len(),
+            queue.capacity().unwrap_or(0)
+        );
+    }
+
+    let bytes = match source_len(file_path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error reading metadata for {}: {}", file_path.display(), e);
+            return None;
+        }
+    };
+
+    if bytes < MIN_FILE_SIZE {
+        if position < 5 {
+            eprintln!(
+                "Warning: {} is smaller than 150KB ({} bytes)",
+                file_path.display(),
+                bytes
+            );
+        }
+        return None;
+    }
+
+    let name = file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let start = Instant::now();
+    match parser.parse_file(file_path) {
+        Ok(words) => {
+            if position < 5 {
+                println!("    Parsed {} words from {}", words.len(), name);
+            }
+            Some(ParsedDocument {
+                index,
+                name,
+                bytes,
+                words,
+                parse_time: start.elapsed(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Error processing {}: {}", file_path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_pipeline_matches_sequential_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let texts = ["war and peace", "peace in the forest", "forest war war"];
+        let mut files = Vec::new();
+        for (i, text) in texts.iter().enumerate() {
+            // Repeat the text to get past the size filter
+            let body = format!("<p>{}</p>", text).repeat(8000);
+            let path = dir.path().join(format!("book{}.fb2", i));
+            fs::write(
+                &path,
+                format!("<FictionBook><body>{}</body></FictionBook>", body),
+            )
+            .unwrap();
+            files.push(path);
+        }
+        files.push(dir.path().join("missing.fb2"));
+
+        let parser = FB2Parser::new();
+        let mut expected = Dictionary::new();
+        for file in &files[..texts.len()] {
+            let name = file.file_name().unwrap().to_string_lossy().to_string();
+            for word in parser.parse_file(file).unwrap() {
+                expected.add_term(word, name.clone());
+            }
+        }
+
+        let options = PipelineOptions {
+            queue_capacity: 1,
+            parse_workers: 3,
+            analyze_workers: 2,
+        };
+        let mut profile = BuildProfile::new();
+        let (dictionary, queues) =
+            run_build_pipeline(&files, &parser, &options, None, &mut profile);
+        assert_eq!(dictionary.documents, expected.documents);
+        assert_eq!(dictionary.total_words, expected.total_words);
+        assert_eq!(dictionary.terms.len(), expected.terms.len());
+        for (term, entry) in &expected.terms {
+            let built = &dictionary.terms[term];
+            assert_eq!(built.frequency, entry.frequency);
+            assert_eq!(built.documents, entry.documents);
+            assert_eq!(built.term_frequencies, entry.term_frequencies);
+        }
+        assert_eq!(dictionary.term_frequency("war", "book2.fb2"), 16_000);
+
+        assert_eq!(queues.len(), 2);
+        assert!(queues.iter().all(|queue| queue.max_depth <= 1));
+        assert_eq!(profile.workers.len(), 3);
+        let workers = &profile.workers;
+        assert_eq!(
+            workers.iter().map(|worker| worker.files).sum::<usize>(),
+            files.len()
+        );
+    }
+}
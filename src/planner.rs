@@ -0,0 +1,540 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::query::{tokenize, Capabilities, QueryAst, QueryParser};
+use crate::{
+    CompressedInvertedIndex, CoordinateIndex, IncidenceMatrix, NGramPhraseIndex,
+    WildcardSearchEngine,
+};
+
+/// Search structures a query can be routed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexKind {
+    Matrix,
+    Inverted,
+    Bigram,
+    Coordinate,
+    Wildcard,
+}
+
+impl FromStr for IndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "matrix" => Ok(IndexKind::Matrix),
+            "inverted" => Ok(IndexKind::Inverted),
+            "bigram" => Ok(IndexKind::Bigram),
+            "coordinate" => Ok(IndexKind::Coordinate),
+            "wildcard" => Ok(IndexKind::Wildcard),
+            _ => Err(format!("Unknown index hint: @{}", s)),
+        }
+    }
+}
+
+impl IndexKind {
+    /// Query features the structure evaluates, as reported by its parser
+    pub fn capabilities(self) -> Capabilities {
+        match self {
+            IndexKind::Matrix => IncidenceMatrix::CAPABILITIES,
+            IndexKind::Inverted => CompressedInvertedIndex::CAPABILITIES,
+            IndexKind::Bigram => NGramPhraseIndex::CAPABILITIES,
+            IndexKind::Coordinate => CoordinateIndex::CAPABILITIES,
+            IndexKind::Wildcard => WildcardSearchEngine::CAPABILITIES,
+        }
+    }
+
+    /// File suffix the structure is saved under, after the prefix and an underscore
+    pub fn file_suffix(self) -> &'static str {
+        match self {
+            IndexKind::Matrix => "matrix",
+            IndexKind::Inverted => "index",
+            IndexKind::Bigram => "bigram",
+            IndexKind::Coordinate => "coordinate",
+            IndexKind::Wildcard => "wildcard",
+        }
+    }
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IndexKind::Matrix => "matrix",
+            IndexKind::Inverted => "inverted",
+            IndexKind::Bigram => "bigram",
+            IndexKind::Coordinate => "coordinate",
+            IndexKind::Wildcard => "wildcard",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Structures to run a query against, after routing hints are stripped from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan {
+    pub query: String,
+    pub structures: Vec<IndexKind>,
+    /// True when the structures came from `@` hints rather than the planner
+    pub hinted: bool,
+    /// When set, `stem*` terms are rewritten to the exact stem plus up to this many
+    /// dictionary terms sharing the prefix, instead of routing to the wildcard engine
+    pub expansion_limit: Option<usize>,
+    /// Query features a structure needs to answer the query
+    pub required: Capabilities,
+    /// True when no single structure covers the query and `SharedSearchers::search_routed`
+    /// splits it between the listed structures instead
+    pub split: bool,
+}
+
+impl QueryPlan {
+    pub fn uses(&self, kind: IndexKind) -> bool {
+        self.structures.contains(&kind)
+    }
+
+    /// Structures running the plan reads; a split plan also reads the inverted index for the
+    /// plain terms and Boolean operators between its parts
+    pub fn loaded_structures(&self) -> Vec<IndexKind> {
+        let mut structures = self.structures.clone();
+        if self.split {
+            structures.insert(0, IndexKind::Inverted);
+        }
+        structures
+    }
+
+    /// Why `kind` cannot answer the query, or `None` when it can
+    pub fn unsupported(&self, kind: IndexKind) -> Option<String> {
+        let missing = kind.capabilities().missing(self.required);
+        (!missing.is_empty()).then(|| {
+            format!(
+                "Unsupported operator: the {} index has no {}",
+                kind, missing
+            )
+        })
+    }
+
+    /// Drop hinted structures that cannot answer the query, returning why each was dropped.
+    /// Split plans keep their structures, which answer only their share of the query.
+    pub fn retain_supported(&mut self) -> Vec<String> {
+        if self.split {
+            return Vec::new();
+        }
+        let (supported, unsupported): (Vec<IndexKind>, Vec<IndexKind>) = self
+            .structures
+            .iter()
+            .partition(|&&kind| self.unsupported(kind).is_none());
+        let reasons = unsupported
+            .iter()
+            .filter_map(|&kind| self.unsupported(kind))
+            .collect();
+        self.structures = supported;
+        reasons
+    }
+
+    /// Drop the structures that are not saved, given those that are. A plan left without a
+    /// structure goes to a saved one able to answer the query; when none is, the features no
+    /// saved structure evaluates are skipped with the query. Returns a warning per change.
+    pub fn retain_available(&mut self, available: &[IndexKind]) -> Vec<String> {
+        let missing: Vec<IndexKind> = self
+            .loaded_structures()
+            .into_iter()
+            .filter(|kind| !available.contains(kind))
+            .collect();
+        if missing.is_empty() {
+            return Vec::new();
+        }
+        let mut warnings: Vec<String> = missing
+            .iter()
+            .map(|kind| format!("the {} index is not saved, skipped it", kind))
+            .collect();
+        if self.split {
+            // The split search needs every structure it reads
+            self.split = false;
+            self.structures.clear();
+        } else {
+            self.structures.retain(|kind| available.contains(kind));
+        }
+        if !self.structures.is_empty() {
+            return warnings;
+        }
+
+        let fallback = ROUTING_ORDER
+            .into_iter()
+            .chain(capable_structures(self.required))
+            .find(|kind| available.contains(kind) && kind.capabilities().contains(self.required));
+        match fallback {
+            Some(kind) => {
+                self.structures.push(kind);
+                warnings.push(format!("the {} index answers instead", kind));
+            }
+            None => {
+                let saved = available.iter().fold(Capabilities::empty(), |saved, kind| {
+                    saved | kind.capabilities()
+                });
+                let unavailable = saved.missing(self.required);
+                warnings.push(if unavailable.is_empty() {
+                    format!(
+                        "no saved structure evaluates {} together, skipped the query",
+                        self.required
+                    )
+                } else {
+                    format!(
+                        "no saved structure evaluates {}, skipped the query",
+                        unavailable
+                    )
+                });
+            }
+        }
+        warnings
+    }
+}
+
+/// Defaults applied when a query does not override them with hints
+#[derive(Debug, Clone, Default)]
+pub struct PlannerOptions {
+    /// Force the coordinate index (e.g. for ranking that reads term frequencies)
+    pub needs_positions: bool,
+    pub expansion_limit: Option<usize>,
+}
+
+/// Every search structure
+pub const ALL_STRUCTURES: [IndexKind; 5] = [
+    IndexKind::Matrix,
+    IndexKind::Inverted,
+    IndexKind::Bigram,
+    IndexKind::Coordinate,
+    IndexKind::Wildcard,
+];
+
+/// Structures the planner picks from, cheapest first
+const ROUTING_ORDER: [IndexKind; 3] = [
+    IndexKind::Inverted,
+    IndexKind::Coordinate,
+    IndexKind::Wildcard,
+];
+
+/// True for a single `stem*` token: one trailing star and no other wildcard
+pub fn is_stem_pattern(token: &str) -> bool {
+    token
+        .strip_suffix('*')
+        .is_some_and(|stem| !stem.is_empty() && !stem.contains(['*', '?', '"']))
+}
+
+/// Query features needed to answer a query. With stem expansion on, a `stem*` outside phrases
+/// becomes ordinary terms, and a `prefix*` closing a phrase is expanded by the coordinate index,
+/// so neither needs wildcards.
+pub fn required_capabilities(
+    query: &str,
+    expansion_limit: Option<usize>,
+) -> Result<Capabilities, String> {
+    let tokens = tokenize(&query.to_lowercase())?;
+    let mut required = Capabilities::empty();
+    let mut in_phrase = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if token == "\"" {
+            in_phrase = !in_phrase;
+            required |= Capabilities::PHRASES;
+        } else if token == "not" && !in_phrase {
+            required |= Capabilities::NOT;
+        } else if token.starts_with("near/") && !in_phrase {
+            required |= Capabilities::POSITIONS;
+        } else if token.contains(['*', '?']) {
+            let phrase_prefix = in_phrase
+                && is_stem_pattern(token)
+                && tokens.get(i + 1).is_some_and(|next| next == "\"");
+            let expanded = !in_phrase && expansion_limit.is_some() && is_stem_pattern(token);
+            if !phrase_prefix && !expanded {
+                required |= Capabilities::WILDCARDS;
+            }
+        }
+    }
+    Ok(required)
+}
+
+/// True when a `*` or `?` pattern sits inside a phrase or near/N group, where no structure can
+/// evaluate it. A `prefix*` closing a phrase is expanded by the coordinate index and is fine.
+fn has_positional_pattern(query: &QueryAst) -> bool {
+    fn has_pattern(query: &QueryAst) -> bool {
+        match query {
+            QueryAst::Wildcard(_) => true,
+            QueryAst::Term(_) => false,
+            QueryAst::Phrase(words) => words.iter().any(|word| word.contains(['*', '?'])),
+            QueryAst::Near { operands, .. } => operands.iter().any(has_pattern),
+            QueryAst::And(left, right) | QueryAst::Or(left, right) => {
+                has_pattern(left) || has_pattern(right)
+            }
+            QueryAst::Not(operand) => has_pattern(operand),
+        }
+    }
+
+    match query {
+        QueryAst::Term(_) | QueryAst::Wildcard(_) => false,
+        QueryAst::Phrase(words) => match words.split_last() {
+            Some((last, rest)) => {
+                rest.iter().any(|word| word.contains(['*', '?']))
+                    || (last.contains(['*', '?']) && !is_stem_pattern(last))
+            }
+            None => false,
+        },
+        QueryAst::Near { operands, .. } => operands.iter().any(has_pattern),
+        QueryAst::And(left, right) | QueryAst::Or(left, right) => {
+            has_positional_pattern(left) || has_positional_pattern(right)
+        }
+        QueryAst::Not(operand) => has_positional_pattern(operand),
+    }
+}
+
+/// Every structure able to answer a query needing `required`. The bigram index answers only
+/// phrases and the wildcard engine is only worth running for patterns; the others answer
+/// whatever their capabilities cover.
+pub fn capable_structures(required: Capabilities) -> Vec<IndexKind> {
+    ALL_STRUCTURES
+        .into_iter()
+        .filter(|&kind| kind.capabilities().contains(required))
+        .filter(|&kind| kind != IndexKind::Bigram || required.contains(Capabilities::PHRASES))
+        .filter(|&kind| kind != IndexKind::Wildcard || required.contains(Capabilities::WILDCARDS))
+        .collect()
+}
+
+/// Route a query to search structures.
+///
+/// Leading `@matrix`, `@inverted`, `@bigram`, `@coordinate`, `@wildcard` hints select structures
+/// explicitly and `@all` runs every structure able to answer the query. `@expand=N` turns on
+/// `stem*` expansion for this query (`@expand=0` turns it off). Without structure hints the
+/// planner picks the cheapest structure whose capabilities cover the query's operators; when
+/// none does, as for patterns mixed with phrases, the query is split between the coordinate
+/// index and the wildcard engine.
+pub fn plan_query(query: &str, options: &PlannerOptions) -> Result<QueryPlan, String> {
+    let mut structures = Vec::new();
+    let mut run_all = false;
+    let mut expansion_limit = options.expansion_limit;
+    let mut rest = query.trim_start();
+
+    while let Some(stripped) = rest.strip_prefix('@') {
+        let end = stripped.find(char::is_whitespace).unwrap_or(stripped.len());
+        let hint = &stripped[..end];
+        if hint.eq_ignore_ascii_case("all") {
+            run_all = true;
+        } else if let Some(limit) = hint.strip_prefix("expand=") {
+            let limit: usize = limit
+                .parse()
+                .map_err(|_| format!("Invalid expansion limit: @{}", hint))?;
+            expansion_limit = (limit > 0).then_some(limit);
+        } else {
+            let kind: IndexKind = hint.parse()?;
+            if !structures.contains(&kind) {
+                structures.push(kind);
+            }
+        }
+        rest = stripped[end..].trim_start();
+    }
+
+    let query = rest.trim_end().to_string();
+    if query.is_empty() {
+        return Err("Empty query".to_string());
+    }
+
+    let mut required = required_capabilities(&query, expansion_limit)?;
+    if options.needs_positions {
+        required |= Capabilities::POSITIONS;
+    }
+
+    let covering = ROUTING_ORDER
+        .into_iter()
+        .find(|kind| kind.capabilities().contains(required));
+    // Patterns next to phrases or proximity groups go to the wildcard engine and the rest to
+    // the coordinate index. Patterns inside them are beyond every structure; the wildcard
+    // engine is the closest match and reports what it cannot do, as it does for queries the
+    // shared grammar rejects.
+    let split = covering.is_none()
+        && QueryAst::parse(&query).is_ok_and(|parsed| !has_positional_pattern(&parsed));
+    let routed = covering.unwrap_or(IndexKind::Wildcard);
+
+    if run_all {
+        structures = capable_structures(required);
+        if structures.is_empty() {
+            structures.push(routed);
+        }
+    }
+
+    let hinted = !structures.is_empty();
+    let split = split && !hinted;
+    if split {
+        structures = vec![IndexKind::Coordinate, IndexKind::Wildcard];
+    } else if !hinted {
+        structures.push(routed);
+    }
+
+    Ok(QueryPlan {
+        query,
+        structures,
+        hinted,
+        expansion_limit,
+        required,
+        split,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hints_are_stripped_and_applied() {
+        let options = PlannerOptions::default();
+        let plan = plan_query("@matrix @coordinate war and peace", &options).unwrap();
+        assert_eq!(plan.query, "war and peace");
+        assert_eq!(
+            plan.structures,
+            vec![IndexKind::Matrix, IndexKind::Coordinate]
+        );
+        assert!(plan.hinted);
+
+        let plan = plan_query("@all \"war peace\"", &options).unwrap();
+        assert!(plan.uses(IndexKind::Bigram));
+        assert!(!plan.uses(IndexKind::Wildcard));
+
+        assert!(plan_query("@btree war", &options).is_err());
+        assert!(plan_query("@inverted", &options).is_err());
+    }
+
+    #[test]
+    fn test_planner_defaults() {
+        let route = |query: &str, needs_positions: bool| {
+            let options = PlannerOptions {
+                needs_positions,
+                expansion_limit: None,
+            };
+            plan_query(query, &options).unwrap().structures
+        };
+
+        assert_eq!(route("war and peace", false), vec![IndexKind::Inverted]);
+        assert_eq!(route("war and peace", true), vec![IndexKind::Coordinate]);
+        assert_eq!(
+            route("\"war and peace\"", false),
+            vec![IndexKind::Coordinate]
+        );
+        assert_eq!(
+            route("near/3(war peace)", false),
+            vec![IndexKind::Coordinate]
+        );
+        assert_eq!(route("wa* and peace", false), vec![IndexKind::Wildcard]);
+        assert_eq!(route("\"new yor*\"", false), vec![IndexKind::Coordinate]);
+        assert_eq!(route("\"ne* york\"", false), vec![IndexKind::Wildcard]);
+    }
+
+    #[test]
+    fn test_mixed_queries_are_split() {
+        let options = PlannerOptions::default();
+        let mut plan =
+            plan_query("\"war and peace\" and near/5(love hate) or fre*", &options).unwrap();
+        assert!(plan.split);
+        assert!(plan.retain_supported().is_empty());
+        assert_eq!(
+            plan.structures,
+            vec![IndexKind::Coordinate, IndexKind::Wildcard]
+        );
+
+        assert!(plan_query("\"new yor*\" or fre*", &options).unwrap().split);
+        assert!(!plan_query("\"ne* york\" or fre*", &options).unwrap().split);
+        assert!(
+            !plan_query("near/2(wa* peace) or fre*", &options)
+                .unwrap()
+                .split
+        );
+        assert!(
+            !plan_query("@wildcard \"war peace\" or fre*", &options)
+                .unwrap()
+                .split
+        );
+    }
+
+    #[test]
+    fn test_missing_structures_are_rerouted_or_skipped() {
+        let options = PlannerOptions::default();
+        let mut plan = plan_query("war and peace", &options).unwrap();
+        let warnings = plan.retain_available(&[IndexKind::Matrix, IndexKind::Coordinate]);
+        assert_eq!(plan.structures, vec![IndexKind::Coordinate]);
+        assert_eq!(
+            warnings,
+            [
+                "the inverted index is not saved, skipped it",
+                "the coordinate index answers instead"
+            ]
+        );
+
+        let mut plan = plan_query("@matrix @inverted war", &options).unwrap();
+        assert_eq!(plan.retain_available(&[IndexKind::Inverted]).len(), 1);
+        assert_eq!(plan.structures, vec![IndexKind::Inverted]);
+
+        let mut plan = plan_query("\"war peace\" or fre*", &options).unwrap();
+        assert!(plan.split);
+        let warnings = plan.retain_available(&[IndexKind::Inverted, IndexKind::Wildcard]);
+        assert!(!plan.split);
+        assert!(plan.structures.is_empty());
+        assert_eq!(
+            warnings.last().unwrap(),
+            "no saved structure evaluates phrases, skipped the query"
+        );
+
+        let mut plan = plan_query("\"war peace\"", &options).unwrap();
+        plan.retain_available(&[IndexKind::Inverted, IndexKind::Bigram]);
+        assert_eq!(plan.structures, vec![IndexKind::Bigram]);
+        assert!(plan.retain_available(&ALL_STRUCTURES).is_empty());
+    }
+
+    #[test]
+    fn test_stem_expansion_hint() {
+        let options = PlannerOptions::default();
+        let plan = plan_query("@expand=5 tolst* and war", &options).unwrap();
+        assert_eq!(plan.expansion_limit, Some(5));
+        assert_eq!(plan.structures, vec![IndexKind::Inverted]);
+
+        let options = PlannerOptions {
+            needs_positions: false,
+            expansion_limit: Some(10),
+        };
+        let plan = plan_query("@expand=0 tolst*", &options).unwrap();
+        assert_eq!(plan.expansion_limit, None);
+        assert_eq!(plan.structures, vec![IndexKind::Wildcard]);
+
+        // Infix patterns still need the wildcard engine
+        let plan = plan_query("t*lst*", &options).unwrap();
+        assert_eq!(plan.structures, vec![IndexKind::Wildcard]);
+    }
+
+    #[test]
+    fn test_capabilities_reject_unsupported_structures() {
+        let options = PlannerOptions::default();
+        let mut plan =
+            plan_query("@matrix @coordinate \"war peace\" and not anna", &options).unwrap();
+        assert_eq!(plan.required, Capabilities::PHRASES | Capabilities::NOT);
+        assert_eq!(
+            plan.unsupported(IndexKind::Matrix).unwrap(),
+            "Unsupported operator: the matrix index has no phrases"
+        );
+        assert_eq!(
+            plan.retain_supported(),
+            vec!["Unsupported operator: the matrix index has no phrases".to_string()]
+        );
+        assert_eq!(plan.structures, vec![IndexKind::Coordinate]);
+
+        let plan = plan_query("@all near/2(war peace)", &options).unwrap();
+        assert_eq!(plan.structures, vec![IndexKind::Coordinate]);
+        let plan = plan_query("@all war or not peace", &options).unwrap();
+        assert_eq!(
+            plan.structures,
+            vec![
+                IndexKind::Matrix,
+                IndexKind::Inverted,
+                IndexKind::Coordinate
+            ]
+        );
+        assert!(IndexKind::Wildcard
+            .capabilities()
+            .contains(Capabilities::WILDCARDS));
+        assert_eq!(
+            IndexKind::Inverted.capabilities().missing(plan.required),
+            Capabilities::empty()
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+
+/// Word position of the first word of every sentence, per document
+pub type SentenceStarts = HashMap<String, Vec<usize>>;
+
+/// Inclusive `(start, end)` word positions covered by one match
+pub type Span = (usize, usize);
+
+/// Positional matches per document, the intermediate result of phrase and proximity
+/// evaluation. Operations combine two postings into a new one, so positional constraints
+/// compose without going back to the index, e.g. the phrase postings of "анна каренина"
+/// `within` 10 words of the `union` of the term postings of "поезд" and "станция".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PositionPostings {
+    /// Sorted, deduplicated spans; documents without a match are absent
+    spans: HashMap<String, Vec<Span>>,
+}
+
+impl PositionPostings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Single-word matches at the given positions
+    pub fn from_positions<I>(positions: I) -> Self
+    where
+        I: IntoIterator<Item = (String, Vec<usize>)>,
+    {
+        Self::from_spans(positions.into_iter().map(|(document, positions)| {
+            (document, positions.into_iter().map(|p| (p, p)).collect())
+        }))
+    }
+
+    pub fn from_spans<I>(spans: I) -> Self
+    where
+        I: IntoIterator<Item = (String, Vec<Span>)>,
+    {
+        let mut postings = PositionPostings::new();
+        for (document, spans) in spans {
+            postings.insert(document, spans);
+        }
+        postings
+    }
+
+    fn insert(&mut self, document: String, mut spans: Vec<Span>) {
+        if spans.is_empty() {
+            return;
+        }
+        spans.sort_unstable();
+        spans.dedup();
+        self.spans.insert(document, spans);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Number of documents with at least one match
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn spans(&self, document: &str) -> &[Span] {
+        self.spans
+            .get(document)
+            .map_or(&[], |spans| spans.as_slice())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &[Span])> {
+        self.spans
+            .iter()
+            .map(|(document, spans)| (document, spans.as_slice()))
+    }
+
+    pub fn documents(&self) -> HashSet<String> {
+        self.spans.keys().cloned().collect()
+    }
+
+    /// Matches of either side
+    pub fn union(&self, other: &PositionPostings) -> PositionPostings {
+        let mut result = self.clone();
+        for (document, spans) in &other.spans {
+            let mut merged = result.spans.remove(document).unwrap_or_default();
+            merged.extend(spans);
+            result.insert(document.clone(), merged);
+        }
+        result
+    }
+
+    /// Matches of both sides, in documents where both sides match
+    pub fn intersect(&self, other: &PositionPostings) -> PositionPostings {
+        self.combine(other, |left, right| {
+            left.iter().chain(right).copied().collect()
+        })
+    }
+
+    /// Spans of `self` immediately followed by a span of `next`, joined into one span
+    pub fn adjacent(&self, next: &PositionPostings) -> PositionPostings {
+        self.ordered_within(next, 1)
+    }
+
+    /// Spans of `self` followed by a span of `next` starting at most `max_gap` words after
+    /// the end of `self`, joined into one span
+    pub fn ordered_within(&self, next: &PositionPostings, max_gap: usize) -> PositionPostings {
+        self.combine(next, |left, right| {
+            let mut joined = Vec::new();
+            for &(start, end) in left {
+                let first = right.partition_point(|&(next_start, _)| next_start <= end);
+                joined.extend(
+                    right[first..]
+                        .iter()
+                        .take_while(|&&(next_start, _)| next_start <= end + max_gap)
+                        .map(|&(_, next_end)| (start, next_end)),
+                );
+            }
+            joined
+        })
+    }
+
+    /// Spans of `self` whose start lies within `max_distance` words of the start of some span
+    /// of `other`, in either order. The spans of `self` are kept as they are, so chaining
+    /// `within` measures every operand from the same anchor.
+    pub fn within(&self, other: &PositionPostings, max_distance: usize) -> PositionPostings {
+        self.combine(other, |left, right| {
+            left.iter()
+                .copied()
+                .filter(|&(start, _)| {
+                    let first = right
+                        .partition_point(|&(other_start, _)| other_start + max_distance < start);
+                    right
+                        .get(first)
+                        .is_some_and(|&(other_start, _)| other_start <= start + max_distance)
+                })
+                .collect()
+        })
+    }
+
+    /// Spans of `self` starting in the same sentence as some span of `other`
+    pub fn same_sentence(
+        &self,
+        other: &PositionPostings,
+        sentence_starts: &SentenceStarts,
+    ) -> PositionPostings {
+        let mut result = PositionPostings::new();
+        for (document, left) in &self.spans {
+            let (Some(right), Some(starts)) =
+                (other.spans.get(document), sentence_starts.get(document))
+            else {
+                continue;
+            };
+            let sentence_of = |position: usize| starts.partition_point(|&s| s <= position);
+            let sentences: HashSet<usize> =
+                right.iter().map(|&(start, _)| sentence_of(start)).collect();
+            let kept = left
+                .iter()
+                .copied()
+                .filter(|&(start, _)| sentences.contains(&sentence_of(start)))
+                .collect();
+            result.insert(document.clone(), kept);
+        }
+        result
+    }
+
+    /// Apply `f` to the spans of every document both sides match
+    fn combine<F>(&self, other: &PositionPostings, f: F) -> PositionPostings
+    where
+        F: Fn(&[Span], &[Span]) -> Vec<Span>,
+    {
+        let mut result = PositionPostings::new();
+        for (document, left) in &self.spans {
+            if let Some(right) = other.spans.get(document) {
+                result.insert(document.clone(), f(left, right));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn postings(document: &str, positions: &[usize]) -> PositionPostings {
+        PositionPostings::from_positions([(document.to_string(), positions.to_vec())])
+    }
+
+    #[test]
+    fn test_adjacency_and_ordering() {
+        let anna = postings("doc", &[0, 10]);
+        let karenina = postings("doc", &[1, 20]);
+        let train = postings("doc", &[4, 8]);
+
+        let phrase = anna.adjacent(&karenina);
+        assert_eq!(phrase.spans("doc"), &[(0, 1)]);
+        assert_eq!(phrase.ordered_within(&train, 3).spans("doc"), &[(0, 4)]);
+        assert!(train.ordered_within(&phrase, 20).is_empty());
+
+        // Unordered: the train at 8 is within 2 of the anna at 10
+        assert_eq!(anna.within(&train, 2).spans("doc"), &[(10, 10)]);
+        assert_eq!(anna.union(&train).spans("doc").len(), 4);
+        assert!(anna.intersect(&postings("other", &[0])).is_empty());
+    }
+
+    #[test]
+    fn test_same_sentence() {
+        let anna = postings("doc", &[0, 10]);
+        let train = postings("doc", &[12]);
+        let sentence_starts = SentenceStarts::from([("doc".to_string(), vec![0, 5, 11])]);
+
+        assert!(anna.same_sentence(&train, &sentence_starts).is_empty());
+        let starts = SentenceStarts::from([("doc".to_string(), vec![0, 5])]);
+        assert_eq!(
+            anna.same_sentence(&train, &starts).spans("doc"),
+            &[(10, 10)]
+        );
+    }
+}
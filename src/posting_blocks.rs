@@ -0,0 +1,247 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::DamagedBlock;
+
+/// Keys per posting block; damage to one block costs the postings of this many keys
+pub const POSTING_BLOCK_SIZE: usize = 128;
+
+/// Directory, its CRC32 and its offset end every posting block payload
+const FOOTER_LEN: usize = 4 + 8;
+
+/// Location of one block in the payload, the first key in it and the CRC32 of its bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockHandle {
+    first_key: String,
+    offset: u64,
+    len: u64,
+    checksum: u32,
+}
+
+/// Fields of the structure kept out of the blocks, and where every block is
+#[derive(Serialize, Deserialize)]
+struct BlockDirectory<M> {
+    meta: M,
+    blocks: Vec<BlockHandle>,
+}
+
+/// A structure stored as posting blocks: the fields kept in the directory, the entries of every
+/// intact block in key order and the blocks that are not.
+///
+/// The payload holds the bincode blocks of `POSTING_BLOCK_SIZE` sorted `(key, postings)`
+/// entries, then the directory with the handle of every block, then the directory's CRC32 and
+/// offset as little-endian `u32` and `u64`, the same layout as `TermBlockFile`. Blocks are
+/// read by the offsets in the directory, so a flipped byte anywhere in the blocks, length
+/// prefixes included, costs the keys of one block: reading skips it with a warning and
+/// reports its key range for `grimoire fsck --repair` to rebuild. Only damage to the
+/// directory or the footer loses the whole structure.
+pub struct PostingBlocks<M, T> {
+    pub meta: M,
+    pub entries: Vec<(String, T)>,
+    pub damaged: Vec<DamagedBlock>,
+}
+
+/// Write `meta` and `entries`, which must be sorted by key, as a posting block payload
+pub fn write_posting_blocks<M, K, T, I>(
+    meta: &M,
+    entries: I,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    M: Serialize,
+    K: AsRef<str> + Serialize,
+    T: Serialize,
+    I: IntoIterator<Item = (K, T)>,
+{
+    let mut data = Vec::new();
+    let mut blocks = Vec::new();
+    let mut chunk = Vec::with_capacity(POSTING_BLOCK_SIZE);
+    for entry in entries {
+        chunk.push(entry);
+        if chunk.len() == POSTING_BLOCK_SIZE {
+            blocks.push(append_block(&mut data, &chunk)?);
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        blocks.push(append_block(&mut data, &chunk)?);
+    }
+
+    let directory_offset = data.len() as u64;
+    let directory = bincode::serialize(&BlockDirectory { meta, blocks })?;
+    let checksum = crc32fast::hash(&directory);
+    data.extend(directory);
+    data.extend(checksum.to_le_bytes());
+    data.extend(directory_offset.to_le_bytes());
+    Ok(data)
+}
+
+/// Read a payload written by `write_posting_blocks`. Each damaged block is reported on stderr
+/// as a block of `structure`; a damaged directory fails the whole read.
+pub fn read_posting_blocks<M, T>(
+    data: &[u8],
+    structure: &str,
+) -> Result<PostingBlocks<M, T>, Box<dyn std::error::Error>>
+where
+    M: DeserializeOwned,
+    T: DeserializeOwned,
+{
+    let corrupt = || {
+        format!(
+            "the directory of {} is corrupt; rebuild the index",
+            structure
+        )
+    };
+    let footer = data.len().checked_sub(FOOTER_LEN).ok_or_else(corrupt)?;
+    let (checksum, offset) = data[footer..].split_at(4);
+    let checksum = u32::from_le_bytes(checksum.try_into()?);
+    let directory_offset = u64::from_le_bytes(offset.try_into()?) as usize;
+    let directory = data.get(directory_offset..footer).ok_or_else(corrupt)?;
+    if crc32fast::hash(directory) != checksum {
+        return Err(corrupt().into());
+    }
+    let directory: BlockDirectory<M> = bincode::deserialize(directory)?;
+
+    let mut entries = Vec::new();
+    let mut damaged = Vec::new();
+    for (index, handle) in directory.blocks.iter().enumerate() {
+        match read_block(&data[..directory_offset], handle) {
+            Some(block_entries) => entries.extend(block_entries),
+            None => {
+                let block = DamagedBlock {
+                    block: index,
+                    first_term: handle.first_key.clone(),
+                    end_term: directory
+                        .blocks
+                        .get(index + 1)
+                        .map(|next| next.first_key.clone()),
+                };
+                eprintln!(
+                    "Warning: {} of {} is corrupt, skipping its terms",
+                    block, structure
+                );
+                damaged.push(block);
+            }
+        }
+    }
+    Ok(PostingBlocks {
+        meta: directory.meta,
+        entries,
+        damaged,
+    })
+}
+
+/// Serialize `entries` onto `data` and return their handle
+fn append_block<K, T>(data: &mut Vec<u8>, entries: &[(K, T)]) -> bincode::Result<BlockHandle>
+where
+    K: AsRef<str> + Serialize,
+    T: Serialize,
+{
+    let block = bincode::serialize(entries)?;
+    let handle = BlockHandle {
+        first_key: entries[0].0.as_ref().to_string(),
+        offset: data.len() as u64,
+        len: block.len() as u64,
+        checksum: crc32fast::hash(&block),
+    };
+    data.extend(block);
+    Ok(handle)
+}
+
+/// Entries of the block at `handle`, or `None` when it fails its checksum or no longer decodes
+fn read_block<T: DeserializeOwned>(
+    blocks: &[u8],
+    handle: &BlockHandle,
+) -> Option<Vec<(String, T)>> {
+    let start = usize::try_from(handle.offset).ok()?;
+    let end = start.checked_add(usize::try_from(handle.len).ok()?)?;
+    let block = blocks.get(start..end)?;
+    if crc32fast::hash(block) != handle.checksum {
+        return None;
+    }
+    bincode::deserialize(block).ok()
+}
+
+impl DamagedBlock {
+    /// Whether `key` sorts into the range of keys the block held
+    pub fn covers(&self, key: &str) -> bool {
+        key >= self.first_term.as_str() && self.end_term.as_deref().is_none_or(|end| key < end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        let keys: Vec<String> = (0..300).map(|i| format!("term{:03}", i)).collect();
+        write_posting_blocks(
+            &"meta".to_string(),
+            keys.iter().map(|key| (key.as_str(), key.len() as u32)),
+        )
+        .unwrap()
+    }
+
+    /// Offset of the block starting with `first_key`, found by its bytes
+    fn block_offset(data: &[u8], first_key: &str) -> usize {
+        let key = bincode::serialize(first_key).unwrap();
+        let position = data
+            .windows(key.len())
+            .position(|window| window == key)
+            .unwrap();
+        // A block starts with its entry count, right before the first key
+        position - 8
+    }
+
+    #[test]
+    fn test_damaged_blocks_are_skipped_and_reported() {
+        let mut data = payload();
+        let offset = block_offset(&data, "term128");
+        data[offset + 30] ^= 0xff;
+        let blocks: PostingBlocks<String, u32> =
+            read_posting_blocks(&data, "the test structure").unwrap();
+
+        assert_eq!(blocks.meta, "meta");
+        assert_eq!(blocks.entries.len(), 172);
+        assert_eq!(blocks.entries[128], ("term256".to_string(), 7));
+        let expected = DamagedBlock {
+            block: 1,
+            first_term: "term128".to_string(),
+            end_term: Some("term256".to_string()),
+        };
+        assert_eq!(blocks.damaged, vec![expected.clone()]);
+        assert!(expected.covers("term200"));
+        assert!(!expected.covers("term256"));
+        assert!(!expected.covers("term127"));
+    }
+
+    #[test]
+    fn test_damaged_length_prefix_costs_one_block() {
+        let mut data = payload();
+        // The entry count of the middle block and the length prefix of its first key
+        let offset = block_offset(&data, "term128");
+        data[offset] ^= 0xff;
+        data[offset + 8] ^= 0x40;
+        let blocks: PostingBlocks<String, u32> =
+            read_posting_blocks(&data, "the test structure").unwrap();
+        assert_eq!(blocks.entries.len(), 172);
+        assert_eq!(blocks.entries[127].0, "term127");
+        assert_eq!(blocks.entries[128].0, "term256");
+        assert_eq!(blocks.damaged.len(), 1);
+
+        let mut data = payload();
+        data[0] ^= 0xff;
+        let blocks: PostingBlocks<String, u32> =
+            read_posting_blocks(&data, "the test structure").unwrap();
+        assert_eq!(blocks.entries.len(), 172);
+        assert_eq!(blocks.damaged[0].first_term, "term000");
+    }
+
+    #[test]
+    fn test_damaged_directory_fails_the_read() {
+        let mut data = payload();
+        let directory_end = data.len() - FOOTER_LEN;
+        data[directory_end - 5] ^= 0xff;
+        assert!(read_posting_blocks::<String, u32>(&data, "the test structure").is_err());
+        assert!(read_posting_blocks::<String, u32>(&data[..4], "the test structure").is_err());
+    }
+}
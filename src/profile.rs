@@ -0,0 +1,343 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{peak_resident_memory, TrackingAllocator};
+
+/// Width of the bar of a phase taking the whole build
+const BAR_WIDTH: usize = 40;
+
+/// Time spent in one build phase. Nested phases use `;`-separated paths like
+/// `structures;bigram`, as in collapsed flame graph stacks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub path: String,
+    pub millis: f64,
+    /// Most heap bytes allocated at once since the previous phase was recorded, known when
+    /// the binary runs on `TrackingAllocator`
+    #[serde(default)]
+    pub peak_bytes: Option<u64>,
+}
+
+/// Parse time of one document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentTiming {
+    pub document: String,
+    pub bytes: u64,
+    pub words: usize,
+    pub parse_millis: f64,
+}
+
+/// What one parse worker did over the parse phase
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerUtilization {
+    pub worker: usize,
+    pub files: usize,
+    /// Files taken from another worker's queue
+    pub stolen: usize,
+    pub bytes: u64,
+    /// Time spent reading and tokenizing files
+    pub busy_millis: f64,
+    /// Time spent waiting for room in the queue to the analyze stage
+    pub blocked_millis: f64,
+}
+
+/// Where the time of a build went, saved as JSON so runs can be compared
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildProfile {
+    pub phases: Vec<PhaseTiming>,
+    pub documents: Vec<DocumentTiming>,
+    /// Empty in profiles saved before workers were tracked
+    #[serde(default)]
+    pub workers: Vec<WorkerUtilization>,
+    /// Most memory the build had resident, from `record_peak_resident`
+    #[serde(default)]
+    pub peak_resident_bytes: Option<u64>,
+}
+
+impl BuildProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn path(prefix: &str) -> String {
+        format!("{}_profile.json", prefix)
+    }
+
+    /// Add `elapsed` to the phase at `path`, which ends now. The heap peak since the previous
+    /// phase was recorded is taken as the phase's, so phases should be recorded as they end.
+    pub fn record(&mut self, path: &str, elapsed: Duration) {
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        let peak_bytes = TrackingAllocator::take_peak();
+        match self.phases.iter_mut().find(|phase| phase.path == path) {
+            Some(phase) => {
+                phase.millis += millis;
+                phase.peak_bytes = phase.peak_bytes.max(peak_bytes);
+            }
+            None => self.phases.push(PhaseTiming {
+                path: path.to_string(),
+                millis,
+                peak_bytes,
+            }),
+        }
+    }
+
+    /// Note the most memory the process has had resident so far
+    pub fn record_peak_resident(&mut self) {
+        self.peak_resident_bytes = peak_resident_memory();
+    }
+
+    /// Heap peak of the phase at `path` or of any phase nested in it
+    pub fn peak_bytes(&self, path: &str) -> Option<u64> {
+        self.phases
+            .iter()
+            .filter(|phase| {
+                phase.path == path
+                    || phase
+                        .path
+                        .strip_prefix(path)
+                        .is_some_and(|rest| rest.starts_with(';'))
+            })
+            .filter_map(|phase| phase.peak_bytes)
+            .max()
+    }
+
+    /// Heap peak of every phase as an indented tree and the peak resident memory, empty when
+    /// memory was not tracked
+    pub fn memory_report(&self) -> String {
+        let mut report = String::new();
+        for (path, _) in self.totals() {
+            if let Some(peak) = self.peak_bytes(&path) {
+                let depth = path.matches(';').count();
+                let name = path.rsplit(';').next().unwrap_or(&path);
+                let _ = writeln!(
+                    report,
+                    "{:<28} {:>10} peak heap",
+                    format!("{}{}", "  ".repeat(depth), name),
+                    megabytes(peak)
+                );
+            }
+        }
+        if let Some(resident) = self.peak_resident_bytes {
+            let _ = writeln!(
+                report,
+                "{:<28} {:>10}",
+                "peak resident",
+                megabytes(resident)
+            );
+        }
+        report
+    }
+
+    pub fn record_document(&mut self, document: &str, bytes: u64, words: usize, elapsed: Duration) {
+        self.documents.push(DocumentTiming {
+            document: document.to_string(),
+            bytes,
+            words,
+            parse_millis: elapsed.as_secs_f64() * 1000.0,
+        });
+    }
+
+    pub fn record_worker(&mut self, worker: WorkerUtilization) {
+        self.workers.push(worker);
+    }
+
+    /// Share of the parse phase `worker` spent parsing
+    pub fn utilization(&self, worker: &WorkerUtilization) -> f64 {
+        let parse = self
+            .phases
+            .iter()
+            .find(|phase| phase.path == "parse")
+            .map_or(0.0, |phase| phase.millis);
+        if parse > 0.0 {
+            (worker.busy_millis / parse).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Every phase and every ancestor of one in first-recorded order, an ancestor's time being
+    /// its own plus its descendants'
+    pub fn totals(&self) -> Vec<(String, f64)> {
+        let mut totals: Vec<(String, f64)> = Vec::new();
+        for phase in &self.phases {
+            let mut end = 0;
+            for segment in phase.path.split(';') {
+                end += segment.len();
+                let path = &phase.path[..end];
+                match totals.iter_mut().find(|(existing, _)| existing == path) {
+                    Some((_, millis)) => *millis += phase.millis,
+                    None => totals.push((path.to_string(), phase.millis)),
+                }
+                end += 1;
+            }
+        }
+        totals
+    }
+
+    /// Time of all phases together
+    pub fn total_millis(&self) -> f64 {
+        self.phases.iter().map(|phase| phase.millis).sum()
+    }
+
+    /// Phases as an indented tree with bars proportional to their share of the build and their
+    /// heap peaks where known, then the `slowest` documents to parse and how busy each parse
+    /// worker was. With a `previous` profile each phase shows its change.
+    pub fn report(&self, slowest: usize, previous: Option<&BuildProfile>) -> String {
+        let total = self.total_millis();
+        let previous_totals = previous.map(|previous| previous.totals());
+        let mut report = String::new();
+
+        for (path, millis) in self.totals() {
+            let depth = path.matches(';').count();
+            let name = path.rsplit(';').next().unwrap_or(&path);
+            let share = if total > 0.0 { millis / total } else { 0.0 };
+            let _ = write!(
+                report,
+                "{:<28} {:>10.1} ms {:>5.1}% {:<width$}",
+                format!("{}{}", "  ".repeat(depth), name),
+                millis,
+                share * 100.0,
+                "#".repeat((share * BAR_WIDTH as f64).round() as usize),
+                width = BAR_WIDTH
+            );
+            if let Some(peak) = self.peak_bytes(&path) {
+                let _ = write!(report, " {:>10}", megabytes(peak));
+            }
+            let before = previous_totals.as_ref().and_then(|totals| {
+                totals
+                    .iter()
+                    .find(|(other, _)| *other == path)
+                    .map(|(_, millis)| *millis)
+            });
+            if let Some(before) = before.filter(|&before| before > 0.0) {
+                let _ = write!(report, " {:+.1}%", (millis - before) / before * 100.0);
+            }
+            report.push('\n');
+        }
+        let _ = writeln!(report, "{:<28} {:>10.1} ms", "total", total);
+        if let Some(resident) = self.peak_resident_bytes {
+            let _ = writeln!(
+                report,
+                "{:<28} {:>10}",
+                "peak resident",
+                megabytes(resident)
+            );
+        }
+
+        let mut documents: Vec<&DocumentTiming> = self.documents.iter().collect();
+        documents.sort_by(|a, b| b.parse_millis.total_cmp(&a.parse_millis));
+        if !documents.is_empty() && slowest > 0 {
+            let _ = writeln!(report, "\nSlowest documents to parse:");
+            for document in documents.into_iter().take(slowest) {
+                let _ = writeln!(
+                    report,
+                    "{:>10.1} ms  {} ({} bytes, {} words)",
+                    document.parse_millis, document.document, document.bytes, document.words
+                );
+            }
+        }
+        if !self.workers.is_empty() {
+            let _ = writeln!(report, "\nParse workers:");
+            for worker in &self.workers {
+                let _ = writeln!(
+                    report,
+                    "  worker {:>2}: {:>5.1}% busy, {} files ({} stolen), {} bytes, \
+                     {:.1} ms blocked on the analyze queue",
+                    worker.worker,
+                    self.utilization(worker) * 100.0,
+                    worker.files,
+                    worker.stolen,
+                    worker.bytes,
+                    worker.blocked_millis
+                );
+            }
+        }
+        report
+    }
+
+    /// Load a saved profile, or `None` when there is none
+    pub fn load(path: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    pub fn save(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, &json)?;
+        Ok(json.len())
+    }
+}
+
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_048_576.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_phases_roll_up_into_parents() {
+        let mut profile = BuildProfile::new();
+        profile.record("parse", Duration::from_millis(60));
+        profile.record("structures;matrix", Duration::from_millis(10));
+        profile.record("structures;bigram", Duration::from_millis(30));
+        profile.record("structures;matrix", Duration::from_millis(10));
+        profile.record_document("a.fb2", 100, 10, Duration::from_millis(5));
+        profile.record_document("b.fb2", 300, 30, Duration::from_millis(15));
+        let worker = WorkerUtilization {
+            worker: 1,
+            files: 2,
+            stolen: 1,
+            bytes: 400,
+            busy_millis: 45.0,
+            blocked_millis: 5.0,
+        };
+        profile.record_worker(worker.clone());
+        assert_eq!(profile.utilization(&worker), 0.75);
+
+        let totals: Vec<(String, u64)> = profile
+            .totals()
+            .into_iter()
+            .map(|(path, millis)| (path, millis.round() as u64))
+            .collect();
+        assert_eq!(
+            totals,
+            vec![
+                ("parse".to_string(), 60),
+                ("structures".to_string(), 50),
+                ("structures;matrix".to_string(), 20),
+                ("structures;bigram".to_string(), 30),
+            ]
+        );
+        assert_eq!(profile.total_millis().round() as u64, 110);
+
+        let mut previous = BuildProfile::new();
+        previous.record("parse", Duration::from_millis(30));
+        let report = profile.report(1, Some(&previous));
+        assert!(report.contains("+100.0%"));
+        assert!(report.contains("  matrix"));
+        assert!(report.contains("b.fb2 (300 bytes, 30 words)"));
+        assert!(!report.contains("a.fb2"));
+        assert!(report.contains("worker  1:  75.0% busy, 2 files (1 stolen), 400 bytes"));
+
+        // Heap peaks roll up to the largest of the nested phases
+        profile.phases[1].peak_bytes = Some(3 << 20);
+        profile.phases[2].peak_bytes = Some(5 << 20);
+        profile.peak_resident_bytes = Some(8 << 20);
+        assert_eq!(profile.peak_bytes("structures"), Some(5 << 20));
+        assert_eq!(profile.peak_bytes("struct"), None);
+        assert_eq!(profile.peak_bytes("parse"), None);
+        let memory = profile.memory_report();
+        assert!(memory.contains("structures                       5.0 MB peak heap"));
+        assert!(memory.contains("  matrix                         3.0 MB peak heap"));
+        assert!(!memory.contains("parse"));
+        assert!(memory.contains("peak resident                    8.0 MB"));
+        assert!(BuildProfile::new().memory_report().is_empty());
+    }
+}
@@ -1,19 +1,120 @@
+use std::fmt;
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::{is_indexable_word, Analyzer, OperatorAliases};
+
+/// Query features a search structure can evaluate. Plain terms combined with AND and OR are
+/// supported everywhere and have no flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// Quoted phrases
+    pub const PHRASES: Capabilities = Capabilities(1);
+    /// Word positions, for near/N proximity
+    pub const POSITIONS: Capabilities = Capabilities(1 << 1);
+    /// `*` and `?` patterns anywhere in a term
+    pub const WILDCARDS: Capabilities = Capabilities(1 << 2);
+    pub const NOT: Capabilities = Capabilities(1 << 3);
+    /// Per-document frequencies to rank results by
+    pub const RANKING: Capabilities = Capabilities(1 << 4);
+
+    const NAMES: [(Capabilities, &'static str); 5] = [
+        (Capabilities::PHRASES, "phrases"),
+        (Capabilities::POSITIONS, "near/N proximity"),
+        (Capabilities::WILDCARDS, "wildcards"),
+        (Capabilities::NOT, "NOT"),
+        (Capabilities::RANKING, "ranking"),
+    ];
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn union(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Flags of `required` not in `self`
+    pub fn missing(self, required: Capabilities) -> Capabilities {
+        Capabilities(required.0 & !self.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+}
+
+/// Comma-separated feature names, or `none`
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
 pub trait QueryParser {
     type Result;
     type Error;
 
+    /// Query features `search` evaluates
+    const CAPABILITIES: Capabilities;
+
     fn search(&self, query: &str) -> Result<Self::Result, Self::Error>;
     fn search_term(&self, term: &str) -> Result<Self::Result, Self::Error>;
 }
 
+/// Marks a query token as a literal term even when it spells an operator, e.g. `\\and`
+pub const RAW_TERM_ESCAPE: char = '\\';
+
+/// Words the query parsers read as operators rather than terms, installed aliases included
+pub fn is_reserved_word(token: &str) -> bool {
+    matches!(token, "and" | "or" | "not")
+        || token.starts_with("near/")
+        || OperatorAliases::current().canonical(token).is_some()
+}
+
+/// The term a token stands for: escaped tokens lose their escape, others are returned as is
+pub fn raw_term(token: &str) -> &str {
+    token.strip_prefix(RAW_TERM_ESCAPE).unwrap_or(token)
+}
+
+/// Split a query into words, parentheses and quotes. A reserved word quoted on its own, as in
+/// `"and"`, comes out escaped like `\\and`, so both spellings search for the word itself.
+/// Operator aliases outside phrases come out as the English keywords.
 pub fn tokenize(query: &str) -> Result<Vec<String>, String> {
     let mut tokens = Vec::new();
     let mut current_token = String::new();
-    let mut chars = query.chars().peekable();
 
-    while let Some(ch) = chars.next() {
+    for ch in query.chars() {
         match ch {
-            '(' | ')' => {
+            '(' | ')' | '"' => {
                 if !current_token.is_empty() {
                     tokens.push(current_token.trim().to_string());
                     current_token.clear();
@@ -36,5 +137,522 @@ pub fn tokenize(query: &str) -> Result<Vec<String>, String> {
         tokens.push(current_token.trim().to_string());
     }
 
-    Ok(tokens)
+    Ok(canonicalize_operators(escape_quoted_reserved_words(tokens)))
+}
+
+fn canonicalize_operators(mut tokens: Vec<String>) -> Vec<String> {
+    let aliases = OperatorAliases::current();
+    let mut in_phrase = false;
+    for token in &mut tokens {
+        if token == "\"" {
+            in_phrase = !in_phrase;
+        } else if !in_phrase {
+            if let Some(keyword) = aliases.canonical(token) {
+                *token = keyword;
+            }
+        }
+    }
+    tokens
+}
+
+fn escape_quoted_reserved_words(tokens: Vec<String>) -> Vec<String> {
+    let mut escaped: Vec<String> = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] == "\""
+            && i + 2 < tokens.len()
+            && tokens[i + 2] == "\""
+            && is_reserved_word(&tokens[i + 1])
+        {
+            escaped.push(format!("{}{}", RAW_TERM_ESCAPE, tokens[i + 1]));
+            i += 3;
+        } else {
+            escaped.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+    escaped
+}
+
+/// Positive terms of a Boolean query, spelled by the installed analyzer: operators,
+/// parentheses, quotes, negated operands, phrase-prefix patterns and dropped words are left out
+pub fn query_terms(query: &str) -> Result<Vec<String>, String> {
+    let analyzer = Analyzer::current();
+    let tokens = tokenize(&query.to_lowercase())?;
+    let mut terms = Vec::new();
+    let mut negated_depth: Option<usize> = None;
+    let mut depth = 0;
+    let mut negate_next = false;
+    let mut in_phrase = false;
+    let mut negated_phrase = false;
+
+    for token in &tokens {
+        match token.as_str() {
+            "\"" => {
+                if !in_phrase {
+                    negated_phrase = negate_next;
+                    negate_next = false;
+                }
+                in_phrase = !in_phrase;
+            }
+            _ if in_phrase => {
+                // A trailing `prefix*` word is a pattern, not a term
+                if !negated_phrase && negated_depth.is_none() && !token.ends_with('*') {
+                    terms.extend(analyzer.analyze(token));
+                }
+            }
+            "(" => {
+                if negate_next && negated_depth.is_none() {
+                    negated_depth = Some(depth);
+                }
+                negate_next = false;
+                depth += 1;
+            }
+            ")" => {
+                depth = depth.saturating_sub(1);
+                if negated_depth == Some(depth) {
+                    negated_depth = None;
+                }
+            }
+            "and" | "or" => {}
+            "not" => negate_next = true,
+            _ if token.starts_with("near/") => {}
+            _ => {
+                if !negate_next && negated_depth.is_none() {
+                    terms.extend(analyzer.analyze(raw_term(token)));
+                }
+                negate_next = false;
+            }
+        }
+    }
+
+    Ok(terms)
+}
+
+/// Rewrite the standalone terms of a Boolean query. Operators, parentheses, quoted phrases and
+/// near/N groups are passed through; `rewrite` returns a replacement or `None` to keep the term.
+pub fn rewrite_terms<F>(query: &str, mut rewrite: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let tokens = tokenize(&query.to_lowercase())?;
+    let mut rewritten: Vec<String> = Vec::with_capacity(tokens.len());
+    let mut in_phrase = false;
+    // Quotes are separate tokens; glue them back onto the words they enclose
+    let mut open_quote = false;
+    let mut near_depth: Option<usize> = None;
+    let mut depth: usize = 0;
+
+    for token in tokens {
+        match token.as_str() {
+            "(" => depth += 1,
+            ")" => {
+                depth = depth.saturating_sub(1);
+                if near_depth == Some(depth) {
+                    near_depth = None;
+                }
+            }
+            _ => {}
+        }
+
+        if token == "\"" {
+            match rewritten.last_mut() {
+                Some(last) if in_phrase && !open_quote => last.push('"'),
+                _ if in_phrase => rewritten.push("\"\"".to_string()),
+                _ => open_quote = true,
+            }
+            if in_phrase {
+                open_quote = false;
+            }
+            in_phrase = !in_phrase;
+            continue;
+        }
+
+        // Escaped terms are literal, so they are never rewritten
+        let is_term = !matches!(token.as_str(), "(" | ")" | "and" | "or" | "not")
+            && !token.starts_with("near/")
+            && !token.starts_with(RAW_TERM_ESCAPE)
+            && !in_phrase
+            && near_depth.is_none();
+
+        if token.starts_with("near/") {
+            near_depth = Some(depth);
+        }
+
+        let mut output = if is_term { rewrite(&token) } else { None }.unwrap_or(token);
+        if open_quote {
+            output.insert(0, '"');
+            open_quote = false;
+        }
+        rewritten.push(output);
+    }
+
+    Ok(rewritten.join(" "))
+}
+
+/// A single term, or a parenthesized OR group of alternatives
+pub fn or_group(terms: &[String]) -> String {
+    if terms.len() == 1 {
+        terms[0].clone()
+    } else {
+        format!("( {} )", terms.join(" or "))
+    }
+}
+
+/// A parsed Boolean query. NOT binds tightest, then AND, then OR; `near/N( ... )` takes the
+/// operands up to its closing parenthesis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryAst {
+    Term(String),
+    /// Words between quotes as written, including ones the tokenizer drops
+    Phrase(Vec<String>),
+    /// Matches of the first operand with a match of every other operand within `distance`
+    /// words. Operands the tokenizer would drop are left out when parsing.
+    Near {
+        distance: usize,
+        operands: Vec<QueryAst>,
+    },
+    /// A term containing `*` or `?`
+    Wildcard(String),
+    And(Box<QueryAst>, Box<QueryAst>),
+    Or(Box<QueryAst>, Box<QueryAst>),
+    Not(Box<QueryAst>),
+}
+
+impl QueryAst {
+    /// Parse a query with the installed analyzer, see `parse_with`
+    pub fn parse(query: &str) -> Result<QueryAst, String> {
+        Self::parse_with(query, &Analyzer::current())
+    }
+
+    /// Parse a query, lowercasing it first, and run its terms through `analyzer` so they are
+    /// spelled like the index terms. Tokens after the first complete expression are ignored.
+    pub fn parse_with(query: &str, analyzer: &Analyzer) -> Result<QueryAst, String> {
+        let tokens = tokenize(&query.to_lowercase())?;
+        let parsed = AstParser {
+            tokens: &tokens,
+            pos: 0,
+        }
+        .parse_or(false)?;
+        if analyzer.is_plain() {
+            return Ok(parsed);
+        }
+        parsed
+            .analyzed(analyzer)
+            .ok_or_else(|| "Every query term is a stopword".to_string())
+    }
+
+    /// The query with its terms analyzed. Operands whose terms are all dropped disappear
+    /// along with their operator, as the index has nothing to match them against.
+    fn analyzed(self, analyzer: &Analyzer) -> Option<QueryAst> {
+        let analyze_word = |word: &str| {
+            if word.contains(['*', '?']) {
+                Some(analyzer.normalize(word))
+            } else {
+                analyzer.analyze(word)
+            }
+        };
+        let combine =
+            |left: Option<QueryAst>, right: Option<QueryAst>, and: bool| match (left, right) {
+                (Some(left), Some(right)) if and => {
+                    Some(QueryAst::And(Box::new(left), Box::new(right)))
+                }
+                (Some(left), Some(right)) => Some(QueryAst::Or(Box::new(left), Box::new(right))),
+                (operand, None) | (None, operand) => operand,
+            };
+
+        match self {
+            QueryAst::Term(term) => analyzer.analyze(&term).map(QueryAst::Term),
+            QueryAst::Wildcard(pattern) => Some(QueryAst::Wildcard(analyzer.normalize(&pattern))),
+            QueryAst::Phrase(words) => {
+                let words: Vec<String> = words.iter().filter_map(|w| analyze_word(w)).collect();
+                (!words.is_empty()).then_some(QueryAst::Phrase(words))
+            }
+            QueryAst::Near { distance, operands } => {
+                let mut operands: Vec<QueryAst> = operands
+                    .into_iter()
+                    .filter_map(|operand| operand.analyzed(analyzer))
+                    .collect();
+                match operands.len() {
+                    0 => None,
+                    1 => operands.pop(),
+                    _ => Some(QueryAst::Near { distance, operands }),
+                }
+            }
+            QueryAst::And(left, right) => {
+                combine(left.analyzed(analyzer), right.analyzed(analyzer), true)
+            }
+            QueryAst::Or(left, right) => {
+                combine(left.analyzed(analyzer), right.analyzed(analyzer), false)
+            }
+            QueryAst::Not(operand) => operand
+                .analyzed(analyzer)
+                .map(|operand| QueryAst::Not(Box::new(operand))),
+        }
+    }
+}
+
+struct AstParser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> AstParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    /// `positional` is set inside near operands, where NOT has no meaning
+    fn parse_or(&mut self, positional: bool) -> Result<QueryAst, String> {
+        let mut result = self.parse_and(positional)?;
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            let right = self.parse_and(positional)?;
+            result = QueryAst::Or(Box::new(result), Box::new(right));
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self, positional: bool) -> Result<QueryAst, String> {
+        let mut result = self.parse_not(positional)?;
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            let right = self.parse_not(positional)?;
+            result = QueryAst::And(Box::new(result), Box::new(right));
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self, positional: bool) -> Result<QueryAst, String> {
+        if self.peek() == Some("not") {
+            if positional {
+                return Err("NOT cannot be used inside a near operand".to_string());
+            }
+            self.pos += 1;
+            return Ok(QueryAst::Not(Box::new(self.parse_primary(positional)?)));
+        }
+        self.parse_primary(positional)
+    }
+
+    fn parse_primary(&mut self, positional: bool) -> Result<QueryAst, String> {
+        let Some(token) = self.peek() else {
+            return Err("Unexpected end of query".to_string());
+        };
+        self.pos += 1;
+
+        if token == "(" {
+            let result = self.parse_or(positional)?;
+            if self.peek() != Some(")") {
+                return Err("Missing closing parenthesis".to_string());
+            }
+            self.pos += 1;
+            Ok(result)
+        } else if token == "\"" {
+            let mut words = Vec::new();
+            while let Some(word) = self.peek() {
+                self.pos += 1;
+                if word == "\"" {
+                    return Ok(QueryAst::Phrase(words));
+                }
+                words.push(word.to_string());
+            }
+            Err("Missing closing quote".to_string())
+        } else if let Some(distance) = token.strip_prefix("near/") {
+            let distance = distance
+                .parse()
+                .map_err(|_| format!("Invalid distance in near operator: {}", distance))?;
+            let operands = self.parse_near_operands()?;
+            Ok(QueryAst::Near { distance, operands })
+        } else if token.starts_with(RAW_TERM_ESCAPE) || !token.contains(['*', '?']) {
+            Ok(QueryAst::Term(raw_term(token).to_string()))
+        } else {
+            Ok(QueryAst::Wildcard(token.to_string()))
+        }
+    }
+
+    /// Operands of `near/N( ... )` up to the closing parenthesis
+    fn parse_near_operands(&mut self) -> Result<Vec<QueryAst>, String> {
+        if self.peek() != Some("(") {
+            return Err("Expected '(' after near operator".to_string());
+        }
+        self.pos += 1;
+
+        let mut operands = Vec::new();
+        while let Some(token) = self.peek() {
+            if token == ")" {
+                break;
+            }
+            if matches!(token, "and" | "or") {
+                return Err(format!("Unexpected '{}' in near operator", token));
+            }
+            if token == "not" {
+                return Err("NOT cannot be used inside a near operand".to_string());
+            }
+            if is_dropped_word(token) {
+                // The tokenizer never indexed it, so it takes no part in the distance
+                self.pos += 1;
+                continue;
+            }
+            operands.push(self.parse_primary(true)?);
+        }
+        if self.peek().is_none() {
+            return Err("Missing closing parenthesis for near operator".to_string());
+        }
+        self.pos += 1;
+
+        if operands.len() < 2 {
+            return Err("Near operator requires at least two operands".to_string());
+        }
+        Ok(operands)
+    }
+}
+
+fn is_dropped_word(token: &str) -> bool {
+    !matches!(token, "(" | "\"" | "not")
+        && !token.starts_with("near/")
+        && !is_indexable_word(raw_term(token))
+}
+
+/// Evaluates a `QueryAst` against one search structure. Implementors look up terms and combine
+/// their result type; phrases, proximity and wildcards are errors unless the structure
+/// evaluates them itself.
+pub trait QueryEvaluator {
+    type Output;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String>;
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output;
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output;
+    /// Every document not in `operand`
+    fn complement(&self, operand: Self::Output) -> Self::Output;
+
+    fn evaluate_phrase(&self, words: &[String]) -> Result<Self::Output, String> {
+        Err(format!(
+            "Phrase queries are not supported here: \"{}\"",
+            words.join(" ")
+        ))
+    }
+
+    fn evaluate_near(
+        &self,
+        _distance: usize,
+        _operands: &[QueryAst],
+    ) -> Result<Self::Output, String> {
+        Err("Proximity queries are not supported here".to_string())
+    }
+
+    fn evaluate_wildcard(&self, pattern: &str) -> Result<Self::Output, String> {
+        Err(format!(
+            "Wildcard patterns are not supported here: '{}'",
+            pattern
+        ))
+    }
+
+    /// `operand AND term`; structures that can skip through a posting list override this to
+    /// avoid materializing all of the term's documents
+    fn intersect_term(&self, operand: Self::Output, term: &str) -> Result<Self::Output, String> {
+        Ok(self.intersect(operand, self.evaluate_term(term)?))
+    }
+
+    /// `left AND NOT right`; structures that can subtract override this to skip building the
+    /// complement of `right`
+    fn difference(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.intersect(left, self.complement(right))
+    }
+
+    fn evaluate(&self, query: &QueryAst) -> Result<Self::Output, String> {
+        match query {
+            QueryAst::Term(term) => self.evaluate_term(term),
+            QueryAst::Phrase(words) => self.evaluate_phrase(words),
+            QueryAst::Near { distance, operands } => self.evaluate_near(*distance, operands),
+            QueryAst::Wildcard(pattern) => self.evaluate_wildcard(pattern),
+            QueryAst::And(left, right) => {
+                let left = self.evaluate(left)?;
+                match right.as_ref() {
+                    QueryAst::Term(term) => self.intersect_term(left, term),
+                    QueryAst::Not(operand) => Ok(self.difference(left, self.evaluate(operand)?)),
+                    right => Ok(self.intersect(left, self.evaluate(right)?)),
+                }
+            }
+            QueryAst::Or(left, right) => {
+                let left = self.evaluate(left)?;
+                Ok(self.unite(left, self.evaluate(right)?))
+            }
+            QueryAst::Not(operand) => Ok(self.complement(self.evaluate(operand)?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(term: &str) -> Box<QueryAst> {
+        Box::new(QueryAst::Term(term.to_string()))
+    }
+
+    #[test]
+    fn test_parse_precedence_and_operators() {
+        assert_eq!(
+            QueryAst::parse("War or peace and not Anna").unwrap(),
+            QueryAst::Or(
+                term("war"),
+                Box::new(QueryAst::And(
+                    term("peace"),
+                    Box::new(QueryAst::Not(term("anna")))
+                ))
+            )
+        );
+        assert_eq!(
+            QueryAst::parse("near/3(anna of \"train station\" (kitty or levin)) and tolst*")
+                .unwrap(),
+            QueryAst::And(
+                Box::new(QueryAst::Near {
+                    distance: 3,
+                    operands: vec![
+                        QueryAst::Term("anna".to_string()),
+                        QueryAst::Phrase(vec!["train".to_string(), "station".to_string()]),
+                        QueryAst::Or(term("kitty"), term("levin")),
+                    ],
+                }),
+                Box::new(QueryAst::Wildcard("tolst*".to_string()))
+            )
+        );
+        assert_eq!(QueryAst::parse("\"and\"").unwrap(), *term("and"));
+        assert_eq!(QueryAst::parse("\\why?").unwrap(), *term("why?"));
+
+        for malformed in [
+            "",
+            "(war",
+            "\"war",
+            "near/x(a b)",
+            "near/2 war",
+            "near/2(anna)",
+        ] {
+            assert!(QueryAst::parse(malformed).is_err(), "{}", malformed);
+        }
+        assert_eq!(
+            QueryAst::parse("near/2(anna (not train))"),
+            Err("NOT cannot be used inside a near operand".to_string())
+        );
+        assert_eq!(
+            QueryAst::parse("near/3(not foo, bar)"),
+            Err("NOT cannot be used inside a near operand".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rewrite_terms_passes_structure_through() {
+        let upper = |term: &str| Some(term.to_uppercase());
+        assert_eq!(
+            rewrite_terms(
+                "war and (peace or \"anna karenina\") near/2(kitty levin)",
+                upper
+            )
+            .unwrap(),
+            "WAR and ( PEACE or \"anna karenina\" ) near/2 ( kitty levin )"
+        );
+        // An unbalanced parenthesis is left for the parser to reject
+        assert_eq!(rewrite_terms("war )", upper).unwrap(), "WAR )");
+        assert_eq!(rewrite_terms(") ) war", |_| None).unwrap(), ") ) war");
+    }
 }
@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{CoordinateIndex, DocumentNorms};
+
+/// Query likelihood ranking with Jelinek-Mercer smoothing over coordinate index statistics
+pub struct QueryLikelihoodScorer<'a> {
+    index: &'a CoordinateIndex,
+    /// Weight of the collection language model in the mixture
+    lambda: f64,
+    document_lengths: HashMap<&'a str, usize>,
+    collection_length: usize,
+}
+
+impl<'a> QueryLikelihoodScorer<'a> {
+    pub fn new(index: &'a CoordinateIndex, lambda: f64) -> Self {
+        Self::from_lengths(index, lambda, index.document_lengths())
+    }
+
+    /// Take document lengths from the norms recorded at build time instead of summing the
+    /// posting lists of every term
+    pub fn with_norms(index: &'a CoordinateIndex, lambda: f64, norms: &'a DocumentNorms) -> Self {
+        Self::from_lengths(index, lambda, norms.document_lengths())
+    }
+
+    fn from_lengths(
+        index: &'a CoordinateIndex,
+        lambda: f64,
+        document_lengths: HashMap<&'a str, usize>,
+    ) -> Self {
+        let collection_length = document_lengths.values().sum();
+
+        QueryLikelihoodScorer {
+            index,
+            lambda: lambda.clamp(0.0, 1.0),
+            document_lengths,
+            collection_length,
+        }
+    }
+
+    /// log P(q | d) = sum over query terms of log((1 - lambda) * tf / |d| + lambda * cf / |C|)
+    pub fn score(&self, document: &str, terms: &[String]) -> f64 {
+        let document_length = self.document_lengths.get(document).copied().unwrap_or(0);
+
+        terms
+            .iter()
+            .map(|term| {
+                let (term_frequency, collection_frequency) = self.frequencies(term, document);
+                let document_probability = if document_length > 0 {
+                    term_frequency as f64 / document_length as f64
+                } else {
+                    0.0
+                };
+                let collection_probability = if self.collection_length > 0 {
+                    collection_frequency as f64 / self.collection_length as f64
+                } else {
+                    0.0
+                };
+
+                let probability = (1.0 - self.lambda) * document_probability
+                    + self.lambda * collection_probability;
+                // Terms unseen in the whole collection would zero out every document alike
+                if probability > 0.0 {
+                    probability.ln()
+                } else {
+                    0.0
+                }
+            })
+            .sum()
+    }
+
+    /// Rank `candidates` by descending query likelihood (ties broken by document name)
+    pub fn rank<'d, I>(&self, candidates: I, terms: &[String]) -> Vec<(&'d str, f64)>
+    where
+        I: IntoIterator<Item = &'d String>,
+    {
+        let unique: HashSet<&String> = candidates.into_iter().collect();
+        let mut ranked: Vec<(&str, f64)> = unique
+            .into_iter()
+            .map(|document| (document.as_str(), self.score(document, terms)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        ranked
+    }
+
+    fn frequencies(&self, term: &str, document: &str) -> (usize, usize) {
+        match self.index.index.get(term) {
+            Some(postings) => {
+                let collection_frequency = postings.iter().map(|p| p.positions.len()).sum();
+                let term_frequency = postings
+                    .binary_search_by(|p| p.document.as_str().cmp(document))
+                    .map_or(0, |i| postings[i].positions.len());
+                (term_frequency, collection_frequency)
+            }
+            None => (0, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{CompressedDictionary, Dictionary};
+    use crate::query_terms;
+
+    fn create_test_index() -> CoordinateIndex {
+        let mut dict = Dictionary::new();
+        for doc in ["doc1", "doc2", "doc3"] {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+
+        CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = match doc {
+                "doc1" => "war war war peace",
+                "doc2" => "war and peace and love and more words here",
+                _ => "love story",
+            };
+            Ok(text.split_whitespace().map(|w| w.to_string()).collect())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_denser_document_ranks_higher() {
+        let index = create_test_index();
+        let scorer = QueryLikelihoodScorer::new(&index, 0.1);
+        let terms = query_terms("war and peace").unwrap();
+
+        let ranked = scorer.rank(&index.documents, &terms);
+        assert_eq!(ranked[0].0, "doc1");
+        assert_eq!(ranked[1].0, "doc2");
+        // Smoothing keeps documents missing a term finite
+        assert!(ranked[2].1.is_finite());
+    }
+
+    #[test]
+    fn test_query_terms_skip_operators_and_negations() {
+        let terms =
+            query_terms("war and not (peace or love) or \"big city\" or near/3(a b)").unwrap();
+        assert_eq!(terms, vec!["war", "big", "city", "a", "b"]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::query::query_terms;
+
+/// Append one search to a tab-separated log of `unix_seconds<TAB>result_count<TAB>query` lines
+pub fn append_query_log(
+    path: &str,
+    query: &str,
+    results: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(
+        file,
+        "{}\t{}\t{}",
+        timestamp,
+        results,
+        normalize_query(query)
+    )?;
+    Ok(())
+}
+
+/// Lowercase and collapse whitespace so trivially different spellings share statistics
+fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Aggregated outcome of every logged run of one query
+#[derive(Debug, Clone, Default)]
+pub struct QueryStats {
+    pub runs: u32,
+    /// Runs that returned at least one document
+    pub successes: u32,
+    pub total_results: u64,
+}
+
+impl QueryStats {
+    pub fn success_rate(&self) -> f64 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.successes as f64 / self.runs as f64
+        }
+    }
+
+    /// Successful runs weighted by how reliably the query finds something
+    pub fn score(&self) -> f64 {
+        self.successes as f64 * self.success_rate()
+    }
+}
+
+/// Completions and related queries mined from a query log; only queries that returned
+/// results at least once are suggested
+#[derive(Debug, Default)]
+pub struct QuerySuggester {
+    queries: BTreeMap<String, QueryStats>,
+    /// Query term to the logged queries containing it
+    by_term: HashMap<String, Vec<String>>,
+}
+
+impl QuerySuggester {
+    /// Build from a log written by `append_query_log`; a missing log gives an empty suggester
+    pub fn from_log(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let entries = contents.lines().filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let _timestamp = fields.next()?;
+            let results = fields.next()?.parse().ok()?;
+            Some((fields.next()?.to_string(), results))
+        });
+        Ok(Self::from_entries(entries))
+    }
+
+    pub fn from_entries<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (String, usize)>,
+    {
+        let mut suggester = QuerySuggester::default();
+        for (query, results) in entries {
+            let stats = suggester
+                .queries
+                .entry(normalize_query(&query))
+                .or_default();
+            stats.runs += 1;
+            stats.total_results += results as u64;
+            if results > 0 {
+                stats.successes += 1;
+            }
+        }
+
+        suggester.queries.retain(|_, stats| stats.successes > 0);
+        for query in suggester.queries.keys() {
+            let terms: HashSet<String> = query_terms(query).unwrap_or_default().into_iter().collect();
+            for term in terms {
+                suggester
+                    .by_term
+                    .entry(term)
+                    .or_default()
+                    .push(query.clone());
+            }
+        }
+        suggester
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+
+    /// Logged queries starting with `prefix`, best first
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(&str, &QueryStats)> {
+        let prefix = normalize_query(prefix);
+        let mut completions: Vec<(&str, &QueryStats)> = self
+            .queries
+            .range(prefix.clone()..)
+            .take_while(|(query, _)| query.starts_with(&prefix))
+            .map(|(query, stats)| (query.as_str(), stats))
+            .collect();
+        completions.sort_by(|a, b| b.1.score().total_cmp(&a.1.score()).then(a.0.cmp(b.0)));
+        completions.truncate(limit);
+        completions
+    }
+
+    /// Other logged queries sharing terms with `query`, by number of shared terms then score
+    pub fn related(&self, query: &str, limit: usize) -> Vec<(&str, &QueryStats)> {
+        let query = normalize_query(query);
+        let terms: HashSet<String> = query_terms(&query)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+
+        let mut shared: HashMap<&str, usize> = HashMap::new();
+        for term in &terms {
+            for other in self.by_term.get(term).into_iter().flatten() {
+                if *other != query {
+                    *shared.entry(other.as_str()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut related: Vec<(&str, usize, &QueryStats)> = shared
+            .into_iter()
+            .map(|(other, count)| (other, count, &self.queries[other]))
+            .collect();
+        related.sort_by(|a, b| {
+            b.1.cmp(&a.1)
+                .then(b.2.score().total_cmp(&a.2.score()))
+                .then(a.0.cmp(b.0))
+        });
+        related
+            .into_iter()
+            .take(limit)
+            .map(|(other, _, stats)| (other, stats))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_suggester() -> QuerySuggester {
+        QuerySuggester::from_entries(
+            [
+                ("war and peace", 3),
+                ("War  and peace", 2),
+                ("war or love", 1),
+                ("war or love", 0),
+                ("warm", 0),
+                ("peace", 4),
+            ]
+            .into_iter()
+            .map(|(query, results)| (query.to_string(), results)),
+        )
+    }
+
+    #[test]
+    fn test_completions_rank_by_successes() {
+        let suggester = create_test_suggester();
+        let completions: Vec<&str> = suggester
+            .complete("WAR", 10)
+            .into_iter()
+            .map(|(query, _)| query)
+            .collect();
+
+        // "warm" never returned anything, so it is not suggested
+        assert_eq!(completions, vec!["war and peace", "war or love"]);
+        assert_eq!(suggester.complete("war", 10)[0].1.runs, 2);
+    }
+
+    #[test]
+    fn test_related_queries_share_terms() {
+        let suggester = create_test_suggester();
+        let related: Vec<&str> = suggester
+            .related("peace and war", 10)
+            .into_iter()
+            .map(|(query, _)| query)
+            .collect();
+
+        assert_eq!(related, vec!["war and peace", "peace", "war or love"]);
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+
+use crate::IndexKind;
+
+/// Persistent cache of per-structure query results, valid for exactly one index generation.
+/// Loading it against any other generation yields an empty cache, so swapping in a rebuilt
+/// index never serves stale results and needs no manual flush.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResultCache {
+    generation: u64,
+    capacity: usize,
+    entries: HashMap<String, Vec<String>>,
+    /// Keys in insertion order; the oldest entry is evicted first
+    order: VecDeque<String>,
+}
+
+impl ResultCache {
+    pub fn new(generation: u64, capacity: usize) -> Self {
+        ResultCache {
+            generation,
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Load the cache at `path`; a missing, unreadable or stale cache starts empty
+    pub fn load(path: &str, generation: u64, capacity: usize) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|data| bincode::deserialize::<ResultCache>(&data).ok())
+            .filter(|cache| cache.generation == generation)
+            .map(|mut cache| {
+                cache.capacity = capacity.max(1);
+                cache.evict();
+                cache
+            })
+            .unwrap_or_else(|| Self::new(generation, capacity))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn key(kind: IndexKind, query: &str) -> String {
+        format!("{}\t{}", kind, query)
+    }
+
+    pub fn get(&self, kind: IndexKind, query: &str) -> Option<&Vec<String>> {
+        self.entries.get(&Self::key(kind, query))
+    }
+
+    pub fn insert(&mut self, kind: IndexKind, query: &str, documents: Vec<String>) {
+        let key = Self::key(kind, query);
+        if self.entries.insert(key.clone(), documents).is_none() {
+            self.order.push_back(key);
+        }
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_is_dropped_for_new_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.bin").to_string_lossy().to_string();
+
+        let mut cache = ResultCache::new(3, 2);
+        cache.insert(IndexKind::Inverted, "war", vec!["a.fb2".to_string()]);
+        cache.insert(IndexKind::Bigram, "war", vec![]);
+        cache.insert(IndexKind::Inverted, "peace", vec!["b.fb2".to_string()]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(IndexKind::Inverted, "war").is_none());
+        cache.save(&path).unwrap();
+
+        let same = ResultCache::load(&path, 3, 2);
+        assert_eq!(
+            same.get(IndexKind::Inverted, "peace"),
+            Some(&vec!["b.fb2".to_string()])
+        );
+
+        let rebuilt = ResultCache::load(&path, 4, 2);
+        assert!(rebuilt.is_empty());
+        assert_eq!(rebuilt.generation(), 4);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::experiment::fnv1a;
+
+/// Which documents a build indexes, to try settings on a representative subset before a
+/// full build. A ratio keeps documents by a hash of their name, so the same share of the
+/// same documents is kept on every run and wherever they sit in the input; `max_docs` then
+/// caps the documents kept, in input order.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DocumentSample {
+    pub max_docs: Option<usize>,
+    pub ratio: Option<f64>,
+}
+
+impl DocumentSample {
+    pub fn new(max_docs: Option<usize>, ratio: Option<f64>) -> Result<Self, String> {
+        if let Some(ratio) = ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(format!(
+                    "Sample ratio must be above 0 and at most 1, got {}",
+                    ratio
+                ));
+            }
+        }
+        if max_docs == Some(0) {
+            return Err("--max-docs must be at least 1".to_string());
+        }
+        Ok(DocumentSample { max_docs, ratio })
+    }
+
+    /// Every document is indexed
+    pub fn is_full(&self) -> bool {
+        self.max_docs.is_none() && self.ratio.is_none_or(|ratio| ratio >= 1.0)
+    }
+
+    /// Whether the ratio keeps the document named `key`; the cap is applied separately
+    pub fn keeps(&self, key: &str) -> bool {
+        match self.ratio {
+            Some(ratio) if ratio < 1.0 => {
+                (fnv1a(key.as_bytes()) % 1_000_000) as f64 / 1_000_000.0 < ratio
+            }
+            _ => true,
+        }
+    }
+
+    /// The sampled items, in input order
+    pub fn apply<T, F>(&self, items: Vec<T>, key: F) -> Vec<T>
+    where
+        F: Fn(&T) -> &str,
+    {
+        let mut kept: Vec<T> = items
+            .into_iter()
+            .filter(|item| self.keeps(key(item)))
+            .collect();
+        if let Some(max_docs) = self.max_docs {
+            kept.truncate(max_docs);
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_is_stable_and_proportional() {
+        let names: Vec<String> = (0..2000).map(|i| format!("book{}.fb2", i)).collect();
+        let sample = DocumentSample::new(None, Some(0.25)).unwrap();
+        let kept = sample.apply(names.clone(), |name| name.as_str());
+        assert!((400..600).contains(&kept.len()), "{} kept", kept.len());
+        let mut reversed = names.clone();
+        reversed.reverse();
+        let mut kept_reversed = sample.apply(reversed, |name| name.as_str());
+        kept_reversed.reverse();
+        assert_eq!(kept, kept_reversed);
+
+        let capped = DocumentSample::new(Some(10), Some(0.25)).unwrap();
+        assert_eq!(
+            capped.apply(names.clone(), |name| name.as_str()),
+            kept[..10]
+        );
+        assert!(DocumentSample::default().is_full());
+        assert!(!capped.is_full());
+        assert!(DocumentSample::new(None, Some(0.0)).is_err());
+        assert!(DocumentSample::new(None, Some(1.5)).is_err());
+        assert!(DocumentSample::new(Some(0), None).is_err());
+    }
+}
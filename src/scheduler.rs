@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::source_len;
+
+/// A file handed to a worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTask {
+    /// Position in the file list
+    pub index: usize,
+    pub bytes: u64,
+    /// Taken from another worker's queue
+    pub stolen: bool,
+}
+
+/// Spreads files over parse workers by size. FB2 files range from a few hundred kilobytes to
+/// tens of megabytes, so handing them out in list order can leave one worker on a huge book
+/// while the others have finished. Files are dealt largest first, each to the worker with the
+/// fewest bytes so far, and a worker takes its own largest file next. Parse time is only
+/// roughly proportional to size, so a worker whose queue runs dry steals the smallest file of
+/// the worker with the most bytes left.
+#[derive(Debug)]
+pub struct ParseScheduler {
+    queues: Vec<Mutex<VecDeque<ParseTask>>>,
+    /// Bytes still queued for each worker
+    pending: Vec<AtomicU64>,
+}
+
+impl ParseScheduler {
+    /// Deal files of the given sizes to `workers` queues
+    pub fn new(sizes: &[u64], workers: usize) -> Self {
+        let workers = workers.max(1);
+        let mut order: Vec<usize> = (0..sizes.len()).collect();
+        order.sort_by(|&a, &b| sizes[b].cmp(&sizes[a]).then(a.cmp(&b)));
+
+        let mut queues = vec![VecDeque::new(); workers];
+        let mut dealt = vec![0u64; workers];
+        for index in order {
+            let worker = (0..workers)
+                .min_by_key(|&worker| (dealt[worker], queues[worker].len()))
+                .unwrap();
+            dealt[worker] += sizes[index];
+            queues[worker].push_back(ParseTask {
+                index,
+                bytes: sizes[index],
+                stolen: false,
+            });
+        }
+        ParseScheduler {
+            queues: queues.into_iter().map(Mutex::new).collect(),
+            pending: dealt.into_iter().map(AtomicU64::new).collect(),
+        }
+    }
+
+    /// Schedule `files` by their size on disk; files that cannot be read count as empty and
+    /// are reported when parsed
+    pub fn for_files(files: &[PathBuf], workers: usize) -> Self {
+        let sizes: Vec<u64> = files
+            .iter()
+            .map(|file| source_len(file).unwrap_or(0))
+            .collect();
+        Self::new(&sizes, workers)
+    }
+
+    pub fn workers(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// The next file for `worker`, `None` once every queue is empty
+    pub fn next(&self, worker: usize) -> Option<ParseTask> {
+        if let Some(task) = self.take(worker, VecDeque::pop_front) {
+            return Some(task);
+        }
+        // Nothing is queued after dealing, so an empty sweep means the work is done
+        loop {
+            let victim = (0..self.workers())
+                .filter(|&other| other != worker)
+                .map(|other| (other, self.pending[other].load(Ordering::Relaxed)))
+                .filter(|&(_, bytes)| bytes > 0)
+                .max_by_key(|&(other, bytes)| (bytes, std::cmp::Reverse(other)));
+            let (victim, _) = match victim {
+                Some(victim) => victim,
+                // Empty files leave no bytes pending, so look for them queue by queue
+                None => {
+                    return (0..self.workers())
+                        .find_map(|other| self.take(other, VecDeque::pop_back))
+                        .map(|task| ParseTask {
+                            stolen: true,
+                            ..task
+                        });
+                }
+            };
+            if let Some(task) = self.take(victim, VecDeque::pop_back) {
+                return Some(ParseTask {
+                    stolen: true,
+                    ..task
+                });
+            }
+        }
+    }
+
+    fn take(
+        &self,
+        worker: usize,
+        pop: fn(&mut VecDeque<ParseTask>) -> Option<ParseTask>,
+    ) -> Option<ParseTask> {
+        let task = pop(&mut self.queues[worker].lock().unwrap())?;
+        self.pending[worker].fetch_sub(task.bytes, Ordering::Relaxed);
+        Some(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_are_dealt_largest_first_and_stolen_when_idle() {
+        let sizes = [100, 900, 300, 0, 500, 200];
+        let scheduler = ParseScheduler::new(&sizes, 2);
+
+        // Worker 0 is dealt 900, 100 and the empty file, worker 1 500, 300 and 200
+        let next = |worker| {
+            let task = scheduler.next(worker)?;
+            Some((task.index, task.stolen))
+        };
+        assert_eq!(next(0), Some((1, false)));
+        assert_eq!(next(1), Some((4, false)));
+        assert_eq!(next(0), Some((0, false)));
+        assert_eq!(next(0), Some((3, false)));
+        assert_eq!(next(0), Some((5, true)));
+        assert_eq!(next(1), Some((2, false)));
+        assert_eq!(next(1), None);
+        assert_eq!(next(0), None);
+
+        // Empty files are found even with no bytes left anywhere
+        let scheduler = ParseScheduler::new(&[0, 0], 2);
+        assert!(scheduler.next(0).is_some());
+        assert!(scheduler.next(0).unwrap().stolen);
+        assert!(scheduler.next(1).is_none());
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+
+use crate::persist::{split_prefix, Persistable};
+use crate::{
+    append_usage_log, plan_query, time_stage, trace_evaluation, CompressedInvertedIndex,
+    CoordinateIndex, IndexKind, IndexUsage, PlannerOptions, QueryAst, QueryEvaluator,
+    QueryExplanation, QueryParser, QueryPlan, Tombstones, TracedEvaluator, WildcardSearchEngine,
+};
+
+/// Search structures saved under one prefix, each loaded on first use and then shared.
+/// Clones are cheap and see the same loaded structures, so any number of threads can search
+/// them at once without copying; searches only read the structures and keep their scratch
+/// buffers to themselves.
+#[derive(Debug, Clone)]
+pub struct SharedSearchers {
+    inner: Arc<LoadedStructures>,
+}
+
+#[derive(Debug)]
+struct LoadedStructures {
+    prefix: String,
+    /// Log the structures each search uses for `grimoire advisor`
+    record_usage: bool,
+    inverted: OnceLock<Arc<CompressedInvertedIndex>>,
+    coordinate: OnceLock<Arc<CoordinateIndex>>,
+    wildcard: OnceLock<Arc<WildcardSearchEngine>>,
+    tombstones: OnceLock<Arc<Tombstones>>,
+}
+
+impl SharedSearchers {
+    /// Nothing is read until a structure is first needed
+    pub fn open(prefix: &str) -> Self {
+        Self::open_with(prefix, false)
+    }
+
+    /// Like `open`, but every search appends the structures it used to the usage log
+    pub fn open_recording_usage(prefix: &str) -> Self {
+        Self::open_with(prefix, true)
+    }
+
+    /// Searchers over structures already in memory; nothing is ever read from disk
+    pub fn from_structures(
+        inverted: CompressedInvertedIndex,
+        coordinate: CoordinateIndex,
+        wildcard: WildcardSearchEngine,
+    ) -> Self {
+        let searchers = Self::open_with("", false);
+        let inner = &searchers.inner;
+        inner.inverted.get_or_init(|| Arc::new(inverted));
+        inner.coordinate.get_or_init(|| Arc::new(coordinate));
+        inner.wildcard.get_or_init(|| Arc::new(wildcard));
+        inner.tombstones.get_or_init(|| Arc::new(Tombstones::new()));
+        searchers
+    }
+
+    fn open_with(prefix: &str, record_usage: bool) -> Self {
+        SharedSearchers {
+            inner: Arc::new(LoadedStructures {
+                prefix: prefix.to_string(),
+                record_usage,
+                inverted: OnceLock::new(),
+                coordinate: OnceLock::new(),
+                wildcard: OnceLock::new(),
+                tombstones: OnceLock::new(),
+            }),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.inner.prefix
+    }
+
+    pub fn inverted(&self) -> Result<Arc<CompressedInvertedIndex>, String> {
+        self.load(&self.inner.inverted)
+    }
+
+    pub fn coordinate(&self) -> Result<Arc<CoordinateIndex>, String> {
+        self.load(&self.inner.coordinate)
+    }
+
+    pub fn wildcard(&self) -> Result<Arc<WildcardSearchEngine>, String> {
+        self.load(&self.inner.wildcard)
+    }
+
+    /// Documents deleted since the last compaction, dropped from every result
+    pub fn tombstones(&self) -> Result<Arc<Tombstones>, String> {
+        if let Some(loaded) = self.inner.tombstones.get() {
+            return Ok(Arc::clone(loaded));
+        }
+        let tombstones = Tombstones::load_or_default(&self.inner.prefix)
+            .map_err(|e| format!("{}: {}", Tombstones::path(&self.inner.prefix), e))?;
+        Ok(Arc::clone(
+            self.inner.tombstones.get_or_init(|| Arc::new(tombstones)),
+        ))
+    }
+
+    /// Documents matching the query in name order, routed as `search` routes it. Whenever the
+    /// inverted index answers, alone or for a split query, its document ids are kept and each
+    /// is named only as it is handed out; the coordinate index and the wildcard engine answer
+    /// with names, which are sorted up front.
+    pub fn results(&self, query: &str) -> Result<SearchResults, String> {
+        let plan = self.plan(query)?;
+        if !plan.split && (plan.uses(IndexKind::Coordinate) || plan.uses(IndexKind::Wildcard)) {
+            let (kind, mut documents) = self.route(&plan)?;
+            self.drop_deleted(&mut documents)?;
+            return Ok(SearchResults::new(kind, documents));
+        }
+
+        let inverted = self.inverted()?;
+        let (kind, mut doc_ids) = if plan.split {
+            let ids = self.routed_ids(&inverted, &plan.query, false)?;
+            (IndexKind::Coordinate, ids)
+        } else {
+            (IndexKind::Inverted, inverted.search_ids(&plan.query)?)
+        };
+        let tombstones = self.tombstones()?;
+        if !tombstones.is_empty() {
+            doc_ids.retain(|&id| {
+                inverted
+                    .doc_id_to_name
+                    .get(id as usize)
+                    .is_some_and(|document| !tombstones.is_deleted(document))
+            });
+        }
+        Ok(SearchResults::from_doc_ids(kind, inverted, doc_ids))
+    }
+
+    /// Route the query with the planner to the coordinate index, the wildcard engine or, for
+    /// plain Boolean queries, the inverted index. Returns the structure that answered; queries
+    /// mixing patterns with phrases or near/N groups are split with `search_routed` and report
+    /// the coordinate index. Deleted documents are left out.
+    pub fn search(&self, query: &str) -> Result<(IndexKind, HashSet<String>), String> {
+        let plan = self.plan(query)?;
+        let (kind, mut documents) = self.route(&plan)?;
+        self.drop_deleted(&mut documents)?;
+        Ok((kind, documents))
+    }
+
+    fn plan(&self, query: &str) -> Result<QueryPlan, String> {
+        let plan = plan_query(query, &PlannerOptions::default())?;
+        if self.inner.record_usage {
+            // Logged before searching, so queries failing on a missing structure count too
+            append_usage_log(&self.inner.prefix, &plan.query, &plan.loaded_structures())
+                .map_err(|e| format!("{}: {}", IndexUsage::path(&self.inner.prefix), e))?;
+        }
+        Ok(plan)
+    }
+
+    fn route(&self, plan: &QueryPlan) -> Result<(IndexKind, HashSet<String>), String> {
+        if plan.split {
+            Ok((IndexKind::Coordinate, self.search_routed(&plan.query)?))
+        } else if plan.uses(IndexKind::Coordinate) {
+            Ok((
+                IndexKind::Coordinate,
+                self.coordinate()?.search(&plan.query)?,
+            ))
+        } else if plan.uses(IndexKind::Wildcard) {
+            Ok((IndexKind::Wildcard, self.wildcard()?.search(&plan.query)?))
+        } else {
+            Ok((IndexKind::Inverted, self.inverted()?.search(&plan.query)?))
+        }
+    }
+
+    /// Evaluate every sub-expression with the structure made for it: phrases and near/N groups
+    /// with the coordinate index, `*` and `?` patterns with the wildcard engine, and terms and
+    /// Boolean operators with the inverted index. A structure is only loaded when the query has
+    /// a sub-expression for it.
+    pub fn search_routed(&self, query: &str) -> Result<HashSet<String>, String> {
+        let inverted = self.inverted()?;
+        let doc_ids = self.routed_ids(&inverted, query, false)?;
+        let mut documents = inverted.document_names(doc_ids);
+        self.drop_deleted(&mut documents)?;
+        Ok(documents)
+    }
+
+    /// `search_routed` for one segment of a collection: a term the segment doesn't index
+    /// matches none of its documents instead of failing the query, since another segment may
+    /// hold it. Deleted documents are left out.
+    pub fn search_segment(&self, query: &str) -> Result<HashSet<String>, String> {
+        let inverted = self.inverted()?;
+        let doc_ids = self.routed_ids(&inverted, query, true)?;
+        let mut documents = inverted.document_names(doc_ids);
+        self.drop_deleted(&mut documents)?;
+        Ok(documents)
+    }
+
+    /// Inverted index ids of the documents matching the query split between the structures
+    fn routed_ids(
+        &self,
+        inverted: &Arc<CompressedInvertedIndex>,
+        query: &str,
+        missing_terms_match_nothing: bool,
+    ) -> Result<Vec<u32>, String> {
+        let query = inverted.optimize_query(QueryAst::parse(query)?);
+        RoutedEvaluator {
+            searchers: self,
+            inverted: Arc::clone(inverted),
+            missing_terms_match_nothing,
+        }
+        .evaluate(&query)
+    }
+
+    /// Evaluate the query as `search` routes it, tracing every node and timing each stage
+    /// from loading the structure to leaving out deleted documents; see `QueryExplanation`
+    pub fn explain(&self, plan: &QueryPlan) -> Result<QueryExplanation, String> {
+        let mut stages = Vec::new();
+        let mut explanation = if plan.split {
+            let inverted = time_stage(&mut stages, "load", || self.inverted())?;
+            let query = time_stage(&mut stages, "parse", || QueryAst::parse(&plan.query))?;
+            let query = time_stage(&mut stages, "optimize", || inverted.optimize_query(query));
+            let evaluator = RoutedEvaluator {
+                searchers: self,
+                inverted: Arc::clone(&inverted),
+                missing_terms_match_nothing: false,
+            };
+            // The coordinate index and the wildcard engine load on first use, inside this stage
+            let (doc_ids, trace) = time_stage(&mut stages, "evaluate", || {
+                trace_evaluation(&evaluator, &query)
+            })?;
+            let documents = time_stage(&mut stages, "names", || inverted.document_names(doc_ids));
+            QueryExplanation {
+                kind: IndexKind::Coordinate,
+                trace,
+                stages: Vec::new(),
+                documents,
+            }
+        } else if plan.uses(IndexKind::Coordinate) {
+            time_stage(&mut stages, "load", || self.coordinate())?.explain(&plan.query)?
+        } else if plan.uses(IndexKind::Wildcard) {
+            time_stage(&mut stages, "load", || self.wildcard())?.explain(&plan.query)?
+        } else {
+            time_stage(&mut stages, "load", || self.inverted())?.explain(&plan.query)?
+        };
+        stages.append(&mut explanation.stages);
+        time_stage(&mut stages, "deleted", || {
+            self.drop_deleted(&mut explanation.documents)
+        })?;
+        explanation.stages = stages;
+        Ok(explanation)
+    }
+
+    fn drop_deleted(&self, documents: &mut HashSet<String>) -> Result<(), String> {
+        let tombstones = self.tombstones()?;
+        if !tombstones.is_empty() {
+            documents.retain(|document| !tombstones.is_deleted(document));
+        }
+        Ok(())
+    }
+
+    fn load<T: Persistable>(&self, cell: &OnceLock<Arc<T>>) -> Result<Arc<T>, String> {
+        if let Some(loaded) = cell.get() {
+            return Ok(Arc::clone(loaded));
+        }
+        let (dir, name) = split_prefix(&self.inner.prefix);
+        let structure = T::load(dir, name).map_err(|e| e.to_string())?;
+        // Threads racing on the first load each deserialize a copy; the first one stored wins
+        Ok(Arc::clone(cell.get_or_init(|| Arc::new(structure))))
+    }
+}
+
+/// Evaluates on the inverted index's document ids; coordinate index results are mapped to
+/// them and patterns expand to inverted index terms
+struct RoutedEvaluator<'a> {
+    searchers: &'a SharedSearchers,
+    inverted: Arc<CompressedInvertedIndex>,
+    /// Evaluate terms missing from the index to no documents instead of an error
+    missing_terms_match_nothing: bool,
+}
+
+impl RoutedEvaluator<'_> {
+    fn is_missing(&self, term: &str) -> bool {
+        self.missing_terms_match_nothing && self.inverted.document_frequency(term) == 0
+    }
+}
+
+impl QueryEvaluator for RoutedEvaluator<'_> {
+    type Output = Vec<u32>;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        if self.is_missing(term) {
+            return Ok(Vec::new());
+        }
+        self.inverted.evaluate_term(term)
+    }
+
+    fn evaluate_phrase(&self, words: &[String]) -> Result<Self::Output, String> {
+        let coordinate = self.searchers.coordinate()?;
+        let documents = if self.missing_terms_match_nothing {
+            // Unlike `evaluate_phrase`, never fails on a one-word phrase the index lacks
+            coordinate.phrase_postings(&words.join(" ")).documents()
+        } else {
+            coordinate.evaluate_phrase(words)?
+        };
+        Ok(self.inverted.doc_ids_of(&documents))
+    }
+
+    fn evaluate_near(
+        &self,
+        distance: usize,
+        operands: &[QueryAst],
+    ) -> Result<Self::Output, String> {
+        let documents = self
+            .searchers
+            .coordinate()?
+            .evaluate_near(distance, operands)?;
+        Ok(self.inverted.doc_ids_of(&documents))
+    }
+
+    fn evaluate_wildcard(&self, pattern: &str) -> Result<Self::Output, String> {
+        let terms = self.searchers.wildcard()?.find_matching_terms(pattern)?;
+        Ok(self.inverted.doc_ids_for_terms(&terms))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.inverted.intersect(left, right)
+    }
+
+    fn intersect_term(&self, operand: Self::Output, term: &str) -> Result<Self::Output, String> {
+        if self.is_missing(term) {
+            return Ok(Vec::new());
+        }
+        self.inverted.intersect_term(operand, term)
+    }
+
+    fn difference(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.inverted.difference(left, right)
+    }
+
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.inverted.unite(left, right)
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.inverted.complement(operand)
+    }
+}
+
+impl TracedEvaluator for RoutedEvaluator<'_> {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len()
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.inverted.document_frequency(term)
+    }
+
+    fn term_method(&self) -> String {
+        self.inverted.term_method()
+    }
+
+    fn intersect_term_method(&self, term: &str) -> String {
+        self.inverted.intersect_term_method(term)
+    }
+
+    fn trace_wildcard(&self, pattern: &str) -> Result<(Self::Output, String, usize), String> {
+        let (terms, method, postings) = self.searchers.wildcard()?.trace_expansion(pattern)?;
+        Ok((self.inverted.doc_ids_for_terms(&terms), method, postings))
+    }
+}
+
+/// A window of `limit` results starting at `offset`; no limit shows everything from `offset`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResultPage {
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl ResultPage {
+    pub fn new(offset: usize, limit: Option<usize>) -> Self {
+        ResultPage { offset, limit }
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.offset == 0 && self.limit.is_none()
+    }
+
+    /// The items of `results` on this page, taken lazily
+    pub fn apply<I: Iterator>(&self, results: I) -> std::iter::Take<std::iter::Skip<I>> {
+        results
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+    }
+
+    /// The line telling which results of `total` are shown, or `None` for a page of everything
+    pub fn describe(&self, total: usize) -> Option<String> {
+        if self.is_everything() {
+            return None;
+        }
+        Some(match self.bounds(total) {
+            Some((first, last)) => format!("Showing results {}-{} of {}", first, last, total),
+            None => format!(
+                "No results past offset {} ({} in total)",
+                self.offset, total
+            ),
+        })
+    }
+
+    /// 1-based first and last position shown out of `total`, or `None` past the end
+    pub fn bounds(&self, total: usize) -> Option<(usize, usize)> {
+        let shown = total
+            .saturating_sub(self.offset)
+            .min(self.limit.unwrap_or(usize::MAX));
+        (shown > 0).then(|| (self.offset + 1, self.offset + shown))
+    }
+}
+
+/// Documents matching a query, handed out one at a time in name order so callers page
+/// through them with `ResultPage` or stop early. Results over inverted index ids name each
+/// document only when it is handed out, so skipped and unread results are never copied.
+#[derive(Debug)]
+pub struct SearchResults {
+    kind: IndexKind,
+    documents: ResultDocuments,
+}
+
+#[derive(Debug)]
+enum ResultDocuments {
+    /// Names a structure answered with, sorted
+    Names(std::vec::IntoIter<String>),
+    /// Inverted index ids in the order of their names
+    DocIds {
+        inverted: Arc<CompressedInvertedIndex>,
+        doc_ids: std::vec::IntoIter<u32>,
+    },
+}
+
+impl SearchResults {
+    pub fn new(kind: IndexKind, documents: HashSet<String>) -> Self {
+        let mut documents: Vec<String> = documents.into_iter().collect();
+        documents.sort_unstable();
+        SearchResults {
+            kind,
+            documents: ResultDocuments::Names(documents.into_iter()),
+        }
+    }
+
+    /// Results over ids of `inverted`. Ids follow name order already unless documents were
+    /// added after the build, and are only sorted by name then; ids without a name are dropped.
+    pub fn from_doc_ids(
+        kind: IndexKind,
+        inverted: Arc<CompressedInvertedIndex>,
+        mut doc_ids: Vec<u32>,
+    ) -> Self {
+        let names = &inverted.doc_id_to_name;
+        doc_ids.retain(|&id| (id as usize) < names.len());
+        let name = |id: &u32| names[*id as usize].as_str();
+        if !doc_ids
+            .windows(2)
+            .all(|pair| name(&pair[0]) <= name(&pair[1]))
+        {
+            doc_ids.sort_unstable_by(|a, b| name(a).cmp(name(b)));
+        }
+        SearchResults {
+            kind,
+            documents: ResultDocuments::DocIds {
+                inverted,
+                doc_ids: doc_ids.into_iter(),
+            },
+        }
+    }
+
+    /// The structure that answered the query
+    pub fn kind(&self) -> IndexKind {
+        self.kind
+    }
+}
+
+impl Iterator for SearchResults {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        match &mut self.documents {
+            ResultDocuments::Names(names) => names.next(),
+            ResultDocuments::DocIds { inverted, doc_ids } => doc_ids
+                .next()
+                .map(|id| inverted.doc_id_to_name[id as usize].clone()),
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<String> {
+        match &mut self.documents {
+            ResultDocuments::Names(names) => names.nth(n),
+            ResultDocuments::DocIds { inverted, doc_ids } => doc_ids
+                .nth(n)
+                .map(|id| inverted.doc_id_to_name[id as usize].clone()),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.documents {
+            ResultDocuments::Names(names) => names.size_hint(),
+            ResultDocuments::DocIds { doc_ids, .. } => doc_ids.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for SearchResults {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressedDictionary, Dictionary, PostingEntry};
+    use std::collections::HashMap;
+    use std::thread;
+
+    #[test]
+    fn test_threads_share_one_loaded_structure() {
+        let mut dict = Dictionary::new();
+        dict.add_term("war".to_string(), "a.fb2".to_string());
+        dict.add_term("peace".to_string(), "a.fb2".to_string());
+        dict.add_term("war".to_string(), "b.fb2".to_string());
+        let index = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dict),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        index.save(dir.path(), "idx").unwrap();
+
+        let searchers = SharedSearchers::open(&prefix);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let searchers = searchers.clone();
+                thread::spawn(move || {
+                    let (kind, documents) = searchers.search("war and not peace").unwrap();
+                    (kind, documents, searchers.inverted().unwrap())
+                })
+            })
+            .collect();
+
+        let first = searchers.inverted().unwrap();
+        for handle in handles {
+            let (kind, documents, loaded) = handle.join().unwrap();
+            assert_eq!(kind, IndexKind::Inverted);
+            assert_eq!(documents, HashSet::from(["b.fb2".to_string()]));
+            assert!(Arc::ptr_eq(&first, &loaded));
+        }
+        assert!(searchers.coordinate().is_err());
+
+        let results = searchers.results("war").unwrap();
+        assert_eq!(results.len(), 2);
+        let page = ResultPage::new(1, Some(5));
+        assert_eq!(page.bounds(results.len()), Some((2, 2)));
+        assert_eq!(page.apply(results).collect::<Vec<_>>(), vec!["b.fb2"]);
+        assert_eq!(ResultPage::new(2, None).bounds(2), None);
+    }
+
+    #[test]
+    fn test_mixed_queries_are_split_between_structures() {
+        let documents = [
+            ("a.fb2", "war and peace in the forest"),
+            ("b.fb2", "peace before war and freedom"),
+            ("c.fb2", "love and hate of freedom"),
+            ("d.fb2", "love is far from any hate"),
+        ];
+        let mut dictionary = Dictionary::new();
+        let mut postings: HashMap<String, Vec<PostingEntry>> = HashMap::new();
+        for (name, text) in documents {
+            for (position, word) in text.split_whitespace().enumerate() {
+                dictionary.add_term(word.to_string(), name.to_string());
+                let entries = postings.entry(word.to_string()).or_default();
+                match entries.last_mut() {
+                    Some(last) if last.document == name => last.positions.push(position),
+                    _ => entries.push(PostingEntry {
+                        document: name.to_string(),
+                        positions: vec![position],
+                    }),
+                }
+            }
+        }
+        let names = documents.iter().map(|(name, _)| name.to_string()).collect();
+        let mut coordinate = CoordinateIndex::from_postings(postings, names);
+        coordinate.optimize();
+        let inverted = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dictionary),
+        );
+        let wildcard = WildcardSearchEngine::from_dictionary(dictionary);
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        inverted.save(dir.path(), "idx").unwrap();
+        coordinate.save(dir.path(), "idx").unwrap();
+
+        let searchers = SharedSearchers::open(&prefix);
+        // Plain terms next to a phrase never touch the wildcard engine
+        assert_eq!(
+            searchers
+                .search_routed("\"war and peace\" or love")
+                .unwrap(),
+            HashSet::from(["a.fb2", "c.fb2", "d.fb2"].map(String::from))
+        );
+        assert!(searchers
+            .search_routed("\"war and peace\" or fre*")
+            .is_err());
+
+        wildcard.save(dir.path(), "idx").unwrap();
+        let searchers = SharedSearchers::open(&prefix);
+        let (kind, found) = searchers
+            .search("\"war and peace\" or near/2(love hate) and fre*")
+            .unwrap();
+        assert_eq!(kind, IndexKind::Coordinate);
+        assert_eq!(found, HashSet::from(["a.fb2", "c.fb2"].map(String::from)));
+        assert_eq!(
+            searchers
+                .search_routed("fore* and not \"war and peace\"")
+                .unwrap(),
+            HashSet::new()
+        );
+    }
+
+    #[test]
+    fn test_results_are_named_in_name_order_as_they_are_read() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "war peace"),
+            ("b.fb2", "war"),
+            ("c.fb2", "war peace"),
+            ("d.fb2", "war"),
+            ("e.fb2", "peace"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let mut inverted = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dict),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        inverted.save(dir.path(), "idx").unwrap();
+        let mut tombstones = Tombstones::new();
+        tombstones.delete("c.fb2");
+        tombstones.save(&prefix).unwrap();
+
+        let searchers = SharedSearchers::open(&prefix);
+        let results = searchers.results("war").unwrap();
+        assert_eq!(results.kind(), IndexKind::Inverted);
+        assert_eq!(results.len(), 3);
+        let all: Vec<String> = searchers.results("war").unwrap().collect();
+        assert_eq!(all, ["a.fb2", "b.fb2", "d.fb2"]);
+
+        // --offset 1 --limit 1, --offset 2 and --offset 5 of `grimoire search`
+        let page = |offset, limit| {
+            let page = ResultPage::new(offset, limit);
+            let results = searchers.results("war or peace").unwrap();
+            let line = page.describe(results.len());
+            (line, page.apply(results).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            page(1, Some(1)),
+            (
+                Some("Showing results 2-2 of 4".to_string()),
+                vec!["b.fb2".to_string()]
+            )
+        );
+        assert_eq!(
+            page(2, None),
+            (
+                Some("Showing results 3-4 of 4".to_string()),
+                vec!["d.fb2".to_string(), "e.fb2".to_string()]
+            )
+        );
+        assert_eq!(
+            page(5, Some(2)),
+            (
+                Some("No results past offset 5 (4 in total)".to_string()),
+                vec![]
+            )
+        );
+        assert_eq!(ResultPage::default().describe(4), None);
+
+        // Ids out of name order, as after adding documents to a built index
+        inverted.doc_id_to_name.reverse();
+        let results =
+            SearchResults::from_doc_ids(IndexKind::Inverted, Arc::new(inverted), vec![0, 1, 4, 9]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results.collect::<Vec<_>>(), ["a.fb2", "d.fb2", "e.fb2"]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+use crate::{OffsetToken, Span};
+
+/// Words of context shown on each side of the matches of a fragment
+pub const DEFAULT_SNIPPET_CONTEXT: usize = 8;
+
+/// Positions of the tokens that are one of `terms`, as single-word spans
+pub fn term_spans(tokens: &[OffsetToken], terms: &HashSet<String>) -> Vec<Span> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, (word, _, _))| terms.contains(word))
+        .map(|(position, _)| (position, position))
+        .collect()
+}
+
+/// A short extract of `text` around the best matches: the window of `2 * context` words
+/// holding the most matched spans, widened by `context` words on each side and cut at whole
+/// words. Matched words are marked with brackets and whitespace is collapsed; `None` when
+/// no span falls inside `tokens`, which are the words of `text` numbered as positions.
+pub fn make_snippet(
+    text: &str,
+    tokens: &[OffsetToken],
+    spans: &[Span],
+    context: usize,
+) -> Option<String> {
+    let mut spans: Vec<Span> = spans
+        .iter()
+        .copied()
+        .filter(|&(_, end)| end < tokens.len())
+        .collect();
+    spans.sort_unstable();
+    if spans.is_empty() {
+        return None;
+    }
+
+    // The span starting the densest window; earlier windows win ties
+    let window = 2 * context.max(1);
+    let (first, _) = (0..spans.len())
+        .map(|i| {
+            let covered = spans[i..]
+                .iter()
+                .take_while(|&&(_, end)| end < spans[i].0 + window)
+                .count();
+            (i, covered)
+        })
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))?;
+    let anchor = spans[first].0;
+    let matched: Vec<Span> = spans[first..]
+        .iter()
+        .copied()
+        .take_while(|&(_, end)| end < anchor + window)
+        .collect();
+    let last = matched.iter().map(|&(_, end)| end).max().unwrap_or(anchor);
+
+    let from = anchor.saturating_sub(context);
+    let to = (last + context).min(tokens.len() - 1);
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push_str("...");
+    }
+    let mut cursor = tokens[from].1;
+    for (position, (_, start, end)) in tokens.iter().enumerate().take(to + 1).skip(from) {
+        let is_start = matched.iter().any(|&(first, _)| first == position);
+        let is_end = matched.iter().any(|&(_, last)| last == position);
+        push_collapsed(&mut snippet, &text[cursor..*start]);
+        if is_start {
+            snippet.push('[');
+        }
+        snippet.push_str(&text[*start..*end]);
+        if is_end {
+            snippet.push(']');
+        }
+        cursor = *end;
+    }
+    if to + 1 < tokens.len() {
+        snippet.push_str("...");
+    }
+    Some(snippet)
+}
+
+/// Append `gap` with every run of whitespace as one space
+fn push_collapsed(snippet: &mut String, gap: &str) {
+    let mut words = gap.split_whitespace().peekable();
+    if gap.starts_with(char::is_whitespace) {
+        snippet.push(' ');
+    }
+    while let Some(word) = words.next() {
+        snippet.push_str(word);
+        if words.peek().is_some() || gap.ends_with(char::is_whitespace) {
+            snippet.push(' ');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FB2Parser;
+
+    #[test]
+    fn test_snippet_marks_the_densest_matches() {
+        let text = "Всё смешалось в доме Облонских.\nЖена узнала, что муж был в связи \
+                    с бывшею в их доме француженкою-гувернанткой, и объявила мужу, что не \
+                    может жить с ним в одном доме.";
+        let tokens = FB2Parser::new().tokenize_text_with_offsets(text);
+        assert_eq!(tokens[1], ("смешалось".to_string(), 7, 25));
+        let terms = HashSet::from(["доме".to_string(), "мужу".to_string()]);
+        let spans = term_spans(&tokens, &terms);
+        assert_eq!(spans.len(), 4);
+
+        // Only the second "доме" has another match close by
+        assert_eq!(
+            make_snippet(text, &tokens, &spans, 3).unwrap(),
+            "...был в связи с бывшею в их [доме] француженкою-гувернанткой, и объявила [мужу], \
+             что не может жить..."
+        );
+        assert_eq!(
+            make_snippet(text, &tokens, &[(1, 2)], 1).unwrap(),
+            "Всё [смешалось в доме] Облонских..."
+        );
+        assert!(make_snippet(text, &tokens, &[], 2).is_none());
+    }
+}
@@ -1,8 +1,12 @@
-use crate::dictionary::Dictionary;
-use std::collections::{HashMap, HashSet};
+use crate::coordinate_index::{CoordinateIndex, PostingEntry};
+use crate::dictionary::{Dictionary, DocIdSet, TermEntry};
+use crossbeam_channel::Receiver;
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 /// SPIMI (Single-Pass In-Memory Indexing) implementation
 /// Builds inverted indexes efficiently for large document collections
@@ -31,24 +35,28 @@ impl SPIMIIndexer {
     }
 
     pub fn add_document(&mut self, doc_id: &str, text: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let words = self.tokenize(text);
-
-        for word in words {
-            let term_size = word.len() + doc_id.len() + 32; // Estimate memory usage
-
-            if self.current_memory_usage + term_size > self.memory_limit {
-                self.write_block_to_disk()?;
-                self.reset_current_block();
-            }
+        for word in self.tokenize(text) {
+            self.add_term(doc_id, word)?;
+        }
+        Ok(())
+    }
 
-            self.current_index
-                .entry(word)
-                .or_insert_with(Vec::new)
-                .push(doc_id.to_string());
+    /// Index one word of `doc_id` tokenized elsewhere, e.g. streamed by
+    /// `FB2Parser::for_each_token`
+    pub fn add_term(&mut self, doc_id: &str, word: String) -> Result<(), Box<dyn std::error::Error>> {
+        let term_size = word.len() + doc_id.len() + 32; // Estimate memory usage
 
-            self.current_memory_usage += term_size;
+        if self.current_memory_usage + term_size > self.memory_limit {
+            self.write_block_to_disk()?;
+            self.reset_current_block();
         }
 
+        self.current_index
+            .entry(word)
+            .or_default()
+            .push(doc_id.to_string());
+
+        self.current_memory_usage += term_size;
         Ok(())
     }
 
@@ -88,12 +96,11 @@ impl SPIMIIndexer {
                  self.current_memory_usage as f64 / 1024.0 / 1024.0);
 
         for (term, docs) in terms {
-            // Deduplicate and sort document IDs
-            let mut unique_docs = docs.clone();
-            unique_docs.sort();
-            unique_docs.dedup();
+            // Sort document IDs, keeping one entry per occurrence for term frequencies
+            let mut sorted_docs = docs.clone();
+            sorted_docs.sort();
 
-            writeln!(writer, "{}:{}", term, unique_docs.join(","))?;
+            writeln!(writer, "{}:{}", term, sorted_docs.join(","))?;
         }
 
         writer.flush()?;
@@ -178,15 +185,27 @@ impl SPIMIIndexer {
                 current_lines[block_idx] = block_readers[block_idx].next().transpose()?;
             }
 
-            // Convert to HashSet for deduplication
-            let documents_set: HashSet<String> = all_docs.into_iter().collect();
-
-            dictionary.add_term(term.clone(), "".to_string());
-            if let Some(entry) = dictionary.terms.get_mut(&term) {
-                entry.frequency = documents_set.len() as u32;
-                entry.documents = documents_set;
+            // Collecting into a DocIdSet deduplicates
+            let ids: Vec<u32> = all_docs
+                .iter()
+                .map(|doc| dictionary.document_id(doc))
+                .collect();
+            let documents: DocIdSet = ids.iter().copied().collect();
+            let mut term_frequencies = HashMap::new();
+            for id in ids {
+                *term_frequencies.entry(id).or_insert(0) += 1;
             }
 
+            dictionary.total_words += 1;
+            dictionary.terms.insert(
+                term.clone(),
+                TermEntry {
+                    frequency: documents.len() as u32,
+                    documents,
+                    term_frequencies,
+                },
+            );
+
             merged_terms += 1;
             if merged_terms % 10000 == 0 {
                 println!("SPIMI: Merged {} terms", merged_terms);
@@ -213,6 +232,201 @@ impl SPIMIIndexer {
     }
 }
 
+/// One term of a positional block with its postings
+type PositionalRecord = (String, Vec<PostingEntry>);
+
+/// SPIMI for the positional index: term -> document -> positions postings are spilled to
+/// sorted blocks whenever the memory limit is reached, then merged into a `CoordinateIndex`
+pub struct PositionalSPIMIIndexer {
+    memory_limit: usize,
+    output_dir: String,
+    current_memory_usage: usize,
+    current_index: HashMap<String, Vec<PostingEntry>>,
+    documents: Vec<String>,
+    block_count: usize,
+}
+
+impl PositionalSPIMIIndexer {
+    pub fn new<P: AsRef<Path>>(
+        memory_limit_mb: usize,
+        output_dir: P,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let output_path = output_dir.as_ref();
+        if !output_path.exists() {
+            fs::create_dir_all(output_path)?;
+        }
+
+        Ok(PositionalSPIMIIndexer {
+            memory_limit: memory_limit_mb * 1024 * 1024,
+            output_dir: output_path.to_string_lossy().to_string(),
+            current_memory_usage: 0,
+            current_index: HashMap::new(),
+            documents: Vec::new(),
+            block_count: 0,
+        })
+    }
+
+    /// Index the words of one document; a word's position is its index in `words`, as in
+    /// `CoordinateIndex::from_dictionary_with_parser`
+    pub fn add_document(
+        &mut self,
+        document: &str,
+        words: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.documents.push(document.to_string());
+        for (position, word) in words.iter().enumerate() {
+            self.add_token(document, word.clone(), position)?;
+        }
+        Ok(())
+    }
+
+    /// Index one word of `document` at `position` as a parser streams it, so the words of a
+    /// document are never collected; positions must grow within a document
+    pub fn add_token(
+        &mut self,
+        document: &str,
+        word: String,
+        position: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.documents.last().is_none_or(|last| last != document) {
+            self.documents.push(document.to_string());
+        }
+
+        let word_len = word.len();
+        let postings = self.current_index.entry(word).or_default();
+        match postings.last_mut() {
+            Some(last) if last.document == document => {
+                last.positions.push(position);
+                self.current_memory_usage += std::mem::size_of::<usize>();
+            }
+            _ => {
+                postings.push(PostingEntry {
+                    document: document.to_string(),
+                    positions: vec![position],
+                });
+                // Estimate memory usage of a new posting, plus the term on first sight
+                self.current_memory_usage += document.len() + word_len + 48;
+            }
+        }
+
+        if self.current_memory_usage > self.memory_limit {
+            self.write_block_to_disk()?;
+        }
+        Ok(())
+    }
+
+    /// Merge every block into the final index and remove the block files
+    pub fn finalize(&mut self) -> Result<CoordinateIndex, Box<dyn std::error::Error>> {
+        if !self.current_index.is_empty() {
+            self.write_block_to_disk()?;
+        }
+
+        println!("Positional SPIMI: Merging {} blocks into final index", self.block_count);
+        let index = self.merge_blocks();
+
+        for i in 0..self.block_count {
+            let _ = fs::remove_file(self.block_path(i)); // Ignore errors
+        }
+
+        let mut documents = std::mem::take(&mut self.documents);
+        documents.sort();
+        documents.dedup();
+        Ok(CoordinateIndex::from_postings(index?, documents))
+    }
+
+    fn block_path(&self, block: usize) -> String {
+        format!("{}/positional_block_{}.bin", self.output_dir, block)
+    }
+
+    /// Write the current postings as bincode `(term, postings)` records in term order
+    fn write_block_to_disk(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let file = File::create(self.block_path(self.block_count))?;
+        let mut writer = BufWriter::new(file);
+
+        let mut terms: Vec<_> = self.current_index.drain().collect();
+        terms.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        println!(
+            "Positional SPIMI: Writing block {} with {} terms ({:.2} MB)",
+            self.block_count,
+            terms.len(),
+            self.current_memory_usage as f64 / 1024.0 / 1024.0
+        );
+
+        for record in &terms {
+            bincode::serialize_into(&mut writer, record)?;
+        }
+
+        writer.flush()?;
+        self.block_count += 1;
+        self.current_memory_usage = 0;
+        Ok(())
+    }
+
+    fn merge_blocks(&self) -> Result<HashMap<String, Vec<PostingEntry>>, Box<dyn std::error::Error>> {
+        let mut readers = Vec::with_capacity(self.block_count);
+        for i in 0..self.block_count {
+            readers.push(BufReader::new(File::open(self.block_path(i))?));
+        }
+        let mut heads: Vec<Option<PositionalRecord>> = readers
+            .iter_mut()
+            .map(read_positional_record)
+            .collect::<Result<_, _>>()?;
+
+        let mut index = HashMap::new();
+        // Repeatedly take the lexicographically smallest term among all blocks
+        while let Some(term) = heads.iter().flatten().map(|(term, _)| term).min().cloned() {
+
+            let mut postings: Vec<PostingEntry> = Vec::new();
+            for (block, head) in heads.iter_mut().enumerate() {
+                if head.as_ref().is_some_and(|(head_term, _)| *head_term == term) {
+                    postings.extend(head.take().unwrap().1);
+                    *head = read_positional_record(&mut readers[block])?;
+                }
+            }
+
+            // Blocks are written in document order, but a document split across blocks
+            // leaves two entries to join
+            postings.sort_by(|a, b| a.document.cmp(&b.document));
+            let mut merged: Vec<PostingEntry> = Vec::with_capacity(postings.len());
+            for posting in postings {
+                match merged.last_mut() {
+                    Some(last) if last.document == posting.document => {
+                        last.positions.extend(posting.positions)
+                    }
+                    _ => merged.push(posting),
+                }
+            }
+            for posting in &mut merged {
+                posting.positions.sort_unstable();
+            }
+
+            index.insert(term, merged);
+        }
+
+        println!("Positional SPIMI: Merge complete - {} unique terms", index.len());
+        Ok(index)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.current_memory_usage
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+}
+
+/// Next `(term, postings)` record of a positional block, or `None` at its end
+fn read_positional_record(
+    reader: &mut BufReader<File>,
+) -> Result<Option<PositionalRecord>, Box<dyn std::error::Error>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize_from(reader)?))
+}
+
 /// Parallel SPIMI implementation for better performance
 pub struct ParallelSPIMIIndexer {
     memory_limit_per_thread: usize,
@@ -231,7 +445,7 @@ impl ParallelSPIMIIndexer {
             fs::create_dir_all(output_path)?;
         }
 
-        let threads = num_threads.unwrap_or_else(|| rayon::current_num_threads());
+        let threads = num_threads.unwrap_or_else(rayon::current_num_threads);
 
         Ok(ParallelSPIMIIndexer {
             memory_limit_per_thread: memory_limit_mb * 1024 * 1024 / threads,
@@ -245,7 +459,7 @@ impl ParallelSPIMIIndexer {
     where
         F: Fn(usize, usize) + Send + Sync,
     {
-        let chunk_size = (documents.len() + self.num_threads - 1) / self.num_threads;
+        let chunk_size = documents.len().div_ceil(self.num_threads);
         let chunks: Vec<_> = documents.chunks(chunk_size).enumerate().collect();
 
         println!("Parallel SPIMI: Processing {} documents across {} threads",
@@ -278,14 +492,76 @@ impl ParallelSPIMIIndexer {
         self.merge_dictionaries(partial_dictionaries)
     }
 
+    /// Index documents as they arrive on a bounded channel, each thread running its own
+    /// SPIMI indexer on whichever document comes next, so indexing keeps pace with parallel
+    /// readers instead of waiting for the whole input. `id_and_text` names the document and
+    /// gives its text. Returns the merged dictionary and the documents in no particular order.
+    pub fn build_index_streaming<D, F>(&self, documents: Receiver<D>, id_and_text: F)
+        -> Result<(Dictionary, Vec<D>), Box<dyn std::error::Error>>
+    where
+        D: Send,
+        F: Fn(&D) -> (&str, &str) + Sync,
+    {
+        println!("Parallel SPIMI: Indexing documents as they are read with {} threads", self.num_threads);
+        let processed = AtomicUsize::new(0);
+
+        let results: Vec<Result<(Dictionary, Vec<D>), String>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.num_threads)
+                .map(|thread_idx| {
+                    let (documents, id_and_text, processed) = (documents.clone(), &id_and_text, &processed);
+                    scope.spawn(move || -> Result<(Dictionary, Vec<D>), String> {
+                        let thread_output_dir = format!("{}/thread_{}", self.output_dir, thread_idx);
+                        let mut indexer = SPIMIIndexer::new(
+                            self.memory_limit_per_thread / (1024 * 1024),
+                            &thread_output_dir
+                        ).map_err(|e| e.to_string())?;
+
+                        let mut indexed = Vec::new();
+                        let mut sizes = Vec::new();
+                        for document in documents {
+                            let (doc_id, text) = id_and_text(&document);
+                            indexer.add_document(doc_id, text).map_err(|e| e.to_string())?;
+                            sizes.push(text.len() as u64);
+                            indexed.push(document);
+
+                            let count = processed.fetch_add(1, Ordering::Relaxed) + 1;
+                            if count % 10000 == 0 {
+                                println!("SPIMI: Processed {} documents", count);
+                            }
+                        }
+                        let mut dictionary = indexer.finalize().map_err(|e| e.to_string())?;
+                        // Blocks hold postings only, so document counts are kept here
+                        for size in sizes {
+                            dictionary.add_file_stats(size);
+                        }
+                        Ok((dictionary, indexed))
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        // Readers blocked on a full channel see it disconnect once every worker is gone
+        drop(documents);
+
+        let mut partial_dictionaries = Vec::new();
+        let mut indexed = Vec::new();
+        for result in results {
+            let (dictionary, documents) = result?;
+            partial_dictionaries.push(dictionary);
+            indexed.extend(documents);
+        }
+        println!("Parallel SPIMI: Merging {} partial dictionaries", partial_dictionaries.len());
+        Ok((self.merge_dictionaries(partial_dictionaries)?, indexed))
+    }
+
     fn merge_dictionaries(&self, dictionaries: Vec<Dictionary>) -> Result<Dictionary, Box<dyn std::error::Error>> {
         let mut final_dict = Dictionary::new();
 
         for dict in dictionaries {
             for (term, entry) in &dict.terms {
                 // Add the term to the final dictionary using the proper method
-                for doc in &entry.documents {
-                    for _ in 0..entry.frequency {
+                for (id, doc) in entry.documents.iter().zip(entry.documents.names(&dict.documents)) {
+                    for _ in 0..entry.term_frequency(id) {
                         final_dict.add_term(term.clone(), doc.clone());
                     }
                 }
@@ -296,7 +572,7 @@ impl ParallelSPIMIIndexer {
             final_dict.collection_size_bytes += dict.collection_size_bytes;
         }
 
-        // Documents are already deduplicated in HashSet
+        // Documents are already deduplicated in the DocIdSet
         for entry in final_dict.terms.values_mut() {
             entry.frequency = entry.documents.len() as u32;
         }
@@ -328,4 +604,111 @@ mod tests {
         assert!(tokens.contains(&"world".to_string()));
         assert!(tokens.contains(&"test".to_string()));
     }
+
+    #[test]
+    fn test_positional_blocks_merge_to_in_memory_index() {
+        let texts = [
+            ("doc1", "war and peace and war"),
+            ("doc2", "peace after war"),
+            ("doc3", "war war war"),
+        ];
+        let words = |text: &str| -> Vec<String> {
+            text.split_whitespace().map(|w| w.to_string()).collect()
+        };
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut indexer = PositionalSPIMIIndexer::new(1, temp_dir.path()).unwrap();
+        // Spill every few postings so documents are split across blocks
+        indexer.memory_limit = 64;
+        for (doc, text) in texts {
+            indexer.add_document(doc, &words(text)).unwrap();
+        }
+        let spilled = indexer.finalize().unwrap();
+        assert!(indexer.block_count() > 3);
+
+        let mut dict = Dictionary::new();
+        for (doc, _) in texts {
+            dict.add_term("war".to_string(), doc.to_string());
+        }
+        let compressed = crate::CompressedDictionary::from_dictionary(&dict);
+        let in_memory = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            let text = texts.iter().find(|(name, _)| *name == doc).unwrap().1;
+            Ok(words(text))
+        })
+        .unwrap();
+
+        assert_eq!(spilled.documents, in_memory.documents);
+        assert_eq!(spilled.index.len(), in_memory.index.len());
+        for (term, postings) in &in_memory.index {
+            let spilled_postings: Vec<(&String, &Vec<usize>)> = spilled.index[term]
+                .iter()
+                .map(|p| (&p.document, &p.positions))
+                .collect();
+            let expected: Vec<(&String, &Vec<usize>)> =
+                postings.iter().map(|p| (&p.document, &p.positions)).collect();
+            assert_eq!(spilled_postings, expected, "postings of {}", term);
+        }
+        assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_streamed_tokens_build_the_same_positional_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let texts = [
+            ("a.fb2", "<p>Война и мир</p><p>мир &amp; война, война</p>"),
+            ("b.fb2", "<p>Мир после войны</p>"),
+        ];
+        let mut dict = Dictionary::new();
+        for (name, body) in texts {
+            let xml = format!("<FictionBook><body>{}</body></FictionBook>", body);
+            fs::write(temp_dir.path().join(name), xml).unwrap();
+            dict.add_term("мир".to_string(), name.to_string());
+        }
+        let compressed = crate::CompressedDictionary::from_dictionary(&dict);
+        let parser = crate::FB2Parser::new();
+        let path = |doc: &str| temp_dir.path().join(doc);
+
+        let mut streamed = Vec::new();
+        let count = parser
+            .for_each_token(&path("a.fb2"), |word, position| {
+                streamed.push((word, position));
+                Ok(())
+            })
+            .unwrap();
+        let words = parser.parse_file(&path("a.fb2")).unwrap();
+        assert_eq!(count, words.len());
+        assert_eq!(streamed[3], ("война".to_string(), 3));
+        assert!(streamed.iter().map(|(word, _)| word).eq(&words));
+
+        let parsed = CoordinateIndex::from_dictionary_with_parser(&compressed, |doc| {
+            parser.parse_file(&path(doc))
+        })
+        .unwrap();
+        let tokens = CoordinateIndex::from_dictionary_with_tokens(&compressed, |doc, sink| {
+            parser.for_each_token(&path(doc), sink)
+        })
+        .unwrap();
+        let mut indexer = PositionalSPIMIIndexer::new(1, temp_dir.path().join("spimi")).unwrap();
+        indexer.memory_limit = 64;
+        for (name, _) in texts {
+            parser
+                .for_each_token(&path(name), |word, position| {
+                    indexer.add_token(name, word, position)
+                })
+                .unwrap();
+        }
+        let spilled = indexer.finalize().unwrap();
+
+        for index in [&tokens, &spilled] {
+            assert_eq!(index.documents, parsed.documents);
+            assert_eq!(index.index.len(), parsed.index.len());
+            for (term, postings) in &parsed.index {
+                let pairs = |postings: &[PostingEntry]| -> Vec<(String, Vec<usize>)> {
+                    postings.iter().map(|p| (p.document.clone(), p.positions.clone())).collect()
+                };
+                assert_eq!(pairs(&index.index[term]), pairs(postings), "postings of {}", term);
+            }
+        }
+        assert!(parser.for_each_token(&path("missing.fb2"), |_, _| Ok(())).is_err());
+    }
 }
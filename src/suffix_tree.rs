@@ -1,4 +1,4 @@
-use crate::CompressedDictionary;
+use crate::{CompressedDictionary, TuningConfig};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
@@ -26,6 +26,12 @@ pub struct SuffixTree {
     root: SuffixNode,
 }
 
+impl Default for SuffixTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SuffixTree {
     pub fn new() -> Self {
         SuffixTree {
@@ -43,7 +49,7 @@ impl SuffixTree {
         let terms = dictionary.extract_terms_parallel();
 
         // Process terms in parallel chunks for better progress reporting
-        let chunk_size = 1000;
+        let chunk_size = TuningConfig::current().chunk_size;
         let chunks: Vec<_> = terms.chunks(chunk_size).collect();
 
         chunks
@@ -59,7 +65,7 @@ impl SuffixTree {
                 }
 
                 let processed = (chunk_idx + 1) * chunk_size;
-                if processed % 5000 == 0 || chunk_idx == chunks.len() - 1 {
+                if processed.is_multiple_of(5000) || chunk_idx == chunks.len() - 1 {
                     println!(
                         "      SuffixTree: Processed ~{} terms",
                         processed.min(terms.len())
@@ -86,7 +92,7 @@ impl SuffixTree {
         let terms = dictionary.extract_terms_parallel();
 
         // Process terms in parallel chunks for better progress reporting
-        let chunk_size = 1000;
+        let chunk_size = TuningConfig::current().chunk_size;
         let chunks: Vec<_> = terms.chunks(chunk_size).collect();
 
         chunks.par_iter().enumerate().for_each(|(chunk_idx, chunk)| {
@@ -102,7 +108,7 @@ impl SuffixTree {
             }
 
             let processed = (chunk_idx + 1) * chunk_size;
-            if processed % 5000 == 0 || chunk_idx == chunks.len() - 1 {
+            if processed.is_multiple_of(5000) || chunk_idx == chunks.len() - 1 {
                 println!(
                     "      SuffixTree: Processed ~{} terms",
                     processed.min(terms.len())
@@ -172,9 +178,10 @@ impl SuffixTree {
             return;
         }
 
-        let chars: Vec<char> = pattern.chars().collect();
-        let first_char = chars[0];
-        let remaining = &pattern[1..];
+        let mut chars = pattern.chars();
+        let first_char = chars.next().unwrap();
+        // Patterns are matched by character; a Cyrillic letter is two bytes
+        let remaining = chars.as_str();
 
         if first_char == '*' {
             results.extend(node.terms.iter().cloned());
@@ -259,4 +266,30 @@ mod tests {
         let results = tree.find_matching_terms("*ing");
         assert!(results.contains("testing"));
     }
+
+    #[test]
+    fn test_single_character_wildcard_on_cyrillic() {
+        let mut dict = Dictionary::new();
+        for term in ["война", "воина", "вина", "мир"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+
+        let compressed_dict = CompressedDictionary::from_dictionary(&dict);
+        let tree = SuffixTree::from_dictionary(&compressed_dict);
+
+        let results = tree.find_matching_terms("во?на");
+        assert_eq!(
+            results,
+            HashSet::from(["война".to_string(), "воина".to_string()])
+        );
+        assert_eq!(
+            tree.find_matching_terms("м?р"),
+            HashSet::from(["мир".to_string()])
+        );
+        assert!(tree.find_matching_terms("м??р").is_empty());
+        assert_eq!(
+            tree.find_matching_terms("?ир*"),
+            HashSet::from(["мир".to_string()])
+        );
+    }
 }
@@ -0,0 +1,130 @@
+use crate::tfidf::{cosine_similarity, idf, tfidf_vector, SparseVector};
+use crate::{CompressedDictionary, FB2Parser};
+
+/// Extractive summarizer ranking sentences with TextRank over TF-IDF cosine similarity
+pub struct Summarizer<'a> {
+    dictionary: &'a CompressedDictionary,
+    parser: FB2Parser,
+    damping: f64,
+    iterations: usize,
+    candidate_limit: usize,
+}
+
+impl<'a> Summarizer<'a> {
+    pub fn new(dictionary: &'a CompressedDictionary) -> Self {
+        Summarizer {
+            dictionary,
+            parser: FB2Parser::new(),
+            damping: 0.85,
+            iterations: 30,
+            candidate_limit: 200,
+        }
+    }
+
+    /// Only the first `limit` sentences are ranked, keeping the similarity graph small for long books
+    pub fn with_candidate_limit(mut self, limit: usize) -> Self {
+        self.candidate_limit = limit.max(1);
+        self
+    }
+
+    /// Pick the `max_sentences` most central sentences, returned in document order
+    pub fn summarize(&self, sentences: &[String], max_sentences: usize) -> Vec<String> {
+        let candidates = &sentences[..sentences.len().min(self.candidate_limit)];
+        if candidates.len() <= max_sentences {
+            return candidates.to_vec();
+        }
+
+        let vectors: Vec<SparseVector> = candidates
+            .iter()
+            .map(|sentence| {
+                let terms = self.parser.tokenize_text(sentence);
+                tfidf_vector(terms.iter().map(|t| t.as_str()), |term| self.term_idf(term))
+            })
+            .collect();
+
+        let scores = self.text_rank(&vectors);
+
+        let mut ranked: Vec<usize> = (0..candidates.len())
+            .filter(|&i| !vectors[i].is_empty())
+            .collect();
+        ranked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]).then_with(|| a.cmp(&b)));
+        ranked.truncate(max_sentences);
+        ranked.sort_unstable();
+
+        ranked.into_iter().map(|i| candidates[i].clone()).collect()
+    }
+
+    fn term_idf(&self, term: &str) -> f64 {
+        let document_frequency = self
+            .dictionary
+            .get_term_entry(term)
+            .map_or(0, |entry| entry.documents.len());
+        idf(self.dictionary.total_documents as usize, document_frequency)
+    }
+
+    fn text_rank(&self, vectors: &[SparseVector]) -> Vec<f64> {
+        let n = vectors.len();
+        let mut weights = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let similarity = cosine_similarity(&vectors[i], &vectors[j]);
+                weights[i][j] = similarity;
+                weights[j][i] = similarity;
+            }
+        }
+        let out_weights: Vec<f64> = weights.iter().map(|row| row.iter().sum()).collect();
+
+        let mut scores = vec![1.0 / n as f64; n];
+        for _ in 0..self.iterations {
+            let mut next = vec![(1.0 - self.damping) / n as f64; n];
+            for (i, row) in weights.iter().enumerate() {
+                if out_weights[i] == 0.0 {
+                    continue;
+                }
+                for (j, &weight) in row.iter().enumerate() {
+                    if weight > 0.0 {
+                        next[j] += self.damping * scores[i] * weight / out_weights[i];
+                    }
+                }
+            }
+            scores = next;
+        }
+
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::Dictionary;
+
+    #[test]
+    fn test_summary_prefers_central_sentences() {
+        let mut dict = Dictionary::new();
+        for (term, doc) in [("war", "doc1"), ("peace", "doc1"), ("war", "doc2"), ("cat", "doc3")] {
+            dict.add_term(term.to_string(), doc.to_string());
+        }
+        let compressed = CompressedDictionary::from_dictionary(&dict);
+
+        let sentences: Vec<String> = vec![
+            "The war and the peace were long.".to_string(),
+            "My cat sleeps.".to_string(),
+            "Peace followed the war.".to_string(),
+            "War again broke the peace.".to_string(),
+        ];
+
+        let summary = Summarizer::new(&compressed).summarize(&sentences, 2);
+        assert_eq!(summary.len(), 2);
+        assert!(!summary.contains(&"My cat sleeps.".to_string()));
+    }
+
+    #[test]
+    fn test_short_documents_are_returned_whole() {
+        let compressed = CompressedDictionary::from_dictionary(&Dictionary::new());
+        let sentences = vec!["Only one sentence here.".to_string()];
+
+        let summary = Summarizer::new(&compressed).summarize(&sentences, 3);
+        assert_eq!(summary, sentences);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::persist::Persistable;
+use crate::OffsetToken;
+
+/// The spellings in the text behind each term: `Война` and `ВОЙНА` behind `война`, `Ёлка`
+/// behind `елка` with `fold-yo`. Only spellings that differ from the term are kept, so an
+/// index of lowercase text stays small; the term's own spelling accounts for the rest of its
+/// collection frequency. Counts cover every document indexed since the last full build.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SurfaceForms {
+    forms: HashMap<String, BTreeMap<String, u32>>,
+}
+
+impl Persistable for SurfaceForms {
+    const SUFFIX: &'static str = "_surface";
+}
+
+impl SurfaceForms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the words of `text`, tokenized into `tokens` with offsets into it
+    pub fn add_text(&mut self, text: &str, tokens: &[OffsetToken]) {
+        for (term, start, end) in tokens {
+            self.add(term, &text[*start..*end]);
+        }
+    }
+
+    pub fn add(&mut self, term: &str, surface: &str) {
+        if surface != term {
+            *self
+                .forms
+                .entry(term.to_string())
+                .or_default()
+                .entry(surface.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: SurfaceForms) {
+        for (term, forms) in other.forms {
+            let merged = self.forms.entry(term).or_default();
+            for (surface, count) in forms {
+                *merged.entry(surface).or_insert(0) += count;
+            }
+        }
+    }
+
+    /// Spellings of `term` other than the term itself, the most frequent first
+    pub fn forms(&self, term: &str) -> Vec<(&str, u32)> {
+        let mut forms: Vec<(&str, u32)> = self
+            .forms
+            .get(term)
+            .into_iter()
+            .flatten()
+            .map(|(surface, &count)| (surface.as_str(), count))
+            .collect();
+        forms.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        forms
+    }
+
+    /// How `term` is written most often, given it occurs `frequency` times in the collection;
+    /// the term itself unless another spelling outnumbers it
+    pub fn display_form<'a>(&'a self, term: &'a str, frequency: u32) -> &'a str {
+        let forms = self.forms(term);
+        let own = frequency.saturating_sub(forms.iter().map(|(_, count)| count).sum());
+        match forms.first() {
+            Some(&(surface, count)) if count > own => surface,
+            _ => term,
+        }
+    }
+
+    /// Terms written some other way at least once
+    pub fn len(&self) -> usize {
+        self.forms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forms.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Analyzer, FB2Parser};
+
+    #[test]
+    fn test_terms_map_back_to_their_spellings() {
+        let parser = FB2Parser::new().with_analyzer(Analyzer::from_spec("fold-yo").unwrap());
+        let mut forms = SurfaceForms::new();
+        let text = "Ёлка в лесу. Елка! ЁЛКА и ёлка, ёлка.";
+        forms.add_text(text, &parser.tokenize_text_with_offsets(text));
+        let mut more = SurfaceForms::new();
+        more.add("лесу", "Лесу");
+        forms.merge(more);
+
+        assert_eq!(
+            forms.forms("елка"),
+            [("ёлка", 2), ("ЁЛКА", 1), ("Ёлка", 1), ("Елка", 1)]
+        );
+        assert_eq!(forms.forms("лесу"), [("Лесу", 1)]);
+        assert!(forms.forms("мир").is_empty());
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms.display_form("елка", 5), "ёлка");
+        assert_eq!(forms.display_form("лесу", 2), "лесу");
+    }
+}
@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::persist::Persistable;
+
+/// Parse `YYYY`, `YYYY-MM` or `YYYY-MM-DD` into a sortable `YYYYMMDD` key.
+/// Missing month/day components are filled with the lowest or highest value depending on `upper`.
+pub fn parse_date_key(date: &str, upper: bool) -> Option<u32> {
+    let mut parts = date.trim().splitn(3, '-');
+
+    let year_part = parts.next()?;
+    if year_part.len() != 4 {
+        return None;
+    }
+    let year: u32 = year_part.parse().ok()?;
+
+    let month: u32 = match parts.next() {
+        Some(month) => month.parse().ok().filter(|m| (1..=12).contains(m))?,
+        None if upper => 12,
+        None => 1,
+    };
+    let day: u32 = match parts.next() {
+        Some(day) => day.get(..2).unwrap_or(day).parse().ok().filter(|d| (1..=31).contains(d))?,
+        None if upper => 31,
+        None => 1,
+    };
+
+    Some(year * 10_000 + month * 100 + day)
+}
+
+/// A contiguous range of years and the documents published within it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemporalPartition {
+    pub start_year: u32,
+    /// Inclusive
+    pub end_year: u32,
+    /// (document, date key) pairs sorted by date
+    pub documents: Vec<(String, u32)>,
+    pub total_words: u64,
+}
+
+impl TemporalPartition {
+    fn start_key(&self) -> u32 {
+        self.start_year * 10_000 + 101
+    }
+
+    fn end_key(&self) -> u32 {
+        self.end_year * 10_000 + 1231
+    }
+
+    /// Earliest and latest document dates in the partition
+    pub fn date_range(&self) -> Option<(u32, u32)> {
+        Some((self.documents.first()?.1, self.documents.last()?.1))
+    }
+}
+
+/// Documents of a collection grouped into fixed-width year partitions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemporalPartitions {
+    pub span_years: u32,
+    pub partitions: Vec<TemporalPartition>,
+    /// Documents without a parseable date; never match a time-sliced query
+    pub undated: Vec<String>,
+}
+
+impl Persistable for TemporalPartitions {
+    const SUFFIX: &'static str = "_partitions";
+}
+
+/// Documents selected by a time slice, with the number of partitions it touched
+#[derive(Debug, Clone)]
+pub struct TimeSlice {
+    pub documents: HashSet<String>,
+    pub scanned_partitions: usize,
+    pub skipped_partitions: usize,
+}
+
+impl TemporalPartitions {
+    /// Group `(document, date, word count)` triples into partitions `span_years` wide
+    pub fn from_documents<I>(documents: I, span_years: u32) -> Self
+    where
+        I: IntoIterator<Item = (String, Option<String>, usize)>,
+    {
+        let span_years = span_years.max(1);
+        let mut dated: Vec<(String, u32, usize)> = Vec::new();
+        let mut undated = Vec::new();
+
+        for (document, date, words) in documents {
+            match date.as_deref().and_then(|d| parse_date_key(d, false)) {
+                Some(key) => dated.push((document, key, words)),
+                None => undated.push(document),
+            }
+        }
+        dated.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        undated.sort();
+
+        let mut partitions: Vec<TemporalPartition> = Vec::new();
+        for (document, key, words) in dated {
+            let year = key / 10_000;
+            let start_year = year - year % span_years;
+
+            let partition = match partitions.last_mut() {
+                Some(last) if last.start_year == start_year => last,
+                _ => {
+                    partitions.push(TemporalPartition {
+                        start_year,
+                        end_year: start_year + span_years - 1,
+                        documents: Vec::new(),
+                        total_words: 0,
+                    });
+                    partitions.last_mut().unwrap()
+                }
+            };
+            partition.documents.push((document, key));
+            partition.total_words += words as u64;
+        }
+
+        println!(
+            "    TemporalPartitions: {} partitions of {} years, {} undated documents",
+            partitions.len(),
+            span_years,
+            undated.len()
+        );
+
+        TemporalPartitions {
+            span_years,
+            partitions,
+            undated,
+        }
+    }
+
+    /// Add a document to the partition of its date, creating the partition if needed
+    pub fn insert(&mut self, document: String, date: Option<String>, words: usize) {
+        let Some(key) = date.as_deref().and_then(|d| parse_date_key(d, false)) else {
+            let idx = self.undated.binary_search(&document).unwrap_or_else(|idx| idx);
+            self.undated.insert(idx, document);
+            return;
+        };
+        let year = key / 10_000;
+        let start_year = year - year % self.span_years;
+        let idx = match self
+            .partitions
+            .binary_search_by_key(&start_year, |partition| partition.start_year)
+        {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.partitions.insert(
+                    idx,
+                    TemporalPartition {
+                        start_year,
+                        end_year: start_year + self.span_years - 1,
+                        documents: Vec::new(),
+                        total_words: 0,
+                    },
+                );
+                idx
+            }
+        };
+        let partition = &mut self.partitions[idx];
+        let position = partition
+            .documents
+            .binary_search_by(|(name, date)| date.cmp(&key).then_with(|| name.cmp(&document)))
+            .unwrap_or_else(|position| position);
+        partition.documents.insert(position, (document, key));
+        partition.total_words += words as u64;
+    }
+
+    /// Take a document with `words` words out of its partition, dropping the partition once
+    /// it is empty
+    pub fn remove(&mut self, document: &str, words: usize) {
+        self.undated.retain(|undated| undated != document);
+        for partition in &mut self.partitions {
+            if let Some(idx) = partition.documents.iter().position(|(name, _)| name == document) {
+                partition.documents.remove(idx);
+                partition.total_words = partition.total_words.saturating_sub(words as u64);
+            }
+        }
+        self.partitions.retain(|partition| !partition.documents.is_empty());
+    }
+
+    /// Documents dated within `[since, until]`; partitions outside the range are skipped whole
+    pub fn slice(&self, since: Option<u32>, until: Option<u32>) -> TimeSlice {
+        let since = since.unwrap_or(0);
+        let until = until.unwrap_or(u32::MAX);
+
+        let mut documents = HashSet::new();
+        let mut scanned_partitions = 0;
+        let mut skipped_partitions = 0;
+
+        for partition in &self.partitions {
+            if partition.end_key() < since || partition.start_key() > until {
+                skipped_partitions += 1;
+                continue;
+            }
+            scanned_partitions += 1;
+
+            if partition.start_key() >= since && partition.end_key() <= until {
+                documents.extend(partition.documents.iter().map(|(d, _)| d.clone()));
+            } else {
+                documents.extend(
+                    partition
+                        .documents
+                        .iter()
+                        .filter(|(_, key)| (since..=until).contains(key))
+                        .map(|(d, _)| d.clone()),
+                );
+            }
+        }
+
+        TimeSlice {
+            documents,
+            scanned_partitions,
+            skipped_partitions,
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .partitions
+                .iter()
+                .map(|p| {
+                    std::mem::size_of::<TemporalPartition>()
+                        + p.documents
+                            .iter()
+                            .map(|(d, _)| d.len() + std::mem::size_of::<(String, u32)>())
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+            + self.undated.iter().map(|d| d.len()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_partitions() -> TemporalPartitions {
+        let documents = vec![
+            ("a.fb2".to_string(), Some("1861-05-02".to_string()), 100),
+            ("b.fb2".to_string(), Some("1869".to_string()), 200),
+            ("c.fb2".to_string(), Some("1875-01-01".to_string()), 300),
+            ("d.fb2".to_string(), Some("1902".to_string()), 400),
+            ("e.fb2".to_string(), None, 500),
+        ];
+        TemporalPartitions::from_documents(documents, 10)
+    }
+
+    #[test]
+    fn test_date_keys() {
+        assert_eq!(parse_date_key("1869", false), Some(18690101));
+        assert_eq!(parse_date_key("1869", true), Some(18691231));
+        assert_eq!(parse_date_key("1869-07", true), Some(18690731));
+        assert_eq!(parse_date_key("1869-07-14T00:00", false), Some(18690714));
+        assert_eq!(parse_date_key("unknown", false), None);
+    }
+
+    #[test]
+    fn test_slice_skips_partitions() {
+        let partitions = create_test_partitions();
+        assert_eq!(partitions.partitions.len(), 3);
+        assert_eq!(partitions.partitions[0].total_words, 300);
+        assert_eq!(partitions.undated, vec!["e.fb2"]);
+
+        let slice = partitions.slice(parse_date_key("1865", false), parse_date_key("1879", true));
+        let mut documents: Vec<_> = slice.documents.into_iter().collect();
+        documents.sort();
+        assert_eq!(documents, vec!["b.fb2", "c.fb2"]);
+        assert_eq!(slice.scanned_partitions, 2);
+        assert_eq!(slice.skipped_partitions, 1);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::dictionary::{CorpusSource, TermEntry};
+use crate::estimate::{estimate_hits, union_frequency, wildcard_prefix};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+
+/// Terms per block; a scan reads whole blocks, so this bounds the overread at both ends
+pub const TERM_BLOCK_SIZE: usize = 128;
+
+/// Dictionary statistics of one term
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TermRecord {
+    pub term: String,
+    pub frequency: u32,
+    pub document_count: u32,
+}
+
+/// Location of one block, the first term in it and the CRC32 of its bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockHandle {
+    first_term: String,
+    offset: u64,
+    len: u64,
+    checksum: u32,
+}
+
+/// A block whose bytes no longer match their checksum, and the terms it held
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedBlock {
+    pub block: usize,
+    pub first_term: String,
+    /// First term of the next block, or `None` when the damaged block is the last
+    pub end_term: Option<String>,
+}
+
+impl fmt::Display for DamagedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.end_term {
+            Some(end) => write!(
+                f,
+                "block {} (terms '{}' to '{}')",
+                self.block, self.first_term, end
+            ),
+            None => write!(f, "block {} (terms from '{}')", self.block, self.first_term),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockDirectory {
+    term_count: usize,
+    total_documents: usize,
+    blocks: Vec<BlockHandle>,
+}
+
+/// Sorted terms on disk in fixed-size blocks, for prefix and range scans without loading the
+/// dictionary. The file holds the bincode blocks, then the block directory, then the directory
+/// offset as a little-endian `u64`. Opening reads only the directory (one key per block); a scan
+/// binary searches it and reads the blocks overlapping the requested range.
+///
+/// The directory keeps a CRC32 of every block. A scan skips blocks that fail it with a warning,
+/// so corruption costs the terms of the damaged blocks rather than the whole file, and
+/// `repair` rewrites just those blocks from the dictionary.
+#[derive(Debug)]
+pub struct TermBlockFile {
+    path: String,
+    directory: BlockDirectory,
+    directory_offset: u64,
+}
+
+impl TermBlockFile {
+    /// Write the terms of `dictionary` to `path`. Returns the number of bytes written.
+    pub fn write<D: CorpusSource>(
+        dictionary: &D,
+        path: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let entries = dictionary.sorted_entries();
+        let mut data = Vec::new();
+        let mut blocks = Vec::with_capacity(entries.len().div_ceil(TERM_BLOCK_SIZE));
+
+        for chunk in entries.chunks(TERM_BLOCK_SIZE) {
+            let records: Vec<TermRecord> = chunk
+                .iter()
+                .map(|(term, entry)| term_record(term, entry))
+                .collect();
+            let first_term = records[0].term.clone();
+            blocks.push(append_block(&mut data, first_term, &records)?);
+        }
+
+        let directory = BlockDirectory {
+            term_count: entries.len(),
+            total_documents: dictionary.document_names().len(),
+            blocks,
+        };
+        append_directory(&mut data, &directory)?;
+
+        fs::write(path, &data)?;
+        Ok(data.len())
+    }
+
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let end = file.seek(SeekFrom::End(-8))?;
+        let mut offset = [0u8; 8];
+        file.read_exact(&mut offset)?;
+        let directory_offset = u64::from_le_bytes(offset);
+        if directory_offset > end {
+            return Err(format!("Corrupt term block file {}", path).into());
+        }
+
+        let mut directory = vec![0u8; (end - directory_offset) as usize];
+        file.seek(SeekFrom::Start(directory_offset))?;
+        file.read_exact(&mut directory)?;
+
+        Ok(TermBlockFile {
+            path: path.to_string(),
+            directory: bincode::deserialize(&directory)?,
+            directory_offset,
+        })
+    }
+
+    pub fn term_count(&self) -> usize {
+        self.directory.term_count
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.directory.blocks.len()
+    }
+
+    pub fn total_documents(&self) -> usize {
+        self.directory.total_documents
+    }
+
+    pub fn get(&self, term: &str) -> Result<Option<TermRecord>, Box<dyn std::error::Error>> {
+        Ok(self.scan(term, |other| other != term)?.pop())
+    }
+
+    /// Terms in `[from, to)`, or from `from` to the end when `to` is `None`
+    pub fn range(
+        &self,
+        from: &str,
+        to: Option<&str>,
+    ) -> Result<Vec<TermRecord>, Box<dyn std::error::Error>> {
+        self.scan(from, |term| to.is_some_and(|to| term >= to))
+    }
+
+    /// Terms starting with `prefix`
+    pub fn prefix(&self, prefix: &str) -> Result<Vec<TermRecord>, Box<dyn std::error::Error>> {
+        self.scan(prefix, |term| !term.starts_with(prefix))
+    }
+
+    /// Up to `limit` terms starting with `stem`, most frequent first; the stem itself leads when
+    /// it is a term. Same order as `WildcardSearchEngine::expand_prefix`.
+    pub fn expand_prefix(
+        &self,
+        stem: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut candidates = self.prefix(stem)?;
+        let has_stem = candidates.first().is_some_and(|record| record.term == stem);
+        if has_stem {
+            candidates.remove(0);
+        }
+        candidates.sort_unstable_by(|a, b| {
+            b.frequency
+                .cmp(&a.frequency)
+                .then_with(|| a.term.cmp(&b.term))
+        });
+        candidates.truncate(limit);
+
+        let mut expansion = Vec::with_capacity(candidates.len() + 1);
+        if has_stem {
+            expansion.push(stem.to_string());
+        }
+        expansion.extend(candidates.into_iter().map(|record| record.term));
+        Ok(expansion)
+    }
+
+    /// Approximate hit count of a Boolean query, see `estimate_hits`. Reads only the blocks
+    /// holding the query terms.
+    pub fn estimate(&self, query: &str) -> Result<usize, String> {
+        let total_documents = self.total_documents();
+        estimate_hits(query, total_documents, |term| {
+            let frequency = match wildcard_prefix(term) {
+                Some(prefix) => self.prefix(prefix).map(|records| {
+                    union_frequency(
+                        records.iter().map(|record| record.document_count as usize),
+                        total_documents,
+                    )
+                }),
+                None => self
+                    .get(term)
+                    .map(|record| record.map_or(0, |record| record.document_count as usize)),
+            };
+            frequency.map_err(|e| e.to_string())
+        })
+    }
+
+    /// Blocks failing their checksum or no longer readable
+    pub fn verify(&self) -> Result<Vec<DamagedBlock>, Box<dyn std::error::Error>> {
+        let mut file = File::open(&self.path)?;
+        Ok((0..self.block_count())
+            .filter(|&block| self.read_block(&mut file, block).is_none())
+            .map(|block| self.damaged_block(block))
+            .collect())
+    }
+
+    /// Rewrite the damaged blocks from the terms of `dictionary` in their ranges, leaving the
+    /// other blocks as they are. Repaired blocks go after the intact ones, followed by a new
+    /// directory, and the file is replaced with a rename. Returns the blocks repaired.
+    pub fn repair<D: CorpusSource>(
+        &mut self,
+        dictionary: &D,
+    ) -> Result<Vec<DamagedBlock>, Box<dyn std::error::Error>> {
+        let damaged = self.verify()?;
+        if damaged.is_empty() {
+            return Ok(damaged);
+        }
+
+        let entries = dictionary.sorted_entries();
+        let mut data = fs::read(&self.path)?;
+        data.truncate(self.directory_offset as usize);
+        let mut directory = self.directory.clone();
+        for block in &damaged {
+            let start = entries.partition_point(|(term, _)| *term < block.first_term.as_str());
+            let end = match &block.end_term {
+                Some(end) => entries.partition_point(|(term, _)| *term < end.as_str()),
+                None => entries.len(),
+            };
+            let records: Vec<TermRecord> = entries[start..end]
+                .iter()
+                .map(|(term, entry)| term_record(term, entry))
+                .collect();
+            directory.blocks[block.block] =
+                append_block(&mut data, block.first_term.clone(), &records)?;
+        }
+        let directory_offset = append_directory(&mut data, &directory)?;
+
+        let temp_path = format!("{}.tmp", self.path);
+        fs::write(&temp_path, &data)?;
+        fs::rename(&temp_path, &self.path)?;
+        self.directory = directory;
+        self.directory_offset = directory_offset;
+        Ok(damaged)
+    }
+
+    /// Records of `block`, or `None` when it cannot be read or fails its checksum
+    fn read_block(&self, file: &mut File, block: usize) -> Option<Vec<TermRecord>> {
+        let handle = &self.directory.blocks[block];
+        let mut data = vec![0u8; handle.len as usize];
+        file.seek(SeekFrom::Start(handle.offset)).ok()?;
+        file.read_exact(&mut data).ok()?;
+        if crc32fast::hash(&data) != handle.checksum {
+            return None;
+        }
+        bincode::deserialize(&data).ok()
+    }
+
+    fn damaged_block(&self, block: usize) -> DamagedBlock {
+        let blocks = &self.directory.blocks;
+        DamagedBlock {
+            block,
+            first_term: blocks[block].first_term.clone(),
+            end_term: blocks.get(block + 1).map(|next| next.first_term.clone()),
+        }
+    }
+
+    /// Records from the first term `>= from` up to the first term for which `stop` holds.
+    /// Damaged blocks are skipped with a warning.
+    fn scan<F>(&self, from: &str, stop: F) -> Result<Vec<TermRecord>, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let blocks = &self.directory.blocks;
+        // The last block starting at or before `from` may still hold it
+        let first = blocks
+            .partition_point(|block| block.first_term.as_str() <= from)
+            .saturating_sub(1);
+
+        let mut file = File::open(&self.path)?;
+        let mut records = Vec::new();
+        for (index, block) in blocks.iter().enumerate().skip(first) {
+            if block.first_term.as_str() > from && stop(&block.first_term) {
+                break;
+            }
+            let Some(block_records) = self.read_block(&mut file, index) else {
+                eprintln!(
+                    "Warning: {} of {} is corrupt, skipping its terms",
+                    self.damaged_block(index),
+                    self.path
+                );
+                continue;
+            };
+
+            for record in block_records {
+                if record.term.as_str() < from {
+                    continue;
+                }
+                if stop(&record.term) {
+                    return Ok(records);
+                }
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn term_record(term: &str, entry: &TermEntry) -> TermRecord {
+    TermRecord {
+        term: term.to_string(),
+        frequency: entry.frequency,
+        document_count: entry.documents.len() as u32,
+    }
+}
+
+/// Serialize `records` onto `data` and return their handle
+fn append_block(
+    data: &mut Vec<u8>,
+    first_term: String,
+    records: &[TermRecord],
+) -> Result<BlockHandle, Box<dyn std::error::Error>> {
+    let block = bincode::serialize(records)?;
+    let handle = BlockHandle {
+        first_term,
+        offset: data.len() as u64,
+        len: block.len() as u64,
+        checksum: crc32fast::hash(&block),
+    };
+    data.extend(block);
+    Ok(handle)
+}
+
+/// Serialize the directory and the footer pointing at it onto `data`. Returns the directory
+/// offset.
+fn append_directory(
+    data: &mut Vec<u8>,
+    directory: &BlockDirectory,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let directory_offset = data.len() as u64;
+    data.extend(bincode::serialize(directory)?);
+    data.extend(directory_offset.to_le_bytes());
+    Ok(directory_offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dictionary;
+
+    #[test]
+    fn test_prefix_and_range_scans_across_blocks() {
+        let mut dict = Dictionary::new();
+        for i in 0..300 {
+            dict.add_term(format!("term{:03}", i), "doc1".to_string());
+        }
+        dict.add_term("term150".to_string(), "doc2".to_string());
+        dict.add_term("zebra".to_string(), "doc1".to_string());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("idx_terms.bin")
+            .to_string_lossy()
+            .to_string();
+        TermBlockFile::write(&dict, &path).unwrap();
+        let blocks = TermBlockFile::open(&path).unwrap();
+
+        assert_eq!(blocks.term_count(), 301);
+        assert_eq!(blocks.block_count(), 3);
+
+        let prefix = blocks.prefix("term12").unwrap();
+        assert_eq!(prefix.len(), 10);
+        assert_eq!(prefix[0].term, "term120");
+
+        let range = blocks.range("term127", Some("term130")).unwrap();
+        let terms: Vec<&str> = range.iter().map(|record| record.term.as_str()).collect();
+        assert_eq!(terms, vec!["term127", "term128", "term129"]);
+        assert_eq!(blocks.range("term299", None).unwrap().len(), 2);
+        assert!(blocks.prefix("x").unwrap().is_empty());
+
+        let expansion = blocks.expand_prefix("term15", 2).unwrap();
+        assert_eq!(expansion, vec!["term150", "term151"]);
+        assert_eq!(blocks.get("term150").unwrap().unwrap().document_count, 2);
+        assert!(blocks.get("term1500").unwrap().is_none());
+        assert_eq!(blocks.total_documents(), 2);
+        assert_eq!(blocks.estimate("term150 and zebra"), Ok(1));
+    }
+
+    #[test]
+    fn test_corrupt_blocks_are_skipped_and_repaired() {
+        let mut dict = Dictionary::new();
+        for i in 0..300 {
+            dict.add_term(format!("term{:03}", i), "doc1".to_string());
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join("idx_terms.bin")
+            .to_string_lossy()
+            .to_string();
+        TermBlockFile::write(&dict, &path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        let offset = TermBlockFile::open(&path).unwrap().directory.blocks[1].offset as usize;
+        data[offset + 20] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let mut blocks = TermBlockFile::open(&path).unwrap();
+        let damaged = blocks.verify().unwrap();
+        assert_eq!(
+            damaged,
+            vec![DamagedBlock {
+                block: 1,
+                first_term: "term128".to_string(),
+                end_term: Some("term256".to_string()),
+            }]
+        );
+        // Terms of the intact blocks are still found
+        assert_eq!(blocks.range("term120", Some("term260")).unwrap().len(), 12);
+        assert!(blocks.get("term200").unwrap().is_none());
+
+        assert_eq!(blocks.repair(&dict).unwrap(), damaged);
+        assert!(blocks.verify().unwrap().is_empty());
+        let reopened = TermBlockFile::open(&path).unwrap();
+        assert!(reopened.verify().unwrap().is_empty());
+        assert_eq!(
+            reopened.range("term120", Some("term260")).unwrap().len(),
+            140
+        );
+        assert_eq!(reopened.get("term200").unwrap().unwrap().frequency, 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+/// Sparse term-weight vector keyed by term
+pub type SparseVector = HashMap<String, f64>;
+
+/// Smoothed inverse document frequency: ln((1 + N) / (1 + df)) + 1
+pub fn idf(total_documents: usize, document_frequency: usize) -> f64 {
+    ((1.0 + total_documents as f64) / (1.0 + document_frequency as f64)).ln() + 1.0
+}
+
+/// Sublinear term frequency weight: 1 + ln(tf), or 0 for absent terms
+pub fn tf_weight(term_frequency: u32) -> f64 {
+    if term_frequency == 0 {
+        0.0
+    } else {
+        1.0 + (term_frequency as f64).ln()
+    }
+}
+
+/// Score documents by the summed TF-IDF weight of the query terms in them, highest first with
+/// ties broken by name
+pub fn rank_by_tfidf<I, D, T>(
+    documents: I,
+    terms: &[String],
+    total_documents: usize,
+    document_frequency: D,
+    term_frequency: T,
+) -> Vec<(String, f64)>
+where
+    I: IntoIterator<Item = String>,
+    D: Fn(&str) -> usize,
+    T: Fn(&str, &str) -> u32,
+{
+    let weights: Vec<(&str, f64)> = terms
+        .iter()
+        .map(|term| {
+            (
+                term.as_str(),
+                idf(total_documents, document_frequency(term)),
+            )
+        })
+        .collect();
+    let mut ranked: Vec<(String, f64)> = documents
+        .into_iter()
+        .map(|document| {
+            let score = weights
+                .iter()
+                .map(|(term, weight)| tf_weight(term_frequency(term, &document)) * weight)
+                .sum();
+            (document, score)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+/// Build an L2-normalized TF-IDF vector from a bag of terms
+pub fn tfidf_vector<'a, I, F>(terms: I, idf: F) -> SparseVector
+where
+    I: IntoIterator<Item = &'a str>,
+    F: Fn(&str) -> f64,
+{
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    for term in terms {
+        *counts.entry(term).or_insert(0) += 1;
+    }
+
+    let mut vector: SparseVector = counts
+        .into_iter()
+        .map(|(term, tf)| (term.to_string(), tf_weight(tf) * idf(term)))
+        .collect();
+    normalize(&mut vector);
+    vector
+}
+
+/// Scale a vector to unit length in place
+pub fn normalize(vector: &mut SparseVector) {
+    let norm = vector.values().map(|w| w * w).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        for weight in vector.values_mut() {
+            *weight /= norm;
+        }
+    }
+}
+
+/// Cosine similarity between two sparse vectors
+pub fn cosine_similarity(a: &SparseVector, b: &SparseVector) -> f64 {
+    let (smaller, larger) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+
+    let dot: f64 = smaller
+        .iter()
+        .filter_map(|(term, weight)| larger.get(term).map(|other| weight * other))
+        .sum();
+    let norm_a = a.values().map(|w| w * w).sum::<f64>().sqrt();
+    let norm_b = b.values().map(|w| w * w).sum::<f64>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
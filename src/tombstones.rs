@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Deleted documents waiting for compaction. Unlike hidden documents they never come back:
+/// queries drop them from every result and `compact_index` removes them from the structures,
+/// after which the tombstones are cleared.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tombstones {
+    pub documents: BTreeSet<String>,
+}
+
+impl Tombstones {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn path(prefix: &str) -> String {
+        format!("{}_deleted.bin", prefix)
+    }
+
+    /// Load the tombstones of `prefix`, treating a missing file as none
+    pub fn load_or_default(prefix: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Self::path(prefix);
+        if !Path::new(&path).exists() {
+            return Ok(Self::new());
+        }
+        Ok(bincode::deserialize(&fs::read(&path)?)?)
+    }
+
+    pub fn save(&self, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(Self::path(prefix), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Returns false if the document was already deleted
+    pub fn delete(&mut self, document: &str) -> bool {
+        self.documents.insert(document.to_string())
+    }
+
+    /// Undo a deletion, as when the document is replaced; returns false if it was not deleted
+    pub fn restore(&mut self, document: &str) -> bool {
+        self.documents.remove(document)
+    }
+
+    pub fn is_deleted(&self, document: &str) -> bool {
+        self.documents.contains(document)
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+}
@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::tfidf::tf_weight;
+
+/// Slack for float rounding when comparing a score bound to the current threshold; a bound
+/// within it of the threshold is scored rather than skipped
+const SCORE_EPSILON: f64 = 1e-9;
+
+/// The posting list of one query term as WAND walks it
+#[derive(Debug, Clone)]
+pub struct TermCursor {
+    /// Sorted, deduplicated document ids
+    pub doc_ids: Vec<u32>,
+    /// Occurrences of the term in each document of `doc_ids`
+    pub counts: Vec<u32>,
+    /// Inverse document frequency of the term
+    pub idf: f64,
+    /// The best score the term adds to any document, `tf_weight(max count) * idf`
+    pub max_score: f64,
+    position: usize,
+    /// Place of the term in the query; a document's term scores are summed in this order
+    order: usize,
+}
+
+impl TermCursor {
+    pub fn new(doc_ids: Vec<u32>, counts: Vec<u32>, idf: f64, max_score: f64) -> Self {
+        TermCursor {
+            doc_ids,
+            counts,
+            idf,
+            max_score,
+            position: 0,
+            order: 0,
+        }
+    }
+
+    fn current(&self) -> Option<u32> {
+        self.doc_ids.get(self.position).copied()
+    }
+
+    /// Move to the first document not below `target`
+    fn advance_to(&mut self, target: u32) {
+        self.position += self.doc_ids[self.position..].partition_point(|&id| id < target);
+    }
+
+    fn score(&self) -> f64 {
+        tf_weight(self.counts.get(self.position).copied().unwrap_or(1)) * self.idf
+    }
+}
+
+/// How much of the candidate set a top-k search scored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TopKStats {
+    /// Documents whose score was computed
+    pub scored: usize,
+    /// Postings stepped over unscored, their documents unable to make the top `k`
+    pub skipped_by_bound: usize,
+}
+
+/// A result kept on the heap; the greatest entry is the one to evict first
+#[derive(Debug, PartialEq)]
+struct Ranked {
+    score: f64,
+    doc_id: u32,
+}
+
+impl Eq for Ranked {}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .total_cmp(&self.score)
+            .then_with(|| self.doc_id.cmp(&other.doc_id))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The `k` best documents by the summed TF-IDF weight of the query terms, highest first with
+/// ties broken by lower id, scoring only documents that can still make the top `k`.
+///
+/// WAND keeps the cursors ordered by their current document. The pivot is the first cursor at
+/// which the summed `max_score`s of it and the cursors before it beat the `k`-th best score so
+/// far; no document before the pivot's can, so the cursors before it skip straight to the
+/// pivot's document, which is scored only once all of them land on it. Documents `accept`
+/// rejects, e.g. those failing a Boolean filter, are stepped over unscored. Only documents
+/// containing a query term are ranked.
+pub fn wand_top_k<F>(
+    mut cursors: Vec<TermCursor>,
+    k: usize,
+    accept: F,
+) -> (Vec<(u32, f64)>, TopKStats)
+where
+    F: Fn(u32) -> bool,
+{
+    let mut stats = TopKStats::default();
+    let mut best: BinaryHeap<Ranked> = BinaryHeap::with_capacity(k + 1);
+    if k == 0 {
+        return (Vec::new(), stats);
+    }
+    for (order, cursor) in cursors.iter_mut().enumerate() {
+        cursor.order = order;
+    }
+    cursors.retain(|cursor| cursor.current().is_some());
+
+    loop {
+        cursors.sort_by_key(|cursor| (cursor.current(), cursor.order));
+        let threshold = if best.len() == k {
+            best.peek().map_or(f64::NEG_INFINITY, |worst| worst.score)
+        } else {
+            f64::NEG_INFINITY
+        };
+        let mut bound = 0.0;
+        let pivot = cursors.iter().position(|cursor| {
+            bound += cursor.max_score;
+            bound + SCORE_EPSILON > threshold
+        });
+        let Some(pivot) = pivot else {
+            break;
+        };
+        let pivot_doc = cursors[pivot]
+            .current()
+            .expect("exhausted cursors are dropped");
+
+        if cursors[0].current() == Some(pivot_doc) {
+            let on_pivot = cursors
+                .iter()
+                .take_while(|cursor| cursor.current() == Some(pivot_doc))
+                .count();
+            if accept(pivot_doc) {
+                let score = cursors[..on_pivot].iter().map(TermCursor::score).sum();
+                stats.scored += 1;
+                // Documents come in ascending id order, so a tie never displaces an earlier one
+                if best.len() < k || score > threshold {
+                    best.push(Ranked {
+                        score,
+                        doc_id: pivot_doc,
+                    });
+                    if best.len() > k {
+                        best.pop();
+                    }
+                }
+            }
+            for cursor in &mut cursors[..on_pivot] {
+                cursor.position += 1;
+            }
+        } else {
+            for cursor in &mut cursors[..pivot] {
+                let before = cursor.position;
+                cursor.advance_to(pivot_doc);
+                stats.skipped_by_bound += cursor.position - before;
+            }
+        }
+        cursors.retain(|cursor| cursor.current().is_some());
+    }
+
+    let mut ranked: Vec<(u32, f64)> = best
+        .into_iter()
+        .map(|ranked| (ranked.doc_id, ranked.score))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    (ranked, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wand_matches_exhaustive_scoring() {
+        let lists: Vec<(Vec<u32>, Vec<u32>, f64)> = vec![
+            (
+                (0..200).collect(),
+                (0..200).map(|id| 1 + id % 3).collect(),
+                1.1,
+            ),
+            (
+                (0..200).step_by(7).collect(),
+                (0..200).step_by(7).map(|id| 1 + id % 5).collect(),
+                2.5,
+            ),
+            (vec![3, 50, 199], vec![9, 1, 4], 4.0),
+        ];
+        let cursors: Vec<TermCursor> = lists
+            .iter()
+            .map(|(doc_ids, counts, idf)| {
+                let max = counts.iter().copied().max().unwrap();
+                TermCursor::new(doc_ids.clone(), counts.clone(), *idf, tf_weight(max) * idf)
+            })
+            .collect();
+        let accept = |id: u32| id % 10 != 3;
+
+        let mut exhaustive: Vec<(u32, f64)> = (0..200)
+            .filter(|&id| accept(id))
+            .map(|id| {
+                let score = lists
+                    .iter()
+                    .filter_map(|(doc_ids, counts, idf)| {
+                        let i = doc_ids.binary_search(&id).ok()?;
+                        Some(tf_weight(counts[i]) * idf)
+                    })
+                    .sum();
+                (id, score)
+            })
+            .collect();
+        exhaustive.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        for k in [1, 5, 20] {
+            let (ranked, stats) = wand_top_k(cursors.clone(), k, accept);
+            let ids: Vec<u32> = ranked.iter().map(|&(id, _)| id).collect();
+            let expected: Vec<u32> = exhaustive[..k].iter().map(|&(id, _)| id).collect();
+            assert_eq!(ids, expected, "k = {}", k);
+            assert!(stats.scored < exhaustive.len(), "k = {}: {:?}", k, stats);
+        }
+        assert!(wand_top_k(cursors, 0, accept).0.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::query::{or_group, rewrite_terms};
+
+/// Default Cyrillic to Latin table (passport-style romanization of Russian and Ukrainian letters)
+const DEFAULT_TABLE: &[(&str, &str)] = &[
+    ("а", "a"),
+    ("б", "b"),
+    ("в", "v"),
+    ("г", "g"),
+    ("ґ", "g"),
+    ("д", "d"),
+    ("е", "e"),
+    ("є", "ye"),
+    ("ё", "yo"),
+    ("ж", "zh"),
+    ("з", "z"),
+    ("и", "i"),
+    ("і", "i"),
+    ("ї", "yi"),
+    ("й", "y"),
+    ("к", "k"),
+    ("л", "l"),
+    ("м", "m"),
+    ("н", "n"),
+    ("о", "o"),
+    ("п", "p"),
+    ("р", "r"),
+    ("с", "s"),
+    ("т", "t"),
+    ("у", "u"),
+    ("ф", "f"),
+    ("х", "kh"),
+    ("ц", "ts"),
+    ("ч", "ch"),
+    ("ш", "sh"),
+    ("щ", "shch"),
+    ("ъ", ""),
+    ("ы", "y"),
+    ("ь", ""),
+    ("э", "e"),
+    ("ю", "yu"),
+    ("я", "ya"),
+];
+
+/// Cyrillic to Latin character mapping
+#[derive(Debug, Clone)]
+pub struct TransliterationTable {
+    mapping: HashMap<char, String>,
+}
+
+impl Default for TransliterationTable {
+    fn default() -> Self {
+        TransliterationTable {
+            mapping: DEFAULT_TABLE
+                .iter()
+                .map(|(cyrillic, latin)| (cyrillic.chars().next().unwrap(), latin.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl TransliterationTable {
+    /// Load a table from a file of `cyrillic=latin` lines; `#` starts a comment.
+    /// Entries override the defaults, so a file only needs the letters it changes.
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut table = Self::default();
+        for (line_number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (cyrillic, latin) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{}:{}: expected cyrillic=latin", path, line_number + 1))?;
+            let mut chars = cyrillic.trim().chars();
+            match (chars.next(), chars.next()) {
+                (Some(letter), None) => {
+                    table.mapping.insert(letter, latin.trim().to_lowercase());
+                }
+                _ => {
+                    return Err(format!(
+                        "{}:{}: expected a single Cyrillic letter",
+                        path,
+                        line_number + 1
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Romanize a lowercase word; characters without a mapping are kept as-is
+    pub fn to_latin(&self, word: &str) -> String {
+        let mut latin = String::with_capacity(word.len());
+        for ch in word.chars() {
+            match self.mapping.get(&ch) {
+                Some(mapped) => latin.push_str(mapped),
+                None => latin.push(ch),
+            }
+        }
+        latin
+    }
+}
+
+fn is_cyrillic(word: &str) -> bool {
+    word.chars()
+        .any(|ch| ('\u{0400}'..='\u{04FF}').contains(&ch))
+}
+
+/// Query-time bridge between scripts: every indexed term is reachable by its romanized form
+pub struct TransliterationBridge {
+    table: TransliterationTable,
+    /// Romanized form to the Cyrillic terms producing it
+    by_latin: HashMap<String, Vec<String>>,
+    /// Latin terms present in the index
+    latin_terms: HashSet<String>,
+}
+
+impl TransliterationBridge {
+    pub fn new<'a, I>(table: TransliterationTable, terms: I) -> Self
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut by_latin: HashMap<String, Vec<String>> = HashMap::new();
+        let mut latin_terms = HashSet::new();
+
+        for term in terms {
+            if is_cyrillic(term) {
+                by_latin
+                    .entry(table.to_latin(term))
+                    .or_default()
+                    .push(term.clone());
+            } else {
+                latin_terms.insert(term.clone());
+            }
+        }
+
+        TransliterationBridge {
+            table,
+            by_latin,
+            latin_terms,
+        }
+    }
+
+    fn is_indexed(&self, term: &str) -> bool {
+        if is_cyrillic(term) {
+            self.by_latin
+                .get(&self.table.to_latin(term))
+                .is_some_and(|terms| terms.iter().any(|t| t == term))
+        } else {
+            self.latin_terms.contains(term)
+        }
+    }
+
+    /// Indexed spellings of `term` in the other script (the term itself is not included)
+    pub fn variants(&self, term: &str) -> Vec<String> {
+        let latin = self.table.to_latin(term);
+        let mut variants: Vec<String> = Vec::new();
+
+        if is_cyrillic(term) && self.latin_terms.contains(&latin) {
+            variants.push(latin.clone());
+        }
+        if let Some(cyrillic_terms) = self.by_latin.get(&latin) {
+            variants.extend(cyrillic_terms.iter().filter(|t| *t != term).cloned());
+        }
+
+        variants.sort();
+        variants.dedup();
+        variants
+    }
+
+    /// Expand plain query terms into `( term or variant ... )` groups; wildcard patterns,
+    /// phrases and near/N groups are left untouched
+    pub fn rewrite_query(&self, query: &str) -> Result<String, String> {
+        rewrite_terms(query, |term| {
+            if term.contains(['*', '?']) {
+                return None;
+            }
+            let variants = self.variants(term);
+            if variants.is_empty() {
+                return None;
+            }
+
+            // Unknown spellings are dropped: some structures reject terms missing from the index
+            let mut alternatives = Vec::with_capacity(variants.len() + 1);
+            if self.is_indexed(term) {
+                alternatives.push(term.to_string());
+            }
+            alternatives.extend(variants);
+            Some(or_group(&alternatives))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_bridge() -> TransliterationBridge {
+        let terms: Vec<String> = ["толстой", "война", "tolstoy", "war", "щука"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        TransliterationBridge::new(TransliterationTable::default(), &terms)
+    }
+
+    #[test]
+    fn test_variants_cross_scripts() {
+        let bridge = create_test_bridge();
+        assert_eq!(bridge.variants("tolstoy"), vec!["толстой"]);
+        assert_eq!(bridge.variants("толстой"), vec!["tolstoy"]);
+        assert_eq!(bridge.variants("shchuka"), vec!["щука"]);
+        assert!(bridge.variants("war").is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_leaves_phrases_and_operators() {
+        let bridge = create_test_bridge();
+        let rewritten = bridge
+            .rewrite_query("voyna and not \"tolstoy war\" or near/2(tolstoy war)")
+            .unwrap();
+        assert_eq!(
+            rewritten,
+            "война and not \"tolstoy war\" or near/2 ( tolstoy war )"
+        );
+        assert_eq!(
+            bridge.rewrite_query("tolstoy").unwrap(),
+            "( tolstoy or толстой )"
+        );
+    }
+}
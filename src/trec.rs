@@ -0,0 +1,130 @@
+use std::io::Write;
+
+/// One query of a batch, identified the way relevance judgments refer to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub id: String,
+    pub query: String,
+}
+
+/// Topics of a query file, one `id<TAB>query` line each; an id without a tab ends at the first
+/// space. Blank lines and lines starting with `#` are skipped.
+pub fn parse_topics(contents: &str) -> Result<Vec<Topic>, String> {
+    let mut topics = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, query) = line
+            .split_once('\t')
+            .or_else(|| line.split_once(' '))
+            .ok_or_else(|| format!("line {}: topic '{}' has no query", number + 1, line))?;
+        let query = query.trim();
+        if query.is_empty() {
+            return Err(format!("line {}: topic '{}' has no query", number + 1, id));
+        }
+        topics.push(Topic {
+            id: id.trim().to_string(),
+            query: query.to_string(),
+        });
+    }
+    Ok(topics)
+}
+
+/// Writes rankings as a TREC run file, the format `trec_eval` reads: one
+/// `topic Q0 document rank score tag` line per retrieved document, ranks counted from 1
+pub struct TrecRun<W: Write> {
+    writer: W,
+    tag: String,
+    lines: usize,
+}
+
+impl<W: Write> TrecRun<W> {
+    /// The tag names the run in every line, so it cannot be empty or hold whitespace
+    pub fn new(writer: W, tag: &str) -> Result<Self, String> {
+        if tag.is_empty() || tag.contains(char::is_whitespace) {
+            return Err(format!("Run tag '{}' must be one word", tag));
+        }
+        Ok(TrecRun {
+            writer,
+            tag: tag.to_string(),
+            lines: 0,
+        })
+    }
+
+    /// Write a topic's ranking, best document first
+    pub fn write_topic(
+        &mut self,
+        topic: &str,
+        ranking: &[(String, f64)],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (rank, (document, score)) in ranking.iter().enumerate() {
+            if document.contains(char::is_whitespace) {
+                return Err(format!(
+                    "Document '{}' has whitespace in its name and cannot be written to a run",
+                    document
+                )
+                .into());
+            }
+            writeln!(
+                self.writer,
+                "{} Q0 {} {} {:.6} {}",
+                topic,
+                document,
+                rank + 1,
+                score,
+                self.tag
+            )?;
+        }
+        self.lines += ranking.len();
+        Ok(())
+    }
+
+    /// Lines written so far
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn finish(mut self) -> Result<W, Box<dyn std::error::Error>> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_are_ranked_into_run_lines() {
+        let topics =
+            parse_topics("# war topics\n401\twar and peace\n\n402 \"анна каренина\"\n").unwrap();
+        assert_eq!(
+            topics,
+            [
+                Topic {
+                    id: "401".to_string(),
+                    query: "war and peace".to_string()
+                },
+                Topic {
+                    id: "402".to_string(),
+                    query: "\"анна каренина\"".to_string()
+                },
+            ]
+        );
+        assert!(parse_topics("403\n").is_err());
+
+        let mut run = TrecRun::new(Vec::new(), "grimoire").unwrap();
+        let ranking = [("b.fb2".to_string(), 2.5), ("a.fb2".to_string(), 1.0)];
+        run.write_topic("401", &ranking).unwrap();
+        run.write_topic("402", &[]).unwrap();
+        assert_eq!(run.lines(), 2);
+        let written = String::from_utf8(run.finish().unwrap()).unwrap();
+        assert_eq!(
+            written,
+            "401 Q0 b.fb2 1 2.500000 grimoire\n401 Q0 a.fb2 2 1.000000 grimoire\n"
+        );
+        assert!(TrecRun::new(Vec::new(), "my run").is_err());
+    }
+}
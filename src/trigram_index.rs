@@ -1,137 +1,144 @@
-use crate::CompressedDictionary;
+use crate::inverted_index::vb_encoding::{decode_delta_vb, encode_delta_vb};
+use crate::two_phase::{GlobVerifier, TwoPhaseSearch};
+use crate::{CompressedDictionary, TuningConfig};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+
+/// Position of a term in the sorted term list the index was built from
+pub type TermId = u32;
+
+/// Term ids of one trigram, ascending
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum TrigramPostings {
+    Plain(Vec<TermId>),
+    /// Delta + VB encoded, see `TrigramIndex::compress_postings`
+    Compressed(Vec<u8>),
+}
+
+impl TrigramPostings {
+    fn ids(&self) -> Cow<'_, [TermId]> {
+        match self {
+            TrigramPostings::Plain(ids) => Cow::Borrowed(ids),
+            TrigramPostings::Compressed(bytes) => Cow::Owned(decode_delta_vb(bytes)),
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            TrigramPostings::Plain(ids) => ids.len() * std::mem::size_of::<TermId>(),
+            TrigramPostings::Compressed(bytes) => bytes.len(),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TrigramIndex {
-    index: HashMap<String, HashSet<String>>,
+    index: HashMap<String, TrigramPostings>,
+    /// Terms by id. Not serialized: the owner stores the same sorted list and hands it back
+    /// with `set_terms` after loading.
+    #[serde(skip)]
+    terms: Vec<String>,
+}
+
+impl Default for TrigramIndex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TrigramIndex {
     pub fn new() -> Self {
         TrigramIndex {
             index: HashMap::new(),
+            terms: Vec::new(),
         }
     }
 
     pub fn from_dictionary(dictionary: &CompressedDictionary) -> Self {
+        Self::from_compressed_dictionary(dictionary)
+    }
+
+    pub fn from_compressed_dictionary(dictionary: &CompressedDictionary) -> Self {
         println!(
             "      TrigramIndex: Processing {} terms in parallel",
             dictionary.sorted_terms.len()
         );
 
-        let index = Arc::new(Mutex::new(HashMap::new()));
         let terms = dictionary.extract_terms_parallel();
 
-        // Process terms in parallel chunks
-        let chunk_size = 1000;
+        // Process terms in parallel chunks, each producing ascending ids per trigram
+        let chunk_size = TuningConfig::current().chunk_size;
         let chunks: Vec<_> = terms.chunks(chunk_size).collect();
 
-        chunks
+        let mut index: HashMap<String, Vec<TermId>> = chunks
             .par_iter()
             .enumerate()
-            .for_each(|(chunk_idx, chunk)| {
-                let mut local_trigrams = HashMap::new();
-
-                // Generate trigrams for this chunk locally (no mutex contention)
-                for term in chunk.iter() {
-                    let trigrams = Self::generate_trigrams_static(term);
+            .map(|(chunk_idx, chunk)| {
+                let mut local_trigrams: HashMap<String, Vec<TermId>> = HashMap::new();
+                for (offset, term) in chunk.iter().enumerate() {
+                    let id = (chunk_idx * chunk_size + offset) as TermId;
+                    let trigrams: HashSet<String> =
+                        Self::generate_trigrams_static(term).into_iter().collect();
                     for trigram in trigrams {
-                        local_trigrams
-                            .entry(trigram)
-                            .or_insert_with(HashSet::new)
-                            .insert((*term).clone());
-                    }
-                }
-
-                // Merge local results into global index (minimal mutex time)
-                if let Ok(mut global_index) = index.lock() {
-                    for (trigram, terms_set) in local_trigrams {
-                        global_index
-                            .entry(trigram)
-                            .or_insert_with(HashSet::new)
-                            .extend(terms_set);
+                        local_trigrams.entry(trigram).or_default().push(id);
                     }
                 }
 
                 let processed = (chunk_idx + 1) * chunk_size;
-                if processed % 5000 == 0 || chunk_idx == chunks.len() - 1 {
+                if processed.is_multiple_of(5000) || chunk_idx == chunks.len() - 1 {
                     println!(
                         "      TrigramIndex: Processed ~{} terms",
                         processed.min(terms.len())
                     );
                 }
+                local_trigrams
+            })
+            .reduce(HashMap::new, |mut merged, local| {
+                for (trigram, ids) in local {
+                    merged.entry(trigram).or_default().extend(ids);
+                }
+                merged
             });
+        // Chunks merge in any order
+        index.par_iter_mut().for_each(|(_, ids)| ids.sort_unstable());
 
-        let final_index = Arc::try_unwrap(index).unwrap().into_inner().unwrap();
         println!(
             "      TrigramIndex: Complete - {} terms, {} trigrams",
             terms.len(),
-            final_index.len()
+            index.len()
         );
 
-        TrigramIndex { index: final_index }
+        TrigramIndex {
+            index: index
+                .into_iter()
+                .map(|(trigram, ids)| (trigram, TrigramPostings::Plain(ids)))
+                .collect(),
+            terms,
+        }
     }
-    
-    pub fn from_compressed_dictionary(dictionary: &CompressedDictionary) -> Self {
-        println!(
-            "      TrigramIndex: Processing {} terms in parallel",
-            dictionary.sorted_terms.len()
-        );
-
-        let index = Arc::new(Mutex::new(HashMap::new()));
-        let terms = dictionary.extract_terms_parallel();
-
-        // Process terms in parallel chunks
-        let chunk_size = 1000;
-        let chunks: Vec<_> = terms.chunks(chunk_size).collect();
-
-        chunks
-            .par_iter()
-            .enumerate()
-            .for_each(|(chunk_idx, chunk)| {
-                let mut local_trigrams = HashMap::new();
 
-                // Generate trigrams for this chunk locally (no mutex contention)
-                for term in chunk.iter() {
-                    let trigrams = Self::generate_trigrams_static(term);
-                    for trigram in trigrams {
-                        local_trigrams
-                            .entry(trigram)
-                            .or_insert_with(HashSet::new)
-                            .insert((*term).clone());
-                    }
-                }
-
-                // Merge local results into global index (minimal mutex time)
-                if let Ok(mut global_index) = index.lock() {
-                    for (trigram, terms_set) in local_trigrams {
-                        global_index
-                            .entry(trigram)
-                            .or_insert_with(HashSet::new)
-                            .extend(terms_set);
-                    }
-                }
-
-                let processed = (chunk_idx + 1) * chunk_size;
-                if processed % 5000 == 0 || chunk_idx == chunks.len() - 1 {
-                    println!(
-                        "      TrigramIndex: Processed ~{} terms",
-                        processed.min(terms.len())
-                    );
-                }
-            });
+    /// Restore the term list after deserialization; it must be the sorted list the index was
+    /// built from
+    pub fn set_terms(&mut self, terms: Vec<String>) {
+        self.terms = terms;
+    }
 
-        let final_index = Arc::try_unwrap(index).unwrap().into_inner().unwrap();
-        println!(
-            "      TrigramIndex: Complete - {} terms, {} trigrams",
-            terms.len(),
-            final_index.len()
-        );
+    /// Switch every posting list to delta + VB encoding, trading decoding time per lookup for
+    /// roughly a quarter of the memory
+    pub fn compress_postings(&mut self) {
+        self.index.par_iter_mut().for_each(|(_, postings)| {
+            if let TrigramPostings::Plain(ids) = postings {
+                *postings = TrigramPostings::Compressed(encode_delta_vb(std::mem::take(ids)));
+            }
+        });
+    }
 
-        TrigramIndex { index: final_index }
+    pub fn is_compressed(&self) -> bool {
+        self.index
+            .values()
+            .all(|postings| matches!(postings, TrigramPostings::Compressed(_)))
     }
 
     pub(crate) fn generate_trigrams_static(term: &str) -> Vec<String> {
@@ -160,62 +167,59 @@ impl TrigramIndex {
             return self.find_exact_match(pattern);
         }
 
-        let required_trigrams = self.extract_required_trigrams(pattern);
+        TwoPhaseSearch::new(self)
+            .with_verifier(GlobVerifier)
+            .search(pattern)
+            .map(|result| result.matches)
+            .unwrap_or_default()
+    }
 
+    /// Terms holding every trigram `pattern` requires, a superset of its matches; every term
+    /// when the pattern has no three consecutive literal characters
+    pub fn candidate_terms(&self, pattern: &str) -> HashSet<String> {
+        let required_trigrams = self.extract_required_trigrams(pattern);
         if required_trigrams.is_empty() {
-            // If no useful trigrams can be extracted, fall back to brute force pattern matching
-            let all_terms: HashSet<String> = self.index.values().flatten().cloned().collect();
-            return all_terms
-                .into_iter()
-                .filter(|term| self.matches_pattern(term, pattern))
-                .collect();
+            return self.terms.iter().cloned().collect();
         }
 
-        let mut candidates: Option<HashSet<String>> = None;
-
-        for trigram in required_trigrams {
-            if let Some(terms) = self.index.get(&trigram) {
-                match candidates {
-                    None => candidates = Some(terms.clone()),
-                    Some(ref mut existing) => {
-                        existing.retain(|term| terms.contains(term));
-                    }
-                }
-            } else {
-                return HashSet::new();
-            }
-        }
-
-        let candidates = candidates.unwrap_or_default();
-
-        candidates
+        self.candidates(&required_trigrams)
             .into_iter()
-            .filter(|term| self.matches_pattern(term, pattern))
+            .map(|id| self.terms[id as usize].clone())
             .collect()
     }
 
     fn find_exact_match(&self, pattern: &str) -> HashSet<String> {
         let pattern_trigrams = Self::generate_trigrams_static(pattern);
-        let mut candidates: Option<HashSet<String>> = None;
-
-        for trigram in pattern_trigrams {
-            if let Some(terms) = self.index.get(&trigram) {
-                match candidates {
-                    None => candidates = Some(terms.clone()),
-                    Some(ref mut existing) => {
-                        existing.retain(|term| terms.contains(term));
-                    }
+        self.candidates(&pattern_trigrams)
+            .into_iter()
+            .map(|id| &self.terms[id as usize])
+            .filter(|term| *term == pattern)
+            .cloned()
+            .collect()
+    }
+
+    /// Ids of the terms containing every trigram, by merging the sorted posting lists. The
+    /// intersection alternates between two buffers owned by the query, so the shared index is
+    /// only read.
+    fn candidates(&self, trigrams: &[String]) -> Vec<TermId> {
+        let mut candidates: Option<Vec<TermId>> = None;
+        let mut scratch = Vec::new();
+
+        for trigram in trigrams {
+            let Some(postings) = self.index.get(trigram) else {
+                return Vec::new();
+            };
+            let ids = postings.ids();
+            match candidates.as_mut() {
+                None => candidates = Some(ids.into_owned()),
+                Some(existing) => {
+                    intersect_sorted_into(existing, &ids, &mut scratch);
+                    std::mem::swap(existing, &mut scratch);
                 }
-            } else {
-                return HashSet::new();
             }
         }
 
-        candidates
-            .unwrap_or_default()
-            .into_iter()
-            .filter(|term| *term == pattern)
-            .collect()
+        candidates.unwrap_or_default()
     }
 
     fn extract_required_trigrams(&self, pattern: &str) -> Vec<String> {
@@ -233,9 +237,9 @@ impl TrigramIndex {
             i += 1;
         }
 
-        if trigrams.is_empty() && pattern.len() >= 3 {
+        if trigrams.is_empty() && chars.len() >= 3 {
             let consecutive_chars = self.find_longest_consecutive_chars(pattern);
-            if consecutive_chars.len() >= 3 {
+            if consecutive_chars.chars().count() >= 3 {
                 let padded = format!("$${}", consecutive_chars);
                 let chars: Vec<char> = padded.chars().collect();
                 for i in 0..=chars.len().saturating_sub(3) {
@@ -257,81 +261,89 @@ impl TrigramIndex {
             if ch != '*' && ch != '?' {
                 current.push(ch);
             } else {
-                if current.len() > longest.len() {
+                if current.chars().count() > longest.chars().count() {
                     longest = current.clone();
                 }
                 current.clear();
             }
         }
 
-        if current.len() > longest.len() {
+        if current.chars().count() > longest.chars().count() {
             longest = current;
         }
 
         longest
     }
 
-    fn matches_pattern(&self, term: &str, pattern: &str) -> bool {
-        self.matches_wildcard(term, pattern)
-    }
-
     pub fn matches_wildcard(&self, text: &str, pattern: &str) -> bool {
-        // Use a well-tested glob matching function
-        self.glob_match(text, pattern)
-    }
-    
-    fn glob_match(&self, text: &str, pattern: &str) -> bool {
-        let text_bytes = text.as_bytes();
-        let pattern_bytes = pattern.as_bytes();
-        self.glob_match_bytes(text_bytes, pattern_bytes)
-    }
-    
-    fn glob_match_bytes(&self, text: &[u8], pattern: &[u8]) -> bool {
-        let mut text_pos = 0;
-        let mut pattern_pos = 0;
-        let mut star_pos = None;
-        let mut text_backup = 0;
-        
-        while text_pos < text.len() {
-            if pattern_pos < pattern.len() && 
-               (pattern[pattern_pos] == text[text_pos] || pattern[pattern_pos] == b'?') {
-                text_pos += 1;
-                pattern_pos += 1;
-            } else if pattern_pos < pattern.len() && pattern[pattern_pos] == b'*' {
-                star_pos = Some(pattern_pos);
-                text_backup = text_pos;
-                pattern_pos += 1;
-            } else if let Some(star) = star_pos {
-                pattern_pos = star + 1;
-                text_backup += 1;
-                text_pos = text_backup;
-            } else {
-                return false;
-            }
-        }
-        
-        while pattern_pos < pattern.len() && pattern[pattern_pos] == b'*' {
-            pattern_pos += 1;
-        }
-        
-        pattern_pos == pattern.len()
+        glob_match(text, pattern)
     }
 
     pub fn memory_size(&self) -> usize {
         let mut size = std::mem::size_of::<TrigramIndex>();
 
-        for (key, values) in &self.index {
+        for (key, postings) in &self.index {
             size += std::mem::size_of::<String>() + key.len();
-            size += std::mem::size_of::<HashSet<String>>();
-            for value in values {
-                size += std::mem::size_of::<String>() + value.len();
-            }
+            size += std::mem::size_of::<TrigramPostings>() + postings.heap_size();
         }
 
         size
     }
 }
 
+/// Match `text` against a glob pattern where `*` matches any run of characters and `?` one
+/// character, so a Cyrillic letter counts once rather than as its two UTF-8 bytes
+pub fn glob_match(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut text_pos = 0;
+    let mut pattern_pos = 0;
+    let mut star_pos = None;
+    let mut text_backup = 0;
+
+    while text_pos < text.len() {
+        if pattern_pos < pattern.len()
+            && (pattern[pattern_pos] == text[text_pos] || pattern[pattern_pos] == '?')
+        {
+            text_pos += 1;
+            pattern_pos += 1;
+        } else if pattern_pos < pattern.len() && pattern[pattern_pos] == '*' {
+            star_pos = Some(pattern_pos);
+            text_backup = text_pos;
+            pattern_pos += 1;
+        } else if let Some(star) = star_pos {
+            pattern_pos = star + 1;
+            text_backup += 1;
+            text_pos = text_backup;
+        } else {
+            return false;
+        }
+    }
+
+    while pattern_pos < pattern.len() && pattern[pattern_pos] == '*' {
+        pattern_pos += 1;
+    }
+
+    pattern_pos == pattern.len()
+}
+
+/// Write the intersection of two sorted id lists to `out`, replacing its contents
+fn intersect_sorted_into(a: &[TermId], b: &[TermId], out: &mut Vec<TermId>) {
+    out.clear();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -378,4 +390,54 @@ mod tests {
         assert!(results.contains("test"));
         assert!(results.contains("contest"));
     }
+
+    #[test]
+    fn test_compressed_postings_match_plain() {
+        let mut dict = Dictionary::new();
+        for term in ["testing", "test", "contest", "tester", "rest"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+        let compressed_dict = CompressedDictionary::from_dictionary(&dict);
+        let plain = TrigramIndex::from_dictionary(&compressed_dict);
+
+        let mut compressed = TrigramIndex::from_dictionary(&compressed_dict);
+        compressed.compress_postings();
+        let mut loaded: TrigramIndex =
+            bincode::deserialize(&bincode::serialize(&compressed).unwrap()).unwrap();
+        loaded.set_terms(compressed_dict.sorted_terms.clone());
+
+        assert!(loaded.is_compressed());
+        for pattern in ["test*", "*est", "t?st", "contest", "*"] {
+            assert_eq!(
+                loaded.find_matching_terms(pattern),
+                plain.find_matching_terms(pattern),
+                "pattern {}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_character_wildcard_on_cyrillic() {
+        assert!(glob_match("мир", "м?р"));
+        assert!(!glob_match("мир", "м??р"));
+        assert!(!glob_match("мир", "м?"));
+        assert!(glob_match("война", "?ой*"));
+        assert!(glob_match("ёлка", "?лк?"));
+
+        let mut dict = Dictionary::new();
+        for term in ["война", "воина", "вина", "воинство"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+        let trigram_index =
+            TrigramIndex::from_dictionary(&CompressedDictionary::from_dictionary(&dict));
+        assert_eq!(
+            trigram_index.find_matching_terms("во?на"),
+            HashSet::from(["война".to_string(), "воина".to_string()])
+        );
+        assert_eq!(
+            trigram_index.find_matching_terms("*ин?"),
+            HashSet::from(["воина".to_string(), "вина".to_string()])
+        );
+    }
 }
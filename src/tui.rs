@@ -0,0 +1,506 @@
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{process_status_bytes, IndexKind, SharedSearchers};
+
+/// Start of the line `grimoire build` prints as it begins the phrase index, whose length
+/// in words follows
+pub const PHRASE_INDEX_STAGE_MARKER: &str = "Building phrase index";
+
+/// Start of the line `grimoire build` prints for every document the phrase index parses
+pub const PHRASE_INDEX_DOCUMENT_MARKER: &str = "Processing document for phrase index:";
+
+/// Build stages in the order `grimoire build` runs them, each with the output line that
+/// announces it
+const BUILD_STAGES: &[(&str, &str)] = &[
+    ("Dictionary", "Building dictionary..."),
+    ("Compression", "Compressing dictionary..."),
+    (
+        "Matrix + inverted index",
+        "=== BUILDING SEARCH STRUCTURES ===",
+    ),
+    ("Phrase index", PHRASE_INDEX_STAGE_MARKER),
+    ("Coordinate index", "Building coordinate index..."),
+    ("Forward index", "Building forward index..."),
+    ("Temporal partitions", "Building temporal partitions..."),
+    ("Wildcard engine", "Building wildcard search engine..."),
+    ("Saving", "Saved incidence matrix to:"),
+];
+
+/// Output lines logged once per document by the stages that parse every document again
+const DOCUMENT_MARKERS: &[(&str, usize)] = &[
+    (PHRASE_INDEX_DOCUMENT_MARKER, 3),
+    ("Processing document for coordinate index:", 4),
+];
+
+const LOG_LINES: usize = 500;
+const MEMORY_SAMPLES: usize = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Per-stage progress of a build, reconstructed from its output
+#[derive(Debug, Clone)]
+pub struct BuildProgress {
+    pub stages: Vec<(&'static str, StageStatus)>,
+    /// Documents processed by the running stage, for stages that report them
+    pub documents_done: usize,
+    pub total_documents: Option<usize>,
+}
+
+impl Default for BuildProgress {
+    fn default() -> Self {
+        BuildProgress {
+            stages: BUILD_STAGES
+                .iter()
+                .map(|(name, _)| (*name, StageStatus::Pending))
+                .collect(),
+            documents_done: 0,
+            total_documents: None,
+        }
+    }
+}
+
+impl BuildProgress {
+    /// Advance the stages according to one line of build output
+    pub fn observe(&mut self, line: &str) {
+        let line = line.trim();
+        if let Some(total) = line.strip_prefix("Total documents: ") {
+            self.total_documents = total.parse().ok();
+        }
+        if let Some(stage) = BUILD_STAGES
+            .iter()
+            .position(|(_, marker)| line.starts_with(marker))
+        {
+            self.start(stage);
+        }
+        if let Some(&(_, stage)) = DOCUMENT_MARKERS
+            .iter()
+            .find(|(marker, _)| line.starts_with(marker))
+        {
+            self.start(stage);
+            self.documents_done += 1;
+        }
+    }
+
+    fn start(&mut self, stage: usize) {
+        if self.stages[stage].1 == StageStatus::Running {
+            return;
+        }
+        for (i, (_, status)) in self.stages.iter_mut().enumerate() {
+            *status = match i.cmp(&stage) {
+                std::cmp::Ordering::Less => StageStatus::Done,
+                std::cmp::Ordering::Equal => StageStatus::Running,
+                std::cmp::Ordering::Greater => StageStatus::Pending,
+            };
+        }
+        self.documents_done = 0;
+    }
+
+    /// Mark the build as finished; a failure is pinned on the stage that was running
+    pub fn finish(&mut self, success: bool) {
+        for (_, status) in &mut self.stages {
+            if success {
+                *status = StageStatus::Done;
+            } else if *status == StageStatus::Running {
+                *status = StageStatus::Failed;
+            }
+        }
+    }
+}
+
+/// Resident set size of a process in bytes, read from procfs; `None` where unavailable
+pub fn resident_memory(pid: u32) -> Option<u64> {
+    process_status_bytes(pid, "VmRSS")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_048_576.0)
+}
+
+pub struct TuiOptions {
+    /// Dictionary file prefix to search and build into
+    pub prefix: String,
+    /// FB2 directory to build from; without it the build pane is read-only
+    pub input: Option<String>,
+    /// Start a build as soon as the dashboard opens
+    pub build_on_start: bool,
+}
+
+struct BuildProcess {
+    child: Child,
+    lines: Receiver<String>,
+}
+
+struct App {
+    options: TuiOptions,
+    progress: BuildProgress,
+    build: Option<BuildProcess>,
+    build_started: Option<Instant>,
+    build_status: String,
+    log: VecDeque<String>,
+    memory_samples: VecDeque<u64>,
+    query: String,
+    results: Vec<String>,
+    result_state: ListState,
+    search_status: String,
+    searchers: SharedSearchers,
+}
+
+/// Run the dashboard until the user quits with Esc or Ctrl-C
+pub fn run_tui(options: TuiOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let build_on_start = options.build_on_start;
+    // Structures load on the first query and are reopened whenever a build replaces them.
+    // Every query is logged for `grimoire advisor`.
+    let searchers = SharedSearchers::open_recording_usage(&options.prefix);
+    let mut app = App {
+        options,
+        progress: BuildProgress::default(),
+        build: None,
+        build_started: None,
+        build_status: String::new(),
+        log: VecDeque::new(),
+        memory_samples: VecDeque::new(),
+        query: String::new(),
+        results: Vec::new(),
+        result_state: ListState::default(),
+        search_status: "Type a query and press Enter".to_string(),
+        searchers,
+    };
+    app.build_status = if app.options.input.is_some() {
+        "F5 starts a build".to_string()
+    } else {
+        "Pass --input to enable builds".to_string()
+    };
+    if build_on_start {
+        app.start_build();
+    }
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    if let Some(mut build) = app.build.take() {
+        let _ = build.child.kill();
+    }
+    result
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            self.poll_build();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(Duration::from_millis(250))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                KeyCode::F(5) => self.start_build(),
+                KeyCode::Enter => self.run_query(),
+                KeyCode::Backspace => {
+                    self.query.pop();
+                }
+                KeyCode::Char(ch) => self.query.push(ch),
+                KeyCode::Down => self.result_state.select_next(),
+                KeyCode::Up => self.result_state.select_previous(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Run `grimoire build` as a child process so its output feeds the progress pane
+    /// instead of the terminal
+    fn start_build(&mut self) {
+        if self.build.is_some() {
+            return;
+        }
+        let Some(input) = self.options.input.clone() else {
+            return;
+        };
+        let spawned = std::env::current_exe().and_then(|exe| {
+            Command::new(exe)
+                .args([
+                    "build",
+                    "-i",
+                    &input,
+                    "-o",
+                    &self.options.prefix,
+                    "-f",
+                    "binary",
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+        });
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                self.build_status = format!("Failed to start build: {}", e);
+                return;
+            }
+        };
+
+        let (sender, lines) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, sender);
+        }
+        self.progress = BuildProgress::default();
+        self.memory_samples.clear();
+        self.build_started = Some(Instant::now());
+        self.build_status = "Building...".to_string();
+        self.build = Some(BuildProcess { child, lines });
+    }
+
+    fn poll_build(&mut self) {
+        let Some(build) = self.build.as_mut() else {
+            return;
+        };
+        while let Ok(line) = build.lines.try_recv() {
+            self.progress.observe(&line);
+            if self.log.len() == LOG_LINES {
+                self.log.pop_front();
+            }
+            self.log.push_back(line);
+        }
+        if let Some(rss) = resident_memory(build.child.id()) {
+            if self.memory_samples.len() == MEMORY_SAMPLES {
+                self.memory_samples.pop_front();
+            }
+            self.memory_samples.push_back(rss);
+        }
+
+        let elapsed = self.build_started.map(|start| start.elapsed());
+        match build.child.try_wait() {
+            Ok(Some(status)) => {
+                self.progress.finish(status.success());
+                self.build_status = format!(
+                    "Build {} after {:.1?}",
+                    if status.success() {
+                        "finished"
+                    } else {
+                        "failed"
+                    },
+                    elapsed.unwrap_or_default()
+                );
+                self.build = None;
+                // The structures on disk were replaced, so reload them on the next query
+                self.searchers = SharedSearchers::open_recording_usage(&self.options.prefix);
+            }
+            Ok(None) => {
+                self.build_status = format!("Building... {:.0?}", elapsed.unwrap_or_default())
+            }
+            Err(e) => self.build_status = format!("Build status unknown: {}", e),
+        }
+    }
+
+    fn run_query(&mut self) {
+        let start = Instant::now();
+        match self.search(self.query.trim().to_string()) {
+            Ok((kind, mut documents)) => {
+                documents.sort();
+                self.search_status = format!(
+                    "{} documents from the {} in {:.2?}",
+                    documents.len(),
+                    kind,
+                    start.elapsed()
+                );
+                self.results = documents;
+                self.result_state.select(if self.results.is_empty() {
+                    None
+                } else {
+                    Some(0)
+                });
+            }
+            Err(e) => {
+                self.search_status = format!("Error: {}", e);
+                self.results.clear();
+                self.result_state.select(None);
+            }
+        }
+    }
+
+    fn search(&self, query: String) -> Result<(&'static str, Vec<String>), String> {
+        if query.is_empty() {
+            return Err("Empty query".to_string());
+        }
+        let (kind, documents) = self.searchers.search(&query)?;
+        let structure = match kind {
+            IndexKind::Coordinate => "coordinate index",
+            IndexKind::Wildcard => "wildcard engine",
+            _ => "inverted index",
+        };
+        Ok((structure, documents.into_iter().collect()))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, query_area, bottom] = Layout::vertical([
+            Constraint::Length(BUILD_STAGES.len() as u16 + 3),
+            Constraint::Length(3),
+            Constraint::Min(5),
+        ])
+        .areas(frame.area());
+        let [stages_area, memory_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+        let [results_area, log_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(bottom);
+
+        self.draw_stages(frame, stages_area);
+        self.draw_memory(frame, memory_area);
+
+        let query = Paragraph::new(Line::from(vec![
+            Span::raw(self.query.as_str()),
+            Span::styled("_", Style::default().add_modifier(Modifier::SLOW_BLINK)),
+        ]))
+        .block(Block::bordered().title(format!(" Query - {} ", self.search_status)));
+        frame.render_widget(query, query_area);
+
+        let results = List::new(self.results.iter().map(|doc| ListItem::new(doc.as_str())))
+            .block(Block::bordered().title(" Results (Up/Down) "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(results, results_area, &mut self.result_state);
+
+        let visible = log_area.height.saturating_sub(2) as usize;
+        let log: Vec<Line> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(visible))
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(log).block(Block::bordered().title(" Build output ")),
+            log_area,
+        );
+    }
+
+    fn draw_stages(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .progress
+            .stages
+            .iter()
+            .map(|&(name, status)| {
+                let (symbol, color) = match status {
+                    StageStatus::Pending => ("  ", Color::DarkGray),
+                    StageStatus::Running => ("> ", Color::Yellow),
+                    StageStatus::Done => ("ok", Color::Green),
+                    StageStatus::Failed => ("!!", Color::Red),
+                };
+                let mut text = format!("{} {}", symbol, name);
+                if status == StageStatus::Running && self.progress.documents_done > 0 {
+                    match self.progress.total_documents {
+                        Some(total) => {
+                            text += &format!(" ({}/{})", self.progress.documents_done, total)
+                        }
+                        None => text += &format!(" ({})", self.progress.documents_done),
+                    }
+                }
+                ListItem::new(text).style(Style::default().fg(color))
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items)
+                .block(Block::bordered().title(format!(" Build - {} (F5) ", self.build_status))),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let own = resident_memory(std::process::id()).map_or("n/a".to_string(), format_bytes);
+        let build = self
+            .memory_samples
+            .back()
+            .map_or("idle".to_string(), |&rss| format_bytes(rss));
+        let peak = self
+            .memory_samples
+            .iter()
+            .max()
+            .map_or("-".to_string(), |&rss| format_bytes(rss));
+        let block = Block::bordered().title(format!(
+            " Memory - dashboard {}, build {} (peak {}) ",
+            own, build, peak
+        ));
+        let samples: Vec<u64> = self.memory_samples.iter().copied().collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(block)
+                .data(&samples)
+                .style(Style::default().fg(Color::Cyan)),
+            area,
+        );
+    }
+}
+
+fn forward_lines<R: Read + Send + 'static>(reader: R, sender: Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_follows_build_output() {
+        let mut progress = BuildProgress::default();
+        for line in [
+            "Building dictionary...",
+            "Total documents: 2",
+            "Building phrase index of 3-word phrases...",
+            "  Processing document for phrase index: a.fb2",
+        ] {
+            progress.observe(line);
+        }
+
+        let status = |progress: &BuildProgress, name: &str| {
+            progress
+                .stages
+                .iter()
+                .find(|(stage, _)| *stage == name)
+                .unwrap()
+                .1
+        };
+        assert_eq!(status(&progress, "Compression"), StageStatus::Done);
+        assert_eq!(status(&progress, "Phrase index"), StageStatus::Running);
+        assert_eq!(status(&progress, "Coordinate index"), StageStatus::Pending);
+        assert_eq!(progress.documents_done, 1);
+        assert_eq!(progress.total_documents, Some(2));
+
+        progress.observe("  Processing document for coordinate index: a.fb2");
+        progress.finish(false);
+        assert_eq!(status(&progress, "Phrase index"), StageStatus::Done);
+        assert_eq!(status(&progress, "Coordinate index"), StageStatus::Failed);
+    }
+}
@@ -0,0 +1,176 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::hint::black_box;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::MergePolicy;
+
+/// Items timed per calibration round
+const CALIBRATION_ITEMS: usize = 20_000;
+/// Calibration rounds; the fastest is kept to filter out scheduling noise
+const CALIBRATION_ROUNDS: usize = 5;
+
+/// Sizes at which builds and searches switch between sequential and parallel code, and how
+/// much work a parallel task gets. Set once per process with `TuningConfig::install`; every
+/// structure reads the installed values through `TuningConfig::current`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TuningConfig {
+    /// Collections of terms or postings smaller than this are built on one thread
+    pub parallel_threshold: usize,
+    /// Sorts of more items than this run in parallel
+    pub parallel_sort_threshold: usize,
+    /// Terms per parallel task when a build is split into chunks
+    pub chunk_size: usize,
+    /// Matched terms above which a wildcard query fetches their documents in parallel
+    pub parallel_lookup_threshold: usize,
+    /// When a near-real-time index merges its segments
+    #[serde(default)]
+    pub merge_policy: MergePolicy,
+}
+
+static CURRENT: RwLock<TuningConfig> = RwLock::new(TuningConfig::DEFAULT);
+
+impl TuningConfig {
+    /// Values tuned by hand on a four-core laptop
+    pub const DEFAULT: TuningConfig = TuningConfig {
+        parallel_threshold: 1000,
+        parallel_sort_threshold: 10_000,
+        chunk_size: 1000,
+        parallel_lookup_threshold: 100,
+        merge_policy: MergePolicy::DEFAULT,
+    };
+
+    /// The installed configuration, `DEFAULT` until one is installed
+    pub fn current() -> TuningConfig {
+        *CURRENT
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Use this configuration for everything built or searched from now on
+    pub fn install(self) {
+        *CURRENT
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = self;
+    }
+
+    /// Pick thresholds from the core count and the measured cost of a small per-term task
+    /// against the cost of fanning work out to the thread pool. Work is worth splitting once
+    /// doing it on one thread takes several times the fan-out overhead.
+    pub fn calibrate() -> TuningConfig {
+        let threads = rayon::current_num_threads();
+        if threads <= 1 {
+            return TuningConfig {
+                parallel_threshold: usize::MAX,
+                parallel_sort_threshold: usize::MAX,
+                chunk_size: Self::DEFAULT.chunk_size,
+                parallel_lookup_threshold: usize::MAX,
+                merge_policy: MergePolicy::DEFAULT,
+            };
+        }
+
+        let terms: Vec<String> = (0..CALIBRATION_ITEMS)
+            .map(|i| format!("term{:06}", i.wrapping_mul(7919) % CALIBRATION_ITEMS))
+            .collect();
+        let per_item = fastest(|| {
+            black_box(terms.iter().fold(0u64, |hash, term| hash ^ term_work(term)));
+        }) / CALIBRATION_ITEMS as u32;
+        let fan_out = fastest(|| {
+            black_box((0..threads).into_par_iter().map(|i| i as u64).max());
+        });
+        let per_compare = fastest(|| {
+            let mut sorted = terms.clone();
+            sorted.sort_unstable();
+            black_box(sorted);
+        }) / (CALIBRATION_ITEMS as f64).log2().ceil() as u32
+            / CALIBRATION_ITEMS as u32;
+
+        let break_even = |unit: Duration| {
+            let unit = unit.as_nanos().max(1);
+            (fan_out.as_nanos() * 4 / unit) as usize
+        };
+        let parallel_threshold = break_even(per_item).clamp(64, 100_000);
+        TuningConfig {
+            parallel_threshold,
+            parallel_sort_threshold: break_even(per_compare).clamp(1000, 1_000_000),
+            // A few chunks per thread keep every core busy when chunks take uneven time
+            chunk_size: parallel_threshold.clamp(256, 10_000),
+            parallel_lookup_threshold: (parallel_threshold / 10).clamp(16, 10_000),
+            merge_policy: MergePolicy::DEFAULT,
+        }
+    }
+
+    /// Load a configuration saved as JSON
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// `auto` calibrates, `default` keeps the defaults and anything else is a saved
+    /// configuration file
+    pub fn from_setting(setting: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match setting {
+            "auto" => Ok(Self::calibrate()),
+            "default" => Ok(Self::DEFAULT),
+            path => Self::load(path),
+        }
+    }
+}
+
+impl Default for TuningConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Roughly what indexing does per term: hash it and look at its characters
+fn term_work(term: &str) -> u64 {
+    term.chars().fold(term.len() as u64, |hash, ch| {
+        hash.wrapping_mul(31).wrapping_add(ch as u64)
+    })
+}
+
+fn fastest<F: FnMut()>(mut run: F) -> Duration {
+    (0..CALIBRATION_ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrated_values_are_usable() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let config = pool.install(TuningConfig::calibrate);
+        assert!(config.parallel_threshold >= 64);
+        assert!(config.parallel_sort_threshold >= 1000);
+        assert!(config.chunk_size >= 256);
+        assert!(config.parallel_lookup_threshold >= 16);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tuning.json").to_string_lossy().to_string();
+        config.save(&path).unwrap();
+        assert_eq!(TuningConfig::from_setting(&path).unwrap(), config);
+        assert_eq!(
+            TuningConfig::from_setting("default").unwrap(),
+            TuningConfig::DEFAULT
+        );
+        assert!(TuningConfig::from_setting("missing.json").is_err());
+    }
+}
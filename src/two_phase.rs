@@ -0,0 +1,272 @@
+use crate::coordinate_index::phrase_words;
+use crate::trigram_index::glob_match;
+use crate::{
+    is_stem_pattern, CompressedInvertedIndex, CoordinateIndex, ForwardIndex, NGramPhraseIndex,
+    TrigramIndex,
+};
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::HashSet;
+use std::hash::Hash;
+
+/// First phase of a search: a cheap superset of the matches of a query
+pub trait CandidateGenerator {
+    /// A term for pattern queries, a document name for phrase queries
+    type Candidate: Eq + Hash + Clone + Send + Sync;
+
+    fn candidates(&self, query: &str) -> Result<HashSet<Self::Candidate>, String>;
+}
+
+/// Second phase of a search: keep the candidates that really match the query. Verifiers get
+/// all candidates at once so they can evaluate the query once instead of per candidate.
+pub trait Verifier<C> {
+    fn verify(&self, query: &str, candidates: HashSet<C>) -> Result<HashSet<C>, String>;
+}
+
+/// Any `Fn(query, candidate) -> Result<bool, String>` verifies candidates one at a time
+impl<C, F> Verifier<C> for F
+where
+    C: Eq + Hash + Send,
+    F: Fn(&str, &C) -> Result<bool, String> + Sync,
+{
+    fn verify(&self, query: &str, candidates: HashSet<C>) -> Result<HashSet<C>, String> {
+        let verified: Result<Vec<Option<C>>, String> = candidates
+            .into_par_iter()
+            .map(|candidate| Ok(self(query, &candidate)?.then_some(candidate)))
+            .collect();
+        Ok(verified?.into_iter().flatten().collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TwoPhaseResult<C> {
+    pub matches: HashSet<C>,
+    /// Candidates produced by the first phase, before verification
+    pub candidates: usize,
+}
+
+/// Candidate generation followed by exact verification. Verifiers run in the order they were
+/// added, each on the survivors of the previous one, so cheap checks should come first.
+pub struct TwoPhaseSearch<'a, G: CandidateGenerator> {
+    generator: &'a G,
+    verifiers: Vec<Box<dyn Verifier<G::Candidate> + Sync + 'a>>,
+}
+
+impl<'a, G: CandidateGenerator> TwoPhaseSearch<'a, G> {
+    pub fn new(generator: &'a G) -> Self {
+        TwoPhaseSearch {
+            generator,
+            verifiers: Vec::new(),
+        }
+    }
+
+    pub fn with_verifier<V>(mut self, verifier: V) -> Self
+    where
+        V: Verifier<G::Candidate> + Sync + 'a,
+    {
+        self.verifiers.push(Box::new(verifier));
+        self
+    }
+
+    pub fn search(&self, query: &str) -> Result<TwoPhaseResult<G::Candidate>, String> {
+        let mut matches = self.generator.candidates(query)?;
+        let candidates = matches.len();
+        for verifier in &self.verifiers {
+            if matches.is_empty() {
+                break;
+            }
+            matches = verifier.verify(query, matches)?;
+        }
+        Ok(TwoPhaseResult {
+            matches,
+            candidates,
+        })
+    }
+}
+
+/// Terms holding every trigram of a wildcard pattern
+impl CandidateGenerator for TrigramIndex {
+    type Candidate = String;
+
+    fn candidates(&self, query: &str) -> Result<HashSet<String>, String> {
+        Ok(self.candidate_terms(query))
+    }
+}
+
+/// Documents containing every consecutive word pair of a phrase
+impl CandidateGenerator for NGramPhraseIndex {
+    type Candidate = String;
+
+    fn candidates(&self, query: &str) -> Result<HashSet<String>, String> {
+        self.search_phrase(query)
+    }
+}
+
+/// Documents containing every word of a phrase
+impl CandidateGenerator for CompressedInvertedIndex {
+    type Candidate = String;
+
+    fn candidates(&self, query: &str) -> Result<HashSet<String>, String> {
+        let words = phrase_words(query);
+        let mut candidates: Option<HashSet<String>> = None;
+        for word in words.iter().filter(|word| !is_stem_pattern(word)) {
+            let documents: HashSet<String> = self
+                .get_documents_for_term(word)
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+            candidates = Some(match candidates {
+                None => documents,
+                Some(existing) => existing.intersection(&documents).cloned().collect(),
+            });
+        }
+        candidates.ok_or_else(|| "Phrase has no indexed words".to_string())
+    }
+}
+
+/// Terms matching the query as a `*`/`?` glob pattern
+pub struct GlobVerifier;
+
+impl Verifier<String> for GlobVerifier {
+    fn verify(
+        &self,
+        query: &str,
+        mut candidates: HashSet<String>,
+    ) -> Result<HashSet<String>, String> {
+        candidates.retain(|term| glob_match(term, query));
+        Ok(candidates)
+    }
+}
+
+/// Terms matching a regular expression; the query itself is not consulted
+pub struct RegexVerifier(pub Regex);
+
+impl Verifier<String> for RegexVerifier {
+    fn verify(
+        &self,
+        _query: &str,
+        mut candidates: HashSet<String>,
+    ) -> Result<HashSet<String>, String> {
+        candidates.retain(|term| self.0.is_match(term));
+        Ok(candidates)
+    }
+}
+
+/// Documents where the phrase occurs, checked against coordinate index positions
+pub struct PositionalVerifier<'a>(pub &'a CoordinateIndex);
+
+impl Verifier<String> for PositionalVerifier<'_> {
+    fn verify(
+        &self,
+        query: &str,
+        mut candidates: HashSet<String>,
+    ) -> Result<HashSet<String>, String> {
+        let postings = self.0.phrase_postings(query);
+        candidates.retain(|document| !postings.spans(document).is_empty());
+        Ok(candidates)
+    }
+}
+
+/// Documents where the phrase occurs, checked by scanning their token sequence in the forward
+/// index. Slower than `PositionalVerifier` per document but needs no coordinate index.
+pub struct ForwardIndexVerifier<'a>(pub &'a ForwardIndex);
+
+impl Verifier<String> for ForwardIndexVerifier<'_> {
+    fn verify(
+        &self,
+        query: &str,
+        mut candidates: HashSet<String>,
+    ) -> Result<HashSet<String>, String> {
+        let words = phrase_words(query);
+        if words.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let last = words.len() - 1;
+        let word_matches = |i: usize, term: &str| {
+            if i == last && is_stem_pattern(&words[i]) {
+                term.starts_with(words[i].trim_end_matches('*'))
+            } else {
+                term == words[i]
+            }
+        };
+
+        let index = self.0;
+        candidates.retain(|document| {
+            let Some(tokens) = index.documents.get(document) else {
+                return false;
+            };
+            tokens.windows(words.len()).any(|window| {
+                window
+                    .iter()
+                    .enumerate()
+                    .all(|(i, token)| word_matches(i, &index.terms[token.term as usize]))
+            })
+        });
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressedDictionary, Dictionary, TokenSpan};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_trigram_candidates_verified_by_glob_and_regex() {
+        let mut dict = Dictionary::new();
+        for term in ["testing", "contest", "tester", "resting"] {
+            dict.add_term(term.to_string(), "doc1".to_string());
+        }
+        let trigram_index =
+            TrigramIndex::from_dictionary(&CompressedDictionary::from_dictionary(&dict));
+
+        let result = TwoPhaseSearch::new(&trigram_index)
+            .with_verifier(GlobVerifier)
+            .search("t*st*")
+            .unwrap();
+        assert_eq!(result.candidates, 4);
+        assert_eq!(
+            result.matches,
+            HashSet::from(["testing".to_string(), "tester".to_string()])
+        );
+
+        let result = TwoPhaseSearch::new(&trigram_index)
+            .with_verifier(RegexVerifier(Regex::new("^[a-z]+ing$").unwrap()))
+            .with_verifier(|_: &str, term: &String| Ok(term.starts_with('r')))
+            .search("*est*")
+            .unwrap();
+        assert_eq!(result.matches, HashSet::from(["resting".to_string()]));
+    }
+
+    #[test]
+    fn test_forward_index_verifier_rejects_scattered_words() {
+        let terms = vec!["война".to_string(), "мир".to_string(), "анна".to_string()];
+        let tokens = |ids: &[u32]| -> Vec<TokenSpan> {
+            ids.iter()
+                .map(|&term| TokenSpan {
+                    term,
+                    start: 0,
+                    end: 0,
+                })
+                .collect()
+        };
+        let forward = ForwardIndex {
+            terms,
+            documents: HashMap::from([
+                ("a.fb2".to_string(), tokens(&[0, 1, 2])),
+                ("b.fb2".to_string(), tokens(&[0, 2, 1])),
+            ]),
+        };
+        let candidates = HashSet::from(["a.fb2".to_string(), "b.fb2".to_string()]);
+
+        let verified = ForwardIndexVerifier(&forward)
+            .verify("война мир", candidates.clone())
+            .unwrap();
+        assert_eq!(verified, HashSet::from(["a.fb2".to_string()]));
+        let verified = ForwardIndexVerifier(&forward)
+            .verify("война ан*", candidates)
+            .unwrap();
+        assert_eq!(verified, HashSet::from(["b.fb2".to_string()]));
+    }
+}
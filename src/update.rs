@@ -0,0 +1,914 @@
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::persist::{split_prefix, Persistable};
+use crate::pipeline::MIN_FILE_SIZE;
+use crate::{
+    source_len, BookMetadata, ChampionLists, CompressedDictionary, CompressedInvertedIndex,
+    CoordinateIndex, Dictionary, DocumentNorm, DocumentNorms, DocumentStore, FB2Parser, FieldIndex,
+    FileChanges, FingerprintCache, ForwardIndex, HiddenDocuments, IncidenceMatrix, IndexManifest,
+    NGramPhraseIndex, OffsetToken, PostingEncoding, StoredDocument, SurfaceForms,
+    TemporalPartitions, TermBlockFile, TermInterner, Tombstones, WildcardSearchEngine,
+};
+
+/// What an update did to the index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpdateReport {
+    /// Documents added, in the order of the files given
+    pub added: Vec<String>,
+    /// Indexed documents whose postings were replaced by those of a file with their name
+    pub replaced: Vec<String>,
+    /// Files whose name is indexed already, left alone
+    pub already_indexed: Vec<String>,
+    /// Files that are too small or could not be parsed
+    pub skipped: Vec<String>,
+    /// Generation committed by the update; the current one when nothing was added
+    pub generation: u64,
+}
+
+/// What `refresh_index` found in the input and did about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    pub changes: FileChanges,
+    /// Documents whose files are gone, deleted until the next compaction
+    pub deleted: Vec<String>,
+    /// The update of the new and modified files
+    pub update: UpdateReport,
+}
+
+/// What an update does with a file whose document name is indexed already
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Remove the indexed document from every structure and add the file in the same commit
+    Replace,
+    /// Leave the indexed document alone
+    #[default]
+    Skip,
+    /// Refuse the update before changing anything
+    Error,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(ConflictPolicy::Replace),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "error" => Ok(ConflictPolicy::Error),
+            _ => Err(format!(
+                "Unknown conflict policy: {} (expected replace, skip or error)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConflictPolicy::Replace => "replace",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What a compaction removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Deleted documents, by name
+    pub removed: Vec<String>,
+    /// Generation committed by the compaction; the current one when nothing was deleted
+    pub generation: u64,
+}
+
+struct NewDocument {
+    name: String,
+    bytes: u64,
+    tokens: Vec<OffsetToken>,
+    date: Option<String>,
+    title_length: usize,
+    language: Option<String>,
+    stored: StoredDocument,
+    surface_forms: Option<SurfaceForms>,
+}
+
+/// Structures an update patches from the body text, which is parsed again only for them
+#[derive(Debug, Clone, Copy)]
+struct BodyNeeds {
+    /// The document store keeps bodies
+    text: bool,
+    surface_forms: bool,
+}
+
+/// Add FB2 files to the index saved under `prefix` without rebuilding it. The coordinate,
+/// bigram and forward indexes, the temporal partitions, the document norms, the metadata
+/// fields, the surface forms and the document store are patched with the new documents, while the
+/// structures derived from the dictionary alone (incidence matrix, inverted index, wildcard
+/// engine and term blocks) are rebuilt from the merged dictionary. Documents are
+/// identified by file name; `on_conflict` decides what happens to files indexed already, and a
+/// replaced document that was deleted comes back. Parsed files are fingerprinted when the
+/// index keeps a fingerprint cache. `parser` must use the analyzer recorded in the manifest.
+pub fn update_index(
+    prefix: &str,
+    files: &[PathBuf],
+    parser: &FB2Parser,
+    on_conflict: ConflictPolicy,
+) -> Result<UpdateReport, Box<dyn std::error::Error>> {
+    let (dir, name) = split_prefix(prefix);
+    let analyzer = IndexManifest::analyzer(prefix)?;
+    if *parser.analyzer() != analyzer {
+        return Err(format!(
+            "{} was built with the analyzer '{}', not '{}'",
+            prefix,
+            analyzer,
+            parser.analyzer()
+        )
+        .into());
+    }
+
+    let mut dictionary = load_dictionary(prefix)?;
+
+    let mut report = UpdateReport::default();
+    let indexed: HashSet<&String> = dictionary.documents.iter().collect();
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    let mut conflicts = Vec::new();
+    for file in files {
+        let name = file
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if !seen.insert(name.clone()) {
+            report.already_indexed.push(name);
+        } else if !indexed.contains(&name) {
+            candidates.push((file, name));
+        } else {
+            match on_conflict {
+                ConflictPolicy::Replace => candidates.push((file, name)),
+                ConflictPolicy::Skip => report.already_indexed.push(name),
+                ConflictPolicy::Error => conflicts.push(name),
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "{} documents are indexed already: {}; update them with --on-conflict replace or \
+             leave them with --on-conflict skip",
+            conflicts.len(),
+            conflicts.join(", ")
+        )
+        .into());
+    }
+    let replaced: HashSet<String> = candidates
+        .iter()
+        .filter(|(_, name)| indexed.contains(name))
+        .map(|(_, name)| name.clone())
+        .collect();
+    drop(indexed);
+
+    let mut store = DocumentStore::load_if_present(dir, name)?;
+    let mut surface_forms = SurfaceForms::load_if_present(dir, name)?;
+    let needs = BodyNeeds {
+        text: store.as_ref().is_some_and(DocumentStore::keeps_bodies),
+        surface_forms: surface_forms.is_some(),
+    };
+    println!("    Update: Parsing {} new documents", candidates.len());
+    let parsed_files: Vec<PathBuf> = candidates
+        .iter()
+        .map(|(file, _)| file.to_path_buf())
+        .collect();
+    let parsed: Vec<Result<NewDocument, String>> = candidates
+        .into_par_iter()
+        .map(|(file, name)| parse_new_document(parser, file, name, needs))
+        .collect();
+    let mut documents = Vec::new();
+    for document in parsed {
+        match document {
+            Ok(document) => documents.push(document),
+            Err(name) => report.skipped.push(name),
+        }
+    }
+
+    if documents.is_empty() {
+        record_fingerprints(prefix, &parsed_files)?;
+        report.generation = IndexManifest::current_generation(prefix)?;
+        return Ok(report);
+    }
+    // Replacements that failed to parse keep the indexed document
+    let replaced: HashSet<String> = documents
+        .iter()
+        .filter(|document| replaced.contains(&document.name))
+        .map(|document| document.name.clone())
+        .collect();
+    if !replaced.is_empty() {
+        println!("    Update: Replacing {} indexed documents", replaced.len());
+        dictionary.remove_documents(&replaced);
+    }
+
+    for document in &documents {
+        let id = dictionary.document_id(&document.name);
+        let mut counts = HashMap::new();
+        for (term, _, _) in &document.tokens {
+            *counts.entry(term.clone()).or_insert(0) += 1;
+        }
+        dictionary.add_document_counts(id, counts);
+        dictionary.add_file_stats(document.bytes);
+        if replaced.contains(&document.name) {
+            report.replaced.push(document.name.clone());
+        } else {
+            report.added.push(document.name.clone());
+        }
+    }
+    let words: Vec<(String, Vec<String>)> = documents
+        .iter()
+        .map(|document| {
+            let words = document.tokens.iter().map(|(term, _, _)| term.clone());
+            (document.name.clone(), words.collect())
+        })
+        .collect();
+
+    let dictionary = Arc::new(CompressedDictionary::from_dictionary(&dictionary));
+    let mut structures = vec![
+        "",
+        "_matrix",
+        "_index",
+        "_wildcard",
+        "_permuterm",
+        "_terms",
+        "_interner",
+    ];
+
+    let mut replaced_lengths: HashMap<String, usize> = HashMap::new();
+    if let Some(mut coordinate_index) = CoordinateIndex::load_if_present(dir, name)? {
+        println!("    Update: Patching coordinate index");
+        if !replaced.is_empty() {
+            for (document, length) in coordinate_index.document_lengths() {
+                if replaced.contains(document) {
+                    replaced_lengths.insert(document.to_string(), length);
+                }
+            }
+            coordinate_index.remove_documents(&replaced);
+        }
+        for (name, words) in &words {
+            coordinate_index.add_document(name, words);
+        }
+        coordinate_index.save(dir, name)?;
+        structures.push("_coordinate");
+        #[cfg(feature = "ann")]
+        rebuild_hnsw_index(&coordinate_index, dir, name, &mut structures)?;
+    }
+
+    if let Some(mut bigram_index) = NGramPhraseIndex::load_if_present(dir, name)? {
+        println!("    Update: Patching bigram index");
+        if !replaced.is_empty() {
+            bigram_index.remove_documents(&replaced);
+        }
+        bigram_index.add_documents(&words);
+        bigram_index.save(dir, name)?;
+        structures.push("_bigram");
+    }
+
+    let mut interner = TermInterner::load_or_default(dir, name)?;
+    interner.intern_all(dictionary.sorted_terms.iter().map(String::as_str));
+    let mut forward_index = ForwardIndex::load_if_present(dir, name)?;
+    let mut partitions = TemporalPartitions::load_if_present(dir, name)?;
+    let mut norms = DocumentNorms::load_if_present(dir, name)?;
+    let mut fields = FieldIndex::load_if_present(dir, name)?;
+    if let Some(fields) = fields.as_mut() {
+        fields.remove_documents(&replaced);
+    }
+    for document in &replaced {
+        if let Some(forward_index) = forward_index.as_mut() {
+            forward_index.documents.remove(document);
+        }
+        if let Some(partitions) = partitions.as_mut() {
+            let words = replaced_lengths.get(document).copied().unwrap_or(0);
+            partitions.remove(document, words);
+        }
+        if let Some(norms) = norms.as_mut() {
+            norms.remove(document);
+        }
+    }
+    for mut document in documents {
+        // Spellings of replaced documents stay counted until the next full build
+        if let (Some(surface_forms), Some(forms)) =
+            (surface_forms.as_mut(), document.surface_forms.take())
+        {
+            surface_forms.merge(forms);
+        }
+        if let Some(fields) = fields.as_mut() {
+            for genre in &document.stored.genres {
+                fields.insert("genre", genre, &document.name);
+            }
+            if let Some(language) = &document.language {
+                fields.insert("lang", language, &document.name);
+            }
+            for author in &document.stored.authors {
+                fields.insert("author", author, &document.name);
+            }
+            if let Some(title) = &document.stored.title {
+                fields.insert_terms("title", &parser.tokenize_text(title), &document.name);
+            }
+            if let Some(annotation) = &document.stored.annotation {
+                let terms = parser.tokenize_text(annotation);
+                fields.insert_terms("annotation", &terms, &document.name);
+            }
+        }
+        // Replaced documents keep their id in the store
+        if let Some(store) = store.as_mut() {
+            store.insert(std::mem::take(&mut document.stored));
+        }
+        if let Some(norms) = norms.as_mut() {
+            let words = document.tokens.iter().map(|(term, _, _)| term.as_str());
+            let norm =
+                DocumentNorm::measure(words, document.title_length, document.language.as_deref());
+            norms.insert(&document.name, norm);
+        }
+        if let Some(partitions) = partitions.as_mut() {
+            partitions.insert(document.name.clone(), document.date, document.tokens.len());
+        }
+        if let Some(forward_index) = forward_index.as_mut() {
+            forward_index.insert_document(&document.name, document.tokens, &mut interner);
+        }
+    }
+    if let Some(forward_index) = forward_index {
+        println!("    Update: Patching forward index");
+        forward_index.save(dir, name)?;
+        structures.push("_forward");
+    }
+    if let Some(partitions) = partitions {
+        partitions.save(dir, name)?;
+        structures.push("_partitions");
+    }
+    if let Some(norms) = norms {
+        norms.save(dir, name)?;
+        structures.push("_norms");
+    }
+    if let Some(fields) = fields {
+        fields.save(dir, name)?;
+        structures.push("_fields");
+    }
+    if let Some(store) = store {
+        store.save(dir, name)?;
+        structures.push("_store");
+    }
+    if let Some(surface_forms) = surface_forms {
+        surface_forms.save(dir, name)?;
+        structures.push("_surface");
+    }
+    interner.save(dir, name)?;
+
+    let mut tombstones = Tombstones::load_or_default(prefix)?;
+    let restored = replaced
+        .iter()
+        .filter(|document| tombstones.restore(document))
+        .count();
+    if restored > 0 {
+        tombstones.save(prefix)?;
+    }
+
+    save_dictionary_structures(prefix, &dictionary, &mut structures)?;
+    report.generation = IndexManifest::commit(prefix, &structures)?.generation;
+    record_fingerprints(prefix, &parsed_files)?;
+    Ok(report)
+}
+
+/// Add `files` to the fingerprint cache of `prefix`, if it has one
+fn record_fingerprints(prefix: &str, files: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut fingerprints) = FingerprintCache::load(prefix)? {
+        fingerprints.record_files(files);
+        fingerprints.save(prefix)?;
+    }
+    Ok(())
+}
+
+/// Bring the index under `prefix` up to date with `files`, the whole input, using the
+/// fingerprint cache a build saves: unchanged files are not parsed, new and modified ones go
+/// through `update_index`, replacing the documents of modified files, and documents whose
+/// files are gone are deleted until the next compaction.
+pub fn refresh_index(
+    prefix: &str,
+    files: &[PathBuf],
+    parser: &FB2Parser,
+) -> Result<RefreshReport, Box<dyn std::error::Error>> {
+    let mut fingerprints = FingerprintCache::load(prefix)?.ok_or_else(|| {
+        format!(
+            "{} has no fingerprint cache, build it again to start one",
+            prefix
+        )
+    })?;
+    let mut report = RefreshReport {
+        changes: fingerprints.changes(files),
+        ..RefreshReport::default()
+    };
+
+    if !report.changes.removed.is_empty() {
+        let dictionary = load_dictionary(prefix)?;
+        let indexed: HashSet<&String> = dictionary.documents.iter().collect();
+        let mut tombstones = Tombstones::load_or_default(prefix)?;
+        for name in &report.changes.removed {
+            fingerprints.remove(name);
+            if indexed.contains(name) && tombstones.delete(name) {
+                report.deleted.push(name.clone());
+            }
+        }
+        tombstones.save(prefix)?;
+    }
+    // Saved before the update, which records the files it parses
+    fingerprints.save(prefix)?;
+
+    let changed = report.changes.changed();
+    report.update = if changed.is_empty() {
+        UpdateReport {
+            generation: IndexManifest::current_generation(prefix)?,
+            ..UpdateReport::default()
+        }
+    } else {
+        update_index(prefix, &changed, parser, ConflictPolicy::Replace)?
+    };
+    Ok(report)
+}
+
+/// Remove the documents deleted under `prefix` from every structure, then clear their
+/// tombstones. Posting lists, bigram ids and the dictionary's document table are rewritten
+/// without them, and terms only they contained are dropped.
+pub fn compact_index(prefix: &str) -> Result<CompactionReport, Box<dyn std::error::Error>> {
+    let (dir, name) = split_prefix(prefix);
+    let tombstones = Tombstones::load_or_default(prefix)?;
+    let mut report = CompactionReport::default();
+    if tombstones.is_empty() {
+        report.generation = IndexManifest::current_generation(prefix)?;
+        return Ok(report);
+    }
+    let deleted: HashSet<String> = tombstones.documents.iter().cloned().collect();
+
+    let mut dictionary = load_dictionary(prefix)?;
+    let in_dictionary = dictionary.remove_documents(&deleted);
+    println!(
+        "    Compact: Removed {} of {} deleted documents from the dictionary",
+        in_dictionary,
+        deleted.len()
+    );
+    let dictionary = Arc::new(CompressedDictionary::from_dictionary(&dictionary));
+    let mut structures = vec!["", "_matrix", "_index", "_wildcard", "_permuterm", "_terms"];
+
+    let mut lengths: HashMap<String, usize> = HashMap::new();
+    if let Some(mut coordinate_index) = CoordinateIndex::load_if_present(dir, name)? {
+        for (document, length) in coordinate_index.document_lengths() {
+            if deleted.contains(document) {
+                lengths.insert(document.to_string(), length);
+            }
+        }
+        coordinate_index.remove_documents(&deleted);
+        coordinate_index.save(dir, name)?;
+        structures.push("_coordinate");
+        #[cfg(feature = "ann")]
+        rebuild_hnsw_index(&coordinate_index, dir, name, &mut structures)?;
+    }
+
+    if let Some(mut bigram_index) = NGramPhraseIndex::load_if_present(dir, name)? {
+        bigram_index.remove_documents(&deleted);
+        bigram_index.save(dir, name)?;
+        structures.push("_bigram");
+    }
+
+    if let Some(mut forward_index) = ForwardIndex::load_if_present(dir, name)? {
+        forward_index
+            .documents
+            .retain(|document, _| !deleted.contains(document));
+        forward_index.save(dir, name)?;
+        structures.push("_forward");
+    }
+
+    if let Some(mut partitions) = TemporalPartitions::load_if_present(dir, name)? {
+        for document in &deleted {
+            let words = lengths.get(document).copied().unwrap_or(0);
+            partitions.remove(document, words);
+        }
+        partitions.save(dir, name)?;
+        structures.push("_partitions");
+    }
+
+    if let Some(mut norms) = DocumentNorms::load_if_present(dir, name)? {
+        for document in &deleted {
+            norms.remove(document);
+        }
+        norms.save(dir, name)?;
+        structures.push("_norms");
+    }
+
+    if let Some(mut fields) = FieldIndex::load_if_present(dir, name)? {
+        fields.remove_documents(&deleted);
+        fields.save(dir, name)?;
+        structures.push("_fields");
+    }
+
+    if let Some(mut store) = DocumentStore::load_if_present(dir, name)? {
+        store.remove_documents(&deleted);
+        store.save(dir, name)?;
+        structures.push("_store");
+    }
+
+    let hidden_path = format!("{}_hidden.bin", prefix);
+    let mut hidden = HiddenDocuments::load_or_default(&hidden_path)?;
+    let hidden_count = hidden.len();
+    for document in &deleted {
+        hidden.undelete(document);
+    }
+    if hidden.len() != hidden_count {
+        hidden.save(&hidden_path)?;
+    }
+
+    save_dictionary_structures(prefix, &dictionary, &mut structures)?;
+    Tombstones::new().save(prefix)?;
+    report.removed = tombstones.documents.into_iter().collect();
+    report.generation = IndexManifest::commit(prefix, &structures)?.generation;
+    Ok(report)
+}
+
+/// The dictionary saved under `prefix`, expanded for changes
+fn load_dictionary(prefix: &str) -> Result<Dictionary, Box<dyn std::error::Error>> {
+    let (dir, name) = split_prefix(prefix);
+    let compressed = CompressedDictionary::load_if_present(dir, name)?.ok_or_else(|| {
+        format!(
+            "{}.bin not found; changing an index needs the binary dictionary of a build",
+            prefix
+        )
+    })?;
+    Ok(compressed.to_dictionary())
+}
+
+/// Rebuild and save the dictionary and the structures derived from it alone
+fn save_dictionary_structures(
+    prefix: &str,
+    dictionary: &Arc<CompressedDictionary>,
+    structures: &mut Vec<&'static str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("    Rebuilding dictionary structures");
+    let (dir, name) = split_prefix(prefix);
+    IncidenceMatrix::from_dictionary(&**dictionary).save(dir, name)?;
+    // Keep the posting encoding the index was built with
+    let encoding = CompressedInvertedIndex::load_if_present(dir, name)?
+        .map_or_else(PostingEncoding::default, |index| index.encoding);
+    let index =
+        CompressedInvertedIndex::from_compressed_dictionary(dictionary).with_encoding(encoding);
+    index.save(dir, name)?;
+    // Champion lists keep the size they were built with
+    if let Some(champions) = ChampionLists::load_if_present(dir, name)? {
+        ChampionLists::from_index(&index, champions.size).save(dir, name)?;
+        structures.push("_champions");
+    }
+    let wildcard_engine = WildcardSearchEngine::from_compressed_dictionary(Arc::clone(dictionary))
+        .with_postings_encoding(encoding);
+    wildcard_engine.save(dir, name)?;
+    wildcard_engine
+        .permutation_index()
+        .save_partitioned(prefix)?;
+    TermBlockFile::write(&**dictionary, &format!("{}_terms.bin", prefix))?;
+
+    dictionary.save(dir, name)?;
+    // Other dictionary formats of the build would describe the old collection
+    if Path::new(&format!("{}.json", prefix)).exists() {
+        dictionary.save_as_json(&format!("{}.json", prefix))?;
+    }
+    if Path::new(&format!("{}.txt", prefix)).exists() {
+        dictionary.save_as_text(&format!("{}.txt", prefix))?;
+    }
+    Ok(())
+}
+
+/// Rebuild a saved nearest neighbor index from the changed coordinate index, keeping its
+/// parameters; every vector changes with the document frequencies anyway
+#[cfg(feature = "ann")]
+fn rebuild_hnsw_index(
+    coordinate_index: &CoordinateIndex,
+    dir: &Path,
+    name: &str,
+    structures: &mut Vec<&'static str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(hnsw) = crate::HnswIndex::load_if_present(dir, name)? {
+        println!("    Rebuilding nearest neighbor index");
+        crate::HnswIndex::from_coordinate_index(coordinate_index, hnsw.params()).save(dir, name)?;
+        structures.push("_hnsw");
+    }
+    Ok(())
+}
+
+/// Parse one file, with its body for the structures in `needs`, or give its name when it is
+/// too small or unreadable
+fn parse_new_document(
+    parser: &FB2Parser,
+    file: &Path,
+    name: String,
+    needs: BodyNeeds,
+) -> Result<NewDocument, String> {
+    let bytes = match source_len(file) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error reading metadata for {}: {}", file.display(), e);
+            return Err(name);
+        }
+    };
+    if bytes < MIN_FILE_SIZE {
+        eprintln!(
+            "Warning: {} is smaller than 150KB ({} bytes)",
+            file.display(),
+            bytes
+        );
+        return Err(name);
+    }
+    match parser.parse_file_with_offsets(file) {
+        Ok(tokens) => {
+            let body = (needs.text || needs.surface_forms)
+                .then(|| parser.parse_body(file).unwrap_or_default());
+            let surface_forms = body.as_deref().filter(|_| needs.surface_forms).map(|text| {
+                let mut forms = SurfaceForms::new();
+                forms.add_text(text, &parser.tokenize_text_with_offsets(text));
+                forms
+            });
+            let metadata = parser.parse_metadata(file).unwrap_or_else(|e| {
+                eprintln!("Error parsing metadata of {}: {}", file.display(), e);
+                BookMetadata::default()
+            });
+            let title_length = metadata
+                .title
+                .as_deref()
+                .map_or(0, |title| parser.tokenize_text(title).len());
+            Ok(NewDocument {
+                date: metadata.date.clone(),
+                title_length,
+                language: metadata.language.clone(),
+                stored: StoredDocument::new(
+                    &name,
+                    file,
+                    metadata,
+                    tokens.len() as u32,
+                    body.as_deref()
+                        .filter(|_| needs.text)
+                        .map(DocumentStore::compress),
+                ),
+                surface_forms,
+                name,
+                bytes,
+                tokens,
+            })
+        }
+        Err(e) => {
+            eprintln!("Error processing {}: {}", file.display(), e);
+            Err(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_dictionary, QueryParser, SharedSearchers};
+    use std::fs;
+
+    fn write_book(dir: &Path, name: &str, text: &str) -> PathBuf {
+        // Repeat the text to get past the size filter
+        let body = format!("<p>{}</p>", text).repeat(8000);
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!("<FictionBook><body>{}</body></FictionBook>", body),
+        )
+        .unwrap();
+        path
+    }
+
+    /// Save the dictionary, coordinate and bigram index of books in one directory
+    fn save_index(prefix: &str, files: &[PathBuf]) {
+        let books = files[0].parent().unwrap();
+        let parser = FB2Parser::new();
+        let parse = |name: &str| parser.parse_file(&books.join(name));
+        let dictionary =
+            CompressedDictionary::from_dictionary(&build_dictionary(files, false).unwrap());
+        let (dir, name) = split_prefix(prefix);
+        dictionary.save(dir, name).unwrap();
+        let coordinate = CoordinateIndex::from_dictionary_with_parser(&dictionary, parse).unwrap();
+        coordinate.save(dir, name).unwrap();
+        let bigram = NGramPhraseIndex::from_dictionary_with_parser(&dictionary, parse).unwrap();
+        bigram.save(dir, name).unwrap();
+    }
+
+    fn found(result: HashSet<String>) -> Vec<String> {
+        let mut found: Vec<String> = result.into_iter().collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn test_update_adds_documents_to_a_built_index() {
+        let books = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let prefix = out.path().join("idx").to_string_lossy().to_string();
+        let old = write_book(books.path(), "old.fb2", "war and peace");
+        save_index(&prefix, std::slice::from_ref(&old));
+        let parser = FB2Parser::new();
+
+        // Sorts before the indexed document, so bigram ids are renumbered
+        let new = write_book(books.path(), "a_new.fb2", "peace in the forest");
+        let small = books.path().join("small.fb2");
+        fs::write(&small, "<FictionBook><body><p>war</p></body></FictionBook>").unwrap();
+        let report = update_index(
+            &prefix,
+            &[old.clone(), new, small],
+            &parser,
+            ConflictPolicy::Skip,
+        )
+        .unwrap();
+        assert_eq!(report.added, vec!["a_new.fb2"]);
+        assert_eq!(report.already_indexed, vec!["old.fb2"]);
+        assert_eq!(report.skipped, vec!["small.fb2"]);
+        assert_eq!(report.generation, 1);
+
+        let dictionary = CompressedDictionary::load(out.path(), "idx").unwrap();
+        assert_eq!(dictionary.total_documents, 2);
+        let inverted = CompressedInvertedIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(
+            found(inverted.search("peace").unwrap()),
+            ["a_new.fb2", "old.fb2"]
+        );
+        let coordinate = CoordinateIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(
+            found(coordinate.search_phrase("the forest").unwrap()),
+            ["a_new.fb2"]
+        );
+        assert_eq!(
+            found(coordinate.search_phrase("war and").unwrap()),
+            ["old.fb2"]
+        );
+        let bigram = NGramPhraseIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(found(bigram.search_phrase("war and").unwrap()), ["old.fb2"]);
+        assert_eq!(
+            found(bigram.search_phrase("forest peace").unwrap()),
+            ["a_new.fb2"]
+        );
+        assert_eq!(bigram.phrase_freq("peace war", "old.fb2"), 7_999);
+
+        let again = update_index(&prefix, &[old], &parser, ConflictPolicy::Skip).unwrap();
+        assert!(again.added.is_empty());
+        assert_eq!(again.generation, 1);
+    }
+
+    #[test]
+    fn test_conflicting_documents_follow_the_policy() {
+        let books = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let prefix = out.path().join("idx").to_string_lossy().to_string();
+        let old = write_book(books.path(), "old.fb2", "war and peace");
+        let other = write_book(books.path(), "other.fb2", "peace in the forest");
+        save_index(&prefix, &[old.clone(), other]);
+        let parser = FB2Parser::new();
+
+        let revised = tempfile::tempdir().unwrap();
+        let old = write_book(revised.path(), "old.fb2", "love and hate");
+        let error = update_index(
+            &prefix,
+            std::slice::from_ref(&old),
+            &parser,
+            ConflictPolicy::Error,
+        );
+        assert!(error.unwrap_err().to_string().contains("old.fb2"));
+
+        let mut tombstones = Tombstones::new();
+        tombstones.delete("old.fb2");
+        tombstones.save(&prefix).unwrap();
+        let report = update_index(&prefix, &[old], &parser, ConflictPolicy::Replace).unwrap();
+        assert_eq!(report.replaced, vec!["old.fb2"]);
+        assert!(report.added.is_empty());
+        assert!(Tombstones::load_or_default(&prefix).unwrap().is_empty());
+
+        let dictionary = CompressedDictionary::load(out.path(), "idx").unwrap();
+        assert_eq!(dictionary.total_documents, 2);
+        assert!(!dictionary.contains_term("war"));
+        let inverted = CompressedInvertedIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(found(inverted.search("love").unwrap()), ["old.fb2"]);
+        assert_eq!(found(inverted.search("peace").unwrap()), ["other.fb2"]);
+        let coordinate = CoordinateIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(
+            found(coordinate.search_phrase("love and").unwrap()),
+            ["old.fb2"]
+        );
+        assert!(found(coordinate.search_phrase("war and").unwrap_or_default()).is_empty());
+        let bigram = NGramPhraseIndex::load(out.path(), "idx").unwrap();
+        assert!(found(bigram.search_phrase("war and").unwrap_or_default()).is_empty());
+        assert_eq!(
+            found(bigram.search_phrase("and hate").unwrap()),
+            ["old.fb2"]
+        );
+    }
+
+    #[test]
+    fn test_compaction_removes_deleted_documents() {
+        let books = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let prefix = out.path().join("idx").to_string_lossy().to_string();
+        let files = [
+            write_book(books.path(), "a.fb2", "war and peace"),
+            write_book(books.path(), "b.fb2", "peace in the forest"),
+            write_book(books.path(), "c.fb2", "forest and war"),
+        ];
+        save_index(&prefix, &files);
+        let mut norms = DocumentNorms::default();
+        for name in ["a.fb2", "b.fb2", "c.fb2"] {
+            norms.insert(name, DocumentNorm::measure(["war"], 0, None));
+        }
+        norms.save(out.path(), "idx").unwrap();
+
+        let mut tombstones = Tombstones::load_or_default(&prefix).unwrap();
+        assert!(tombstones.delete("b.fb2"));
+        assert!(!tombstones.delete("b.fb2"));
+        tombstones.save(&prefix).unwrap();
+
+        // Tombstones hide the document before compaction
+        let searchers = SharedSearchers::open(&prefix);
+        let (_, documents) = searchers.search("\"the forest\"").unwrap();
+        assert!(documents.is_empty());
+
+        let report = compact_index(&prefix).unwrap();
+        assert_eq!(report.removed, ["b.fb2"]);
+        assert_eq!(report.generation, 1);
+        assert!(Tombstones::load_or_default(&prefix).unwrap().is_empty());
+
+        let dictionary = CompressedDictionary::load(out.path(), "idx").unwrap();
+        assert_eq!(dictionary.documents, ["a.fb2", "c.fb2"]);
+        assert_eq!(dictionary.total_documents, 2);
+        assert!(!dictionary.contains_term("the"));
+        assert_eq!(
+            dictionary.get_term_entry("war").unwrap().frequency,
+            2 * 8000
+        );
+        let coordinate = CoordinateIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(coordinate.documents, ["a.fb2", "c.fb2"]);
+        assert!(!coordinate.index.contains_key("the"));
+        assert_eq!(
+            found(coordinate.search_phrase("war forest").unwrap()),
+            ["c.fb2"]
+        );
+        let bigram = NGramPhraseIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(bigram.documents, ["a.fb2", "c.fb2"]);
+        assert_eq!(found(bigram.search_phrase("and war").unwrap()), ["c.fb2"]);
+        assert_eq!(bigram.frequency("peace the"), 0);
+        assert_eq!(bigram.phrase_freq("forest and", "c.fb2"), 8000);
+        assert_eq!(DocumentNorms::load(out.path(), "idx").unwrap().len(), 2);
+
+        let again = compact_index(&prefix).unwrap();
+        assert!(again.removed.is_empty());
+        assert_eq!(again.generation, 1);
+    }
+
+    #[test]
+    fn test_refresh_indexes_only_changed_files() {
+        let books = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let prefix = out.path().join("idx").to_string_lossy().to_string();
+        let files = [
+            write_book(books.path(), "a.fb2", "war and peace"),
+            write_book(books.path(), "b.fb2", "peace in the forest"),
+            write_book(books.path(), "c.fb2", "forest and war"),
+        ];
+        save_index(&prefix, &files);
+        let parser = FB2Parser::new();
+        assert!(refresh_index(&prefix, &files, &parser).is_err());
+        let mut fingerprints = FingerprintCache::new();
+        fingerprints.record_files(&files);
+        fingerprints.save(&prefix).unwrap();
+
+        let unchanged = refresh_index(&prefix, &files, &parser).unwrap();
+        assert!(unchanged.changes.is_empty());
+        assert_eq!(unchanged.changes.unchanged, 3);
+        assert_eq!(unchanged.update.generation, 0);
+
+        fs::remove_file(&files[2]).unwrap();
+        let b = write_book(books.path(), "b.fb2", "love in the tower");
+        let d = write_book(books.path(), "d.fb2", "tower and forest");
+        let input = [files[0].clone(), b, d];
+        let report = refresh_index(&prefix, &input, &parser).unwrap();
+        assert_eq!(report.changes.unchanged, 1);
+        assert_eq!(report.update.added, ["d.fb2"]);
+        assert_eq!(report.update.replaced, ["b.fb2"]);
+        assert_eq!(report.deleted, ["c.fb2"]);
+        assert_eq!(report.update.generation, 1);
+        assert!(Tombstones::load_or_default(&prefix)
+            .unwrap()
+            .is_deleted("c.fb2"));
+
+        let inverted = CompressedInvertedIndex::load(out.path(), "idx").unwrap();
+        assert_eq!(found(inverted.search("tower").unwrap()), ["b.fb2", "d.fb2"]);
+        assert!(inverted.search("love and peace").unwrap().is_empty());
+        let again = refresh_index(&prefix, &input, &parser).unwrap();
+        assert!(again.changes.is_empty());
+        assert_eq!(FingerprintCache::load(&prefix).unwrap().unwrap().len(), 3);
+    }
+}
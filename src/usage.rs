@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::planner::{required_capabilities, IndexKind, ALL_STRUCTURES};
+use crate::query::Capabilities;
+
+/// Share of logged queries with phrases or near/N groups above which a missing coordinate
+/// index is worth building even if no query was routed to it
+const PHRASE_SHARE: f64 = 0.1;
+
+/// Append the structures one search used to `{prefix}_usage.log`, as
+/// `unix_seconds<TAB>structures<TAB>query` lines with comma-separated structure names
+pub fn append_usage_log(
+    prefix: &str,
+    query: &str,
+    structures: &[IndexKind],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let structures: Vec<String> = structures.iter().map(|kind| kind.to_string()).collect();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(IndexUsage::path(prefix))?;
+    writeln!(
+        file,
+        "{}\t{}\t{}",
+        timestamp,
+        structures.join(","),
+        query.split_whitespace().collect::<Vec<_>>().join(" ")
+    )?;
+    Ok(())
+}
+
+/// Structure files saved under `prefix` and their sizes in bytes
+pub fn structure_files(prefix: &str) -> Vec<(IndexKind, u64)> {
+    ALL_STRUCTURES
+        .into_iter()
+        .filter_map(|kind| {
+            let path = format!("{}_{}.bin", prefix, kind.file_suffix());
+            fs::metadata(path)
+                .ok()
+                .map(|metadata| (kind, metadata.len()))
+        })
+        .collect()
+}
+
+/// How often logged queries used each structure and which query features they had
+#[derive(Debug, Clone, Default)]
+pub struct IndexUsage {
+    pub queries: u64,
+    pub structures: HashMap<IndexKind, u64>,
+    pub phrase_queries: u64,
+    pub near_queries: u64,
+    pub wildcard_queries: u64,
+    /// Queries with a phrase, a near/N group or both
+    pub positional_queries: u64,
+}
+
+impl IndexUsage {
+    pub fn path(prefix: &str) -> String {
+        format!("{}_usage.log", prefix)
+    }
+
+    /// Usage logged under `prefix`; a missing log means no queries yet
+    pub fn from_log(prefix: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = match fs::read_to_string(Self::path(prefix)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut usage = IndexUsage::default();
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, '\t');
+            let (Some(_timestamp), Some(structures), Some(query)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let structures: Vec<IndexKind> = structures
+                .split(',')
+                .filter_map(|name| name.parse().ok())
+                .collect();
+            usage.record(query, &structures);
+        }
+        Ok(usage)
+    }
+
+    pub fn record(&mut self, query: &str, structures: &[IndexKind]) {
+        self.queries += 1;
+        for &kind in structures {
+            *self.structures.entry(kind).or_insert(0) += 1;
+        }
+        let required = required_capabilities(query, None).unwrap_or_default();
+        let phrases = required.contains(Capabilities::PHRASES);
+        let near = required.contains(Capabilities::POSITIONS);
+        self.phrase_queries += phrases as u64;
+        self.near_queries += near as u64;
+        self.positional_queries += (phrases || near) as u64;
+        self.wildcard_queries += required.contains(Capabilities::WILDCARDS) as u64;
+    }
+
+    /// Queries that used `kind`
+    pub fn uses(&self, kind: IndexKind) -> u64 {
+        self.structures.get(&kind).copied().unwrap_or(0)
+    }
+
+    /// Structures among `present` no logged query used, and missing structures queries
+    /// needed. Nothing is advised until `min_queries` queries are logged.
+    pub fn advise(&self, present: &[(IndexKind, u64)], min_queries: u64) -> Vec<Advice> {
+        if self.queries < min_queries {
+            return Vec::new();
+        }
+        let mut advice: Vec<Advice> = present
+            .iter()
+            .filter(|(kind, _)| self.uses(*kind) == 0)
+            .map(|&(kind, bytes)| Advice::Drop { kind, bytes })
+            .collect();
+
+        let is_present = |kind: IndexKind| present.iter().any(|(present, _)| *present == kind);
+        let positional = self.positional_queries;
+        for kind in ALL_STRUCTURES {
+            if is_present(kind) {
+                continue;
+            }
+            let needed = self.uses(kind);
+            if needed > 0 {
+                advice.push(Advice::Add {
+                    kind,
+                    reason: format!("{} of {} queries were routed to it", needed, self.queries),
+                });
+            } else if kind == IndexKind::Coordinate
+                && positional as f64 >= self.queries as f64 * PHRASE_SHARE
+                && positional > 0
+            {
+                advice.push(Advice::Add {
+                    kind,
+                    reason: format!(
+                        "{} of {} queries have phrases or near/N groups",
+                        positional, self.queries
+                    ),
+                });
+            }
+        }
+        advice
+    }
+}
+
+/// One suggestion of `IndexUsage::advise`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Advice {
+    /// Never used by a logged query; dropping it saves `bytes` of disk and its build time
+    Drop { kind: IndexKind, bytes: u64 },
+    /// Missing although queries need it
+    Add { kind: IndexKind, reason: String },
+}
+
+impl fmt::Display for Advice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Advice::Drop { kind, bytes } => write!(
+                f,
+                "Drop the {} index: no logged query used it ({:.1} MB on disk and its build time)",
+                kind,
+                *bytes as f64 / 1_048_576.0
+            ),
+            Advice::Add { kind, reason } => write!(f, "Add a {} index: {}", kind, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advice_follows_logged_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("idx").to_string_lossy().to_string();
+        append_usage_log(&prefix, "war  and peace", &[IndexKind::Inverted]).unwrap();
+        append_usage_log(&prefix, "\"war and peace\"", &[IndexKind::Coordinate]).unwrap();
+        append_usage_log(
+            &prefix,
+            "near/3(war peace) or wa*",
+            &[
+                IndexKind::Inverted,
+                IndexKind::Coordinate,
+                IndexKind::Wildcard,
+            ],
+        )
+        .unwrap();
+        fs::write(format!("{}_index.bin", prefix), [0u8; 16]).unwrap();
+        fs::write(format!("{}_matrix.bin", prefix), [0u8; 8]).unwrap();
+
+        let usage = IndexUsage::from_log(&prefix).unwrap();
+        assert_eq!(usage.queries, 3);
+        assert_eq!(usage.uses(IndexKind::Inverted), 2);
+        assert_eq!(usage.uses(IndexKind::Bigram), 0);
+        assert_eq!(
+            (
+                usage.phrase_queries,
+                usage.near_queries,
+                usage.wildcard_queries
+            ),
+            (1, 1, 1)
+        );
+
+        let present = structure_files(&prefix);
+        assert_eq!(
+            present,
+            vec![(IndexKind::Matrix, 8), (IndexKind::Inverted, 16)]
+        );
+        assert!(usage.advise(&present, 4).is_empty());
+        let advice = usage.advise(&present, 3);
+        assert_eq!(
+            advice[0],
+            Advice::Drop {
+                kind: IndexKind::Matrix,
+                bytes: 8
+            }
+        );
+        let added: Vec<IndexKind> = advice
+            .iter()
+            .filter_map(|advice| match advice {
+                Advice::Add { kind, .. } => Some(*kind),
+                Advice::Drop { .. } => None,
+            })
+            .collect();
+        assert_eq!(added, vec![IndexKind::Coordinate, IndexKind::Wildcard]);
+
+        // Phrase queries hinted to the bigram index still call for a coordinate index
+        let mut usage = IndexUsage::default();
+        for _ in 0..9 {
+            usage.record("war", &[IndexKind::Inverted]);
+        }
+        usage.record("\"war and peace\"", &[IndexKind::Bigram]);
+        let present = [(IndexKind::Inverted, 16), (IndexKind::Bigram, 16)];
+        assert_eq!(usage.advise(&present, 1).len(), 1);
+        assert!(usage.advise(&present, 1)[0]
+            .to_string()
+            .starts_with("Add a coordinate index: 1 of 10 queries"));
+    }
+}
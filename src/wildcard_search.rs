@@ -1,24 +1,104 @@
-use crate::{Dictionary, CompressedDictionary, CompressedInvertedIndex, PermutationIndex, QueryParser, SuffixTree, TrigramIndex};
-use rayon::prelude::*;
+use crate::codec::PostingEncoding;
+use crate::explain::{time_stage, trace_evaluation, QueryExplanation, TracedEvaluator};
+use crate::persist::Persistable;
+use crate::planner::IndexKind;
+use crate::query::{or_group, rewrite_terms, Capabilities, QueryAst, QueryEvaluator};
+use crate::{is_stem_pattern, Dictionary, CompressedDictionary, CompressedInvertedIndex, PermutationIndex, SuffixTree, TrigramIndex};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Matched terms a wildcard pattern is expanded to by default: the most frequent ones in the
+/// collection, so a pattern like `a*` does not fetch the postings of half the dictionary
+pub const DEFAULT_MAX_EXPANSIONS: usize = 1000;
+
+/// Rewrite every `stem*` term of a Boolean query into an OR group of the terms `expand` returns
+/// for its stem
+pub fn expand_stems_with<F>(query: &str, mut expand: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Result<Vec<String>, String>,
+{
+    let mut error = None;
+    let rewritten = rewrite_terms(query, |term| {
+        if !is_stem_pattern(term) || error.is_some() {
+            return None;
+        }
+        let stem = term.trim_end_matches('*');
+        match expand(stem) {
+            // Keep the query well-formed; the stem then matches nothing
+            Ok(expansion) if expansion.is_empty() => Some(stem.to_string()),
+            Ok(expansion) => Some(or_group(&expansion)),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        }
+    })?;
+    error.map_or(Ok(rewritten), Err)
+}
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "StoredWildcardEngine")]
 pub struct WildcardSearchEngine {
+    /// Exact-term postings, saved with the engine so loading never rebuilds them
     inverted_index: CompressedInvertedIndex,
     suffix_tree: SuffixTree,
     permutation_index: PermutationIndex,
     trigram_index: TrigramIndex,
-    dictionary: CompressedDictionary,
+    /// Shared with whoever built the engine; serialized by value
+    dictionary: Arc<CompressedDictionary>,
+    /// Matched terms each pattern is expanded to, `None` for all of them
+    #[serde(skip)]
+    max_expansions: Option<usize>,
+}
+
+impl Persistable for WildcardSearchEngine {
+    const SUFFIX: &'static str = "_wildcard";
+
+    fn format_version() -> u32 {
+        2
+    }
+}
+
+/// On-disk layout of `WildcardSearchEngine`, without the settings chosen per query
+#[derive(Deserialize)]
+struct StoredWildcardEngine {
+    inverted_index: CompressedInvertedIndex,
+    suffix_tree: SuffixTree,
+    permutation_index: PermutationIndex,
+    trigram_index: TrigramIndex,
+    dictionary: Arc<CompressedDictionary>,
+}
+
+impl From<StoredWildcardEngine> for WildcardSearchEngine {
+    fn from(stored: StoredWildcardEngine) -> Self {
+        WildcardSearchEngine {
+            inverted_index: stored.inverted_index,
+            suffix_tree: stored.suffix_tree,
+            permutation_index: stored.permutation_index,
+            trigram_index: {
+                let mut trigram_index = stored.trigram_index;
+                trigram_index.set_terms(stored.dictionary.sorted_terms.clone());
+                trigram_index
+            },
+            dictionary: stored.dictionary,
+            max_expansions: Some(DEFAULT_MAX_EXPANSIONS),
+        }
+    }
 }
 
 impl WildcardSearchEngine {
+    /// Queries without wildcards go to the embedded inverted index, hence NOT
+    pub const CAPABILITIES: Capabilities = Capabilities::WILDCARDS.union(Capabilities::NOT);
+
     pub fn from_dictionary(dictionary: Dictionary) -> Self {
         let compressed_dict = CompressedDictionary::from_dictionary(&dictionary);
         Self::from_compressed_dictionary(compressed_dict)
     }
-    
-    pub fn from_compressed_dictionary(dictionary: CompressedDictionary) -> Self {
+
+    /// Build over a dictionary; pass an `Arc` to share one already used elsewhere
+    pub fn from_compressed_dictionary(dictionary: impl Into<Arc<CompressedDictionary>>) -> Self {
+        let dictionary = dictionary.into();
         println!("  WildcardSearchEngine: Starting construction");
 
         println!("  WildcardSearchEngine: Building inverted index...");
@@ -48,81 +128,122 @@ impl WildcardSearchEngine {
             permutation_index,
             trigram_index,
             dictionary,
+            max_expansions: Some(DEFAULT_MAX_EXPANSIONS),
         }
     }
 
+    /// Expand each pattern to at most `limit` matched terms, the most frequent in the
+    /// collection; `None` expands to every match
+    pub fn with_max_expansions(mut self, limit: Option<usize>) -> Self {
+        self.max_expansions = limit;
+        self
+    }
+
+    /// Encode the exact-term postings with `encoding`, the codec of the index's `_index`
+    /// structure
+    pub fn with_postings_encoding(mut self, encoding: PostingEncoding) -> Self {
+        self.inverted_index = self.inverted_index.with_encoding(encoding);
+        self
+    }
+
+    /// Compact the embedded dictionary and exact-term index
+    pub fn optimize(&mut self) {
+        Arc::make_mut(&mut self.dictionary).optimize();
+        self.inverted_index.optimize();
+        self.trigram_index.compress_postings();
+    }
+
+    /// Boolean query whose terms may be `*` and `?` patterns, each matching the union of the
+    /// terms it expands to
     pub fn search(&self, query: &str) -> Result<HashSet<String>, String> {
         if query.is_empty() {
             return Err("Empty query".to_string());
         }
 
-        if !query.contains('*') && !query.contains('?') {
-            return self.exact_search(query);
-        }
-
-        self.wildcard_search(query)
+        let doc_ids = self.evaluate(&QueryAst::parse(query)?)?;
+        Ok(self.inverted_index.document_names(doc_ids))
     }
 
-    fn exact_search(&self, term: &str) -> Result<HashSet<String>, String> {
-        match self.inverted_index.search(term) {
-            Ok(documents) => Ok(documents),
-            Err(e) => Err(e),
+    /// The stem itself (when indexed) followed by up to `limit` other terms starting with it,
+    /// most frequent first
+    pub fn expand_prefix(&self, stem: &str, limit: usize) -> Vec<String> {
+        let mut candidates: Vec<(String, u32)> = self
+            .permutation_index
+            .find_matching_terms(&format!("{}*", stem))
+            .into_iter()
+            .filter(|term| term != stem)
+            .filter_map(|term| {
+                let frequency = self.dictionary.get_term_entry(&term)?.frequency;
+                Some((term, frequency))
+            })
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        candidates.truncate(limit);
+
+        let mut expansion = Vec::with_capacity(candidates.len() + 1);
+        if self.dictionary.contains_term(stem) {
+            expansion.push(stem.to_string());
         }
+        expansion.extend(candidates.into_iter().map(|(term, _)| term));
+        expansion
     }
 
-    fn wildcard_search(&self, pattern: &str) -> Result<HashSet<String>, String> {
-        let matching_terms = self.find_matching_terms(pattern)?;
-
-        if matching_terms.is_empty() {
-            return Ok(HashSet::new());
-        }
+    /// Rewrite every `stem*` term of a Boolean query into an OR group of its prefix expansion
+    pub fn expand_stems(&self, query: &str, limit: usize) -> Result<String, String> {
+        expand_stems_with(query, |stem| Ok(self.expand_prefix(stem, limit)))
+    }
 
-        // Parallelize document lookup for large term sets
-        let documents_sets: Vec<_> = if matching_terms.len() > 100 {
-            // For large result sets, use parallel processing
-            matching_terms
-                .par_iter()
-                .filter_map(|term| {
-                    // Find the term in the dictionary by looking up its position
-                    self.dictionary.get_term_entry(term).map(|entry| &entry.documents)
-                })
-                .map(|docs| docs.iter().cloned().collect::<HashSet<String>>())
-                .collect()
+    /// Dictionary terms a wildcard pattern expands to, within the expansion limit, with the
+    /// number of documents each one contributes, most documents first; a plain term expands to
+    /// itself when indexed
+    pub fn expansions(&self, pattern: &str) -> Result<Vec<TermExpansion>, String> {
+        let terms = if pattern.contains('*') || pattern.contains('?') {
+            self.expand_pattern(pattern)?.0
         } else {
-            // For small result sets, sequential is faster due to overhead
-            matching_terms
-                .iter()
-                .filter_map(|term| {
-                    // Find the term in the dictionary by looking up its position
-                    self.dictionary.get_term_entry(term).map(|entry| &entry.documents)
-                })
-                .map(|docs| docs.iter().cloned().collect::<HashSet<String>>())
-                .collect()
+            vec![pattern.to_string()]
         };
-
-        // Union all document sets
-        let all_documents = documents_sets
+        let mut expansions: Vec<TermExpansion> = terms
             .into_iter()
-            .fold(HashSet::new(), |mut acc, docs| {
-                acc.extend(docs);
-                acc
-            });
+            .filter_map(|term| {
+                let document_count = self.dictionary.get_term_entry(&term)?.documents.len();
+                Some(TermExpansion {
+                    term,
+                    document_count,
+                })
+            })
+            .collect();
+        expansions.sort_unstable_by(|a, b| {
+            b.document_count
+                .cmp(&a.document_count)
+                .then_with(|| a.term.cmp(&b.term))
+        });
+        Ok(expansions)
+    }
 
-        Ok(all_documents)
+    /// Terms `pattern` is expanded to when searching: every match, or the `max_expansions`
+    /// with the highest collection frequency. Also returns how many terms matched.
+    pub fn expand_pattern(&self, pattern: &str) -> Result<(Vec<String>, usize), String> {
+        let terms = self.find_matching_terms(pattern)?;
+        let matched = terms.len();
+        let mut terms: Vec<String> = terms.into_iter().collect();
+        if let Some(limit) = self.max_expansions.filter(|&limit| limit < matched) {
+            let frequency =
+                |term: &String| self.dictionary.get_term_entry(term).map_or(0, |e| e.frequency);
+            terms.sort_unstable_by(|a, b| frequency(b).cmp(&frequency(a)).then_with(|| a.cmp(b)));
+            terms.truncate(limit);
+        }
+        Ok((terms, matched))
     }
 
-    fn find_matching_terms(&self, pattern: &str) -> Result<HashSet<String>, String> {
+    pub(crate) fn find_matching_terms(&self, pattern: &str) -> Result<HashSet<String>, String> {
         let wildcard_complexity = self.analyze_wildcard_complexity(pattern);
 
         match wildcard_complexity {
             WildcardComplexity::Simple => {
                 if pattern.starts_with('*') && pattern.ends_with('*') {
                     Ok(self.suffix_tree.find_matching_terms(pattern))
-                } else if pattern.starts_with('*') {
-                    Ok(self.permutation_index.find_matching_terms(pattern))
-                } else if pattern.ends_with('*') {
-                    Ok(self.permutation_index.find_matching_terms(pattern))
                 } else {
+                    // Prefix, suffix and middle wildcards all map onto a single rotation
                     Ok(self.permutation_index.find_matching_terms(pattern))
                 }
             }
@@ -171,7 +292,7 @@ impl WildcardSearchEngine {
 
     fn analyze_wildcard_complexity(&self, pattern: &str) -> WildcardComplexity {
         let wildcard_count = pattern.chars().filter(|&c| c == '*' || c == '?').count();
-        let total_chars = pattern.len();
+        let total_chars = pattern.chars().count();
 
         if wildcard_count == 0 {
             WildcardComplexity::Simple
@@ -185,6 +306,61 @@ impl WildcardSearchEngine {
         }
     }
 
+    /// The structures `find_matching_terms` expands `pattern` with
+    pub fn wildcard_strategy(&self, pattern: &str) -> &'static str {
+        match self.analyze_wildcard_complexity(pattern) {
+            WildcardComplexity::Simple if pattern.starts_with('*') && pattern.ends_with('*') => {
+                "Suffix Tree"
+            }
+            WildcardComplexity::Simple => "Permutation Index",
+            WildcardComplexity::Medium => "Hybrid (Multiple Indices)",
+            WildcardComplexity::Complex => "Trigram Index",
+        }
+    }
+
+    /// `search` with every node of the query traced and each stage timed
+    pub fn explain(&self, query: &str) -> Result<QueryExplanation, String> {
+        let mut stages = Vec::new();
+        let query = time_stage(&mut stages, "parse", || QueryAst::parse(query))?;
+        let (doc_ids, trace) =
+            time_stage(&mut stages, "evaluate", || trace_evaluation(self, &query))?;
+        let documents = time_stage(&mut stages, "names", || {
+            self.inverted_index.document_names(doc_ids)
+        });
+        Ok(QueryExplanation {
+            kind: IndexKind::Wildcard,
+            trace,
+            stages,
+            documents,
+        })
+    }
+
+    /// Terms `pattern` expands to, with how they were found and the size of their posting
+    /// lists
+    pub(crate) fn trace_expansion(
+        &self,
+        pattern: &str,
+    ) -> Result<(Vec<String>, String, usize), String> {
+        let (terms, matched) = self.expand_pattern(pattern)?;
+        let mut method = format!(
+            "{} expanded to {} terms",
+            self.wildcard_strategy(pattern),
+            terms.len()
+        );
+        if terms.len() < matched {
+            method.push_str(&format!(" of {} matched", matched));
+        }
+        let postings = terms
+            .iter()
+            .map(|term| self.inverted_index.document_frequency(term))
+            .sum();
+        Ok((terms, method, postings))
+    }
+
+    pub fn permutation_index(&self) -> &PermutationIndex {
+        &self.permutation_index
+    }
+
     pub fn memory_size(&self) -> WildcardMemoryStats {
         WildcardMemoryStats {
             inverted_index_size: self.inverted_index.memory_size(),
@@ -205,11 +381,7 @@ impl WildcardSearchEngine {
         let search_time = start_time.elapsed();
 
         let strategy = if query.contains('*') || query.contains('?') {
-            match self.analyze_wildcard_complexity(query) {
-                WildcardComplexity::Simple => "Permutation Index".to_string(),
-                WildcardComplexity::Medium => "Hybrid (Multiple Indices)".to_string(),
-                WildcardComplexity::Complex => "Trigram Index".to_string(),
-            }
+            self.wildcard_strategy(query).to_string()
         } else {
             "Inverted Index".to_string()
         };
@@ -218,6 +390,8 @@ impl WildcardSearchEngine {
             Ok(documents) => WildcardSearchResult {
                 query: query.to_string(),
                 documents,
+                expansions: self.expansions(query).unwrap_or_default(),
+                truncations: self.truncations(query),
                 search_time,
                 strategy,
                 error: None,
@@ -225,12 +399,95 @@ impl WildcardSearchEngine {
             Err(e) => WildcardSearchResult {
                 query: query.to_string(),
                 documents: HashSet::new(),
+                expansions: Vec::new(),
+                truncations: Vec::new(),
                 search_time,
                 strategy,
                 error: Some(e),
             },
         }
     }
+
+    /// Patterns of `query` that matched more terms than were expanded
+    fn truncations(&self, query: &str) -> Vec<ExpansionTruncation> {
+        let mut patterns = Vec::new();
+        if let Ok(ast) = QueryAst::parse(query) {
+            collect_patterns(&ast, &mut patterns);
+        }
+        patterns
+            .into_iter()
+            .filter_map(|pattern| {
+                let (terms, matched) = self.expand_pattern(&pattern).ok()?;
+                (terms.len() < matched).then_some(ExpansionTruncation {
+                    pattern,
+                    matched,
+                    expanded: terms.len(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Wildcard patterns anywhere in `query`, in order
+fn collect_patterns(query: &QueryAst, patterns: &mut Vec<String>) {
+    match query {
+        QueryAst::Wildcard(pattern) => patterns.push(pattern.clone()),
+        QueryAst::And(left, right) | QueryAst::Or(left, right) => {
+            collect_patterns(left, patterns);
+            collect_patterns(right, patterns);
+        }
+        QueryAst::Not(operand) => collect_patterns(operand, patterns),
+        QueryAst::Near { operands, .. } => {
+            for operand in operands {
+                collect_patterns(operand, patterns);
+            }
+        }
+        QueryAst::Term(_) | QueryAst::Phrase(_) => {}
+    }
+}
+
+impl QueryEvaluator for WildcardSearchEngine {
+    type Output = Vec<u32>;
+
+    fn evaluate_term(&self, term: &str) -> Result<Self::Output, String> {
+        self.inverted_index.evaluate_term(term)
+    }
+
+    fn evaluate_wildcard(&self, pattern: &str) -> Result<Self::Output, String> {
+        let (terms, _) = self.expand_pattern(pattern)?;
+        Ok(self.inverted_index.doc_ids_for_terms(&terms))
+    }
+
+    fn intersect(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.inverted_index.intersect(left, right)
+    }
+
+    fn unite(&self, left: Self::Output, right: Self::Output) -> Self::Output {
+        self.inverted_index.unite(left, right)
+    }
+
+    fn complement(&self, operand: Self::Output) -> Self::Output {
+        self.inverted_index.complement(operand)
+    }
+}
+
+impl TracedEvaluator for WildcardSearchEngine {
+    fn candidate_count(&self, output: &Self::Output) -> usize {
+        output.len()
+    }
+
+    fn posting_list_size(&self, term: &str) -> usize {
+        self.inverted_index.document_frequency(term)
+    }
+
+    fn term_method(&self) -> String {
+        self.inverted_index.term_method()
+    }
+
+    fn trace_wildcard(&self, pattern: &str) -> Result<(Self::Output, String, usize), String> {
+        let (terms, method, postings) = self.trace_expansion(pattern)?;
+        Ok((self.inverted_index.doc_ids_for_terms(&terms), method, postings))
+    }
 }
 
 #[derive(Debug)]
@@ -249,10 +506,31 @@ pub struct WildcardMemoryStats {
     pub total_size: usize,
 }
 
+/// A dictionary term matched by a wildcard pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TermExpansion {
+    pub term: String,
+    /// Documents containing the term
+    pub document_count: usize,
+}
+
+/// A pattern that matched more terms than the engine's expansion limit; only the `expanded`
+/// most frequent were searched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpansionTruncation {
+    pub pattern: String,
+    pub matched: usize,
+    pub expanded: usize,
+}
+
 #[derive(Debug)]
 pub struct WildcardSearchResult {
     pub query: String,
     pub documents: HashSet<String>,
+    /// Terms the pattern expanded to, see `WildcardSearchEngine::expansions`
+    pub expansions: Vec<TermExpansion>,
+    /// Patterns whose expansion was cut to the most frequent terms
+    pub truncations: Vec<ExpansionTruncation>,
     pub search_time: std::time::Duration,
     pub strategy: String,
     pub error: Option<String>,
@@ -293,6 +571,21 @@ mod tests {
         assert!(result.contains("doc2.fb2"));
     }
 
+    #[test]
+    fn test_patterns_in_boolean_queries() {
+        let engine = WildcardSearchEngine::from_dictionary(create_test_dictionary());
+
+        let mut result: Vec<String> = engine
+            .search("hel* and not wor*")
+            .unwrap()
+            .into_iter()
+            .collect();
+        result.sort();
+        assert_eq!(result, vec!["doc2.fb2"]);
+        assert_eq!(engine.search("won* or *ing").unwrap().len(), 2);
+        assert!(engine.search("\"hel* world\"").is_err());
+    }
+
     #[test]
     fn test_suffix_wildcard() {
         let dict = create_test_dictionary();
@@ -311,4 +604,91 @@ mod tests {
         assert!(result.contains("doc1.fb2"));
         assert!(result.contains("doc3.fb2"));
     }
+
+    #[test]
+    fn test_expansions_explain_matches() {
+        let mut dict = create_test_dictionary();
+        dict.add_term("help".to_string(), "doc3.fb2".to_string());
+        let engine = WildcardSearchEngine::from_dictionary(dict);
+
+        let result = engine.search_with_stats("hel*");
+        let expansion = |term: &str, document_count| TermExpansion {
+            term: term.to_string(),
+            document_count,
+        };
+        assert_eq!(
+            result.expansions,
+            vec![expansion("help", 2), expansion("hello", 1)]
+        );
+        assert_eq!(
+            engine.expansions("hello").unwrap(),
+            vec![expansion("hello", 1)]
+        );
+        assert!(engine.expansions("xyz*").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expansion_limited_to_most_frequent_terms() {
+        let mut dict = create_test_dictionary();
+        for (term, document) in [
+            ("help", "doc2.fb2"),
+            ("help", "doc3.fb2"),
+            ("helm", "doc4.fb2"),
+            ("helm", "doc4.fb2"),
+        ] {
+            dict.add_term(term.to_string(), document.to_string());
+        }
+        let engine = WildcardSearchEngine::from_dictionary(dict).with_max_expansions(Some(2));
+
+        // "hello", the rarest match, is the one left out
+        let (terms, matched) = engine.expand_pattern("hel*").unwrap();
+        assert_eq!((terms, matched), (vec!["help".to_string(), "helm".to_string()], 3));
+        let result = engine.search_with_stats("hel* or wonder*");
+        let mut documents: Vec<&String> = result.documents.iter().collect();
+        documents.sort();
+        assert_eq!(documents, ["doc2.fb2", "doc3.fb2", "doc4.fb2"]);
+        assert_eq!(
+            result.truncations,
+            vec![ExpansionTruncation {
+                pattern: "hel*".to_string(),
+                matched: 3,
+                expanded: 2,
+            }]
+        );
+
+        let exhaustive = engine.with_max_expansions(None);
+        assert!(exhaustive.search("hel*").unwrap().contains("doc1.fb2"));
+        assert!(exhaustive.search_with_stats("hel*").truncations.is_empty());
+    }
+
+    #[test]
+    fn test_stem_expansion() {
+        let mut dict = create_test_dictionary();
+        dict.add_term("help".to_string(), "doc3.fb2".to_string());
+        dict.add_term("hel".to_string(), "doc3.fb2".to_string());
+        let engine = WildcardSearchEngine::from_dictionary(dict);
+
+        assert_eq!(engine.expand_prefix("hel", 1), vec!["hel", "help"]);
+        assert_eq!(
+            engine.expand_stems("hel* and not wor*", 5).unwrap(),
+            "( hel or help or hello ) and not world"
+        );
+        assert_eq!(engine.expand_stems("xyz*", 5).unwrap(), "xyz");
+    }
+
+    #[test]
+    fn test_exact_term_index_is_loaded_with_its_encoding() {
+        let engine = WildcardSearchEngine::from_dictionary(create_test_dictionary())
+            .with_postings_encoding(PostingEncoding::Roaring);
+        let data = engine.to_bytes().unwrap();
+        let loaded = WildcardSearchEngine::from_bytes(&data).unwrap();
+
+        assert_eq!(loaded.inverted_index.encoding, PostingEncoding::Roaring);
+        assert_eq!(loaded.search("hello").unwrap(), engine.search("hello").unwrap());
+        assert_eq!(loaded.search("hel*").unwrap(), engine.search("hel*").unwrap());
+        assert_eq!(
+            loaded.inverted_index.doc_name_to_id,
+            engine.inverted_index.doc_name_to_id
+        );
+    }
 }
@@ -0,0 +1,408 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::persist::{split_prefix, Persistable};
+use crate::{
+    CompactionLog, FB2Parser, Grimoire, IndexManifest, MergePolicy, MergeStats, SharedSearchers,
+    TuningConfig,
+};
+
+/// Text the write buffer holds before it is flushed to a persistent segment
+pub const DEFAULT_FLUSH_BYTES: usize = 1 << 20;
+
+/// An index that keeps growing while it is searched. Added documents enter an in-memory write
+/// buffer and are searchable as soon as `add` returns; once the buffered text exceeds the
+/// flush threshold, the buffer is saved as a new persistent segment under
+/// `{prefix}_segment{N}` and emptied. Queries run on every segment and the buffer, a term one
+/// of them lacks matching none of its documents.
+///
+/// The buffer is rebuilt from all of its documents on every `add`, so the threshold bounds
+/// both the memory it takes and the time a document takes to become searchable.
+///
+/// Flushed segments keep their documents and are merged into larger ones as the merge policy
+/// asks, so a long-running index does not search ever more small segments. Each merge is
+/// appended to the compaction log under the prefix.
+pub struct NearRealTimeIndex {
+    prefix: String,
+    parser: FB2Parser,
+    flush_bytes: usize,
+    merge_policy: MergePolicy,
+    segments: Vec<SharedSearchers>,
+    /// Number of the next segment flushed or merged
+    next_segment: u64,
+    buffered: Vec<(String, String)>,
+    buffered_bytes: usize,
+    /// Structures over `buffered`, `None` while the buffer is empty
+    buffer: Option<Grimoire>,
+}
+
+impl NearRealTimeIndex {
+    /// Search the index built under `prefix`, if there is one, and the segments flushed next
+    /// to it. Nothing is read until a query needs it. Segments are merged by the merge policy
+    /// of the installed `TuningConfig`.
+    pub fn open(prefix: &str, parser: FB2Parser) -> Self {
+        let mut segments = Vec::new();
+        if Path::new(&format!("{}_index.bin", prefix)).exists() {
+            segments.push(SharedSearchers::open(prefix));
+        }
+        let numbers = segment_numbers(prefix);
+        for number in &numbers {
+            segments.push(SharedSearchers::open(&segment_prefix(prefix, *number)));
+        }
+        NearRealTimeIndex {
+            prefix: prefix.to_string(),
+            parser,
+            flush_bytes: DEFAULT_FLUSH_BYTES,
+            merge_policy: TuningConfig::current().merge_policy,
+            segments,
+            next_segment: numbers.last().map_or(1, |last| last + 1),
+            buffered: Vec::new(),
+            buffered_bytes: 0,
+            buffer: None,
+        }
+    }
+
+    /// Flush the buffer once it holds more than `bytes` of text
+    pub fn with_flush_threshold(mut self, bytes: usize) -> Self {
+        self.flush_bytes = bytes;
+        self
+    }
+
+    pub fn with_merge_policy(mut self, policy: MergePolicy) -> Self {
+        self.merge_policy = policy;
+        self
+    }
+
+    /// Buffer a document, searchable once this returns. Returns the prefix of the segment the
+    /// buffer was flushed to, if adding the document made it exceed the flush threshold; the
+    /// merges the policy asks for follow the flush, so that segment may already be merged.
+    pub fn add(
+        &mut self,
+        id: &str,
+        text: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if self.buffered.iter().any(|(buffered, _)| buffered == id) {
+            return Err(format!("Document already buffered: {}", id).into());
+        }
+        self.buffered.push((id.to_string(), text.to_string()));
+        self.buffered_bytes += text.len();
+        match Grimoire::index_in_memory_with(self.buffered.clone(), &self.parser) {
+            Ok(buffer) => self.buffer = Some(buffer),
+            Err(e) => {
+                self.buffered.pop();
+                self.buffered_bytes -= text.len();
+                return Err(e);
+            }
+        }
+        if self.buffered_bytes > self.flush_bytes {
+            let flushed = self.flush()?;
+            self.merge()?;
+            Ok(flushed)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Save the buffer as a new persistent segment and empty it. Returns the segment's
+    /// prefix, or `None` when nothing was buffered.
+    pub fn flush(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let Some(buffer) = self.buffer.take() else {
+            return Ok(None);
+        };
+        let prefix = segment_prefix(&self.prefix, self.next_segment);
+        let saved = buffer.save(&prefix).and_then(|()| {
+            let documents = SegmentDocuments {
+                documents: self.buffered.clone(),
+                written_bytes: structure_bytes(&prefix)?,
+            };
+            let (dir, name) = split_prefix(&prefix);
+            documents.save(dir, name).map(|_| ())
+        });
+        if let Err(e) = saved {
+            self.buffer = Some(buffer);
+            return Err(e);
+        }
+        self.next_segment += 1;
+        self.segments.push(SharedSearchers::open(&prefix));
+        self.buffered.clear();
+        self.buffered_bytes = 0;
+        Ok(Some(prefix))
+    }
+
+    /// Merge segments while the merge policy finds a full tier, appending every merge to the
+    /// compaction log. Only flushed segments are merged, never the index built under the
+    /// prefix.
+    pub fn merge(&mut self) -> Result<Vec<MergeStats>, Box<dyn std::error::Error>> {
+        let mut merges = Vec::new();
+        loop {
+            let mergeable: Vec<(usize, u64)> = self
+                .segments
+                .iter()
+                .enumerate()
+                .filter_map(|(position, segment)| {
+                    let (dir, name) = split_prefix(segment.prefix());
+                    let metadata = fs::metadata(SegmentDocuments::file_path(dir, name)).ok()?;
+                    Some((position, metadata.len()))
+                })
+                .collect();
+            let sizes: Vec<u64> = mergeable.iter().map(|&(_, bytes)| bytes).collect();
+            let Some(chosen) = self.merge_policy.select(&sizes) else {
+                break;
+            };
+            let inputs: Vec<(usize, u64)> = chosen.iter().map(|&i| mergeable[i]).collect();
+            let stats = self.merge_segments(&inputs)?;
+            CompactionLog::append(&self.prefix, &stats)?;
+            merges.push(stats);
+        }
+        Ok(merges)
+    }
+
+    /// Documents of every segment and the buffer matching the query, see
+    /// `SharedSearchers::search_segment`
+    pub fn search(&self, query: &str) -> Result<HashSet<String>, String> {
+        let searchers: Vec<&SharedSearchers> = self
+            .segments
+            .iter()
+            .chain(self.buffer.as_ref().map(Grimoire::searchers))
+            .collect();
+        let per_segment = searchers
+            .par_iter()
+            .map(|searchers| searchers.search_segment(query))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(per_segment.into_iter().flatten().collect())
+    }
+
+    /// Persistent segments, the index built under the prefix first
+    pub fn segments(&self) -> &[SharedSearchers] {
+        &self.segments
+    }
+
+    /// Documents waiting in the buffer
+    pub fn buffered_documents(&self) -> usize {
+        self.buffered.len()
+    }
+
+    /// Text waiting in the buffer, in bytes
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Index the documents of the segments at the given positions, with their sizes, as one
+    /// new segment, then remove them
+    fn merge_segments(
+        &mut self,
+        inputs: &[(usize, u64)],
+    ) -> Result<MergeStats, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let mut documents = Vec::new();
+        let mut written_bytes = 0;
+        let mut merged_inputs = Vec::new();
+        for &(position, bytes) in inputs {
+            let prefix = self.segments[position].prefix().to_string();
+            let (dir, name) = split_prefix(&prefix);
+            let segment = SegmentDocuments::load(dir, name)?;
+            written_bytes += segment.written_bytes;
+            documents.extend(segment.documents);
+            merged_inputs.push((prefix, bytes));
+        }
+        let text_bytes: usize = documents.iter().map(|(_, text)| text.len()).sum();
+        let document_count = documents.len();
+
+        let output = segment_prefix(&self.prefix, self.next_segment);
+        Grimoire::index_in_memory_with(documents.clone(), &self.parser)?.save(&output)?;
+        self.next_segment += 1;
+        let output_bytes = structure_bytes(&output)?;
+        written_bytes += output_bytes;
+        let (dir, name) = split_prefix(&output);
+        SegmentDocuments {
+            documents,
+            written_bytes,
+        }
+        .save(dir, name)?;
+
+        // The merged segment is complete before its inputs go, so a crash in between leaves
+        // documents in two segments, which the union of their results hides
+        for &(position, _) in inputs.iter().rev() {
+            let segment = self.segments.remove(position);
+            remove_segment(segment.prefix())?;
+        }
+        self.segments.push(SharedSearchers::open(&output));
+        Ok(MergeStats {
+            merged_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            output,
+            inputs: merged_inputs,
+            output_bytes,
+            documents: document_count,
+            elapsed: start.elapsed(),
+            write_amplification: written_bytes as f64 / text_bytes.max(1) as f64,
+        })
+    }
+}
+
+/// Documents of a flushed segment, kept so it can be merged into a larger one
+#[derive(Serialize, Deserialize)]
+struct SegmentDocuments {
+    documents: Vec<(String, String)>,
+    /// Bytes of structures written for these documents, by their flush and every merge since
+    written_bytes: u64,
+}
+
+impl Persistable for SegmentDocuments {
+    const SUFFIX: &'static str = "_documents";
+}
+
+fn segment_prefix(prefix: &str, number: u64) -> String {
+    format!("{}_segment{}", prefix, number)
+}
+
+/// Numbers of the segments flushed or merged next to `prefix`, ascending
+fn segment_numbers(prefix: &str) -> Vec<u64> {
+    let path = Path::new(prefix);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Vec::new();
+    };
+    let head = format!("{}_segment", name);
+    let mut numbers: Vec<u64> = fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let file = entry.file_name().into_string().ok()?;
+            file.strip_prefix(&head)?
+                .strip_suffix("_index.bin")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    numbers.sort_unstable();
+    numbers
+}
+
+/// Bytes of the structures the manifest of a segment lists
+fn structure_bytes(prefix: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let Some(manifest) = IndexManifest::load(prefix)? else {
+        return Ok(0);
+    };
+    let mut bytes = 0;
+    for suffix in &manifest.structures {
+        bytes += fs::metadata(format!("{}{}.bin", prefix, suffix))?.len();
+    }
+    Ok(bytes)
+}
+
+/// Delete every file of a segment
+fn remove_segment(prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // Without its inverted index a segment is no longer found, so that goes first
+    let mut suffixes = vec!["_index".to_string()];
+    if let Some(manifest) = IndexManifest::load(prefix)? {
+        suffixes.extend(
+            manifest
+                .structures
+                .into_iter()
+                .filter(|suffix| suffix != "_index"),
+        );
+    }
+    suffixes.extend(["_documents".to_string(), "_manifest".to_string()]);
+    for suffix in suffixes {
+        match fs::remove_file(format!("{}{}.bin", prefix, suffix)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffered_documents_are_searchable_and_flushed() {
+        let directory = tempfile::tempdir().unwrap();
+        let prefix = directory.path().join("idx").to_string_lossy().to_string();
+        let found = |index: &NearRealTimeIndex, query: &str| {
+            let mut found: Vec<String> = index.search(query).unwrap().into_iter().collect();
+            found.sort();
+            found
+        };
+
+        let mut index = NearRealTimeIndex::open(&prefix, FB2Parser::new()).with_flush_threshold(30);
+        assert_eq!(index.add("a", "war and peace").unwrap(), None);
+        assert_eq!(found(&index, "war"), ["a"]);
+        assert_eq!(index.buffered_documents(), 1);
+        assert!(index.add("a", "again").is_err());
+
+        let flushed = index.add("b", "peace in the quiet forest").unwrap();
+        assert_eq!(flushed, Some(format!("{}_segment1", prefix)));
+        assert_eq!(index.buffered_documents(), 0);
+        index.add("c", "forest fire").unwrap();
+        // Terms only the buffer or only the segment holds match nothing in the other
+        assert_eq!(found(&index, "forest"), ["b", "c"]);
+        assert_eq!(found(&index, "fire or war"), ["a", "c"]);
+        assert_eq!(found(&index, "forest and not fire"), ["b"]);
+        assert_eq!(found(&index, "\"forest fire\""), ["c"]);
+
+        index.flush().unwrap();
+        let reopened = NearRealTimeIndex::open(&prefix, FB2Parser::new());
+        assert_eq!(reopened.segments().len(), 2);
+        assert_eq!(found(&reopened, "peace or fir*"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_full_tiers_are_merged_and_logged() {
+        let directory = tempfile::tempdir().unwrap();
+        let prefix = directory.path().join("idx").to_string_lossy().to_string();
+        let policy = MergePolicy {
+            max_segments_per_tier: 2,
+            min_merge_bytes: 1 << 20,
+            max_segment_bytes: 1 << 30,
+        };
+        let mut index = NearRealTimeIndex::open(&prefix, FB2Parser::new())
+            .with_flush_threshold(0)
+            .with_merge_policy(policy);
+
+        for (id, text) in [("a", "war"), ("b", "peace"), ("c", "forest")] {
+            index.add(id, text).unwrap();
+        }
+        // Every segment is in the lowest tier, so each pair merges: 1 and 2 into 3, which
+        // then merged with 4 into 5
+        let segments: Vec<&str> = index.segments().iter().map(|s| s.prefix()).collect();
+        assert_eq!(segments, [format!("{}_segment5", prefix)]);
+        assert!(!Path::new(&format!("{}_segment1_index.bin", prefix)).exists());
+        let mut found: Vec<String> = index
+            .search("war or peace or forest")
+            .unwrap()
+            .into_iter()
+            .collect();
+        found.sort();
+        assert_eq!(found, ["a", "b", "c"]);
+
+        let merges = CompactionLog::load(&prefix).unwrap();
+        let outputs: Vec<&str> = merges.iter().map(|merge| merge.output.as_str()).collect();
+        assert_eq!(
+            outputs,
+            [
+                format!("{}_segment3", prefix),
+                format!("{}_segment5", prefix)
+            ]
+        );
+        assert_eq!(merges[1].documents, 3);
+        // The documents of segment 3 were written by their flushes and both merges
+        assert!(merges[1].write_amplification > merges[0].write_amplification);
+
+        index.merge().unwrap();
+        let reopened = NearRealTimeIndex::open(&prefix, FB2Parser::new());
+        assert_eq!(reopened.segments().len(), 1);
+        assert_eq!(reopened.next_segment, 6);
+    }
+}
@@ -0,0 +1,214 @@
+use roaring::RoaringBitmap;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{query_terms, CompressedInvertedIndex, FieldIndex};
+use crate::{DEFAULT_FIELD, METADATA_FIELDS, ZONE_FIELDS};
+
+/// What a query term found in each zone of a document adds to its score. Weights sum to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoneWeights {
+    pub title: f64,
+    pub annotation: f64,
+    pub body: f64,
+}
+
+impl Default for ZoneWeights {
+    fn default() -> Self {
+        ZoneWeights {
+            title: 0.5,
+            annotation: 0.2,
+            body: 0.3,
+        }
+    }
+}
+
+impl ZoneWeights {
+    /// Every zone with its weight, the body last
+    pub fn zones(&self) -> [(&'static str, f64); 3] {
+        [
+            (ZONE_FIELDS[0], self.title),
+            (ZONE_FIELDS[1], self.annotation),
+            (DEFAULT_FIELD, self.body),
+        ]
+    }
+}
+
+/// `title=0.6,body=0.4`: zones left out weigh nothing and the rest are scaled to sum to 1
+impl FromStr for ZoneWeights {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut weights = ZoneWeights {
+            title: 0.0,
+            annotation: 0.0,
+            body: 0.0,
+        };
+        for part in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let (zone, weight) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected zone=weight, got '{}'", part))?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid weight in '{}'", part))?;
+            if !(weight >= 0.0 && weight.is_finite()) {
+                return Err(format!("Zone weights must not be negative, got '{}'", part));
+            }
+            match zone.trim() {
+                "title" => weights.title = weight,
+                "annotation" => weights.annotation = weight,
+                "body" => weights.body = weight,
+                other => {
+                    return Err(format!(
+                        "Unknown zone '{}', expected title, annotation or body",
+                        other
+                    ))
+                }
+            }
+        }
+        let total = weights.title + weights.annotation + weights.body;
+        if total <= 0.0 {
+            return Err("At least one zone needs a positive weight".to_string());
+        }
+        Ok(ZoneWeights {
+            title: weights.title / total,
+            annotation: weights.annotation / total,
+            body: weights.body / total,
+        })
+    }
+}
+
+impl fmt::Display for ZoneWeights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let zones: Vec<String> = self
+            .zones()
+            .iter()
+            .map(|(zone, weight)| format!("{}={:.2}", zone, weight))
+            .collect();
+        write!(f, "{}", zones.join(","))
+    }
+}
+
+/// The best `k` of `candidates`, ids in `index`, by weighted zone score for the positive terms
+/// of `query`: every zone adds its weight times the share of the terms it holds, so at the
+/// default weights a book with the terms in its title outranks one with them only in the body.
+/// A `zone:term` counts in its zone alone and metadata terms such as `author:` are left out.
+/// Title and annotation terms come from `fields`, body terms from the posting lists. Ties go
+/// to the first name.
+pub fn rank_by_zones(
+    index: &CompressedInvertedIndex,
+    fields: &FieldIndex,
+    weights: &ZoneWeights,
+    query: &str,
+    candidates: &RoaringBitmap,
+    k: usize,
+) -> Result<Vec<(String, f64)>, String> {
+    let terms = query_terms(query)?;
+    let terms: Vec<(Option<&str>, &str)> = terms
+        .iter()
+        .filter_map(|term| match term.split_once(':') {
+            Some((field, _)) if METADATA_FIELDS.contains(&field) => None,
+            Some((zone, word)) if ZONE_FIELDS.contains(&zone) || zone == DEFAULT_FIELD => {
+                Some((Some(zone), word))
+            }
+            _ => Some((None, term.as_str())),
+        })
+        .collect();
+    if terms.is_empty() {
+        return Err("The query has no terms to score zones by".to_string());
+    }
+
+    let mut scores: HashMap<u32, f64> = candidates.iter().map(|id| (id, 0.0)).collect();
+    let share = 1.0 / terms.len() as f64;
+    for (restriction, word) in terms {
+        for (zone, weight) in weights.zones() {
+            if weight == 0.0 || restriction.is_some_and(|restriction| restriction != zone) {
+                continue;
+            }
+            let documents: RoaringBitmap = if zone == DEFAULT_FIELD {
+                index
+                    .compressed_index
+                    .get(word)
+                    .map(|bytes| index.encoding.decode_bitmap(bytes))
+                    .unwrap_or_default()
+            } else {
+                fields
+                    .documents(zone, word)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|document| index.doc_name_to_id.get(document).copied())
+                    .collect()
+            };
+            for id in documents & candidates {
+                *scores.entry(id).or_insert(0.0) += weight * share;
+            }
+        }
+    }
+
+    let mut ranked: Vec<(String, f64)> = scores
+        .into_iter()
+        .map(|(id, score)| (index.doc_id_to_name[id as usize].clone(), score))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(k);
+    Ok(ranked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressedDictionary, Dictionary};
+
+    #[test]
+    fn test_title_matches_outrank_body_matches() {
+        let mut dict = Dictionary::new();
+        for (document, text) in [
+            ("a.fb2", "буря в море"),
+            ("b.fb2", "тишина в лесу"),
+            ("c.fb2", "буря и море буря"),
+        ] {
+            for word in text.split_whitespace() {
+                dict.add_term(word.to_string(), document.to_string());
+            }
+        }
+        let index = CompressedInvertedIndex::from_compressed_dictionary(
+            &CompressedDictionary::from_dictionary(&dict),
+        );
+        let mut fields = FieldIndex::new();
+        fields.insert_terms("title", &["буря".to_string()], "b.fb2");
+        fields.insert_terms("annotation", &["море".to_string()], "c.fb2");
+        fields.insert("author", "Лев Толстой", "c.fb2");
+        let everything: RoaringBitmap = (0..3).collect();
+        let weights = ZoneWeights::default();
+
+        let ranked = rank_by_zones(&index, &fields, &weights, "буря", &everything, 10).unwrap();
+        let names: Vec<&str> = ranked.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["b.fb2", "a.fb2", "c.fb2"]);
+        assert!((ranked[0].1 - 0.5).abs() < 1e-9);
+
+        // Both terms in c's body and "море" in its annotation, one of them in b's title
+        let query = "буря and море and author:толстой";
+        let ranked = rank_by_zones(&index, &fields, &weights, query, &everything, 2).unwrap();
+        assert_eq!(ranked[0].0, "c.fb2");
+        assert!((ranked[0].1 - 0.4).abs() < 1e-9);
+        assert_eq!(ranked.len(), 2);
+
+        let ranked =
+            rank_by_zones(&index, &fields, &weights, "title:буря", &everything, 10).unwrap();
+        assert_eq!(ranked[0], ("b.fb2".to_string(), 0.5));
+        assert_eq!(ranked[1].1, 0.0);
+        assert!(rank_by_zones(&index, &fields, &weights, "author:x", &everything, 1).is_err());
+
+        let weights: ZoneWeights = "title=3, body=1".parse().unwrap();
+        assert_eq!(weights.to_string(), "title=0.75,annotation=0.00,body=0.25");
+        assert!("title=0".parse::<ZoneWeights>().is_err());
+        assert!("header=1".parse::<ZoneWeights>().is_err());
+        assert!("title=-1,body=2".parse::<ZoneWeights>().is_err());
+    }
+}